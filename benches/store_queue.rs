@@ -0,0 +1,124 @@
+//! Request-path latency of cache stores while the cache is full and evicting
+//!
+//! The cache is kept at capacity with small entries, as a busy edge's would
+//! be, and each request stores a large response that has to evict thousands
+//! of them. Stores run inline on the request, then through the store queue.
+//!
+//! ```text
+//! cargo bench --bench store_queue
+//! ```
+
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::Bytes;
+use screaming_eagle::cache::{Cache, CacheEntry};
+use screaming_eagle::config::{CacheConfig, StoreQueueConfig};
+use screaming_eagle::headers::ResponseHeaders;
+use screaming_eagle::store_queue::StoreQueue;
+
+const CACHE_MB: usize = 64;
+const SMALL_BYTES: usize = 1024;
+const LARGE_BYTES: usize = 4 * 1024 * 1024;
+const REQUESTS: usize = 200;
+
+fn entry(body: Bytes) -> CacheEntry {
+    let now = Instant::now();
+    CacheEntry {
+        size: body.len(),
+        body,
+        headers: ResponseHeaders::default(),
+        status_code: 200,
+        content_type: None,
+        etag: None,
+        last_modified: None,
+        created_at: now,
+        expires_at: now + Duration::from_secs(3600),
+        expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
+        stale_if_error_secs: None,
+        access_count: 0,
+        last_accessed: now,
+        cache_tags: Vec::new(),
+        headers_only: false,
+        fetch_duration: Duration::ZERO,
+        immutable: false,
+    }
+}
+
+/// Top the cache up with small entries until it is full
+fn fill(cache: &Cache, small: &Bytes, next: &mut usize) {
+    let stats = cache.stats();
+    let free = stats.max_size_bytes.saturating_sub(stats.total_size_bytes);
+    for _ in 0..free / SMALL_BYTES {
+        cache.set(format!("web/small/{}", next), entry(small.clone()));
+        *next += 1;
+    }
+}
+
+/// Time each request's store, returning the latencies and stores dropped
+async fn run(queued: bool) -> (Vec<Duration>, usize) {
+    let mut config = CacheConfig {
+        max_size_mb: CACHE_MB,
+        ..Default::default()
+    };
+    config.hierarchy.enabled = true;
+    let cache = Arc::new(Cache::new(config));
+    let queue = StoreQueue::new(
+        StoreQueueConfig {
+            enabled: queued,
+            ..Default::default()
+        },
+        cache.clone(),
+    );
+
+    let small = Bytes::from(vec![b's'; SMALL_BYTES]);
+    let large = Bytes::from(vec![b'l'; LARGE_BYTES]);
+    let mut next = 0;
+    let mut latencies = Vec::with_capacity(REQUESTS);
+    let mut dropped = 0;
+    for i in 0..REQUESTS {
+        fill(&cache, &small, &mut next);
+
+        let slot = cache.reserve(&format!("web/large/{}", i));
+        let started = Instant::now();
+        if !queue.store(slot, entry(large.clone())) {
+            dropped += 1;
+        }
+        latencies.push(started.elapsed());
+
+        // Other traffic refills the cache before the next large response
+        queue.flush().await;
+    }
+    (latencies, dropped)
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    sorted[(sorted.len() * percent / 100).min(sorted.len() - 1)]
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    println!(
+        "{} stores of {} KiB into a full {} MiB cache of {} KiB entries",
+        REQUESTS,
+        LARGE_BYTES / 1024,
+        CACHE_MB,
+        SMALL_BYTES / 1024
+    );
+    for (name, queued) in [("inline", false), ("queued", true)] {
+        let (mut latencies, dropped) = runtime.block_on(run(queued));
+        latencies.sort_unstable();
+        println!(
+            "{:>8}: p50 {:>10.3?}  p99 {:>10.3?}  max {:>10.3?}  dropped {}",
+            name,
+            percentile(&latencies, 50),
+            percentile(&latencies, 99),
+            latencies[latencies.len() - 1],
+            dropped
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Media type negotiation for cache keys (RFC 9110 §12.5.1)
+//!
+//! An origin that serves one resource in several formats sends
+//! `Vary: Accept`, but browsers send long, ever-changing `Accept` values and
+//! keying on them raw stores a copy per browser. `accept_rules` reduce the
+//! header to the one media type the origin would choose among those it
+//! produces, and that is what goes in the key.
+
+use crate::encoding::parse_qvalue;
+
+/// Highest q-value, in thousandths
+const Q_MAX: u16 = 1000;
+
+/// A parsed `Accept` header
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AcceptMediaTypes {
+    /// Media ranges listed (lowercased `type/subtype`), with their q-value
+    /// in thousandths
+    ranges: Vec<(String, u16)>,
+}
+
+impl AcceptMediaTypes {
+    /// Parse a header value
+    ///
+    /// Entries with a malformed q-value or no `/` are ignored. Parameters
+    /// other than `q` don't narrow a range.
+    pub fn parse(value: &str) -> Self {
+        let mut accept = AcceptMediaTypes::default();
+        for item in value.split(',') {
+            let mut parts = item.split(';');
+            let range = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            if !range.contains('/') {
+                continue;
+            }
+
+            let mut quality = Some(Q_MAX);
+            for param in parts {
+                if let Some((name, value)) = param.split_once('=')
+                    && name.trim().eq_ignore_ascii_case("q")
+                {
+                    quality = parse_qvalue(value.trim());
+                }
+            }
+            if let Some(quality) = quality {
+                accept.ranges.push((range, quality));
+            }
+        }
+        accept
+    }
+
+    /// How acceptable a media type is, in thousandths (0 = not acceptable)
+    ///
+    /// The most specific range matching it decides: `type/subtype` over
+    /// `type/*` over `*/*`. Among equally specific ranges the first wins.
+    pub fn quality(&self, media_type: &str) -> u16 {
+        let media_type = media_type.to_ascii_lowercase();
+        let main_type = media_type.split('/').next().unwrap_or_default();
+        let mut best: Option<(u8, u16)> = None;
+        for (range, quality) in &self.ranges {
+            let specificity = if *range == media_type {
+                2
+            } else if range.strip_suffix("/*") == Some(main_type) {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                continue;
+            };
+            if best.is_none_or(|(s, _)| specificity > s) {
+                best = Some((specificity, *quality));
+            }
+        }
+        best.map_or(0, |(_, quality)| quality)
+    }
+
+    /// The most acceptable of `available` (listed most preferred first), or
+    /// `None` when the client accepts none of them
+    pub fn select<'a>(&self, available: &'a [String]) -> Option<&'a str> {
+        let mut best: Option<(&str, u16)> = None;
+        for media_type in available {
+            let quality = self.quality(media_type);
+            if quality > 0 && best.is_none_or(|(_, q)| quality > q) {
+                best = Some((media_type, quality));
+            }
+        }
+        best.map(|(media_type, _)| media_type)
+    }
+}
+
+/// The media type an `Accept` value selects among `available`, as used in
+/// cache keys; no header accepts anything, so selects the first
+pub fn cache_key_value(accept: Option<&str>, available: &[String], default_token: &str) -> String {
+    let selected = match accept.map(str::trim) {
+        Some(value) if !value.is_empty() => AcceptMediaTypes::parse(value).select(available),
+        _ => available.first().map(String::as_str),
+    };
+    selected.unwrap_or(default_token).to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn produced() -> Vec<String> {
+        vec![
+            "application/json".to_string(),
+            "application/msgpack".to_string(),
+        ]
+    }
+
+    #[test]
+    fn test_browser_accepts_share_the_json_variant() {
+        let available = produced();
+        for accept in [
+            // Chrome and Edge navigation
+            "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.7",
+            // Firefox navigation
+            "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/png,image/svg+xml,*/*;q=0.8",
+            // Safari navigation
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+            // fetch() and curl
+            "*/*",
+            // axios
+            "application/json, text/plain, */*",
+            // jQuery getJSON
+            "application/json, text/javascript, */*; q=0.01",
+            "application/*",
+            "Application/JSON",
+        ] {
+            assert_eq!(
+                cache_key_value(Some(accept), &available, "none"),
+                "application/json",
+                "{}",
+                accept
+            );
+        }
+        assert_eq!(
+            cache_key_value(None, &available, "none"),
+            "application/json"
+        );
+    }
+
+    #[test]
+    fn test_msgpack_and_unmatched_accepts() {
+        let available = produced();
+        let key = |accept| cache_key_value(Some(accept), &available, "none");
+
+        assert_eq!(key("application/msgpack"), "application/msgpack");
+        assert_eq!(
+            key("application/msgpack, application/json;q=0.5"),
+            "application/msgpack"
+        );
+        assert_eq!(
+            key("application/json;q=0.5, application/msgpack;q=0.8"),
+            "application/msgpack"
+        );
+        // A specific range outweighs the wildcard it would otherwise fall under
+        assert_eq!(key("*/*, application/json;q=0"), "application/msgpack");
+        assert_eq!(
+            key("application/*;q=0.1, application/msgpack"),
+            "application/msgpack"
+        );
+
+        assert_eq!(key("text/html"), "none");
+        assert_eq!(key("*/*;q=0"), "none");
+        assert_eq!(key("application/json;q=abc"), "none");
+        assert_eq!(
+            cache_key_value(Some("image/png"), &available, "json"),
+            "json"
+        );
+    }
+}
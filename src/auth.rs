@@ -1,30 +1,111 @@
 //! Admin API authentication module
 //!
-//! Provides middleware for authenticating admin API requests using bearer tokens
-//! and optional IP-based access control.
+//! Provides middleware for authenticating admin API requests using bearer tokens,
+//! optional IP-based access control, and per-IP lockout after repeated failures.
+//! Also mints and verifies scoped purge tokens, which let third parties purge
+//! their own paths or tags without the admin token.
 
 use axum::{
     body::Body,
     extract::{ConnectInfo, State},
-    http::{Request, StatusCode, header},
+    http::{HeaderMap, Request, StatusCode, header},
     middleware::Next,
     response::{IntoResponse, Response},
 };
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
 use tracing::{debug, warn};
 
+use crate::cache_key::KeyPrefix;
 use crate::config::AdminConfig;
+use crate::metrics::Metrics;
+use crate::security::{TrustedProxies, check_cidr};
+
+/// Most client IPs with failure history at once; beyond it the oldest is dropped
+const MAX_FAILURE_RECORDS: usize = 10_000;
+
+/// Failed authentication history for one client IP
+#[derive(Debug, Clone)]
+struct FailureRecord {
+    /// Invalid tokens since the last lockout or success
+    failures: u32,
+    /// Lockouts served so far; each one doubles the next
+    lockouts: u32,
+    locked_until: Option<Instant>,
+    last_failure: Instant,
+}
+
+/// How an admin request was let in, added to its extensions for the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminIdentity {
+    /// Admin auth is disabled
+    Anonymous,
+    /// The admin bearer token
+    AdminToken,
+    /// A scoped purge token
+    PurgeToken,
+    /// A PURGE from an address in `purge_method.allowed_ips`
+    PurgeIp,
+}
+
+impl AdminIdentity {
+    /// Name recorded in audit entries; never the token itself
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdminIdentity::Anonymous => "anonymous",
+            AdminIdentity::AdminToken => "admin",
+            AdminIdentity::PurgeToken => "purge_token",
+            AdminIdentity::PurgeIp => "purge_ip",
+        }
+    }
+}
 
 /// Admin authentication state
 #[derive(Clone)]
 pub struct AdminAuth {
     config: AdminConfig,
+    failures: Arc<DashMap<IpAddr, FailureRecord>>,
+    max_failure_records: usize,
+    trusted_proxies: TrustedProxies,
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl AdminAuth {
     pub fn new(config: AdminConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            failures: Arc::new(DashMap::new()),
+            max_failure_records: MAX_FAILURE_RECORDS,
+            trusted_proxies: TrustedProxies::default(),
+            metrics: None,
+        }
+    }
+
+    /// Honour forwarding headers from these proxies when identifying clients
+    pub fn with_trusted_proxies(mut self, trusted_proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    /// Address a request is allowed, counted and locked out as
+    ///
+    /// This is the connecting peer unless it is a trusted proxy, so clients
+    /// can't dodge a lockout or the allowlist by sending `X-Forwarded-For`.
+    pub fn client_ip(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        self.trusted_proxies.client_ip(headers, peer)
+    }
+
+    /// Report authentication failures to the metrics registry
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
     }
 
     /// Check if authentication is enabled
@@ -46,6 +127,106 @@ impl AdminAuth {
         }
     }
 
+    /// Verify a purge token's signature and expiry against the admin secret
+    pub fn verify_purge_token(
+        &self,
+        token: &str,
+        now: u64,
+    ) -> Result<PurgeTokenClaims, PurgeTokenError> {
+        verify_purge_token(&self.config, token, now)
+    }
+
+    /// Remaining lockout for an IP, if it is currently locked out
+    pub fn lockout_remaining(&self, ip: &IpAddr) -> Option<Duration> {
+        if !self.config.lockout.enabled {
+            return None;
+        }
+
+        let record = self.failures.get(ip)?;
+        let locked_until = record.locked_until?;
+        locked_until.checked_duration_since(Instant::now())
+    }
+
+    /// Record an invalid token from an IP, returning the lockout it triggered
+    pub fn record_failure(&self, ip: IpAddr) -> Option<Duration> {
+        if !self.config.lockout.enabled {
+            return None;
+        }
+
+        if !self.failures.contains_key(&ip) && self.failures.len() >= self.max_failure_records {
+            self.evict_oldest();
+        }
+
+        let lockout = &self.config.lockout;
+        let now = Instant::now();
+        let mut record = self.failures.entry(ip).or_insert(FailureRecord {
+            failures: 0,
+            lockouts: 0,
+            locked_until: None,
+            last_failure: now,
+        });
+
+        // An expired lockout starts a fresh count, but the next one lasts longer
+        if record.locked_until.is_some_and(|until| until <= now) {
+            record.locked_until = None;
+            record.failures = 0;
+        }
+
+        record.failures += 1;
+        record.last_failure = now;
+
+        if record.failures < lockout.max_failures {
+            return None;
+        }
+
+        let secs = lockout
+            .lockout_secs
+            .saturating_mul(1u64 << record.lockouts.min(16))
+            .min(lockout.max_lockout_secs);
+        let duration = Duration::from_secs(secs);
+        record.locked_until = Some(now + duration);
+        record.lockouts += 1;
+        record.failures = 0;
+        Some(duration)
+    }
+
+    /// Clear an IP's failure history after a successful authentication
+    pub fn record_success(&self, ip: &IpAddr) {
+        self.failures.remove(ip);
+    }
+
+    /// Drop the record with the oldest failure, sparing active lockouts if any
+    /// other record can go instead
+    fn evict_oldest(&self) {
+        let now = Instant::now();
+        let oldest = self
+            .failures
+            .iter()
+            .min_by_key(|entry| {
+                let locked = entry.locked_until.is_some_and(|until| until > now);
+                (locked, entry.last_failure)
+            })
+            .map(|entry| *entry.key());
+        if let Some(ip) = oldest {
+            self.failures.remove(&ip);
+        }
+    }
+
+    /// Drop failure records that are neither locked nor recently active
+    pub fn cleanup(&self, max_age: Duration) {
+        let now = Instant::now();
+        self.failures.retain(|_, record| {
+            record.locked_until.is_some_and(|until| until > now)
+                || now.duration_since(record.last_failure) < max_age
+        });
+    }
+
+    fn record_metric(&self, reason: &str) {
+        if let Some(ref metrics) = self.metrics {
+            metrics.record_admin_auth_failure(reason);
+        }
+    }
+
     /// Check if IP is allowed
     pub fn is_ip_allowed(&self, ip: &IpAddr) -> bool {
         if self.config.allowed_ips.is_empty() {
@@ -53,53 +234,66 @@ impl AdminAuth {
             return true;
         }
 
-        let ip_str = ip.to_string();
-        self.config.allowed_ips.iter().any(|allowed| {
-            if allowed.contains('/') {
-                // CIDR notation - simple prefix match for now
-                // For production, use a proper CIDR parser
-                ip_str.starts_with(allowed.split('/').next().unwrap_or(""))
-            } else {
-                &ip_str == allowed
-            }
-        })
+        ip_in_list(&self.config.allowed_ips, ip)
+    }
+
+    /// Check if an IP may use the PURGE method without a token
+    pub fn is_purge_ip_allowed(&self, ip: &IpAddr) -> bool {
+        ip_in_list(&self.config.purge_method.allowed_ips, ip)
     }
 }
 
+/// Whether `ip` is one of the addresses or CIDR ranges in `list`
+fn ip_in_list(list: &[String], ip: &IpAddr) -> bool {
+    list.iter().any(|allowed| {
+        if allowed.contains('/') {
+            check_cidr(ip, allowed) == Some(true)
+        } else {
+            allowed
+                .parse::<IpAddr>()
+                .is_ok_and(|allowed| allowed == *ip)
+        }
+    })
+}
+
 /// Constant-time string comparison to prevent timing attacks
+///
+/// Both sides are hashed first so the comparison also doesn't leak the
+/// length of the expected token.
 fn constant_time_compare(a: &str, b: &str) -> bool {
-    if a.len() != b.len() {
-        return false;
-    }
-
-    let mut result = 0u8;
-    for (x, y) in a.bytes().zip(b.bytes()) {
-        result |= x ^ y;
-    }
-    result == 0
+    let a = Sha256::digest(a.as_bytes());
+    let b = Sha256::digest(b.as_bytes());
+    a.ct_eq(&b).into()
 }
 
 /// Middleware for admin API authentication
 pub async fn admin_auth_middleware(
     State(auth): State<Arc<AdminAuth>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    request: Request<Body>,
+    mut request: Request<Body>,
     next: Next,
 ) -> Response {
     // Check if auth is enabled
     if !auth.is_enabled() {
         debug!("Admin auth disabled, allowing request");
+        request.extensions_mut().insert(AdminIdentity::Anonymous);
         return next.run(request).await;
     }
 
-    let client_ip = extract_client_ip(&request, addr.ip());
+    let client_ip = auth.client_ip(request.headers(), addr.ip());
 
     // Check IP allowlist
     if !auth.is_ip_allowed(&client_ip) {
         warn!(ip = %client_ip, "Admin request from non-allowed IP");
+        auth.record_metric("ip_not_allowed");
         return (StatusCode::FORBIDDEN, "Access denied: IP not in allowlist").into_response();
     }
 
+    // Locked-out IPs are rejected even if they now present the right token
+    if let Some(response) = locked_out_response(&auth, client_ip) {
+        return response;
+    }
+
     // Check Authorization header
     let auth_header = request
         .headers()
@@ -111,14 +305,25 @@ pub async fn admin_auth_middleware(
             let token = &header[7..]; // Skip "Bearer "
             if auth.verify_token(token) {
                 debug!(ip = %client_ip, "Admin auth successful");
+                auth.record_success(&client_ip);
+                request.extensions_mut().insert(AdminIdentity::AdminToken);
                 next.run(request).await
             } else {
-                warn!(ip = %client_ip, "Invalid admin token");
+                auth.record_metric("invalid_token");
+                match auth.record_failure(client_ip) {
+                    Some(lockout) => warn!(
+                        ip = %client_ip,
+                        lockout_secs = lockout.as_secs(),
+                        "Invalid admin token, locking out IP"
+                    ),
+                    None => warn!(ip = %client_ip, "Invalid admin token"),
+                }
                 (StatusCode::UNAUTHORIZED, "Invalid authentication token").into_response()
             }
         }
         Some(_) => {
             warn!(ip = %client_ip, "Invalid Authorization header format");
+            auth.record_metric("invalid_scheme");
             (
                 StatusCode::UNAUTHORIZED,
                 "Authorization header must use Bearer scheme",
@@ -127,6 +332,7 @@ pub async fn admin_auth_middleware(
         }
         None => {
             warn!(ip = %client_ip, "Missing Authorization header for admin endpoint");
+            auth.record_metric("missing_token");
             (
                 StatusCode::UNAUTHORIZED,
                 "Authentication required. Use: Authorization: Bearer <token>",
@@ -136,29 +342,313 @@ pub async fn admin_auth_middleware(
     }
 }
 
-/// Extract client IP from request headers or connection info
-fn extract_client_ip(request: &Request<Body>, fallback: IpAddr) -> IpAddr {
-    // Check X-Forwarded-For header
-    if let Some(forwarded) = request.headers().get("X-Forwarded-For")
-        && let Ok(value) = forwarded.to_str()
-            && let Some(first_ip) = value.split(',').next()
-                && let Ok(ip) = first_ip.trim().parse() {
-                    return ip;
+/// 429 response for an IP that is currently locked out
+fn locked_out_response(auth: &AdminAuth, client_ip: IpAddr) -> Option<Response> {
+    let remaining = auth.lockout_remaining(&client_ip)?;
+    warn!(ip = %client_ip, retry_after_secs = remaining.as_secs(), "Admin request from locked-out IP");
+    auth.record_metric("locked_out");
+    Some(
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, remaining.as_secs().max(1).to_string())],
+            "Too many failed authentication attempts",
+        )
+            .into_response(),
+    )
+}
+
+/// Middleware for the purge endpoint: a scoped purge token or the admin token
+///
+/// Requests carrying `X-Purge-Token` are authorized by the token alone (the IP
+/// allowlist is for operators, not third parties) and the verified claims are
+/// added to the request extensions for the handler to enforce. Anything else
+/// goes through [`admin_auth_middleware`].
+pub async fn purge_auth_middleware(
+    State(auth): State<Arc<AdminAuth>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let token = request
+        .headers()
+        .get(PURGE_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let Some(token) = token.filter(|_| auth.is_enabled()) else {
+        return admin_auth_middleware(State(auth), ConnectInfo(addr), request, next).await;
+    };
+
+    let client_ip = auth.client_ip(request.headers(), addr.ip());
+    if let Some(response) = locked_out_response(&auth, client_ip) {
+        return response;
+    }
+
+    match auth.verify_purge_token(&token, unix_now()) {
+        Ok(claims) => {
+            debug!(ip = %client_ip, prefixes = ?claims.prefixes, tags = ?claims.tags, "Purge token accepted");
+            request.extensions_mut().insert(claims);
+            request.extensions_mut().insert(AdminIdentity::PurgeToken);
+            next.run(request).await
+        }
+        Err(e) => {
+            match e {
+                PurgeTokenError::Expired => auth.record_metric("expired_purge_token"),
+                PurgeTokenError::Disabled => auth.record_metric("purge_tokens_disabled"),
+                _ => {
+                    // Forged or mangled tokens count towards the IP's lockout
+                    auth.record_metric("invalid_purge_token");
+                    auth.record_failure(client_ip);
                 }
+            }
+            warn!(ip = %client_ip, error = %e, "Rejected purge token");
+            (StatusCode::UNAUTHORIZED, e.to_string()).into_response()
+        }
+    }
+}
+
+/// Middleware for the PURGE method on CDN routes
+///
+/// Other methods pass straight through. A PURGE from an address in
+/// `purge_method.allowed_ips` needs no token; that check uses the connecting
+/// socket, since forwarding headers are client-supplied. Anything else is
+/// authenticated like the purge endpoint, see [`purge_auth_middleware`].
+pub async fn purge_method_auth_middleware(
+    State(auth): State<Arc<AdminAuth>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    if request.method().as_str() != PURGE_METHOD {
+        return next.run(request).await;
+    }
+
+    if auth.is_purge_ip_allowed(&addr.ip()) {
+        debug!(ip = %addr.ip(), "PURGE allowed by IP");
+        request.extensions_mut().insert(AdminIdentity::PurgeIp);
+        return next.run(request).await;
+    }
+
+    purge_auth_middleware(State(auth), ConnectInfo(addr), request, next).await
+}
+
+/// Header carrying a scoped purge token
+pub const PURGE_TOKEN_HEADER: &str = "x-purge-token";
+
+/// Varnish-style HTTP method that invalidates the requested resource
+pub const PURGE_METHOD: &str = "PURGE";
+
+/// Domain separator so purge token signatures can't be reused elsewhere
+const PURGE_TOKEN_CONTEXT: &[u8] = b"screaming-eagle-purge-token-v1.";
+
+/// What a purge token allows, and until when
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PurgeTokenClaims {
+    /// Cache key prefixes the holder may purge under
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    /// Cache tags the holder may purge
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The holder may purge the whole cache
+    #[serde(default)]
+    pub purge_all: bool,
+    /// Unix timestamp (seconds) after which the token is rejected
+    pub expires_at: u64,
+}
+
+/// A purge request item that falls outside a token's scope
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PurgeScopeViolation {
+    /// "key", "prefix", "tag" or "all"
+    pub kind: String,
+    pub item: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PurgeTokenError {
+    #[error("Purge tokens are disabled")]
+    Disabled,
+
+    #[error("Purge tokens require admin.auth_token to be set")]
+    NoSecret,
+
+    #[error("Purge token must allow at least one prefix or tag, or purge_all")]
+    EmptyScope,
+
+    #[error("Malformed purge token")]
+    Malformed,
+
+    #[error("Invalid purge token signature")]
+    InvalidSignature,
+
+    #[error("Purge token has expired")]
+    Expired,
+}
 
-    // Check X-Real-IP header
-    if let Some(real_ip) = request.headers().get("X-Real-IP")
-        && let Ok(value) = real_ip.to_str()
-            && let Ok(ip) = value.trim().parse() {
-                return ip;
+impl PurgeTokenClaims {
+    /// Whether a single cache key is under one of the allowed prefixes
+    pub fn allows_key(&self, key: &str) -> bool {
+        self.prefixes
+            .iter()
+            .any(|p| KeyPrefix::parse(p).matches(key))
+    }
+
+    /// Whether every key matching `prefix` is under one of the allowed prefixes
+    pub fn allows_prefix(&self, prefix: &str) -> bool {
+        let prefix = KeyPrefix::parse(prefix);
+        self.prefixes
+            .iter()
+            .any(|p| KeyPrefix::parse(p).covers(&prefix))
+    }
+
+    pub fn allows_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    /// Every part of a purge request outside this token's scope
+    ///
+    /// An empty result means the whole request may proceed. A full purge is
+    /// only in scope for a token minted with `purge_all`.
+    pub fn violations(
+        &self,
+        keys: &[String],
+        prefix: Option<&str>,
+        tag: Option<&str>,
+        all: bool,
+    ) -> Vec<PurgeScopeViolation> {
+        let mut violations = Vec::new();
+        let mut reject = |kind: &str, item: &str, error: &str| {
+            violations.push(PurgeScopeViolation {
+                kind: kind.to_string(),
+                item: item.to_string(),
+                error: error.to_string(),
+            })
+        };
+
+        if all && !self.purge_all {
+            reject("all", "*", "This purge token cannot purge the whole cache");
+        }
+        if let Some(tag) = tag
+            && !self.allows_tag(tag)
+        {
+            reject("tag", tag, "Tag is not allowed by this token");
+        }
+        if let Some(prefix) = prefix
+            && !self.allows_prefix(prefix)
+        {
+            reject("prefix", prefix, "Prefix is outside this token's scope");
+        }
+        for key in keys {
+            if !self.allows_key(key) {
+                reject("key", key, "Key is outside this token's scope");
             }
+        }
+
+        violations
+    }
+}
 
-    fallback
+/// Current time as a unix timestamp in seconds
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn purge_token_mac(config: &AdminConfig) -> Result<Hmac<Sha256>, PurgeTokenError> {
+    if !config.purge_tokens.enabled {
+        return Err(PurgeTokenError::Disabled);
+    }
+    let secret = config
+        .auth_token
+        .as_deref()
+        .filter(|s| !s.is_empty())
+        .ok_or(PurgeTokenError::NoSecret)?;
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(PURGE_TOKEN_CONTEXT);
+    Ok(mac)
+}
+
+/// Mint a signed purge token limited to `prefixes` and `tags`, and allowed
+/// to purge the whole cache if `purge_all` is set
+///
+/// The lifetime defaults to `purge_tokens.default_ttl_secs` and is capped at
+/// `purge_tokens.max_ttl_secs`. Tokens are `<payload>.<signature>`, both
+/// base64url; rotating `auth_token` invalidates every outstanding token.
+pub fn mint_purge_token(
+    config: &AdminConfig,
+    prefixes: Vec<String>,
+    tags: Vec<String>,
+    purge_all: bool,
+    ttl_secs: Option<u64>,
+    now: u64,
+) -> Result<(String, PurgeTokenClaims), PurgeTokenError> {
+    let mut mac = purge_token_mac(config)?;
+
+    let prefixes: Vec<String> = prefixes.into_iter().filter(|p| !p.is_empty()).collect();
+    let tags: Vec<String> = tags.into_iter().filter(|t| !t.is_empty()).collect();
+    if prefixes.is_empty() && tags.is_empty() && !purge_all {
+        return Err(PurgeTokenError::EmptyScope);
+    }
+
+    let ttl = ttl_secs
+        .unwrap_or(config.purge_tokens.default_ttl_secs)
+        .min(config.purge_tokens.max_ttl_secs);
+    let claims = PurgeTokenClaims {
+        prefixes,
+        tags,
+        purge_all,
+        expires_at: now.saturating_add(ttl),
+    };
+
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).expect("claims serialize"));
+    mac.update(payload.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+    Ok((format!("{}.{}", payload, signature), claims))
+}
+
+/// Check a purge token's signature and expiry, returning its claims
+pub fn verify_purge_token(
+    config: &AdminConfig,
+    token: &str,
+    now: u64,
+) -> Result<PurgeTokenClaims, PurgeTokenError> {
+    let mut mac = purge_token_mac(config)?;
+
+    let (payload, signature) = token
+        .trim()
+        .split_once('.')
+        .ok_or(PurgeTokenError::Malformed)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| PurgeTokenError::Malformed)?;
+
+    // Signature first, so nothing is parsed from an unauthenticated payload
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| PurgeTokenError::InvalidSignature)?;
+
+    let claims: PurgeTokenClaims = URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or(PurgeTokenError::Malformed)?;
+
+    if now >= claims.expires_at {
+        return Err(PurgeTokenError::Expired);
+    }
+    Ok(claims)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{AdminLockoutConfig, PurgeMethodConfig};
 
     #[test]
     fn test_constant_time_compare() {
@@ -174,6 +664,7 @@ mod tests {
             auth_enabled: false,
             auth_token: None,
             allowed_ips: vec![],
+            ..Default::default()
         });
         assert!(!auth.is_enabled());
     }
@@ -184,6 +675,7 @@ mod tests {
             auth_enabled: true,
             auth_token: Some("secret123".to_string()),
             allowed_ips: vec![],
+            ..Default::default()
         });
 
         assert!(auth.verify_token("secret123"));
@@ -198,6 +690,7 @@ mod tests {
             auth_enabled: true,
             auth_token: Some("secret".to_string()),
             allowed_ips: vec!["127.0.0.1".to_string(), "192.168.1.1".to_string()],
+            ..Default::default()
         });
 
         assert!(auth.is_ip_allowed(&"127.0.0.1".parse().unwrap()));
@@ -211,10 +704,359 @@ mod tests {
             auth_enabled: true,
             auth_token: Some("secret".to_string()),
             allowed_ips: vec![],
+            ..Default::default()
         });
 
         // Empty allowlist means all IPs allowed
         assert!(auth.is_ip_allowed(&"127.0.0.1".parse().unwrap()));
         assert!(auth.is_ip_allowed(&"10.0.0.1".parse().unwrap()));
     }
+
+    #[test]
+    fn test_purge_method_allowlist() {
+        let mut config = AdminConfig {
+            auth_enabled: true,
+            auth_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        // Unlike the admin allowlist, an empty list lets nobody in without a token
+        assert!(!AdminAuth::new(config.clone()).is_purge_ip_allowed(&"10.0.0.1".parse().unwrap()));
+
+        config.purge_method.allowed_ips = vec!["10.0.0.1".to_string()];
+        let auth = AdminAuth::new(config);
+        assert!(auth.is_purge_ip_allowed(&"10.0.0.1".parse().unwrap()));
+        assert!(!auth.is_purge_ip_allowed(&"10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_purge_method_allowlist_cidrs() {
+        let auth = AdminAuth::new(AdminConfig {
+            auth_enabled: true,
+            auth_token: Some("secret".to_string()),
+            purge_method: PurgeMethodConfig {
+                enabled: true,
+                allowed_ips: vec!["10.0.0.0/8".to_string(), "192.168.1.10/32".to_string()],
+            },
+            ..Default::default()
+        });
+        let allowed = |ip: &str| auth.is_purge_ip_allowed(&ip.parse().unwrap());
+
+        assert!(allowed("10.0.0.1"));
+        assert!(allowed("10.255.3.4"));
+        // Textual prefixes of the network aren't in the range
+        assert!(!allowed("100.0.0.1"));
+        assert!(!allowed("11.0.0.1"));
+
+        assert!(allowed("192.168.1.10"));
+        assert!(!allowed("192.168.1.100"));
+        assert!(!allowed("192.168.1.11"));
+    }
+
+    fn lockout_auth(enabled: bool) -> AdminAuth {
+        AdminAuth::new(AdminConfig {
+            auth_enabled: true,
+            auth_token: Some("secret".to_string()),
+            allowed_ips: vec![],
+            lockout: AdminLockoutConfig {
+                enabled,
+                max_failures: 3,
+                lockout_secs: 300,
+                max_lockout_secs: 900,
+            },
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_lockout_after_max_failures() {
+        let auth = lockout_auth(true);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert!(auth.record_failure(ip).is_none());
+        assert!(auth.record_failure(ip).is_none());
+        assert!(auth.lockout_remaining(&ip).is_none());
+
+        assert_eq!(auth.record_failure(ip), Some(Duration::from_secs(300)));
+        assert!(auth.lockout_remaining(&ip).is_some());
+
+        // Other IPs are unaffected
+        assert!(
+            auth.lockout_remaining(&"10.0.0.2".parse().unwrap())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_lockout_doubles_up_to_max() {
+        let auth = lockout_auth(true);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let mut lockouts = Vec::new();
+        for _ in 0..3 {
+            for _ in 0..3 {
+                if let Some(lockout) = auth.record_failure(ip) {
+                    lockouts.push(lockout.as_secs());
+                }
+            }
+            // Let the lockout lapse
+            auth.failures.get_mut(&ip).unwrap().locked_until = Some(Instant::now());
+        }
+
+        assert_eq!(lockouts, vec![300, 600, 900]);
+    }
+
+    #[test]
+    fn test_success_clears_failures() {
+        let auth = lockout_auth(true);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        auth.record_failure(ip);
+        auth.record_failure(ip);
+        auth.record_success(&ip);
+
+        // The count starts over, so two more failures don't lock the IP out
+        assert!(auth.record_failure(ip).is_none());
+        assert!(auth.record_failure(ip).is_none());
+        assert!(auth.lockout_remaining(&ip).is_none());
+    }
+
+    #[test]
+    fn test_lockout_disabled() {
+        let auth = lockout_auth(false);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        for _ in 0..10 {
+            assert!(auth.record_failure(ip).is_none());
+        }
+        assert!(auth.lockout_remaining(&ip).is_none());
+    }
+
+    #[test]
+    fn test_cleanup_keeps_active_lockouts() {
+        let auth = lockout_auth(true);
+        let locked: IpAddr = "10.0.0.1".parse().unwrap();
+        let idle: IpAddr = "10.0.0.2".parse().unwrap();
+
+        for _ in 0..3 {
+            auth.record_failure(locked);
+        }
+        auth.record_failure(idle);
+
+        auth.cleanup(Duration::ZERO);
+        assert!(auth.failures.contains_key(&locked));
+        assert!(!auth.failures.contains_key(&idle));
+    }
+
+    #[test]
+    fn test_failure_records_are_capped() {
+        let mut auth = lockout_auth(true);
+        auth.max_failure_records = 3;
+        let ip = |last: u8| IpAddr::from([10, 0, 0, last]);
+
+        // A locked-out IP outlives newer records that aren't locked
+        for _ in 0..3 {
+            auth.record_failure(ip(1));
+        }
+        for last in 2..=5 {
+            auth.record_failure(ip(last));
+        }
+
+        assert_eq!(auth.failures.len(), 3);
+        assert!(auth.lockout_remaining(&ip(1)).is_some());
+        assert!(!auth.failures.contains_key(&ip(2)));
+        assert!(!auth.failures.contains_key(&ip(3)));
+        assert!(auth.failures.contains_key(&ip(5)));
+    }
+
+    #[test]
+    fn test_lockout_ignores_forged_forwarded_for() {
+        let auth = lockout_auth(true)
+            .with_trusted_proxies(TrustedProxies::new(&["10.0.0.2/32".to_string()]));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.9".parse().unwrap());
+        headers.insert("x-real-ip", "203.0.113.10".parse().unwrap());
+
+        // Untrusted peers are locked out as themselves, whatever they claim
+        let peer: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(auth.client_ip(&headers, peer), peer);
+
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(
+            auth.client_ip(&headers, proxy),
+            "203.0.113.9".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    fn purge_config() -> AdminConfig {
+        AdminConfig {
+            auth_enabled: true,
+            auth_token: Some("admin-secret".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn scoped(prefixes: &[&str], tags: &[&str]) -> PurgeTokenClaims {
+        PurgeTokenClaims {
+            prefixes: prefixes.iter().map(|s| s.to_string()).collect(),
+            tags: tags.iter().map(|s| s.to_string()).collect(),
+            purge_all: false,
+            expires_at: u64::MAX,
+        }
+    }
+
+    #[test]
+    fn test_purge_token_roundtrip() {
+        let config = purge_config();
+        let (token, claims) = mint_purge_token(
+            &config,
+            vec!["acme/".to_string()],
+            vec!["acme-assets".to_string()],
+            false,
+            Some(60),
+            1_000,
+        )
+        .unwrap();
+
+        assert_eq!(claims.expires_at, 1_060);
+        assert_eq!(
+            verify_purge_token(&config, &token, 1_000),
+            Ok(claims.clone())
+        );
+        assert_eq!(
+            AdminAuth::new(config).verify_purge_token(&token, 1_059),
+            Ok(claims)
+        );
+    }
+
+    #[test]
+    fn test_purge_token_rejections() {
+        let config = purge_config();
+        let (token, _) = mint_purge_token(
+            &config,
+            vec!["acme/".into()],
+            vec![],
+            false,
+            Some(60),
+            1_000,
+        )
+        .unwrap();
+
+        assert_eq!(
+            verify_purge_token(&config, &token, 1_060),
+            Err(PurgeTokenError::Expired)
+        );
+
+        // Widening the scope invalidates the signature
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged_claims = scoped(&[""], &[]);
+        let forged_payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged_claims).unwrap());
+        assert_eq!(
+            verify_purge_token(&config, &format!("{}.{}", forged_payload, signature), 1_000),
+            Err(PurgeTokenError::InvalidSignature)
+        );
+
+        // A rotated admin token invalidates outstanding purge tokens
+        let rotated = AdminConfig {
+            auth_token: Some("rotated-secret".to_string()),
+            ..purge_config()
+        };
+        assert_eq!(
+            verify_purge_token(&rotated, &token, 1_000),
+            Err(PurgeTokenError::InvalidSignature)
+        );
+
+        for malformed in ["", "no-dot", "abc.!!!"] {
+            assert_eq!(
+                verify_purge_token(&config, malformed, 1_000),
+                Err(PurgeTokenError::Malformed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_purge_token_minting_limits() {
+        let mut config = purge_config();
+
+        assert_eq!(
+            mint_purge_token(&config, vec![String::new()], vec![], false, None, 0).map(|(_, c)| c),
+            Err(PurgeTokenError::EmptyScope)
+        );
+
+        // Default TTL applies when none is given, and the max caps longer ones
+        let (_, claims) =
+            mint_purge_token(&config, vec!["a/".into()], vec![], false, None, 0).unwrap();
+        assert_eq!(claims.expires_at, config.purge_tokens.default_ttl_secs);
+        let (_, claims) =
+            mint_purge_token(&config, vec!["a/".into()], vec![], false, Some(u64::MAX), 0).unwrap();
+        assert_eq!(claims.expires_at, config.purge_tokens.max_ttl_secs);
+
+        config.purge_tokens.enabled = false;
+        assert_eq!(
+            mint_purge_token(&config, vec!["a/".into()], vec![], false, None, 0).map(|(_, c)| c),
+            Err(PurgeTokenError::Disabled)
+        );
+
+        let no_secret = AdminConfig {
+            auth_token: None,
+            ..purge_config()
+        };
+        assert_eq!(
+            verify_purge_token(&no_secret, "a.b", 0),
+            Err(PurgeTokenError::NoSecret)
+        );
+    }
+
+    #[test]
+    fn test_purge_scope_violations() {
+        let claims = scoped(&["acme.example/assets/"], &["acme"]);
+
+        assert!(claims.allows_key("acme.example/assets/logo.png"));
+        assert!(!claims.allows_key("acme.example/admin"));
+        assert!(claims.allows_prefix("acme.example/assets/img/"));
+        // A shorter prefix would reach keys outside the scope
+        assert!(!claims.allows_prefix("acme.example/"));
+        assert!(claims.allows_tag("acme"));
+        assert!(!claims.allows_tag("acme-private"));
+
+        let in_scope = claims.violations(
+            &["acme.example/assets/a.css".to_string()],
+            Some("acme.example/assets/js/"),
+            Some("acme"),
+            false,
+        );
+        assert!(in_scope.is_empty());
+
+        let keys = vec![
+            "acme.example/assets/a.css".to_string(),
+            "other.example/index.html".to_string(),
+        ];
+        let violations = claims.violations(&keys, Some("/"), Some("other"), true);
+        let rejected: Vec<(&str, &str)> = violations
+            .iter()
+            .map(|v| (v.kind.as_str(), v.item.as_str()))
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![
+                ("all", "*"),
+                ("tag", "other"),
+                ("prefix", "/"),
+                ("key", "other.example/index.html"),
+            ]
+        );
+
+        // A tag-only token can't purge by key or prefix
+        let tags_only = scoped(&[], &["acme"]);
+        assert_eq!(tags_only.violations(&keys, None, None, false).len(), 2);
+
+        // Purging everything takes a token minted for it
+        let purge_all = PurgeTokenClaims {
+            purge_all: true,
+            ..scoped(&[], &[])
+        };
+        assert!(purge_all.violations(&[], None, None, true).is_empty());
+        assert_eq!(purge_all.violations(&keys, None, None, false).len(), 2);
+        let (_, claims) = mint_purge_token(&purge_config(), vec![], vec![], true, None, 0).unwrap();
+        assert!(claims.purge_all);
+    }
 }
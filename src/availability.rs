@@ -0,0 +1,318 @@
+//! Per-origin availability accounting
+//!
+//! Every live fetch and health check outcome is counted in a fixed-width
+//! time bucket. Each origin keeps a ring of buckets covering the retention
+//! period, allocated on its first outcome, so memory is bounded no matter
+//! how much traffic it sees. A bucket is reused once its slot comes round
+//! again; the per-origin lock makes that rollover exact when outcomes race.
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::config::AvailabilityConfig;
+
+/// Windows reported for each origin, as (label, seconds)
+pub const AVAILABILITY_WINDOWS: [(&str, u64); 3] =
+    [("1h", 3600), ("24h", 24 * 3600), ("7d", 7 * 24 * 3600)];
+
+/// Outcomes in one time bucket
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    /// Bucket number since the epoch (`unix_secs / bucket_secs`)
+    index: u64,
+    successes: u32,
+    failures: u32,
+}
+
+/// One origin's buckets and current failure streak
+#[derive(Debug, Default)]
+struct OriginSeries {
+    /// Empty until the first outcome, then `bucket_count` long
+    buckets: Vec<Bucket>,
+    consecutive_failures: u64,
+    /// When the current failure streak began (Unix seconds)
+    failing_since: Option<u64>,
+}
+
+/// Successes and failures over one window
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AvailabilityWindow {
+    pub successes: u64,
+    pub failures: u64,
+    /// Share of outcomes that succeeded, or `None` with no outcomes
+    pub availability_percent: Option<f64>,
+}
+
+/// An origin's availability over each reported window
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OriginAvailability {
+    pub windows: BTreeMap<String, AvailabilityWindow>,
+    /// Failed outcomes in a row, up to the latest
+    pub consecutive_failures: u64,
+    /// When the current failure streak began (Unix seconds)
+    pub failing_since: Option<u64>,
+}
+
+/// Availability of every registered origin
+pub struct AvailabilityTracker {
+    config: AvailabilityConfig,
+    origins: DashMap<String, Mutex<OriginSeries>>,
+}
+
+impl AvailabilityTracker {
+    pub fn new(config: AvailabilityConfig) -> Self {
+        Self {
+            config,
+            origins: DashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Start tracking an origin; an origin already tracked keeps its history
+    pub fn register(&self, origin: &str) {
+        if self.config.enabled && !self.origins.contains_key(origin) {
+            self.origins
+                .entry(origin.to_string())
+                .or_insert_with(|| Mutex::new(OriginSeries::default()));
+        }
+    }
+
+    /// Stop tracking an origin and drop its history
+    pub fn remove(&self, origin: &str) {
+        self.origins.remove(origin);
+    }
+
+    /// Count an outcome for a registered origin; others are ignored
+    pub fn record(&self, origin: &str, success: bool) {
+        self.record_at(origin, success, unix_now());
+    }
+
+    /// Count an outcome at `now` (Unix seconds)
+    pub fn record_at(&self, origin: &str, success: bool, now: u64) {
+        let Some(series) = self.origins.get(origin) else {
+            return;
+        };
+        let mut series = series.lock().unwrap_or_else(|e| e.into_inner());
+
+        if success {
+            series.consecutive_failures = 0;
+            series.failing_since = None;
+        } else {
+            series.consecutive_failures += 1;
+            series.failing_since.get_or_insert(now);
+        }
+
+        if series.buckets.is_empty() {
+            series.buckets = vec![Bucket::default(); self.config.bucket_count()];
+        }
+        let index = now / self.config.bucket_secs;
+        let slot = (index % series.buckets.len() as u64) as usize;
+        let bucket = &mut series.buckets[slot];
+        if bucket.index < index {
+            *bucket = Bucket {
+                index,
+                ..Bucket::default()
+            };
+        } else if bucket.index > index {
+            // Measured before a racing outcome rolled the slot over; too old to keep
+            return;
+        }
+        if success {
+            bucket.successes = bucket.successes.saturating_add(1);
+        } else {
+            bucket.failures = bucket.failures.saturating_add(1);
+        }
+    }
+
+    /// An origin's availability now, or `None` if it isn't tracked
+    pub fn summary(&self, origin: &str) -> Option<OriginAvailability> {
+        self.summary_at(origin, unix_now())
+    }
+
+    /// An origin's availability at `now` (Unix seconds)
+    pub fn summary_at(&self, origin: &str, now: u64) -> Option<OriginAvailability> {
+        let series = self.origins.get(origin)?;
+        let series = series.lock().unwrap_or_else(|e| e.into_inner());
+        let current = now / self.config.bucket_secs;
+
+        let windows = AVAILABILITY_WINDOWS
+            .iter()
+            .map(|(label, secs)| {
+                // Buckets wholly or partly inside the window, the current one
+                // included; a slot not reused since has aged out of retention
+                let span = secs
+                    .div_ceil(self.config.bucket_secs)
+                    .min(self.config.bucket_count() as u64);
+                let oldest = current.saturating_sub(span - 1);
+                let (successes, failures) = series
+                    .buckets
+                    .iter()
+                    .filter(|bucket| (oldest..=current).contains(&bucket.index))
+                    .fold((0u64, 0u64), |(s, f), bucket| {
+                        (s + bucket.successes as u64, f + bucket.failures as u64)
+                    });
+                let total = successes + failures;
+                let window = AvailabilityWindow {
+                    successes,
+                    failures,
+                    availability_percent: (total > 0)
+                        .then(|| successes as f64 * 100.0 / total as f64),
+                };
+                (label.to_string(), window)
+            })
+            .collect();
+
+        Some(OriginAvailability {
+            windows,
+            consecutive_failures: series.consecutive_failures,
+            failing_since: series.failing_since,
+        })
+    }
+
+    /// Availability of every tracked origin
+    pub fn all_summaries(&self) -> BTreeMap<String, OriginAvailability> {
+        let now = unix_now();
+        let names: Vec<String> = self.origins.iter().map(|e| e.key().clone()).collect();
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let summary = self.summary_at(&name, now)?;
+                Some((name, summary))
+            })
+            .collect()
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn tracker(bucket_secs: u64, retention_hours: u64) -> AvailabilityTracker {
+        let tracker = AvailabilityTracker::new(AvailabilityConfig {
+            enabled: true,
+            bucket_secs,
+            retention_hours,
+        });
+        tracker.register("web");
+        tracker
+    }
+
+    #[test]
+    fn test_windows_and_streak() {
+        let tracker = tracker(60, 24 * 7);
+        let now = 1_700_000_000;
+
+        // Two days ago: all failures; an hour and a half ago: mixed; now: fine
+        for _ in 0..10 {
+            tracker.record_at("web", false, now - 2 * 24 * 3600);
+        }
+        tracker.record_at("web", true, now - 5400);
+        tracker.record_at("web", false, now - 5400);
+        for _ in 0..3 {
+            tracker.record_at("web", true, now - 30);
+        }
+        tracker.record_at("web", true, now);
+
+        let summary = tracker.summary_at("web", now).unwrap();
+        let window = |label: &str| summary.windows[label];
+        assert_eq!((window("1h").successes, window("1h").failures), (4, 0));
+        assert_eq!(window("1h").availability_percent, Some(100.0));
+        assert_eq!((window("24h").successes, window("24h").failures), (5, 1));
+        assert_eq!((window("7d").successes, window("7d").failures), (5, 11));
+        assert_eq!(window("7d").availability_percent, Some(500.0 / 16.0));
+        assert_eq!(summary.consecutive_failures, 0);
+
+        tracker.record_at("web", false, now + 1);
+        tracker.record_at("web", false, now + 2);
+        let summary = tracker.summary_at("web", now + 2).unwrap();
+        assert_eq!(summary.consecutive_failures, 2);
+        assert_eq!(summary.failing_since, Some(now + 1));
+
+        // Untracked origins have no summary and aren't recorded
+        tracker.record_at("other", true, now);
+        assert!(tracker.summary_at("other", now).is_none());
+    }
+
+    #[test]
+    fn test_buckets_roll_over_within_bounded_memory() {
+        // One hour of 10-minute buckets: six slots, reused as time passes
+        let tracker = tracker(600, 1);
+        let start = 1_700_000_400;
+        for minute in 0..6 * 60 {
+            tracker.record_at("web", minute % 2 == 0, start + minute * 60);
+        }
+        let now = start + (6 * 60 - 1) * 60;
+
+        let series = tracker.origins.get("web").unwrap();
+        assert_eq!(series.lock().unwrap().buckets.len(), 6);
+        drop(series);
+
+        // Only the last hour is left, even for the 7d window
+        let summary = tracker.summary_at("web", now).unwrap();
+        assert_eq!(summary.windows["1h"], summary.windows["7d"]);
+        assert_eq!(
+            summary.windows["1h"].successes + summary.windows["1h"].failures,
+            60
+        );
+
+        // An outcome measured before its slot rolled over is dropped
+        tracker.record_at("web", true, now - 3600);
+        assert_eq!(
+            tracker.summary_at("web", now).unwrap().windows,
+            summary.windows
+        );
+
+        // With no traffic for longer than the retention, nothing is counted
+        let idle = tracker.summary_at("web", now + 2 * 3600).unwrap();
+        assert_eq!(idle.windows["7d"].availability_percent, None);
+    }
+
+    #[test]
+    fn test_concurrent_records_across_rollover() {
+        let tracker = Arc::new(tracker(1, 1));
+        let start = 1_700_000_000;
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let tracker = tracker.clone();
+                std::thread::spawn(move || {
+                    for second in 0..100 {
+                        for _ in 0..10 {
+                            tracker.record_at("web", true, start + second);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let summary = tracker.summary_at("web", start + 99).unwrap();
+        assert_eq!(summary.windows["1h"].successes, 8 * 100 * 10);
+    }
+
+    #[test]
+    fn test_disabled_tracks_nothing() {
+        let tracker = AvailabilityTracker::new(AvailabilityConfig {
+            enabled: false,
+            ..AvailabilityConfig::default()
+        });
+        tracker.register("web");
+        tracker.record("web", true);
+        assert!(tracker.summary("web").is_none());
+        assert!(tracker.all_summaries().is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+//! Bandwidth accounting for Screaming Eagle CDN
+//!
+//! Counts response body bytes as frames are handed to the connection, so
+//! chunked and compressed responses (which carry no Content-Length) are
+//! measured accurately and aborted transfers only count what was sent.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Body as HttpBody, Frame, SizeHint};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::debug;
+
+use crate::cache::CacheStatus;
+use crate::metrics::Metrics;
+
+/// Response extension naming the origin and cache status a response was served from
+#[derive(Debug, Clone)]
+pub struct ServedFrom {
+    pub origin: String,
+    pub cache_status: CacheStatus,
+}
+
+/// How a response body finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyOutcome {
+    /// Data bytes handed to the connection
+    pub bytes_sent: u64,
+    /// Whether the body reached its end (false when the client went away)
+    pub completed: bool,
+    /// When the last frame was handed over, or the body was dropped unfinished
+    pub finished_at: Instant,
+}
+
+type OnFinish = Box<dyn FnOnce(BodyOutcome) + Send>;
+
+/// Body wrapper that counts data frames and reports the outcome once, when dropped
+pub struct CountingBody {
+    inner: Body,
+    bytes_sent: u64,
+    completed: bool,
+    finished_at: Option<Instant>,
+    on_finish: Option<OnFinish>,
+}
+
+impl CountingBody {
+    pub fn new(inner: Body, on_finish: impl FnOnce(BodyOutcome) + Send + 'static) -> Self {
+        Self {
+            inner,
+            bytes_sent: 0,
+            completed: false,
+            finished_at: None,
+            on_finish: Some(Box::new(on_finish)),
+        }
+    }
+}
+
+impl HttpBody for CountingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.bytes_sent += data.len() as u64;
+                }
+                // A body that knows it's done is never polled for its end
+                if self.inner.is_end_stream() {
+                    self.finished_at.get_or_insert_with(Instant::now);
+                }
+            }
+            Poll::Ready(None) => {
+                self.completed = true;
+                self.finished_at.get_or_insert_with(Instant::now);
+            }
+            _ => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CountingBody {
+    fn drop(&mut self) {
+        if let Some(on_finish) = self.on_finish.take() {
+            // Empty bodies (HEAD, 304) may never be polled by the server
+            on_finish(BodyOutcome {
+                bytes_sent: self.bytes_sent,
+                completed: self.completed || self.inner.is_end_stream(),
+                finished_at: self.finished_at.unwrap_or_else(Instant::now),
+            });
+        }
+    }
+}
+
+/// Middleware recording bytes actually sent for CDN responses
+///
+/// Must sit outside the compression layer so compressed sizes are counted.
+/// Responses without a [`ServedFrom`] extension (admin API, early errors)
+/// pass through untouched.
+pub async fn bytes_sent_middleware(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+
+    let Some(served) = response.extensions().get::<ServedFrom>().cloned() else {
+        return response;
+    };
+
+    response.map(|body| {
+        Body::new(CountingBody::new(body, move |outcome| {
+            metrics.record_bytes_served(&served.origin, served.cache_status, outcome.bytes_sent);
+            metrics.record_delivery(&served.origin, outcome.completed);
+            if !outcome.completed {
+                debug!(
+                    origin = %served.origin,
+                    bytes_sent = outcome.bytes_sent,
+                    "Client aborted response transfer"
+                );
+            }
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::poll_fn;
+    use std::sync::Mutex;
+
+    fn counting(body: Body) -> (CountingBody, Arc<Mutex<Option<BodyOutcome>>>) {
+        let outcome = Arc::new(Mutex::new(None));
+        let slot = outcome.clone();
+        let body = CountingBody::new(body, move |o| *slot.lock().unwrap() = Some(o));
+        (body, outcome)
+    }
+
+    /// Bytes sent and whether the body completed, once it has finished
+    fn sent(outcome: &Mutex<Option<BodyOutcome>>) -> Option<(u64, bool)> {
+        outcome.lock().unwrap().map(|o| (o.bytes_sent, o.completed))
+    }
+
+    fn chunked_body() -> Body {
+        let chunks: Vec<Result<Bytes, std::io::Error>> =
+            vec![Ok(Bytes::from_static(b"hello ")), Ok(Bytes::from_static(b"world"))];
+        Body::from_stream(futures::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn test_counts_full_body() {
+        let (body, outcome) = counting(chunked_body());
+        let bytes = axum::body::to_bytes(Body::new(body), usize::MAX)
+            .await
+            .unwrap();
+
+        assert_eq!(bytes.len(), 11);
+        assert_eq!(sent(&outcome), Some((11, true)));
+    }
+
+    #[tokio::test]
+    async fn test_aborted_body_counts_only_sent_frames() {
+        let (mut body, outcome) = counting(chunked_body());
+        poll_fn(|cx| Pin::new(&mut body).poll_frame(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        drop(body);
+
+        assert_eq!(sent(&outcome), Some((6, false)));
+    }
+
+    #[tokio::test]
+    async fn test_unpolled_empty_body_is_complete() {
+        let (body, outcome) = counting(Body::empty());
+        drop(body);
+
+        assert_eq!(sent(&outcome), Some((0, true)));
+    }
+
+    #[tokio::test]
+    async fn test_finish_is_stamped_at_the_last_frame() {
+        let (mut body, outcome) = counting(Body::from("whole"));
+        poll_fn(|cx| Pin::new(&mut body).poll_frame(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        let sent_by = Instant::now();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        drop(body);
+
+        let outcome = outcome.lock().unwrap().unwrap();
+        assert!(outcome.completed);
+        assert!(outcome.finished_at <= sent_by);
+    }
+}
@@ -1,24 +1,36 @@
 use bytes::Bytes;
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
 use tracing::{debug, info, warn};
 
-use crate::config::CacheConfig;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::accept;
+use crate::cache_key::{
+    CacheKey, KeyDimension, KeyPrefix, RANGE_SEGMENT, is_variant_of, key_hash, split_range,
+};
+use crate::config::{CacheConfig, CacheKeyConfig, ExpiryClock};
+use crate::encoding;
+use crate::freshness::freshness_lifetime;
+use crate::headers::{NotModifiedMismatch, ResponseHeaders};
+use crate::range::{ByteRange, RangeParseResult, parse_content_range, parse_range_header};
 
 #[derive(Debug, Clone)]
 pub struct CacheEntry {
     pub body: Bytes,
-    pub headers: HashMap<String, String>,
+    pub headers: ResponseHeaders,
     pub status_code: u16,
     pub content_type: Option<String>,
     pub etag: Option<String>,
     pub last_modified: Option<String>,
     pub created_at: Instant,
     pub expires_at: Instant,
+    /// Wall-clock expiry; unlike `expires_at` it keeps counting through a suspend
+    pub expires_at_wall: SystemTime,
     pub size: usize,
     /// stale-if-error window in seconds (RFC 5861)
     pub stale_if_error_secs: Option<u64>,
@@ -28,6 +40,42 @@ pub struct CacheEntry {
     pub last_accessed: Instant,
     /// Cache tags for tag-based invalidation
     pub cache_tags: Vec<String>,
+    /// Stored from an origin HEAD; only usable for HEAD requests
+    pub headers_only: bool,
+    /// How long the origin fetch that produced this entry took; set by
+    /// [`Cache::fill`] and used to time early refreshes
+    pub fetch_duration: Duration,
+    /// Sent with `Cache-Control: immutable`: never revalidated or served stale
+    pub immutable: bool,
+}
+
+/// Source of the current time, so tests can simulate suspends and clock jumps
+pub trait Clock: Send + Sync {
+    /// Monotonic time
+    fn now(&self) -> Instant;
+    /// Wall-clock time
+    fn wall_now(&self) -> SystemTime;
+}
+
+/// The system's monotonic and wall clocks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// One reading of both clocks
+#[derive(Debug, Clone, Copy)]
+struct Now {
+    instant: Instant,
+    wall: SystemTime,
 }
 
 impl CacheEntry {
@@ -45,6 +93,9 @@ impl CacheEntry {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CacheStats {
+    /// False when caching is disabled or sized to zero; everything else is then zero
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
     pub hits: u64,
     pub misses: u64,
     pub total_entries: usize,
@@ -53,10 +104,34 @@ pub struct CacheStats {
     pub hit_ratio: f64,
     pub evictions: u64,
     pub stale_hits: u64,
+    /// Stale hits served past the normal window because the origin was slow
+    pub adaptive_stale_hits: u64,
+    /// Fresh entries refreshed ahead of expiry
+    pub early_refreshes: u64,
     pub avg_entry_size_bytes: usize,
     pub hot_entries: usize, // Entries with access_count > threshold
     pub total_tags: usize,
     pub tagged_entries: usize,
+    /// Entries and bytes per normalized content type (see [`content_type_bucket`])
+    #[serde(default)]
+    pub by_content_type: BTreeMap<String, ContentTypeStats>,
+    /// Partial entries stored by range warming, included in the totals above
+    #[serde(default)]
+    pub range_entries: usize,
+    #[serde(default)]
+    pub range_size_bytes: usize,
+    /// Cached entries pinned against eviction, included in the totals above
+    #[serde(default)]
+    pub pinned_entries: usize,
+    #[serde(default)]
+    pub pinned_size_bytes: usize,
+    /// Keys purgeable by hash; zero when `cache.key_hash_index` is off
+    #[serde(default)]
+    pub key_hash_index_entries: usize,
+}
+
+fn default_enabled() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -66,6 +141,26 @@ pub struct TagStats {
     pub total_size_bytes: usize,
 }
 
+/// What one tick of [`Cache::sweep_expired`] looked at and removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SweepStats {
+    pub entries_examined: usize,
+    /// Entries past their TTL and stale window
+    pub entries_removed: usize,
+    pub tags_examined: usize,
+    /// Tag index references to keys no longer cached under the tag
+    pub tag_keys_removed: usize,
+    /// The tick reached the end of the keyspace, so the next starts over
+    pub cycle_complete: bool,
+}
+
+/// Entries and bytes cached under one normalized content type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentTypeStats {
+    pub entry_count: usize,
+    pub total_size_bytes: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HierarchyStats {
     pub enabled: bool,
@@ -81,11 +176,103 @@ pub struct HierarchyStats {
     pub l2_hit_ratio: f64,
 }
 
+/// Metadata for one cache entry, without its body (used for export/import)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheKeyRecord {
+    pub key: String,
+    pub origin: String,
+    /// Path including any query string
+    pub path: String,
+    #[serde(default)]
+    pub size: usize,
+    #[serde(default)]
+    pub ttl_remaining_secs: u64,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl CacheKeyRecord {
+    fn from_entry(key: &str, entry: &CacheEntry, ttl_remaining: Duration) -> Self {
+        let (origin, path) = match CacheKey::parse(key) {
+            Ok(parsed) => match parsed.query {
+                Some(query) => (parsed.origin, format!("{}?{}", parsed.path, query)),
+                None => (parsed.origin, parsed.path),
+            },
+            Err(_) => (key.to_string(), "/".to_string()),
+        };
+
+        Self {
+            key: key.to_string(),
+            origin,
+            path,
+            size: entry.size,
+            ttl_remaining_secs: ttl_remaining.as_secs(),
+            tags: entry.cache_tags.clone(),
+        }
+    }
+}
+
+/// Target number of entries per export partition
+const EXPORT_PARTITION_SIZE: usize = 50_000;
+
+/// A Range request answered from a cached partial entry
+#[derive(Debug, Clone)]
+pub struct CachedRange {
+    pub entry: CacheEntry,
+    /// The bytes of the object the entry holds
+    pub cached: ByteRange,
+    /// The bytes requested, within `cached`
+    pub requested: ByteRange,
+    /// Length of the whole object
+    pub total_length: u64,
+}
+
+impl CachedRange {
+    /// The requested bytes, cut from the cached ones
+    pub fn body(&self) -> Bytes {
+        let start = (self.requested.start - self.cached.start) as usize;
+        let end = (self.requested.end - self.cached.start) as usize;
+        let body = &self.entry.body;
+        body.slice(start..(end + 1).min(body.len()))
+    }
+
+    /// The entry's headers, describing the requested bytes
+    pub fn headers(&self) -> ResponseHeaders {
+        let mut headers = self.entry.headers.clone();
+        headers.insert(
+            "content-range",
+            self.requested.content_range_header(self.total_length),
+        );
+        headers.insert("content-length", self.requested.length().to_string());
+        headers
+    }
+}
+
+/// What [`Cache::pin`] did with each key
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PinOutcome {
+    pub pinned: Vec<String>,
+    /// Keys with nothing cached under them
+    pub not_cached: Vec<String>,
+    /// Keys left unpinned because they'd take the pinned bytes past the cap
+    pub over_limit: Vec<String>,
+}
+
+/// A pinned key and the size of what's cached under it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinnedKey {
+    pub key: String,
+    /// `None` while nothing is cached under the key (purged or expired)
+    pub size_bytes: Option<usize>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CacheStatus {
     Hit,
     Miss,
     Stale,
+    /// Past the stale window, served because the origin is slow
+    StaleAdaptive,
     StaleIfError,
     Bypass,
 }
@@ -96,6 +283,7 @@ impl CacheStatus {
             CacheStatus::Hit => "HIT",
             CacheStatus::Miss => "MISS",
             CacheStatus::Stale => "STALE",
+            CacheStatus::StaleAdaptive => "STALE-ADAPTIVE",
             CacheStatus::StaleIfError => "STALE-IF-ERROR",
             CacheStatus::Bypass => "BYPASS",
         }
@@ -105,14 +293,160 @@ impl CacheStatus {
 /// Threshold for considering an entry "hot" (frequently accessed)
 const HOT_ENTRY_THRESHOLD: u32 = 3;
 
+/// Number of striped locks serialising writes to the same key
+const WRITE_LOCK_STRIPES: usize = 64;
+
+/// Result of [`Cache::get_or_reserve`]
+// Short-lived and matched immediately, so the entry isn't boxed
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum CacheLookup {
+    /// A usable entry was cached
+    Found(CacheEntry, CacheStatus),
+    /// Nothing usable; fetch from the origin and [`Cache::fill`] the slot
+    Vacant(FillSlot),
+}
+
+/// Token for filling a key, stamped with when its origin fetch began
+///
+/// It holds no lock, so it can be carried across the fetch. Dropping it
+/// without filling leaves the cache untouched. While it's alive the fill is
+/// in flight and a purge of its key cancels it.
+#[derive(Debug)]
+pub struct FillSlot {
+    key: String,
+    requested_at: Instant,
+    requested_at_wall: SystemTime,
+    fill: Arc<InFlightFill>,
+}
+
+/// What purges arriving mid-fetch have said about a fill
+#[derive(Debug, Default)]
+struct FillTicket {
+    cancelled: AtomicBool,
+    /// Tags purged while in flight; an entry carrying one isn't stored
+    purged_tags: Mutex<Vec<String>>,
+}
+
+impl FillTicket {
+    fn blocks(&self, tags: &[String]) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+            || self
+                .purged_tags
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .any(|tag| tags.contains(tag))
+    }
+}
+
+/// In-flight fills by ID, with the key each was reserved for
+type FillRegistry = DashMap<u64, (String, Arc<FillTicket>)>;
+
+/// A registered fill, unregistered once every slot for it is gone
+#[derive(Debug)]
+struct InFlightFill {
+    id: u64,
+    ticket: Arc<FillTicket>,
+    registry: Arc<FillRegistry>,
+}
+
+impl Drop for InFlightFill {
+    fn drop(&mut self) {
+        self.registry.remove(&self.id);
+    }
+}
+
+impl FillSlot {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// When the fetch began; entries filled from it should be created at this time
+    pub fn requested_at(&self) -> Instant {
+        self.requested_at
+    }
+
+    /// Wall-clock counterpart of [`FillSlot::requested_at`]
+    pub fn requested_at_wall(&self) -> SystemTime {
+        self.requested_at_wall
+    }
+
+    /// A slot for another key from the same fetch, e.g. once the response's Vary is known
+    pub fn for_key(&self, key: String) -> FillSlot {
+        FillSlot {
+            key,
+            requested_at: self.requested_at,
+            requested_at_wall: self.requested_at_wall,
+            fill: self.fill.clone(),
+        }
+    }
+}
+
+/// What [`Cache::fill`] did with an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FillOutcome {
+    Stored,
+    /// A newer entry was already cached and kept
+    Superseded,
+    /// Too large to cache, stores are paused under memory pressure, or
+    /// the cache is disabled
+    Rejected,
+    /// A purge matched the fill while it was in flight
+    Cancelled,
+}
+
+/// What [`Cache::refresh`] did with an origin 304
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefreshOutcome {
+    /// The merged entry was handed to [`Cache::fill`]
+    Refreshed(FillOutcome),
+    /// Nothing is cached under the key any more
+    Missing,
+    /// The 304 describes another representation; fetch it in full
+    Mismatch(NotModifiedMismatch),
+}
+
+/// Storage tier holding an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tier {
+    L1,
+    L2,
+}
+
+/// An index the cleanup sweep walks, shard by shard
+#[derive(Debug, Clone, Copy)]
+enum SweepTarget {
+    Entries(Tier),
+    Tags,
+}
+
+/// Visit the entries of one shard of `map` under its read lock
+///
+/// DashMap's safe iterators always cover the whole map, and the sweep needs
+/// to stop and resume between shards.
+fn for_each_in_shard<V>(map: &DashMap<String, V>, shard: usize, mut f: impl FnMut(&str, &V)) {
+    let guard = map.shards()[shard].read();
+    // SAFETY: the read guard is held for the whole walk, so no bucket is
+    // moved or freed while it's being read
+    unsafe {
+        for bucket in guard.iter() {
+            let (key, value) = bucket.as_ref();
+            f(key, value.get());
+        }
+    }
+}
+
 pub struct Cache {
     /// L1 cache (hot tier) - frequently accessed entries
     l1_cache: Arc<DashMap<String, CacheEntry>>,
-    /// L2 cache (cold tier) - less frequently accessed entries
+    /// L2 cache (cold tier) - less frequently accessed entries; the only
+    /// tier when the hierarchy is disabled
     l2_cache: Arc<DashMap<String, CacheEntry>>,
-    /// Legacy entries field for backwards compatibility (now unused)
-    entries: DashMap<String, CacheEntry>,
     config: CacheConfig,
+    /// Off when caching is disabled or sized to zero: lookups miss and
+    /// stores are dropped without touching the tiers
+    enabled: bool,
     l1_current_size: AtomicUsize,
     l2_current_size: AtomicUsize,
     current_size: AtomicUsize,
@@ -120,12 +454,36 @@ pub struct Cache {
     misses: AtomicU64,
     evictions: AtomicU64,
     stale_hits: AtomicU64,
+    adaptive_stale_hits: AtomicU64,
+    early_refreshes: AtomicU64,
     l1_hits: AtomicU64,
     l2_hits: AtomicU64,
     promotions: AtomicU64,
     demotions: AtomicU64,
     /// Tag to cache keys mapping for tag-based invalidation
     tag_to_keys: Arc<DashMap<String, HashSet<String>>>,
+    /// Entry count and bytes per content type, kept in step with the tiers
+    content_types: DashMap<String, ContentTypeStats>,
+    /// Time source for expiry checks
+    clock: Arc<dyn Clock>,
+    /// Held while writing a key so `fill` can compare and replace atomically
+    write_locks: Box<[Mutex<()>]>,
+    /// Fills whose origin fetch is in flight, so purges can cancel them
+    fills: Arc<FillRegistry>,
+    next_fill_id: AtomicU64,
+    /// Set by the memory watchdog to refuse new entries under critical pressure
+    stores_paused: AtomicBool,
+    /// Keys of partial entries by the key of the object they're part of
+    range_keys: DashMap<String, HashSet<String>>,
+    /// Stored keys by [`key_hash`], when `cache.key_hash_index` is on
+    key_hashes: Option<DashMap<String, String>>,
+    /// Keys whose entries eviction, shedding and L1 demotion pass over
+    ///
+    /// Never locked while a tier's shard lock is held; take a copy first.
+    pinned: Mutex<HashSet<String>>,
+    /// Shard the next cleanup sweep tick starts at, counted across the
+    /// tiers and then the tag index; held for the tick
+    sweep_cursor: Mutex<usize>,
 }
 
 impl Cache {
@@ -138,7 +496,9 @@ impl Cache {
         let l1_percent = config.hierarchy.l1_size_percent;
         let l2_percent = config.hierarchy.l2_size_percent;
 
-        let total_capacity = 10000;
+        // Nothing is ever stored when disabled, so don't preallocate
+        let enabled = config.is_enabled();
+        let total_capacity = if enabled { 10000 } else { 0 };
         let l1_capacity = if hierarchy_enabled {
             (total_capacity * l1_percent) / 100
         } else {
@@ -150,18 +510,28 @@ impl Cache {
             total_capacity
         };
 
+        let (l1_min, l2_min, tags_min) = if enabled {
+            (100, 1000, 1000)
+        } else {
+            (0, 0, 0)
+        };
         let l1_cache = Arc::new(DashMap::with_capacity_and_shard_amount(
-            l1_capacity.max(100),
+            l1_capacity.max(l1_min),
             shard_count,
         ));
         let l2_cache = Arc::new(DashMap::with_capacity_and_shard_amount(
-            l2_capacity.max(1000),
+            l2_capacity.max(l2_min),
+            shard_count,
+        ));
+        let tag_to_keys = Arc::new(DashMap::with_capacity_and_shard_amount(
+            tags_min,
             shard_count,
         ));
-        let entries = DashMap::with_capacity_and_shard_amount(10000, shard_count);
-        let tag_to_keys = Arc::new(DashMap::with_capacity_and_shard_amount(1000, shard_count));
+        let key_hashes = (enabled && config.key_hash_index.enabled).then(DashMap::new);
 
-        if hierarchy_enabled {
+        if !enabled {
+            info!("Cache disabled; every request goes to the origin");
+        } else if hierarchy_enabled {
             info!(
                 shards = shard_count,
                 l1_percent = l1_percent,
@@ -179,8 +549,8 @@ impl Cache {
         Self {
             l1_cache,
             l2_cache,
-            entries,
             config,
+            enabled,
             l1_current_size: AtomicUsize::new(0),
             l2_current_size: AtomicUsize::new(0),
             current_size: AtomicUsize::new(0),
@@ -188,22 +558,125 @@ impl Cache {
             misses: AtomicU64::new(0),
             evictions: AtomicU64::new(0),
             stale_hits: AtomicU64::new(0),
+            adaptive_stale_hits: AtomicU64::new(0),
+            early_refreshes: AtomicU64::new(0),
             l1_hits: AtomicU64::new(0),
             l2_hits: AtomicU64::new(0),
             promotions: AtomicU64::new(0),
             demotions: AtomicU64::new(0),
             tag_to_keys,
+            content_types: DashMap::new(),
+            clock: Arc::new(SystemClock),
+            write_locks: (0..WRITE_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+            fills: Arc::new(DashMap::new()),
+            next_fill_id: AtomicU64::new(0),
+            stores_paused: AtomicBool::new(false),
+            range_keys: DashMap::new(),
+            key_hashes,
+            pinned: Mutex::new(HashSet::new()),
+            sweep_cursor: Mutex::new(0),
+        }
+    }
+
+    /// Measure entry expiry against `clock` instead of the system clocks
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Whether entries can be cached; see [`CacheConfig::is_enabled`]
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The clock new entries' `created_at`/`expires_at` should be stamped from
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    fn now(&self) -> Now {
+        Now {
+            instant: self.clock.now(),
+            wall: self.clock.wall_now(),
+        }
+    }
+
+    /// How far past its TTL an entry is by the configured clock(s); `None` while fresh
+    fn overdue(&self, entry: &CacheEntry, now: Now) -> Option<Duration> {
+        let monotonic = now.instant.checked_duration_since(entry.expires_at);
+        let wall = now.wall.duration_since(entry.expires_at_wall).ok();
+        match self.config.expiry_clock {
+            ExpiryClock::Monotonic => monotonic,
+            ExpiryClock::Wall => wall,
+            ExpiryClock::Either => monotonic.max(wall),
+        }
+    }
+
+    /// Whether an entry is fresh or less than `window` past its TTL
+    fn within(&self, entry: &CacheEntry, now: Now, window: Duration) -> bool {
+        self.overdue(entry, now).is_none_or(|late| late < window)
+    }
+
+    /// Time left before an entry expires by the configured clock(s)
+    fn ttl_remaining(&self, entry: &CacheEntry, now: Now) -> Duration {
+        let monotonic = entry.expires_at.saturating_duration_since(now.instant);
+        let wall = entry
+            .expires_at_wall
+            .duration_since(now.wall)
+            .unwrap_or_default();
+        match self.config.expiry_clock {
+            ExpiryClock::Monotonic => monotonic,
+            ExpiryClock::Wall => wall,
+            ExpiryClock::Either => monotonic.min(wall),
+        }
+    }
+
+    /// Whole seconds of freshness an entry has left, negative once it's past its TTL
+    pub fn freshness_secs(&self, entry: &CacheEntry) -> i64 {
+        let now = self.now();
+        match self.overdue(entry, now) {
+            Some(late) => -(late.as_secs_f64().ceil() as i64),
+            None => self.ttl_remaining(entry, now).as_secs() as i64,
         }
     }
 
+    /// Look up an entry with a body
+    ///
+    /// Headers-only entries are treated as a miss so a GET replaces them.
     pub fn get(&self, key: &str) -> Option<(CacheEntry, CacheStatus)> {
-        let now = Instant::now();
+        self.lookup(key, false, Duration::ZERO)
+    }
+
+    /// Look up an entry to answer a HEAD request, accepting headers-only entries
+    pub fn get_head(&self, key: &str) -> Option<(CacheEntry, CacheStatus)> {
+        self.lookup(key, true, Duration::ZERO)
+    }
+
+    /// Look up an entry, optionally extending the stale window
+    ///
+    /// Entries up to `extra_stale` past the stale-while-revalidate window are
+    /// returned as [`CacheStatus::StaleAdaptive`]; used while the origin is slow.
+    pub fn lookup(
+        &self,
+        key: &str,
+        allow_headers_only: bool,
+        extra_stale: Duration,
+    ) -> Option<(CacheEntry, CacheStatus)> {
+        // Requests bypass a disabled cache, so its lookups aren't counted
+        if !self.enabled {
+            return None;
+        }
+
+        let now = self.now();
+        let usable = |entry: &CacheEntry| allow_headers_only || !entry.headers_only;
 
-        // If hierarchy is enabled, check L1 then L2
+        // If hierarchy is enabled, check L1 before L2
         if self.config.hierarchy.enabled {
             // Check L1 cache first
-            if let Some(mut entry) = self.l1_cache.get_mut(key) {
-                if now < entry.expires_at {
+            if let Some(mut entry) = self.l1_cache.get_mut(key)
+                && usable(&entry)
+            {
+                if self.overdue(&entry, now).is_none() {
                     entry.record_access();
                     self.hits.fetch_add(1, Ordering::Relaxed);
                     self.l1_hits.fetch_add(1, Ordering::Relaxed);
@@ -211,77 +684,68 @@ impl Cache {
                     return Some((entry.clone(), CacheStatus::Hit));
                 }
 
-                // Check stale-while-revalidate window
+                // Check stale-while-revalidate window (plus any adaptive extension)
                 let stale_window = Duration::from_secs(self.config.stale_while_revalidate_secs);
-                if now < entry.expires_at + stale_window {
+                if !entry.immutable && self.within(&entry, now, stale_window + extra_stale) {
+                    let status = self.stale_status(&entry, now, stale_window);
                     entry.record_access();
                     self.hits.fetch_add(1, Ordering::Relaxed);
                     self.stale_hits.fetch_add(1, Ordering::Relaxed);
                     self.l1_hits.fetch_add(1, Ordering::Relaxed);
-                    debug!(key = %key, tier = "L1", "Cache STALE (within revalidation window)");
-                    return Some((entry.clone(), CacheStatus::Stale));
+                    debug!(key = %key, tier = "L1", status = status.as_str(), "Cache STALE");
+                    return Some((entry.clone(), status));
                 }
             }
+        }
 
-            // Check L2 cache
-            if let Some(mut entry) = self.l2_cache.get_mut(key) {
-                if now < entry.expires_at {
-                    entry.record_access();
-                    self.hits.fetch_add(1, Ordering::Relaxed);
-                    self.l2_hits.fetch_add(1, Ordering::Relaxed);
+        // Check L2 cache (the single tier when the hierarchy is disabled)
+        if let Some(mut entry) = self.l2_cache.get_mut(key)
+            && usable(&entry)
+        {
+            let hierarchy = self.config.hierarchy.enabled;
 
-                    let should_promote =
-                        entry.access_count() >= self.config.hierarchy.promotion_threshold;
-                    let entry_clone = entry.clone();
+            if self.overdue(&entry, now).is_none() {
+                entry.record_access();
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                if hierarchy {
+                    self.l2_hits.fetch_add(1, Ordering::Relaxed);
+                }
 
-                    // Drop the mutable reference before promotion
-                    drop(entry);
+                let should_promote =
+                    hierarchy && entry.access_count() >= self.config.hierarchy.promotion_threshold;
+                let entry_clone = entry.clone();
 
-                    if should_promote {
-                        // Promote to L1
-                        self.promote_to_l1(key, entry_clone.clone());
-                    }
+                // Drop the mutable reference before promotion
+                drop(entry);
 
-                    debug!(
-                        key = %key,
-                        tier = "L2",
-                        access_count = entry_clone.access_count(),
-                        promoted = should_promote,
-                        "Cache HIT"
-                    );
-                    return Some((entry_clone, CacheStatus::Hit));
+                if should_promote {
+                    // Promote to L1
+                    self.promote_to_l1(key, entry_clone.clone());
                 }
 
-                // Check stale-while-revalidate window
-                let stale_window = Duration::from_secs(self.config.stale_while_revalidate_secs);
-                if now < entry.expires_at + stale_window {
-                    entry.record_access();
-                    self.hits.fetch_add(1, Ordering::Relaxed);
-                    self.stale_hits.fetch_add(1, Ordering::Relaxed);
-                    self.l2_hits.fetch_add(1, Ordering::Relaxed);
-                    debug!(key = %key, tier = "L2", "Cache STALE (within revalidation window)");
-                    return Some((entry.clone(), CacheStatus::Stale));
-                }
+                debug!(
+                    key = %key,
+                    tier = "L2",
+                    access_count = entry_clone.access_count(),
+                    promoted = should_promote,
+                    "Cache HIT"
+                );
+                return Some((entry_clone, CacheStatus::Hit));
             }
-        } else {
-            // Legacy single-tier cache lookup (hierarchy disabled)
-            if let Some(mut entry) = self.entries.get_mut(key) {
-                if now < entry.expires_at {
-                    entry.record_access();
-                    self.hits.fetch_add(1, Ordering::Relaxed);
-                    debug!(key = %key, access_count = entry.access_count(), "Cache HIT");
-                    return Some((entry.clone(), CacheStatus::Hit));
-                }
 
-                // Check stale-while-revalidate window
-                let stale_window = Duration::from_secs(self.config.stale_while_revalidate_secs);
-                if now < entry.expires_at + stale_window {
-                    entry.record_access();
-                    self.hits.fetch_add(1, Ordering::Relaxed);
-                    self.stale_hits.fetch_add(1, Ordering::Relaxed);
-                    debug!(key = %key, "Cache STALE (within revalidation window)");
-                    return Some((entry.clone(), CacheStatus::Stale));
+            // Check stale-while-revalidate window (plus any adaptive extension); an
+            // immutable entry is only ever served fresh
+            let stale_window = Duration::from_secs(self.config.stale_while_revalidate_secs);
+            if !entry.immutable && self.within(&entry, now, stale_window + extra_stale) {
+                let status = self.stale_status(&entry, now, stale_window);
+                entry.record_access();
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                self.stale_hits.fetch_add(1, Ordering::Relaxed);
+                if hierarchy {
+                    self.l2_hits.fetch_add(1, Ordering::Relaxed);
                 }
+                debug!(key = %key, tier = "L2", status = status.as_str(), "Cache STALE");
+                return Some((entry.clone(), status));
             }
         }
 
@@ -290,17 +754,85 @@ impl Cache {
         None
     }
 
+    /// Object size from a fresh headers-only entry, without counting a hit or miss
+    ///
+    /// Lets a Range miss learn the object's size from an earlier origin HEAD.
+    pub fn headers_only_length(&self, key: &str) -> Option<u64> {
+        let now = self.now();
+        self.tiers().find_map(|(_, map)| {
+            let entry = map.get(key)?;
+            if !entry.headers_only || self.overdue(&entry, now).is_some() {
+                return None;
+            }
+            entry.headers.get("content-length")?.parse().ok()
+        })
+    }
+
+    /// A fresh partial entry of `key` holding every byte a Range header asks for
+    ///
+    /// Partial entries are stored by range warming under [`range_key`]. Only
+    /// a hit is counted; with no covering entry the caller falls back to the
+    /// full object, which counts its own hit or miss.
+    pub fn get_range(&self, key: &str, range_header: &str) -> Option<CachedRange> {
+        let range_keys: Vec<String> = self.range_keys.get(key)?.iter().cloned().collect();
+        let now = self.now();
+
+        let (range_key, cached, requested, total_length) =
+            range_keys.into_iter().find_map(|range_key| {
+                let (cached, total_length) = self.tiers().find_map(|(_, map)| {
+                    let entry = map.get(&range_key)?;
+                    if self.overdue(&entry, now).is_some() {
+                        return None;
+                    }
+                    parse_content_range(entry.headers.get("content-range")?)
+                })?;
+                let RangeParseResult::Single(requested) =
+                    parse_range_header(range_header, total_length)
+                else {
+                    return None;
+                };
+                cached
+                    .contains(&requested)
+                    .then_some((range_key, cached, requested, total_length))
+            })?;
+
+        match self.lookup(&range_key, false, Duration::ZERO)? {
+            (entry, CacheStatus::Hit) => Some(CachedRange {
+                entry,
+                cached,
+                requested,
+                total_length,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Stale, or adaptively stale once past the stale-while-revalidate window
+    fn stale_status(&self, entry: &CacheEntry, now: Now, stale_window: Duration) -> CacheStatus {
+        if self.within(entry, now, stale_window) {
+            CacheStatus::Stale
+        } else {
+            self.adaptive_stale_hits.fetch_add(1, Ordering::Relaxed);
+            CacheStatus::StaleAdaptive
+        }
+    }
+
     /// Get a stale entry for stale-if-error handling (RFC 5861)
     /// Returns the entry if it's within the stale-if-error window
     pub fn get_stale_for_error(&self, key: &str) -> Option<CacheEntry> {
-        let now = Instant::now();
+        let now = self.now();
 
         // Helper function to check stale windows
         let check_stale = |entry: &CacheEntry| -> Option<CacheEntry> {
+            // A headers-only entry has nothing to serve in place of a body
+            if entry.headers_only {
+                return None;
+            }
+
             // Check if within stale-if-error window
             if let Some(stale_if_error_secs) = entry.stale_if_error_secs {
                 let stale_if_error_window = Duration::from_secs(stale_if_error_secs);
-                if now < entry.expires_at + stale_if_error_window {
+                if self.within(entry, now, stale_if_error_window) {
                     debug!(key = %key, "Cache STALE-IF-ERROR (within error window)");
                     return Some(entry.clone());
                 }
@@ -308,7 +840,7 @@ impl Cache {
 
             // Also check the configured stale_while_revalidate as fallback for errors
             let stale_window = Duration::from_secs(self.config.stale_while_revalidate_secs);
-            if now < entry.expires_at + stale_window {
+            if self.within(entry, now, stale_window) {
                 debug!(key = %key, "Cache STALE-IF-ERROR (within revalidate window)");
                 return Some(entry.clone());
             }
@@ -316,29 +848,311 @@ impl Cache {
             None
         };
 
-        if self.config.hierarchy.enabled {
-            // Check L1 first, then L2
-            if let Some(entry) = self.l1_cache.get(key)
-                && let Some(result) = check_stale(&entry) {
-                    return Some(result);
-                }
+        self.tiers()
+            .find_map(|(_, map)| map.get(key).and_then(|entry| check_stale(&entry)))
+    }
+
+    /// Look up an entry like [`Cache::lookup`], reserving a fill slot on a miss
+    pub fn get_or_reserve(
+        &self,
+        key: &str,
+        allow_headers_only: bool,
+        extra_stale: Duration,
+    ) -> CacheLookup {
+        match self.lookup(key, allow_headers_only, extra_stale) {
+            Some((entry, status)) => CacheLookup::Found(entry, status),
+            None => CacheLookup::Vacant(self.reserve(key)),
+        }
+    }
+
+    /// A fill slot for a key about to be fetched, e.g. to revalidate a stale entry
+    pub fn reserve(&self, key: &str) -> FillSlot {
+        let id = self.next_fill_id.fetch_add(1, Ordering::Relaxed);
+        let ticket = Arc::new(FillTicket::default());
+        self.fills.insert(id, (key.to_string(), ticket.clone()));
+        FillSlot {
+            key: key.to_string(),
+            requested_at: self.clock.now(),
+            requested_at_wall: self.clock.wall_now(),
+            fill: Arc::new(InFlightFill {
+                id,
+                ticket,
+                registry: self.fills.clone(),
+            }),
+        }
+    }
+
+    /// A fill slot to refresh a fresh entry ahead of its expiry (XFetch)
+    ///
+    /// Fires when `fetch_duration * beta * -ln(rand)` reaches the entry's
+    /// remaining TTL, so it becomes likely only in roughly the last fetch
+    /// duration before expiry. Returns `None` when early refresh is disabled,
+    /// the draw doesn't fire, or a fill for the key is already in flight.
+    pub fn reserve_early_refresh(&self, key: &str, entry: &CacheEntry) -> Option<FillSlot> {
+        self.reserve_early_refresh_with(key, entry, rand::random::<f64>())
+    }
+
+    /// [`Cache::reserve_early_refresh`] with the uniform draw in `[0, 1)` supplied
+    pub fn reserve_early_refresh_with(
+        &self,
+        key: &str,
+        entry: &CacheEntry,
+        draw: f64,
+    ) -> Option<FillSlot> {
+        let config = &self.config.early_refresh;
+        // An immutable entry can't have changed, so there's nothing to refresh
+        if !config.enabled || entry.headers_only || entry.immutable {
+            return None;
+        }
+
+        // 1 - draw is in (0, 1], so the gap is finite and never negative
+        let gap = entry.fetch_duration.as_secs_f64() * config.beta * -(1.0 - draw).ln();
+        let remaining = self.ttl_remaining(entry, self.now());
+        if gap < remaining.as_secs_f64() {
+            return None;
+        }
+
+        let slot = self.reserve_unless_filling(key)?;
+        self.early_refreshes.fetch_add(1, Ordering::Relaxed);
+        debug!(key = %key, remaining_ms = remaining.as_millis() as u64, "Early refresh");
+        Some(slot)
+    }
 
-            if let Some(entry) = self.l2_cache.get(key) {
-                return check_stale(&entry);
+    /// A fill slot for `key`, unless a fill for it is already in flight
+    ///
+    /// Background refreshes use it so a run of stale hits on one key starts
+    /// a single revalidation.
+    pub fn reserve_unless_filling(&self, key: &str) -> Option<FillSlot> {
+        if self.fills.iter().any(|fill| fill.value().0 == key) {
+            return None;
+        }
+        Some(self.reserve(key))
+    }
+
+    /// Cancel in-flight fills reserved for matching keys
+    ///
+    /// Returns how many distinct keys had a fill cancelled. Call before
+    /// invalidating the same keys: a fill that stores in between is removed
+    /// by the invalidation, and one that stores after it sees the cancel.
+    pub fn cancel_fills(&self, matches: impl Fn(&str) -> bool) -> usize {
+        let mut keys = HashSet::new();
+        for fill in self.fills.iter() {
+            let (key, ticket) = fill.value();
+            if matches(key) {
+                ticket.cancelled.store(true, Ordering::SeqCst);
+                keys.insert(key.clone());
             }
+        }
+        keys.len()
+    }
+
+    /// Stop in-flight fills from storing an entry tagged `tag`
+    ///
+    /// A fill's tags aren't known until its response arrives, so these
+    /// aren't counted. Call before [`Cache::invalidate_by_tag`].
+    pub fn cancel_tagged_fills(&self, tag: &str) {
+        for fill in self.fills.iter() {
+            fill.value()
+                .1
+                .purged_tags
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(tag.to_string());
+        }
+    }
+
+    /// Store an entry fetched for `slot`, unless a newer one got there first
+    ///
+    /// The compare and replace happen under the key's write lock, so of two
+    /// racing fills the entry with the later `created_at` is the one kept.
+    /// A full entry always wins over a headers-only one, and entries past the
+    /// stale window never block a fill. The entry's `cache_tags` are indexed
+    /// with it, replacing the previous entry's tags.
+    pub fn fill(&self, slot: FillSlot, mut entry: CacheEntry) -> FillOutcome {
+        if self.config.tags.enabled {
+            entry
+                .cache_tags
+                .truncate(self.config.tags.max_tags_per_entry);
+        } else {
+            entry.cache_tags.clear();
+        }
+
+        let ticket = slot.fill.ticket.clone();
+        if ticket.blocks(&entry.cache_tags) {
+            debug!(key = %slot.key, "Fill cancelled by purge");
+            return FillOutcome::Cancelled;
+        }
+
+        let _guard = self.write_lock(&slot.key);
+        let now = self.now();
+        entry.fetch_duration = now.instant.saturating_duration_since(slot.requested_at);
+        let stale_window = Duration::from_secs(self.config.stale_while_revalidate_secs);
+        let superseded = self.tiers().any(|(_, map)| {
+            map.get(&slot.key)
+                .is_some_and(|existing| self.supersedes(&existing, &entry, now, stale_window))
+        });
+        if superseded {
+            debug!(key = %slot.key, "Newer entry already cached, discarding fill");
+            return FillOutcome::Superseded;
+        }
+
+        let tags = entry.cache_tags.clone();
+        if !self.store(slot.key.clone(), entry) {
+            return FillOutcome::Rejected;
+        }
+
+        // A purge that cancelled us mid-store may have missed the entry
+        if ticket.blocks(&tags) {
+            self.invalidate_internal(&slot.key, false);
+            debug!(key = %slot.key, "Fill cancelled by purge");
+            return FillOutcome::Cancelled;
+        }
+        FillOutcome::Stored
+    }
+
+    /// Apply an origin 304 to the entry revalidated for `slot` (RFC 9111 Section 4.3.4)
+    ///
+    /// Only the variant stored under the slot's key is touched. Its headers are
+    /// merged with the 304's, and its TTL is recomputed from the merged headers
+    /// and dated from when the revalidation began. The body is never changed.
+    pub fn refresh(&self, slot: FillSlot, not_modified: &ResponseHeaders) -> RefreshOutcome {
+        let Some(mut entry) = self
+            .tiers()
+            .find_map(|(_, map)| map.get(&slot.key).map(|entry| entry.clone()))
+        else {
+            return RefreshOutcome::Missing;
+        };
+
+        if let Err(mismatch) = entry.headers.merge_not_modified(not_modified) {
+            debug!(key = %slot.key, ?mismatch, "304 doesn't match the cached variant");
+            return RefreshOutcome::Mismatch(mismatch);
+        }
+
+        let directives = entry
+            .headers
+            .get("cache-control")
+            .map(|cc| parse_cache_control(cc))
+            .unwrap_or_default();
+        let ttl = self.entry_ttl(
+            &directives,
+            &entry.headers,
+            slot.requested_at_wall,
+            entry.headers_only,
+        );
+        entry.created_at = slot.requested_at;
+        entry.expires_at = slot.requested_at + ttl;
+        entry.expires_at_wall = slot.requested_at_wall + ttl;
+        entry.stale_if_error_secs = directives.stale_if_error;
+        entry.immutable = directives.immutable;
+        // A generated ETag is kept unless the origin now sends its own
+        if let Some(etag) = entry.headers.get("etag") {
+            entry.etag = Some(etag.clone());
+        }
+        if let Some(last_modified) = entry.headers.get("last-modified") {
+            entry.last_modified = Some(last_modified.clone());
+        }
+
+        debug!(key = %slot.key, ttl_secs = ttl.as_secs(), "Refreshed cached entry from 304");
+        RefreshOutcome::Refreshed(self.fill(slot, entry))
+    }
+
+    /// TTL of an entry with these headers and Cache-Control directives
+    ///
+    /// The response's freshness lifetime (see [`freshness_lifetime`]), or the
+    /// default TTL if it has none, capped at the maximum TTL. Immutable
+    /// responses are capped at `immutable_max_ttl_secs` instead. Headers-only
+    /// entries are also capped at `head.ttl_secs`.
+    pub fn entry_ttl(
+        &self,
+        directives: &CacheControlDirectives,
+        headers: &ResponseHeaders,
+        received_at: SystemTime,
+        headers_only: bool,
+    ) -> Duration {
+        let max_ttl = if directives.immutable {
+            self.config.immutable_max_ttl()
+        } else {
+            self.config.max_ttl()
+        };
+        let ttl = self
+            .origin_ttl(headers)
+            .or_else(|| {
+                freshness_lifetime(
+                    directives,
+                    headers,
+                    received_at,
+                    self.config.heuristic_freshness,
+                )
+                .map(|(lifetime, _)| lifetime)
+            })
+            .unwrap_or(self.config.default_ttl())
+            .min(max_ttl);
+        if headers_only {
+            ttl.min(Duration::from_secs(self.config.head.ttl_secs))
         } else {
-            // Legacy single-tier lookup
-            if let Some(entry) = self.entries.get(key) {
-                return check_stale(&entry);
+            ttl
+        }
+    }
+
+    /// TTL the origin set for the CDN alone, in `cache.origin_ttl_header`
+    fn origin_ttl(&self, headers: &ResponseHeaders) -> Option<Duration> {
+        let name = self.config.origin_ttl_header.as_deref()?;
+        let value = headers.get(name)?;
+        match value.trim().parse::<u64>() {
+            Ok(secs) => {
+                debug!(header = %name, ttl_secs = secs, "Origin set the CDN TTL");
+                Some(Duration::from_secs(secs))
+            }
+            Err(_) => {
+                debug!(header = %name, value = %value, "Ignoring unparseable origin TTL header");
+                None
             }
         }
+    }
 
-        None
+    /// Whether a cached entry should be kept over an incoming one for the same key
+    fn supersedes(
+        &self,
+        existing: &CacheEntry,
+        incoming: &CacheEntry,
+        now: Now,
+        stale_window: Duration,
+    ) -> bool {
+        if !self.within(existing, now, stale_window) {
+            return false;
+        }
+        if existing.headers_only != incoming.headers_only {
+            return incoming.headers_only;
+        }
+        existing.created_at > incoming.created_at
     }
 
+    fn write_lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        let stripe = xxh3_64(key.as_bytes()) as usize % self.write_locks.len();
+        self.write_locks[stripe]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Store an entry unconditionally, replacing whatever is cached for the key
     pub fn set(&self, key: String, entry: CacheEntry) {
+        let _guard = self.write_lock(&key);
+        self.store(key, entry);
+    }
+
+    /// Insert an entry and index its tags; the caller holds the key's write lock
+    fn store(&self, key: String, entry: CacheEntry) -> bool {
         let entry_size = entry.size;
 
+        if !self.enabled {
+            return false;
+        }
+
+        if self.stores_paused() {
+            debug!(key = %key, "Cache stores paused, not caching entry");
+            return false;
+        }
+
         // Check if entry is too large
         if entry_size > self.config.max_entry_size_bytes() {
             warn!(
@@ -347,49 +1161,54 @@ impl Cache {
                 max = self.config.max_entry_size_bytes(),
                 "Entry too large to cache"
             );
-            return;
+            return false;
         }
 
         // Evict entries if necessary
         self.evict_if_needed(entry_size);
 
-        if self.config.hierarchy.enabled {
-            // Determine which tier based on access count
-            let is_hot = entry.access_count >= self.config.hierarchy.promotion_threshold;
-
-            // Remove from old locations if exists
-            self.remove_from_both_tiers(&key);
-
-            if is_hot {
-                // Store in L1 (hot tier)
-                self.l1_current_size
-                    .fetch_add(entry_size, Ordering::Relaxed);
-                self.l1_cache.insert(key.clone(), entry);
-                debug!(key = %key, size = entry_size, tier = "L1", "Cached entry");
-            } else {
-                // Store in L2 (cold tier)
-                self.l2_current_size
-                    .fetch_add(entry_size, Ordering::Relaxed);
-                self.l2_cache.insert(key.clone(), entry);
-                debug!(key = %key, size = entry_size, tier = "L2", "Cached entry");
-            }
+        // Remove from old location if exists, along with its tags
+        if let Some(old) = self.remove_entry(&key) {
+            self.unindex_tags(&key, &old);
+        }
+        self.index_tags(&key, &entry.cache_tags);
+        self.index_range(&key);
+        self.index_key_hash(&key);
 
-            self.current_size.fetch_add(entry_size, Ordering::Relaxed);
-        } else {
-            // Legacy single-tier storage (hierarchy disabled)
-            if let Some(old_entry) = self.entries.get(&key) {
-                self.current_size
-                    .fetch_sub(old_entry.size, Ordering::Relaxed);
-            }
+        // Determine which tier based on access count
+        let is_hot = self.config.hierarchy.enabled
+            && entry.access_count >= self.config.hierarchy.promotion_threshold;
 
-            self.current_size.fetch_add(entry_size, Ordering::Relaxed);
-            self.entries.insert(key.clone(), entry);
-            debug!(key = %key, size = entry_size, "Cached entry");
+        self.track_content_type(&entry);
+        if is_hot {
+            // Store in L1 (hot tier)
+            self.l1_current_size
+                .fetch_add(entry_size, Ordering::Relaxed);
+            self.l1_cache.insert(key.clone(), entry);
+            debug!(key = %key, size = entry_size, tier = "L1", "Cached entry");
+        } else {
+            // Store in L2 (cold tier, or the only tier)
+            self.l2_current_size
+                .fetch_add(entry_size, Ordering::Relaxed);
+            self.l2_cache.insert(key.clone(), entry);
+            debug!(key = %key, size = entry_size, tier = "L2", "Cached entry");
         }
+
+        self.current_size.fetch_add(entry_size, Ordering::Relaxed);
+        true
     }
 
+    /// Invalidate an entry along with any partial entries of it
     pub fn invalidate(&self, key: &str) -> bool {
-        let removed = self.invalidate_internal(key, false);
+        let mut removed = self.invalidate_internal(key, false);
+        let range_keys: Vec<String> = self
+            .range_keys
+            .get(key)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default();
+        for range_key in range_keys {
+            removed |= self.invalidate_internal(&range_key, false);
+        }
         if removed {
             info!(key = %key, "Invalidated cache entry");
         }
@@ -397,39 +1216,78 @@ impl Cache {
     }
 
     pub fn invalidate_prefix(&self, prefix: &str) -> usize {
+        self.invalidate_prefixes(&[prefix])[0]
+    }
+
+    /// Invalidate the entries under any of `prefixes` in one pass over the keys
+    ///
+    /// Prefixes match on key fields (see [`KeyPrefix`]). Returns a count per
+    /// prefix; a key under several prefixes counts for each.
+    pub fn invalidate_prefixes(&self, prefixes: &[&str]) -> Vec<usize> {
+        let matchers: Vec<KeyPrefix> = prefixes.iter().map(|p| KeyPrefix::parse(p)).collect();
+        let mut counts = vec![0; prefixes.len()];
         let keys_to_remove: Vec<String> = self
-            .entries
-            .iter()
-            .filter(|e| e.key().starts_with(prefix))
-            .map(|e| e.key().clone())
+            .iter_keys()
+            .filter(|key| {
+                let mut matched = false;
+                for (prefix, count) in matchers.iter().zip(counts.iter_mut()) {
+                    if prefix.matches(key) {
+                        *count += 1;
+                        matched = true;
+                    }
+                }
+                matched
+            })
             .collect();
 
-        let count = keys_to_remove.len();
         for key in keys_to_remove {
             self.invalidate(&key);
         }
 
-        info!(prefix = %prefix, count = count, "Invalidated cache entries by prefix");
-        count
+        for (prefix, count) in prefixes.iter().zip(&counts) {
+            info!(prefix = %prefix, count = count, "Invalidated cache entries by prefix");
+        }
+        counts
     }
 
-    pub fn purge_all(&self) -> usize {
-        let count = if self.config.hierarchy.enabled {
-            let l1_count = self.l1_cache.len();
-            let l2_count = self.l2_cache.len();
-            self.l1_cache.clear();
-            self.l2_cache.clear();
-            self.l1_current_size.store(0, Ordering::Relaxed);
-            self.l2_current_size.store(0, Ordering::Relaxed);
-            l1_count + l2_count
-        } else {
-            let count = self.entries.len();
-            self.entries.clear();
-            count
-        };
+    /// Entries under `prefix` and entries in all, counted in one pass
+    /// without invalidating any
+    pub fn count_prefix(&self, prefix: &str) -> (usize, usize) {
+        let prefix = KeyPrefix::parse(prefix);
+        self.iter_keys().fold((0, 0), |(matched, total), key| {
+            (matched + usize::from(prefix.matches(&key)), total + 1)
+        })
+    }
 
-        self.current_size.store(0, Ordering::Relaxed);
+    /// Invalidate a URL's entry along with every Vary and key-dimension variant
+    pub fn invalidate_variants(&self, base_key: &str) -> usize {
+        let keys_to_remove: Vec<String> = self
+            .iter_keys()
+            .filter(|key| is_variant_of(key, base_key))
+            .collect();
+
+        let count = keys_to_remove.len();
+        for key in keys_to_remove {
+            self.invalidate(&key);
+        }
+        count
+    }
+
+    pub fn purge_all(&self) -> usize {
+        let count = self.len();
+        for (_, map) in self.tiers() {
+            map.clear();
+        }
+
+        self.l1_current_size.store(0, Ordering::Relaxed);
+        self.l2_current_size.store(0, Ordering::Relaxed);
+        self.current_size.store(0, Ordering::Relaxed);
         self.tag_to_keys.clear(); // Also clear tag index
+        self.content_types.clear();
+        self.range_keys.clear();
+        if let Some(key_hashes) = &self.key_hashes {
+            key_hashes.clear();
+        }
         info!(count = count, "Purged all cache entries");
         count
     }
@@ -444,73 +1302,77 @@ impl Cache {
             0.0
         };
 
-        let (total_entries, hot_entries, tagged_entries) = if self.config.hierarchy.enabled {
-            let l1_count = self.l1_cache.len();
-            let l2_count = self.l2_cache.len();
-            let total = l1_count + l2_count;
-
-            // Count hot entries (in L1 or high access count in L2)
-            let l1_hot = self.l1_cache.iter().count(); // All L1 entries are hot by definition
-            let l2_hot = self
-                .l2_cache
-                .iter()
-                .filter(|e| e.access_count() >= HOT_ENTRY_THRESHOLD)
-                .count();
-
-            // Count tagged entries
-            let l1_tagged = self
-                .l1_cache
-                .iter()
-                .filter(|e| !e.cache_tags.is_empty())
-                .count();
-            let l2_tagged = self
-                .l2_cache
-                .iter()
-                .filter(|e| !e.cache_tags.is_empty())
-                .count();
-
-            (total, l1_hot + l2_hot, l1_tagged + l2_tagged)
-        } else {
-            let total = self.entries.len();
-            let hot = self
-                .entries
-                .iter()
-                .filter(|e| e.access_count() >= HOT_ENTRY_THRESHOLD)
-                .count();
-            let tagged = self
-                .entries
-                .iter()
-                .filter(|e| !e.cache_tags.is_empty())
-                .count();
-
-            (total, hot, tagged)
-        };
+        let mut total_entries = 0;
+        let mut hot_entries = 0;
+        let mut tagged_entries = 0;
+        let mut range_entries = 0;
+        let mut range_size_bytes = 0;
+        let mut pinned_entries = 0;
+        let mut pinned_size_bytes = 0;
+        let pinned = self.pinned_snapshot();
+        self.for_each_entry(|key, entry, tier| {
+            total_entries += 1;
+            // All L1 entries are hot by definition
+            if tier == Tier::L1 || entry.access_count() >= HOT_ENTRY_THRESHOLD {
+                hot_entries += 1;
+            }
+            if !entry.cache_tags.is_empty() {
+                tagged_entries += 1;
+            }
+            if split_range(key).is_some() {
+                range_entries += 1;
+                range_size_bytes += entry.size;
+            }
+            if pinned.contains(key) {
+                pinned_entries += 1;
+                pinned_size_bytes += entry.size;
+            }
+        });
 
         let total_size_bytes = self.current_size.load(Ordering::Relaxed);
-        let avg_entry_size_bytes = if total_entries > 0 {
-            total_size_bytes / total_entries
-        } else {
-            0
-        };
+        let avg_entry_size_bytes = total_size_bytes.checked_div(total_entries).unwrap_or(0);
 
         let total_tags = self.tag_to_keys.len();
 
         CacheStats {
+            enabled: self.enabled,
             hits,
             misses,
             total_entries,
             total_size_bytes,
-            max_size_bytes: self.config.max_size_bytes(),
+            max_size_bytes: if self.enabled {
+                self.config.max_size_bytes()
+            } else {
+                0
+            },
             hit_ratio,
             evictions: self.evictions.load(Ordering::Relaxed),
             stale_hits: self.stale_hits.load(Ordering::Relaxed),
+            adaptive_stale_hits: self.adaptive_stale_hits.load(Ordering::Relaxed),
+            early_refreshes: self.early_refreshes.load(Ordering::Relaxed),
             avg_entry_size_bytes,
             hot_entries,
             total_tags,
             tagged_entries,
+            by_content_type: self.content_type_stats(),
+            range_entries,
+            range_size_bytes,
+            pinned_entries,
+            pinned_size_bytes,
+            key_hash_index_entries: self.key_hashes.as_ref().map_or(0, DashMap::len),
         }
     }
 
+    /// Entry count and bytes per normalized content type
+    ///
+    /// Maintained as entries are stored and removed, so this doesn't scan the cache.
+    pub fn content_type_stats(&self) -> BTreeMap<String, ContentTypeStats> {
+        self.content_types
+            .iter()
+            .map(|e| (e.key().clone(), *e.value()))
+            .collect()
+    }
+
     fn evict_if_needed(&self, needed_space: usize) {
         let max_size = self.config.max_size_bytes();
         let current = self.current_size.load(Ordering::Relaxed);
@@ -524,15 +1386,15 @@ impl Cache {
         // 2. Remove cold entries (low access count) before hot entries
         // 3. Within same access count, remove oldest entries
 
-        let now = Instant::now();
+        let now = self.now();
 
         // First pass: remove expired entries
-        let expired_keys: Vec<String> = self
-            .entries
-            .iter()
-            .filter(|e| now >= e.expires_at)
-            .map(|e| e.key().clone())
-            .collect();
+        let mut expired_keys: Vec<String> = Vec::new();
+        self.for_each_entry(|key, entry, _| {
+            if self.overdue(entry, now).is_some() {
+                expired_keys.push(key.to_string());
+            }
+        });
 
         let expired_count = expired_keys.len();
         for key in expired_keys {
@@ -545,24 +1407,8 @@ impl Cache {
         }
 
         // Second pass: LRU-K eviction - prioritize cold entries
-        // Score = access_count * 1000 + recency_score
-        // Lower score = more likely to evict
-        let mut entries_by_score: Vec<(String, u64)> = self
-            .entries
-            .iter()
-            .map(|e| {
-                let recency = e.last_accessed.elapsed().as_secs().min(1000);
-                // Lower access count and older access = lower score = evict first
-                let score = (e.access_count() as u64 * 1000).saturating_sub(recency);
-                (e.key().clone(), score)
-            })
-            .collect();
-
-        // Sort by score ascending (lowest score = evict first)
-        entries_by_score.sort_by_key(|(_, score)| *score);
-
         let mut evict_count = 0;
-        for (key, _) in entries_by_score {
+        for key in self.coldest_first() {
             if self.current_size.load(Ordering::Relaxed) + needed_space <= max_size {
                 break;
             }
@@ -579,50 +1425,164 @@ impl Cache {
         }
     }
 
-    /// Internal invalidation that optionally tracks evictions
-    fn invalidate_internal(&self, key: &str, is_eviction: bool) -> bool {
-        // Helper to remove tags from index
-        let remove_tags = |entry: &CacheEntry| {
-            for tag in &entry.cache_tags {
-                if let Some(mut keys_set) = self.tag_to_keys.get_mut(tag) {
-                    keys_set.remove(key);
-                    if keys_set.is_empty() {
-                        drop(keys_set);
-                        self.tag_to_keys.remove(tag);
-                    }
-                }
+    /// Unpinned keys ordered for LRU-K eviction, coldest first
+    fn coldest_first(&self) -> Vec<String> {
+        // Score = access_count * 1000 + recency_score
+        // Lower score = more likely to evict
+        let mut entries_by_score: Vec<(String, u64)> = Vec::new();
+        let pinned = self.pinned_snapshot();
+        self.for_each_entry(|key, entry, _| {
+            if pinned.contains(key) {
+                return;
             }
-        };
+            let recency = entry.last_accessed.elapsed().as_secs().min(1000);
+            // Lower access count and older access = lower score = evict first
+            let score = (entry.access_count() as u64 * 1000).saturating_sub(recency);
+            entries_by_score.push((key.to_string(), score));
+        });
+
+        // Sort by score ascending (lowest score = evict first)
+        entries_by_score.sort_by_key(|(_, score)| *score);
+        entries_by_score.into_iter().map(|(key, _)| key).collect()
+    }
 
-        let mut removed = false;
+    /// Evict the coldest `percent` of unpinned entries regardless of size limits
+    ///
+    /// Used under memory pressure; returns how many entries were evicted.
+    pub fn shed(&self, percent: u8) -> usize {
+        let keys = self.coldest_first();
+        let count = (keys.len() * usize::from(percent.min(100))).div_ceil(100);
+        keys.into_iter()
+            .take(count)
+            .filter(|key| self.invalidate_internal(key, true))
+            .count()
+    }
 
-        if self.config.hierarchy.enabled {
-            // Try removing from L1
-            if let Some((_, entry)) = self.l1_cache.remove(key) {
-                self.l1_current_size
-                    .fetch_sub(entry.size, Ordering::Relaxed);
-                self.current_size.fetch_sub(entry.size, Ordering::Relaxed);
-                remove_tags(&entry);
-                removed = true;
-            }
+    /// Refuse (or accept again) new entries; lookups are unaffected
+    pub fn set_stores_paused(&self, paused: bool) {
+        self.stores_paused.store(paused, Ordering::Relaxed);
+    }
 
-            // Try removing from L2
-            if let Some((_, entry)) = self.l2_cache.remove(key) {
-                self.l2_current_size
-                    .fetch_sub(entry.size, Ordering::Relaxed);
-                self.current_size.fetch_sub(entry.size, Ordering::Relaxed);
-                remove_tags(&entry);
-                removed = true;
+    pub fn stores_paused(&self) -> bool {
+        self.stores_paused.load(Ordering::Relaxed)
+    }
+
+    /// Most bytes pinned entries may hold: `max_pinned_mb`, capped at half the cache
+    pub fn max_pinned_bytes(&self) -> usize {
+        self.config
+            .max_pinned_bytes()
+            .min(self.config.max_size_bytes() / 2)
+    }
+
+    /// Pin cached entries so eviction, shedding and L1 demotion pass them over
+    ///
+    /// Keys with nothing cached, or whose entry would take the pinned total
+    /// past [`Cache::max_pinned_bytes`], are left unpinned. A key stays pinned
+    /// when its entry is refreshed, purged or expires, until it's unpinned;
+    /// pinned entries still expire and can be purged like any other.
+    pub fn pin(&self, keys: impl IntoIterator<Item = String>) -> PinOutcome {
+        let mut pinned = self.pinned.lock().unwrap_or_else(PoisonError::into_inner);
+        let limit = self.max_pinned_bytes();
+        let mut total: usize = pinned.iter().filter_map(|key| self.entry_size(key)).sum();
+
+        let mut outcome = PinOutcome::default();
+        for key in keys {
+            if pinned.contains(&key) {
+                outcome.pinned.push(key);
+                continue;
             }
-        } else {
-            // Legacy single-tier removal
-            if let Some((_, entry)) = self.entries.remove(key) {
-                self.current_size.fetch_sub(entry.size, Ordering::Relaxed);
-                remove_tags(&entry);
-                removed = true;
+            let Some(size) = self.entry_size(&key) else {
+                outcome.not_cached.push(key);
+                continue;
+            };
+            if total + size > limit {
+                outcome.over_limit.push(key);
+                continue;
             }
+            total += size;
+            pinned.insert(key.clone());
+            outcome.pinned.push(key);
         }
 
+        info!(
+            pinned = outcome.pinned.len(),
+            not_cached = outcome.not_cached.len(),
+            over_limit = outcome.over_limit.len(),
+            pinned_bytes = total,
+            "Pinned cache entries"
+        );
+        outcome
+    }
+
+    /// Pin every cached entry under `prefix` (see [`KeyPrefix`])
+    pub fn pin_prefix(&self, prefix: &str) -> PinOutcome {
+        let prefix = KeyPrefix::parse(prefix);
+        let mut keys: Vec<String> = self.iter_keys().filter(|key| prefix.matches(key)).collect();
+        keys.sort();
+        self.pin(keys)
+    }
+
+    /// Unpin keys, returning how many were pinned
+    pub fn unpin(&self, keys: &[String]) -> usize {
+        let mut pinned = self.pinned.lock().unwrap_or_else(PoisonError::into_inner);
+        keys.iter()
+            .filter(|key| pinned.remove(key.as_str()))
+            .count()
+    }
+
+    /// Unpin every key under `prefix`, returning how many there were
+    pub fn unpin_prefix(&self, prefix: &str) -> usize {
+        let prefix = KeyPrefix::parse(prefix);
+        let mut pinned = self.pinned.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = pinned.len();
+        pinned.retain(|key| !prefix.matches(key));
+        before - pinned.len()
+    }
+
+    pub fn is_pinned(&self, key: &str) -> bool {
+        self.pinned
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(key)
+    }
+
+    /// Pinned keys in order, with the size of what's cached under each
+    pub fn pins(&self) -> Vec<PinnedKey> {
+        let mut keys: Vec<String> = self.pinned_snapshot().into_iter().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|key| PinnedKey {
+                size_bytes: self.entry_size(&key),
+                key,
+            })
+            .collect()
+    }
+
+    /// A copy of the pinned keys, for checking while tier shards are locked
+    fn pinned_snapshot(&self) -> HashSet<String> {
+        self.pinned
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn entry_size(&self, key: &str) -> Option<usize> {
+        self.tiers()
+            .find_map(|(_, map)| map.get(key).map(|entry| entry.size))
+    }
+
+    /// Internal invalidation that optionally tracks evictions
+    fn invalidate_internal(&self, key: &str, is_eviction: bool) -> bool {
+        let removed = match self.remove_entry(key) {
+            Some(entry) => {
+                self.unindex_tags(key, &entry);
+                self.unindex_range(key);
+                self.unindex_key_hash(key);
+                true
+            }
+            None => false,
+        };
+
         if removed && is_eviction {
             self.evictions.fetch_add(1, Ordering::Relaxed);
         }
@@ -630,19 +1590,160 @@ impl Cache {
         removed
     }
 
-    /// Remove an entry from both L1 and L2 tiers (used during tier transitions)
-    fn remove_from_both_tiers(&self, key: &str) {
-        if let Some((_, entry)) = self.l1_cache.remove(key) {
-            self.l1_current_size
-                .fetch_sub(entry.size, Ordering::Relaxed);
-            self.current_size.fetch_sub(entry.size, Ordering::Relaxed);
+    /// Add a key to the tag index under each of `tags`
+    fn index_tags(&self, key: &str, tags: &[String]) {
+        for tag in tags {
+            self.tag_to_keys
+                .entry(tag.clone())
+                .or_default()
+                .insert(key.to_string());
         }
+    }
 
-        if let Some((_, entry)) = self.l2_cache.remove(key) {
-            self.l2_current_size
-                .fetch_sub(entry.size, Ordering::Relaxed);
-            self.current_size.fetch_sub(entry.size, Ordering::Relaxed);
+    /// Drop a removed entry's key from the tag index
+    fn unindex_tags(&self, key: &str, entry: &CacheEntry) {
+        for tag in &entry.cache_tags {
+            if let Some(mut keys_set) = self.tag_to_keys.get_mut(tag) {
+                keys_set.remove(key);
+                if keys_set.is_empty() {
+                    drop(keys_set);
+                    self.tag_to_keys.remove(tag);
+                }
+            }
+        }
+    }
+
+    /// Record a partial entry's key under the object it's part of
+    fn index_range(&self, key: &str) {
+        if let Some((object_key, _)) = split_range(key) {
+            self.range_keys
+                .entry(object_key.to_string())
+                .or_default()
+                .insert(key.to_string());
+        }
+    }
+
+    /// Drop a removed partial entry's key from the range index
+    fn unindex_range(&self, key: &str) {
+        if let Some((object_key, _)) = split_range(key)
+            && let Some(mut keys) = self.range_keys.get_mut(object_key)
+        {
+            keys.remove(key);
+            if keys.is_empty() {
+                drop(keys);
+                self.range_keys.remove(object_key);
+            }
+        }
+    }
+
+    /// Record a stored key under its hash, unless the index is off or full
+    fn index_key_hash(&self, key: &str) {
+        if let Some(key_hashes) = &self.key_hashes
+            && key_hashes.len() < self.config.key_hash_index.max_entries
+        {
+            key_hashes
+                .entry(key_hash(key))
+                .or_insert_with(|| key.to_string());
+        }
+    }
+
+    /// Drop a removed entry's key from the hash index
+    fn unindex_key_hash(&self, key: &str) {
+        if let Some(key_hashes) = &self.key_hashes {
+            key_hashes.remove_if(&key_hash(key), |_, indexed| indexed == key);
+        }
+    }
+
+    /// Whether purges can name keys by hash
+    pub fn key_hash_index_enabled(&self) -> bool {
+        self.key_hashes.is_some()
+    }
+
+    /// The cached key with this `X-Cache-Key-Hash`, if it was indexed
+    pub fn key_for_hash(&self, hash: &str) -> Option<String> {
+        let key_hashes = self.key_hashes.as_ref()?;
+        key_hashes
+            .get(&hash.trim().to_ascii_lowercase())
+            .map(|key| key.clone())
+    }
+
+    /// Count an entry just placed in a tier under its content type
+    fn track_content_type(&self, entry: &CacheEntry) {
+        let mut stats = self
+            .content_types
+            .entry(content_type_bucket(entry.content_type.as_deref()))
+            .or_default();
+        stats.entry_count += 1;
+        stats.total_size_bytes += entry.size;
+    }
+
+    /// Uncount an entry taken out of a tier, dropping types left empty
+    fn untrack_content_type(&self, entry: &CacheEntry) {
+        let bucket = content_type_bucket(entry.content_type.as_deref());
+        if let Some(mut stats) = self.content_types.get_mut(&bucket) {
+            stats.entry_count = stats.entry_count.saturating_sub(1);
+            stats.total_size_bytes = stats.total_size_bytes.saturating_sub(entry.size);
+        }
+        self.content_types
+            .remove_if(&bucket, |_, stats| stats.entry_count == 0);
+    }
+
+    /// Tiers in lookup order: L1 then L2 with the hierarchy, L2 alone without
+    fn tiers(&self) -> impl Iterator<Item = (Tier, &DashMap<String, CacheEntry>)> {
+        let l1 = self
+            .config
+            .hierarchy
+            .enabled
+            .then_some((Tier::L1, &*self.l1_cache));
+        l1.into_iter()
+            .chain(std::iter::once((Tier::L2, &*self.l2_cache)))
+    }
+
+    fn tier_size(&self, tier: Tier) -> &AtomicUsize {
+        match tier {
+            Tier::L1 => &self.l1_current_size,
+            Tier::L2 => &self.l2_current_size,
+        }
+    }
+
+    /// Number of stored entries across all active tiers
+    fn len(&self) -> usize {
+        self.tiers().map(|(_, map)| map.len()).sum()
+    }
+
+    /// Visit every stored entry with the tier holding it
+    ///
+    /// Shard locks are held while `f` runs, so it must not touch the cache.
+    fn for_each_entry(&self, mut f: impl FnMut(&str, &CacheEntry, Tier)) {
+        for (tier, map) in self.tiers() {
+            for e in map.iter() {
+                f(e.key(), e.value(), tier);
+            }
+        }
+    }
+
+    /// Iterate over the keys of every stored entry
+    fn iter_keys(&self) -> impl Iterator<Item = String> + '_ {
+        self.tiers()
+            .flat_map(|(_, map)| map.iter().map(|e| e.key().clone()))
+    }
+
+    /// Remove an entry from whichever tier holds it, keeping size counters in sync
+    ///
+    /// Does not touch the tag index; callers that drop the entry for good go
+    /// through `invalidate_internal`.
+    fn remove_entry(&self, key: &str) -> Option<CacheEntry> {
+        let mut removed = None;
+        for (tier, map) in [(Tier::L1, &*self.l1_cache), (Tier::L2, &*self.l2_cache)] {
+            if let Some((_, entry)) = map.remove(key) {
+                self.tier_size(tier)
+                    .fetch_sub(entry.size, Ordering::Relaxed);
+                self.current_size.fetch_sub(entry.size, Ordering::Relaxed);
+                self.untrack_content_type(&entry);
+                removed = Some(entry);
+            }
         }
+        removed
     }
 
     /// Promote an entry from L2 to L1
@@ -651,6 +1752,8 @@ impl Cache {
         if let Some((_, old_entry)) = self.l2_cache.remove(key) {
             self.l2_current_size
                 .fetch_sub(old_entry.size, Ordering::Relaxed);
+            // The entry moved may be an older copy than the one it replaces
+            self.untrack_content_type(&old_entry);
 
             // Check if L1 has space or needs eviction
             let max_l1_size =
@@ -665,6 +1768,7 @@ impl Cache {
             // Add to L1
             self.l1_current_size
                 .fetch_add(entry.size, Ordering::Relaxed);
+            self.track_content_type(&entry);
             self.l1_cache.insert(key.to_string(), entry);
             self.promotions.fetch_add(1, Ordering::Relaxed);
 
@@ -678,10 +1782,12 @@ impl Cache {
         if let Some((_, old_entry)) = self.l1_cache.remove(key) {
             self.l1_current_size
                 .fetch_sub(old_entry.size, Ordering::Relaxed);
+            self.untrack_content_type(&old_entry);
 
             // Add to L2
             self.l2_current_size
                 .fetch_add(entry.size, Ordering::Relaxed);
+            self.track_content_type(&entry);
             self.l2_cache.insert(key.to_string(), entry);
             self.demotions.fetch_add(1, Ordering::Relaxed);
 
@@ -691,10 +1797,12 @@ impl Cache {
 
     /// Evict entries from L1 to L2 (used when L1 is full)
     fn evict_from_l1_to_l2(&self) {
-        // Find coldest entries in L1 (lowest access count)
+        // Find coldest entries in L1 (lowest access count); pinned ones stay
+        let pinned = self.pinned_snapshot();
         let mut entries_by_score: Vec<(String, u64, CacheEntry)> = self
             .l1_cache
             .iter()
+            .filter(|e| !pinned.contains(e.key()))
             .map(|e| {
                 let recency = e.last_accessed.elapsed().as_secs().min(1000);
                 let score = (e.access_count() as u64 * 1000).saturating_sub(recency);
@@ -739,24 +1847,15 @@ impl Cache {
             }
         };
 
-        // Update the entry with tags (check both tiers if hierarchy enabled)
-        let mut updated = false;
-
-        if self.config.hierarchy.enabled {
-            if let Some(mut entry) = self.l1_cache.get_mut(key) {
+        // Update the entry with tags in whichever tier holds it
+        let updated = self.tiers().any(|(_, map)| match map.get_mut(key) {
+            Some(mut entry) => {
                 update_tags(&mut entry, tags_to_add.clone());
-                updated = true;
-                debug!(key = %key, tier = "L1", tag_count = entry.cache_tags.len(), "Added tags to cache entry");
-            } else if let Some(mut entry) = self.l2_cache.get_mut(key) {
-                update_tags(&mut entry, tags_to_add.clone());
-                updated = true;
-                debug!(key = %key, tier = "L2", tag_count = entry.cache_tags.len(), "Added tags to cache entry");
+                debug!(key = %key, tag_count = entry.cache_tags.len(), "Added tags to cache entry");
+                true
             }
-        } else if let Some(mut entry) = self.entries.get_mut(key) {
-            update_tags(&mut entry, tags_to_add.clone());
-            updated = true;
-            debug!(key = %key, tag_count = entry.cache_tags.len(), "Added tags to cache entry");
-        }
+            None => false,
+        });
 
         if !updated {
             warn!(key = %key, "Failed to add tags: entry not found in cache");
@@ -796,8 +1895,10 @@ impl Cache {
             let entry_count = keys_set.len();
             let total_size: usize = keys_set
                 .iter()
-                .filter_map(|key| self.entries.get(key))
-                .map(|entry| entry.size)
+                .filter_map(|key| {
+                    self.tiers()
+                        .find_map(|(_, map)| map.get(key).map(|entry| entry.size))
+                })
                 .sum();
 
             TagStats {
@@ -853,16 +1954,70 @@ impl Cache {
         }
     }
 
+    /// Metadata for a URL's entry and every cached variant of it, by key
+    pub fn variants(&self, base_key: &str) -> Vec<CacheKeyRecord> {
+        let now = self.now();
+        let mut records = Vec::new();
+        self.for_each_entry(|key, entry, _| {
+            if is_variant_of(key, base_key) {
+                let ttl_remaining = self.ttl_remaining(entry, now);
+                records.push(CacheKeyRecord::from_entry(key, entry, ttl_remaining));
+            }
+        });
+        records.sort_by(|a, b| a.key.cmp(&b.key));
+        records
+    }
+
+    /// Number of partitions an export should be split into
+    ///
+    /// Exports walk the cache one partition at a time so that only a slice of
+    /// the key inventory is held in memory and no shard lock is held while the
+    /// records are written to a (possibly slow) client.
+    pub fn export_partition_count(&self) -> usize {
+        self.len() / EXPORT_PARTITION_SIZE + 1
+    }
+
+    /// Collect metadata for entries whose key hashes into `partition` of `partitions`
+    pub fn export_partition(&self, partition: usize, partitions: usize) -> Vec<CacheKeyRecord> {
+        let partitions = partitions.max(1) as u64;
+        let now = self.now();
+
+        let mut records = Vec::new();
+        self.for_each_entry(|key, entry, _| {
+            if xxh3_64(key.as_bytes()) % partitions == partition as u64 {
+                let ttl_remaining = self.ttl_remaining(entry, now);
+                records.push(CacheKeyRecord::from_entry(key, entry, ttl_remaining));
+            }
+        });
+        records
+    }
+
+    /// How long past its TTL an entry is kept before cleanup removes it
+    fn cleanup_window(&self) -> Duration {
+        let mut stale_window = Duration::from_secs(self.config.stale_while_revalidate_secs);
+
+        // Keep entries that may still be served while an origin is slow
+        let adaptive = &self.config.adaptive_stale;
+        if adaptive.enabled {
+            stale_window += Duration::from_secs(adaptive.max_extra_stale_secs);
+        }
+        stale_window
+    }
+
+    /// Remove every expired entry in one pass over the cache
+    ///
+    /// The background task uses [`Cache::sweep_expired`] instead, which
+    /// spreads the same work over many short ticks.
     pub fn cleanup_expired(&self) -> usize {
-        let now = Instant::now();
-        let stale_window = Duration::from_secs(self.config.stale_while_revalidate_secs);
+        let now = self.now();
+        let stale_window = self.cleanup_window();
 
-        let expired_keys: Vec<String> = self
-            .entries
-            .iter()
-            .filter(|e| now >= e.expires_at + stale_window)
-            .map(|e| e.key().clone())
-            .collect();
+        let mut expired_keys: Vec<String> = Vec::new();
+        self.for_each_entry(|key, entry, _| {
+            if !self.within(entry, now, stale_window) {
+                expired_keys.push(key.to_string());
+            }
+        });
 
         let count = expired_keys.len();
         for key in expired_keys {
@@ -874,117 +2029,462 @@ impl Cache {
         }
         count
     }
-}
 
-pub fn generate_cache_key(host: &str, path: &str, query: Option<&str>) -> String {
-    match query {
-        Some(q) if !q.is_empty() => format!("{}{}?{}", host, path, q),
-        _ => format!("{}{}", host, path),
+    /// Run one tick of the incremental cleanup sweep
+    ///
+    /// Walks whole shards, starting where the last tick stopped, until
+    /// `cache.cleanup.batch_size` entries and tags have been examined or
+    /// the end of the keyspace is reached. Each tier is swept for expired
+    /// entries, then the tag index for references to keys that are no
+    /// longer cached under the tag. Only one shard lock is held at a time,
+    /// and none while entries are removed.
+    pub fn sweep_expired(&self) -> SweepStats {
+        let mut stats = SweepStats::default();
+        let mut cursor = self
+            .sweep_cursor
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let now = self.now();
+        let stale_window = self.cleanup_window();
+        let targets: Vec<SweepTarget> = self
+            .tiers()
+            .map(|(tier, _)| SweepTarget::Entries(tier))
+            .chain(std::iter::once(SweepTarget::Tags))
+            .collect();
+        // Every index is created with the same shard count
+        let shards = self.l2_cache.shards().len();
+        let positions = targets.len() * shards;
+        let batch_size = self.config.cleanup.batch_size;
+
+        while !stats.cycle_complete && stats.entries_examined + stats.tags_examined < batch_size {
+            let position = *cursor % positions;
+            *cursor = (position + 1) % positions;
+            stats.cycle_complete = *cursor == 0;
+
+            let shard = position % shards;
+            match targets[position / shards] {
+                SweepTarget::Entries(tier) => {
+                    self.sweep_entry_shard(tier, shard, now, stale_window, &mut stats)
+                }
+                SweepTarget::Tags => self.sweep_tag_shard(shard, &mut stats),
+            }
+        }
+
+        if stats.entries_removed > 0 || stats.tag_keys_removed > 0 {
+            debug!(
+                entries = stats.entries_removed,
+                tag_keys = stats.tag_keys_removed,
+                "Swept expired cache entries"
+            );
+        }
+        stats
     }
-}
 
-/// Generate a cache key that includes Vary header values (RFC 9111)
-/// This ensures different content variants are cached separately
-pub fn generate_cache_key_with_vary(
-    host: &str,
-    path: &str,
-    query: Option<&str>,
-    vary_header: Option<&str>,
-    request_headers: &std::collections::HashMap<String, String>,
-) -> String {
-    let base_key = generate_cache_key(host, path, query);
+    /// Remove a tier shard's expired entries
+    fn sweep_entry_shard(
+        &self,
+        tier: Tier,
+        shard: usize,
+        now: Now,
+        stale_window: Duration,
+        stats: &mut SweepStats,
+    ) {
+        let map = match tier {
+            Tier::L1 => &*self.l1_cache,
+            Tier::L2 => &*self.l2_cache,
+        };
+        let mut expired_keys = Vec::new();
+        for_each_in_shard(map, shard, |key, entry| {
+            stats.entries_examined += 1;
+            if !self.within(entry, now, stale_window) {
+                expired_keys.push(key.to_string());
+            }
+        });
 
-    // If no Vary header, use base key
-    let vary = match vary_header {
-        Some(v) => v,
-        None => return base_key,
-    };
+        for key in expired_keys {
+            if self.invalidate_internal(&key, true) {
+                stats.entries_removed += 1;
+            }
+        }
+    }
 
-    // Handle Vary: * (never cache)
-    if vary.trim() == "*" {
-        return format!("{}|vary=*|{}", base_key, uuid_simple());
+    /// Drop a tag index shard's references to keys no longer cached under the tag
+    ///
+    /// A tag purge only removes what the index lists, so a stale reference
+    /// is harmless but never cleared by anything else.
+    fn sweep_tag_shard(&self, shard: usize, stats: &mut SweepStats) {
+        let mut tags = Vec::new();
+        for_each_in_shard(&self.tag_to_keys, shard, |tag, _| {
+            stats.tags_examined += 1;
+            tags.push(tag.to_string());
+        });
+
+        // Tier locks are taken before the tag index's elsewhere, so no tag
+        // lock is held while the tiers are checked
+        for tag in tags {
+            let keys: Vec<String> = match self.tag_to_keys.get(&tag) {
+                Some(keys) => keys.iter().cloned().collect(),
+                None => continue,
+            };
+            for key in keys {
+                if self.is_tagged(&key, &tag) {
+                    continue;
+                }
+                // A fill indexes its tags just before storing its entry
+                let _guard = self.write_lock(&key);
+                if self.is_tagged(&key, &tag) {
+                    continue;
+                }
+                if let Some(mut keys) = self.tag_to_keys.get_mut(&tag)
+                    && keys.remove(&key)
+                {
+                    stats.tag_keys_removed += 1;
+                    drop(keys);
+                    self.tag_to_keys.remove_if(&tag, |_, keys| keys.is_empty());
+                }
+            }
+        }
     }
 
-    // Extract relevant request header values based on Vary header
-    let mut vary_values: Vec<String> = Vec::new();
+    /// Whether `key` is cached with `tag` among its tags
+    fn is_tagged(&self, key: &str, tag: &str) -> bool {
+        self.tiers().any(|(_, map)| {
+            map.get(key)
+                .is_some_and(|entry| entry.cache_tags.iter().any(|t| t == tag))
+        })
+    }
+}
 
-    for header_name in vary.split(',') {
-        let header_name = header_name.trim().to_lowercase();
-        // Skip Vary: * in a list
-        if header_name == "*" {
-            continue;
-        }
+/// The cache reads a client request makes
+///
+/// Implemented by [`Cache`]; handler tests substitute a fake to force the
+/// miss, stale and error branches without arranging real entries. Admin
+/// operations and fills still go through the concrete [`Cache`].
+pub trait ContentCache: Send + Sync {
+    /// See [`Cache::get_or_reserve`]
+    fn get_or_reserve(
+        &self,
+        key: &str,
+        allow_headers_only: bool,
+        extra_stale: Duration,
+    ) -> CacheLookup;
+
+    /// See [`Cache::get_stale_for_error`]
+    fn get_stale_for_error(&self, key: &str) -> Option<CacheEntry>;
+
+    /// See [`Cache::get_range`]
+    fn get_range(&self, key: &str, range_header: &str) -> Option<CachedRange>;
+}
 
-        let value = request_headers
-            .get(&header_name)
-            .or_else(|| request_headers.get(&header_name.to_uppercase()))
-            .map(|s| s.as_str())
-            .unwrap_or("");
+impl ContentCache for Cache {
+    fn get_or_reserve(
+        &self,
+        key: &str,
+        allow_headers_only: bool,
+        extra_stale: Duration,
+    ) -> CacheLookup {
+        Cache::get_or_reserve(self, key, allow_headers_only, extra_stale)
+    }
 
-        vary_values.push(format!("{}={}", header_name, value));
+    fn get_stale_for_error(&self, key: &str) -> Option<CacheEntry> {
+        Cache::get_stale_for_error(self, key)
     }
 
-    if vary_values.is_empty() {
-        base_key
-    } else {
-        format!("{}|vary:{}", base_key, vary_values.join("|"))
+    fn get_range(&self, key: &str, range_header: &str) -> Option<CachedRange> {
+        Cache::get_range(self, key, range_header)
     }
 }
 
-/// Simple UUID-like generator for unique keys (for Vary: *)
-fn uuid_simple() -> String {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    let nanos = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
+/// Content type an entry is counted under in [`CacheStats::by_content_type`]
+///
+/// The media type without parameters, lowercased; a missing or malformed
+/// type is bucketed as "other".
+pub fn content_type_bucket(content_type: Option<&str>) -> String {
+    let is_token = |s: &str| {
+        !s.is_empty()
+            && s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b))
+    };
+    let media_type = content_type
+        .and_then(|ct| ct.split(';').next())
         .unwrap_or_default()
-        .as_nanos();
-    format!("{:x}", nanos)
+        .trim();
+    match media_type.split_once('/') {
+        Some((kind, subtype)) if is_token(kind) && is_token(subtype) => {
+            media_type.to_ascii_lowercase()
+        }
+        _ => "other".to_string(),
+    }
 }
 
-pub fn parse_cache_control(header: &str) -> CacheControlDirectives {
-    let mut directives = CacheControlDirectives::default();
+/// The Vary a lookup is keyed on, and a response without its own Vary is
+/// stored under, so compressed variants are cached apart
+const DEFAULT_VARY: &str = "accept-encoding";
+
+/// Cache keys for one request, computed the way the CDN path computes them
+///
+/// A lookup is keyed on `Accept-Encoding`; a response is stored under the
+/// key for its own `Vary`. Both add the headers and cookies configured in
+/// `cache.key`, and key on the query as its `query_rules` filter it. Every
+/// variant starts with the base key, which is what per-URL purges match on.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheKeyBuilder<'a> {
+    origin: &'a str,
+    path: &'a str,
+    query: Option<&'a str>,
+    request_headers: Option<&'a HashMap<String, String>>,
+    key_config: &'a CacheKeyConfig,
+}
 
-    for part in header.split(',') {
-        let part = part.trim().to_lowercase();
+impl<'a> CacheKeyBuilder<'a> {
+    /// Keys for `path` on `origin`; the leading slash of `path` is optional
+    pub fn new(origin: &'a str, path: &'a str, key_config: &'a CacheKeyConfig) -> Self {
+        Self {
+            origin,
+            path: path.strip_prefix('/').unwrap_or(path),
+            query: None,
+            request_headers: None,
+            key_config,
+        }
+    }
 
-        if part == "no-cache" {
-            directives.no_cache = true;
-        } else if part == "no-store" {
-            directives.no_store = true;
-        } else if part == "private" {
-            directives.private = true;
-        } else if part == "public" {
-            directives.public = true;
-        } else if part == "must-revalidate" {
-            directives.must_revalidate = true;
-        } else if let Some(value) = part.strip_prefix("max-age=") {
-            if let Ok(secs) = value.parse() {
-                directives.max_age = Some(secs);
-            }
-        } else if let Some(value) = part.strip_prefix("s-maxage=") {
-            if let Ok(secs) = value.parse() {
-                directives.s_maxage = Some(secs);
-            }
-        } else if let Some(value) = part.strip_prefix("stale-while-revalidate=") {
-            if let Ok(secs) = value.parse() {
-                directives.stale_while_revalidate = Some(secs);
-            }
-        } else if let Some(value) = part.strip_prefix("stale-if-error=")
-            && let Ok(secs) = value.parse() {
-                directives.stale_if_error = Some(secs);
-            }
+    pub fn query(mut self, query: Option<&'a str>) -> Self {
+        self.query = query;
+        self
     }
 
-    directives
-}
+    /// Request headers (lowercase names) for Vary and configured key headers
+    pub fn request_headers(mut self, request_headers: &'a HashMap<String, String>) -> Self {
+        self.request_headers = Some(request_headers);
+        self
+    }
 
-#[derive(Debug, Default, Clone)]
-pub struct CacheControlDirectives {
-    pub no_cache: bool,
+    /// The key every variant of the URL starts with
+    pub fn base_key(&self) -> String {
+        let path = format!("/{}", self.path);
+        let query = self.key_config.key_query(&path, self.query);
+        generate_cache_key(self.origin, &path, query.as_deref())
+    }
+
+    /// The key a request is looked up under
+    pub fn lookup_key(&self) -> String {
+        self.response_key(None)
+    }
+
+    /// The key concurrent misses share an origin fetch under
+    ///
+    /// Leaves out `Accept-Encoding`: the origin is never sent the client's,
+    /// so requests differing only in it make the same origin request.
+    pub fn fetch_key(&self) -> String {
+        self.keyed_on(None)
+    }
+
+    /// Which variant of a response with this `Vary` the request's own fetch
+    /// would have returned, over the headers that reach the origin
+    ///
+    /// Two requests with the same variant can share one fetch's response.
+    /// `None` for `Vary: *`, which no two requests may share.
+    pub fn fetch_variant(&self, vary: Option<&str>) -> Option<String> {
+        let mut headers = Vec::new();
+        for name in vary.unwrap_or_default().split(',').map(str::trim) {
+            if name == "*" {
+                return None;
+            }
+            if !name.is_empty() && !name.eq_ignore_ascii_case(DEFAULT_VARY) {
+                headers.push(name);
+            }
+        }
+        let vary = headers.join(",");
+        Some(self.keyed_on((!vary.is_empty()).then_some(vary.as_str())))
+    }
+
+    /// The key a response with this `Vary` header is stored under
+    pub fn response_key(&self, vary: Option<&str>) -> String {
+        self.keyed_on(vary.or(Some(DEFAULT_VARY)))
+    }
+
+    /// The key over exactly these Vary headers and the configured dimensions
+    fn keyed_on(&self, vary: Option<&str>) -> String {
+        let no_headers = HashMap::new();
+        let path = format!("/{}", self.path);
+        generate_cache_key_with_vary(
+            self.origin,
+            &path,
+            self.key_config.key_query(&path, self.query).as_deref(),
+            vary,
+            self.request_headers.unwrap_or(&no_headers),
+            self.key_config,
+        )
+    }
+}
+
+pub fn generate_cache_key(host: &str, path: &str, query: Option<&str>) -> String {
+    CacheKey::new(host, path, query).to_string()
+}
+
+/// Generate a cache key that includes Vary header values (RFC 9111)
+/// This ensures different content variants are cached separately
+///
+/// Headers and cookies configured in `key_config` are appended after the
+/// Vary dimensions, so the key varies on the union of both. `Accept-Encoding`
+/// is keyed by the coding it negotiates rather than its raw value, and so is
+/// `Accept` when an `accept_rules` entry matches the request.
+pub fn generate_cache_key_with_vary(
+    host: &str,
+    path: &str,
+    query: Option<&str>,
+    vary_header: Option<&str>,
+    request_headers: &std::collections::HashMap<String, String>,
+    key_config: &CacheKeyConfig,
+) -> String {
+    let mut key = CacheKey::new(host, path, query);
+
+    if let Some(vary) = vary_header {
+        // Extract relevant request header values based on Vary header.
+        // Responses with Vary: * are never stored, so it has no key of its own.
+        for header_name in vary.split(',') {
+            let header_name = header_name.trim().to_lowercase();
+            if header_name == "*" {
+                continue;
+            }
+
+            let value = request_headers
+                .get(&header_name)
+                .or_else(|| request_headers.get(&header_name.to_uppercase()))
+                .map(|s| s.as_str());
+            let value = if header_name == "accept-encoding" {
+                encoding::cache_key_value(value).to_string()
+            } else if header_name == "accept"
+                && let Some(rule) = key_config.accept_rule(host, path)
+            {
+                accept::cache_key_value(value, &rule.media_types, &rule.default_token)
+            } else {
+                value.unwrap_or("").to_string()
+            };
+
+            key.vary.push((header_name, value));
+        }
+    }
+
+    key.dimensions = configured_key_values(request_headers, key_config);
+    key.to_string()
+}
+
+/// Configured header and cookie values in a canonical, sorted order
+///
+/// A value absent from the request is kept as such, so requests with and
+/// without the header or cookie never share an entry.
+fn configured_key_values(
+    request_headers: &std::collections::HashMap<String, String>,
+    key_config: &CacheKeyConfig,
+) -> Vec<KeyDimension> {
+    let mut headers: Vec<String> = key_config
+        .include_headers
+        .iter()
+        .map(|h| h.trim().to_lowercase())
+        .collect();
+    headers.sort();
+    headers.dedup();
+
+    let mut cookies: Vec<&str> = key_config
+        .include_cookies
+        .iter()
+        .map(|c| c.trim())
+        .collect();
+    cookies.sort_unstable();
+    cookies.dedup();
+
+    let mut values = Vec::with_capacity(headers.len() + cookies.len());
+    for name in headers {
+        let value = request_headers.get(&name).cloned();
+        values.push(KeyDimension::Header(name, value));
+    }
+
+    let cookie_header = request_headers.get("cookie").map(|s| s.as_str());
+    for name in cookies {
+        let value = cookie_header.and_then(|header| find_cookie(header, name));
+        values.push(KeyDimension::Cookie(
+            name.to_string(),
+            value.map(str::to_string),
+        ));
+    }
+
+    values
+}
+
+/// Key of a partial entry holding `range` of the object cached under `key`
+///
+/// The range is always a key's last field, so it's appended to `key`, and
+/// purging the URL's variants removes it too.
+pub fn range_key(key: &str, range: &ByteRange) -> String {
+    format!("{}{}{}-{}", key, RANGE_SEGMENT, range.start, range.end)
+}
+
+/// Look up a cookie value by name in a Cookie request header
+pub(crate) fn find_cookie<'a>(cookie_header: &'a str, name: &str) -> Option<&'a str> {
+    cookie_header.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        (key.trim() == name).then(|| value.trim())
+    })
+}
+
+/// Whether a `Vary` header lists `*`
+///
+/// Such a response matches no later request (RFC 9111 Section 4.1), so a
+/// shared cache must not store it.
+pub fn varies_on_everything(vary: &str) -> bool {
+    vary.split(',').any(|name| name.trim() == "*")
+}
+
+pub fn parse_cache_control(header: &str) -> CacheControlDirectives {
+    let mut directives = CacheControlDirectives::default();
+
+    for part in header.split(',') {
+        let part = part.trim().to_lowercase();
+
+        if part == "no-cache" {
+            directives.no_cache = true;
+        } else if part == "no-store" {
+            directives.no_store = true;
+        } else if part == "private" {
+            directives.private = true;
+        } else if part == "public" {
+            directives.public = true;
+        } else if part == "must-revalidate" {
+            directives.must_revalidate = true;
+        } else if part == "immutable" {
+            directives.immutable = true;
+        } else if let Some(value) = part.strip_prefix("max-age=") {
+            if let Ok(secs) = value.parse() {
+                directives.max_age = Some(secs);
+            }
+        } else if let Some(value) = part.strip_prefix("s-maxage=") {
+            if let Ok(secs) = value.parse() {
+                directives.s_maxage = Some(secs);
+            }
+        } else if let Some(value) = part.strip_prefix("stale-while-revalidate=") {
+            if let Ok(secs) = value.parse() {
+                directives.stale_while_revalidate = Some(secs);
+            }
+        } else if let Some(value) = part.strip_prefix("stale-if-error=")
+            && let Ok(secs) = value.parse() {
+                directives.stale_if_error = Some(secs);
+            }
+    }
+
+    directives
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CacheControlDirectives {
+    pub no_cache: bool,
     pub no_store: bool,
     pub private: bool,
     pub public: bool,
     pub must_revalidate: bool,
+    /// RFC 8246: the response won't change while fresh
+    pub immutable: bool,
     pub max_age: Option<u64>,
     pub s_maxage: Option<u64>,
     pub stale_while_revalidate: Option<u64>,
@@ -995,22 +2495,12 @@ impl CacheControlDirectives {
     pub fn is_cacheable(&self) -> bool {
         !self.no_store && !self.private
     }
-
-    pub fn ttl(&self, default_ttl: Duration, max_ttl: Duration) -> Duration {
-        // s-maxage takes precedence for shared caches (CDN)
-        let ttl_secs = self
-            .s_maxage
-            .or(self.max_age)
-            .map(Duration::from_secs)
-            .unwrap_or(default_ttl);
-
-        std::cmp::min(ttl_secs, max_ttl)
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn test_parse_cache_control() {
@@ -1028,6 +2518,66 @@ mod tests {
         assert_eq!(directives.max_age, Some(300));
     }
 
+    #[test]
+    fn test_entry_ttl() {
+        let config = CacheConfig {
+            default_ttl_secs: 3600,
+            max_ttl_secs: 86400,
+            ..Default::default()
+        };
+        let ttl = |cache: &Cache, headers: &[(&str, &str)]| {
+            let mut response = ResponseHeaders::new();
+            for (name, value) in headers {
+                response.insert(*name, *value);
+            }
+            let directives = response
+                .get("cache-control")
+                .map(|cc| parse_cache_control(cc))
+                .unwrap_or_default();
+            cache.entry_ttl(&directives, &response, SystemTime::now(), false)
+        };
+        let date = ("date", "Mon, 01 Jan 2024 00:00:00 GMT");
+
+        let cache = Cache::new(config.clone());
+        assert_eq!(
+            ttl(
+                &cache,
+                &[date, ("expires", "Mon, 01 Jan 2024 00:05:00 GMT")]
+            ),
+            Duration::from_secs(300)
+        );
+        // An Expires in the past is stale at once, not given the default TTL
+        assert_eq!(
+            ttl(
+                &cache,
+                &[date, ("expires", "Sun, 31 Dec 2023 00:00:00 GMT")]
+            ),
+            Duration::ZERO
+        );
+        assert_eq!(
+            ttl(
+                &cache,
+                &[date, ("expires", "Tue, 01 Jan 2030 00:00:00 GMT")]
+            ),
+            Duration::from_secs(86400)
+        );
+        let last_modified = ("last-modified", "Sun, 31 Dec 2023 12:00:00 GMT");
+        assert_eq!(
+            ttl(&cache, &[date, last_modified]),
+            Duration::from_secs(3600)
+        );
+
+        let cache = Cache::new(CacheConfig {
+            heuristic_freshness: true,
+            ..config
+        });
+        assert_eq!(
+            ttl(&cache, &[date, last_modified]),
+            Duration::from_secs(4320)
+        );
+        assert_eq!(ttl(&cache, &[date]), Duration::from_secs(3600));
+    }
+
     #[test]
     fn test_generate_cache_key() {
         assert_eq!(
@@ -1045,10 +2595,11 @@ mod tests {
         let mut headers = HashMap::new();
         headers.insert("accept-encoding".to_string(), "gzip, br".to_string());
         headers.insert("accept-language".to_string(), "en-US".to_string());
+        let no_key = CacheKeyConfig::default();
 
         // No Vary header - should return base key
         assert_eq!(
-            generate_cache_key_with_vary("example.com", "/path", None, None, &headers),
+            generate_cache_key_with_vary("example.com", "/path", None, None, &headers, &no_key),
             "example.com/path"
         );
 
@@ -1059,9 +2610,27 @@ mod tests {
             None,
             Some("accept-encoding"),
             &headers,
+            &no_key,
         );
         assert!(key.contains("example.com/path"));
-        assert!(key.contains("accept-encoding=gzip, br"));
+        // Keyed by the negotiated coding, so equivalent headers share an entry
+        assert!(key.contains("accept-encoding=br"));
+        let mut equivalent = headers.clone();
+        equivalent.insert(
+            "accept-encoding".to_string(),
+            "br;q=1.0, gzip;q=0.8, deflate".to_string(),
+        );
+        assert_eq!(
+            generate_cache_key_with_vary(
+                "example.com",
+                "/path",
+                None,
+                Some("accept-encoding"),
+                &equivalent,
+                &no_key,
+            ),
+            key
+        );
 
         // Multiple Vary headers
         let key = generate_cache_key_with_vary(
@@ -1070,8 +2639,9 @@ mod tests {
             None,
             Some("accept-encoding, accept-language"),
             &headers,
+            &no_key,
         );
-        assert!(key.contains("accept-encoding=gzip, br"));
+        assert!(key.contains("accept-encoding=br"));
         assert!(key.contains("accept-language=en-US"));
 
         // Vary header for non-existent request header
@@ -1081,38 +2651,293 @@ mod tests {
             None,
             Some("x-custom-header"),
             &headers,
+            &no_key,
         );
         assert!(key.contains("x-custom-header="));
 
-        // Vary: * should generate unique key
-        let key1 = generate_cache_key_with_vary("example.com", "/path", None, Some("*"), &headers);
-        let key2 = generate_cache_key_with_vary("example.com", "/path", None, Some("*"), &headers);
-        assert!(key1.contains("vary=*"));
-        assert_ne!(key1, key2); // Each should be unique
+        // Vary: * contributes nothing to a key; such responses aren't stored
+        let key1 = generate_cache_key_with_vary(
+            "example.com",
+            "/path",
+            None,
+            Some("*"),
+            &headers,
+            &no_key,
+        );
+        let key2 = generate_cache_key_with_vary(
+            "example.com",
+            "/path",
+            None,
+            Some("*"),
+            &headers,
+            &no_key,
+        );
+        assert_eq!(key1, "example.com/path");
+        assert_eq!(key1, key2);
+        assert!(varies_on_everything("*"));
+        assert!(varies_on_everything("accept-encoding, *"));
+        assert!(!varies_on_everything("accept-encoding"));
     }
 
     #[test]
-    fn test_cache_tags_basic() {
-        use crate::config::CacheConfig;
+    fn test_content_type_bucket() {
+        for (content_type, expected) in [
+            (Some("text/html"), "text/html"),
+            (Some("text/html; charset=utf-8"), "text/html"),
+            (Some(" Image/PNG "), "image/png"),
+            (
+                Some("application/vnd.api+json;v=2"),
+                "application/vnd.api+json",
+            ),
+            (Some("text"), "other"),
+            (Some("text/"), "other"),
+            (Some("/html"), "other"),
+            (Some("text/html/extra"), "other"),
+            (Some(""), "other"),
+            (None, "other"),
+        ] {
+            assert_eq!(
+                content_type_bucket(content_type),
+                expected,
+                "{:?}",
+                content_type
+            );
+        }
+    }
 
-        let config = CacheConfig::default();
+    #[test]
+    fn test_cache_key_builder() {
+        let key_config = CacheKeyConfig {
+            include_headers: vec!["X-Tenant".to_string()],
+            include_cookies: Vec::new(),
+            ..Default::default()
+        };
+        let mut headers = HashMap::new();
+        headers.insert("accept-encoding".to_string(), "gzip".to_string());
+        headers.insert("accept-language".to_string(), "en".to_string());
+        headers.insert("x-tenant".to_string(), "acme".to_string());
+
+        let keys = CacheKeyBuilder::new("web", "docs/a.html", &key_config)
+            .query(Some("v=2"))
+            .request_headers(&headers);
+        assert_eq!(keys.base_key(), "web/docs/a.html?v=2");
+        assert_eq!(
+            keys.lookup_key(),
+            "web/docs/a.html?v=2|vary:accept-encoding=gzip|key:h.x-tenant=acme"
+        );
+        assert_eq!(
+            keys.response_key(Some("Accept-Language")),
+            "web/docs/a.html?v=2|vary:accept-language=en|key:h.x-tenant=acme"
+        );
+        for key in [keys.lookup_key(), keys.response_key(Some("accept-language"))] {
+            assert!(is_variant_of(&key, &keys.base_key()));
+        }
+
+        // Fetches are shared across encodings, which never reach the origin
+        assert_eq!(keys.fetch_key(), "web/docs/a.html?v=2|key:h.x-tenant=acme");
+        assert_eq!(
+            keys.fetch_variant(None).as_deref(),
+            Some(&*keys.fetch_key())
+        );
+        assert_eq!(
+            keys.fetch_variant(Some("Accept-Encoding")),
+            keys.fetch_variant(None)
+        );
+        assert_eq!(
+            keys.fetch_variant(Some("accept-encoding, Accept-Language"))
+                .unwrap(),
+            keys.response_key(Some("Accept-Language"))
+        );
+        assert_eq!(keys.fetch_variant(Some("Accept-Language, *")), None);
+
+        // The leading slash is optional, and no headers key as absent values
+        let bare = CacheKeyBuilder::new("web", "/docs/a.html", &key_config).query(Some("v=2"));
+        assert_eq!(bare.base_key(), keys.base_key());
+        assert_eq!(
+            bare.lookup_key(),
+            "web/docs/a.html?v=2|vary:accept-encoding=identity|key:h.x-tenant"
+        );
+    }
+
+    #[test]
+    fn test_cache_key_normalizes_accept_for_matching_rules() {
+        let key_config: CacheKeyConfig = toml::from_str(
+            r#"
+            [[accept_rules]]
+            origins = ["api"]
+            path_pattern = "^/v1/"
+            media_types = ["application/json", "application/msgpack"]
+            "#,
+        )
+        .unwrap();
+        let key = |origin, path, accept: &str| {
+            let mut headers = HashMap::new();
+            headers.insert("accept".to_string(), accept.to_string());
+            generate_cache_key_with_vary(origin, path, None, Some("Accept"), &headers, &key_config)
+        };
+
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert_eq!(
+            key("api", "/v1/users", browser),
+            "api/v1/users|vary:accept=application/json"
+        );
+        assert_eq!(
+            key("api", "/v1/users", "application/json, text/plain, */*"),
+            key("api", "/v1/users", browser)
+        );
+        assert_eq!(
+            key("api", "/v1/users", "application/msgpack"),
+            "api/v1/users|vary:accept=application/msgpack"
+        );
+        assert_eq!(
+            key("api", "/v1/users", "text/html"),
+            "api/v1/users|vary:accept=none"
+        );
+
+        // Another origin or path keeps the raw value
+        assert_eq!(
+            key("web", "/v1/users", "text/html"),
+            "web/v1/users|vary:accept=text/html"
+        );
+        assert_eq!(
+            key("api", "/v2/users", "text/html"),
+            "api/v2/users|vary:accept=text/html"
+        );
+    }
+
+    fn tenant_key_config() -> CacheKeyConfig {
+        CacheKeyConfig {
+            include_headers: vec!["X-Tenant".to_string(), "x-region".to_string()],
+            include_cookies: vec!["plan".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cache_key_includes_configured_headers_and_cookies() {
+        let mut headers = HashMap::new();
+        headers.insert("x-tenant".to_string(), "acme".to_string());
+        headers.insert("x-region".to_string(), "eu".to_string());
+        headers.insert("cookie".to_string(), "session=abc; plan=pro".to_string());
+
+        let key = generate_cache_key_with_vary(
+            "example.com",
+            "/path",
+            None,
+            None,
+            &headers,
+            &tenant_key_config(),
+        );
+        assert_eq!(
+            key,
+            "example.com/path|key:h.x-region=eu|h.x-tenant=acme|c.plan=pro"
+        );
+
+        // Order of the configured names doesn't change the key
+        let reordered = CacheKeyConfig {
+            include_headers: vec!["x-region".to_string(), "x-tenant".to_string()],
+            include_cookies: vec!["plan".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            generate_cache_key_with_vary("example.com", "/path", None, None, &headers, &reordered),
+            key
+        );
+    }
+
+    #[test]
+    fn test_cache_key_marks_absent_values() {
+        let config = tenant_key_config();
+
+        let mut empty = HashMap::new();
+        empty.insert("x-tenant".to_string(), String::new());
+        empty.insert("x-region".to_string(), String::new());
+        empty.insert("cookie".to_string(), "plan=".to_string());
+
+        let absent_key = generate_cache_key_with_vary(
+            "example.com",
+            "/path",
+            None,
+            None,
+            &HashMap::new(),
+            &config,
+        );
+        let empty_key =
+            generate_cache_key_with_vary("example.com", "/path", None, None, &empty, &config);
+
+        assert_eq!(
+            absent_key,
+            "example.com/path|key:h.x-region|h.x-tenant|c.plan"
+        );
+        assert_eq!(
+            empty_key,
+            "example.com/path|key:h.x-region=|h.x-tenant=|c.plan="
+        );
+        assert_ne!(absent_key, empty_key);
+    }
+
+    #[test]
+    fn test_cache_key_combines_vary_and_configured_dimensions() {
+        let mut headers = HashMap::new();
+        headers.insert("accept-encoding".to_string(), "gzip".to_string());
+        headers.insert("x-tenant".to_string(), "acme".to_string());
+
+        let config = CacheKeyConfig {
+            include_headers: vec!["x-tenant".to_string()],
+            include_cookies: Vec::new(),
+            ..Default::default()
+        };
+        let key = generate_cache_key_with_vary(
+            "example.com",
+            "/path",
+            None,
+            Some("accept-encoding, x-tenant"),
+            &headers,
+            &config,
+        );
+        assert_eq!(
+            key,
+            "example.com/path|vary:accept-encoding=gzip|x-tenant=acme|key:h.x-tenant=acme"
+        );
+
+        // A different tenant lands on a different entry even when the origin omits it from Vary
+        headers.insert("x-tenant".to_string(), "globex".to_string());
+        let other = generate_cache_key_with_vary(
+            "example.com",
+            "/path",
+            None,
+            Some("accept-encoding"),
+            &headers,
+            &config,
+        );
+        assert_eq!(
+            other,
+            "example.com/path|vary:accept-encoding=gzip|key:h.x-tenant=globex"
+        );
+    }
+
+    fn cache_tags_basic(config: CacheConfig) {
         let cache = Cache::new(config);
 
         // Create a cache entry
         let entry = CacheEntry {
             body: Bytes::from("test body"),
-            headers: HashMap::new(),
+            headers: ResponseHeaders::new(),
             status_code: 200,
             content_type: Some("text/html".to_string()),
             etag: None,
             last_modified: None,
             created_at: Instant::now(),
             expires_at: Instant::now() + Duration::from_secs(3600),
+            expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
             size: 9,
             stale_if_error_secs: None,
             access_count: 0,
             last_accessed: Instant::now(),
             cache_tags: Vec::new(),
+            headers_only: false,
+            fetch_duration: Duration::ZERO,
+            immutable: false,
         };
 
         // Store entry
@@ -1136,29 +2961,29 @@ mod tests {
         assert!(all_tags.contains(&"category-shoes".to_string()));
     }
 
-    #[test]
-    fn test_cache_tags_invalidation() {
-        use crate::config::CacheConfig;
-
-        let config = CacheConfig::default();
+    fn cache_tags_invalidation(config: CacheConfig) {
         let cache = Cache::new(config);
 
         // Create multiple entries with shared tags
         for i in 1..=3 {
             let entry = CacheEntry {
                 body: Bytes::from(format!("body {}", i)),
-                headers: HashMap::new(),
+                headers: ResponseHeaders::new(),
                 status_code: 200,
                 content_type: Some("text/html".to_string()),
                 etag: None,
                 last_modified: None,
                 created_at: Instant::now(),
                 expires_at: Instant::now() + Duration::from_secs(3600),
+                expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
                 size: 10,
                 stale_if_error_secs: None,
                 access_count: 0,
                 last_accessed: Instant::now(),
                 cache_tags: Vec::new(),
+                headers_only: false,
+                fetch_duration: Duration::ZERO,
+                immutable: false,
             };
 
             cache.set(format!("key-{}", i), entry);
@@ -1184,12 +3009,14 @@ mod tests {
     fn test_l1_l2_hierarchy_disabled() {
         use crate::config::{CacheConfig, CacheHierarchyConfig};
 
-        let mut config = CacheConfig::default();
-        config.hierarchy = CacheHierarchyConfig {
-            enabled: false,
-            l1_size_percent: 20,
-            l2_size_percent: 80,
-            promotion_threshold: 3,
+        let config = CacheConfig {
+            hierarchy: CacheHierarchyConfig {
+                enabled: false,
+                l1_size_percent: 20,
+                l2_size_percent: 80,
+                promotion_threshold: 3,
+            },
+            ..Default::default()
         };
 
         let cache = Cache::new(config);
@@ -1197,18 +3024,22 @@ mod tests {
         // Create entry
         let entry = CacheEntry {
             body: Bytes::from("test body"),
-            headers: HashMap::new(),
+            headers: ResponseHeaders::new(),
             status_code: 200,
             content_type: Some("text/html".to_string()),
             etag: None,
             last_modified: None,
             created_at: Instant::now(),
             expires_at: Instant::now() + Duration::from_secs(3600),
+            expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
             size: 9,
             stale_if_error_secs: None,
             access_count: 0,
             last_accessed: Instant::now(),
             cache_tags: Vec::new(),
+            headers_only: false,
+            fetch_duration: Duration::ZERO,
+            immutable: false,
         };
 
         cache.set("test-key".to_string(), entry);
@@ -1232,18 +3063,22 @@ mod tests {
         // Create a cold entry (access_count < threshold)
         let cold_entry = CacheEntry {
             body: Bytes::from("cold body"),
-            headers: HashMap::new(),
+            headers: ResponseHeaders::new(),
             status_code: 200,
             content_type: Some("text/html".to_string()),
             etag: None,
             last_modified: None,
             created_at: Instant::now(),
             expires_at: Instant::now() + Duration::from_secs(3600),
+            expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
             size: 9,
             stale_if_error_secs: None,
             access_count: 1, // Below threshold
             last_accessed: Instant::now(),
             cache_tags: Vec::new(),
+            headers_only: false,
+            fetch_duration: Duration::ZERO,
+            immutable: false,
         };
 
         cache.set("cold-key".to_string(), cold_entry);
@@ -1251,18 +3086,22 @@ mod tests {
         // Create a hot entry (access_count >= threshold)
         let hot_entry = CacheEntry {
             body: Bytes::from("hot body"),
-            headers: HashMap::new(),
+            headers: ResponseHeaders::new(),
             status_code: 200,
             content_type: Some("text/html".to_string()),
             etag: None,
             last_modified: None,
             created_at: Instant::now(),
             expires_at: Instant::now() + Duration::from_secs(3600),
+            expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
             size: 8,
             stale_if_error_secs: None,
             access_count: 3, // At threshold
             last_accessed: Instant::now(),
             cache_tags: Vec::new(),
+            headers_only: false,
+            fetch_duration: Duration::ZERO,
+            immutable: false,
         };
 
         cache.set("hot-key".to_string(), hot_entry);
@@ -1286,18 +3125,22 @@ mod tests {
         // Create entry that starts in L2
         let entry = CacheEntry {
             body: Bytes::from("test body"),
-            headers: HashMap::new(),
+            headers: ResponseHeaders::new(),
             status_code: 200,
             content_type: Some("text/html".to_string()),
             etag: None,
             last_modified: None,
             created_at: Instant::now(),
             expires_at: Instant::now() + Duration::from_secs(3600),
+            expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
             size: 9,
             stale_if_error_secs: None,
             access_count: 1, // Below promotion threshold
             last_accessed: Instant::now(),
             cache_tags: Vec::new(),
+            headers_only: false,
+            fetch_duration: Duration::ZERO,
+            immutable: false,
         };
 
         cache.set("test-key".to_string(), entry);
@@ -1313,29 +3156,29 @@ mod tests {
         assert!(hierarchy_stats.promotions > 0 || hierarchy_stats.l1_hits > 0);
     }
 
-    #[test]
-    fn test_cache_stats_with_hierarchy() {
-        use crate::config::CacheConfig;
-
-        let config = CacheConfig::default();
+    fn cache_stats_hot_and_tagged_entries(config: CacheConfig) {
         let cache = Cache::new(config);
 
         // Add some entries
         for i in 0..5 {
             let entry = CacheEntry {
                 body: Bytes::from(format!("body {}", i)),
-                headers: HashMap::new(),
+                headers: ResponseHeaders::new(),
                 status_code: 200,
                 content_type: Some("text/html".to_string()),
                 etag: None,
                 last_modified: None,
                 created_at: Instant::now(),
                 expires_at: Instant::now() + Duration::from_secs(3600),
+                expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
                 size: 10,
                 stale_if_error_secs: None,
                 access_count: i as u32, // Varying access counts
                 last_accessed: Instant::now(),
                 cache_tags: Vec::new(),
+                headers_only: false,
+                fetch_duration: Duration::ZERO,
+                immutable: false,
             };
 
             cache.set(format!("key-{}", i), entry);
@@ -1350,41 +3193,41 @@ mod tests {
         assert!(stats.hot_entries >= 2); // keys 3 and 4
     }
 
-    #[test]
-    fn test_tag_and_hierarchy_integration() {
-        use crate::config::CacheConfig;
-
-        let config = CacheConfig::default();
+    fn tag_and_tier_integration(config: CacheConfig) {
         let cache = Cache::new(config);
 
         // Create entries with tags in both L1 and L2
         for i in 0..4 {
             let entry = CacheEntry {
                 body: Bytes::from(format!("body {}", i)),
-                headers: HashMap::new(),
+                headers: ResponseHeaders::new(),
                 status_code: 200,
                 content_type: Some("text/html".to_string()),
                 etag: None,
                 last_modified: None,
                 created_at: Instant::now(),
                 expires_at: Instant::now() + Duration::from_secs(3600),
+                expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
                 size: 10,
                 stale_if_error_secs: None,
                 access_count: if i >= 2 { 3 } else { 1 }, // Half hot, half cold
                 last_accessed: Instant::now(),
                 cache_tags: Vec::new(),
+                headers_only: false,
+                fetch_duration: Duration::ZERO,
+                immutable: false,
             };
 
             cache.set(format!("key-{}", i), entry);
             cache.add_tags(&format!("key-{}", i), vec!["test-tag".to_string()]);
         }
 
-        // Verify tags work across both tiers
+        // Verify tags work wherever the entries are stored
         let tag_stats = cache.get_tag_stats("test-tag");
         assert!(tag_stats.is_some());
         assert_eq!(tag_stats.unwrap().entry_count, 4);
 
-        // Invalidate by tag should remove from both tiers
+        // Invalidate by tag should remove from every tier
         let purged = cache.invalidate_by_tag("test-tag");
         assert_eq!(purged, 4);
 
@@ -1392,4 +3235,1216 @@ mod tests {
         let stats = cache.stats();
         assert_eq!(stats.total_entries, 0);
     }
+
+    fn export_partitions_cover_every_key_once(config: CacheConfig) {
+        let cache = Cache::new(config);
+        let now = Instant::now();
+        for i in 0..200 {
+            let entry = CacheEntry {
+                body: Bytes::from("x"),
+                headers: ResponseHeaders::new(),
+                status_code: 200,
+                content_type: None,
+                etag: None,
+                last_modified: None,
+                created_at: now,
+                expires_at: now + Duration::from_secs(600),
+                expires_at_wall: SystemTime::now() + Duration::from_secs(600),
+                size: 1,
+                stale_if_error_secs: None,
+                access_count: 0,
+                last_accessed: now,
+                cache_tags: Vec::new(),
+                headers_only: false,
+                fetch_duration: Duration::ZERO,
+                immutable: false,
+            };
+            cache.set(format!("origin{}/assets/{}.js?v=1|vary:accept-encoding=gzip", i % 3, i), entry);
+        }
+
+        let partitions = 7;
+        let mut keys: Vec<String> = (0..partitions)
+            .flat_map(|p| cache.export_partition(p, partitions))
+            .map(|r| r.key)
+            .collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 200);
+
+        let record = cache
+            .export_partition(0, 1)
+            .into_iter()
+            .find(|r| r.key.starts_with("origin1/assets/1.js"))
+            .unwrap();
+        assert_eq!(record.origin, "origin1");
+        assert_eq!(record.path, "/assets/1.js?v=1");
+        assert!(record.ttl_remaining_secs > 500);
+    }
+
+    /// Wall-clock time matching a monotonic one
+    fn wall_time(at: Instant) -> SystemTime {
+        let now = Instant::now();
+        match at.checked_duration_since(now) {
+            Some(ahead) => SystemTime::now() + ahead,
+            None => SystemTime::now() - now.duration_since(at),
+        }
+    }
+
+    fn entry_expiring_at(expires_at: Instant, size: usize, access_count: u32) -> CacheEntry {
+        CacheEntry {
+            body: Bytes::from("x"),
+            headers: ResponseHeaders::new(),
+            status_code: 200,
+            content_type: None,
+            etag: None,
+            last_modified: None,
+            created_at: Instant::now(),
+            expires_at,
+            expires_at_wall: wall_time(expires_at),
+            size,
+            stale_if_error_secs: None,
+            access_count,
+            last_accessed: Instant::now(),
+            cache_tags: Vec::new(),
+            headers_only: false,
+            fetch_duration: Duration::ZERO,
+            immutable: false,
+        }
+    }
+
+    fn fresh_entry(size: usize, access_count: u32) -> CacheEntry {
+        entry_expiring_at(
+            Instant::now() + Duration::from_secs(3600),
+            size,
+            access_count,
+        )
+    }
+
+    fn partial_entry(content_range: &str, body: &'static str) -> CacheEntry {
+        let mut entry = fresh_entry(body.len(), 0);
+        entry.body = Bytes::from(body);
+        entry.status_code = 206;
+        entry.headers.insert("content-range", content_range);
+        entry
+    }
+
+    fn range_entries_follow_their_object(config: CacheConfig) {
+        let cache = Cache::new(config);
+        let key = "web/video.mp4|vary:accept-encoding=identity";
+        cache.set(
+            range_key(key, &ByteRange::new(0, 3)),
+            partial_entry("bytes 0-3/10", "abcd"),
+        );
+        cache.set(
+            range_key(key, &ByteRange::new(6, 9)),
+            partial_entry("bytes 6-9/10", "ghij"),
+        );
+
+        let hit = cache.get_range(key, "bytes=1-2").unwrap();
+        assert_eq!(hit.body(), Bytes::from("bc"));
+        assert_eq!(
+            hit.headers().get("content-range").map(|s| s.as_str()),
+            Some("bytes 1-2/10")
+        );
+        let hit = cache.get_range(key, "bytes=-3").unwrap();
+        assert_eq!(hit.body(), Bytes::from("hij"));
+        // Spans both parts, so only the whole object can answer it
+        assert!(cache.get_range(key, "bytes=2-7").is_none());
+        assert!(cache.get_range("web/other.mp4", "bytes=0-1").is_none());
+
+        let stats = cache.stats();
+        assert_eq!(stats.range_entries, 2);
+        assert_eq!(stats.range_size_bytes, 8);
+        assert_eq!(stats.total_size_bytes, 8);
+
+        // Purging the object's key takes its parts with it
+        assert!(cache.invalidate(key));
+        assert!(cache.get_range(key, "bytes=0-1").is_none());
+        assert_eq!(cache.stats().total_size_bytes, 0);
+
+        // As does purging every variant of the URL
+        cache.set(
+            range_key(key, &ByteRange::new(0, 3)),
+            partial_entry("bytes 0-3/10", "abcd"),
+        );
+        assert_eq!(cache.invalidate_variants("web/video.mp4"), 1);
+        assert_eq!(cache.stats().range_entries, 0);
+        assert!(cache.range_keys.is_empty());
+    }
+
+    fn shed_evicts_coldest_entries(config: CacheConfig) {
+        let cache = Cache::new(config);
+        for i in 0..10 {
+            cache.set(format!("cold-{}", i), fresh_entry(10, 0));
+        }
+        cache.set("hot".to_string(), fresh_entry(10, 50));
+
+        // 20% of 11 entries rounds up to 3
+        assert_eq!(cache.shed(20), 3);
+        let stats = cache.stats();
+        assert_eq!(stats.total_entries, 8);
+        assert_eq!(stats.evictions, 3);
+        assert!(cache.get("hot").is_some());
+
+        assert_eq!(cache.shed(100), 8);
+        assert_eq!(cache.stats().total_size_bytes, 0);
+    }
+
+    fn pinned_entries_survive_an_eviction_storm(mut config: CacheConfig) {
+        // 1 MiB cache, so at most 512 KiB may be pinned
+        config.max_size_mb = 1;
+        let cache = Cache::new(config);
+        assert_eq!(cache.max_pinned_bytes(), 512 * 1024);
+        cache.set("web/app.js".to_string(), fresh_entry(100_000, 0));
+        cache.set("web/index.html".to_string(), fresh_entry(100_000, 0));
+        cache.set("web/index.html|vary:x".to_string(), fresh_entry(100_000, 0));
+        cache.set("web/big.bin".to_string(), fresh_entry(400_000, 0));
+
+        let outcome = cache.pin(["web/app.js".to_string(), "web/missing".to_string()]);
+        assert_eq!(outcome.pinned, ["web/app.js"]);
+        assert_eq!(outcome.not_cached, ["web/missing"]);
+        let outcome = cache.pin_prefix("web/index.html");
+        assert_eq!(outcome.pinned.len(), 2);
+        // Another 400 KB would take the pins past the cap
+        let outcome = cache.pin(["web/big.bin".to_string()]);
+        assert_eq!(outcome.over_limit, ["web/big.bin"]);
+
+        // Hot entries that aren't pinned go under pressure; pinned cold ones stay
+        for i in 0..50 {
+            cache.set(format!("web/page-{}", i), fresh_entry(100_000, 10));
+            cache.get(&format!("web/page-{}", i));
+        }
+        cache.shed(100);
+        let stats = cache.stats();
+        assert_eq!(stats.total_entries, 3);
+        assert_eq!(stats.pinned_entries, 3);
+        assert_eq!(stats.pinned_size_bytes, 300_000);
+        assert!(cache.get("web/app.js").is_some());
+        assert!(cache.get("web/big.bin").is_none());
+
+        // Pinned entries can still be purged; the pin waits for the next fill
+        assert!(cache.invalidate("web/app.js"));
+        let pins = cache.pins();
+        assert_eq!(pins.len(), 3);
+        assert_eq!(pins[0].key, "web/app.js");
+        assert_eq!(pins[0].size_bytes, None);
+        cache.set("web/app.js".to_string(), fresh_entry(100_000, 0));
+        assert!(cache.is_pinned("web/app.js"));
+        assert_eq!(cache.shed(100), 0);
+
+        assert_eq!(cache.unpin(&["web/app.js".to_string()]), 1);
+        assert_eq!(cache.unpin_prefix("web/index.html"), 2);
+        assert_eq!(cache.shed(100), 3);
+        assert_eq!(cache.stats().pinned_entries, 0);
+    }
+
+    fn pinned_entries_still_expire(mut config: CacheConfig) {
+        config.max_size_mb = 1;
+        let cache = Cache::new(config);
+        let expired = entry_expiring_at(Instant::now() - Duration::from_secs(3600), 100_000, 0);
+        cache.set("web/old.js".to_string(), expired);
+        assert_eq!(cache.pin(["web/old.js".to_string()]).pinned.len(), 1);
+
+        // Making room drops expired entries first, pinned or not
+        for i in 0..10 {
+            cache.set(format!("web/page-{}", i), fresh_entry(100_000, 0));
+        }
+        assert_eq!(cache.stats().pinned_entries, 0);
+        assert!(cache.is_pinned("web/old.js"));
+    }
+
+    fn stale_variant(cache_control: &str) -> CacheEntry {
+        let mut entry = entry_expiring_at(Instant::now() - Duration::from_secs(5), 10, 3);
+        entry.created_at = Instant::now() - Duration::from_secs(65);
+        entry.etag = Some("\"v1\"".to_string());
+        entry.headers = [
+            ("content-type", "text/css"),
+            ("cache-control", cache_control),
+            ("etag", "\"v1\""),
+            ("vary", "Accept-Encoding"),
+        ]
+        .into_iter()
+        .collect();
+        entry
+    }
+
+    fn refresh_merges_304_into_revalidated_variant(config: CacheConfig) {
+        let cache = Cache::new(config);
+        cache.set("k|gzip".to_string(), stale_variant("max-age=60"));
+        cache.set("k|identity".to_string(), stale_variant("max-age=60"));
+        let untouched = cache.get_stale_for_error("k|identity").unwrap();
+
+        let slot = cache.reserve("k|gzip");
+        let requested_at = slot.requested_at();
+        let not_modified: ResponseHeaders = [
+            ("cache-control", "max-age=600, stale-if-error=30"),
+            ("etag", "W/\"v1\""),
+            ("content-type", "application/json"),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            cache.refresh(slot, &not_modified),
+            RefreshOutcome::Refreshed(FillOutcome::Stored)
+        );
+
+        let (entry, status) = cache.get("k|gzip").unwrap();
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(entry.body, Bytes::from("x"));
+        assert_eq!(entry.size, 10);
+        assert_eq!(entry.created_at, requested_at);
+        assert_eq!(entry.expires_at, requested_at + Duration::from_secs(600));
+        assert_eq!(entry.stale_if_error_secs, Some(30));
+        assert_eq!(entry.etag.as_deref(), Some("W/\"v1\""));
+        assert_eq!(entry.headers.get("content-type").unwrap(), "text/css");
+        assert_eq!(
+            entry.headers.get("cache-control").unwrap(),
+            "max-age=600, stale-if-error=30"
+        );
+
+        // The sibling variant is left for its own revalidation
+        let sibling = cache.get_stale_for_error("k|identity").unwrap();
+        assert_eq!(sibling.headers, untouched.headers);
+        assert_eq!(sibling.expires_at, untouched.expires_at);
+    }
+
+    fn refresh_rejects_304_for_other_variant(config: CacheConfig) {
+        let cache = Cache::new(config);
+        cache.set("k|gzip".to_string(), stale_variant("max-age=60"));
+        let before = cache.get_stale_for_error("k|gzip").unwrap();
+
+        let other: ResponseHeaders = [("etag", "\"v2\""), ("cache-control", "max-age=600")]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            cache.refresh(cache.reserve("k|gzip"), &other),
+            RefreshOutcome::Mismatch(NotModifiedMismatch::Validator)
+        );
+        let revary: ResponseHeaders = [("vary", "Accept-Language")].into_iter().collect();
+        assert_eq!(
+            cache.refresh(cache.reserve("k|gzip"), &revary),
+            RefreshOutcome::Mismatch(NotModifiedMismatch::Vary)
+        );
+        let after = cache.get_stale_for_error("k|gzip").unwrap();
+        assert_eq!(after.headers, before.headers);
+        assert_eq!(after.expires_at, before.expires_at);
+
+        assert_eq!(
+            cache.refresh(cache.reserve("gone"), &ResponseHeaders::new()),
+            RefreshOutcome::Missing
+        );
+    }
+
+    fn paused_stores_reject_fills(config: CacheConfig) {
+        let cache = Cache::new(config);
+        cache.set("kept".to_string(), fresh_entry(10, 0));
+
+        cache.set_stores_paused(true);
+        let slot = cache.reserve("new");
+        assert_eq!(cache.fill(slot, fresh_entry(10, 0)), FillOutcome::Rejected);
+        cache.set("other".to_string(), fresh_entry(10, 0));
+        assert!(cache.get("new").is_none());
+        assert!(cache.get("other").is_none());
+        assert!(cache.get("kept").is_some());
+
+        cache.set_stores_paused(false);
+        let slot = cache.reserve("new");
+        assert_eq!(cache.fill(slot, fresh_entry(10, 0)), FillOutcome::Stored);
+    }
+
+    fn disabled_cache_stores_nothing(config: CacheConfig) {
+        let mut disabled = config.clone();
+        disabled.enabled = false;
+        let mut zero_size = config;
+        zero_size.max_size_mb = 0;
+
+        for config in [disabled, zero_size] {
+            let cache = Cache::new(config);
+            assert!(!cache.enabled());
+            cache.set("web/a".to_string(), fresh_entry(10, 5));
+            let slot = cache.reserve("web/b");
+            assert_eq!(cache.fill(slot, fresh_entry(10, 0)), FillOutcome::Rejected);
+            assert!(cache.get("web/a").is_none());
+            assert!(cache.get_stale_for_error("web/a").is_none());
+            assert_eq!(cache.cleanup_expired(), 0);
+
+            let stats = cache.stats();
+            assert!(!stats.enabled);
+            assert_eq!((stats.hits, stats.misses, stats.evictions), (0, 0, 0));
+            assert_eq!((stats.total_entries, stats.max_size_bytes), (0, 0));
+            assert_eq!(stats.hit_ratio, 0.0);
+        }
+    }
+
+    fn invalidate_prefix_removes_matching_entries(config: CacheConfig) {
+        let cache = Cache::new(config);
+        cache.set("web/a/1".to_string(), fresh_entry(10, 0));
+        cache.set("web/a/2".to_string(), fresh_entry(10, 5));
+        cache.set("web/b/1".to_string(), fresh_entry(10, 0));
+
+        assert_eq!(cache.invalidate_prefix("web/a/"), 2);
+        assert!(cache.get("web/a/1").is_none());
+        assert!(cache.get("web/a/2").is_none());
+        assert!(cache.get("web/b/1").is_some());
+        assert_eq!(cache.stats().total_size_bytes, 10);
+    }
+
+    fn cleanup_expired_removes_entries_past_stale_window(config: CacheConfig) {
+        let cache = Cache::new(config);
+        let long_ago = Instant::now() - Duration::from_secs(120);
+        cache.set(
+            "expired-cold".to_string(),
+            entry_expiring_at(long_ago, 10, 0),
+        );
+        cache.set(
+            "expired-hot".to_string(),
+            entry_expiring_at(long_ago, 10, 5),
+        );
+        cache.set("fresh".to_string(), fresh_entry(10, 0));
+
+        assert_eq!(cache.cleanup_expired(), 2);
+        let stats = cache.stats();
+        assert_eq!(stats.total_entries, 1);
+        assert_eq!(stats.total_size_bytes, 10);
+        assert_eq!(stats.evictions, 2);
+    }
+
+    fn eviction_keeps_cache_within_max_size(config: CacheConfig) {
+        let config = CacheConfig {
+            max_size_mb: 1,
+            ..config
+        };
+        let cache = Cache::new(config);
+        let size = 400 * 1024;
+        for i in 0..4 {
+            cache.set(format!("key-{}", i), fresh_entry(size, i % 2 * 5));
+        }
+
+        let stats = cache.stats();
+        assert!(stats.total_size_bytes <= 1024 * 1024);
+        assert!(stats.evictions >= 1);
+        assert_eq!(stats.total_size_bytes, stats.total_entries * size);
+    }
+
+    fn overwriting_entry_keeps_size_accounting(config: CacheConfig) {
+        let cache = Cache::new(config);
+        cache.set("key".to_string(), fresh_entry(10, 0));
+        cache.set("key".to_string(), fresh_entry(25, 5));
+        cache.set("key".to_string(), fresh_entry(40, 0));
+
+        let stats = cache.stats();
+        assert_eq!(stats.total_entries, 1);
+        assert_eq!(stats.total_size_bytes, 40);
+    }
+
+    fn key_hash_index_follows_the_tiers(mut config: CacheConfig) {
+        config.max_size_mb = 1;
+        config.key_hash_index.enabled = true;
+        config.key_hash_index.max_entries = 3;
+        let cache = Cache::new(config);
+        cache.set("a".to_string(), fresh_entry(10, 0));
+        cache.set("b".to_string(), fresh_entry(10, 0));
+        assert_eq!(cache.key_for_hash(&key_hash("a")).as_deref(), Some("a"));
+        assert_eq!(
+            cache
+                .key_for_hash(&key_hash("b").to_ascii_uppercase())
+                .as_deref(),
+            Some("b")
+        );
+
+        // Replacing an entry keeps its key indexed once
+        cache.set("a".to_string(), fresh_entry(20, 0));
+        assert!(cache.invalidate("a"));
+        assert!(cache.key_for_hash(&key_hash("a")).is_none());
+        assert_eq!(cache.stats().key_hash_index_entries, 1);
+
+        // Evicted keys leave the index with their entries
+        for i in 0..2 {
+            cache.set(format!("big-{}", i), fresh_entry(600 * 1024, 0));
+        }
+        let stats = cache.stats();
+        assert!(stats.evictions >= 1);
+        assert_eq!(stats.key_hash_index_entries, stats.total_entries);
+
+        // Past max_entries new keys are cached but not indexed
+        for i in 0..4 {
+            cache.set(format!("small-{}", i), fresh_entry(10, 0));
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.key_hash_index_entries, 3);
+        assert!(stats.total_entries > 3);
+        assert!(cache.key_for_hash(&key_hash("small-3")).is_none());
+
+        cache.purge_all();
+        assert_eq!(cache.stats().key_hash_index_entries, 0);
+        assert!(!Cache::new(CacheConfig::default()).key_hash_index_enabled());
+    }
+
+    fn tag_stats_sum_entry_sizes(config: CacheConfig) {
+        let cache = Cache::new(config);
+        cache.set("cold".to_string(), fresh_entry(10, 0));
+        cache.set("hot".to_string(), fresh_entry(30, 5));
+        cache.add_tags("cold", vec!["shared".to_string()]);
+        cache.add_tags("hot", vec!["shared".to_string()]);
+
+        let stats = cache.get_tag_stats("shared").unwrap();
+        assert_eq!(stats.entry_count, 2);
+        assert_eq!(stats.total_size_bytes, 40);
+    }
+
+    fn stale_for_error_finds_expired_entry(config: CacheConfig) {
+        let cache = Cache::new(config);
+        let mut entry = entry_expiring_at(Instant::now() - Duration::from_secs(120), 10, 5);
+        entry.stale_if_error_secs = Some(600);
+        cache.set("key".to_string(), entry);
+
+        assert!(cache.get_stale_for_error("key").is_some());
+        assert!(cache.get_stale_for_error("missing").is_none());
+    }
+
+    fn purge_all_empties_every_tier(config: CacheConfig) {
+        let cache = Cache::new(config);
+        cache.set("cold".to_string(), fresh_entry(10, 0));
+        cache.set("hot".to_string(), fresh_entry(10, 5));
+        cache.add_tags("hot", vec!["tag".to_string()]);
+
+        assert_eq!(cache.purge_all(), 2);
+        let stats = cache.stats();
+        assert_eq!(stats.total_entries, 0);
+        assert_eq!(stats.total_size_bytes, 0);
+        assert_eq!(stats.total_tags, 0);
+        assert!(stats.by_content_type.is_empty());
+        assert_eq!(cache.export_partition(0, 1).len(), 0);
+    }
+
+    fn variants_lists_every_cached_variant(config: CacheConfig) {
+        let cache = Cache::new(config);
+        for key in [
+            "web/a.html|vary:accept-encoding=gzip",
+            "web/a.html",
+            "web/a.html|vary:accept-encoding=br",
+            "web/a.html.bak",
+            "web/a.html?v=1",
+        ] {
+            cache.set(key.to_string(), fresh_entry(10, 5));
+        }
+
+        let keys: Vec<String> = cache
+            .variants("web/a.html")
+            .into_iter()
+            .map(|record| record.key)
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "web/a.html",
+                "web/a.html|vary:accept-encoding=br",
+                "web/a.html|vary:accept-encoding=gzip",
+            ]
+        );
+        assert!(cache.variants("web/b.html").is_empty());
+    }
+
+    /// Content-type totals recomputed by scanning every tier
+    fn scan_content_types(cache: &Cache) -> BTreeMap<String, ContentTypeStats> {
+        let mut totals = BTreeMap::<String, ContentTypeStats>::new();
+        cache.for_each_entry(|_, entry, _| {
+            let stats = totals
+                .entry(content_type_bucket(entry.content_type.as_deref()))
+                .or_default();
+            stats.entry_count += 1;
+            stats.total_size_bytes += entry.size;
+        });
+        totals
+    }
+
+    fn content_type_totals_match_full_scan(config: CacheConfig) {
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+
+        let content_types = [
+            Some("text/html; charset=utf-8"),
+            Some("TEXT/HTML"),
+            Some("application/javascript"),
+            Some("image/png"),
+            Some("image/webp"),
+            Some("nonsense"),
+            None,
+        ];
+        let cache = Cache::new(CacheConfig {
+            max_size_mb: 1,
+            ..config
+        });
+        let mut rng = StdRng::seed_from_u64(1672);
+
+        for op in 0..5000 {
+            let key = format!("key-{}", rng.random_range(0..200));
+            match rng.random_range(0..100) {
+                // Stores and overwrites, some hot enough for L1, some evicting
+                0..45 => {
+                    let mut entry =
+                        fresh_entry(rng.random_range(0..64 * 1024), rng.random_range(0..8));
+                    entry.content_type =
+                        content_types[rng.random_range(0..content_types.len())].map(String::from);
+                    if rng.random_bool(0.1) {
+                        entry.expires_at = Instant::now() - Duration::from_secs(3600);
+                        entry.expires_at_wall = wall_time(entry.expires_at);
+                    }
+                    cache.set(key, entry);
+                }
+                // Hits, which promote once the threshold is reached
+                45..75 => {
+                    cache.get(&key);
+                }
+                75..88 => {
+                    cache.invalidate(&key);
+                }
+                88..92 => {
+                    cache.invalidate_prefix(&format!("key-{}", rng.random_range(0..10)));
+                }
+                92..96 => {
+                    cache.shed(rng.random_range(0..30));
+                }
+                96..99 => {
+                    cache.cleanup_expired();
+                }
+                _ => {
+                    cache.purge_all();
+                }
+            }
+
+            if op % 100 == 0 {
+                assert_eq!(
+                    cache.content_type_stats(),
+                    scan_content_types(&cache),
+                    "op {}",
+                    op
+                );
+            }
+        }
+
+        if cache.config.hierarchy.enabled {
+            let hierarchy = cache.get_hierarchy_stats();
+            assert!(hierarchy.promotions > 0 && hierarchy.demotions > 0);
+        }
+        let stats = cache.stats();
+        assert!(stats.evictions > 0);
+        assert_eq!(stats.by_content_type, scan_content_types(&cache));
+        let by_type = stats.by_content_type.values();
+        assert_eq!(
+            by_type.clone().map(|s| s.entry_count).sum::<usize>(),
+            stats.total_entries
+        );
+        assert_eq!(
+            by_type.map(|s| s.total_size_bytes).sum::<usize>(),
+            stats.total_size_bytes
+        );
+    }
+
+    fn headers_only_entries_serve_head_but_not_get(config: CacheConfig) {
+        let cache = Cache::new(config);
+        let mut entry = fresh_entry(0, 5);
+        entry.body = Bytes::new();
+        entry.headers_only = true;
+        entry.headers.insert("content-length", "4294967296");
+        cache.set("big".to_string(), entry);
+
+        assert!(cache.get("big").is_none());
+        assert_eq!(cache.headers_only_length("big"), Some(4294967296));
+        let (entry, status) = cache.get_head("big").unwrap();
+        assert!(entry.headers_only);
+        assert_eq!(status, CacheStatus::Hit);
+
+        // A GET fill replaces it with a full entry usable by both methods
+        cache.set("big".to_string(), fresh_entry(10, 0));
+        assert_eq!(cache.headers_only_length("big"), None);
+        assert!(!cache.get("big").unwrap().0.headers_only);
+        assert!(!cache.get_head("big").unwrap().0.headers_only);
+        assert_eq!(cache.stats().total_size_bytes, 10);
+    }
+
+    fn config_with_hierarchy(enabled: bool) -> CacheConfig {
+        let mut config = CacheConfig::default();
+        config.hierarchy.enabled = enabled;
+        config
+    }
+
+    /// Runs each cache test with the L1/L2 hierarchy both on and off
+    macro_rules! hierarchy_matrix {
+        ($($test:ident),* $(,)?) => {
+            mod hierarchy_on {
+                $(
+                    #[test]
+                    fn $test() {
+                        super::$test(super::config_with_hierarchy(true));
+                    }
+                )*
+            }
+
+            mod hierarchy_off {
+                $(
+                    #[test]
+                    fn $test() {
+                        super::$test(super::config_with_hierarchy(false));
+                    }
+                )*
+            }
+        };
+    }
+
+    fn extra_stale_window_serves_stale_adaptive(config: CacheConfig) {
+        let mut config = config;
+        config.adaptive_stale.enabled = true;
+        config.adaptive_stale.max_extra_stale_secs = 300;
+        let cache = Cache::new(config);
+        let now = Instant::now();
+        cache.set(
+            "recent".to_string(),
+            entry_expiring_at(now - Duration::from_secs(30), 10, 5),
+        );
+        cache.set(
+            "old".to_string(),
+            entry_expiring_at(now - Duration::from_secs(120), 10, 0),
+        );
+        cache.set(
+            "ancient".to_string(),
+            entry_expiring_at(now - Duration::from_secs(600), 10, 0),
+        );
+
+        let extra = Duration::from_secs(300);
+        assert!(cache.get("old").is_none());
+        assert_eq!(
+            cache.lookup("old", false, extra).unwrap().1,
+            CacheStatus::StaleAdaptive
+        );
+        // Inside the normal window the extension changes nothing
+        assert_eq!(
+            cache.lookup("recent", false, extra).unwrap().1,
+            CacheStatus::Stale
+        );
+        assert!(cache.lookup("ancient", false, extra).is_none());
+        assert_eq!(cache.stats().adaptive_stale_hits, 1);
+
+        // Cleanup keeps what adaptive stale may still serve
+        assert_eq!(cache.cleanup_expired(), 1);
+        assert!(cache.lookup("old", false, extra).is_some());
+    }
+
+    fn entry_created_at(body: &'static str, created_at: Instant) -> CacheEntry {
+        let mut entry = fresh_entry(body.len(), 0);
+        entry.body = Bytes::from(body);
+        entry.created_at = created_at;
+        entry
+    }
+
+    fn fill_keeps_newer_entry(config: CacheConfig) {
+        let cache = Cache::new(config);
+        let now = Instant::now();
+        let older = entry_created_at("older", now - Duration::from_secs(10));
+        let newer = entry_created_at("newer", now);
+
+        assert_eq!(cache.fill(cache.reserve("k"), newer), FillOutcome::Stored);
+        assert_eq!(
+            cache.fill(cache.reserve("k"), older),
+            FillOutcome::Superseded
+        );
+        assert_eq!(cache.get("k").unwrap().0.body, Bytes::from("newer"));
+        assert_eq!(cache.stats().total_size_bytes, "newer".len());
+
+        let newest = entry_created_at("newest", now + Duration::from_secs(1));
+        assert_eq!(cache.fill(cache.reserve("k"), newest), FillOutcome::Stored);
+        assert_eq!(cache.get("k").unwrap().0.body, Bytes::from("newest"));
+        assert_eq!(cache.stats().total_size_bytes, "newest".len());
+    }
+
+    fn fill_keeps_tag_index_in_step(config: CacheConfig) {
+        let cache = Cache::new(config);
+        let now = Instant::now();
+        let mut older = entry_created_at("older", now - Duration::from_secs(10));
+        older.cache_tags = vec!["old".to_string()];
+        let mut newer = entry_created_at("newer", now);
+        newer.cache_tags = vec!["new".to_string()];
+
+        cache.fill(cache.reserve("k"), newer);
+        cache.fill(cache.reserve("k"), older);
+        assert_eq!(cache.get_all_tags(), vec!["new".to_string()]);
+
+        // A replacement takes the old entry's tags out of the index
+        let mut newest = entry_created_at("newest", now + Duration::from_secs(1));
+        newest.cache_tags = vec!["v2".to_string()];
+        cache.fill(cache.reserve("k"), newest);
+        assert_eq!(cache.get_all_tags(), vec!["v2".to_string()]);
+        assert_eq!(cache.invalidate_by_tag("new"), 0);
+        assert_eq!(cache.invalidate_by_tag("v2"), 1);
+        assert!(cache.get("k").is_none());
+    }
+
+    fn sweep_covers_the_keyspace_in_batches(mut config: CacheConfig) {
+        config.cleanup.batch_size = 10;
+        let cache = Cache::new(config);
+        let long_ago = Instant::now() - Duration::from_secs(120);
+        for i in 0..100 {
+            let entry = if i % 4 == 0 {
+                entry_expiring_at(long_ago, 10, 0)
+            } else {
+                fresh_entry(10, 0)
+            };
+            cache.set(format!("k{}", i), entry);
+        }
+        let mut tagged = fresh_entry(10, 0);
+        tagged.cache_tags = vec!["t".to_string()];
+        cache.set("tagged".to_string(), tagged);
+        // A reference to a key that isn't cached under the tag
+        cache
+            .tag_to_keys
+            .entry("t".to_string())
+            .or_default()
+            .insert("gone".to_string());
+
+        let mut ticks = 0;
+        let mut total = SweepStats::default();
+        loop {
+            let stats = cache.sweep_expired();
+            ticks += 1;
+            total.entries_examined += stats.entries_examined;
+            total.entries_removed += stats.entries_removed;
+            total.tag_keys_removed += stats.tag_keys_removed;
+            if stats.cycle_complete {
+                break;
+            }
+            assert!(stats.entries_examined + stats.tags_examined >= 10);
+        }
+
+        assert!(ticks > 1);
+        assert_eq!(total.entries_examined, 101);
+        assert_eq!(total.entries_removed, 25);
+        assert_eq!(total.tag_keys_removed, 1);
+        assert_eq!(cache.stats().total_entries, 76);
+        assert_eq!(cache.get_tag_stats("t").unwrap().entry_count, 1);
+
+        // The next cycle starts over and finds nothing left to do
+        let stats = cache.sweep_expired();
+        assert_eq!((stats.entries_removed, stats.tag_keys_removed), (0, 0));
+    }
+
+    fn fill_prefers_full_entries_over_headers_only(config: CacheConfig) {
+        let cache = Cache::new(config);
+        let now = Instant::now();
+        let mut head = entry_created_at("", now);
+        head.headers_only = true;
+        let full = entry_created_at("body", now - Duration::from_secs(10));
+
+        cache.fill(cache.reserve("k"), head.clone());
+        assert_eq!(cache.fill(cache.reserve("k"), full), FillOutcome::Stored);
+        head.created_at = now + Duration::from_secs(1);
+        assert_eq!(
+            cache.fill(cache.reserve("k"), head),
+            FillOutcome::Superseded
+        );
+        assert_eq!(cache.get("k").unwrap().0.body, Bytes::from("body"));
+
+        // An entry past the stale window never blocks a fill
+        let expired = entry_expiring_at(now - Duration::from_secs(3600), 4, 0);
+        cache.set("old".to_string(), expired);
+        let refill = entry_created_at("refill", now - Duration::from_secs(7200));
+        assert_eq!(
+            cache.fill(cache.reserve("old"), refill),
+            FillOutcome::Stored
+        );
+    }
+
+    fn concurrent_fills_keep_later_created_entry(config: CacheConfig) {
+        let cache = Arc::new(Cache::new(config));
+        let base = Instant::now();
+
+        for round in 0..200u64 {
+            let key = format!("k{round}");
+            let early = entry_created_at("early", base + Duration::from_millis(round));
+            let late = entry_created_at("late", base + Duration::from_millis(round + 1));
+            let barrier = Arc::new(std::sync::Barrier::new(2));
+
+            let writers: Vec<_> = [early, late]
+                .into_iter()
+                .map(|entry| {
+                    let cache = cache.clone();
+                    let barrier = barrier.clone();
+                    let key = key.clone();
+                    std::thread::spawn(move || {
+                        let slot = cache.reserve(&key);
+                        barrier.wait();
+                        cache.fill(slot, entry)
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap();
+            }
+
+            assert_eq!(cache.get(&key).unwrap().0.body, Bytes::from("late"));
+        }
+        assert_eq!(cache.stats().total_size_bytes, 200 * "late".len());
+    }
+
+    fn purge_cancels_in_flight_fills(config: CacheConfig) {
+        let cache = Cache::new(config);
+        let now = Instant::now();
+
+        let slot = cache.reserve("web/a|gzip");
+        let other = cache.reserve("web/ab");
+        assert_eq!(cache.cancel_fills(|key| is_variant_of(key, "web/a")), 1);
+
+        // Slots for another key from the same fetch are cancelled too
+        let variant = slot.for_key("web/a|br".to_string());
+        assert_eq!(
+            cache.fill(variant, entry_created_at("old", now)),
+            FillOutcome::Cancelled
+        );
+        assert_eq!(
+            cache.fill(slot, entry_created_at("old", now)),
+            FillOutcome::Cancelled
+        );
+        assert!(cache.get("web/a|gzip").is_none());
+        assert_eq!(
+            cache.fill(other, entry_created_at("ab", now)),
+            FillOutcome::Stored
+        );
+
+        // A fetch started after the purge fills normally
+        assert_eq!(
+            cache.fill(cache.reserve("web/a|gzip"), entry_created_at("new", now)),
+            FillOutcome::Stored
+        );
+
+        // Finished fills are no longer tracked
+        assert_eq!(cache.cancel_fills(|_| true), 0);
+    }
+
+    fn tag_purge_cancels_in_flight_fills_with_tag(config: CacheConfig) {
+        let cache = Cache::new(config);
+        let now = Instant::now();
+        let tagged = cache.reserve("a");
+        let untagged = cache.reserve("b");
+        cache.cancel_tagged_fills("release-1");
+
+        let mut entry = entry_created_at("a", now);
+        entry.cache_tags = vec!["release-1".to_string()];
+        assert_eq!(cache.fill(tagged, entry), FillOutcome::Cancelled);
+        assert!(cache.get_all_tags().is_empty());
+
+        let mut entry = entry_created_at("b", now);
+        entry.cache_tags = vec!["release-2".to_string()];
+        assert_eq!(cache.fill(untagged, entry), FillOutcome::Stored);
+    }
+
+    fn purge_racing_fill_never_leaves_entry(config: CacheConfig) {
+        let cache = Arc::new(Cache::new(config));
+
+        for round in 0..200 {
+            let key = format!("k{round}");
+            let slot = cache.reserve(&key);
+            let barrier = Arc::new(std::sync::Barrier::new(2));
+
+            let filler = {
+                let cache = cache.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    cache.fill(slot, entry_created_at("old", Instant::now()))
+                })
+            };
+            let purger = {
+                let cache = cache.clone();
+                let key = key.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    let cancelled = cache.cancel_fills(|k| k == key);
+                    cache.invalidate(&key);
+                    cancelled
+                })
+            };
+
+            let outcome = filler.join().unwrap();
+            let cancelled = purger.join().unwrap();
+            // The purge either found the fill in flight or ran after it stored
+            assert!(cancelled == 1 || outcome == FillOutcome::Stored);
+            assert!(cache.get(&key).is_none(), "round {round}: {outcome:?}");
+        }
+        assert_eq!(cache.stats().total_size_bytes, 0);
+    }
+
+    /// Clock whose monotonic and wall readings move independently
+    struct MockClock {
+        instant: std::sync::Mutex<Instant>,
+        wall: std::sync::Mutex<SystemTime>,
+    }
+
+    impl MockClock {
+        fn new() -> Arc<Self> {
+            Arc::new(Self {
+                instant: std::sync::Mutex::new(Instant::now()),
+                wall: std::sync::Mutex::new(SystemTime::now()),
+            })
+        }
+
+        /// Host suspend or NTP step forward: only the wall clock moves
+        fn jump_wall_forward(&self, by: Duration) {
+            *self.wall.lock().unwrap() += by;
+        }
+
+        /// NTP step backwards: only the wall clock moves
+        fn jump_wall_back(&self, by: Duration) {
+            *self.wall.lock().unwrap() -= by;
+        }
+
+        /// Normal passage of time on both clocks
+        fn advance(&self, by: Duration) {
+            *self.instant.lock().unwrap() += by;
+            self.jump_wall_forward(by);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.instant.lock().unwrap()
+        }
+
+        fn wall_now(&self) -> SystemTime {
+            *self.wall.lock().unwrap()
+        }
+    }
+
+    fn entry_from_clock(clock: &dyn Clock, ttl: Duration) -> CacheEntry {
+        let mut entry = fresh_entry(10, 0);
+        entry.created_at = clock.now();
+        entry.expires_at = clock.now() + ttl;
+        entry.expires_at_wall = clock.wall_now() + ttl;
+        entry
+    }
+
+    fn cache_with_clock(expiry_clock: ExpiryClock, clock: Arc<MockClock>) -> Cache {
+        let config = CacheConfig {
+            expiry_clock,
+            stale_while_revalidate_secs: 60,
+            ..Default::default()
+        };
+        Cache::new(config).with_clock(clock)
+    }
+
+    #[test]
+    fn test_wall_clock_jump_expires_entries() {
+        for (expiry_clock, expires) in [
+            (ExpiryClock::Either, true),
+            (ExpiryClock::Wall, true),
+            (ExpiryClock::Monotonic, false),
+        ] {
+            let clock = MockClock::new();
+            let cache = cache_with_clock(expiry_clock, clock.clone());
+            cache.set(
+                "k".to_string(),
+                entry_from_clock(&*clock, Duration::from_secs(600)),
+            );
+            assert_eq!(cache.get("k").unwrap().1, CacheStatus::Hit);
+
+            // Two hours of suspend never reach the monotonic clock
+            clock.jump_wall_forward(Duration::from_secs(7200));
+            assert_eq!(cache.get("k").is_none(), expires, "{expiry_clock:?}");
+            assert_eq!(
+                cache.get_stale_for_error("k").is_none(),
+                expires,
+                "{expiry_clock:?}"
+            );
+            assert_eq!(cache.cleanup_expired(), usize::from(expires));
+        }
+    }
+
+    #[test]
+    fn test_wall_clock_jump_into_stale_window() {
+        let clock = MockClock::new();
+        let cache = cache_with_clock(ExpiryClock::Either, clock.clone());
+        cache.set(
+            "k".to_string(),
+            entry_from_clock(&*clock, Duration::from_secs(600)),
+        );
+
+        // 30s past expiry on the wall clock is inside the 60s stale window
+        clock.jump_wall_forward(Duration::from_secs(630));
+        assert_eq!(cache.get("k").unwrap().1, CacheStatus::Stale);
+        assert!(cache.get_stale_for_error("k").is_some());
+        assert_eq!(cache.cleanup_expired(), 0);
+    }
+
+    #[test]
+    fn test_wall_clock_step_back_does_not_extend_ttl() {
+        let clock = MockClock::new();
+        let cache = cache_with_clock(ExpiryClock::Either, clock.clone());
+        cache.set(
+            "k".to_string(),
+            entry_from_clock(&*clock, Duration::from_secs(600)),
+        );
+
+        // A backwards step leaves the monotonic deadline in charge
+        clock.jump_wall_back(Duration::from_secs(3600));
+        assert_eq!(cache.get("k").unwrap().1, CacheStatus::Hit);
+        clock.advance(Duration::from_secs(7200));
+        assert!(cache.get("k").is_none());
+        assert_eq!(cache.cleanup_expired(), 1);
+
+        // Wall-only expiry trusts the stepped-back clock
+        let clock = MockClock::new();
+        let cache = cache_with_clock(ExpiryClock::Wall, clock.clone());
+        cache.set(
+            "k".to_string(),
+            entry_from_clock(&*clock, Duration::from_secs(600)),
+        );
+        clock.jump_wall_back(Duration::from_secs(3600));
+        clock.advance(Duration::from_secs(1200));
+        assert_eq!(cache.get("k").unwrap().1, CacheStatus::Hit);
+    }
+
+    #[test]
+    fn test_immutable_entries_outlive_max_ttl_and_never_go_stale() {
+        let directives = parse_cache_control("public, max-age=31536000, immutable");
+        assert!(directives.immutable);
+        let mut response = ResponseHeaders::new();
+        response.insert("cache-control", "public, max-age=31536000, immutable");
+        let ttl = |cache: &Cache, directives: &CacheControlDirectives| {
+            cache.entry_ttl(directives, &response, SystemTime::now(), false)
+        };
+
+        let cache = Cache::new(CacheConfig::default());
+        assert_eq!(ttl(&cache, &directives), Duration::from_secs(31_536_000));
+        let mutable = parse_cache_control("public, max-age=31536000");
+        assert_eq!(ttl(&cache, &mutable), Duration::from_secs(86400));
+        let capped = Cache::new(CacheConfig {
+            immutable_max_ttl_secs: 2_592_000,
+            ..Default::default()
+        });
+        assert_eq!(ttl(&capped, &directives), Duration::from_secs(2_592_000));
+
+        // Inside the stale window a mutable entry is STALE; an immutable one misses
+        let clock = MockClock::new();
+        let cache = cache_with_clock(ExpiryClock::Either, clock.clone());
+        let mut immutable = entry_from_clock(&*clock, Duration::from_secs(600));
+        immutable.immutable = true;
+        cache.set("immutable".to_string(), immutable);
+        cache.set(
+            "mutable".to_string(),
+            entry_from_clock(&*clock, Duration::from_secs(600)),
+        );
+        clock.advance(Duration::from_secs(630));
+        assert_eq!(cache.get("mutable").unwrap().1, CacheStatus::Stale);
+        assert!(cache.get("immutable").is_none());
+    }
+
+    #[test]
+    fn test_early_refresh_skips_immutable_entries() {
+        let clock = MockClock::new();
+        let mut config = CacheConfig::default();
+        config.early_refresh.enabled = true;
+        let cache = Cache::new(config).with_clock(clock.clone());
+
+        let slot = cache.reserve("k");
+        clock.advance(Duration::from_secs(2));
+        let mut entry = entry_from_clock(&*clock, Duration::from_secs(1));
+        entry.immutable = true;
+        assert_eq!(cache.fill(slot, entry), FillOutcome::Stored);
+        let (entry, _) = cache.get("k").unwrap();
+        assert!(
+            cache
+                .reserve_early_refresh_with("k", &entry, 0.99)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_early_refresh_fires_near_expiry() {
+        let clock = MockClock::new();
+        let mut config = CacheConfig::default();
+        config.early_refresh.enabled = true;
+        let cache = Cache::new(config).with_clock(clock.clone());
+
+        // The fill records how long the fetch took
+        let slot = cache.reserve("k");
+        clock.advance(Duration::from_secs(2));
+        let entry = entry_from_clock(&*clock, Duration::from_secs(60));
+        assert_eq!(cache.fill(slot, entry), FillOutcome::Stored);
+        let (entry, _) = cache.get("k").unwrap();
+        assert_eq!(entry.fetch_duration, Duration::from_secs(2));
+
+        // With 60s left a 2s fetch needs a draw above 1 - e^-30 to fire
+        assert!(
+            cache
+                .reserve_early_refresh_with("k", &entry, 0.99)
+                .is_none()
+        );
+
+        // 1s left: fires once -ln(1 - draw) >= 0.5, i.e. draw >= ~0.39
+        clock.advance(Duration::from_secs(59));
+        assert!(cache.reserve_early_refresh_with("k", &entry, 0.3).is_none());
+        let slot = cache.reserve_early_refresh_with("k", &entry, 0.5).unwrap();
+
+        // Only one refresh runs at a time
+        assert!(
+            cache
+                .reserve_early_refresh_with("k", &entry, 0.99)
+                .is_none()
+        );
+        drop(slot);
+        assert!(
+            cache
+                .reserve_early_refresh_with("k", &entry, 0.99)
+                .is_some()
+        );
+        assert_eq!(cache.stats().early_refreshes, 2);
+
+        // Entries that never went through a fill have no delta to go on
+        let unmeasured = entry_from_clock(&*clock, Duration::from_millis(1));
+        assert!(
+            cache
+                .reserve_early_refresh_with("u", &unmeasured, 0.99)
+                .is_none()
+        );
+
+        let disabled = Cache::new(CacheConfig::default()).with_clock(clock.clone());
+        assert!(
+            disabled
+                .reserve_early_refresh_with("k", &entry, 0.99)
+                .is_none()
+        );
+    }
+
+    hierarchy_matrix!(
+        cache_tags_basic,
+        cache_tags_invalidation,
+        cache_stats_hot_and_tagged_entries,
+        tag_and_tier_integration,
+        export_partitions_cover_every_key_once,
+        invalidate_prefix_removes_matching_entries,
+        cleanup_expired_removes_entries_past_stale_window,
+        eviction_keeps_cache_within_max_size,
+        overwriting_entry_keeps_size_accounting,
+        key_hash_index_follows_the_tiers,
+        tag_stats_sum_entry_sizes,
+        stale_for_error_finds_expired_entry,
+        purge_all_empties_every_tier,
+        headers_only_entries_serve_head_but_not_get,
+        content_type_totals_match_full_scan,
+        variants_lists_every_cached_variant,
+        extra_stale_window_serves_stale_adaptive,
+        fill_keeps_newer_entry,
+        fill_keeps_tag_index_in_step,
+        sweep_covers_the_keyspace_in_batches,
+        fill_prefers_full_entries_over_headers_only,
+        concurrent_fills_keep_later_created_entry,
+        purge_cancels_in_flight_fills,
+        tag_purge_cancels_in_flight_fills_with_tag,
+        purge_racing_fill_never_leaves_entry,
+        shed_evicts_coldest_entries,
+        paused_stores_reject_fills,
+        disabled_cache_stores_nothing,
+        refresh_merges_304_into_revalidated_variant,
+        refresh_rejects_304_for_other_variant,
+        range_entries_follow_their_object,
+        pinned_entries_survive_an_eviction_storm,
+        pinned_entries_still_expire,
+    );
 }
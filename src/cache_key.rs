@@ -0,0 +1,533 @@
+//! Structured cache keys
+//!
+//! An entry's key is built from the request's origin, path and query, the
+//! request header values its response varies on, the headers and cookies
+//! configured in `cache.key`, and, for a partial entry, the byte range it
+//! holds. Entries are stored under the canonical string form:
+//!
+//! `origin/path?query|vary:name=value|...|key:h.name=value|c.name|...|range=0-99`
+//!
+//! A `|` inside any field is written `%7C`, the same character as far as a
+//! URL is concerned, so every key parses back into its fields unambiguously.
+//! Prefix matching works on those fields: `web/foo` covers `/foo` and
+//! `/foo/bar` on origin `web`, never `/foobar`.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Separates a partial entry's byte range from the rest of its key
+pub const RANGE_SEGMENT: &str = "|range=";
+
+/// Written in place of `|` inside a field
+const ESCAPED_SEPARATOR: &str = "%7C";
+
+/// A cache key, field by field
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheKey {
+    pub origin: String,
+    /// Always starts with `/`
+    pub path: String,
+    /// `None` for no query or an empty one
+    pub query: Option<String>,
+    /// Request header values the response varies on, in its Vary's order
+    pub vary: Vec<(String, String)>,
+    /// Headers and cookies configured in `cache.key`, sorted by name
+    pub dimensions: Vec<KeyDimension>,
+    /// Byte range held by a partial entry
+    pub range: Option<(u64, u64)>,
+}
+
+/// A configured header or cookie value; `None` when the request lacks it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyDimension {
+    Header(String, Option<String>),
+    Cookie(String, Option<String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CacheKeyError {
+    #[error("Cache key {0:?} has no origin and path")]
+    NoPath(String),
+
+    #[error("Unexpected segment {segment:?} in cache key {key:?}")]
+    UnexpectedSegment { key: String, segment: String },
+}
+
+impl CacheKey {
+    /// Key of `path` (with or without its leading slash) on `origin`
+    pub fn new(origin: &str, path: &str, query: Option<&str>) -> Self {
+        Self {
+            origin: origin.to_string(),
+            path: format!("/{}", path.strip_prefix('/').unwrap_or(path)),
+            query: query.filter(|q| !q.is_empty()).map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    /// Parse a key's canonical form
+    pub fn parse(key: &str) -> Result<Self, CacheKeyError> {
+        let unexpected = |segment: &str| CacheKeyError::UnexpectedSegment {
+            key: key.to_string(),
+            segment: segment.to_string(),
+        };
+
+        let mut segments = key.split('|');
+        let base = segments.next().unwrap_or_default();
+        let (origin, rest) = match base.find('/') {
+            Some(slash) if slash > 0 => base.split_at(slash),
+            _ => return Err(CacheKeyError::NoPath(key.to_string())),
+        };
+        let (path, query) = match rest.split_once('?') {
+            Some((path, query)) => (path, Some(query).filter(|q| !q.is_empty())),
+            None => (rest, None),
+        };
+        let mut parsed = Self {
+            origin: unescape(origin),
+            path: unescape(path),
+            query: query.map(unescape),
+            ..Default::default()
+        };
+
+        #[derive(PartialEq, PartialOrd)]
+        enum Section {
+            Base,
+            Vary,
+            Dimensions,
+        }
+        let segments: Vec<&str> = segments.collect();
+        let mut section = Section::Base;
+        for (i, segment) in segments.iter().copied().enumerate() {
+            // The range is always last, so a Vary on Range can't pass for one
+            if i + 1 == segments.len()
+                && let Some(range) = segment.strip_prefix("range=").and_then(parse_range)
+            {
+                parsed.range = Some(range);
+                break;
+            }
+
+            if section == Section::Base
+                && let Some(first) = segment.strip_prefix("vary:")
+            {
+                parsed
+                    .vary
+                    .push(parse_vary(first).ok_or_else(|| unexpected(segment))?);
+                section = Section::Vary;
+            } else if section < Section::Dimensions
+                && let Some(first) = segment.strip_prefix("key:")
+            {
+                parsed
+                    .dimensions
+                    .push(parse_dimension(first).ok_or_else(|| unexpected(segment))?);
+                section = Section::Dimensions;
+            } else if section == Section::Vary {
+                parsed
+                    .vary
+                    .push(parse_vary(segment).ok_or_else(|| unexpected(segment))?);
+            } else if section == Section::Dimensions {
+                parsed
+                    .dimensions
+                    .push(parse_dimension(segment).ok_or_else(|| unexpected(segment))?);
+            } else {
+                return Err(unexpected(segment));
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// The key shared by every variant of this key's URL
+    pub fn base(&self) -> Self {
+        Self {
+            origin: self.origin.clone(),
+            path: self.path.clone(),
+            query: self.query.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether both keys are for the same URL, whatever their variant
+    pub fn same_url(&self, other: &Self) -> bool {
+        self.origin == other.origin && self.path == other.path && self.query == other.query
+    }
+
+    /// This key with the byte range of a partial entry
+    pub fn with_range(mut self, start: u64, end: u64) -> Self {
+        self.range = Some((start, end));
+        self
+    }
+}
+
+impl FromStr for CacheKey {
+    type Err = CacheKeyError;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        Self::parse(key)
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", escape(&self.origin), escape(&self.path))?;
+        if let Some(query) = &self.query {
+            write!(f, "?{}", escape(query))?;
+        }
+        for (i, (name, value)) in self.vary.iter().enumerate() {
+            let separator = if i == 0 { "|vary:" } else { "|" };
+            write!(f, "{}{}={}", separator, escape(name), escape(value))?;
+        }
+        for (i, dimension) in self.dimensions.iter().enumerate() {
+            f.write_str(if i == 0 { "|key:" } else { "|" })?;
+            let (kind, name, value) = match dimension {
+                KeyDimension::Header(name, value) => ("h", name, value),
+                KeyDimension::Cookie(name, value) => ("c", name, value),
+            };
+            write!(f, "{}.{}", kind, escape(name))?;
+            if let Some(value) = value {
+                write!(f, "={}", escape(value))?;
+            }
+        }
+        if let Some((start, end)) = self.range {
+            write!(f, "{}{}-{}", RANGE_SEGMENT, start, end)?;
+        }
+        Ok(())
+    }
+}
+
+/// The canonical form of a key given to an admin API
+///
+/// Keys that don't parse are passed through as they are, so one copied from
+/// an older listing still finds its entry.
+pub fn canonical_key(key: &str) -> String {
+    CacheKey::parse(key)
+        .map(|parsed| parsed.to_string())
+        .unwrap_or_else(|_| key.to_string())
+}
+
+/// Short hash of a stored key, as sent in `X-Cache-Key-Hash`
+///
+/// 16 hex digits of the key's XXH3; the key itself isn't recoverable from
+/// it, only looked up in the cache's key hash index.
+pub fn key_hash(key: &str) -> String {
+    format!("{:016x}", xxh3_64(key.as_bytes()))
+}
+
+/// Split a partial entry's key into the object's key and the range held
+///
+/// Cheaper than a full parse, for the paths that run on every store.
+pub fn split_range(key: &str) -> Option<(&str, (u64, u64))> {
+    let (object_key, range) = key.rsplit_once(RANGE_SEGMENT)?;
+    Some((object_key, parse_range(range)?))
+}
+
+/// Whether `key` is `base_key`'s URL, or one of its variants or ranges
+pub fn is_variant_of(key: &str, base_key: &str) -> bool {
+    // Every variant starts with the base key, so most keys fail here cheaply
+    if !key.starts_with(base_key) {
+        return false;
+    }
+    match (CacheKey::parse(key), CacheKey::parse(base_key)) {
+        (Ok(key), Ok(base)) => key.same_url(&base),
+        _ => key[base_key.len()..].is_empty() || key[base_key.len()..].starts_with('|'),
+    }
+}
+
+/// A prefix given to purge, pin or scope a purge token
+///
+/// `origin` alone covers every key of the origin, and `origin/path` covers
+/// that path and everything below it. A prefix with a query or variant part
+/// can't be matched by fields and keeps matching the raw key string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyPrefix {
+    Origin(String),
+    Path { origin: String, path: String },
+    Raw(String),
+}
+
+impl KeyPrefix {
+    pub fn parse(prefix: &str) -> Self {
+        if prefix.contains(['?', '|']) {
+            return Self::Raw(prefix.to_string());
+        }
+        match prefix.split_once('/') {
+            None => Self::Origin(unescape(prefix)),
+            Some(("", _)) => Self::Raw(prefix.to_string()),
+            Some((origin, path)) => Self::Path {
+                origin: unescape(origin),
+                path: format!("/{}", unescape(path)),
+            },
+        }
+    }
+
+    /// Whether a stored key is covered by this prefix
+    ///
+    /// Keys that don't parse are matched on their raw string.
+    pub fn matches(&self, key: &str) -> bool {
+        match (self, CacheKey::parse(key)) {
+            (Self::Origin(origin), Ok(parsed)) => parsed.origin == *origin,
+            (Self::Path { origin, path }, Ok(parsed)) => {
+                parsed.origin == *origin && path_within(&parsed.path, path)
+            }
+            _ => key.starts_with(self.raw().as_ref()),
+        }
+    }
+
+    /// Whether every key `other` matches is also matched by this prefix
+    pub fn covers(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Origin(a), Self::Origin(b)) => a == b,
+            (Self::Origin(a), Self::Path { origin, .. }) => a == origin,
+            (Self::Path { .. }, Self::Origin(_)) => false,
+            (
+                Self::Path { origin, path },
+                Self::Path {
+                    origin: other_origin,
+                    path: other_path,
+                },
+            ) => origin == other_origin && path_within(other_path, path),
+            (_, Self::Raw(raw)) => self.matches(raw),
+            (Self::Raw(raw), _) => other.raw().starts_with(raw.as_str()),
+        }
+    }
+
+    /// The prefix as a raw key string
+    fn raw(&self) -> Cow<'_, str> {
+        match self {
+            Self::Origin(origin) => escape(origin),
+            Self::Path { origin, path } => format!("{}{}", escape(origin), escape(path)).into(),
+            Self::Raw(raw) => raw.into(),
+        }
+    }
+}
+
+/// Whether `path` is `prefix` or below it, at a segment boundary
+fn path_within(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn parse_vary(segment: &str) -> Option<(String, String)> {
+    let (name, value) = segment.split_once('=')?;
+    Some((unescape(name), unescape(value)))
+}
+
+fn parse_dimension(segment: &str) -> Option<KeyDimension> {
+    let (kind, rest) = segment.split_once('.')?;
+    let (name, value) = match rest.split_once('=') {
+        Some((name, value)) => (unescape(name), Some(unescape(value))),
+        None => (unescape(rest), None),
+    };
+    match kind {
+        "h" => Some(KeyDimension::Header(name, value)),
+        "c" => Some(KeyDimension::Cookie(name, value)),
+        _ => None,
+    }
+}
+
+fn parse_range(range: &str) -> Option<(u64, u64)> {
+    let (start, end) = range.split_once('-')?;
+    Some((start.parse().ok()?, end.parse().ok()?))
+}
+
+fn escape(field: &str) -> Cow<'_, str> {
+    if field.contains('|') {
+        field.replace('|', ESCAPED_SEPARATOR).into()
+    } else {
+        field.into()
+    }
+}
+
+fn unescape(field: &str) -> String {
+    field.replace(ESCAPED_SEPARATOR, "|")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: Option<&str>) -> KeyDimension {
+        KeyDimension::Header(name.to_string(), value.map(str::to_string))
+    }
+
+    fn cookie(name: &str, value: Option<&str>) -> KeyDimension {
+        KeyDimension::Cookie(name.to_string(), value.map(str::to_string))
+    }
+
+    fn keys() -> Vec<CacheKey> {
+        let base = CacheKey::new("web", "/a.js", None);
+        let vary = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        };
+        vec![
+            base.clone(),
+            CacheKey::new("web", "/", None),
+            CacheKey::new("web", "/a.js", Some("v=1&w=2")),
+            CacheKey::new("web", "/search", Some("q=a=b?c/d:e")),
+            CacheKey {
+                vary: vary(&[("accept-encoding", "gzip")]),
+                ..base.clone()
+            },
+            CacheKey {
+                vary: vary(&[("accept-encoding", ""), ("accept-language", "en=us")]),
+                dimensions: vec![header("x-tenant", Some("acme")), cookie("session", None)],
+                ..CacheKey::new("web", "/a.js", Some("v=1"))
+            },
+            CacheKey {
+                dimensions: vec![header("x-tenant", None), cookie("ab", Some("b=1"))],
+                ..base.clone()
+            },
+            CacheKey {
+                vary: vary(&[("accept-encoding", "br")]),
+                ..base.clone()
+            }
+            .with_range(0, 1048575),
+            base.clone().with_range(6, 9),
+            // Separators inside fields
+            CacheKey::new("web", "/a|b", Some("x=|vary:y|key:h.z|range=0-1")),
+            CacheKey {
+                vary: vary(&[("x-list", "a|b"), ("range", "bytes=0-1")]),
+                dimensions: vec![header("x-pipe", Some("|")), cookie("c|d", Some("e|f"))],
+                ..base.clone()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for key in keys() {
+            let canonical = key.to_string();
+            let parsed = CacheKey::parse(&canonical).unwrap();
+            assert_eq!(parsed, key, "{}", canonical);
+            assert_eq!(parsed.to_string(), canonical);
+            assert_eq!(canonical.parse::<CacheKey>().unwrap(), key);
+            assert_eq!(canonical_key(&canonical), canonical);
+        }
+    }
+
+    #[test]
+    fn test_parses_the_stored_format() {
+        let key = CacheKey::parse(
+            "web/a.js?v=1|vary:accept-encoding=gzip|accept-language=en|key:h.x-tenant=acme|c.session|range=0-99",
+        )
+        .unwrap();
+        assert_eq!(key.origin, "web");
+        assert_eq!(key.path, "/a.js");
+        assert_eq!(key.query.as_deref(), Some("v=1"));
+        assert_eq!(
+            key.vary,
+            [
+                ("accept-encoding".to_string(), "gzip".to_string()),
+                ("accept-language".to_string(), "en".to_string())
+            ]
+        );
+        assert_eq!(
+            key.dimensions,
+            [header("x-tenant", Some("acme")), cookie("session", None)]
+        );
+        assert_eq!(key.range, Some((0, 99)));
+
+        // An empty query is no query
+        assert_eq!(
+            CacheKey::parse("web/a?").unwrap(),
+            CacheKey::new("web", "a", None)
+        );
+
+        // A query that looks like a variant stays a query
+        let key = CacheKey::new("web", "/a", Some("x=|vary:y"));
+        assert_eq!(key.to_string(), "web/a?x=%7Cvary:y");
+        assert_eq!(CacheKey::parse(&key.to_string()).unwrap().vary, []);
+    }
+
+    #[test]
+    fn test_malformed_keys() {
+        for key in [
+            "",
+            "web",
+            "/a.js",
+            "web/a|bogus",
+            "web/a|vary:novalue",
+            "web/a|key:x.y=1",
+            // Keys of Vary: * responses, which are no longer stored
+            "web/a|vary=*|18c5a1f3e2b",
+        ] {
+            assert!(CacheKey::parse(key).is_err(), "{}", key);
+            assert_eq!(canonical_key(key), key);
+        }
+        // Out of order sections
+        for key in [
+            "web/a|key:h.x=1|vary:accept-encoding=gzip",
+            "web/a|range=0-1|vary:accept-encoding=gzip",
+        ] {
+            assert!(CacheKey::parse(key).is_err(), "{}", key);
+        }
+        assert_eq!(canonical_key("web/a|b"), "web/a|b");
+    }
+
+    #[test]
+    fn test_prefix_matches_on_fields() {
+        let matches = |prefix: &str, key: &str| KeyPrefix::parse(prefix).matches(key);
+
+        assert!(matches("web/foo", "web/foo"));
+        assert!(matches("web/foo", "web/foo?v=1|vary:accept-encoding=gzip"));
+        assert!(matches("web/foo", "web/foo/bar.js"));
+        assert!(!matches("web/foo", "web/foobar"));
+        assert!(matches("web/foo/", "web/foo/bar.js"));
+        assert!(!matches("web/foo/", "web/foo"));
+        assert!(matches("web/", "web/anything"));
+        assert!(matches("web", "web/anything"));
+        assert!(!matches("web", "website/anything"));
+        assert!(!matches("web/foo", "api/foo"));
+
+        // A query or variant in the prefix matches the raw key, as before
+        assert!(matches("web/foo?v=", "web/foo?v=2"));
+        assert!(!matches("web/foo?v=", "web/foobar?v=2"));
+        assert!(matches(
+            "web/a.js|vary:",
+            "web/a.js|vary:accept-encoding=br"
+        ));
+        // So do keys that aren't in the structured form
+        assert!(matches("key-1", "key-12"));
+    }
+
+    #[test]
+    fn test_prefix_covers() {
+        let covers = |a: &str, b: &str| KeyPrefix::parse(a).covers(&KeyPrefix::parse(b));
+
+        assert!(covers("web", "web"));
+        assert!(covers("web", "web/foo"));
+        assert!(!covers("web/foo", "web"));
+        assert!(covers("web/assets/", "web/assets/img/"));
+        assert!(covers("web/assets", "web/assets/img"));
+        assert!(!covers("web/assets", "web/assets2"));
+        assert!(!covers("web/assets/", "web/"));
+        assert!(covers("web/assets/", "web/assets/a.js?v=1"));
+        assert!(!covers("web/assets/", "web/assetsx?v=1"));
+        assert!(covers("web/a?v=", "web/a?v=1"));
+        assert!(!covers("web/a?v=", "web/a"));
+    }
+
+    #[test]
+    fn test_variants_and_ranges() {
+        assert!(is_variant_of("web/a", "web/a"));
+        assert!(is_variant_of("web/a|vary:accept-encoding=gzip", "web/a"));
+        assert!(is_variant_of("web/a|range=0-3", "web/a"));
+        assert!(!is_variant_of("web/a.js", "web/a"));
+        assert!(!is_variant_of("web/a?v=1", "web/a"));
+        assert!(!is_variant_of("web/a?x=%7Cvary:y", "web/a?x="));
+
+        assert_eq!(split_range("web/a|range=0-3"), Some(("web/a", (0, 3))));
+        assert_eq!(
+            split_range("web/a|vary:accept-encoding=br|range=10-19"),
+            Some(("web/a|vary:accept-encoding=br", (10, 19)))
+        );
+        assert_eq!(split_range("web/a|vary:range=bytes=0-3"), None);
+        assert_eq!(split_range("web/a"), None);
+    }
+}
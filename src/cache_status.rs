@@ -0,0 +1,301 @@
+//! RFC 9211 `Cache-Status` header values
+//!
+//! The header is a Structured Fields list (RFC 8941) with one member per
+//! cache, each the cache's name followed by parameters. The grammar is
+//! strict: booleans that are true are written bare, false ones are left
+//! out, integers have at most 15 digits and strings are printable ASCII
+//! only. [`CacheStatusValue`] takes care of all of that.
+
+use std::fmt;
+
+/// Name this CDN reports itself as in `Cache-Status`
+pub const CACHE_NAME: &str = "Screaming-Eagle";
+
+/// Largest magnitude of a Structured Fields integer
+const MAX_INTEGER: i64 = 999_999_999_999_999;
+
+/// Why a request went forward to the origin (RFC 9211 Section 2.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardReason {
+    /// Configured not to use the cache for this request
+    Bypass,
+    /// The request method isn't cacheable
+    Method,
+    /// Nothing was cached for the URI
+    UriMiss,
+    /// Something was cached for the URI, but not for its Vary headers
+    VaryMiss,
+    /// Nothing was cached, without saying more
+    Miss,
+    /// The request's own directives (e.g. `no-cache`) sent it forward
+    Request,
+    /// A stale response was cached
+    Stale,
+    /// Part of the requested ranges was cached
+    Partial,
+}
+
+impl ForwardReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ForwardReason::Bypass => "bypass",
+            ForwardReason::Method => "method",
+            ForwardReason::UriMiss => "uri-miss",
+            ForwardReason::VaryMiss => "vary-miss",
+            ForwardReason::Miss => "miss",
+            ForwardReason::Request => "request",
+            ForwardReason::Stale => "stale",
+            ForwardReason::Partial => "partial",
+        }
+    }
+}
+
+/// One cache's member of a `Cache-Status` header
+///
+/// Built up parameter by parameter and written out with `Display`, e.g.
+/// `Screaming-Eagle; fwd=uri-miss; fwd-status=200; stored`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStatusValue {
+    cache: String,
+    hit: bool,
+    fwd: Option<ForwardReason>,
+    fwd_status: Option<u16>,
+    ttl: Option<i64>,
+    stored: bool,
+    collapsed: bool,
+    key: Option<String>,
+    detail: Option<String>,
+}
+
+impl CacheStatusValue {
+    pub fn new(cache: impl Into<String>) -> Self {
+        Self {
+            cache: cache.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Answered from the cache without going to the origin
+    pub fn hit(mut self) -> Self {
+        self.hit = true;
+        self
+    }
+
+    /// Went forward to the origin, and why
+    pub fn fwd(mut self, reason: ForwardReason) -> Self {
+        self.fwd = Some(reason);
+        self
+    }
+
+    /// Status of the origin's response to the forwarded request
+    pub fn fwd_status(mut self, status: u16) -> Self {
+        self.fwd_status = Some(status);
+        self
+    }
+
+    /// Seconds of freshness left; negative once stale
+    pub fn ttl(mut self, secs: i64) -> Self {
+        self.ttl = Some(secs.clamp(-MAX_INTEGER, MAX_INTEGER));
+        self
+    }
+
+    /// Whether the forwarded response was stored
+    pub fn stored(mut self, stored: bool) -> Self {
+        self.stored = stored;
+        self
+    }
+
+    /// Whether the request was collapsed into another one's origin fetch
+    pub fn collapsed(mut self, collapsed: bool) -> Self {
+        self.collapsed = collapsed;
+        self
+    }
+
+    /// The cache key the response was looked up under
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// Implementation-specific detail
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+impl fmt::Display for CacheStatusValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_bare_item(f, &self.cache)?;
+        if self.hit {
+            f.write_str("; hit")?;
+        }
+        if let Some(reason) = self.fwd {
+            write!(f, "; fwd={}", reason.as_str())?;
+        }
+        if let Some(status) = self.fwd_status {
+            write!(f, "; fwd-status={}", status)?;
+        }
+        if let Some(ttl) = self.ttl {
+            write!(f, "; ttl={}", ttl)?;
+        }
+        if self.stored {
+            f.write_str("; stored")?;
+        }
+        if self.collapsed {
+            f.write_str("; collapsed")?;
+        }
+        if let Some(key) = &self.key {
+            f.write_str("; key=")?;
+            write_string(f, key)?;
+        }
+        if let Some(detail) = &self.detail {
+            f.write_str("; detail=")?;
+            write_bare_item(f, detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// A token when the value is one, a string otherwise
+fn write_bare_item(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    if is_token(value) {
+        f.write_str(value)
+    } else {
+        write_string(f, value)
+    }
+}
+
+/// Whether `value` is a Structured Fields token (RFC 8941 Section 3.3.4)
+fn is_token(value: &str) -> bool {
+    let mut chars = value.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '*')
+        && chars.all(|c| is_tchar(c) || c == ':' || c == '/')
+}
+
+/// RFC 9110 token characters
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// A Structured Fields string (RFC 8941 Section 3.3.3)
+///
+/// Strings can only hold printable ASCII, so any other byte is
+/// percent-encoded, as it would be in a URL.
+fn write_string(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for byte in value.bytes() {
+        match byte {
+            b'"' | b'\\' => write!(f, "\\{}", byte as char)?,
+            0x20..=0x7e => write!(f, "{}", byte as char)?,
+            _ => write!(f, "%{:02X}", byte)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> CacheStatusValue {
+        CacheStatusValue::new(CACHE_NAME)
+    }
+
+    #[test]
+    fn test_hits() {
+        assert_eq!(
+            status().hit().ttl(123).to_string(),
+            "Screaming-Eagle; hit; ttl=123"
+        );
+        // A stale hit has a negative TTL
+        assert_eq!(
+            status().hit().ttl(-30).to_string(),
+            "Screaming-Eagle; hit; ttl=-30"
+        );
+        assert_eq!(status().to_string(), "Screaming-Eagle");
+    }
+
+    #[test]
+    fn test_forwarded() {
+        assert_eq!(
+            status()
+                .fwd(ForwardReason::UriMiss)
+                .fwd_status(200)
+                .stored(true)
+                .collapsed(true)
+                .to_string(),
+            "Screaming-Eagle; fwd=uri-miss; fwd-status=200; stored; collapsed"
+        );
+        // False booleans are left out rather than written as ?0
+        assert_eq!(
+            status()
+                .fwd(ForwardReason::Request)
+                .fwd_status(503)
+                .stored(false)
+                .collapsed(false)
+                .to_string(),
+            "Screaming-Eagle; fwd=request; fwd-status=503"
+        );
+        assert_eq!(
+            status()
+                .fwd(ForwardReason::Stale)
+                .fwd_status(502)
+                .ttl(-5)
+                .to_string(),
+            "Screaming-Eagle; fwd=stale; fwd-status=502; ttl=-5"
+        );
+        for (reason, expected) in [
+            (ForwardReason::Bypass, "bypass"),
+            (ForwardReason::Method, "method"),
+            (ForwardReason::VaryMiss, "vary-miss"),
+            (ForwardReason::Miss, "miss"),
+            (ForwardReason::Partial, "partial"),
+        ] {
+            assert_eq!(reason.as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn test_strings_are_escaped() {
+        assert_eq!(
+            status().hit().key("web/a.js?q=\"x\"\\y").to_string(),
+            r#"Screaming-Eagle; hit; key="web/a.js?q=\"x\"\\y""#
+        );
+        // Outside printable ASCII nothing can be escaped, so it's percent-encoded
+        assert_eq!(
+            status().key("web/caf\u{e9}\ttab").to_string(),
+            r#"Screaming-Eagle; key="web/caf%C3%A9%09tab""#
+        );
+    }
+
+    #[test]
+    fn test_tokens_fall_back_to_strings() {
+        assert_eq!(
+            status().detail("stale-if-error").to_string(),
+            "Screaming-Eagle; detail=stale-if-error"
+        );
+        assert_eq!(
+            status().detail("not a token").to_string(),
+            r#"Screaming-Eagle; detail="not a token""#
+        );
+        // Tokens can't start with a digit
+        assert_eq!(CacheStatusValue::new("1cdn").to_string(), r#""1cdn""#);
+        assert_eq!(CacheStatusValue::new("edge/1:a").to_string(), "edge/1:a");
+        assert_eq!(CacheStatusValue::new("").to_string(), r#""""#);
+    }
+
+    #[test]
+    fn test_integers_are_clamped() {
+        assert_eq!(
+            status().ttl(i64::MAX).to_string(),
+            "Screaming-Eagle; ttl=999999999999999"
+        );
+        assert_eq!(
+            status().ttl(i64::MIN).to_string(),
+            "Screaming-Eagle; ttl=-999999999999999"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! Cache tags read from origin response headers
+//!
+//! Upstream stacks announce tags differently: `Surrogate-Key: a b c`,
+//! `Cache-Tag: a,b,c`, or one tag per repeated header. `cache.tags.headers`
+//! lists the headers to read, each with its own delimiter, and every format
+//! ends up as the same list of tags on the stored entry.
+
+use crate::config::{CacheTagsConfig, TagHeaderConfig};
+use crate::headers::ResponseHeaders;
+
+/// Tags in the first configured header the response carries, normalized and
+/// without duplicates, in the order they appear
+pub fn extract_tags(headers: &ResponseHeaders, config: &CacheTagsConfig) -> Vec<String> {
+    let Some(header) = config
+        .headers
+        .iter()
+        .find(|header| headers.contains_key(&header.name))
+    else {
+        return Vec::new();
+    };
+
+    let mut tags: Vec<String> = Vec::new();
+    for value in headers.get_all(&header.name) {
+        for tag in split(value, header) {
+            if let Some(tag) = normalize(tag, config)
+                && !tags.contains(&tag)
+            {
+                tags.push(tag);
+            }
+        }
+    }
+    tags
+}
+
+/// One header value's tags, before normalization
+fn split<'a>(
+    value: &'a str,
+    header: &'a TagHeaderConfig,
+) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+    match header.delimiter.as_str() {
+        "" => Box::new(std::iter::once(value)),
+        delimiter if delimiter.trim().is_empty() => Box::new(value.split_whitespace()),
+        delimiter => Box::new(value.split(delimiter)),
+    }
+}
+
+/// A tag trimmed and, if configured, lowercased; `None` if it's empty or
+/// over `max_tag_length`
+fn normalize(tag: &str, config: &CacheTagsConfig) -> Option<String> {
+    let tag = tag.trim();
+    if tag.is_empty() || (config.max_tag_length > 0 && tag.len() > config.max_tag_length) {
+        return None;
+    }
+    Some(if config.lowercase {
+        tag.to_lowercase()
+    } else {
+        tag.to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CacheTagsConfig {
+        toml::from_str(
+            r#"
+            lowercase = true
+            max_tag_length = 16
+
+            [[headers]]
+            name = "surrogate-key"
+            delimiter = " "
+
+            [[headers]]
+            name = "cache-tag"
+
+            [[headers]]
+            name = "x-cache-tags"
+            delimiter = ""
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> ResponseHeaders {
+        let mut headers = ResponseHeaders::new();
+        for (name, value) in pairs {
+            headers.append(*name, *value);
+        }
+        headers
+    }
+
+    #[test]
+    fn test_upstream_formats_give_the_same_tags() {
+        let config = config();
+        let expected = vec!["product-123", "category-shoes", "brand-nike"];
+
+        let surrogate = headers(&[("surrogate-key", "product-123  Category-Shoes\tbrand-nike")]);
+        let cache_tag = headers(&[("cache-tag", "product-123, category-shoes ,Brand-Nike,")]);
+        let repeated = headers(&[
+            ("x-cache-tags", "product-123"),
+            ("x-cache-tags", " category-shoes "),
+            ("x-cache-tags", "brand-nike"),
+            ("x-cache-tags", "PRODUCT-123"),
+        ]);
+        for response in [surrogate, cache_tag, repeated] {
+            assert_eq!(extract_tags(&response, &config), expected);
+        }
+    }
+
+    #[test]
+    fn test_first_listed_header_wins() {
+        let config = config();
+        let response = headers(&[("cache-tag", "second"), ("surrogate-key", "first")]);
+        assert_eq!(extract_tags(&response, &config), vec!["first"]);
+
+        assert!(extract_tags(&headers(&[("etag", "\"1\"")]), &config).is_empty());
+    }
+
+    #[test]
+    fn test_normalization() {
+        let mut config = config();
+        let response = headers(&[("cache-tag", "Short, this-tag-is-far-too-long, ,ok")]);
+        assert_eq!(extract_tags(&response, &config), vec!["short", "ok"]);
+
+        config.lowercase = false;
+        config.max_tag_length = 0;
+        assert_eq!(
+            extract_tags(&response, &config),
+            vec!["Short", "this-tag-is-far-too-long", "ok"]
+        );
+
+        // The default reads comma-separated Cache-Tag as it always has
+        let response = headers(&[("cache-tag", "a, B,a")]);
+        assert_eq!(
+            extract_tags(&response, &CacheTagsConfig::default()),
+            vec!["a", "B"]
+        );
+    }
+}
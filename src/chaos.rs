@@ -0,0 +1,408 @@
+//! Fault injection
+//!
+//! Rules added through `/_cdn/faults` make matching origin fetches slow,
+//! fail or break, so retries, stale serving and the circuit breaker can be
+//! exercised against a healthy origin. Rules live only in memory and expire
+//! on their own; none can be added unless `chaos.enabled` is set.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::{ChaosConfig, RegexLimitsConfig};
+use crate::error::{CdnError, CdnResult};
+use crate::metrics::Metrics;
+
+/// What happens to a fetch a rule picks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FaultKind {
+    /// Wait this long before fetching
+    Delay { ms: u64 },
+    /// Answer with this status without contacting the origin
+    Error { status: u16 },
+    /// Fail as if the origin reset the connection
+    Abort,
+    /// Fetch, then take as long to deliver the body as this rate allows
+    Drip { bytes_per_sec: u64 },
+}
+
+impl FaultKind {
+    /// Label used in metrics and logs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FaultKind::Delay { .. } => "delay",
+            FaultKind::Error { .. } => "error",
+            FaultKind::Abort => "abort",
+            FaultKind::Drip { .. } => "drip",
+        }
+    }
+}
+
+/// A rule as posted to `/_cdn/faults`
+#[derive(Debug, Clone, Deserialize)]
+pub struct FaultRuleRequest {
+    /// Origin whose fetches are affected; every origin when unset
+    #[serde(default)]
+    pub origin: Option<String>,
+    /// Regex matched against the request path; every path when unset
+    #[serde(default)]
+    pub path: Option<String>,
+    pub fault: FaultKind,
+    /// Chance that a matching fetch is affected (default: 1.0)
+    #[serde(default = "default_probability")]
+    pub probability: f64,
+    /// Seconds until the rule is removed
+    pub ttl_secs: u64,
+}
+
+fn default_probability() -> f64 {
+    1.0
+}
+
+/// An active rule, as listed by `GET /_cdn/faults`
+#[derive(Debug, Clone, Serialize)]
+pub struct FaultRuleInfo {
+    pub id: String,
+    pub origin: Option<String>,
+    pub path: Option<String>,
+    pub fault: FaultKind,
+    pub probability: f64,
+    pub expires_in_secs: u64,
+    /// Fetches affected so far
+    pub injected: u64,
+}
+
+struct FaultRule {
+    id: String,
+    origin: Option<String>,
+    path: Option<Regex>,
+    fault: FaultKind,
+    probability: f64,
+    expires_at: Instant,
+    injected: AtomicU64,
+}
+
+impl FaultRule {
+    fn matches(&self, origin: &str, path: &str) -> bool {
+        self.origin.as_deref().is_none_or(|o| o == origin)
+            && self.path.as_ref().is_none_or(|p| p.is_match(path))
+    }
+
+    fn info(&self, now: Instant) -> FaultRuleInfo {
+        FaultRuleInfo {
+            id: self.id.clone(),
+            origin: self.origin.clone(),
+            path: self.path.as_ref().map(|p| p.as_str().to_string()),
+            fault: self.fault,
+            probability: self.probability,
+            expires_in_secs: self.expires_at.saturating_duration_since(now).as_secs(),
+            injected: self.injected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The fault picked for one origin fetch
+#[derive(Debug, Clone, PartialEq)]
+pub struct InjectedFault {
+    pub rule_id: String,
+    pub kind: FaultKind,
+}
+
+/// Active fault rules, consulted by the origin fetcher before every attempt
+pub struct FaultInjector {
+    config: ChaosConfig,
+    rules: RwLock<Vec<Arc<FaultRule>>>,
+    next_id: AtomicU64,
+    regex_limits: RegexLimitsConfig,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl FaultInjector {
+    pub fn new(config: ChaosConfig) -> Self {
+        Self {
+            config,
+            rules: RwLock::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+            regex_limits: RegexLimitsConfig::default(),
+            metrics: None,
+        }
+    }
+
+    /// Size limits a rule's path pattern compiles within
+    pub fn with_regex_limits(mut self, limits: RegexLimitsConfig) -> Self {
+        self.regex_limits = limits;
+        self
+    }
+
+    /// Count injected faults in `cdn_injected_faults_total`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Add a rule, returning it as it will be listed
+    pub fn add(&self, request: FaultRuleRequest) -> CdnResult<FaultRuleInfo> {
+        if !self.config.enabled {
+            return Err(CdnError::NotFound(
+                "Fault injection is disabled (chaos.enabled)".to_string(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&request.probability) {
+            return Err(CdnError::InvalidRequest(format!(
+                "probability must be between 0.0 and 1.0, got {}",
+                request.probability
+            )));
+        }
+        if request.ttl_secs == 0 || request.ttl_secs > self.config.max_ttl_secs {
+            return Err(CdnError::InvalidRequest(format!(
+                "ttl_secs must be between 1 and {}",
+                self.config.max_ttl_secs
+            )));
+        }
+        match request.fault {
+            FaultKind::Error { status } if !(400..=599).contains(&status) => {
+                return Err(CdnError::InvalidRequest(format!(
+                    "Injected error status must be 4xx or 5xx, got {}",
+                    status
+                )));
+            }
+            FaultKind::Drip { bytes_per_sec: 0 } => {
+                return Err(CdnError::InvalidRequest(
+                    "bytes_per_sec must be positive".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        let path = request
+            .path
+            .as_deref()
+            .map(|pattern| self.regex_limits.compile(pattern))
+            .transpose()
+            .map_err(|e| CdnError::InvalidRequest(format!("Invalid path pattern: {}", e)))?;
+
+        let now = Instant::now();
+        let mut rules = self.rules.write().unwrap_or_else(|e| e.into_inner());
+        rules.retain(|rule| rule.expires_at > now);
+        if rules.len() >= self.config.max_rules {
+            return Err(CdnError::Conflict(format!(
+                "{} fault rules are already active",
+                rules.len()
+            )));
+        }
+
+        let rule = FaultRule {
+            id: format!("fault-{}", self.next_id.fetch_add(1, Ordering::Relaxed)),
+            origin: request.origin,
+            path,
+            fault: request.fault,
+            probability: request.probability,
+            expires_at: now + Duration::from_secs(request.ttl_secs),
+            injected: AtomicU64::new(0),
+        };
+        let info = rule.info(now);
+        warn!(
+            rule = %info.id,
+            origin = ?info.origin,
+            path = ?info.path,
+            fault = info.fault.as_str(),
+            probability = info.probability,
+            ttl_secs = request.ttl_secs,
+            "Fault injection rule added"
+        );
+        rules.push(Arc::new(rule));
+        Ok(info)
+    }
+
+    /// Rules that haven't expired, oldest first
+    pub fn list(&self) -> Vec<FaultRuleInfo> {
+        let now = Instant::now();
+        let mut rules = self.rules.write().unwrap_or_else(|e| e.into_inner());
+        rules.retain(|rule| rule.expires_at > now);
+        rules.iter().map(|rule| rule.info(now)).collect()
+    }
+
+    /// Remove a rule before it expires
+    pub fn remove(&self, id: &str) -> bool {
+        let mut rules = self.rules.write().unwrap_or_else(|e| e.into_inner());
+        let before = rules.len();
+        rules.retain(|rule| rule.id != id);
+        let removed = rules.len() < before;
+        if removed {
+            info!(rule = %id, "Fault injection rule removed");
+        }
+        removed
+    }
+
+    /// Remove every rule, returning how many there were
+    pub fn clear(&self) -> usize {
+        let mut rules = self.rules.write().unwrap_or_else(|e| e.into_inner());
+        let count = rules.len();
+        rules.clear();
+        count
+    }
+
+    /// The fault to inject into a fetch of `path` from `origin`, if any
+    ///
+    /// Rules are checked oldest first; the first that matches decides, with
+    /// its probability, whether the fetch is affected.
+    pub fn pick(&self, origin: &str, path: &str) -> Option<InjectedFault> {
+        self.pick_with(origin, path, rand::random::<f64>())
+    }
+
+    fn pick_with(&self, origin: &str, path: &str, roll: f64) -> Option<InjectedFault> {
+        let rule = {
+            let rules = self.rules.read().unwrap_or_else(|e| e.into_inner());
+            if rules.is_empty() {
+                return None;
+            }
+            let path = format!("/{}", path.trim_start_matches('/'));
+            let now = Instant::now();
+            rules
+                .iter()
+                .find(|rule| rule.expires_at > now && rule.matches(origin, &path))
+                .cloned()?
+        };
+        if roll >= rule.probability {
+            return None;
+        }
+
+        rule.injected.fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_injected_fault(origin, rule.fault.as_str());
+        }
+        Some(InjectedFault {
+            rule_id: rule.id.clone(),
+            kind: rule.fault,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn injector() -> FaultInjector {
+        FaultInjector::new(ChaosConfig {
+            enabled: true,
+            ..Default::default()
+        })
+    }
+
+    fn rule(origin: Option<&str>, path: Option<&str>, fault: FaultKind) -> FaultRuleRequest {
+        FaultRuleRequest {
+            origin: origin.map(str::to_string),
+            path: path.map(str::to_string),
+            fault,
+            probability: 1.0,
+            ttl_secs: 60,
+        }
+    }
+
+    #[test]
+    fn test_rules_match_origin_path_and_probability() {
+        let faults = injector();
+        let delay = FaultKind::Delay { ms: 50 };
+        faults
+            .add(rule(Some("api"), Some("^/slow/"), delay))
+            .unwrap();
+        let error = faults
+            .add(FaultRuleRequest {
+                probability: 0.25,
+                ..rule(None, None, FaultKind::Error { status: 503 })
+            })
+            .unwrap();
+
+        let picked = faults.pick_with("api", "slow/a.json", 0.9).unwrap();
+        assert_eq!(picked.kind, delay);
+        assert_eq!(faults.pick_with("api", "/slow/a.json", 0.9), Some(picked));
+
+        // Falls through to the catch-all rule, which only fires a quarter of the time
+        assert!(faults.pick_with("web", "slow/a.json", 0.5).is_none());
+        let picked = faults.pick_with("web", "slow/a.json", 0.1).unwrap();
+        assert_eq!(picked.rule_id, error.id);
+        assert_eq!(picked.kind, FaultKind::Error { status: 503 });
+
+        let listed = faults.list();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].injected, 2);
+        assert_eq!(listed[1].injected, 1);
+
+        assert!(faults.remove(&error.id));
+        assert!(!faults.remove(&error.id));
+        assert!(faults.pick_with("web", "index.html", 0.0).is_none());
+        assert_eq!(faults.clear(), 1);
+        assert!(faults.pick_with("api", "slow/a.json", 0.0).is_none());
+    }
+
+    #[test]
+    fn test_rules_are_validated_and_need_the_switch() {
+        let disabled = FaultInjector::new(ChaosConfig::default());
+        assert!(disabled.add(rule(None, None, FaultKind::Abort)).is_err());
+
+        let faults = injector();
+        for bad in [
+            rule(None, Some("("), FaultKind::Abort),
+            rule(None, None, FaultKind::Error { status: 200 }),
+            rule(None, None, FaultKind::Drip { bytes_per_sec: 0 }),
+            FaultRuleRequest {
+                probability: 1.5,
+                ..rule(None, None, FaultKind::Abort)
+            },
+            FaultRuleRequest {
+                ttl_secs: 0,
+                ..rule(None, None, FaultKind::Abort)
+            },
+            FaultRuleRequest {
+                ttl_secs: 86400,
+                ..rule(None, None, FaultKind::Abort)
+            },
+        ] {
+            assert!(faults.add(bad).is_err());
+        }
+        assert!(faults.list().is_empty());
+
+        let request: FaultRuleRequest = serde_json::from_str(
+            r#"{"origin": "api", "fault": {"type": "drip", "bytes_per_sec": 1024}, "ttl_secs": 30}"#,
+        )
+        .unwrap();
+        assert_eq!(request.fault.as_str(), "drip");
+        assert_eq!(request.probability, 1.0);
+        assert!(faults.add(request).is_ok());
+    }
+
+    #[test]
+    fn test_rules_expire() {
+        let faults = injector();
+        faults.add(rule(None, None, FaultKind::Abort)).unwrap();
+        faults.rules.write().unwrap()[0] = Arc::new(FaultRule {
+            expires_at: Instant::now(),
+            id: "fault-1".to_string(),
+            origin: None,
+            path: None,
+            fault: FaultKind::Abort,
+            probability: 1.0,
+            injected: AtomicU64::new(0),
+        });
+        assert!(faults.pick_with("web", "index.html", 0.0).is_none());
+        assert!(faults.list().is_empty());
+    }
+
+    #[test]
+    fn test_max_rules() {
+        let faults = FaultInjector::new(ChaosConfig {
+            enabled: true,
+            max_rules: 1,
+            ..Default::default()
+        });
+        faults.add(rule(None, None, FaultKind::Abort)).unwrap();
+        assert!(faults.add(rule(None, None, FaultKind::Abort)).is_err());
+    }
+}
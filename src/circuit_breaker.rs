@@ -83,8 +83,8 @@ impl CircuitBreaker {
         }
     }
 
-    /// Record a successful request
-    pub fn record_success(&self) {
+    /// Record a successful request; true when it closed the circuit
+    pub fn record_success(&self) -> bool {
         let state = *self.state.read().unwrap();
 
         match state {
@@ -101,13 +101,14 @@ impl CircuitBreaker {
                 );
 
                 if count >= self.config.success_threshold {
-                    self.transition_to_closed();
+                    return self.transition_to_closed();
                 }
             }
             CircuitState::Open => {
                 // Shouldn't happen, but handle gracefully
             }
         }
+        false
     }
 
     /// Record a failed request
@@ -138,11 +139,37 @@ impl CircuitBreaker {
         }
     }
 
+    /// Record a failure that mustn't open the circuit, as in a maintenance window
+    ///
+    /// The count stops one short of the threshold, so a single failure after
+    /// the window doesn't open the circuit on its own.
+    pub fn record_held_failure(&self) {
+        *self.last_failure_time.write().unwrap() = Some(Instant::now());
+        if self.state() == CircuitState::Closed {
+            let limit = self.config.failure_threshold.saturating_sub(1);
+            let count = self.failure_count.load(Ordering::Relaxed);
+            self.failure_count
+                .store((count + 1).min(limit), Ordering::Relaxed);
+        }
+    }
+
     /// Get current state
     pub fn state(&self) -> CircuitState {
         *self.state.read().unwrap()
     }
 
+    /// How long until an open circuit lets a trial request through
+    ///
+    /// `None` unless the circuit is open.
+    pub fn retry_after(&self) -> Option<Duration> {
+        if self.state() != CircuitState::Open {
+            return None;
+        }
+        let opened_at = (*self.opened_at.read().unwrap())?;
+        let reset = Duration::from_secs(self.config.reset_timeout_secs);
+        Some(reset.saturating_sub(opened_at.elapsed()))
+    }
+
     fn should_transition_to_half_open(&self) -> bool {
         if let Some(opened_at) = *self.opened_at.read().unwrap() {
             let elapsed = Instant::now().duration_since(opened_at);
@@ -172,13 +199,18 @@ impl CircuitBreaker {
         }
     }
 
-    fn transition_to_closed(&self) {
+    /// Close the circuit; false when a concurrent success already did
+    fn transition_to_closed(&self) -> bool {
         let mut state = self.state.write().unwrap();
+        if *state == CircuitState::Closed {
+            return false;
+        }
         info!("Circuit breaker CLOSED");
         *state = CircuitState::Closed;
         *self.opened_at.write().unwrap() = None;
         self.failure_count.store(0, Ordering::Relaxed);
         self.success_count.store(0, Ordering::Relaxed);
+        true
     }
 }
 
@@ -213,9 +245,9 @@ impl CircuitBreakerManager {
         self.get_breaker(origin).should_allow()
     }
 
-    /// Record a successful request to an origin
-    pub fn record_success(&self, origin: &str) {
-        self.get_breaker(origin).record_success();
+    /// Record a successful request to an origin; true when it closed the circuit
+    pub fn record_success(&self, origin: &str) -> bool {
+        self.get_breaker(origin).record_success()
     }
 
     /// Record a failed request to an origin
@@ -223,11 +255,26 @@ impl CircuitBreakerManager {
         self.get_breaker(origin).record_failure();
     }
 
+    /// Record a failed request that mustn't open the origin's circuit
+    pub fn record_held_failure(&self, origin: &str) {
+        self.get_breaker(origin).record_held_failure();
+    }
+
     /// Get the state of a circuit breaker for an origin
     pub fn state(&self, origin: &str) -> CircuitState {
         self.get_breaker(origin).state()
     }
 
+    /// Time until an origin's open circuit allows a trial request
+    pub fn retry_after(&self, origin: &str) -> Option<Duration> {
+        self.get_breaker(origin).retry_after()
+    }
+
+    /// Drop an origin's breaker; the next request starts with a closed one
+    pub fn remove(&self, origin: &str) -> bool {
+        self.breakers.remove(origin).is_some()
+    }
+
     /// Get states for all origins
     pub fn all_states(&self) -> Vec<(String, CircuitState)> {
         self.breakers
@@ -254,6 +301,7 @@ mod tests {
 
         assert_eq!(cb.state(), CircuitState::Closed);
         assert!(cb.should_allow());
+        assert_eq!(cb.retry_after(), None);
 
         // Record failures
         cb.record_failure();
@@ -265,6 +313,9 @@ mod tests {
         cb.record_failure();
         assert_eq!(cb.state(), CircuitState::Open);
         assert!(!cb.should_allow());
+
+        let retry_after = cb.retry_after().unwrap();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
     }
 
     #[test]
@@ -287,12 +338,13 @@ mod tests {
         assert!(cb.should_allow());
         assert_eq!(cb.state(), CircuitState::HalfOpen);
 
-        // Record successes
-        cb.record_success();
+        // Record successes; only the one that closes the circuit says so
+        assert!(!cb.record_success());
         assert_eq!(cb.state(), CircuitState::HalfOpen);
 
-        cb.record_success();
+        assert!(cb.record_success());
         assert_eq!(cb.state(), CircuitState::Closed);
+        assert!(!cb.record_success());
     }
 
     #[test]
@@ -319,6 +371,23 @@ mod tests {
         assert_eq!(cb.state(), CircuitState::Open);
     }
 
+    #[test]
+    fn test_held_failures_keep_the_circuit_closed() {
+        let cb = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 3,
+            ..Default::default()
+        });
+
+        for _ in 0..10 {
+            cb.record_held_failure();
+        }
+        assert_eq!(cb.state(), CircuitState::Closed);
+
+        // Counted, so a real failure streak after the window still opens it
+        cb.record_failure();
+        assert_eq!(cb.state(), CircuitState::Open);
+    }
+
     #[test]
     fn test_circuit_breaker_manager() {
         let config = CircuitBreakerConfig {
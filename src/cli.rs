@@ -0,0 +1,760 @@
+//! Operator subcommands
+//!
+//! `screaming-eagle purge|warm|stats|status` call the admin API of a running
+//! node with the same request and response types the handlers use, so the
+//! two can't drift; `check-config` validates a config file offline. With no
+//! subcommand the binary runs the server.
+//!
+//! Exit codes are stable for use in CI: see the `EXIT_*` constants.
+
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::cache::CacheStats;
+use crate::config::Config;
+use crate::handlers::{
+    HealthResponse, OriginHealthResponse, PurgeRequest, PurgeResponse, WarmCacheRequest,
+    WarmCacheResponse, WarmTarget,
+};
+use crate::health::HealthStatus;
+
+/// The command succeeded
+pub const EXIT_OK: u8 = 0;
+/// The node answered but the operation failed: items were rejected or not
+/// warmed, an origin is unhealthy, or the config is invalid
+pub const EXIT_FAILURE: u8 = 1;
+/// Bad arguments (also what clap exits with)
+pub const EXIT_USAGE: u8 = 2;
+/// The node couldn't be reached, refused the request or answered with
+/// something other than the expected response
+pub const EXIT_UNAVAILABLE: u8 = 3;
+
+#[derive(Debug, Parser)]
+#[command(name = "screaming-eagle", version, about = "Screaming Eagle CDN")]
+pub struct Cli {
+    /// Operator command; runs the server when omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Purge cached entries by key, prefix or tag
+    #[command(group(
+        ArgGroup::new("target")
+            .required(true)
+            .multiple(true)
+            .args(["keys", "key_hashes", "prefix", "tag", "all"])
+    ))]
+    Purge {
+        #[command(flatten)]
+        server: ServerArgs,
+        /// Cache key to purge (repeatable)
+        #[arg(long = "key", value_name = "KEY")]
+        keys: Vec<String>,
+        /// X-Cache-Key-Hash of a cached key to purge (repeatable)
+        #[arg(long = "key-hash", value_name = "HASH")]
+        key_hashes: Vec<String>,
+        /// Purge every key under this prefix
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Purge every entry with this cache tag
+        #[arg(long)]
+        tag: Option<String>,
+        /// Purge the whole cache
+        #[arg(long)]
+        all: bool,
+        /// Run a purge of most of the cache even during the cooldown
+        #[arg(long)]
+        force: bool,
+    },
+    /// Fetch URLs into the cache ahead of traffic
+    Warm {
+        #[command(flatten)]
+        server: ServerArgs,
+        /// URLs to warm, as "/origin/path"
+        urls: Vec<String>,
+        /// Read URLs from a file, one per line ("-" for stdin)
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
+    },
+    /// Show cache statistics
+    Stats {
+        #[command(flatten)]
+        server: ServerArgs,
+    },
+    /// Show node and origin health; fails if any origin is unhealthy
+    Status {
+        #[command(flatten)]
+        server: ServerArgs,
+    },
+    /// Validate a config file without starting the server
+    CheckConfig {
+        /// Config file to check
+        #[arg(env = "CDN_CONFIG", default_value = "config/cdn.toml")]
+        path: PathBuf,
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// How to reach the node
+#[derive(Debug, Clone, Args)]
+pub struct ServerArgs {
+    /// Base URL of the node
+    #[arg(long, env = "CDN_SERVER", default_value = "http://localhost:8080")]
+    pub server: String,
+    /// Admin (or purge) token
+    #[arg(long, env = "CDN_ADMIN_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    /// Request timeout in seconds
+    #[arg(long, default_value_t = 30)]
+    pub timeout: u64,
+    /// Print the server's response as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("{0}")]
+    Usage(String),
+
+    #[error("request to {url} failed: {source}")]
+    Unreachable { url: String, source: reqwest::Error },
+
+    #[error("server returned {status}: {message}")]
+    Status { status: StatusCode, message: String },
+
+    #[error("unexpected response from server: {0}")]
+    InvalidResponse(String),
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Usage(_) => EXIT_USAGE,
+            CliError::Unreachable { .. }
+            | CliError::Status { .. }
+            | CliError::InvalidResponse(_) => EXIT_UNAVAILABLE,
+        }
+    }
+}
+
+/// Run an operator command, returning the process exit code
+pub async fn run(command: Command) -> u8 {
+    let result = match command {
+        Command::Purge {
+            server,
+            keys,
+            key_hashes,
+            prefix,
+            tag,
+            all,
+            force,
+        } => {
+            let request = PurgeRequest {
+                keys,
+                prefix,
+                all,
+                tag,
+                key_hashes,
+                force,
+            };
+            purge(&server, &request).await
+        }
+        Command::Warm { server, urls, file } => warm(&server, urls, file.as_deref()).await,
+        Command::Stats { server } => stats(&server).await,
+        Command::Status { server } => status(&server).await,
+        Command::CheckConfig { path, json } => Ok(check_config(&path, json)),
+    };
+    result.unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        e.exit_code()
+    })
+}
+
+async fn purge(server: &ServerArgs, request: &PurgeRequest) -> Result<u8, CliError> {
+    let (_, response): (_, PurgeResponse) =
+        server.send(server.post_json("/purge", request)?).await?;
+
+    if server.json {
+        print_json(&response);
+    } else {
+        println!("{}", response.message);
+        if response.cancelled_fills > 0 {
+            println!("Cancelled {} in-flight fills", response.cancelled_fills);
+        }
+        for violation in &response.errors {
+            println!(
+                "  rejected {} {}: {}",
+                violation.kind, violation.item, violation.error
+            );
+        }
+        for hash in &response.unknown_key_hashes {
+            println!("  unknown key hash {}", hash);
+        }
+    }
+
+    Ok(if response.success && response.errors.is_empty() {
+        EXIT_OK
+    } else {
+        EXIT_FAILURE
+    })
+}
+
+async fn warm(
+    server: &ServerArgs,
+    mut urls: Vec<String>,
+    file: Option<&Path>,
+) -> Result<u8, CliError> {
+    if let Some(path) = file {
+        let content = if path == Path::new("-") {
+            std::io::read_to_string(std::io::stdin())
+        } else {
+            std::fs::read_to_string(path)
+        }
+        .map_err(|e| CliError::Usage(format!("failed to read {}: {}", path.display(), e)))?;
+        urls.extend(parse_url_list(&content));
+    }
+    if urls.is_empty() {
+        return Err(CliError::Usage("no URLs to warm".to_string()));
+    }
+
+    let request = WarmCacheRequest {
+        urls: urls.into_iter().map(WarmTarget::from).collect(),
+    };
+    let (_, response): (_, WarmCacheResponse) = server
+        .send(server.post_json("/warm?sync=true", &request)?)
+        .await?;
+
+    if server.json {
+        print_json(&response);
+    } else {
+        for result in &response.results {
+            let target = match &result.range {
+                Some(range) => format!("{} bytes={}", result.url, range),
+                None => result.url.clone(),
+            };
+            match (&result.error, &result.cache_status) {
+                (Some(error), _) => println!("  FAIL {}: {}", target, error),
+                (None, Some(status)) => println!("  ok   {} ({})", target, status),
+                (None, None) => println!("  ok   {}", target),
+            }
+        }
+        println!("Warmed {}, failed {}", response.warmed, response.failed);
+    }
+
+    Ok(if response.failed == 0 {
+        EXIT_OK
+    } else {
+        EXIT_FAILURE
+    })
+}
+
+async fn stats(server: &ServerArgs) -> Result<u8, CliError> {
+    let (_, stats): (_, CacheStats) = server.send(server.request(Method::GET, "/stats")?).await?;
+
+    if server.json {
+        print_json(&stats);
+    } else {
+        println!(
+            "Entries:       {} ({} hot, {} tagged)",
+            stats.total_entries, stats.hot_entries, stats.tagged_entries
+        );
+        println!(
+            "Size:          {} / {} bytes (avg {} per entry)",
+            stats.total_size_bytes, stats.max_size_bytes, stats.avg_entry_size_bytes
+        );
+        println!(
+            "Hit ratio:     {:.1}% ({} hits, {} misses)",
+            stats.hit_ratio * 100.0,
+            stats.hits,
+            stats.misses
+        );
+        println!(
+            "Stale hits:    {} ({} adaptive)",
+            stats.stale_hits, stats.adaptive_stale_hits
+        );
+        println!("Early refresh: {}", stats.early_refreshes);
+        println!("Evictions:     {}", stats.evictions);
+        println!("Tags:          {}", stats.total_tags);
+    }
+    Ok(EXIT_OK)
+}
+
+/// What `status` reports: the node's own health and its view of each origin
+#[derive(Debug, Serialize)]
+struct StatusReport {
+    node: HealthResponse,
+    origins: OriginHealthResponse,
+}
+
+async fn status(server: &ServerArgs) -> Result<u8, CliError> {
+    let (_, node): (_, HealthResponse) =
+        server.send(server.request(Method::GET, "/health")?).await?;
+    let (_, origins): (_, OriginHealthResponse) = server
+        .send(server.request(Method::GET, "/origins/health")?)
+        .await?;
+
+    let unhealthy = origins
+        .origins
+        .values()
+        .filter(|health| health.status == HealthStatus::Unhealthy)
+        .count();
+
+    if server.json {
+        print_json(&StatusReport { node, origins });
+    } else {
+        println!("Node: {} (v{})", node.status, node.version);
+        let mut names: Vec<&String> = origins.origins.keys().collect();
+        names.sort();
+        for name in names {
+            let health = &origins.origins[name];
+            let status = serde_json::to_value(health.status)
+                .ok()
+                .and_then(|v| v.as_str().map(str::to_string))
+                .unwrap_or_default();
+            let mut line = format!("  {:<20} {:<9}", name, status);
+            if let Some(ms) = health.response_time_ms {
+                line.push_str(&format!(" {}ms", ms));
+            }
+            if let Some(percent) = origins
+                .availability
+                .get(name)
+                .and_then(|a| a.windows.get("24h"))
+                .and_then(|w| w.availability_percent)
+            {
+                line.push_str(&format!(" {:.2}% (24h)", percent));
+            }
+            if let Some(error) = &health.error_message {
+                line.push_str(&format!(" - {}", error));
+            }
+            println!("{}", line.trim_end());
+        }
+        if unhealthy > 0 {
+            println!("{} origin(s) unhealthy", unhealthy);
+        }
+    }
+
+    Ok(if unhealthy == 0 {
+        EXIT_OK
+    } else {
+        EXIT_FAILURE
+    })
+}
+
+/// What `check-config --json` reports
+#[derive(Debug, Serialize)]
+struct ConfigCheckReport {
+    path: String,
+    valid: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    origins: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn check_config(path: &Path, json: bool) -> u8 {
+    let result = Config::load(path);
+    let report = ConfigCheckReport {
+        path: path.display().to_string(),
+        valid: result.is_ok(),
+        origins: result
+            .as_ref()
+            .map(|config| {
+                let mut names: Vec<String> = config.origins.keys().cloned().collect();
+                names.sort();
+                names
+            })
+            .unwrap_or_default(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+    };
+
+    if json {
+        print_json(&report);
+    } else if let Some(error) = &report.error {
+        eprintln!("{}: invalid: {}", report.path, error);
+    } else {
+        println!(
+            "{}: ok ({} origins: {})",
+            report.path,
+            report.origins.len(),
+            report.origins.join(", ")
+        );
+    }
+
+    if report.valid { EXIT_OK } else { EXIT_FAILURE }
+}
+
+impl ServerArgs {
+    /// A request to `/_cdn{path}` carrying the token, if any
+    fn request(&self, method: Method, path: &str) -> Result<RequestBuilder, CliError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(self.timeout))
+            .build()
+            .map_err(|e| CliError::Usage(format!("failed to build HTTP client: {}", e)))?;
+        let url = format!("{}/_cdn{}", self.server.trim_end_matches('/'), path);
+        let request = client.request(method, url);
+        Ok(match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        })
+    }
+
+    /// A POST to `/_cdn{path}` with `body` encoded as JSON
+    fn post_json<B: Serialize>(&self, path: &str, body: &B) -> Result<RequestBuilder, CliError> {
+        let body = serde_json::to_vec(body)
+            .map_err(|e| CliError::Usage(format!("failed to encode request: {}", e)))?;
+        Ok(self
+            .request(Method::POST, path)?
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body))
+    }
+
+    /// Send a request and decode the response body as `T`
+    ///
+    /// An error status is still decoded when its body has the expected
+    /// shape (a purge rejected for scope answers 403 with a `PurgeResponse`);
+    /// anything else becomes `CliError::Status`.
+    async fn send<T: DeserializeOwned>(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<(StatusCode, T), CliError> {
+        let unreachable = |source: reqwest::Error| CliError::Unreachable {
+            url: source
+                .url()
+                .map(|url| url.to_string())
+                .unwrap_or_else(|| self.server.clone()),
+            source: source.without_url(),
+        };
+        let response = request.send().await.map_err(unreachable)?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(unreachable)?;
+
+        match serde_json::from_slice::<T>(&body) {
+            Ok(value) => Ok((status, value)),
+            Err(_) if !status.is_success() => Err(CliError::Status {
+                status,
+                message: error_message(&body),
+            }),
+            Err(e) => Err(CliError::InvalidResponse(e.to_string())),
+        }
+    }
+}
+
+/// The `error` field of a JSON error body, or the body itself
+fn error_message(body: &[u8]) -> String {
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|v| v.get("error")?.as_str().map(str::to_string))
+        .unwrap_or_else(|| {
+            let text = String::from_utf8_lossy(body);
+            text.trim().chars().take(200).collect()
+        })
+}
+
+/// URLs from a warm list: one per line, blank lines and `#` comments skipped
+fn parse_url_list(content: &str) -> impl Iterator<Item = String> + '_ {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("error: failed to encode response: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::WarmResult;
+    use crate::health::OriginHealth;
+    use axum::{
+        Json, Router,
+        http::HeaderMap,
+        routing::{get, post},
+    };
+    use clap::CommandFactory;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex};
+
+    fn server_args(addr: std::net::SocketAddr) -> ServerArgs {
+        ServerArgs {
+            server: format!("http://{}/", addr),
+            token: Some("secret".to_string()),
+            timeout: 5,
+            json: false,
+        }
+    }
+
+    async fn spawn_server(router: Router) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        addr
+    }
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from([
+            "screaming-eagle",
+            "purge",
+            "--prefix",
+            "/static/",
+            "--key",
+            "web/a",
+            "--key",
+            "web/b",
+            "--server",
+            "http://cdn:8080",
+            "--token",
+            "t",
+            "--json",
+        ])
+        .unwrap();
+        match cli.command {
+            Some(Command::Purge {
+                server,
+                keys,
+                prefix,
+                tag,
+                all,
+                ..
+            }) => {
+                assert_eq!(keys, ["web/a", "web/b"]);
+                assert_eq!(prefix.as_deref(), Some("/static/"));
+                assert!(tag.is_none() && !all);
+                assert_eq!(server.server, "http://cdn:8080");
+                assert_eq!(server.token.as_deref(), Some("t"));
+                assert!(server.json);
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+
+        // No subcommand runs the server; a purge needs something to purge
+        assert!(
+            Cli::try_parse_from(["screaming-eagle"])
+                .unwrap()
+                .command
+                .is_none()
+        );
+        let err = Cli::try_parse_from(["screaming-eagle", "purge"]).unwrap_err();
+        assert_eq!(err.exit_code(), EXIT_USAGE as i32);
+    }
+
+    #[tokio::test]
+    async fn test_purge_sends_request_and_maps_outcome() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new().route(
+            "/_cdn/purge",
+            post({
+                let seen = seen.clone();
+                move |headers: HeaderMap, Json(request): Json<PurgeRequest>| async move {
+                    let auth = headers
+                        .get("authorization")
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default()
+                        .to_string();
+                    seen.lock().unwrap().push((auth, request.prefix.clone()));
+                    if request.prefix.as_deref() == Some("/private/") {
+                        let errors = vec![crate::auth::PurgeScopeViolation {
+                            kind: "prefix".to_string(),
+                            item: "/private/".to_string(),
+                            error: "outside scope".to_string(),
+                        }];
+                        return (
+                            StatusCode::FORBIDDEN,
+                            Json(PurgeResponse {
+                                success: false,
+                                message: "1 item(s) outside the purge token's scope".to_string(),
+                                purged_count: 0,
+                                cancelled_fills: 0,
+                                errors,
+                                deduplicated: false,
+                                unknown_key_hashes: Vec::new(),
+                            }),
+                        );
+                    }
+                    (
+                        StatusCode::OK,
+                        Json(PurgeResponse {
+                            success: true,
+                            message: "Purged".to_string(),
+                            purged_count: 3,
+                            cancelled_fills: 0,
+                            errors: Vec::new(),
+                            deduplicated: false,
+                            unknown_key_hashes: Vec::new(),
+                        }),
+                    )
+                }
+            }),
+        );
+        let server = server_args(spawn_server(router).await);
+
+        let request = |prefix: &str| PurgeRequest {
+            prefix: Some(prefix.to_string()),
+            ..PurgeRequest::default()
+        };
+        assert_eq!(purge(&server, &request("/static/")).await.unwrap(), EXIT_OK);
+        assert_eq!(
+            purge(&server, &request("/private/")).await.unwrap(),
+            EXIT_FAILURE
+        );
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                ("Bearer secret".to_string(), Some("/static/".to_string())),
+                ("Bearer secret".to_string(), Some("/private/".to_string())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_map_to_exit_codes() {
+        let router = Router::new().route(
+            "/_cdn/stats",
+            get(|| async {
+                (
+                    StatusCode::UNAUTHORIZED,
+                    Json(serde_json::json!({"error": "Invalid token", "status": 401})),
+                )
+            }),
+        );
+        let server = server_args(spawn_server(router).await);
+        let err = stats(&server).await.unwrap_err();
+        assert!(matches!(err, CliError::Status { status, ref message }
+            if status == StatusCode::UNAUTHORIZED && message == "Invalid token"));
+        assert_eq!(err.exit_code(), EXIT_UNAVAILABLE);
+
+        // Nothing listening
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let err = stats(&server_args(addr)).await.unwrap_err();
+        assert!(matches!(err, CliError::Unreachable { .. }));
+        assert_eq!(
+            run(Command::Stats {
+                server: server_args(addr)
+            })
+            .await,
+            EXIT_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_warm_and_status_fail_on_partial_failure() {
+        let router = Router::new()
+            .route(
+                "/_cdn/warm",
+                post(|Json(request): Json<WarmCacheRequest>| async move {
+                    let results: Vec<WarmResult> = request
+                        .urls
+                        .iter()
+                        .map(|target| target.url())
+                        .map(|url| WarmResult {
+                            url: url.to_string(),
+                            success: !url.contains("missing"),
+                            cached: !url.contains("missing"),
+                            cache_status: Some("MISS".to_string()),
+                            range: None,
+                            error: url.contains("missing").then(|| "404".to_string()),
+                        })
+                        .collect();
+                    let failed = results.iter().filter(|r| !r.success).count();
+                    Json(WarmCacheResponse {
+                        success: failed == 0,
+                        message: String::new(),
+                        warmed: results.len() - failed,
+                        failed,
+                        results,
+                    })
+                }),
+            )
+            .route(
+                "/_cdn/health",
+                get(|| async {
+                    Json(HealthResponse {
+                        status: "healthy".to_string(),
+                        version: "test".to_string(),
+                    })
+                }),
+            )
+            .route(
+                "/_cdn/origins/health",
+                get(|| async {
+                    let unhealthy = OriginHealth {
+                        status: HealthStatus::Unhealthy,
+                        ..OriginHealth::default()
+                    };
+                    Json(OriginHealthResponse {
+                        origins: HashMap::from([
+                            ("web".to_string(), OriginHealth::default()),
+                            ("api".to_string(), unhealthy),
+                        ]),
+                        availability: BTreeMap::new(),
+                        cluster: None,
+                    })
+                }),
+            );
+        let server = server_args(spawn_server(router).await);
+
+        let urls = |list: &[&str]| list.iter().map(|u| u.to_string()).collect();
+        assert_eq!(
+            warm(&server, urls(&["/web/a", "/web/b"]), None)
+                .await
+                .unwrap(),
+            EXIT_OK
+        );
+        assert_eq!(
+            warm(&server, urls(&["/web/a", "/web/missing"]), None)
+                .await
+                .unwrap(),
+            EXIT_FAILURE
+        );
+        assert!(matches!(
+            warm(&server, Vec::new(), None).await,
+            Err(CliError::Usage(_))
+        ));
+
+        assert_eq!(status(&server).await.unwrap(), EXIT_FAILURE);
+    }
+
+    #[test]
+    fn test_parse_url_list() {
+        let urls: Vec<String> = parse_url_list("/web/a\n\n  # comment\n /web/b  \n").collect();
+        assert_eq!(urls, ["/web/a", "/web/b"]);
+    }
+
+    #[test]
+    fn test_check_config() {
+        let dir = std::env::temp_dir().join(format!("cdn-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let valid = dir.join("valid.toml");
+        std::fs::write(&valid, "[origins.web]\nurl = \"http://localhost:3000\"\n").unwrap();
+        assert_eq!(check_config(&valid, false), EXIT_OK);
+
+        let invalid = dir.join("invalid.toml");
+        std::fs::write(&invalid, "[availability]\nbucket_secs = 0\n").unwrap();
+        assert_eq!(check_config(&invalid, true), EXIT_FAILURE);
+
+        assert_eq!(check_config(&dir.join("missing.toml"), false), EXIT_FAILURE);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,28 +3,75 @@
 //! Prevents the "thundering herd" problem by deduplicating concurrent requests
 //! for the same resource. When multiple requests arrive for an uncached resource,
 //! only one request is sent to the origin and all waiters receive the same response.
+//! Requests beyond `max_waiters` for one key fetch independently instead of queuing.
+//!
+//! Fetches are keyed before Vary is known, so a response carries the variant
+//! it was fetched as and a waiter whose request would have been a different
+//! variant fetches for itself.
 
 use bytes::Bytes;
 use dashmap::DashMap;
-use std::collections::HashMap;
+use dashmap::mapref::entry::Entry;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use tokio::sync::broadcast;
 use tracing::{debug, info};
 
+use crate::headers::ResponseHeaders;
+use crate::origin::OriginErrorKind;
+
 /// Result of a coalesced request
 #[derive(Debug, Clone)]
 pub struct CoalescedResponse {
     pub body: Bytes,
-    pub headers: HashMap<String, String>,
+    pub headers: ResponseHeaders,
     pub status_code: u16,
+    /// Variant of the resource the fetch returned, by the response's Vary;
+    /// `None` if it can't be shared with other variants' requests
+    pub variant: Option<String>,
+    /// Whether the leader goes on to cache the response. It stores after
+    /// waking the waiters, so they can't tell by looking in the cache.
+    pub cacheable: bool,
+}
+
+impl CoalescedResponse {
+    /// Whether a waiter whose request is `variant` can use this response
+    pub fn serves(&self, variant: Option<&str>) -> bool {
+        self.variant.is_some() && self.variant.as_deref() == variant
+    }
+}
+
+/// Failure shared with coalesced waiters
+#[derive(Debug, Clone)]
+pub struct CoalescedError {
+    pub message: String,
+    /// Kind of origin failure, so waiters apply the same stale-if-error policy
+    pub kind: Option<OriginErrorKind>,
+}
+
+impl CoalescedError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            kind: None,
+        }
+    }
 }
 
 /// Internal state for the coalescer
 struct CoalescerInner {
     /// Map of cache keys to broadcast channels for in-flight requests
-    in_flight: DashMap<String, broadcast::Sender<Result<CoalescedResponse, String>>>,
+    in_flight: DashMap<String, broadcast::Sender<Result<CoalescedResponse, CoalescedError>>>,
     /// Maximum number of waiters per request
     max_waiters: usize,
+    /// Requests that fetched independently because a key had too many waiters
+    overflows: AtomicU64,
+    /// Waiters that gave up on a slow in-flight fetch
+    waiter_timeouts: AtomicU64,
+    /// Waiters whose request turned out to be a different variant
+    variant_mismatches: AtomicU64,
+    /// Most waiters seen on a single key
+    peak_waiters: AtomicUsize,
 }
 
 /// Manages in-flight requests to prevent duplicate origin fetches
@@ -39,26 +86,42 @@ impl RequestCoalescer {
             inner: Arc::new(CoalescerInner {
                 in_flight: DashMap::new(),
                 max_waiters,
+                overflows: AtomicU64::new(0),
+                waiter_timeouts: AtomicU64::new(0),
+                variant_mismatches: AtomicU64::new(0),
+                peak_waiters: AtomicUsize::new(0),
             }),
         }
     }
 
     /// Try to acquire the right to fetch from origin.
-    /// Returns Ok(None) if this request should fetch from origin.
-    /// Returns Ok(Some(receiver)) if another request is already fetching.
+    /// Returns Fetch if this request should fetch from origin, Wait if another
+    /// request is already fetching, or Overflow if that fetch has no room left.
     pub fn try_acquire(&self, cache_key: &str) -> AcquireResult {
-        // Check if there's already an in-flight request
-        if let Some(sender) = self.inner.in_flight.get(cache_key) {
-            // Subscribe to the existing request
-            let receiver = sender.subscribe();
-            debug!(cache_key = %cache_key, "Coalescing request with in-flight fetch");
-            return AcquireResult::Wait(receiver);
+        match self.inner.in_flight.entry(cache_key.to_string()) {
+            Entry::Occupied(entry) => {
+                let waiters = entry.get().receiver_count();
+                if waiters >= self.inner.max_waiters {
+                    self.inner.overflows.fetch_add(1, Ordering::Relaxed);
+                    debug!(cache_key = %cache_key, waiters, "Coalescing waiter limit reached, fetching independently");
+                    return AcquireResult::Overflow;
+                }
+
+                // Subscribe to the existing request
+                let receiver = entry.get().subscribe();
+                self.inner
+                    .peak_waiters
+                    .fetch_max(waiters + 1, Ordering::Relaxed);
+                debug!(cache_key = %cache_key, "Coalescing request with in-flight fetch");
+                return AcquireResult::Wait(receiver);
+            }
+            Entry::Vacant(entry) => {
+                // Only one result is ever sent, so one slot is enough
+                let (tx, _) = broadcast::channel(1);
+                entry.insert(tx);
+            }
         }
 
-        // No in-flight request, create a new broadcast channel
-        let (tx, _) = broadcast::channel(self.inner.max_waiters);
-        self.inner.in_flight.insert(cache_key.to_string(), tx);
-
         debug!(cache_key = %cache_key, "Acquired origin fetch lock");
         AcquireResult::Fetch(FetchGuard {
             cache_key: cache_key.to_string(),
@@ -79,8 +142,25 @@ impl RequestCoalescer {
         CoalesceStats {
             in_flight_requests: in_flight_count,
             total_waiters,
+            overflows: self.inner.overflows.load(Ordering::Relaxed),
+            waiter_timeouts: self.inner.waiter_timeouts.load(Ordering::Relaxed),
+            variant_mismatches: self.inner.variant_mismatches.load(Ordering::Relaxed),
+            peak_waiters: self.inner.peak_waiters.load(Ordering::Relaxed),
         }
     }
+
+    /// Record a waiter that stopped waiting for a slow in-flight fetch
+    pub fn record_waiter_timeout(&self) {
+        self.inner.waiter_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a waiter that refetched because the shared response was for
+    /// another variant
+    pub fn record_variant_mismatch(&self) {
+        self.inner
+            .variant_mismatches
+            .fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 /// Result of trying to acquire a fetch lock
@@ -88,7 +168,9 @@ pub enum AcquireResult {
     /// This request should fetch from origin
     Fetch(FetchGuard),
     /// Another request is fetching, wait for result
-    Wait(broadcast::Receiver<Result<CoalescedResponse, String>>),
+    Wait(broadcast::Receiver<Result<CoalescedResponse, CoalescedError>>),
+    /// Another request is fetching but already has `max_waiters`; fetch without coalescing
+    Overflow,
 }
 
 /// Guard that ensures we notify waiters when the fetch completes
@@ -104,11 +186,11 @@ impl FetchGuard {
     }
 
     /// Complete the fetch with an error
-    pub fn complete_error(self, error: String) {
+    pub fn complete_error(self, error: CoalescedError) {
         self.complete_internal(Err(error));
     }
 
-    fn complete_internal(self, result: Result<CoalescedResponse, String>) {
+    fn complete_internal(self, result: Result<CoalescedResponse, CoalescedError>) {
         if let Some((_, sender)) = self.inner.in_flight.remove(&self.cache_key) {
             let waiter_count = sender.receiver_count();
             if waiter_count > 0 {
@@ -131,7 +213,7 @@ impl Drop for FetchGuard {
         // If the guard is dropped without completing, remove the in-flight entry
         // This handles panics or early returns
         if let Some((_, sender)) = self.inner.in_flight.remove(&self.cache_key) {
-            let _ = sender.send(Err("Request was cancelled".to_string()));
+            let _ = sender.send(Err(CoalescedError::new("Request was cancelled")));
         }
     }
 }
@@ -141,6 +223,15 @@ impl Drop for FetchGuard {
 pub struct CoalesceStats {
     pub in_flight_requests: usize,
     pub total_waiters: usize,
+    /// Requests that fetched independently because `max_waiters` was reached
+    pub overflows: u64,
+    /// Waiters that stopped waiting after the waiter timeout
+    pub waiter_timeouts: u64,
+    /// Waiters that refetched because the response varied on a header their
+    /// request had a different value for
+    pub variant_mismatches: u64,
+    /// Most waiters observed on a single in-flight fetch
+    pub peak_waiters: usize,
 }
 
 #[cfg(test)]
@@ -156,11 +247,13 @@ mod tests {
             AcquireResult::Fetch(guard) => {
                 guard.complete(CoalescedResponse {
                     body: Bytes::from("hello"),
-                    headers: HashMap::new(),
+                    headers: ResponseHeaders::new(),
                     status_code: 200,
+                    variant: None,
+                    cacheable: false,
                 });
             }
-            AcquireResult::Wait(_) => panic!("Should have acquired fetch lock"),
+            _ => panic!("Should have acquired fetch lock"),
         }
 
         // After completion, a new request should get a fresh fetch lock
@@ -168,11 +261,13 @@ mod tests {
             AcquireResult::Fetch(guard) => {
                 guard.complete(CoalescedResponse {
                     body: Bytes::from("hello2"),
-                    headers: HashMap::new(),
+                    headers: ResponseHeaders::new(),
                     status_code: 200,
+                    variant: None,
+                    cacheable: false,
                 });
             }
-            AcquireResult::Wait(_) => panic!("Should have acquired fetch lock"),
+            _ => panic!("Should have acquired fetch lock"),
         }
     }
 
@@ -183,26 +278,28 @@ mod tests {
         // First request acquires lock
         let guard = match coalescer.try_acquire("test-key") {
             AcquireResult::Fetch(guard) => guard,
-            AcquireResult::Wait(_) => panic!("Should have acquired fetch lock"),
+            _ => panic!("Should have acquired fetch lock"),
         };
 
         // Second request should wait
         let mut receiver = match coalescer.try_acquire("test-key") {
             AcquireResult::Wait(rx) => rx,
-            AcquireResult::Fetch(_) => panic!("Should have waited"),
+            _ => panic!("Should have waited"),
         };
 
         // Third request should also wait
         let mut receiver2 = match coalescer.try_acquire("test-key") {
             AcquireResult::Wait(rx) => rx,
-            AcquireResult::Fetch(_) => panic!("Should have waited"),
+            _ => panic!("Should have waited"),
         };
 
         // Complete the first request
         guard.complete(CoalescedResponse {
             body: Bytes::from("shared response"),
-            headers: HashMap::new(),
+            headers: ResponseHeaders::new(),
             status_code: 200,
+            variant: None,
+            cacheable: false,
         });
 
         // Both waiters should receive the response
@@ -219,19 +316,19 @@ mod tests {
 
         let guard = match coalescer.try_acquire("test-key") {
             AcquireResult::Fetch(guard) => guard,
-            AcquireResult::Wait(_) => panic!("Should have acquired fetch lock"),
+            _ => panic!("Should have acquired fetch lock"),
         };
 
         let mut receiver = match coalescer.try_acquire("test-key") {
             AcquireResult::Wait(rx) => rx,
-            AcquireResult::Fetch(_) => panic!("Should have waited"),
+            _ => panic!("Should have waited"),
         };
 
-        guard.complete_error("origin error".to_string());
+        guard.complete_error(CoalescedError::new("origin error"));
 
         let result = receiver.recv().await.unwrap();
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "origin error");
+        assert_eq!(result.unwrap_err().message, "origin error");
     }
 
     #[test]
@@ -245,7 +342,7 @@ mod tests {
         // Acquire a lock and keep it
         let guard = match coalescer.try_acquire("test-key") {
             AcquireResult::Fetch(guard) => guard,
-            AcquireResult::Wait(_) => panic!("Should have acquired fetch lock"),
+            _ => panic!("Should have acquired fetch lock"),
         };
 
         let stats = coalescer.stats();
@@ -254,8 +351,81 @@ mod tests {
         // Complete the guard to clean up
         guard.complete(CoalescedResponse {
             body: Bytes::from("test"),
-            headers: HashMap::new(),
+            headers: ResponseHeaders::new(),
+            status_code: 200,
+            variant: None,
+            cacheable: false,
+        });
+    }
+
+    #[tokio::test]
+    async fn test_overflow_beyond_max_waiters() {
+        let coalescer = RequestCoalescer::new(2);
+
+        let guard = match coalescer.try_acquire("test-key") {
+            AcquireResult::Fetch(guard) => guard,
+            _ => panic!("Should have acquired fetch lock"),
+        };
+
+        let mut receivers = Vec::new();
+        for _ in 0..2 {
+            match coalescer.try_acquire("test-key") {
+                AcquireResult::Wait(rx) => receivers.push(rx),
+                _ => panic!("Should have waited"),
+            }
+        }
+
+        // The third waiter doesn't fit and must fetch on its own
+        assert!(matches!(
+            coalescer.try_acquire("test-key"),
+            AcquireResult::Overflow
+        ));
+
+        let stats = coalescer.stats();
+        assert_eq!(stats.total_waiters, 2);
+        assert_eq!(stats.overflows, 1);
+        assert_eq!(stats.peak_waiters, 2);
+
+        guard.complete(CoalescedResponse {
+            body: Bytes::from("shared"),
+            headers: ResponseHeaders::new(),
             status_code: 200,
+            variant: None,
+            cacheable: false,
         });
+        for mut rx in receivers {
+            assert_eq!(
+                rx.recv().await.unwrap().unwrap().body,
+                Bytes::from("shared")
+            );
+        }
+    }
+
+    #[test]
+    fn test_waiter_timeout_counter() {
+        let coalescer = RequestCoalescer::new(100);
+        coalescer.record_waiter_timeout();
+        coalescer.record_waiter_timeout();
+
+        assert_eq!(coalescer.stats().waiter_timeouts, 2);
+    }
+
+    #[test]
+    fn test_variant_matching() {
+        let response = |variant: Option<&str>| CoalescedResponse {
+            body: Bytes::from("shared"),
+            headers: ResponseHeaders::new(),
+            status_code: 200,
+            variant: variant.map(str::to_string),
+            cacheable: false,
+        };
+        assert!(response(Some("web/a")).serves(Some("web/a")));
+        assert!(!response(Some("web/a|vary:accept-language=en")).serves(Some("web/a")));
+        // Vary: * is never shared
+        assert!(!response(None).serves(None));
+
+        let coalescer = RequestCoalescer::new(100);
+        coalescer.record_variant_mismatch();
+        assert_eq!(coalescer.stats().variant_mismatches, 1);
     }
 }
@@ -0,0 +1,153 @@
+//! Which responses the CDN compresses for clients
+//!
+//! The compression layer asks [`CompressionPolicy`] about every response it
+//! could encode. Small bodies, content types that are already compressed and
+//! anything marked [`SkipCompression`] go out as they are, keeping their
+//! `Content-Length` and `Accept-Ranges`. The handler marks responses from
+//! origins with `compress_responses = false` and paths covered by a
+//! `[[compression.bypass]]` rule, since the policy only sees the response.
+
+use axum::http::{Response, header};
+use http_body::Body;
+use std::sync::Arc;
+use tower_http::compression::Predicate;
+
+use crate::config::CompressionConfig;
+
+/// Response extension that keeps the CDN from compressing a response
+#[derive(Debug, Clone, Copy)]
+pub struct SkipCompression;
+
+/// [`Predicate`] built from `[compression]`
+#[derive(Debug, Clone)]
+pub struct CompressionPolicy {
+    config: Arc<CompressionConfig>,
+}
+
+impl CompressionPolicy {
+    pub fn new(config: CompressionConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl Predicate for CompressionPolicy {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: Body,
+    {
+        if !self.config.enabled || response.extensions().get::<SkipCompression>().is_some() {
+            return false;
+        }
+        let size = response.body().size_hint().exact().or_else(|| {
+            response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse().ok())
+        });
+        if size.is_some_and(|size| size < self.config.min_size_bytes) {
+            return false;
+        }
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        self.config.compresses_content_type(content_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use tower_http::compression::CompressionLayer;
+
+    /// A server compressing with the default policy, and a client that
+    /// leaves responses encoded
+    async fn serve() -> (reqwest::Client, String) {
+        let json = |size: usize| {
+            (
+                [(header::CONTENT_TYPE, "application/json")],
+                format!("\"{}\"", "a".repeat(size - 2)),
+            )
+        };
+        let app = Router::new()
+            .route("/small.json", get(move || async move { json(200) }))
+            .route("/large.json", get(move || async move { json(4096) }))
+            .route(
+                "/photo.jpg",
+                get(|| async { ([(header::CONTENT_TYPE, "image/jpeg")], vec![0xffu8; 4096]) }),
+            )
+            .route(
+                "/marked.json",
+                get(move || async move {
+                    let mut response = json(4096).into_response();
+                    response.extensions_mut().insert(SkipCompression);
+                    response
+                }),
+            )
+            .layer(
+                CompressionLayer::new()
+                    .compress_when(CompressionPolicy::new(CompressionConfig::default())),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let client = reqwest::Client::builder()
+            .no_gzip()
+            .no_brotli()
+            .build()
+            .unwrap();
+        (client, format!("http://{}", addr))
+    }
+
+    async fn get_gzip(client: &reqwest::Client, url: String) -> reqwest::Response {
+        client
+            .get(url)
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_small_and_precompressed_responses_pass_through() {
+        let (client, base) = serve().await;
+        for (path, size) in [("/small.json", 200), ("/photo.jpg", 4096)] {
+            let response = get_gzip(&client, format!("{}{}", base, path)).await;
+            assert!(
+                response.headers().get(header::CONTENT_ENCODING).is_none(),
+                "{}",
+                path
+            );
+            assert_eq!(
+                response.headers().get(header::CONTENT_LENGTH).unwrap(),
+                &size.to_string(),
+                "{}",
+                path
+            );
+            assert_eq!(response.bytes().await.unwrap().len(), size);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_text_compressed_unless_marked() {
+        let (client, base) = serve().await;
+        let response = get_gzip(&client, format!("{}/large.json", base)).await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+
+        let response = get_gzip(&client, format!("{}/marked.json", base)).await;
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(
+            response.headers().get(header::CONTENT_LENGTH).unwrap(),
+            "4096"
+        );
+    }
+}
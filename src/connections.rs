@@ -0,0 +1,332 @@
+//! Client connection tracking and socket options
+//!
+//! Every listener's make-service is wrapped so each accepted connection is
+//! counted when its service is made and again when hyper drops the service
+//! and the last response body, which is when the connection has closed. A
+//! connection that ends while a response on it is unfinished (the client
+//! went away, or shutdown cut it off) counts as aborted rather than closed.
+
+use axum::BoxError;
+use axum::body::{Body, Bytes};
+use axum::http::Response;
+use axum_server::accept::Accept;
+use http_body::Body as HttpBody;
+use socket2::{SockRef, TcpKeepalive};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tower::Service;
+use tracing::debug;
+
+use crate::bandwidth::CountingBody;
+use crate::config::ServerConfig;
+use crate::metrics::Metrics;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Set the options accepted client connections inherit from `listener`
+///
+/// Linux and the BSDs copy `TCP_NODELAY` and the keepalive settings from a
+/// listening socket to the connections it accepts, so they're set once here
+/// rather than on every accept.
+pub fn set_client_socket_options(
+    listener: &std::net::TcpListener,
+    server: &ServerConfig,
+) -> io::Result<()> {
+    let socket = SockRef::from(listener);
+    socket.set_tcp_nodelay(server.tcp_nodelay)?;
+    match server.tcp_keepalive() {
+        Some(idle) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle)),
+        None => socket.set_keepalive(false),
+    }
+}
+
+/// Counts the client connections open across every listener
+pub struct ConnectionTracker {
+    open: AtomicUsize,
+    metrics: Arc<Metrics>,
+}
+
+impl ConnectionTracker {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            open: AtomicUsize::new(0),
+            metrics,
+        }
+    }
+
+    /// Client connections open now
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Acquire)
+    }
+
+    /// Wrap a listener's make-service so the connections it serves are tracked
+    pub fn track<M>(self: &Arc<Self>, make_service: M) -> TrackConnections<M> {
+        TrackConnections {
+            inner: make_service,
+            tracker: self.clone(),
+        }
+    }
+
+    /// Wrap a TLS acceptor so failed handshakes are counted
+    pub fn count_handshake_failures<A>(&self, acceptor: A) -> CountHandshakeFailures<A> {
+        CountHandshakeFailures {
+            inner: acceptor,
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    fn opened(self: &Arc<Self>) -> Arc<Connection> {
+        let open = self.open.fetch_add(1, Ordering::AcqRel) + 1;
+        self.metrics.record_connection_accepted(open);
+        Arc::new(Connection {
+            tracker: self.clone(),
+            opened_at: Instant::now(),
+            aborted: AtomicBool::new(false),
+        })
+    }
+}
+
+/// One client connection, closed when the last reference drops
+struct Connection {
+    tracker: Arc<ConnectionTracker>,
+    opened_at: Instant,
+    /// A response on it was dropped before it was fully sent
+    aborted: AtomicBool,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let open = self.tracker.open.fetch_sub(1, Ordering::AcqRel) - 1;
+        self.tracker.metrics.record_connection_closed(
+            open,
+            self.opened_at.elapsed(),
+            self.aborted.load(Ordering::Acquire),
+        );
+    }
+}
+
+/// A response in progress on a connection; dropped unfinished, it marks
+/// the connection aborted
+struct InFlight {
+    connection: Arc<Connection>,
+    finished: bool,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.connection.aborted.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// Make-service that hands out [`TrackedConnection`]s
+#[derive(Clone)]
+pub struct TrackConnections<M> {
+    inner: M,
+    tracker: Arc<ConnectionTracker>,
+}
+
+impl<M, T> Service<T> for TrackConnections<M>
+where
+    M: Service<T>,
+    M::Future: Send + 'static,
+{
+    type Response = TrackedConnection<M::Response>;
+    type Error = M::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        let tracker = self.tracker.clone();
+        let make = self.inner.call(target);
+        Box::pin(async move {
+            let service = make.await?;
+            Ok(TrackedConnection {
+                inner: service,
+                connection: tracker.opened(),
+            })
+        })
+    }
+}
+
+/// A connection's service; the connection stays open while any clone of it
+/// or any of its response bodies is alive
+#[derive(Clone)]
+pub struct TrackedConnection<S> {
+    inner: S,
+    connection: Arc<Connection>,
+}
+
+impl<S, R, B> Service<R> for TrackedConnection<S>
+where
+    S: Service<R, Response = Response<B>>,
+    S::Future: Send + 'static,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let in_flight = InFlight {
+            connection: self.connection.clone(),
+            finished: false,
+        };
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await?;
+            Ok(response.map(|body| {
+                Body::new(CountingBody::new(Body::new(body), move |outcome| {
+                    let mut in_flight = in_flight;
+                    in_flight.finished = outcome.completed;
+                }))
+            }))
+        })
+    }
+}
+
+/// TLS acceptor that counts the handshakes failing or timing out
+#[derive(Clone)]
+pub struct CountHandshakeFailures<A> {
+    inner: A,
+    metrics: Arc<Metrics>,
+}
+
+impl<A, I, S> Accept<I, S> for CountHandshakeFailures<A>
+where
+    A: Accept<I, S>,
+    A::Future: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = BoxFuture<io::Result<(A::Stream, A::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let handshake = self.inner.accept(stream, service);
+        let metrics = self.metrics.clone();
+        Box::pin(async move {
+            let result = handshake.await;
+            if let Err(e) = &result {
+                metrics.record_tls_handshake_failure();
+                debug!(error = %e, "TLS handshake failed");
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::routing::get;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn wait_for(tracker: &ConnectionTracker, open: usize) {
+        for _ in 0..200 {
+            if tracker.open() == open {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} connections open, expected {}", tracker.open(), open);
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_counted_and_aborts_told_apart() {
+        let metrics = Arc::new(Metrics::new());
+        let tracker = Arc::new(ConnectionTracker::new(metrics.clone()));
+        let (release, released) = tokio::sync::watch::channel(false);
+        let app = Router::new().route("/ok", get(|| async { "ok" })).route(
+            "/slow",
+            get(move || {
+                let mut released = released.clone();
+                async move {
+                    let _ = released.wait_for(|r| *r).await;
+                    "late"
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let make_service = tracker.track(app.into_make_service_with_connect_info::<SocketAddr>());
+        tokio::spawn(async move { axum::serve(listener, make_service).await });
+
+        // A keep-alive connection answered in full, then closed by the client
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /ok HTTP/1.1\r\nhost: cdn\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        let read = client.read(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..read]).ends_with("ok"));
+        wait_for(&tracker, 1).await;
+        assert!(metrics.gather().contains("cdn_active_connections 1"));
+        drop(client);
+        wait_for(&tracker, 0).await;
+
+        // A client that leaves before its response is ready
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /slow HTTP/1.1\r\nhost: cdn\r\n\r\n")
+            .await
+            .unwrap();
+        wait_for(&tracker, 1).await;
+        drop(client);
+        wait_for(&tracker, 0).await;
+        let _ = release.send(true);
+
+        let text = metrics.gather();
+        assert!(text.contains("cdn_connections_total{event=\"accepted\"} 2"));
+        assert!(text.contains("cdn_connections_total{event=\"closed\"} 1"));
+        assert!(text.contains("cdn_connections_total{event=\"aborted\"} 1"));
+        assert!(text.contains("cdn_connection_duration_seconds_count 2"));
+        assert!(text.contains("cdn_active_connections 0"));
+    }
+
+    #[tokio::test]
+    async fn test_accepted_connections_inherit_socket_options() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = ServerConfig {
+            tcp_nodelay: true,
+            tcp_keepalive: true,
+            ..crate::config::Config::default().server
+        };
+        set_client_socket_options(&listener, &server).unwrap();
+
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let accepted = SockRef::from(&accepted);
+        assert!(accepted.tcp_nodelay().unwrap());
+        assert!(accepted.keepalive().unwrap());
+
+        let off = ServerConfig {
+            tcp_nodelay: false,
+            tcp_keepalive: false,
+            ..server
+        };
+        set_client_socket_options(&listener, &off).unwrap();
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let accepted = SockRef::from(&accepted);
+        assert!(!accepted.tcp_nodelay().unwrap());
+        assert!(!accepted.keepalive().unwrap());
+    }
+}
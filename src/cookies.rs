@@ -0,0 +1,182 @@
+//! Set-Cookie rewriting
+//!
+//! Origins that sit behind the CDN often set cookies for their internal host
+//! name and without the attributes a public site needs. Each origin can map
+//! cookie domains to the public domain and force `Secure` and `SameSite`. Every
+//! Set-Cookie header is rewritten on its own; the cookie name and value are
+//! never touched.
+
+use crate::config::{CookieRewriteConfig, SameSite};
+use crate::headers::ResponseHeaders;
+
+/// Apply `config` to every Set-Cookie header in `headers`
+pub fn rewrite_set_cookies(headers: &mut ResponseHeaders, config: &CookieRewriteConfig) {
+    if !config.is_enabled() {
+        return;
+    }
+    headers.map_values("set-cookie", |value| rewrite_set_cookie(value, config));
+}
+
+/// Rewrite one Set-Cookie value, returning it unchanged if no rule applies
+pub fn rewrite_set_cookie(value: &str, config: &CookieRewriteConfig) -> String {
+    let mut parts = value.split(';');
+    let pair = parts.next().unwrap_or_default();
+    let mut attributes: Vec<String> = parts
+        .map(str::trim)
+        .filter(|attr| !attr.is_empty())
+        .map(String::from)
+        .collect();
+    let mut changed = false;
+
+    for attr in &mut attributes {
+        let Some((name, domain)) = attr.split_once('=') else {
+            continue;
+        };
+        if !name.trim().eq_ignore_ascii_case("domain") {
+            continue;
+        }
+        let domain = domain.trim();
+        let bare = domain.trim_start_matches('.');
+        let mapped = config
+            .domain_map
+            .iter()
+            .find(|(from, _)| from.trim_start_matches('.').eq_ignore_ascii_case(bare));
+        if let Some((_, to)) = mapped {
+            let dot = if domain.starts_with('.') { "." } else { "" };
+            *attr = format!("Domain={}{}", dot, to.trim_start_matches('.'));
+            changed = true;
+        }
+    }
+
+    if let Some(same_site) = config.force_samesite {
+        let forced = format!("SameSite={}", same_site.as_str());
+        match attributes
+            .iter_mut()
+            .find(|attr| attribute_is(attr, "samesite"))
+        {
+            Some(attr) if *attr == forced => {}
+            Some(attr) => {
+                *attr = forced;
+                changed = true;
+            }
+            None => {
+                attributes.push(forced);
+                changed = true;
+            }
+        }
+    }
+
+    let secure = config.force_secure || config.force_samesite == Some(SameSite::None);
+    if secure && !attributes.iter().any(|attr| attribute_is(attr, "secure")) {
+        attributes.push("Secure".to_string());
+        changed = true;
+    }
+
+    if !changed {
+        return value.to_string();
+    }
+    std::iter::once(pair.trim())
+        .chain(attributes.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Whether an attribute (`Name` or `Name=value`) is `name`, case-insensitively
+fn attribute_is(attr: &str, name: &str) -> bool {
+    attr.split('=')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .eq_ignore_ascii_case(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CookieRewriteConfig {
+        CookieRewriteConfig {
+            domain_map: [("app.internal".to_string(), "example.com".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_domain_rewrite() {
+        let config = config();
+        assert_eq!(
+            rewrite_set_cookie("sid=abc; Path=/; Domain=app.internal; HttpOnly", &config),
+            "sid=abc; Path=/; Domain=example.com; HttpOnly"
+        );
+        // A leading dot is kept, and the match ignores case
+        assert_eq!(
+            rewrite_set_cookie("sid=abc; domain=.APP.internal", &config),
+            "sid=abc; Domain=.example.com"
+        );
+        // Other domains, host-only cookies and lookalike values stay as sent
+        for value in [
+            "sid=abc; Domain=other.internal; Path=/",
+            "sid=abc;Path=/",
+            "domain=app.internal; Path=/",
+        ] {
+            assert_eq!(rewrite_set_cookie(value, &config), value);
+        }
+    }
+
+    #[test]
+    fn test_attribute_injection() {
+        let config = CookieRewriteConfig {
+            force_secure: true,
+            force_samesite: Some(SameSite::Lax),
+            ..Default::default()
+        };
+        assert_eq!(
+            rewrite_set_cookie("sid=abc; Path=/", &config),
+            "sid=abc; Path=/; SameSite=Lax; Secure"
+        );
+        assert_eq!(
+            rewrite_set_cookie("sid=abc; samesite=None; secure", &config),
+            "sid=abc; SameSite=Lax; secure"
+        );
+        let already = "sid=abc; SameSite=Lax; Secure";
+        assert_eq!(rewrite_set_cookie(already, &config), already);
+
+        let none = CookieRewriteConfig {
+            force_samesite: Some(SameSite::None),
+            ..Default::default()
+        };
+        assert_eq!(
+            rewrite_set_cookie("sid=abc", &none),
+            "sid=abc; SameSite=None; Secure"
+        );
+    }
+
+    #[test]
+    fn test_each_set_cookie_rewritten_independently() {
+        let mut headers = ResponseHeaders::new();
+        headers.append("Set-Cookie", "sid=abc; Domain=app.internal");
+        headers.append("content-type", "text/html");
+        headers.append("Set-Cookie", "theme=dark; Domain=cdn.example.net");
+        headers.append("set-cookie", "lang=en; Domain=.app.internal; Secure");
+
+        rewrite_set_cookies(&mut headers, &config());
+
+        let cookies: Vec<_> = headers.get_all("set-cookie").collect();
+        assert_eq!(
+            cookies,
+            vec![
+                "sid=abc; Domain=example.com",
+                "theme=dark; Domain=cdn.example.net",
+                "lang=en; Domain=.example.com; Secure",
+            ]
+        );
+        assert_eq!(headers.get("content-type").unwrap(), "text/html");
+
+        // No rules configured leaves headers byte-for-byte alone
+        let before = headers.clone();
+        rewrite_set_cookies(&mut headers, &CookieRewriteConfig::default());
+        assert_eq!(headers, before);
+    }
+}
@@ -0,0 +1,234 @@
+//! Device classification
+//!
+//! Buckets requests into a handful of device types from the User-Agent and the
+//! `Sec-CH-UA-Mobile` client hint. Origins that serve different markup per
+//! device receive the result as `X-Device-Type`, and listing that header in
+//! `cache.key.include_headers` caches one variant per device type instead of
+//! one per User-Agent string.
+
+use axum::http::{HeaderMap, header};
+
+/// Request header carrying the classification to the origin
+pub const DEVICE_TYPE_HEADER: &str = "x-device-type";
+
+/// Client hint sent by Chromium-based browsers: `?1` on phones, `?0` elsewhere
+const CH_UA_MOBILE: &str = "sec-ch-ua-mobile";
+
+/// User-Agent fragments (lowercase) of crawlers and headless clients
+const BOT_MARKERS: &[&str] = &[
+    "bot",
+    "crawler",
+    "spider",
+    "slurp",
+    "facebookexternalhit",
+    "mediapartners-google",
+    "bingpreview",
+    "headlesschrome",
+    "lighthouse",
+];
+
+const TABLET_MARKERS: &[&str] = &["ipad", "tablet", "kindle", "silk/", "playbook"];
+
+const MOBILE_MARKERS: &[&str] = &[
+    "mobi",
+    "iphone",
+    "ipod",
+    "windows phone",
+    "blackberry",
+    "opera mini",
+];
+
+/// Coarse device class of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceType {
+    Mobile,
+    Tablet,
+    Desktop,
+    Bot,
+}
+
+impl DeviceType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceType::Mobile => "mobile",
+            DeviceType::Tablet => "tablet",
+            DeviceType::Desktop => "desktop",
+            DeviceType::Bot => "bot",
+        }
+    }
+
+    /// Classify a request by its headers
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let value = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        Self::classify(value(header::USER_AGENT.as_str()), value(CH_UA_MOBILE))
+    }
+
+    /// Classify a User-Agent, using the `Sec-CH-UA-Mobile` hint when present
+    ///
+    /// Anything unrecognized, including a missing User-Agent, is a desktop.
+    pub fn classify(user_agent: Option<&str>, ch_ua_mobile: Option<&str>) -> Self {
+        let ua = user_agent.unwrap_or_default().to_ascii_lowercase();
+        let has = |markers: &[&str]| markers.iter().any(|m| ua.contains(m));
+
+        if has(BOT_MARKERS) {
+            return DeviceType::Bot;
+        }
+        // The hint is authoritative for phones, but `?0` covers tablets too
+        if ch_ua_mobile.map(str::trim) == Some("?1") {
+            return DeviceType::Mobile;
+        }
+        let android = ua.contains("android");
+        if has(TABLET_MARKERS) || (android && !ua.contains("mobile")) {
+            DeviceType::Tablet
+        } else if ch_ua_mobile.is_none() && (has(MOBILE_MARKERS) || android) {
+            DeviceType::Mobile
+        } else {
+            DeviceType::Desktop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORPUS: &[(&str, DeviceType)] = &[
+        // Phones
+        (
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1",
+            DeviceType::Mobile,
+        ),
+        (
+            "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36",
+            DeviceType::Mobile,
+        ),
+        (
+            "Mozilla/5.0 (Linux; Android 13; SM-S918B) AppleWebKit/537.36 (KHTML, like Gecko) SamsungBrowser/24.0 Chrome/117.0.0.0 Mobile Safari/537.36",
+            DeviceType::Mobile,
+        ),
+        (
+            "Mozilla/5.0 (Android 14; Mobile; rv:125.0) Gecko/125.0 Firefox/125.0",
+            DeviceType::Mobile,
+        ),
+        (
+            "Opera/9.80 (J2ME/MIDP; Opera Mini/9.80 (S60; SymbOS; Opera Mobi/23.348; U; en) Presto/2.5.25 Version/10.54",
+            DeviceType::Mobile,
+        ),
+        // Tablets
+        (
+            "Mozilla/5.0 (iPad; CPU OS 16_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.6 Mobile/15E148 Safari/604.1",
+            DeviceType::Tablet,
+        ),
+        (
+            "Mozilla/5.0 (Linux; Android 13; SM-X710) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+            DeviceType::Tablet,
+        ),
+        (
+            "Mozilla/5.0 (Android 14; Tablet; rv:125.0) Gecko/125.0 Firefox/125.0",
+            DeviceType::Tablet,
+        ),
+        (
+            "Mozilla/5.0 (Linux; U; Android 4.0.3; en-us; KFTT Build/IML74K) AppleWebKit/537.36 (KHTML, like Gecko) Silk/3.68 like Chrome/39.0.2171.93 Safari/537.36",
+            DeviceType::Tablet,
+        ),
+        // Desktops
+        (
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+            DeviceType::Desktop,
+        ),
+        (
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_4_1) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4.1 Safari/605.1.15",
+            DeviceType::Desktop,
+        ),
+        (
+            "Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0",
+            DeviceType::Desktop,
+        ),
+        (
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 Edg/124.0.0.0",
+            DeviceType::Desktop,
+        ),
+        ("curl/8.5.0", DeviceType::Desktop),
+        // Crawlers
+        (
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            DeviceType::Bot,
+        ),
+        (
+            "Mozilla/5.0 (Linux; Android 6.0.1; Nexus 5X Build/MMB29P) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            DeviceType::Bot,
+        ),
+        (
+            "Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)",
+            DeviceType::Bot,
+        ),
+        (
+            "Mozilla/5.0 (compatible; Yahoo! Slurp; http://help.yahoo.com/help/us/ysearch/slurp)",
+            DeviceType::Bot,
+        ),
+        (
+            "Mozilla/5.0 (compatible; Baiduspider/2.0; +http://www.baidu.com/search/spider.html)",
+            DeviceType::Bot,
+        ),
+        (
+            "facebookexternalhit/1.1 (+http://www.facebook.com/externalhit_uatext.php)",
+            DeviceType::Bot,
+        ),
+        (
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) HeadlessChrome/124.0.0.0 Safari/537.36",
+            DeviceType::Bot,
+        ),
+    ];
+
+    #[test]
+    fn test_user_agent_corpus() {
+        for (ua, expected) in CORPUS {
+            assert_eq!(DeviceType::classify(Some(ua), None), *expected, "{}", ua);
+        }
+        assert_eq!(DeviceType::classify(None, None), DeviceType::Desktop);
+    }
+
+    #[test]
+    fn test_client_hint() {
+        // Reduced Chrome UAs look alike; the hint tells phones apart
+        let reduced = "Mozilla/5.0 (Linux; Android 10; K) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36";
+        assert_eq!(
+            DeviceType::classify(Some(reduced), Some("?1")),
+            DeviceType::Mobile
+        );
+        let desktop = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
+        assert_eq!(
+            DeviceType::classify(Some(desktop), Some("?1")),
+            DeviceType::Mobile
+        );
+        assert_eq!(
+            DeviceType::classify(Some(desktop), Some("?0")),
+            DeviceType::Desktop
+        );
+        // A `?0` Android device is a tablet, not a phone
+        let tablet = "Mozilla/5.0 (Linux; Android 13; SM-X710) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
+        assert_eq!(
+            DeviceType::classify(Some(tablet), Some("?0")),
+            DeviceType::Tablet
+        );
+        // Crawlers stay bots whatever they claim
+        let googlebot = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+        assert_eq!(
+            DeviceType::classify(Some(googlebot), Some("?1")),
+            DeviceType::Bot
+        );
+    }
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(DeviceType::from_headers(&headers), DeviceType::Desktop);
+        headers.insert(
+            header::USER_AGENT,
+            "Mozilla/5.0 (iPad; CPU OS 16_6)".parse().unwrap(),
+        );
+        assert_eq!(DeviceType::from_headers(&headers), DeviceType::Tablet);
+        headers.insert(CH_UA_MOBILE, "?1".parse().unwrap());
+        assert_eq!(DeviceType::from_headers(&headers), DeviceType::Mobile);
+    }
+}
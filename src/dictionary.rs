@@ -0,0 +1,215 @@
+//! Edge dictionaries
+//!
+//! Named key-value maps loaded from CSV or JSON files and consulted by
+//! `lookup` rewrite rules. Each map sits behind an [`ArcSwap`], so lookups
+//! never take a lock and a reload replaces the whole map at once.
+
+use arc_swap::ArcSwap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::info;
+
+use crate::config::{DictionaryConfig, DictionaryFormat};
+use crate::error::{CdnError, CdnResult};
+
+/// One named dictionary and the file it was loaded from
+pub struct Dictionary {
+    name: String,
+    config: DictionaryConfig,
+    entries: ArcSwap<HashMap<String, String>>,
+}
+
+impl Dictionary {
+    /// Load a dictionary from its file
+    pub fn load(name: &str, config: &DictionaryConfig) -> CdnResult<Self> {
+        let dictionary = Self {
+            name: name.to_string(),
+            config: config.clone(),
+            entries: ArcSwap::from_pointee(HashMap::new()),
+        };
+        dictionary.reload()?;
+        Ok(dictionary)
+    }
+
+    /// Build a dictionary from entries in memory; reloading it empties it
+    pub fn from_entries(name: &str, entries: HashMap<String, String>) -> Self {
+        Self {
+            name: name.to_string(),
+            config: DictionaryConfig {
+                path: String::new(),
+                format: None,
+            },
+            entries: ArcSwap::from_pointee(entries),
+        }
+    }
+
+    /// Re-read the file, returning the number of entries loaded
+    ///
+    /// A file that can't be read or parsed leaves the current entries in place.
+    pub fn reload(&self) -> CdnResult<usize> {
+        let entries = read_entries(&self.name, &self.config)?;
+        let count = entries.len();
+        self.entries.store(Arc::new(entries));
+        info!(dictionary = %self.name, path = %self.config.path, entries = count, "Dictionary loaded");
+        Ok(count)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &str {
+        &self.config.path
+    }
+
+    /// Value for `key`, if the dictionary has one
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.entries.load().get(key).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.load().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Every configured dictionary, by name
+#[derive(Default)]
+pub struct Dictionaries {
+    dictionaries: HashMap<String, Dictionary>,
+}
+
+impl Dictionaries {
+    /// Load every dictionary in `edge.dictionaries`; any bad file fails the lot
+    pub fn load(configs: &HashMap<String, DictionaryConfig>) -> CdnResult<Self> {
+        let dictionaries = configs
+            .iter()
+            .map(|(name, config)| Ok((name.clone(), Dictionary::load(name, config)?)))
+            .collect::<CdnResult<_>>()?;
+        Ok(Self { dictionaries })
+    }
+
+    /// Add a dictionary, replacing any with the same name
+    pub fn insert(&mut self, dictionary: Dictionary) {
+        self.dictionaries
+            .insert(dictionary.name.clone(), dictionary);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Dictionary> {
+        self.dictionaries.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.dictionaries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dictionaries.is_empty()
+    }
+}
+
+fn read_entries(name: &str, config: &DictionaryConfig) -> CdnResult<HashMap<String, String>> {
+    let format = config
+        .resolved_format()
+        .ok_or_else(|| CdnError::ConfigError(format!("Dictionary {} has no format", name)))?;
+    let contents = std::fs::read_to_string(&config.path).map_err(|e| {
+        CdnError::ConfigError(format!(
+            "Failed to read dictionary {} from {}: {}",
+            name, config.path, e
+        ))
+    })?;
+
+    match format {
+        DictionaryFormat::Csv => parse_csv(&contents)
+            .map_err(|e| CdnError::ConfigError(format!("Dictionary {}: {}", name, e))),
+        DictionaryFormat::Json => serde_json::from_str(&contents)
+            .map_err(|e| CdnError::ConfigError(format!("Dictionary {}: {}", name, e))),
+    }
+}
+
+/// Parse `key,value` lines; the value is everything after the first comma
+fn parse_csv(contents: &str) -> Result<HashMap<String, String>, String> {
+    let mut entries = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once(',')
+            .ok_or_else(|| format!("line {} has no comma", number + 1))?;
+        entries.insert(key.trim().to_string(), value.trim().to_string());
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "screaming-eagle-dictionary-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn config(path: &str) -> DictionaryConfig {
+        DictionaryConfig {
+            path: path.to_string(),
+            format: None,
+        }
+    }
+
+    #[test]
+    fn test_csv_and_json_dictionaries() {
+        let csv = scratch_file(
+            "slugs.csv",
+            "# legacy slug,new path\nhello-world, /articles/1\n\nsecond,/articles/2,draft\n",
+        );
+        let dictionary = Dictionary::load("slugs", &config(&csv)).unwrap();
+        assert_eq!(dictionary.len(), 2);
+        assert_eq!(
+            dictionary.get("hello-world").as_deref(),
+            Some("/articles/1")
+        );
+        assert_eq!(
+            dictionary.get("second").as_deref(),
+            Some("/articles/2,draft")
+        );
+        assert_eq!(dictionary.get("missing"), None);
+
+        let json = scratch_file("slugs.json", r#"{"hello-world": "/articles/1"}"#);
+        let dictionary = Dictionary::load("slugs", &config(&json)).unwrap();
+        assert_eq!(
+            dictionary.get("hello-world").as_deref(),
+            Some("/articles/1")
+        );
+
+        let bad = scratch_file("bad.csv", "no-comma-here\n");
+        assert!(Dictionary::load("bad", &config(&bad)).is_err());
+        let bad = scratch_file("bad.json", r#"{"key": 1}"#);
+        assert!(Dictionary::load("bad", &config(&bad)).is_err());
+    }
+
+    #[test]
+    fn test_reload_swaps_entries_and_keeps_them_on_error() {
+        let path = scratch_file("reload.csv", "a,1\n");
+        let dictionary = Dictionary::load("reload", &config(&path)).unwrap();
+        assert_eq!(dictionary.get("a").as_deref(), Some("1"));
+
+        std::fs::write(&path, "a,2\nb,3\n").unwrap();
+        assert_eq!(dictionary.reload().unwrap(), 2);
+        assert_eq!(dictionary.get("a").as_deref(), Some("2"));
+
+        std::fs::write(&path, "broken\n").unwrap();
+        assert!(dictionary.reload().is_err());
+        assert_eq!(dictionary.get("b").as_deref(), Some("3"));
+    }
+}
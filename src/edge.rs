@@ -5,17 +5,28 @@
 
 use axum::{
     body::Body,
-    extract::State,
-    http::{HeaderMap, HeaderValue, Method, Request, Uri, header::HeaderName},
+    extract::{ConnectInfo, State},
+    http::{
+        HeaderMap, HeaderValue, Method, Request, StatusCode, Uri,
+        header::{COOKIE, HeaderName, SET_COOKIE},
+    },
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 use tracing::{debug, instrument, warn};
+use xxhash_rust::xxh3::xxh3_64;
 
-use crate::config::{EdgeConfig as ConfigEdgeConfig, RoutingActionConfig, RoutingConditionConfig};
+use crate::cache::find_cookie;
+use crate::config::{
+    EdgeConfig as ConfigEdgeConfig, LookupMiss, QueryLimitsConfig, RegexLimitsConfig,
+    RewriteActionConfig, RoutingActionConfig, RoutingConditionConfig,
+};
+use crate::dictionary::Dictionaries;
+use crate::error::CdnError;
+use crate::metrics::Metrics;
 
 /// Edge processing configuration
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -35,6 +46,14 @@ pub struct EdgeConfig {
     /// Conditional routing rules
     #[serde(default)]
     pub routing_rules: Vec<RoutingRule>,
+
+    /// Query string limits
+    #[serde(default)]
+    pub query_limits: QueryLimitsConfig,
+
+    /// Size limits patterns compile within, and the per-request rule budget
+    #[serde(default)]
+    pub regex_limits: RegexLimitsConfig,
 }
 
 // ============================================================================
@@ -60,6 +79,47 @@ pub struct RewriteRule {
     /// Optional condition for when this rule applies
     #[serde(default)]
     pub condition: Option<RewriteCondition>,
+
+    /// How the rule rewrites a matching path
+    #[serde(default)]
+    pub action: RewriteAction,
+}
+
+/// How a rewrite rule produces the new path
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RewriteAction {
+    /// Substitute `replacement` for the match
+    #[default]
+    Replace,
+
+    /// Look the path, or one capture group of it, up in an edge dictionary
+    Lookup(LookupRewrite),
+}
+
+/// A dictionary lookup rewrite
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LookupRewrite {
+    /// Dictionary to look the key up in
+    pub dictionary: String,
+
+    /// Capture group to look up and replace; the whole path when unset
+    #[serde(default)]
+    pub group: Option<usize>,
+
+    /// What to do when the key isn't in the dictionary
+    #[serde(default)]
+    pub on_miss: LookupMiss,
+
+    /// Path to rewrite to on a miss with [`LookupMiss::Fallback`]
+    #[serde(default)]
+    pub fallback: Option<String>,
+}
+
+/// A lookup rule missed and asked for a 404
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookupNotFound {
+    pub rule: String,
 }
 
 /// Condition for rewrite rule application
@@ -89,6 +149,7 @@ pub struct CompiledRewriteRule {
     pub replacement: String,
     pub stop: bool,
     pub condition: Option<CompiledCondition>,
+    pub action: RewriteAction,
 }
 
 /// Compiled condition
@@ -103,45 +164,66 @@ pub struct CompiledCondition {
 /// URL rewriter with compiled rules
 pub struct UrlRewriter {
     rules: Vec<CompiledRewriteRule>,
+    dictionaries: Arc<Dictionaries>,
 }
 
 impl UrlRewriter {
-    pub fn new(rules: &[RewriteRule]) -> Self {
+    /// Compile `rules` within `limits`
+    ///
+    /// `Config::validate` rejects patterns that don't compile; a rule built
+    /// some other way with one is skipped whole rather than run without it.
+    pub fn new(rules: &[RewriteRule], limits: &RegexLimitsConfig) -> Self {
         let compiled_rules = rules
             .iter()
             .filter_map(|rule| {
-                let pattern = match Regex::new(&rule.pattern) {
-                    Ok(p) => p,
-                    Err(e) => {
+                let compile = |pattern: &str| {
+                    limits.compile(pattern).map_err(|e| {
                         warn!(rule = %rule.name, error = %e, "Failed to compile rewrite pattern");
-                        return None;
+                    })
+                };
+                let pattern = compile(&rule.pattern).ok()?;
+
+                let condition = match &rule.condition {
+                    Some(c) => {
+                        let header_pattern = c.header_pattern.as_deref().map(compile);
+                        let query_pattern = c.query_pattern.as_deref().map(compile);
+                        Some(CompiledCondition {
+                            header: c.header.clone(),
+                            header_pattern: header_pattern.transpose().ok()?,
+                            query_param: c.query_param.clone(),
+                            query_pattern: query_pattern.transpose().ok()?,
+                            methods: c.methods.iter().filter_map(|m| m.parse().ok()).collect(),
+                        })
                     }
+                    None => None,
                 };
 
-                let condition = rule.condition.as_ref().map(|c| CompiledCondition {
-                    header: c.header.clone(),
-                    header_pattern: c.header_pattern.as_ref().and_then(|p| Regex::new(p).ok()),
-                    query_param: c.query_param.clone(),
-                    query_pattern: c.query_pattern.as_ref().and_then(|p| Regex::new(p).ok()),
-                    methods: c.methods.iter().filter_map(|m| m.parse().ok()).collect(),
-                });
-
                 Some(CompiledRewriteRule {
                     name: rule.name.clone(),
                     pattern,
                     replacement: rule.replacement.clone(),
                     stop: rule.stop,
                     condition,
+                    action: rule.action.clone(),
                 })
             })
             .collect();
 
         Self {
             rules: compiled_rules,
+            dictionaries: Arc::new(Dictionaries::default()),
         }
     }
 
+    /// Dictionaries that `lookup` rules read from
+    pub fn with_dictionaries(mut self, dictionaries: Arc<Dictionaries>) -> Self {
+        self.dictionaries = dictionaries;
+        self
+    }
+
     /// Rewrite a URL path based on configured rules
+    ///
+    /// Returns `Err` when a lookup rule missed with `on_miss = "not_found"`.
     #[instrument(skip(self, headers))]
     pub fn rewrite(
         &self,
@@ -149,11 +231,28 @@ impl UrlRewriter {
         query: Option<&str>,
         method: &Method,
         headers: &HeaderMap,
-    ) -> Option<String> {
+    ) -> Result<Option<String>, LookupNotFound> {
+        self.rewrite_traced(path, query, method, headers, None, &mut RegexBudget::new(0))
+    }
+
+    /// Rewrite a URL path, recording each rule that changed it in `steps`
+    fn rewrite_traced(
+        &self,
+        path: &str,
+        query: Option<&str>,
+        method: &Method,
+        headers: &HeaderMap,
+        mut steps: Option<&mut Vec<RewriteStep>>,
+        budget: &mut RegexBudget,
+    ) -> Result<Option<String>, LookupNotFound> {
         let mut current_path = path.to_string();
         let mut rewritten = false;
 
         for rule in &self.rules {
+            if !budget.spend() {
+                break;
+            }
+
             // Check condition if present
             if let Some(ref condition) = rule.condition
                 && !self.check_condition(condition, query, method, headers) {
@@ -161,30 +260,83 @@ impl UrlRewriter {
                 }
 
             // Try to match and replace
-            if rule.pattern.is_match(&current_path) {
-                let new_path = rule
+            let new_path = match &rule.action {
+                RewriteAction::Replace if rule.pattern.is_match(&current_path) => rule
                     .pattern
                     .replace_all(&current_path, &rule.replacement)
-                    .to_string();
-
-                if new_path != current_path {
-                    debug!(
-                        rule = %rule.name,
-                        from = %current_path,
-                        to = %new_path,
-                        "URL rewritten"
-                    );
-                    current_path = new_path;
-                    rewritten = true;
-
-                    if rule.stop {
-                        break;
+                    .to_string(),
+                RewriteAction::Replace => continue,
+                RewriteAction::Lookup(lookup) => {
+                    match self.apply_lookup(rule, lookup, &current_path)? {
+                        Some(new_path) => new_path,
+                        None => continue,
                     }
                 }
+            };
+
+            if new_path != current_path {
+                debug!(
+                    rule = %rule.name,
+                    from = %current_path,
+                    to = %new_path,
+                    "URL rewritten"
+                );
+                if let Some(steps) = steps.as_deref_mut() {
+                    steps.push(RewriteStep {
+                        rule: rule.name.clone(),
+                        from: current_path.clone(),
+                        to: new_path.clone(),
+                        stop: rule.stop,
+                    });
+                }
+                current_path = new_path;
+                rewritten = true;
+
+                if rule.stop {
+                    break;
+                }
             }
         }
 
-        if rewritten { Some(current_path) } else { None }
+        Ok(if rewritten { Some(current_path) } else { None })
+    }
+
+    /// The path a lookup rule rewrites to; `None` leaves it alone
+    fn apply_lookup(
+        &self,
+        rule: &CompiledRewriteRule,
+        lookup: &LookupRewrite,
+        path: &str,
+    ) -> Result<Option<String>, LookupNotFound> {
+        let Some(captures) = rule.pattern.captures(path) else {
+            return Ok(None);
+        };
+        // Without a group the key is the whole path, and so is the value
+        let (key, span) = match lookup.group {
+            Some(group) => match captures.get(group) {
+                Some(key) => (key.as_str(), key.range()),
+                None => return Ok(None),
+            },
+            None => (path, 0..path.len()),
+        };
+        let value = self
+            .dictionaries
+            .get(&lookup.dictionary)
+            .and_then(|d| d.get(key));
+
+        match (value, lookup.on_miss) {
+            (Some(value), _) => Ok(Some(format!(
+                "{}{}{}",
+                &path[..span.start],
+                value,
+                &path[span.end..]
+            ))),
+            (None, LookupMiss::Continue) => Ok(None),
+            (None, LookupMiss::NotFound) => Err(LookupNotFound {
+                rule: rule.name.clone(),
+            }),
+            (None, LookupMiss::Fallback) => Ok(lookup.fallback.clone()),
+        }
     }
 
     fn check_condition(
@@ -292,7 +444,7 @@ struct CompiledHeaderTransform {
 }
 
 impl HeaderTransformer {
-    pub fn new(config: &HeaderTransforms) -> Self {
+    pub fn new(config: &HeaderTransforms, limits: &RegexLimitsConfig) -> Self {
         let request_add = config
             .request_add
             .iter()
@@ -331,9 +483,12 @@ impl HeaderTransformer {
             .transformations
             .iter()
             .filter_map(|t| {
+                let pattern = limits.compile(&t.pattern).map_err(|e| {
+                    warn!(header = %t.header, error = %e, "Failed to compile header transformation pattern");
+                });
                 Some(CompiledHeaderTransform {
                     header: HeaderName::try_from(&t.header).ok()?,
-                    pattern: Regex::new(&t.pattern).ok()?,
+                    pattern: pattern.ok()?,
                     replacement: t.replacement.clone(),
                 })
             })
@@ -459,14 +614,28 @@ impl Default for QueryNormalizationConfig {
     }
 }
 
+/// Most parameters the normalizer parses, whatever the configured limits
+pub const NORMALIZER_MAX_PARAMS: usize = 1024;
+
 /// Query string normalizer
 pub struct QueryNormalizer {
     config: QueryNormalizationConfig,
+    max_params: usize,
 }
 
 impl QueryNormalizer {
     pub fn new(config: QueryNormalizationConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            max_params: NORMALIZER_MAX_PARAMS,
+        }
+    }
+
+    /// Parse at most `max_params` parameters (never more than [`NORMALIZER_MAX_PARAMS`]);
+    /// the rest of the query is dropped
+    pub fn with_max_params(mut self, max_params: usize) -> Self {
+        self.max_params = max_params.min(NORMALIZER_MAX_PARAMS);
+        self
     }
 
     /// Normalize a query string
@@ -477,6 +646,7 @@ impl QueryNormalizer {
         }
 
         let mut params: Vec<(String, String)> = url::form_urlencoded::parse(query.as_bytes())
+            .take(self.max_params)
             .map(|(k, v)| {
                 let key = if self.config.lowercase_names {
                     k.to_lowercase()
@@ -520,6 +690,62 @@ impl QueryNormalizer {
     }
 }
 
+// ============================================================================
+// Query Limits
+// ============================================================================
+
+/// Query string limit a request went over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryLimitExceeded {
+    Params,
+    Length,
+    ValueLength,
+}
+
+impl QueryLimitExceeded {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueryLimitExceeded::Params => "params",
+            QueryLimitExceeded::Length => "length",
+            QueryLimitExceeded::ValueLength => "value_length",
+        }
+    }
+
+    /// 414 for an overlong query, 400 otherwise
+    pub fn status(&self) -> StatusCode {
+        match self {
+            QueryLimitExceeded::Length => StatusCode::URI_TOO_LONG,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// Check a raw query string against `limits` without decoding it
+///
+/// The scan stops at the first limit exceeded, so its cost is bounded by
+/// `max_length` and `max_params` whatever the client sends.
+pub fn check_query_limits(
+    query: &str,
+    limits: &QueryLimitsConfig,
+) -> Result<(), QueryLimitExceeded> {
+    if !limits.enabled || query.is_empty() {
+        return Ok(());
+    }
+    if query.len() > limits.max_length {
+        return Err(QueryLimitExceeded::Length);
+    }
+    for (count, pair) in query.split('&').enumerate() {
+        if count >= limits.max_params {
+            return Err(QueryLimitExceeded::Params);
+        }
+        let value = pair.split_once('=').map_or("", |(_, value)| value);
+        if value.len() > limits.max_value_length {
+            return Err(QueryLimitExceeded::ValueLength);
+        }
+    }
+    Ok(())
+}
+
 // ============================================================================
 // Conditional Routing
 // ============================================================================
@@ -579,6 +805,23 @@ pub enum RoutingCondition {
         /// End hour (0-23)
         end_hour: Option<u8>,
     },
+
+    /// Match a cookie value
+    #[serde(rename = "cookie")]
+    Cookie { name: String, pattern: String },
+
+    /// Deterministic percentage bucket, for sticky A/B and canary splits
+    #[serde(rename = "split")]
+    Split {
+        /// Share of users in the bucket (0-100)
+        percent: u8,
+        /// Cookie to bucket by; the client IP is used when it's absent
+        #[serde(default)]
+        seed_cookie: Option<String>,
+        /// Set `seed_cookie` on responses to users without one, so their bucket sticks
+        #[serde(default)]
+        pin: bool,
+    },
 }
 
 /// Action to take when routing conditions match
@@ -637,26 +880,50 @@ pub enum CompiledRoutingCondition {
         start_hour: Option<u8>,
         end_hour: Option<u8>,
     },
+    Cookie(String, Regex),
+    Split {
+        percent: u8,
+        seed_cookie: Option<String>,
+        pin: bool,
+    },
 }
 
+/// Lifetime of a split's pinning cookie (one year)
+const SPLIT_PIN_MAX_AGE_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// Request extension naming the origin a `RouteToOrigin` rule picked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutedOrigin(pub String);
+
 /// Conditional router
 pub struct ConditionalRouter {
     rules: Vec<CompiledRoutingRule>,
 }
 
 impl ConditionalRouter {
-    pub fn new(mut rules: Vec<RoutingRule>) -> Self {
+    /// Compile `rules` within `limits`
+    ///
+    /// A rule with a pattern that doesn't compile is skipped whole, since
+    /// dropping just the condition would widen what it matches.
+    pub fn new(mut rules: Vec<RoutingRule>, limits: &RegexLimitsConfig) -> Self {
         // Sort by priority (descending)
-        rules.sort_by(|a, b| b.priority.cmp(&a.priority));
+        rules.sort_by_key(|r| std::cmp::Reverse(r.priority));
 
         let compiled_rules = rules
             .into_iter()
             .filter_map(|rule| {
-                let conditions: Vec<CompiledRoutingCondition> = rule
+                let conditions = rule
                     .conditions
                     .into_iter()
-                    .filter_map(Self::compile_condition)
-                    .collect();
+                    .map(|condition| Self::compile_condition(condition, limits))
+                    .collect::<Result<Vec<_>, _>>();
+                let conditions = match conditions {
+                    Ok(conditions) => conditions,
+                    Err(e) => {
+                        warn!(rule = %rule.name, error = %e, "Failed to compile routing condition");
+                        return None;
+                    }
+                };
 
                 Some(CompiledRoutingRule {
                     name: rule.name,
@@ -672,33 +939,87 @@ impl ConditionalRouter {
         }
     }
 
-    fn compile_condition(condition: RoutingCondition) -> Option<CompiledRoutingCondition> {
-        match condition {
-            RoutingCondition::Path { pattern } => Regex::new(&pattern)
-                .ok()
-                .map(CompiledRoutingCondition::Path),
-            RoutingCondition::Header { name, pattern } => Regex::new(&pattern)
-                .ok()
-                .map(|r| CompiledRoutingCondition::Header(name, r)),
-            RoutingCondition::Query { param, pattern } => Regex::new(&pattern)
-                .ok()
-                .map(|r| CompiledRoutingCondition::Query(param, r)),
+    fn compile_condition(
+        condition: RoutingCondition,
+        limits: &RegexLimitsConfig,
+    ) -> Result<CompiledRoutingCondition, regex::Error> {
+        Ok(match condition {
+            RoutingCondition::Path { pattern } => {
+                CompiledRoutingCondition::Path(limits.compile(&pattern)?)
+            }
+            RoutingCondition::Header { name, pattern } => {
+                CompiledRoutingCondition::Header(name, limits.compile(&pattern)?)
+            }
+            RoutingCondition::Query { param, pattern } => {
+                CompiledRoutingCondition::Query(param, limits.compile(&pattern)?)
+            }
             RoutingCondition::Method { methods } => {
                 let parsed: Vec<Method> = methods.iter().filter_map(|m| m.parse().ok()).collect();
-                Some(CompiledRoutingCondition::Method(parsed))
+                CompiledRoutingCondition::Method(parsed)
             }
-            RoutingCondition::ClientIp { cidrs } => Some(CompiledRoutingCondition::ClientIp(cidrs)),
-            RoutingCondition::Geo { countries } => Some(CompiledRoutingCondition::Geo(countries)),
+            RoutingCondition::ClientIp { cidrs } => CompiledRoutingCondition::ClientIp(cidrs),
+            RoutingCondition::Geo { countries } => CompiledRoutingCondition::Geo(countries),
             RoutingCondition::Time {
                 days,
                 start_hour,
                 end_hour,
-            } => Some(CompiledRoutingCondition::Time {
+            } => CompiledRoutingCondition::Time {
                 days,
                 start_hour,
                 end_hour,
-            }),
-        }
+            },
+            RoutingCondition::Cookie { name, pattern } => {
+                CompiledRoutingCondition::Cookie(name, limits.compile(&pattern)?)
+            }
+            RoutingCondition::Split {
+                percent,
+                seed_cookie,
+                pin,
+            } => CompiledRoutingCondition::Split {
+                percent,
+                seed_cookie,
+                pin,
+            },
+        })
+    }
+
+    /// Set-Cookie values pinning new users to their split buckets
+    ///
+    /// A user without a pinned split's seed cookie is given one holding the
+    /// visitor ID their IP bucketed them by, so the bucket survives IP changes.
+    pub fn pin_cookies(&self, headers: &HeaderMap, client_ip: Option<&str>) -> Vec<String> {
+        let Some(ip) = client_ip else {
+            return Vec::new();
+        };
+
+        let mut names: Vec<&str> = self
+            .rules
+            .iter()
+            .flat_map(|rule| &rule.conditions)
+            .filter_map(|condition| match condition {
+                CompiledRoutingCondition::Split {
+                    seed_cookie: Some(name),
+                    pin: true,
+                    ..
+                } => Some(name.as_str()),
+                _ => None,
+            })
+            .filter(|name| request_cookie(headers, name).is_none_or(str::is_empty))
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+
+        names
+            .into_iter()
+            .map(|name| {
+                format!(
+                    "{}={}; Path=/; Max-Age={}; SameSite=Lax",
+                    name,
+                    visitor_id(ip),
+                    SPLIT_PIN_MAX_AGE_SECS
+                )
+            })
+            .collect()
     }
 
     /// Evaluate routing rules and return the first matching action
@@ -711,7 +1032,31 @@ impl ConditionalRouter {
         headers: &HeaderMap,
         client_ip: Option<&str>,
     ) -> Option<&RoutingAction> {
+        self.matching_rule(
+            path,
+            query,
+            method,
+            headers,
+            client_ip,
+            &mut RegexBudget::new(0),
+        )
+        .map(|rule| &rule.action)
+    }
+
+    /// First rule, by priority, whose conditions all match
+    fn matching_rule(
+        &self,
+        path: &str,
+        query: Option<&str>,
+        method: &Method,
+        headers: &HeaderMap,
+        client_ip: Option<&str>,
+        budget: &mut RegexBudget,
+    ) -> Option<&CompiledRoutingRule> {
         for rule in &self.rules {
+            if !budget.spend() {
+                break;
+            }
             if self.matches_all_conditions(
                 &rule.conditions,
                 path,
@@ -721,7 +1066,7 @@ impl ConditionalRouter {
                 client_ip,
             ) {
                 debug!(rule = %rule.name, "Routing rule matched");
-                return Some(&rule.action);
+                return Some(rule);
             }
         }
         None
@@ -803,10 +1148,52 @@ impl ConditionalRouter {
 
                 true
             }
+            CompiledRoutingCondition::Cookie(name, pattern) => request_cookie(headers, name)
+                .map(|v| pattern.is_match(v))
+                .unwrap_or(false),
+            CompiledRoutingCondition::Split {
+                percent,
+                seed_cookie,
+                ..
+            } => split_seed(headers, seed_cookie.as_deref(), client_ip)
+                .map(|seed| in_split_bucket(&seed, *percent))
+                .unwrap_or(false),
         }
     }
 }
 
+/// A cookie's value across all of a request's Cookie headers
+fn request_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .find_map(|header| find_cookie(header, name))
+}
+
+/// What a split buckets a user by: their seed cookie, else their IP's visitor ID
+fn split_seed(
+    headers: &HeaderMap,
+    seed_cookie: Option<&str>,
+    client_ip: Option<&str>,
+) -> Option<String> {
+    seed_cookie
+        .and_then(|name| request_cookie(headers, name))
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+        .or_else(|| client_ip.map(visitor_id))
+}
+
+/// Opaque, stable ID for a client IP; what a pinned split stores in its cookie
+fn visitor_id(ip: &str) -> String {
+    format!("{:016x}", xxh3_64(ip.as_bytes()))
+}
+
+/// Whether a seed hashes into the first `percent` of 100 buckets
+fn in_split_bucket(seed: &str, percent: u8) -> bool {
+    xxh3_64(seed.as_bytes()) % 100 < u64::from(percent)
+}
+
 /// Check if an IP matches a CIDR range (simplified)
 fn ip_matches_cidr(ip: &str, cidr: &str) -> bool {
     use std::net::IpAddr;
@@ -866,26 +1253,86 @@ fn ip_matches_cidr(ip: &str, cidr: &str) -> bool {
 // Edge Processor - combines all edge logic
 // ============================================================================
 
+/// Routing and rewrite rule evaluations one request has left
+struct RegexBudget {
+    remaining: Option<usize>,
+    exceeded: bool,
+}
+
+impl RegexBudget {
+    /// A budget of `limit` evaluations; 0 for no limit
+    fn new(limit: usize) -> Self {
+        Self {
+            remaining: (limit > 0).then_some(limit),
+            exceeded: false,
+        }
+    }
+
+    /// Spend one evaluation, returning false once the budget is used up
+    fn spend(&mut self) -> bool {
+        match &mut self.remaining {
+            None => true,
+            Some(0) => {
+                self.exceeded = true;
+                false
+            }
+            Some(remaining) => {
+                *remaining -= 1;
+                true
+            }
+        }
+    }
+}
+
 /// Main edge processor that combines all edge logic
 pub struct EdgeProcessor {
     rewriter: UrlRewriter,
     header_transformer: HeaderTransformer,
     query_normalizer: QueryNormalizer,
     router: ConditionalRouter,
+    query_limits: QueryLimitsConfig,
+    /// Rule evaluations allowed per request; 0 for no limit
+    max_evaluations: usize,
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl EdgeProcessor {
     pub fn new(config: EdgeConfig) -> Self {
+        let mut query_normalizer = QueryNormalizer::new(config.query_normalization);
+        if config.query_limits.enabled {
+            query_normalizer = query_normalizer.with_max_params(config.query_limits.max_params);
+        }
+        let limits = &config.regex_limits;
         Self {
-            rewriter: UrlRewriter::new(&config.rewrite_rules),
-            header_transformer: HeaderTransformer::new(&config.header_transforms),
-            query_normalizer: QueryNormalizer::new(config.query_normalization),
-            router: ConditionalRouter::new(config.routing_rules),
+            rewriter: UrlRewriter::new(&config.rewrite_rules, limits),
+            header_transformer: HeaderTransformer::new(&config.header_transforms, limits),
+            query_normalizer,
+            router: ConditionalRouter::new(config.routing_rules, limits),
+            query_limits: config.query_limits,
+            max_evaluations: limits.max_evaluations_per_request,
+            metrics: None,
         }
     }
 
+    /// Count query limit rejections and exhausted rule budgets in the metrics registry
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Dictionaries that `lookup` rewrite rules read from
+    pub fn with_dictionaries(mut self, dictionaries: Arc<Dictionaries>) -> Self {
+        self.rewriter = self.rewriter.with_dictionaries(dictionaries);
+        self
+    }
+
+    /// The loaded edge dictionaries
+    pub fn dictionaries(&self) -> &Dictionaries {
+        &self.rewriter.dictionaries
+    }
+
     /// Create from config module types
-    pub fn from_config(config: &ConfigEdgeConfig) -> Self {
+    pub fn from_config(config: &ConfigEdgeConfig, regex_limits: &RegexLimitsConfig) -> Self {
         // Convert rewrite rules
         let rewrite_rules: Vec<RewriteRule> = config
             .rewrite_rules
@@ -902,6 +1349,15 @@ impl EdgeProcessor {
                     query_pattern: c.query_pattern.clone(),
                     methods: c.methods.clone(),
                 }),
+                action: match &r.action {
+                    RewriteActionConfig::Replace => RewriteAction::Replace,
+                    RewriteActionConfig::Lookup(lookup) => RewriteAction::Lookup(LookupRewrite {
+                        dictionary: lookup.dictionary.clone(),
+                        group: lookup.group,
+                        on_miss: lookup.on_miss,
+                        fallback: lookup.fallback.clone(),
+                    }),
+                },
             })
             .collect();
 
@@ -977,6 +1433,21 @@ impl EdgeProcessor {
                             start_hour: *start_hour,
                             end_hour: *end_hour,
                         },
+                        RoutingConditionConfig::Cookie { name, pattern } => {
+                            RoutingCondition::Cookie {
+                                name: name.clone(),
+                                pattern: pattern.clone(),
+                            }
+                        }
+                        RoutingConditionConfig::Split {
+                            percent,
+                            seed_cookie,
+                            pin,
+                        } => RoutingCondition::Split {
+                            percent: *percent,
+                            seed_cookie: seed_cookie.clone(),
+                            pin: *pin,
+                        },
                     })
                     .collect(),
                 action: match &r.action {
@@ -1017,6 +1488,8 @@ impl EdgeProcessor {
             header_transforms,
             query_normalization,
             routing_rules,
+            query_limits: config.query_limits.clone(),
+            regex_limits: regex_limits.clone(),
         };
 
         Self::new(edge_config)
@@ -1030,29 +1503,166 @@ impl EdgeProcessor {
         method: &Method,
         headers: &HeaderMap,
         client_ip: Option<&str>,
+    ) -> EdgeProcessingResult {
+        let mut budget = RegexBudget::new(self.max_evaluations);
+        let result =
+            self.process_traced(path, query, method, headers, client_ip, None, &mut budget);
+        if budget.exceeded {
+            debug!(
+                path = %path,
+                limit = self.max_evaluations,
+                "Edge rule evaluation budget exceeded, skipping remaining rules"
+            );
+            if let Some(metrics) = &self.metrics {
+                metrics.record_edge_regex_budget_exceeded();
+            }
+        }
+        result
+    }
+
+    /// `process_request`, recording the matched routing rule and each step
+    #[allow(clippy::too_many_arguments)]
+    fn process_traced(
+        &self,
+        path: &str,
+        query: Option<&str>,
+        method: &Method,
+        headers: &HeaderMap,
+        client_ip: Option<&str>,
+        mut explanation: Option<&mut EdgeExplanation>,
+        budget: &mut RegexBudget,
     ) -> EdgeProcessingResult {
         // First, check conditional routing
-        if let Some(action) = self
+        if let Some(rule) = self
             .router
-            .evaluate(path, query, method, headers, client_ip)
+            .matching_rule(path, query, method, headers, client_ip, budget)
         {
-            return EdgeProcessingResult::RouteAction(action.clone());
+            if let Some(explanation) = explanation {
+                explanation.routing_rule = Some(MatchedRoutingRule {
+                    name: rule.name.clone(),
+                    action: rule.action.clone(),
+                });
+            }
+            return EdgeProcessingResult::RouteAction(rule.action.clone());
         }
 
         // Normalize query string
         let normalized_query = self.query_normalizer.normalize(query);
 
         // Rewrite URL
-        let rewritten_path =
-            self.rewriter
-                .rewrite(path, normalized_query.as_deref().or(query), method, headers);
+        let rewritten_path = match self.rewriter.rewrite_traced(
+            path,
+            normalized_query.as_deref().or(query),
+            method,
+            headers,
+            explanation.as_deref_mut().map(|e| &mut e.rewrites),
+            budget,
+        ) {
+            Ok(rewritten_path) => rewritten_path,
+            Err(LookupNotFound { rule }) => return EdgeProcessingResult::NotFound { rule },
+        };
 
+        if let Some(explanation) = explanation {
+            explanation.normalized_query = normalized_query.clone();
+        }
         EdgeProcessingResult::Continue {
             path: rewritten_path,
             query: normalized_query,
         }
     }
 
+    /// What edge processing would do with a request, without running it
+    ///
+    /// Follows the same code path as [`edge_processing_middleware`]: the
+    /// matched routing rule, rewrites in order, the final path and query, and
+    /// the header changes on the way to the origin and back. Header changes
+    /// are only reported when the request would reach the CDN handler.
+    /// `response_headers` stand in for an origin response.
+    pub fn explain(
+        &self,
+        path: &str,
+        query: Option<&str>,
+        method: &Method,
+        headers: &HeaderMap,
+        client_ip: Option<&str>,
+        response_headers: &HeaderMap,
+    ) -> EdgeExplanation {
+        let mut explanation = EdgeExplanation {
+            routing_rule: None,
+            normalized_query: None,
+            rewrites: Vec::new(),
+            forwarded: true,
+            final_path: path.to_string(),
+            final_query: query.map(String::from),
+            request_headers: Vec::new(),
+            response_headers: Vec::new(),
+            set_cookies: Vec::new(),
+            query_limit_exceeded: None,
+            not_found_rule: None,
+        };
+
+        if let Err(exceeded) = check_query_limits(query.unwrap_or_default(), &self.query_limits) {
+            explanation.query_limit_exceeded = Some(exceeded.as_str());
+            explanation.forwarded = false;
+            return explanation;
+        }
+        explanation.set_cookies = self.pin_cookies(headers, client_ip);
+
+        match self.process_traced(
+            path,
+            query,
+            method,
+            headers,
+            client_ip,
+            Some(&mut explanation),
+            &mut RegexBudget::new(self.max_evaluations),
+        ) {
+            EdgeProcessingResult::RouteAction(RoutingAction::RouteToOrigin { .. }) => {}
+            EdgeProcessingResult::RouteAction(_) => {
+                explanation.forwarded = false;
+                return explanation;
+            }
+            EdgeProcessingResult::NotFound { rule } => {
+                explanation.not_found_rule = Some(rule);
+                explanation.forwarded = false;
+                return explanation;
+            }
+            EdgeProcessingResult::Continue {
+                path: new_path,
+                query: new_query,
+            } => {
+                if let Some(new_path) = new_path {
+                    explanation.final_path = new_path;
+                }
+                if new_query.is_some() {
+                    explanation.final_query = new_query;
+                }
+            }
+        }
+
+        let mut request = headers.clone();
+        self.transform_request_headers(&mut request);
+        explanation.request_headers = header_changes(headers, &request);
+        let mut response = response_headers.clone();
+        self.transform_response_headers(&mut response);
+        explanation.response_headers = header_changes(response_headers, &response);
+        explanation
+    }
+
+    /// Set-Cookie values pinning new users to their split buckets
+    pub fn pin_cookies(&self, headers: &HeaderMap, client_ip: Option<&str>) -> Vec<String> {
+        self.router.pin_cookies(headers, client_ip)
+    }
+
+    /// Check the query against the configured limits, counting rejections
+    pub fn check_query_limits(&self, query: Option<&str>) -> Result<(), QueryLimitExceeded> {
+        let result = check_query_limits(query.unwrap_or_default(), &self.query_limits);
+        if let (Err(exceeded), Some(metrics)) = (result, &self.metrics) {
+            metrics.record_query_limit_rejection(exceeded.as_str());
+        }
+        result
+    }
+
     /// Transform request headers
     pub fn transform_request_headers(&self, headers: &mut HeaderMap) {
         self.header_transformer.transform_request_headers(headers);
@@ -1064,6 +1674,95 @@ impl EdgeProcessor {
     }
 }
 
+/// A rewrite rule that changed the path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RewriteStep {
+    pub rule: String,
+    pub from: String,
+    pub to: String,
+    /// Whether the rule ended rewriting
+    pub stop: bool,
+}
+
+/// The routing rule that took a request
+#[derive(Debug, Clone, Serialize)]
+pub struct MatchedRoutingRule {
+    pub name: String,
+    pub action: RoutingAction,
+}
+
+/// How a header transform changed one header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// One header changed by the header transforms
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeaderChange {
+    pub header: String,
+    pub change: HeaderChangeKind,
+    /// The new value; absent for removals
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+/// Trace of edge processing for one request, from [`EdgeProcessor::explain`]
+#[derive(Debug, Clone, Serialize)]
+pub struct EdgeExplanation {
+    /// The routing rule that matched; routing is checked before rewriting
+    pub routing_rule: Option<MatchedRoutingRule>,
+    /// Query string after normalization, if normalization changed it
+    pub normalized_query: Option<String>,
+    /// Rewrite rules that changed the path, in the order they applied
+    pub rewrites: Vec<RewriteStep>,
+    /// Whether the request reaches the CDN handler (false for redirects,
+    /// fixed responses, blocks and modify actions)
+    pub forwarded: bool,
+    pub final_path: String,
+    pub final_query: Option<String>,
+    pub request_headers: Vec<HeaderChange>,
+    pub response_headers: Vec<HeaderChange>,
+    /// Set-Cookie values pinning split buckets, added to any response
+    pub set_cookies: Vec<String>,
+    /// Query limit the request is over; it is rejected before any other processing
+    pub query_limit_exceeded: Option<&'static str>,
+    /// Lookup rule whose dictionary miss answers 404
+    pub not_found_rule: Option<String>,
+}
+
+/// Headers that differ between `before` and `after`, sorted by name
+fn header_changes(before: &HeaderMap, after: &HeaderMap) -> Vec<HeaderChange> {
+    let value = |map: &HeaderMap, name: &HeaderName| {
+        map.get(name)
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+    };
+    let mut names: Vec<&HeaderName> = before.keys().chain(after.keys()).collect();
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let (old, new) = (value(before, name), value(after, name));
+            let change = match (&old, &new) {
+                (None, Some(_)) => HeaderChangeKind::Added,
+                (Some(_), None) => HeaderChangeKind::Removed,
+                (Some(old), Some(new)) if old != new => HeaderChangeKind::Changed,
+                _ => return None,
+            };
+            Some(HeaderChange {
+                header: name.to_string(),
+                change,
+                value: new,
+            })
+        })
+        .collect()
+}
+
 /// Result of edge processing
 #[derive(Debug, Clone)]
 pub enum EdgeProcessingResult {
@@ -1074,6 +1773,8 @@ pub enum EdgeProcessingResult {
     },
     /// Take a routing action
     RouteAction(RoutingAction),
+    /// A lookup rewrite rule missed and answers 404
+    NotFound { rule: String },
 }
 
 // ============================================================================
@@ -1091,13 +1792,27 @@ pub async fn edge_processing_middleware(
     let query = uri.query();
     let method = request.method().clone();
 
+    // Oversized queries are turned away before anything parses them
+    if let Err(exceeded) = processor.check_query_limits(query) {
+        debug!(
+            path = %path,
+            query_len = query.map_or(0, str::len),
+            limit = exceeded.as_str(),
+            "Rejected request over query limit"
+        );
+        return (
+            exceeded.status(),
+            format!("Query string exceeds the {} limit", exceeded.as_str()),
+        )
+            .into_response();
+    }
+
     // Extract client IP from headers or connection
-    let client_ip = request
-        .headers()
-        .get("x-forwarded-for")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.split(',').next())
-        .map(|s| s.trim().to_string());
+    let peer_ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    let client_ip = edge_client_ip(request.headers(), peer_ip);
 
     // Process through edge logic
     let result = processor.process_request(
@@ -1107,9 +1822,22 @@ pub async fn edge_processing_middleware(
         request.headers(),
         client_ip.as_deref(),
     );
+    let pins = processor.pin_cookies(request.headers(), client_ip.as_deref());
 
-    match result {
+    let mut response = match result {
+        EdgeProcessingResult::RouteAction(RoutingAction::RouteToOrigin { origin }) => {
+            // The CDN handler serves from this origin instead of the one in the path
+            request.extensions_mut().insert(RoutedOrigin(origin));
+            processor.transform_request_headers(request.headers_mut());
+            let mut response = next.run(request).await;
+            processor.transform_response_headers(response.headers_mut());
+            response
+        }
         EdgeProcessingResult::RouteAction(action) => handle_routing_action(action),
+        EdgeProcessingResult::NotFound { rule } => {
+            debug!(rule = %rule, path = %path, "Dictionary lookup missed");
+            CdnError::NotFound(path.to_string()).into_response()
+        }
         EdgeProcessingResult::Continue {
             path: new_path,
             query: new_query,
@@ -1138,7 +1866,24 @@ pub async fn edge_processing_middleware(
 
             response
         }
+    };
+
+    for pin in pins {
+        if let Ok(value) = HeaderValue::try_from(pin) {
+            response.headers_mut().append(SET_COOKIE, value);
+        }
     }
+    response
+}
+
+/// Client IP edge rules see: the first X-Forwarded-For hop, else the peer address
+pub fn edge_client_ip(headers: &HeaderMap, peer_ip: Option<String>) -> Option<String> {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.split(',').next())
+        .map(|s| s.trim().to_string())
+        .or(peer_ip)
 }
 
 /// Handle a routing action by generating an appropriate response
@@ -1184,7 +1929,7 @@ fn handle_routing_action(action: RoutingAction) -> Response<Body> {
             response
         }
         RoutingAction::RouteToOrigin { origin: _ } => {
-            // Origin routing is handled elsewhere; just continue
+            // Origin routing is handled by the middleware; this shouldn't reach here
             Response::new(Body::empty())
         }
         RoutingAction::Modify { .. } => {
@@ -1197,6 +1942,7 @@ fn handle_routing_action(action: RoutingAction) -> Response<Body> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dictionary::Dictionary;
     use axum::http::HeaderValue;
 
     #[test]
@@ -1208,6 +1954,7 @@ mod tests {
                 replacement: "/$1".to_string(),
                 stop: false,
                 condition: None,
+                action: RewriteAction::Replace,
             },
             RewriteRule {
                 name: "normalize-api".to_string(),
@@ -1215,23 +1962,24 @@ mod tests {
                 replacement: "/v1/api/$1".to_string(),
                 stop: true,
                 condition: None,
+                action: RewriteAction::Replace,
             },
         ];
 
-        let rewriter = UrlRewriter::new(&rules);
+        let rewriter = UrlRewriter::new(&rules, &RegexLimitsConfig::default());
         let headers = HeaderMap::new();
 
         // Test version removal
         let result = rewriter.rewrite("/v2/users", None, &Method::GET, &headers);
-        assert_eq!(result, Some("/users".to_string()));
+        assert_eq!(result, Ok(Some("/users".to_string())));
 
         // Test API normalization
         let result = rewriter.rewrite("/api/users", None, &Method::GET, &headers);
-        assert_eq!(result, Some("/v1/api/users".to_string()));
+        assert_eq!(result, Ok(Some("/v1/api/users".to_string())));
 
         // Test no match
         let result = rewriter.rewrite("/static/file.js", None, &Method::GET, &headers);
-        assert_eq!(result, None);
+        assert_eq!(result, Ok(None));
     }
 
     #[test]
@@ -1260,6 +2008,82 @@ mod tests {
         assert_eq!(result, None);
     }
 
+    #[test]
+    fn test_query_limits() {
+        let limits = QueryLimitsConfig {
+            max_params: 3,
+            max_length: 40,
+            max_value_length: 8,
+            ..Default::default()
+        };
+        for ok in ["", "a=1&b=2&c=3", "flag&v=12345678"] {
+            assert_eq!(check_query_limits(ok, &limits), Ok(()), "{}", ok);
+        }
+        assert_eq!(
+            check_query_limits("a=1&b=2&c=3&d=4", &limits),
+            Err(QueryLimitExceeded::Params)
+        );
+        // Empty pairs still cost a split each
+        assert_eq!(
+            check_query_limits("a=1&&&", &limits),
+            Err(QueryLimitExceeded::Params)
+        );
+        assert_eq!(
+            check_query_limits("v=123456789", &limits),
+            Err(QueryLimitExceeded::ValueLength)
+        );
+        assert_eq!(
+            check_query_limits(&"a".repeat(41), &limits),
+            Err(QueryLimitExceeded::Length)
+        );
+        assert_eq!(
+            QueryLimitExceeded::Length.status(),
+            StatusCode::URI_TOO_LONG
+        );
+        assert_eq!(QueryLimitExceeded::Params.status(), StatusCode::BAD_REQUEST);
+
+        let disabled = QueryLimitsConfig {
+            enabled: false,
+            ..limits
+        };
+        assert_eq!(check_query_limits(&"a=1&".repeat(100), &disabled), Ok(()));
+    }
+
+    #[test]
+    fn test_adversarial_query_work_is_bounded() {
+        let query: String = (0..10_000)
+            .map(|i| format!("p{}={}", 10_000 - i, i))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        // The limit check gives up at the first parameter over the cap
+        let limits = QueryLimitsConfig {
+            max_length: usize::MAX,
+            ..Default::default()
+        };
+        let start = std::time::Instant::now();
+        assert_eq!(
+            check_query_limits(&query, &limits),
+            Err(QueryLimitExceeded::Params)
+        );
+        assert!(start.elapsed() < std::time::Duration::from_millis(50));
+
+        // With the limits off, the normalizer still parses no more than its ceiling
+        let processor = EdgeProcessor::new(EdgeConfig {
+            query_limits: QueryLimitsConfig {
+                enabled: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let normalized = processor.query_normalizer.normalize(Some(&query)).unwrap();
+        assert_eq!(normalized.split('&').count(), NORMALIZER_MAX_PARAMS);
+
+        let capped = EdgeProcessor::new(EdgeConfig::default());
+        let normalized = capped.query_normalizer.normalize(Some(&query)).unwrap();
+        assert_eq!(normalized.split('&').count(), 256);
+    }
+
     #[test]
     fn test_header_transformation() {
         let config = HeaderTransforms {
@@ -1274,7 +2098,7 @@ mod tests {
             transformations: Vec::new(),
         };
 
-        let transformer = HeaderTransformer::new(&config);
+        let transformer = HeaderTransformer::new(&config, &RegexLimitsConfig::default());
 
         // Test request transformation
         let mut headers = HeaderMap::new();
@@ -1320,7 +2144,7 @@ mod tests {
             },
         ];
 
-        let router = ConditionalRouter::new(rules);
+        let router = compile_router(rules);
         let headers = HeaderMap::new();
 
         // Test admin block
@@ -1357,10 +2181,13 @@ mod tests {
                 replacement: "/static/images/$1".to_string(),
                 stop: true,
                 condition: None,
+                action: RewriteAction::Replace,
             }],
             header_transforms: HeaderTransforms::default(),
             query_normalization: QueryNormalizationConfig::default(),
             routing_rules: vec![],
+            query_limits: QueryLimitsConfig::default(),
+            regex_limits: RegexLimitsConfig::default(),
         };
 
         let processor = EdgeProcessor::new(config);
@@ -1383,4 +2210,641 @@ mod tests {
             _ => panic!("Expected Continue result"),
         }
     }
+
+    #[test]
+    fn test_rule_evaluation_budget() {
+        let request = |processor: &EdgeProcessor| match processor.process_request(
+            "/v2/img/a.png",
+            None,
+            &Method::GET,
+            &HeaderMap::new(),
+            None,
+        ) {
+            EdgeProcessingResult::Continue { path, .. } => path,
+            _ => panic!("Expected Continue result"),
+        };
+
+        let unlimited = EdgeProcessor::new(explained_config());
+        assert_eq!(request(&unlimited).as_deref(), Some("/static/images/a.png"));
+
+        // The routing rule and the first rewrite use it up; the image rewrite is skipped
+        let metrics = Arc::new(Metrics::new());
+        let mut config = explained_config();
+        config.regex_limits.max_evaluations_per_request = 2;
+        let budgeted = EdgeProcessor::new(config).with_metrics(metrics.clone());
+        assert_eq!(request(&budgeted).as_deref(), Some("/img/a.png"));
+        assert!(
+            metrics
+                .gather()
+                .contains("cdn_edge_regex_budget_exceeded_total 1")
+        );
+    }
+
+    #[test]
+    fn test_routing_rule_with_bad_pattern_is_skipped_whole() {
+        let router = compile_router(vec![RoutingRule {
+            name: "beta-api".to_string(),
+            conditions: vec![
+                RoutingCondition::Path {
+                    pattern: "^/api".to_string(),
+                },
+                RoutingCondition::Header {
+                    name: "x-beta".to_string(),
+                    pattern: "(".to_string(),
+                },
+            ],
+            action: RoutingAction::RouteToOrigin {
+                origin: "beta".to_string(),
+            },
+            priority: 0,
+        }]);
+        assert!(
+            router
+                .evaluate("/api/users", None, &Method::GET, &HeaderMap::new(), None)
+                .is_none()
+        );
+    }
+
+    fn compile_router(rules: Vec<RoutingRule>) -> ConditionalRouter {
+        ConditionalRouter::new(rules, &RegexLimitsConfig::default())
+    }
+
+    fn canary_rule(percent: u8, pin: bool) -> RoutingRule {
+        RoutingRule {
+            name: "canary".to_string(),
+            conditions: vec![RoutingCondition::Split {
+                percent,
+                seed_cookie: Some("uid".to_string()),
+                pin,
+            }],
+            action: RoutingAction::RouteToOrigin {
+                origin: "canary".to_string(),
+            },
+            priority: 0,
+        }
+    }
+
+    fn with_cookie(cookie: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(cookie).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_cookie_condition() {
+        let router = compile_router(vec![RoutingRule {
+            name: "beta".to_string(),
+            conditions: vec![RoutingCondition::Cookie {
+                name: "beta".to_string(),
+                pattern: "^(1|true)$".to_string(),
+            }],
+            action: RoutingAction::RouteToOrigin {
+                origin: "beta".to_string(),
+            },
+            priority: 0,
+        }]);
+        let evaluate = |headers: &HeaderMap| {
+            router
+                .evaluate("/", None, &Method::GET, headers, None)
+                .is_some()
+        };
+
+        assert!(evaluate(&with_cookie("session=abc; beta=1")));
+        assert!(!evaluate(&with_cookie("beta=0")));
+        // A similarly named cookie doesn't match
+        assert!(!evaluate(&with_cookie("notbeta=1")));
+        assert!(!evaluate(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_split_is_deterministic_and_honours_ratio() {
+        let router = compile_router(vec![canary_rule(10, false)]);
+        let in_canary = |headers: &HeaderMap, ip: Option<&str>| {
+            router
+                .evaluate("/", None, &Method::GET, headers, ip)
+                .is_some()
+        };
+
+        let users = 10_000;
+        let mut canary = 0;
+        for user in 0..users {
+            let headers = with_cookie(&format!("uid=user-{}", user));
+            let first = in_canary(&headers, Some("198.51.100.1"));
+            // Same user, same bucket, whatever IP they come from
+            assert_eq!(first, in_canary(&headers, Some("203.0.113.9")));
+            canary += usize::from(first);
+        }
+        assert!(
+            (900..=1100).contains(&canary),
+            "{} of {} users in a 10% split",
+            canary,
+            users
+        );
+
+        // Without the seed cookie, users are bucketed by IP
+        let headers = HeaderMap::new();
+        let mut canary = 0;
+        for user in 0..users {
+            let ip = format!("10.{}.{}.1", user / 256, user % 256);
+            let first = in_canary(&headers, Some(&ip));
+            assert_eq!(first, in_canary(&headers, Some(&ip)));
+            canary += usize::from(first);
+        }
+        assert!((900..=1100).contains(&canary));
+        assert!(!in_canary(&headers, None));
+
+        // The edges of the range are all or nothing
+        let everyone = compile_router(vec![canary_rule(100, false)]);
+        let nobody = compile_router(vec![canary_rule(0, false)]);
+        for user in 0..100 {
+            let headers = with_cookie(&format!("uid=user-{}", user));
+            assert!(
+                everyone
+                    .evaluate("/", None, &Method::GET, &headers, None)
+                    .is_some()
+            );
+            assert!(
+                nobody
+                    .evaluate("/", None, &Method::GET, &headers, None)
+                    .is_none()
+            );
+        }
+    }
+
+    #[test]
+    fn test_split_pin_cookie_keeps_ip_bucket() {
+        let router = compile_router(vec![canary_rule(50, true)]);
+        let ip = Some("192.0.2.44");
+
+        let pins = router.pin_cookies(&HeaderMap::new(), ip);
+        assert_eq!(pins.len(), 1);
+        let cookie = pins[0].split(';').next().unwrap();
+        assert!(cookie.starts_with("uid="));
+
+        // The pinned cookie buckets the user exactly as their IP did
+        let by_ip = router
+            .evaluate("/", None, &Method::GET, &HeaderMap::new(), ip)
+            .is_some();
+        for other_ip in [None, Some("198.51.100.7")] {
+            let pinned = router
+                .evaluate("/", None, &Method::GET, &with_cookie(cookie), other_ip)
+                .is_some();
+            assert_eq!(pinned, by_ip);
+        }
+
+        // Users who already have the cookie aren't pinned again
+        assert!(router.pin_cookies(&with_cookie(cookie), ip).is_empty());
+        let unpinned = compile_router(vec![canary_rule(50, false)]);
+        assert!(unpinned.pin_cookies(&HeaderMap::new(), ip).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_route_to_origin_sets_routed_origin() {
+        use axum::{Extension, Router, middleware::from_fn_with_state, routing::get};
+        use tower::ServiceExt;
+
+        let processor = Arc::new(EdgeProcessor::new(EdgeConfig {
+            routing_rules: vec![canary_rule(100, true)],
+            ..Default::default()
+        }));
+        let app = Router::new()
+            .route(
+                "/{*path}",
+                get(|routed: Option<Extension<RoutedOrigin>>| async move {
+                    routed
+                        .map(|Extension(RoutedOrigin(origin))| origin)
+                        .unwrap_or_default()
+                }),
+            )
+            .layer(from_fn_with_state(processor, edge_processing_middleware));
+
+        let response = app
+            .oneshot(
+                Request::get("/web/app.js")
+                    .header("x-forwarded-for", "192.0.2.1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let set_cookie = response
+            .headers()
+            .get(SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(set_cookie.starts_with(&format!("uid={}", visitor_id("192.0.2.1"))));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "canary");
+    }
+
+    fn explained_config() -> EdgeConfig {
+        EdgeConfig {
+            rewrite_rules: vec![
+                RewriteRule {
+                    name: "strip-version".to_string(),
+                    pattern: r"^/v\d+/(.*)$".to_string(),
+                    replacement: "/$1".to_string(),
+                    stop: false,
+                    condition: None,
+                    action: RewriteAction::Replace,
+                },
+                RewriteRule {
+                    name: "images".to_string(),
+                    pattern: r"^/img/(.*)$".to_string(),
+                    replacement: "/static/images/$1".to_string(),
+                    stop: true,
+                    condition: None,
+                    action: RewriteAction::Replace,
+                },
+                RewriteRule {
+                    name: "never".to_string(),
+                    pattern: r"^/static/(.*)$".to_string(),
+                    replacement: "/assets/$1".to_string(),
+                    stop: false,
+                    condition: None,
+                    action: RewriteAction::Replace,
+                },
+            ],
+            header_transforms: HeaderTransforms {
+                request_add: [("x-edge".to_string(), "1".to_string())]
+                    .into_iter()
+                    .collect(),
+                request_remove: vec!["x-debug".to_string()],
+                response_add: [("x-served-by".to_string(), "cdn".to_string())]
+                    .into_iter()
+                    .collect(),
+                response_remove: vec!["server".to_string()],
+                transformations: Vec::new(),
+            },
+            query_normalization: QueryNormalizationConfig::default(),
+            routing_rules: vec![RoutingRule {
+                name: "legacy".to_string(),
+                conditions: vec![RoutingCondition::Path {
+                    pattern: r"^/legacy".to_string(),
+                }],
+                action: RoutingAction::Redirect {
+                    url: "/new".to_string(),
+                    status: 301,
+                },
+                priority: 0,
+            }],
+            query_limits: QueryLimitsConfig::default(),
+            regex_limits: RegexLimitsConfig::default(),
+        }
+    }
+
+    /// Run `request` through the real middleware in front of a handler that echoes
+    /// the URI and request headers it sees and answers with `server: origin`
+    async fn run_middleware(
+        processor: Arc<EdgeProcessor>,
+        request: Request<Body>,
+    ) -> Response<Body> {
+        use axum::{Router, middleware::from_fn_with_state, routing::get};
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route(
+                "/{*path}",
+                get(|request: Request<Body>| async move {
+                    let mut response = Response::new(Body::from(request.uri().to_string()));
+                    for (name, value) in request.headers() {
+                        let echoed = format!("x-echo-{}", name);
+                        response
+                            .headers_mut()
+                            .insert(HeaderName::try_from(echoed).unwrap(), value.clone());
+                    }
+                    response
+                        .headers_mut()
+                        .insert("server", HeaderValue::from_static("origin"));
+                    response
+                }),
+            )
+            .layer(from_fn_with_state(processor, edge_processing_middleware));
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_middleware_rejects_oversized_queries() {
+        let metrics = Arc::new(Metrics::new());
+        let processor = Arc::new(
+            EdgeProcessor::new(EdgeConfig {
+                query_limits: QueryLimitsConfig {
+                    max_params: 4,
+                    max_length: 64,
+                    max_value_length: 16,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .with_metrics(metrics.clone()),
+        );
+        let request = |uri: String| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = run_middleware(processor.clone(), request("/a?x=1&y=2".to_string())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for (query, status) in [
+            ("a&b&c&d&e".to_string(), StatusCode::BAD_REQUEST),
+            (format!("v={}", "x".repeat(17)), StatusCode::BAD_REQUEST),
+            (format!("v={}", "x".repeat(70)), StatusCode::URI_TOO_LONG),
+        ] {
+            let response =
+                run_middleware(processor.clone(), request(format!("/a?{}", query))).await;
+            assert_eq!(response.status(), status, "{}", query);
+
+            let explanation = processor.explain(
+                "/a",
+                Some(&query),
+                &Method::GET,
+                &HeaderMap::new(),
+                None,
+                &HeaderMap::new(),
+            );
+            assert!(!explanation.forwarded);
+            assert!(explanation.query_limit_exceeded.is_some());
+        }
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"cdn_query_limit_rejections_total{limit="params"} 1"#));
+        assert!(output.contains(r#"cdn_query_limit_rejections_total{limit="value_length"} 1"#));
+        assert!(output.contains(r#"cdn_query_limit_rejections_total{limit="length"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn test_explain_matches_middleware() {
+        let processor = Arc::new(EdgeProcessor::new(explained_config()));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-debug", HeaderValue::from_static("1"));
+        headers.insert("accept", HeaderValue::from_static("image/webp"));
+        let mut origin_headers = HeaderMap::new();
+        origin_headers.insert("server", HeaderValue::from_static("origin"));
+
+        let explanation = processor.explain(
+            "/v2/img/logo.png",
+            Some("w=100&utm_source=mail&a=1"),
+            &Method::GET,
+            &headers,
+            None,
+            &origin_headers,
+        );
+
+        assert!(explanation.routing_rule.is_none());
+        assert!(explanation.forwarded);
+        let rules: Vec<_> = explanation
+            .rewrites
+            .iter()
+            .map(|s| s.rule.as_str())
+            .collect();
+        assert_eq!(rules, ["strip-version", "images"]);
+        assert_eq!(explanation.rewrites[0].to, "/img/logo.png");
+        assert!(explanation.rewrites[1].stop);
+        assert_eq!(explanation.normalized_query.as_deref(), Some("a=1&w=100"));
+        assert_eq!(
+            explanation.request_headers,
+            vec![
+                HeaderChange {
+                    header: "x-debug".to_string(),
+                    change: HeaderChangeKind::Removed,
+                    value: None,
+                },
+                HeaderChange {
+                    header: "x-edge".to_string(),
+                    change: HeaderChangeKind::Added,
+                    value: Some("1".to_string()),
+                },
+            ]
+        );
+
+        // The same request through the middleware
+        let mut request = Request::get("/v2/img/logo.png?w=100&utm_source=mail&a=1");
+        for (name, value) in &headers {
+            request = request.header(name, value);
+        }
+        let response = run_middleware(processor, request.body(Body::empty()).unwrap()).await;
+        let seen = response.headers().clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        let final_uri = format!(
+            "{}?{}",
+            explanation.final_path,
+            explanation.final_query.as_deref().unwrap()
+        );
+        assert_eq!(body, final_uri);
+        for change in &explanation.request_headers {
+            let echoed = seen.get(format!("x-echo-{}", change.header));
+            assert_eq!(
+                echoed.map(|v| v.to_str().unwrap().to_string()),
+                change.value,
+                "{}",
+                change.header
+            );
+        }
+        assert!(seen.get("x-echo-accept").is_some());
+
+        // Response transforms as applied to the origin's `server: origin`
+        for change in &explanation.response_headers {
+            assert_eq!(
+                seen.get(&change.header)
+                    .map(|v| v.to_str().unwrap().to_string()),
+                change.value,
+                "{}",
+                change.header
+            );
+        }
+        let changed: Vec<_> = explanation
+            .response_headers
+            .iter()
+            .map(|c| (c.header.as_str(), c.change))
+            .collect();
+        assert_eq!(
+            changed,
+            [
+                ("server", HeaderChangeKind::Removed),
+                ("x-served-by", HeaderChangeKind::Added)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_explain_routing_rule_short_circuits() {
+        let processor = Arc::new(EdgeProcessor::new(explained_config()));
+        let explanation = processor.explain(
+            "/legacy/page",
+            None,
+            &Method::GET,
+            &HeaderMap::new(),
+            None,
+            &HeaderMap::new(),
+        );
+
+        let rule = explanation.routing_rule.as_ref().unwrap();
+        assert_eq!(rule.name, "legacy");
+        assert!(matches!(
+            rule.action,
+            RoutingAction::Redirect { status: 301, .. }
+        ));
+        assert!(!explanation.forwarded);
+        assert!(explanation.rewrites.is_empty());
+        assert!(explanation.request_headers.is_empty());
+        assert_eq!(explanation.final_path, "/legacy/page");
+
+        let response = run_middleware(
+            processor,
+            Request::get("/legacy/page").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), 301);
+        assert_eq!(response.headers().get("location").unwrap(), "/new");
+        assert!(response.headers().get("x-served-by").is_none());
+    }
+
+    fn lookup_rule(
+        name: &str,
+        pattern: &str,
+        group: Option<usize>,
+        on_miss: LookupMiss,
+        fallback: Option<&str>,
+    ) -> RewriteRule {
+        RewriteRule {
+            name: name.to_string(),
+            pattern: pattern.to_string(),
+            replacement: String::new(),
+            stop: false,
+            condition: None,
+            action: RewriteAction::Lookup(LookupRewrite {
+                dictionary: "paths".to_string(),
+                group,
+                on_miss,
+                fallback: fallback.map(String::from),
+            }),
+        }
+    }
+
+    fn paths_dictionary(entries: &[(&str, &str)]) -> Arc<Dictionaries> {
+        let entries = entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut dictionaries = Dictionaries::default();
+        dictionaries.insert(Dictionary::from_entries("paths", entries));
+        Arc::new(dictionaries)
+    }
+
+    #[tokio::test]
+    async fn test_lookup_rewrites() {
+        let processor = Arc::new(
+            EdgeProcessor::new(EdgeConfig {
+                rewrite_rules: vec![
+                    lookup_rule(
+                        "slug",
+                        r"^/blog/([^/]+)$",
+                        Some(1),
+                        LookupMiss::Continue,
+                        None,
+                    ),
+                    lookup_rule("gone", r"^/gone/", None, LookupMiss::NotFound, None),
+                    lookup_rule(
+                        "legacy",
+                        r"^/legacy/",
+                        None,
+                        LookupMiss::Fallback,
+                        Some("/legacy/index.html"),
+                    ),
+                ],
+                ..Default::default()
+            })
+            .with_dictionaries(paths_dictionary(&[
+                ("hello-world", "2024/hello"),
+                ("/gone/kept", "/archive/kept"),
+                ("/legacy/a", "/new/a"),
+            ])),
+        );
+        let path = |path: &str| match processor.process_request(
+            path,
+            None,
+            &Method::GET,
+            &HeaderMap::new(),
+            None,
+        ) {
+            EdgeProcessingResult::Continue { path, .. } => path,
+            other => panic!("{} wasn't forwarded: {:?}", path, other),
+        };
+
+        // A capture group is replaced in place; a miss leaves the path alone
+        assert_eq!(
+            path("/blog/hello-world").as_deref(),
+            Some("/blog/2024/hello")
+        );
+        assert_eq!(path("/blog/unknown"), None);
+        // Without a group the whole path is the key
+        assert_eq!(path("/gone/kept").as_deref(), Some("/archive/kept"));
+        assert_eq!(path("/legacy/a").as_deref(), Some("/new/a"));
+        assert_eq!(path("/legacy/b").as_deref(), Some("/legacy/index.html"));
+
+        let explanation = processor.explain(
+            "/gone/missing",
+            None,
+            &Method::GET,
+            &HeaderMap::new(),
+            None,
+            &HeaderMap::new(),
+        );
+        assert_eq!(explanation.not_found_rule.as_deref(), Some("gone"));
+        assert!(!explanation.forwarded);
+
+        let response = run_middleware(
+            processor.clone(),
+            Request::get("/gone/missing").body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = run_middleware(
+            processor,
+            Request::get("/blog/hello-world")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "/blog/2024/hello");
+    }
+
+    #[test]
+    fn test_lookup_in_a_large_dictionary() {
+        let entries = (0..300_000)
+            .map(|i| (format!("sku-{}", i), format!("p{}", i)))
+            .collect();
+        let mut dictionaries = Dictionaries::default();
+        dictionaries.insert(Dictionary::from_entries("paths", entries));
+        let rewriter = UrlRewriter::new(
+            &[lookup_rule(
+                "sku",
+                r"^/products/([^/]+)",
+                Some(1),
+                LookupMiss::Continue,
+                None,
+            )],
+            &RegexLimitsConfig::default(),
+        )
+        .with_dictionaries(Arc::new(dictionaries));
+
+        let headers = HeaderMap::new();
+        for i in (0..300_000).step_by(997) {
+            let result = rewriter.rewrite(
+                &format!("/products/sku-{}/photo.jpg", i),
+                None,
+                &Method::GET,
+                &headers,
+            );
+            assert_eq!(result, Ok(Some(format!("/products/p{}/photo.jpg", i))));
+        }
+    }
 }
@@ -0,0 +1,338 @@
+//! Content-coding negotiation (RFC 9110 §12.5.3)
+//!
+//! `Accept-Encoding` is parsed once, with q-values, wildcards and the special
+//! handling of `identity`, and every place that depends on the client's
+//! encoding preference goes through `AcceptEncoding::select`: the middleware
+//! that fixes the coding the compression layer will use, and the cache key
+//! for responses that vary on `Accept-Encoding`.
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderValue, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::error::CdnError;
+
+/// A content coding the CDN knows about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentCoding {
+    Br,
+    Zstd,
+    Gzip,
+    Identity,
+}
+
+/// Codings responses can be served in, most preferred first (ties in
+/// q-value go to the earlier one)
+#[cfg(feature = "zstd")]
+pub const SUPPORTED_CODINGS: &[ContentCoding] = &[
+    ContentCoding::Br,
+    ContentCoding::Zstd,
+    ContentCoding::Gzip,
+    ContentCoding::Identity,
+];
+
+/// Codings responses can be served in, most preferred first (ties in
+/// q-value go to the earlier one)
+#[cfg(not(feature = "zstd"))]
+pub const SUPPORTED_CODINGS: &[ContentCoding] = &[
+    ContentCoding::Br,
+    ContentCoding::Gzip,
+    ContentCoding::Identity,
+];
+
+/// Highest q-value, in thousandths
+const Q_MAX: u16 = 1000;
+
+impl ContentCoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentCoding::Br => "br",
+            ContentCoding::Zstd => "zstd",
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Identity => "identity",
+        }
+    }
+
+    /// The coding named by an `Accept-Encoding` token (case-insensitive)
+    pub fn from_token(token: &str) -> Option<Self> {
+        match token.to_ascii_lowercase().as_str() {
+            "br" => Some(ContentCoding::Br),
+            "zstd" => Some(ContentCoding::Zstd),
+            "gzip" | "x-gzip" => Some(ContentCoding::Gzip),
+            "identity" => Some(ContentCoding::Identity),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed `Accept-Encoding` header
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AcceptEncoding {
+    /// Known codings listed, with their q-value in thousandths
+    entries: Vec<(ContentCoding, u16)>,
+    /// The q-value of `*`, if listed
+    wildcard: Option<u16>,
+}
+
+impl AcceptEncoding {
+    /// Parse a header value
+    ///
+    /// Entries with a malformed q-value are ignored, as are unknown codings;
+    /// when a coding is listed twice the first wins.
+    pub fn parse(value: &str) -> Self {
+        let mut accept = AcceptEncoding::default();
+        for item in value.split(',') {
+            let mut parts = item.split(';');
+            let token = parts.next().unwrap_or_default().trim();
+            if token.is_empty() {
+                continue;
+            }
+
+            let mut quality = Some(Q_MAX);
+            for param in parts {
+                if let Some((name, value)) = param.split_once('=')
+                    && name.trim().eq_ignore_ascii_case("q")
+                {
+                    quality = parse_qvalue(value.trim());
+                }
+            }
+            let Some(quality) = quality else {
+                continue;
+            };
+
+            if token == "*" {
+                accept.wildcard.get_or_insert(quality);
+            } else if let Some(coding) = ContentCoding::from_token(token)
+                && !accept.entries.iter().any(|(c, _)| *c == coding)
+            {
+                accept.entries.push((coding, quality));
+            }
+        }
+        accept
+    }
+
+    /// Parse every `Accept-Encoding` header of a request, or `None` without one
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let mut values = headers.get_all(header::ACCEPT_ENCODING).iter().peekable();
+        values.peek()?;
+        let joined = values
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        Some(Self::parse(&joined))
+    }
+
+    /// How acceptable a coding is, in thousandths (0 = not acceptable)
+    ///
+    /// A coding that isn't listed takes the `*` weight. Without one, only
+    /// `identity` is acceptable, and only barely: it is chosen when nothing
+    /// listed is, never over a listed coding however low its weight.
+    pub fn quality(&self, coding: ContentCoding) -> u16 {
+        if let Some((_, quality)) = self.entries.iter().find(|(c, _)| *c == coding) {
+            return *quality;
+        }
+        match (self.wildcard, coding) {
+            (Some(quality), _) => quality,
+            (None, ContentCoding::Identity) => 1,
+            (None, _) => 0,
+        }
+    }
+
+    /// The most acceptable of `available` (listed most preferred first), or
+    /// `None` when the client accepts none of them
+    pub fn select(&self, available: &[ContentCoding]) -> Option<ContentCoding> {
+        let mut best: Option<(ContentCoding, u16)> = None;
+        for &coding in available {
+            let quality = self.quality(coding);
+            if quality > 0 && best.is_none_or(|(_, q)| quality > q) {
+                best = Some((coding, quality));
+            }
+        }
+        best.map(|(coding, _)| coding)
+    }
+
+    /// The coding a response will be served in
+    pub fn negotiate(&self) -> Option<ContentCoding> {
+        self.select(SUPPORTED_CODINGS)
+    }
+}
+
+/// The negotiated coding for a request's `Accept-Encoding` value, as used in
+/// cache keys; no header means `identity`, no acceptable coding "none"
+pub fn cache_key_value(accept_encoding: Option<&str>) -> &'static str {
+    let coding = match accept_encoding {
+        Some(value) => AcceptEncoding::parse(value).negotiate(),
+        None => Some(ContentCoding::Identity),
+    };
+    coding.map_or("none", ContentCoding::as_str)
+}
+
+/// A q-value in thousandths: "0", "1" or up to three decimals
+pub(crate) fn parse_qvalue(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths: u16 = format!("{:0<3}", fraction).parse().ok()?;
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(Q_MAX),
+        _ => None,
+    }
+}
+
+/// Settle the response coding before the compression layer sees the request
+///
+/// The request's `Accept-Encoding` is replaced by the single negotiated
+/// coding, so compression, the cache key and anything downstream agree on
+/// it. A request that accepts none of the supported codings (not even
+/// `identity`) gets 406. A compressed coding may still be answered with
+/// `identity` when the response isn't worth compressing.
+pub async fn negotiate_encoding_middleware(mut request: Request, next: Next) -> Response {
+    let Some(accept) = AcceptEncoding::from_headers(request.headers()) else {
+        return next.run(request).await;
+    };
+    let Some(coding) = accept.negotiate() else {
+        return CdnError::NotAcceptable(
+            "None of the supported content codings are acceptable".to_string(),
+        )
+        .into_response();
+    };
+    request.headers_mut().insert(
+        header::ACCEPT_ENCODING,
+        HeaderValue::from_static(coding.as_str()),
+    );
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ContentCoding::*;
+
+    #[test]
+    fn test_select_table() {
+        // Every coding, most preferred first, as a zstd build serves them
+        let all = [Br, Zstd, Gzip, Identity];
+        let cases: &[(&str, Option<ContentCoding>)] = &[
+            ("", Some(Identity)),
+            (" , ,", Some(Identity)),
+            ("gzip", Some(Gzip)),
+            ("x-gzip", Some(Gzip)),
+            ("gzip, br", Some(Br)),
+            ("gzip, deflate, br, zstd", Some(Br)),
+            ("br;q=1.0, gzip;q=1", Some(Br)),
+            ("br;q=0.5, gzip", Some(Gzip)),
+            ("gzip;q=0, br", Some(Br)),
+            ("gzip;q=0", Some(Identity)),
+            ("gzip;q=0.001", Some(Gzip)),
+            ("zstd;q=1, br;q=0.9", Some(Zstd)),
+            ("GZIP;Q=0.8, Br;q=0.9", Some(Br)),
+            ("gzip ; q=0.5 , br ; q=0.4", Some(Gzip)),
+            ("deflate", Some(Identity)),
+            ("compress, deflate;q=0.5", Some(Identity)),
+            // Wildcards
+            ("*", Some(Br)),
+            ("*;q=0.5, gzip", Some(Gzip)),
+            ("*;q=0", None),
+            ("*;q=0, gzip", Some(Gzip)),
+            ("*;q=0, identity", Some(Identity)),
+            ("*;q=0, identity;q=0.5, gzip;q=0.4", Some(Identity)),
+            ("*, br;q=0", Some(Zstd)),
+            ("*;q=0.1, identity;q=0.5", Some(Identity)),
+            // identity excluded
+            ("identity;q=0", None),
+            ("identity;q=0, gzip", Some(Gzip)),
+            ("identity;q=0, *", Some(Br)),
+            ("gzip;q=0, identity;q=0", None),
+            // Malformed q-values drop their entry; other parameters are ignored
+            ("gzip;q=abc", Some(Identity)),
+            ("gzip;q=abc, br", Some(Br)),
+            ("gzip;q=1.5", Some(Identity)),
+            ("gzip;q=0.0001", Some(Identity)),
+            ("gzip;q=", Some(Identity)),
+            ("br;level=5", Some(Br)),
+            ("*;q=-1", Some(Identity)),
+            // The first of duplicate entries wins
+            ("gzip, gzip;q=0", Some(Gzip)),
+            ("gzip;q=0, gzip", Some(Identity)),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(
+                AcceptEncoding::parse(header).select(&all),
+                *expected,
+                "Accept-Encoding: {:?}",
+                header
+            );
+        }
+
+        // Only what is available is chosen
+        let gzip_only = [Gzip, Identity];
+        let cases: &[(&str, Option<ContentCoding>)] = &[
+            ("br", Some(Identity)),
+            ("br, gzip;q=0.1", Some(Gzip)),
+            ("br, *;q=0", None),
+            ("zstd, identity;q=0", None),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(
+                AcceptEncoding::parse(header).select(&gzip_only),
+                *expected,
+                "Accept-Encoding: {:?}",
+                header
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_qvalue() {
+        for (value, expected) in [
+            ("0", Some(0)),
+            ("0.", Some(0)),
+            ("0.5", Some(500)),
+            ("0.25", Some(250)),
+            ("0.125", Some(125)),
+            ("1", Some(1000)),
+            ("1.000", Some(1000)),
+            ("1.001", None),
+            ("0.1234", None),
+            ("2", None),
+            (".5", None),
+            ("0.5x", None),
+            ("", None),
+        ] {
+            assert_eq!(parse_qvalue(value), expected, "q={:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_cache_key_value() {
+        assert_eq!(cache_key_value(None), "identity");
+        assert_eq!(cache_key_value(Some("gzip, deflate")), "gzip");
+        assert_eq!(cache_key_value(Some("deflate, gzip;q=1.0")), "gzip");
+        assert_eq!(cache_key_value(Some("br;q=0, gzip;q=0")), "identity");
+        assert_eq!(cache_key_value(Some("*;q=0")), "none");
+    }
+
+    #[test]
+    fn test_supported_codings_follow_feature() {
+        assert_eq!(SUPPORTED_CODINGS.contains(&Zstd), cfg!(feature = "zstd"));
+        assert_eq!(SUPPORTED_CODINGS.last(), Some(&Identity));
+    }
+
+    #[test]
+    fn test_from_headers_combines_values() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(AcceptEncoding::from_headers(&headers), None);
+
+        headers.append(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip;q=0.5"));
+        headers.append(header::ACCEPT_ENCODING, HeaderValue::from_static("br"));
+        let accept = AcceptEncoding::from_headers(&headers).unwrap();
+        assert_eq!(accept.quality(Gzip), 500);
+        assert_eq!(accept.quality(Br), 1000);
+        assert_eq!(accept.negotiate(), Some(Br));
+    }
+}
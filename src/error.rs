@@ -8,6 +8,8 @@ use std::sync::OnceLock;
 use thiserror::Error;
 
 use crate::error_pages::{ErrorPages, default_error_page};
+use crate::observability::current_request_id;
+use crate::origin::OriginErrorKind;
 
 /// Global error pages instance (set during initialization)
 static ERROR_PAGES: OnceLock<ErrorPages> = OnceLock::new();
@@ -27,8 +29,32 @@ pub enum CdnError {
     #[error("Origin server error: {0}")]
     OriginError(String),
 
-    #[error("Origin server unreachable: {0}")]
-    OriginUnreachable(String),
+    /// The origin can't be tried right now (its circuit breaker is open)
+    #[error("Origin unavailable: {message}")]
+    OriginUnavailable {
+        message: String,
+        /// Seconds until the origin may be tried again, for `Retry-After`
+        retry_after_secs: Option<u64>,
+    },
+
+    /// A failed origin fetch, classified for retry and circuit breaker policy
+    #[error("Origin fetch failed ({kind}): {message}")]
+    OriginFetch {
+        kind: OriginErrorKind,
+        message: String,
+    },
+
+    /// The origin answered with a 5xx and no stale copy could stand in;
+    /// a masked status is answered as 502
+    #[error("Origin returned {status}: {message}")]
+    OriginStatus {
+        status: StatusCode,
+        masked: bool,
+        message: String,
+    },
+
+    #[error("Gateway timeout: {0}")]
+    GatewayTimeout(String),
 
     #[error("Cache error: {0}")]
     CacheError(String),
@@ -39,11 +65,27 @@ pub enum CdnError {
     #[error("Resource not found: {0}")]
     NotFound(String),
 
+    #[error("URI too long: {0}")]
+    UriTooLong(String),
+
+    #[error("Not acceptable: {0}")]
+    NotAcceptable(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
     #[error("Configuration error: {0}")]
     ConfigError(String),
 
     #[error("Internal server error: {0}")]
     Internal(String),
+
+    /// An error raised while serving a known origin (selects its error pages)
+    #[error("{source}")]
+    ForOrigin {
+        origin: String,
+        source: Box<CdnError>,
+    },
 }
 
 impl CdnError {
@@ -51,12 +93,26 @@ impl CdnError {
     pub fn status_code(&self) -> StatusCode {
         match self {
             CdnError::OriginError(_) => StatusCode::BAD_GATEWAY,
-            CdnError::OriginUnreachable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            CdnError::OriginUnavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            // Unreachable origins are bad gateways; only waiting on one is a timeout
+            CdnError::OriginFetch { kind, .. } => match kind {
+                OriginErrorKind::Timeout | OriginErrorKind::FirstByteTimeout => {
+                    StatusCode::GATEWAY_TIMEOUT
+                }
+                _ => StatusCode::BAD_GATEWAY,
+            },
+            CdnError::OriginStatus { masked: true, .. } => StatusCode::BAD_GATEWAY,
+            CdnError::OriginStatus { status, .. } => *status,
+            CdnError::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
             CdnError::CacheError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             CdnError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
             CdnError::NotFound(_) => StatusCode::NOT_FOUND,
+            CdnError::UriTooLong(_) => StatusCode::URI_TOO_LONG,
+            CdnError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            CdnError::Conflict(_) => StatusCode::CONFLICT,
             CdnError::ConfigError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             CdnError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            CdnError::ForOrigin { source, .. } => source.status_code(),
         }
     }
 
@@ -64,26 +120,98 @@ impl CdnError {
     pub fn message(&self) -> &str {
         match self {
             CdnError::OriginError(msg) => msg,
-            CdnError::OriginUnreachable(msg) => msg,
+            CdnError::OriginUnavailable { message, .. } => message,
+            CdnError::OriginFetch { message, .. } => message,
+            CdnError::OriginStatus { message, .. } => message,
+            CdnError::GatewayTimeout(msg) => msg,
             CdnError::CacheError(msg) => msg,
             CdnError::InvalidRequest(msg) => msg,
             CdnError::NotFound(msg) => msg,
+            CdnError::UriTooLong(msg) => msg,
+            CdnError::NotAcceptable(msg) => msg,
+            CdnError::Conflict(msg) => msg,
             CdnError::ConfigError(msg) => msg,
             CdnError::Internal(msg) => msg,
+            CdnError::ForOrigin { source, .. } => source.message(),
+        }
+    }
+
+    /// Tag this error with the origin being served; an existing tag is kept
+    pub fn with_origin(self, origin: impl Into<String>) -> Self {
+        match self {
+            CdnError::ForOrigin { .. } => self,
+            other => CdnError::ForOrigin {
+                origin: origin.into(),
+                source: Box::new(other),
+            },
+        }
+    }
+
+    /// The origin this error occurred for, if known
+    pub fn origin(&self) -> Option<&str> {
+        match self {
+            CdnError::ForOrigin { origin, .. } => Some(origin),
+            _ => None,
+        }
+    }
+
+    /// What kind of origin failure this was, if it was one
+    pub fn origin_error_kind(&self) -> Option<OriginErrorKind> {
+        match self {
+            CdnError::OriginFetch { kind, .. } => Some(*kind),
+            CdnError::OriginStatus { status, .. } => {
+                Some(OriginErrorKind::Http5xx(status.as_u16()))
+            }
+            CdnError::ForOrigin { source, .. } => source.origin_error_kind(),
+            _ => None,
+        }
+    }
+
+    /// Seconds a client should wait before retrying, if known
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            CdnError::OriginUnavailable {
+                retry_after_secs, ..
+            } => *retry_after_secs,
+            CdnError::ForOrigin { source, .. } => source.retry_after_secs(),
+            _ => None,
         }
     }
 }
 
 impl IntoResponse for CdnError {
     fn into_response(self) -> Response {
+        let origin_error = self.origin_error_kind();
+        let retry_after = self.retry_after_secs();
+        let mut response = self.render();
+        // Lets the request log say which kind of origin failure this was
+        if let Some(kind) = origin_error {
+            response.extensions_mut().insert(kind);
+        }
+        if let Some(secs) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, header::HeaderValue::from(secs));
+        }
+        response
+    }
+}
+
+impl CdnError {
+    fn render(self) -> Response {
         let status = self.status_code();
         let message = self.message().to_string();
+        let origin = self.origin();
+        // Quoted back so a user's report can be matched to the request log
+        let request_id = current_request_id();
 
         // Check if we have custom error pages enabled
         if let Some(error_pages) = get_error_pages()
             && error_pages.is_enabled() {
-                // Try to render custom error page
-                if let Some(html) = error_pages.render_page(status, &message) {
+                // Try to render custom error page, preferring the origin's own
+                if let Some(html) =
+                    error_pages.render_page_for(origin, status, &message, request_id.as_deref())
+                {
                     return (
                         status,
                         [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
@@ -93,7 +221,7 @@ impl IntoResponse for CdnError {
                 }
 
                 // Fall back to default styled error page
-                let html = default_error_page(status, &message);
+                let html = default_error_page(status, &message, request_id.as_deref());
                 return (
                     status,
                     [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
@@ -103,10 +231,14 @@ impl IntoResponse for CdnError {
             }
 
         // Default JSON error response
-        let body = Json(json!({
+        let mut body = json!({
             "error": message,
             "status": status.as_u16()
-        }));
+        });
+        if let Some(request_id) = request_id {
+            body["request_id"] = request_id.into();
+        }
+        let body = Json(body);
 
         (status, body).into_response()
     }
@@ -114,14 +246,88 @@ impl IntoResponse for CdnError {
 
 impl From<reqwest::Error> for CdnError {
     fn from(err: reqwest::Error) -> Self {
-        if err.is_connect() {
-            CdnError::OriginUnreachable(err.to_string())
-        } else if err.is_timeout() {
-            CdnError::OriginUnreachable(format!("Origin timeout: {}", err))
-        } else {
-            CdnError::OriginError(err.to_string())
+        CdnError::OriginFetch {
+            kind: OriginErrorKind::classify(&err),
+            message: err.to_string(),
         }
     }
 }
 
 pub type CdnResult<T> = Result<T, CdnError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_origin_keeps_status_and_message() {
+        let err = CdnError::NotFound("missing".to_string()).with_origin("brand");
+
+        assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(err.message(), "missing");
+        assert_eq!(err.origin(), Some("brand"));
+        assert_eq!(err.to_string(), "Resource not found: missing");
+
+        // The first origin tag wins
+        let err = err.with_origin("other");
+        assert_eq!(err.origin(), Some("brand"));
+    }
+
+    #[test]
+    fn test_origin_fetch_status_and_kind() {
+        let err = CdnError::OriginFetch {
+            kind: OriginErrorKind::DnsError,
+            message: "no such host".to_string(),
+        }
+        .with_origin("brand");
+        assert_eq!(err.status_code(), StatusCode::BAD_GATEWAY);
+        assert_eq!(err.origin_error_kind(), Some(OriginErrorKind::DnsError));
+        assert_eq!(err.to_string(), "Origin fetch failed (dns): no such host");
+
+        let err = CdnError::OriginFetch {
+            kind: OriginErrorKind::BodyError,
+            message: "connection reset".to_string(),
+        };
+        assert_eq!(err.status_code(), StatusCode::BAD_GATEWAY);
+        assert!(
+            err.into_response()
+                .extensions()
+                .get::<OriginErrorKind>()
+                .is_some()
+        );
+        assert_eq!(
+            CdnError::NotFound("x".to_string()).origin_error_kind(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_origin_failure_status_mapping() {
+        let timeout = CdnError::OriginFetch {
+            kind: OriginErrorKind::Timeout,
+            message: "timed out".to_string(),
+        };
+        assert_eq!(timeout.status_code(), StatusCode::GATEWAY_TIMEOUT);
+
+        let status = |masked| CdnError::OriginStatus {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            masked,
+            message: "origin returned 503".to_string(),
+        };
+        assert_eq!(status(false).status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status(true).status_code(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            status(true).origin_error_kind(),
+            Some(OriginErrorKind::Http5xx(503))
+        );
+
+        let response = CdnError::OriginUnavailable {
+            message: "circuit breaker is open".to_string(),
+            retry_after_secs: Some(12),
+        }
+        .with_origin("brand")
+        .into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "12");
+    }
+}
@@ -5,112 +5,208 @@
 use axum::http::StatusCode;
 use std::collections::HashMap;
 use std::path::Path;
-use std::sync::Arc;
-use tracing::{info, warn};
+use std::sync::{Arc, RwLock};
+use tracing::{debug, info, warn};
 
 use crate::config::ErrorPagesConfig;
 
+/// Status codes picked up automatically as `<code>.html`
+const DISCOVERED_STATUS_CODES: [u16; 8] = [400, 401, 403, 404, 500, 502, 503, 504];
+
+/// Templates currently loaded from disk; replaced as a whole on reload
+#[derive(Default)]
+struct PageSet {
+    global: HashMap<u16, String>,
+    origins: HashMap<String, HashMap<u16, String>>,
+}
+
 /// Manages custom error pages
+///
+/// Templates can be re-read from disk with [`ErrorPages::reload`]. Origins
+/// with their own directory get those pages first and fall back to the
+/// global set for status codes they don't override.
 #[derive(Clone)]
 pub struct ErrorPages {
-    enabled: bool,
-    pages: Arc<HashMap<u16, String>>,
+    config: ErrorPagesConfig,
+    /// Per-origin template directories, keyed by origin name
+    origin_dirs: HashMap<String, String>,
+    pages: Arc<RwLock<PageSet>>,
 }
 
 impl ErrorPages {
     /// Create a new ErrorPages instance from configuration
     pub fn new(config: &ErrorPagesConfig) -> Self {
-        if !config.enabled {
-            return Self {
-                enabled: false,
-                pages: Arc::new(HashMap::new()),
-            };
-        }
+        let pages = Self {
+            config: config.clone(),
+            origin_dirs: HashMap::new(),
+            pages: Arc::new(RwLock::new(PageSet::default())),
+        };
+        pages.reload();
+        pages
+    }
 
-        let mut pages = HashMap::new();
-
-        // Load pages from explicit configuration
-        let page_configs = [
-            (400, &config.page_400),
-            (404, &config.page_404),
-            (500, &config.page_500),
-            (502, &config.page_502),
-            (503, &config.page_503),
-            (504, &config.page_504),
-        ];
-
-        for (status_code, page_option) in page_configs {
-            if let Some(page_path) = page_option
-                && let Some(content) = load_error_page(page_path, &config.directory) {
-                    pages.insert(status_code, content);
-                    info!(status_code = status_code, path = %page_path, "Loaded custom error page");
-                }
+    /// Add per-origin template directories, consulted before the global one
+    pub fn with_origin_dirs(mut self, dirs: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.origin_dirs = dirs.into_iter().collect();
+        if self.config.enabled {
+            let origins = self.load_origin_pages();
+            self.pages.write().unwrap().origins = origins;
         }
+        self
+    }
 
-        // Also try to auto-discover error pages in the directory
-        // Look for files named like "400.html", "404.html", etc.
-        if Path::new(&config.directory).exists() {
-            for status_code in [400, 401, 403, 404, 500, 502, 503, 504] {
-                if pages.contains_key(&status_code) {
-                    continue; // Already loaded from explicit config
-                }
-
-                let filename = format!("{}.html", status_code);
-                let filepath = Path::new(&config.directory).join(&filename);
-
-                if filepath.exists()
-                    && let Ok(content) = std::fs::read_to_string(&filepath) {
-                        pages.insert(status_code, content);
-                        info!(status_code = status_code, path = ?filepath, "Auto-discovered custom error page");
-                    }
-            }
+    /// Re-read every template from disk, returning the number of pages loaded
+    pub fn reload(&self) -> usize {
+        if !self.config.enabled {
+            return 0;
         }
 
-        if pages.is_empty() {
+        let global = load_global_pages(&self.config);
+        if global.is_empty() {
             warn!("Error pages enabled but no custom pages found");
         } else {
-            info!(count = pages.len(), "Custom error pages loaded");
+            info!(count = global.len(), "Custom error pages loaded");
         }
 
-        Self {
-            enabled: true,
-            pages: Arc::new(pages),
-        }
+        let origins = self.load_origin_pages();
+
+        let count = global.len() + origins.values().map(HashMap::len).sum::<usize>();
+        *self.pages.write().unwrap() = PageSet { global, origins };
+        count
+    }
+
+    fn load_origin_pages(&self) -> HashMap<String, HashMap<u16, String>> {
+        self.origin_dirs
+            .iter()
+            .map(|(origin, dir)| {
+                let pages = discover_pages(dir);
+                info!(origin = %origin, directory = %dir, count = pages.len(), "Origin error pages loaded");
+                (origin.clone(), pages)
+            })
+            .collect()
     }
 
     /// Check if custom error pages are enabled
     pub fn is_enabled(&self) -> bool {
-        self.enabled
+        self.config.enabled
     }
 
     /// Get custom error page content for a status code
-    pub fn get_page(&self, status_code: StatusCode) -> Option<&str> {
-        if !self.enabled {
+    pub fn get_page(&self, status_code: StatusCode) -> Option<String> {
+        self.get_page_for(None, status_code)
+    }
+
+    /// Get error page content for a status code, preferring the origin's own page
+    pub fn get_page_for(&self, origin: Option<&str>, status_code: StatusCode) -> Option<String> {
+        if !self.config.enabled {
             return None;
         }
 
-        self.pages.get(&status_code.as_u16()).map(|s| s.as_str())
+        let code = status_code.as_u16();
+        let pages = self.pages.read().unwrap();
+        origin
+            .and_then(|origin| pages.origins.get(origin))
+            .and_then(|origin_pages| origin_pages.get(&code))
+            .or_else(|| pages.global.get(&code))
+            .cloned()
     }
 
     /// Get custom error page content with variable substitution
-    /// Supports placeholders: {{status_code}}, {{status_text}}, {{message}}
+    /// Supports placeholders: {{status_code}}, {{status_text}}, {{message}}, {{request_id}}
     pub fn render_page(&self, status_code: StatusCode, message: &str) -> Option<String> {
-        let template = self.get_page(status_code)?;
+        self.render_page_for(None, status_code, message, None)
+    }
+
+    /// Render an error page for a request whose origin is known
+    pub fn render_page_for(
+        &self,
+        origin: Option<&str>,
+        status_code: StatusCode,
+        message: &str,
+        request_id: Option<&str>,
+    ) -> Option<String> {
+        let template = self.get_page_for(origin, status_code)?;
 
         let status_text = status_code.canonical_reason().unwrap_or("Error");
 
         let rendered = template
             .replace("{{status_code}}", &status_code.as_u16().to_string())
             .replace("{{status_text}}", status_text)
-            .replace("{{message}}", message);
+            .replace("{{message}}", message)
+            .replace("{{request_id}}", request_id.unwrap_or(""));
 
         Some(rendered)
     }
 
     /// List all available custom error pages
     pub fn available_pages(&self) -> Vec<u16> {
-        self.pages.keys().copied().collect()
+        self.pages.read().unwrap().global.keys().copied().collect()
+    }
+
+    /// List the status codes an origin overrides with its own pages
+    pub fn origin_pages(&self, origin: &str) -> Vec<u16> {
+        self.pages
+            .read()
+            .unwrap()
+            .origins
+            .get(origin)
+            .map(|pages| pages.keys().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Load the global pages: explicitly configured files, then `<code>.html`
+fn load_global_pages(config: &ErrorPagesConfig) -> HashMap<u16, String> {
+    let mut pages = HashMap::new();
+
+    // Load pages from explicit configuration
+    let page_configs = [
+        (400, &config.page_400),
+        (404, &config.page_404),
+        (500, &config.page_500),
+        (502, &config.page_502),
+        (503, &config.page_503),
+        (504, &config.page_504),
+    ];
+
+    for (status_code, page_option) in page_configs {
+        if let Some(page_path) = page_option
+            && let Some(content) = load_error_page(page_path, &config.directory)
+        {
+            pages.insert(status_code, content);
+            info!(status_code = status_code, path = %page_path, "Loaded custom error page");
+        }
+    }
+
+    // Also try to auto-discover error pages in the directory
+    for (status_code, content) in discover_pages(&config.directory) {
+        // Explicit config wins
+        pages.entry(status_code).or_insert(content);
+    }
+
+    pages
+}
+
+/// Find pages named like "400.html", "404.html", etc. in a directory
+fn discover_pages(directory: &str) -> HashMap<u16, String> {
+    let mut pages = HashMap::new();
+    if !Path::new(directory).exists() {
+        return pages;
+    }
+
+    for status_code in DISCOVERED_STATUS_CODES {
+        let filename = format!("{}.html", status_code);
+        let filepath = Path::new(directory).join(&filename);
+
+        if filepath.exists()
+            && let Ok(content) = std::fs::read_to_string(&filepath)
+        {
+            pages.insert(status_code, content);
+            debug!(status_code = status_code, path = ?filepath, "Auto-discovered custom error page");
+        }
     }
+
+    pages
 }
 
 /// Load an error page from the filesystem
@@ -144,8 +240,20 @@ fn load_error_page(page_path: &str, base_dir: &str) -> Option<String> {
 }
 
 /// Generate a default HTML error page (used when no custom page is available)
-pub fn default_error_page(status_code: StatusCode, message: &str) -> String {
+pub fn default_error_page(
+    status_code: StatusCode,
+    message: &str,
+    request_id: Option<&str>,
+) -> String {
     let status_text = status_code.canonical_reason().unwrap_or("Error");
+    let request_id = request_id
+        .map(|id| {
+            format!(
+                "\n        <p class=\"request-id\">Request ID: {}</p>",
+                html_escape(id)
+            )
+        })
+        .unwrap_or_default();
 
     format!(
         r#"<!DOCTYPE html>
@@ -189,6 +297,11 @@ pub fn default_error_page(status_code: StatusCode, message: &str) -> String {
             max-width: 500px;
             margin: 1rem auto;
         }}
+        .request-id {{
+            font-family: monospace;
+            font-size: 0.8rem;
+            color: #505050;
+        }}
         .powered-by {{
             margin-top: 3rem;
             font-size: 0.8rem;
@@ -200,7 +313,7 @@ pub fn default_error_page(status_code: StatusCode, message: &str) -> String {
     <div class="container">
         <h1 class="status-code">{}</h1>
         <p class="status-text">{}</p>
-        <p class="message">{}</p>
+        <p class="message">{}</p>{}
         <p class="powered-by">Powered by Screaming Eagle CDN</p>
     </div>
 </body>
@@ -209,7 +322,8 @@ pub fn default_error_page(status_code: StatusCode, message: &str) -> String {
         status_text,
         status_code.as_u16(),
         status_text,
-        html_escape(message)
+        html_escape(message),
+        request_id
     )
 }
 
@@ -237,12 +351,16 @@ mod tests {
 
     #[test]
     fn test_default_error_page() {
-        let html = default_error_page(StatusCode::NOT_FOUND, "Page not found");
+        let html = default_error_page(StatusCode::NOT_FOUND, "Page not found", None);
 
         assert!(html.contains("404"));
         assert!(html.contains("Not Found"));
         assert!(html.contains("Page not found"));
         assert!(html.contains("Screaming Eagle"));
+        assert!(!html.contains("Request ID"));
+
+        let html = default_error_page(StatusCode::BAD_GATEWAY, "Origin down", Some("abc-123"));
+        assert!(html.contains("Request ID: abc-123"));
     }
 
     #[test]
@@ -254,8 +372,10 @@ mod tests {
 
     #[test]
     fn test_render_page() {
-        let mut config = ErrorPagesConfig::default();
-        config.enabled = true;
+        let config = ErrorPagesConfig {
+            enabled: true,
+            ..Default::default()
+        };
 
         // Since we can't easily create files in tests, we'll test the disabled case
         let pages = ErrorPages::new(&config);
@@ -263,4 +383,87 @@ mod tests {
         // No pages loaded, should return None
         assert!(pages.render_page(StatusCode::NOT_FOUND, "test").is_none());
     }
+
+    /// Fresh scratch directory for template files
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "screaming-eagle-error-pages-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn enabled_config(directory: &Path) -> ErrorPagesConfig {
+        ErrorPagesConfig {
+            enabled: true,
+            directory: directory.to_string_lossy().into_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_origin_pages_override_global() {
+        let global = scratch_dir("global");
+        let brand = scratch_dir("brand");
+        std::fs::write(global.join("404.html"), "global {{status_code}}").unwrap();
+        std::fs::write(global.join("502.html"), "global bad gateway").unwrap();
+        std::fs::write(brand.join("404.html"), "brand {{message}} ({{request_id}})").unwrap();
+
+        let pages = ErrorPages::new(&enabled_config(&global))
+            .with_origin_dirs([("brand".to_string(), brand.to_string_lossy().into_owned())]);
+
+        assert_eq!(
+            pages
+                .render_page_for(Some("brand"), StatusCode::NOT_FOUND, "gone", Some("req-1"))
+                .unwrap(),
+            "brand gone (req-1)"
+        );
+        // Status codes the origin doesn't override come from the global set
+        assert_eq!(
+            pages
+                .get_page_for(Some("brand"), StatusCode::BAD_GATEWAY)
+                .unwrap(),
+            "global bad gateway"
+        );
+        // Other origins and unknown origins only see global pages
+        assert_eq!(
+            pages
+                .render_page_for(Some("other"), StatusCode::NOT_FOUND, "gone", None)
+                .unwrap(),
+            "global 404"
+        );
+        assert_eq!(pages.origin_pages("brand"), vec![404]);
+
+        let _ = std::fs::remove_dir_all(&global);
+        let _ = std::fs::remove_dir_all(&brand);
+    }
+
+    #[test]
+    fn test_reload_picks_up_edits() {
+        let dir = scratch_dir("reload");
+        std::fs::write(dir.join("503.html"), "before").unwrap();
+
+        let pages = ErrorPages::new(&enabled_config(&dir));
+        // Clones share the loaded templates, so the global instance sees reloads
+        let shared = pages.clone();
+        assert_eq!(
+            pages.get_page(StatusCode::SERVICE_UNAVAILABLE).unwrap(),
+            "before"
+        );
+
+        std::fs::write(dir.join("503.html"), "after").unwrap();
+        std::fs::write(dir.join("404.html"), "new page").unwrap();
+        assert_eq!(pages.reload(), 2);
+
+        assert_eq!(
+            shared.get_page(StatusCode::SERVICE_UNAVAILABLE).unwrap(),
+            "after"
+        );
+        assert_eq!(shared.get_page(StatusCode::NOT_FOUND).unwrap(), "new page");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,260 @@
+//! Freshness lifetime of origin responses (RFC 9111 Section 4.2.1)
+//!
+//! In order of precedence: `s-maxage`, `max-age`, then `Expires` measured
+//! against the origin's own `Date`, so a skewed origin clock shifts both
+//! ends equally. Without any of these a response has no explicit lifetime
+//! and the cache falls back to a heuristic or its default TTL.
+
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use crate::cache::CacheControlDirectives;
+use crate::headers::ResponseHeaders;
+
+/// Share of a response's Last-Modified age taken as its heuristic lifetime
+/// (RFC 9111 Section 4.2.2)
+const HEURISTIC_FRACTION: f64 = 0.1;
+
+/// Where a response's freshness lifetime came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreshnessSource {
+    SMaxAge,
+    MaxAge,
+    Expires,
+    Heuristic,
+}
+
+/// Explicit or heuristic freshness lifetime of a response
+///
+/// `None` when the response doesn't say, and either `heuristic` is off or it
+/// has no usable Last-Modified. An Expires in the past, or one that can't be
+/// parsed, gives a lifetime of zero: the response is already stale.
+/// `received_at` stands in for a missing or unparseable Date.
+pub fn freshness_lifetime(
+    directives: &CacheControlDirectives,
+    headers: &ResponseHeaders,
+    received_at: SystemTime,
+    heuristic: bool,
+) -> Option<(Duration, FreshnessSource)> {
+    if let Some(secs) = directives.s_maxage {
+        return Some((Duration::from_secs(secs), FreshnessSource::SMaxAge));
+    }
+    if let Some(secs) = directives.max_age {
+        return Some((Duration::from_secs(secs), FreshnessSource::MaxAge));
+    }
+
+    let date = headers
+        .get("date")
+        .and_then(|value| parse_http_date(value))
+        .unwrap_or(received_at);
+
+    if let Some(expires) = headers.get("expires") {
+        let lifetime = parse_http_date(expires)
+            .and_then(|expires| expires.duration_since(date).ok())
+            .unwrap_or(Duration::ZERO);
+        return Some((lifetime, FreshnessSource::Expires));
+    }
+
+    if heuristic
+        && let Some(last_modified) = headers
+            .get("last-modified")
+            .and_then(|v| parse_http_date(v))
+        && let Ok(age) = date.duration_since(last_modified)
+    {
+        return Some((age.mul_f64(HEURISTIC_FRACTION), FreshnessSource::Heuristic));
+    }
+
+    None
+}
+
+/// Parse an HTTP date (RFC 9110 Section 5.6.7)
+///
+/// Accepts IMF-fixdate and the obsolete RFC 850 and asctime forms that
+/// recipients must still read, and falls back to the slips origins commonly
+/// make: numeric zones, `UTC` for `GMT`, a missing weekday or a wrong one.
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+
+    // Numeric zones and other RFC 2822 dates, with a weekday that must match
+    if let Ok(date) = DateTime::parse_from_rfc2822(value) {
+        return Some(date.with_timezone(&Utc).into());
+    }
+
+    // The weekday is redundant, so a wrong or missing one is ignored
+    let value = match value.split_once(',') {
+        Some((weekday, rest)) if weekday.chars().all(|c| c.is_ascii_alphabetic()) => rest.trim(),
+        _ => value,
+    };
+    let value = value
+        .strip_suffix(" GMT")
+        .or_else(|| value.strip_suffix(" UTC"))
+        .or_else(|| value.strip_suffix(" UT"))
+        .unwrap_or(value);
+
+    // IMF-fixdate, then RFC 850 (two-digit years), then asctime with its
+    // weekday still in front
+    [
+        "%d %b %Y %H:%M:%S",
+        "%d-%b-%y %H:%M:%S",
+        "%a %b %e %H:%M:%S %Y",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+    .map(|date| date.and_utc().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::parse_cache_control;
+
+    /// 2024-01-01T00:00:00Z
+    const JAN_1: u64 = 1_704_067_200;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn lifetime(headers: &[(&str, &str)], heuristic: bool) -> Option<(Duration, FreshnessSource)> {
+        let mut response = ResponseHeaders::new();
+        for (name, value) in headers {
+            response.insert(*name, *value);
+        }
+        let directives = response
+            .get("cache-control")
+            .map(|cc| parse_cache_control(cc))
+            .unwrap_or_default();
+        // Received an hour after the origin's Date, as if its clock were behind
+        freshness_lifetime(&directives, &response, at(JAN_1 + 3600), heuristic)
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        for value in [
+            "Mon, 01 Jan 2024 00:00:00 GMT",
+            "Monday, 01-Jan-24 00:00:00 GMT",
+            "Mon Jan  1 00:00:00 2024",
+            // Common slips
+            "Mon, 01 Jan 2024 00:00:00 UTC",
+            "Mon, 01 Jan 2024 01:00:00 +0100",
+            "01 Jan 2024 00:00:00 GMT",
+            "Tue, 01 Jan 2024 00:00:00 GMT",
+            " Mon, 01 Jan 2024 00:00:00 GMT ",
+        ] {
+            assert_eq!(parse_http_date(value), Some(at(JAN_1)), "{}", value);
+        }
+        for value in ["0", "-1", "", "yesterday", "Mon, 32 Jan 2024 00:00:00 GMT"] {
+            assert_eq!(parse_http_date(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        let expires = ("expires", "Mon, 01 Jan 2024 02:00:00 GMT");
+        let date = ("date", "Mon, 01 Jan 2024 00:00:00 GMT");
+        assert_eq!(
+            lifetime(
+                &[("cache-control", "max-age=60, s-maxage=30"), expires, date],
+                false
+            ),
+            Some((Duration::from_secs(30), FreshnessSource::SMaxAge))
+        );
+        assert_eq!(
+            lifetime(
+                &[("cache-control", "public, max-age=60"), expires, date],
+                false
+            ),
+            Some((Duration::from_secs(60), FreshnessSource::MaxAge))
+        );
+        assert_eq!(
+            lifetime(&[("cache-control", "public"), expires, date], false),
+            Some((Duration::from_secs(7200), FreshnessSource::Expires))
+        );
+        assert_eq!(lifetime(&[date], false), None);
+    }
+
+    #[test]
+    fn test_expires_against_skewed_date() {
+        // Measured against the origin's Date, not our clock an hour ahead of it
+        assert_eq!(
+            lifetime(
+                &[
+                    ("date", "Mon, 01 Jan 2024 00:00:00 GMT"),
+                    ("expires", "Mon, 01 Jan 2024 00:10:00 GMT"),
+                ],
+                false
+            ),
+            Some((Duration::from_secs(600), FreshnessSource::Expires))
+        );
+        // An origin clock running a day fast moves both ends
+        assert_eq!(
+            lifetime(
+                &[
+                    ("date", "Tue, 02 Jan 2024 01:00:00 GMT"),
+                    ("expires", "Tue, 02 Jan 2024 01:05:00 GMT"),
+                ],
+                false
+            )
+            .unwrap()
+            .0,
+            Duration::from_secs(300)
+        );
+        // Without a usable Date, our receipt time stands in
+        for date in [None, Some("garbage")] {
+            let mut headers = vec![("expires", "Mon, 01 Jan 2024 01:30:00 GMT")];
+            headers.extend(date.map(|d| ("date", d)));
+            assert_eq!(
+                lifetime(&headers, false).unwrap().0,
+                Duration::from_secs(1800)
+            );
+        }
+    }
+
+    #[test]
+    fn test_past_or_invalid_expires_is_stale() {
+        for expires in [
+            "Sun, 31 Dec 2023 00:00:00 GMT",
+            "Mon, 01 Jan 2024 00:00:00 GMT",
+            "0",
+            "-1",
+        ] {
+            assert_eq!(
+                lifetime(
+                    &[
+                        ("date", "Mon, 01 Jan 2024 00:00:00 GMT"),
+                        ("expires", expires)
+                    ],
+                    false
+                ),
+                Some((Duration::ZERO, FreshnessSource::Expires)),
+                "{}",
+                expires
+            );
+        }
+    }
+
+    #[test]
+    fn test_heuristic() {
+        let headers = [
+            ("date", "Mon, 01 Jan 2024 00:00:00 GMT"),
+            ("last-modified", "Fri, 22 Dec 2023 00:00:00 GMT"),
+        ];
+        // 10% of the ten days since it was last modified
+        assert_eq!(
+            lifetime(&headers, true),
+            Some((Duration::from_secs(86400), FreshnessSource::Heuristic))
+        );
+        assert_eq!(lifetime(&headers, false), None);
+        // Explicit lifetimes win, and a Last-Modified after Date is ignored
+        let expires = ("expires", "Mon, 01 Jan 2024 00:01:00 GMT");
+        assert_eq!(
+            lifetime(&[headers[0], headers[1], expires], true)
+                .unwrap()
+                .1,
+            FreshnessSource::Expires
+        );
+        let future = ("last-modified", "Tue, 02 Jan 2024 00:00:00 GMT");
+        assert_eq!(lifetime(&[headers[0], future], true), None);
+    }
+}
@@ -1,37 +1,83 @@
 use axum::{
-    Json,
+    Extension, Json, Router,
     body::Body,
     extract::{ConnectInfo, Path, Query, State},
-    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header},
+    middleware,
     response::{IntoResponse, Response},
+    routing::get,
 };
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use bytes::Bytes;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use xxhash_rust::xxh3::xxh3_64;
 
+use crate::audit::{AuditAction, AuditEntry, AuditLog, audit_middleware};
+use crate::auth::{
+    AdminAuth, PURGE_METHOD, PurgeScopeViolation, PurgeTokenClaims, PurgeTokenError,
+    mint_purge_token, purge_method_auth_middleware, unix_now,
+};
+use crate::availability::OriginAvailability;
+use crate::bandwidth::ServedFrom;
 use crate::cache::{
-    Cache, CacheEntry, CacheStats, CacheStatus, HierarchyStats, generate_cache_key_with_vary,
-    parse_cache_control,
+    Cache, CacheEntry, CacheLookup, CacheStats, CacheStatus, ContentCache, FillSlot, HierarchyStats,
+    CacheKeyBuilder, CacheKeyRecord, PinOutcome, PinnedKey, RefreshOutcome, parse_cache_control,
+    range_key, varies_on_everything,
 };
+use crate::cache_key::{canonical_key, is_variant_of, key_hash};
+use crate::cache_status::{CACHE_NAME, CacheStatusValue, ForwardReason};
+use crate::cache_tags::extract_tags;
+use crate::chaos::{FaultInjector, FaultRuleInfo, FaultRuleRequest};
 use crate::circuit_breaker::{CircuitBreakerManager, CircuitState};
-use crate::coalesce::{AcquireResult, CoalesceStats, CoalescedResponse, RequestCoalescer};
-use crate::config::Config;
-use crate::error::{CdnError, CdnResult};
-use crate::health::{HealthChecker, OriginHealth};
-use crate::metrics::Metrics;
-use crate::origin::OriginFetcher;
-use crate::range::{ByteRange, RangeParseResult, extract_range, parse_range_header};
-use crate::rate_limit::{RateLimitResult, RateLimiter};
+use crate::coalesce::{
+    AcquireResult, CoalesceStats, CoalescedError, CoalescedResponse, RequestCoalescer,
+};
+use crate::compression::SkipCompression;
+use crate::config::{
+    CacheConfig, Config, OriginConfig, ResponseHeaderMode, UnkeyedHeaderAction,
+    ValidationFailureAction, WaiterTimeoutAction,
+};
+use crate::cookies::rewrite_set_cookies;
+use crate::device::{DEVICE_TYPE_HEADER, DeviceType};
+use crate::edge::{
+    EdgeExplanation, EdgeProcessingResult, EdgeProcessor, RoutedOrigin, RoutingAction,
+    edge_client_ip,
+};
+use crate::error::{CdnError, CdnResult, get_error_pages};
+use crate::headers::{HeaderLimitOutcome, ResponseHeaders};
+use crate::health::{ClusterHealthView, HealthChecker, HealthGossip, OriginHealth};
+use crate::jobs::{Job, JobRegistry, JobStatus};
+use crate::maintenance::active_maintenance;
+use crate::metrics::{Metrics, OPENMETRICS_CONTENT_TYPE, RequestSource};
+use crate::normalize::PathNormalizer;
+use crate::origin::{OriginClient, OriginErrorKind, OriginFetcher};
+use crate::origin_registry::OriginRegistry;
+use crate::pool_sizing::OriginPoolStatus;
+use crate::purge::{DestructivePurge, PurgeDeduplicator, PurgeGuard, PurgeOperation, PurgeRefusal};
+use crate::range::{
+    ByteRange, RangeParseResult, extract_range, parse_content_range, parse_range_header,
+};
+use crate::rate_limit::{
+    QuotaStatus, RateLimitConfig, RateLimitStats, RateLimitUpdate, RateLimiter,
+};
+use crate::recent::{RecentEntry, RecentRequests, parse_window};
+use crate::request_timing::{self, Phase};
+use crate::revalidation::RevalidationLimiter;
+use crate::store_queue::StoreQueue;
+use crate::validation::VALIDATION_FAILED_HEADER;
 
 pub struct AppState {
     pub cache: Arc<Cache>,
     pub origin: Arc<OriginFetcher>,
+    /// Cache reads on the request path; `cache` outside tests
+    pub content_cache: Arc<dyn ContentCache>,
+    /// Origin fetches on the request path; `origin` outside tests
+    pub origin_client: Arc<dyn OriginClient>,
     pub config: Arc<Config>,
     pub metrics: Arc<Metrics>,
     pub rate_limiter: Arc<RateLimiter>,
@@ -39,6 +85,25 @@ pub struct AppState {
     pub health_checker: Arc<HealthChecker>,
     pub coalescer: Arc<RequestCoalescer>,
     pub coalesce_enabled: bool,
+    pub started_at: DateTime<Utc>,
+    pub jobs: Arc<JobRegistry>,
+    pub edge: Arc<EdgeProcessor>,
+    pub recent: Arc<RecentRequests>,
+    pub origins: Arc<OriginRegistry>,
+    /// Fault injection rules, shared with the origin fetcher
+    pub faults: Arc<FaultInjector>,
+    /// Mutating admin requests, recorded by the audit middleware
+    pub audit: Arc<AuditLog>,
+    /// Deduplicates and batches `POST /_cdn/purge` requests
+    pub purges: Arc<PurgeDeduplicator>,
+    /// Cooldown and permission checks for purges emptying most of the cache
+    pub purge_guard: Arc<PurgeGuard>,
+    /// Writes large responses to the cache off the request path
+    pub store_queue: Arc<StoreQueue>,
+    /// Caps background revalidations of stale and early-refreshed entries
+    pub revalidations: Arc<RevalidationLimiter>,
+    /// Set to start the graceful shutdown (signal or admin drain)
+    pub shutdown: tokio::sync::watch::Sender<bool>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -47,20 +112,68 @@ pub struct CdnQuery {
     pub params: HashMap<String, String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct HealthResponse {
     pub status: String,
     pub version: String,
 }
 
 #[derive(Debug, Serialize)]
+pub struct InfoResponse {
+    pub version: String,
+    pub git_sha: String,
+    pub rustc_version: String,
+    pub started_at: String,
+    pub uptime_secs: i64,
+    /// Process ID, to tell apart processes sharing a port during an upgrade
+    pub pid: u32,
+    /// Effective configuration with secrets redacted
+    pub config: Config,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OriginListResponse {
+    /// Effective config of each origin with secrets redacted
+    pub origins: BTreeMap<String, OriginConfig>,
+    /// Each origin's connection pool and observed concurrency, when listing
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub pools: BTreeMap<String, OriginPoolStatus>,
+}
+
+/// Origins to add, as they would be written under `[origins]`
+#[derive(Debug, Deserialize)]
+pub struct OriginAddRequest {
+    pub origins: BTreeMap<String, toml::Table>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OriginRemoveResponse {
+    pub success: bool,
+    pub message: String,
+    /// Cached responses of the origin that were purged
+    pub purged_count: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PurgeResponse {
     pub success: bool,
     pub message: String,
     pub purged_count: usize,
+    /// Keys whose in-flight origin fetch will no longer be stored
+    pub cancelled_fills: usize,
+    /// Items rejected because they fall outside the purge token's scope
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<PurgeScopeViolation>,
+    /// Answered with the result of an identical recent purge instead of running
+    #[serde(default)]
+    pub deduplicated: bool,
+    /// Requested key hashes not in the key hash index, so nothing was purged
+    /// for them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unknown_key_hashes: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct PurgeRequest {
     #[serde(default)]
     pub keys: Vec<String>,
@@ -70,6 +183,96 @@ pub struct PurgeRequest {
     pub all: bool,
     #[serde(default)]
     pub tag: Option<String>,
+    /// `X-Cache-Key-Hash` values of keys to purge, alongside `keys`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub key_hashes: Vec<String>,
+    /// Run a destructive purge even during the cooldown after another
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Cache keys to pin or unpin, by key or key prefix
+#[derive(Debug, Deserialize)]
+pub struct PinRequest {
+    #[serde(default)]
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub prefix: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PinResponse {
+    #[serde(flatten)]
+    pub outcome: PinOutcome,
+    pub pinned_size_bytes: usize,
+    pub max_pinned_bytes: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnpinResponse {
+    pub unpinned: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PinListResponse {
+    pub pins: Vec<PinnedKey>,
+    pub pinned_size_bytes: usize,
+    pub max_pinned_bytes: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MintPurgeTokenRequest {
+    /// Cache key prefixes the token may purge under
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    /// Cache tags the token may purge
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Let the token purge the whole cache
+    #[serde(default)]
+    pub purge_all: bool,
+    /// Token lifetime in seconds (default: `admin.purge_tokens.default_ttl_secs`)
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MintPurgeTokenResponse {
+    pub token: String,
+    pub expires_at: u64,
+    pub prefixes: Vec<String>,
+    pub tags: Vec<String>,
+    pub purge_all: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EdgeTestRequest {
+    #[serde(default = "default_edge_test_method")]
+    pub method: String,
+    pub path: String,
+    /// Raw query string, without the leading `?`
+    #[serde(default)]
+    pub query: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Peer address; X-Forwarded-For in `headers` takes precedence, as it does live
+    #[serde(default)]
+    pub client_ip: Option<String>,
+    /// Stand-in origin response headers for the response transforms
+    #[serde(default)]
+    pub response_headers: HashMap<String, String>,
+}
+
+fn default_edge_test_method() -> String {
+    "GET".to_string()
+}
+
+#[derive(Debug, Serialize)]
+pub struct EdgeTestResponse {
+    /// Whether edge processing runs on live traffic
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub explanation: EdgeExplanation,
 }
 
 #[derive(Debug, Serialize)]
@@ -83,9 +286,35 @@ pub struct OriginCircuitStatus {
     pub state: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct OriginHealthResponse {
     pub origins: HashMap<String, OriginHealth>,
+    /// Rolling availability of each origin, when tracking is enabled
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub availability: BTreeMap<String, OriginAvailability>,
+    /// Peer observations and merged status, when `include_peers=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<ClusterHealthView>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OriginSlaResponse {
+    pub origin: String,
+    #[serde(flatten)]
+    pub availability: OriginAvailability,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct OriginHealthQuery {
+    #[serde(default)]
+    pub include_peers: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RateLimitStatusResponse {
+    pub config: RateLimitConfig,
+    #[serde(flatten)]
+    pub stats: RateLimitStats,
 }
 
 #[derive(Debug, Serialize)]
@@ -95,13 +324,104 @@ pub struct CoalesceStatsResponse {
     pub stats: CoalesceStats,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DrainResponse {
+    /// Whether an earlier request had already started the drain
+    pub already_draining: bool,
+    /// The process that is draining
+    pub pid: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorPagesReloadResponse {
+    pub enabled: bool,
+    /// Pages loaded across the global and per-origin directories
+    pub pages_loaded: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DictionaryQuery {
+    /// Key to look up
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DictionaryResponse {
+    pub name: String,
+    pub path: String,
+    pub entries: usize,
+    /// The key asked about, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Its value; null when the dictionary doesn't have the key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FaultListResponse {
+    pub faults: Vec<FaultRuleInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FaultRemoveResponse {
+    pub removed: usize,
+}
+
 #[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    /// Number of entries to return, newest first (default: 100)
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditResponse {
+    pub entries: Vec<AuditEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct WarmCacheRequest {
     /// List of URLs to warm (relative paths like "/origin/path")
-    pub urls: Vec<String>,
+    pub urls: Vec<WarmTarget>,
 }
 
-#[derive(Debug, Serialize)]
+/// A URL to warm: a bare "/origin/path", or an object naming byte ranges of it
+///
+/// With `ranges` (e.g. `["0-2097151"]`) only those bytes are fetched, each
+/// cached as its own partial entry, instead of the whole object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum WarmTarget {
+    Url(String),
+    Ranges {
+        url: String,
+        #[serde(default)]
+        ranges: Vec<String>,
+    },
+}
+
+impl WarmTarget {
+    pub fn url(&self) -> &str {
+        match self {
+            WarmTarget::Url(url) | WarmTarget::Ranges { url, .. } => url,
+        }
+    }
+
+    pub fn ranges(&self) -> &[String] {
+        match self {
+            WarmTarget::Url(_) => &[],
+            WarmTarget::Ranges { ranges, .. } => ranges,
+        }
+    }
+}
+
+impl From<String> for WarmTarget {
+    fn from(url: String) -> Self {
+        WarmTarget::Url(url)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct WarmCacheResponse {
     pub success: bool,
     pub message: String,
@@ -110,14 +430,174 @@ pub struct WarmCacheResponse {
     pub results: Vec<WarmResult>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct WarmResult {
     pub url: String,
     pub success: bool,
     pub cached: bool,
+    /// Cache status a live request would have seen (HIT if already filled)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_status: Option<String>,
+    /// Byte range warmed, for targets that name ranges
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<String>,
     pub error: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct CacheImportQuery {
+    /// Maximum concurrent origin fetches (default: 8, max: 64)
+    pub concurrency: Option<usize>,
+}
+
+/// A line of an import file; extra export fields are ignored
+#[derive(Debug, Deserialize)]
+struct CacheImportRecord {
+    origin: String,
+    path: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheImportResponse {
+    pub job_id: String,
+    pub total: usize,
+}
+
+/// Records serialized per chunk when streaming an export
+const EXPORT_CHUNK_RECORDS: usize = 1000;
+
+/// Default and maximum concurrency for cache imports
+const DEFAULT_IMPORT_CONCURRENCY: usize = 8;
+const MAX_IMPORT_CONCURRENCY: usize = 64;
+
+#[derive(Debug, Deserialize)]
+pub struct RecentKeysQuery {
+    /// Number of keys to return (default: 100)
+    pub top: Option<usize>,
+    /// Only keys seen within this window, e.g. `30m` or `1h`
+    pub window: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecentKeysResponse {
+    pub enabled: bool,
+    pub tracked: usize,
+    pub entries: Vec<RecentEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CacheKeyQuery {
+    /// Origin name, the first path segment of a CDN URL
+    pub origin: String,
+    /// Path below the origin
+    pub path: String,
+    /// Query string, without the leading `?`
+    pub query: Option<String>,
+    /// Request headers as a JSON object, e.g. `{"Accept-Encoding":"gzip"}`
+    pub headers: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheKeyResponse {
+    /// Origin serving the request, after any edge routing
+    pub origin: String,
+    /// Path below the origin after normalization and rewrites
+    pub path: String,
+    /// Query string as it appears in the key
+    pub query: Option<String>,
+    /// Prefix of every variant's key; what a per-URL purge matches
+    pub base_key: String,
+    /// The key the request is looked up under
+    pub key: String,
+    /// `X-Cache-Key-Hash` of `key`, for purging by `key_hashes`
+    pub key_hash: String,
+    /// Whether an entry is cached under `key`
+    pub cached: bool,
+    /// Every variant of the URL currently cached
+    pub variants: Vec<CacheKeyRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SelfTestQuery {
+    /// Test only this origin (default: every configured origin)
+    pub origin: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SelfTestResponse {
+    /// Whether every stage passed for every origin tested
+    pub passed: bool,
+    pub duration_ms: f64,
+    pub origins: Vec<SelfTestOriginResult>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SelfTestOriginResult {
+    pub origin: String,
+    /// Path fetched below the origin
+    pub path: String,
+    pub passed: bool,
+    /// The first stage that failed; later stages other than cleanup are not run
+    pub failed_stage: Option<SelfTestStageName>,
+    pub stages: Vec<SelfTestStage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SelfTestStage {
+    pub stage: SelfTestStageName,
+    pub status: SelfTestStageStatus,
+    pub duration_ms: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Self-test stages, in the order a request passes through them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTestStageName {
+    /// Path normalization and edge rules
+    Edge,
+    CircuitBreaker,
+    /// Origin fetch through the request coalescer
+    Origin,
+    /// Storing the response under a throwaway key and reading it back
+    Cache,
+    /// Removing the throwaway entry
+    Cleanup,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTestStageStatus {
+    Pass,
+    Fail,
+    /// Not enabled on this node
+    Skip,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplayWarmQuery {
+    /// Number of keys to warm, busiest first (default: 1000)
+    pub top: Option<usize>,
+    /// Only keys seen within this window, e.g. `30m` or `1h`
+    pub window: Option<String>,
+    /// Maximum concurrent origin fetches (default: 8, max: 64)
+    pub concurrency: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct WarmQuery {
+    /// Warm before answering, with every result in the response; for short lists
+    #[serde(default)]
+    pub sync: bool,
+    /// Maximum concurrent origin fetches for a background warm (default: 8, max: 64)
+    pub concurrency: Option<usize>,
+}
+
+const DEFAULT_RECENT_TOP: usize = 100;
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+const DEFAULT_REPLAY_TOP: usize = 1000;
+
 // Health check endpoint
 pub async fn health() -> Json<HealthResponse> {
     Json(HealthResponse {
@@ -126,6 +606,31 @@ pub async fn health() -> Json<HealthResponse> {
     })
 }
 
+// Build info and effective configuration endpoint
+pub async fn info(State(state): State<Arc<AppState>>) -> Json<InfoResponse> {
+    let mut config = state.config.redacted();
+
+    // Rate limits can be changed at runtime, so report the ones in force
+    let rate_limit = state.rate_limiter.config();
+    config.rate_limit.enabled = rate_limit.enabled;
+    config.rate_limit.requests_per_window = rate_limit.requests_per_window;
+    config.rate_limit.window_secs = rate_limit.window_secs;
+    config.rate_limit.burst_size = rate_limit.burst_size;
+
+    // So can origins
+    config.origins = state.origins.list().into_iter().collect();
+
+    Json(InfoResponse {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_sha: env!("CDN_GIT_SHA").to_string(),
+        rustc_version: env!("CDN_RUSTC_VERSION").to_string(),
+        started_at: state.started_at.to_rfc3339(),
+        uptime_secs: (Utc::now() - state.started_at).num_seconds(),
+        pid: std::process::id(),
+        config,
+    })
+}
+
 // Cache statistics endpoint
 pub async fn cache_stats(State(state): State<Arc<AppState>>) -> Json<CacheStats> {
     Json(state.cache.stats())
@@ -136,797 +641,7501 @@ pub async fn hierarchy_stats(State(state): State<Arc<AppState>>) -> Json<Hierarc
     Json(state.cache.get_hierarchy_stats())
 }
 
-// Metrics endpoint (Prometheus format)
-pub async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let metrics = state.metrics.gather();
+// Metrics endpoint (Prometheus or OpenMetrics format)
+//
+// Exemplars only exist in the OpenMetrics format, so that is served to
+// scrapers that accept it while exemplars are enabled. The body is streamed
+// a metric family at a time rather than built as one string.
+pub async fn metrics(State(state): State<Arc<AppState>>, headers: HeaderMap) -> impl IntoResponse {
+    let wants_openmetrics = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    state
+        .metrics
+        .set_cache_content_types(&state.cache.content_type_stats());
+    state
+        .metrics
+        .set_rate_limit_tracked_clients(state.rate_limiter.tracked_clients());
+    let openmetrics = wants_openmetrics && state.config.observability.exemplars_enabled();
+    let content_type = if openmetrics {
+        OPENMETRICS_CONTENT_TYPE
+    } else {
+        "text/plain; version=0.0.4"
+    };
     (
-        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
-        metrics,
+        [(header::CONTENT_TYPE, content_type)],
+        Body::from_stream(state.metrics.gather_stream(openmetrics)),
     )
 }
 
+// Quota endpoint - the caller's own rate limit usage, reported without spending any
+pub async fn quota_status(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Json<QuotaStatus> {
+    let client = state.rate_limiter.identify(&headers, addr.ip());
+    Json(state.rate_limiter.quota(client))
+}
+
 // Cache purge endpoint
+//
+// Requests authorized by a purge token carry its claims; anything outside the
+// token's scope rejects the whole request with 403 and nothing is purged.
 pub async fn purge_cache(
     State(state): State<Arc<AppState>>,
-    Json(request): Json<PurgeRequest>,
-) -> Json<PurgeResponse> {
-    let purged_count = if request.all {
-        state.cache.purge_all()
-    } else if let Some(tag) = request.tag {
-        state.cache.invalidate_by_tag(&tag)
-    } else if let Some(prefix) = request.prefix {
-        state.cache.invalidate_prefix(&prefix)
-    } else {
-        let mut count = 0;
-        for key in &request.keys {
-            if state.cache.invalidate(key) {
-                count += 1;
+    claims: Option<Extension<PurgeTokenClaims>>,
+    Json(mut request): Json<PurgeRequest>,
+) -> Response {
+    let mut unknown_key_hashes = Vec::new();
+    if !request.key_hashes.is_empty() {
+        if !state.cache.key_hash_index_enabled() {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(PurgeResponse {
+                    success: false,
+                    message: "Purging by key_hashes needs cache.key_hash_index.enabled".to_string(),
+                    purged_count: 0,
+                    cancelled_fills: 0,
+                    errors: Vec::new(),
+                    deduplicated: false,
+                    unknown_key_hashes: Vec::new(),
+                }),
+            )
+                .into_response();
+        }
+        // Resolved before the scope check, so a token can't reach keys by hash
+        // that it couldn't name
+        for hash in std::mem::take(&mut request.key_hashes) {
+            match state.cache.key_for_hash(&hash) {
+                Some(key) => request.keys.push(key),
+                None => unknown_key_hashes.push(hash),
+            }
+        }
+    }
+
+    if let Some(Extension(claims)) = &claims {
+        let errors = claims.violations(
+            &request.keys,
+            request.prefix.as_deref(),
+            request.tag.as_deref(),
+            request.all,
+        );
+        if !errors.is_empty() {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(PurgeResponse {
+                    success: false,
+                    message: format!("{} item(s) outside the purge token's scope", errors.len()),
+                    purged_count: 0,
+                    cancelled_fills: 0,
+                    errors,
+                    deduplicated: false,
+                    unknown_key_hashes: Vec::new(),
+                }),
+            )
+                .into_response();
+        }
+    }
+
+    let operation = PurgeOperation::from_request(&request);
+    let purge_all_token = claims.is_some_and(|Extension(claims)| claims.purge_all);
+    let admitted =
+        match state
+            .purge_guard
+            .admit(&state.cache, &operation, request.force, purge_all_token)
+        {
+            Ok(admitted) => admitted,
+            Err(refusal) => return purge_refused(refusal),
+        };
+
+    let outcome = state.purges.purge(&state.cache, operation).await;
+
+    let mut response = (
+        StatusCode::OK,
+        Json(PurgeResponse {
+            success: true,
+            message: purge_message(outcome.purged_count, outcome.cancelled_fills),
+            purged_count: outcome.purged_count,
+            cancelled_fills: outcome.cancelled_fills,
+            errors: Vec::new(),
+            deduplicated: outcome.deduplicated,
+            unknown_key_hashes,
+        }),
+    )
+        .into_response();
+    if let Some(admitted) = admitted {
+        let purge = match admitted.purge {
+            DestructivePurge::All => "purge_all".to_string(),
+            DestructivePurge::Prefix { matched, total } => {
+                format!("prefix purge matching {} of {} entries", matched, total)
             }
+        };
+        let forced = if admitted.forced {
+            " (forced during cooldown)"
+        } else {
+            ""
+        };
+        response
+            .extensions_mut()
+            .insert(AuditAction(format!("{}{}", purge, forced)));
+    }
+    response
+}
+
+/// 403 or 429 for a destructive purge the guard refused
+fn purge_refused(refusal: PurgeRefusal) -> Response {
+    // Rounded up, so a retry right on time isn't refused again
+    let retry_after = match refusal {
+        PurgeRefusal::CoolingDown(remaining) => {
+            Some(remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0))
         }
-        count
+        PurgeRefusal::Forbidden => None,
     };
+    let (status, message) = match retry_after {
+        None => (
+            StatusCode::FORBIDDEN,
+            "Purging the whole cache needs a purge token minted with purge_all".to_string(),
+        ),
+        Some(secs) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            format!(
+                "A destructive purge ran recently; retry in {}s or resend with \"force\": true",
+                secs
+            ),
+        ),
+    };
+    let mut response = (
+        status,
+        Json(PurgeResponse {
+            success: false,
+            message,
+            purged_count: 0,
+            cancelled_fills: 0,
+            errors: Vec::new(),
+            deduplicated: false,
+            unknown_key_hashes: Vec::new(),
+        }),
+    )
+        .into_response();
+    if let Some(secs) = retry_after {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    }
+    response
+}
 
-    Json(PurgeResponse {
-        success: true,
-        message: format!("Purged {} cache entries", purged_count),
-        purged_count,
-    })
+fn purge_message(purged_count: usize, cancelled_fills: usize) -> String {
+    if cancelled_fills == 0 {
+        format!("Purged {} cache entries", purged_count)
+    } else {
+        format!(
+            "Purged {} cache entries and cancelled {} in-flight fills",
+            purged_count, cancelled_fills
+        )
+    }
 }
 
-// Circuit breaker status endpoint
-pub async fn circuit_breaker_status(
+// Purge token endpoint - mint a scoped token for a third party
+pub async fn mint_purge_token_handler(
     State(state): State<Arc<AppState>>,
-) -> Json<CircuitBreakerStatusResponse> {
-    let origins = state
-        .circuit_breaker
-        .all_states()
-        .into_iter()
-        .map(|(origin, state)| OriginCircuitStatus {
-            origin,
-            state: match state {
-                CircuitState::Closed => "closed".to_string(),
-                CircuitState::Open => "open".to_string(),
-                CircuitState::HalfOpen => "half-open".to_string(),
-            },
-        })
-        .collect();
+    Json(request): Json<MintPurgeTokenRequest>,
+) -> CdnResult<Json<MintPurgeTokenResponse>> {
+    let (token, claims) = mint_purge_token(
+        &state.config.admin,
+        request.prefixes,
+        request.tags,
+        request.purge_all,
+        request.ttl_secs,
+        unix_now(),
+    )
+    .map_err(|e| match e {
+        PurgeTokenError::EmptyScope => CdnError::InvalidRequest(e.to_string()),
+        _ => CdnError::ConfigError(e.to_string()),
+    })?;
 
-    Json(CircuitBreakerStatusResponse { origins })
+    Ok(Json(MintPurgeTokenResponse {
+        token,
+        expires_at: claims.expires_at,
+        prefixes: claims.prefixes,
+        tags: claims.tags,
+        purge_all: claims.purge_all,
+    }))
 }
 
-// Origin health status endpoint
-pub async fn origin_health_status(
+// Edge rule test endpoint - explain what edge processing does to a request
+pub async fn test_edge_rules(
     State(state): State<Arc<AppState>>,
-) -> Json<OriginHealthResponse> {
-    let origins = state.health_checker.get_all_statuses();
-    Json(OriginHealthResponse { origins })
+    Json(request): Json<EdgeTestRequest>,
+) -> CdnResult<Json<EdgeTestResponse>> {
+    let method = Method::from_bytes(request.method.to_ascii_uppercase().as_bytes())
+        .map_err(|_| CdnError::InvalidRequest(format!("Invalid method: {}", request.method)))?;
+    if !request.path.starts_with('/') {
+        return Err(CdnError::InvalidRequest(
+            "Path must start with '/'".to_string(),
+        ));
+    }
+    if let Some(ip) = &request.client_ip
+        && ip.parse::<std::net::IpAddr>().is_err()
+    {
+        return Err(CdnError::InvalidRequest(format!(
+            "Invalid client IP: {}",
+            ip
+        )));
+    }
+    let headers = edge_test_headers(&request.headers)?;
+    let response_headers = edge_test_headers(&request.response_headers)?;
+
+    let client_ip = edge_client_ip(&headers, request.client_ip.clone());
+    let explanation = state.edge.explain(
+        &request.path,
+        request.query.as_deref().filter(|q| !q.is_empty()),
+        &method,
+        &headers,
+        client_ip.as_deref(),
+        &response_headers,
+    );
+
+    Ok(Json(EdgeTestResponse {
+        enabled: state.config.edge.enabled,
+        explanation,
+    }))
 }
 
-// Coalesce statistics endpoint
-pub async fn coalesce_stats(State(state): State<Arc<AppState>>) -> Json<CoalesceStatsResponse> {
-    Json(CoalesceStatsResponse {
-        enabled: state.coalesce_enabled,
-        stats: state.coalescer.stats(),
-    })
+fn edge_test_headers(headers: &HashMap<String, String>) -> CdnResult<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| CdnError::InvalidRequest(format!("Invalid header name: {}", name)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| CdnError::InvalidRequest(format!("Invalid value for header {}", name)))?;
+        map.insert(name, value);
+    }
+    Ok(map)
 }
 
-// Cache warming endpoint - preload content into cache
-pub async fn warm_cache(
+// Edge dictionary endpoint - entry count and, with ?key=, a single value
+pub async fn dictionary_lookup(
     State(state): State<Arc<AppState>>,
-    Json(request): Json<WarmCacheRequest>,
-) -> Json<WarmCacheResponse> {
-    let mut results = Vec::with_capacity(request.urls.len());
-    let mut warmed = 0;
-    let mut failed = 0;
-
-    for url in &request.urls {
-        // Parse URL to extract origin and path
-        // Expected format: "/origin/path" or "origin/path"
-        let url = url.trim_start_matches('/');
-        let parts: Vec<&str> = url.splitn(2, '/').collect();
-
-        if parts.is_empty() {
-            results.push(WarmResult {
-                url: url.to_string(),
-                success: false,
-                cached: false,
-                error: Some("Invalid URL format".to_string()),
-            });
-            failed += 1;
+    Path(name): Path<String>,
+    Query(query): Query<DictionaryQuery>,
+) -> CdnResult<Json<DictionaryResponse>> {
+    let dictionary = state
+        .edge
+        .dictionaries()
+        .get(&name)
+        .ok_or_else(|| CdnError::NotFound(format!("Unknown dictionary: {}", name)))?;
+    let value = query.key.as_deref().and_then(|key| dictionary.get(key));
+    Ok(Json(DictionaryResponse {
+        name,
+        path: dictionary.path().to_string(),
+        entries: dictionary.len(),
+        key: query.key,
+        value,
+    }))
+}
+
+// Edge dictionary reload endpoint - re-read one dictionary from disk
+pub async fn reload_dictionary(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> CdnResult<Json<DictionaryResponse>> {
+    let dictionary = state
+        .edge
+        .dictionaries()
+        .get(&name)
+        .ok_or_else(|| CdnError::NotFound(format!("Unknown dictionary: {}", name)))?;
+    let entries = dictionary.reload()?;
+    Ok(Json(DictionaryResponse {
+        name,
+        path: dictionary.path().to_string(),
+        entries,
+        key: None,
+        value: None,
+    }))
+}
+
+/// The fault injector, or a 404 unless `chaos.enabled` is set
+fn fault_injector(state: &AppState) -> CdnResult<&FaultInjector> {
+    if state.faults.is_enabled() {
+        Ok(&state.faults)
+    } else {
+        Err(CdnError::NotFound(
+            "Fault injection is disabled (chaos.enabled)".to_string(),
+        ))
+    }
+}
+
+// Fault injection list endpoint - active rules, expired ones dropped
+pub async fn list_faults(State(state): State<Arc<AppState>>) -> CdnResult<Json<FaultListResponse>> {
+    let faults = fault_injector(&state)?.list();
+    Ok(Json(FaultListResponse { faults }))
+}
+
+// Fault injection endpoint - add a rule that affects matching origin fetches
+pub async fn add_fault(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<FaultRuleRequest>,
+) -> CdnResult<Json<FaultRuleInfo>> {
+    let rule = fault_injector(&state)?.add(request)?;
+    Ok(Json(rule))
+}
+
+// Fault injection removal endpoint - drop one rule before it expires
+pub async fn remove_fault(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> CdnResult<Json<FaultRemoveResponse>> {
+    if !fault_injector(&state)?.remove(&id) {
+        return Err(CdnError::NotFound(format!("Unknown fault rule: {}", id)));
+    }
+    Ok(Json(FaultRemoveResponse { removed: 1 }))
+}
+
+// Fault injection reset endpoint - drop every rule
+pub async fn clear_faults(
+    State(state): State<Arc<AppState>>,
+) -> CdnResult<Json<FaultRemoveResponse>> {
+    let removed = fault_injector(&state)?.clear();
+    Ok(Json(FaultRemoveResponse { removed }))
+}
+
+// Audit log endpoint - recent mutating admin requests
+pub async fn audit_log(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AuditQuery>,
+) -> Json<AuditResponse> {
+    Json(AuditResponse {
+        entries: state
+            .audit
+            .recent(params.limit.unwrap_or(DEFAULT_AUDIT_LIMIT)),
+    })
+}
+
+// Rate limiter status endpoint - active limits and live stats
+pub async fn rate_limit_status(
+    State(state): State<Arc<AppState>>,
+) -> Json<RateLimitStatusResponse> {
+    Json(RateLimitStatusResponse {
+        config: state.rate_limiter.config(),
+        stats: state.rate_limiter.stats(),
+    })
+}
+
+// Rate limiter update endpoint - change limits without a restart
+pub async fn update_rate_limit(
+    State(state): State<Arc<AppState>>,
+    Json(update): Json<RateLimitUpdate>,
+) -> CdnResult<Json<RateLimitStatusResponse>> {
+    let config = state
+        .rate_limiter
+        .update(update)
+        .map_err(CdnError::InvalidRequest)?;
+
+    Ok(Json(RateLimitStatusResponse {
+        config,
+        stats: state.rate_limiter.stats(),
+    }))
+}
+
+// Origin list endpoint - effective config of every live origin
+pub async fn list_origins(State(state): State<Arc<AppState>>) -> Json<OriginListResponse> {
+    Json(OriginListResponse {
+        origins: state.origins.list(),
+        pools: state.origin.pool_status(),
+    })
+}
+
+// Origin add endpoint - register new origins without a restart
+pub async fn add_origins(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<OriginAddRequest>,
+) -> CdnResult<(StatusCode, Json<OriginListResponse>)> {
+    let origins = state.origins.add(request.origins)?;
+    Ok((
+        StatusCode::CREATED,
+        Json(OriginListResponse {
+            origins,
+            pools: BTreeMap::new(),
+        }),
+    ))
+}
+
+// Origin update endpoint - replace one origin's config
+pub async fn update_origin(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(origin): Json<toml::Table>,
+) -> CdnResult<Json<OriginListResponse>> {
+    let origin = state.origins.update(&name, origin)?;
+    Ok(Json(OriginListResponse {
+        origins: BTreeMap::from([(name, origin)]),
+        pools: BTreeMap::new(),
+    }))
+}
+
+// Origin removal endpoint - in-flight requests finish, new ones get a 404
+pub async fn remove_origin(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> CdnResult<Json<OriginRemoveResponse>> {
+    let purged_count = state.origins.remove(&name)?;
+    Ok(Json(OriginRemoveResponse {
+        success: true,
+        message: format!("Removed origin {}", name),
+        purged_count,
+    }))
+}
+
+// Circuit breaker status endpoint
+pub async fn circuit_breaker_status(
+    State(state): State<Arc<AppState>>,
+) -> Json<CircuitBreakerStatusResponse> {
+    let origins = state
+        .circuit_breaker
+        .all_states()
+        .into_iter()
+        .map(|(origin, state)| OriginCircuitStatus {
+            origin,
+            state: match state {
+                CircuitState::Closed => "closed".to_string(),
+                CircuitState::Open => "open".to_string(),
+                CircuitState::HalfOpen => "half-open".to_string(),
+            },
+        })
+        .collect();
+
+    Json(CircuitBreakerStatusResponse { origins })
+}
+
+// Origin health status endpoint
+pub async fn origin_health_status(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<OriginHealthQuery>,
+) -> Json<OriginHealthResponse> {
+    let origins = state.health_checker.get_all_statuses();
+    let availability = state.health_checker.availability().all_summaries();
+    let cluster = query
+        .include_peers
+        .then(|| state.health_checker.cluster_view());
+    Json(OriginHealthResponse {
+        origins,
+        availability,
+        cluster,
+    })
+}
+
+// Origin SLA endpoint - rolling availability and failure streak of one origin
+pub async fn origin_sla(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> CdnResult<Json<OriginSlaResponse>> {
+    if !state.origin.has_origin(&name) {
+        return Err(CdnError::NotFound(format!("Unknown origin: {}", name)));
+    }
+    let availability = state.health_checker.availability();
+    let summary = availability
+        .summary(&name)
+        .ok_or_else(|| CdnError::NotFound("Availability tracking is disabled".to_string()))?;
+    Ok(Json(OriginSlaResponse {
+        origin: name,
+        availability: summary,
+    }))
+}
+
+// Cluster health gossip endpoint - a peer's origin health snapshot
+pub async fn receive_health_gossip(
+    State(state): State<Arc<AppState>>,
+    Json(gossip): Json<HealthGossip>,
+) -> StatusCode {
+    if !state.config.cluster.health_gossip.enabled {
+        return StatusCode::NOT_FOUND;
+    }
+    state.health_checker.merge_peer_snapshot(gossip);
+    StatusCode::NO_CONTENT
+}
+
+// Coalesce statistics endpoint
+pub async fn coalesce_stats(State(state): State<Arc<AppState>>) -> Json<CoalesceStatsResponse> {
+    Json(CoalesceStatsResponse {
+        enabled: state.coalesce_enabled,
+        stats: state.coalescer.stats(),
+    })
+}
+
+/// Stop accepting connections and exit once in-flight requests finish
+///
+/// The same drain as SIGTERM. With `server.reuse_port`, a new process already
+/// listening on the same ports takes over new connections.
+pub async fn drain(State(state): State<Arc<AppState>>) -> (StatusCode, Json<DrainResponse>) {
+    let already_draining = state.shutdown.send_replace(true);
+    let pid = std::process::id();
+    if !already_draining {
+        tracing::info!(pid, "Drain requested through the admin API");
+    }
+    (
+        StatusCode::ACCEPTED,
+        Json(DrainResponse {
+            already_draining,
+            pid,
+        }),
+    )
+}
+
+// Error page reload endpoint - re-read templates from disk
+pub async fn reload_error_pages() -> Json<ErrorPagesReloadResponse> {
+    let (enabled, pages_loaded) = match get_error_pages() {
+        Some(pages) => (pages.is_enabled(), pages.reload()),
+        None => (false, 0),
+    };
+
+    Json(ErrorPagesReloadResponse {
+        enabled,
+        pages_loaded,
+    })
+}
+
+// Cache warming endpoint - preload content into cache in a background job,
+// or inline with `?sync=true`
+pub async fn warm_cache(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<WarmQuery>,
+    Json(request): Json<WarmCacheRequest>,
+) -> Response {
+    if params.sync {
+        return Json(warm_urls(&state, &request.urls).await).into_response();
+    }
+
+    let concurrency = params
+        .concurrency
+        .unwrap_or(DEFAULT_IMPORT_CONCURRENCY)
+        .clamp(1, MAX_IMPORT_CONCURRENCY);
+    let normalizer = Arc::new(PathNormalizer::new(state.config.path_normalization.clone()));
+    let response = spawn_warm_job(
+        state,
+        "warm",
+        request.urls,
+        concurrency,
+        move |state, target| {
+            let normalizer = normalizer.clone();
+            async move { warm_target(&state, &normalizer, &target).await }
+        },
+    );
+    (StatusCode::ACCEPTED, Json(response)).into_response()
+}
+
+/// Warm `targets` one after another, answering with every result
+async fn warm_urls(state: &Arc<AppState>, targets: &[WarmTarget]) -> WarmCacheResponse {
+    let normalizer = PathNormalizer::new(state.config.path_normalization.clone());
+    let mut results = Vec::with_capacity(targets.len());
+    for target in targets {
+        results.extend(warm_target(state, &normalizer, target).await);
+    }
+
+    let warmed = results.iter().filter(|result| result.success).count();
+    let failed = results.len() - warmed;
+    WarmCacheResponse {
+        success: failed == 0,
+        message: format!("Warmed {} URLs, {} failed", warmed, failed),
+        warmed,
+        failed,
+        results,
+    }
+}
+
+/// Warm one requested URL, or each of the byte ranges it names
+async fn warm_target(
+    state: &Arc<AppState>,
+    normalizer: &PathNormalizer,
+    target: &WarmTarget,
+) -> Vec<WarmResult> {
+    let invalid = |url: &str, error: &str| {
+        vec![WarmResult {
+            url: url.to_string(),
+            success: false,
+            cached: false,
+            cache_status: None,
+            range: None,
+            error: Some(error.to_string()),
+        }]
+    };
+
+    // Parse URL to extract origin and path
+    // Expected format: "/origin/path" or "origin/path"
+    // Normalized the same way as live requests so warmed keys match
+    let url = target.url();
+    let normalized = normalizer.normalize(&format!("/{}", url.trim_start_matches('/')));
+    let url = normalized.trim_start_matches('/');
+    let parts: Vec<&str> = url.splitn(2, '/').collect();
+
+    if parts.is_empty() {
+        return invalid(url, "Invalid URL format");
+    }
+
+    let origins = state.origin.origin_names();
+    let (origin, path) = if parts.len() == 2 {
+        (parts[0], parts[1])
+    } else {
+        // Try default origin if only one configured
+        if origins.len() == 1 {
+            (origins[0].as_str(), parts[0])
+        } else {
+            return invalid(url, "Origin must be specified: /origin/path");
+        }
+    };
+
+    let path = format!("/{}", path);
+    if target.ranges().is_empty() {
+        return vec![warm_one(state, url, origin, &path, None).await];
+    }
+    let mut results = Vec::with_capacity(target.ranges().len());
+    for range in target.ranges() {
+        results.push(warm_range(state, url, origin, &path, range).await);
+    }
+    results
+}
+
+// Cache inventory export endpoint - streams newline-delimited JSON
+pub async fn export_cache(State(state): State<Arc<AppState>>) -> CdnResult<Response> {
+    let (tx, rx) = tokio::sync::mpsc::channel::<Bytes>(4);
+    let cache = state.cache.clone();
+
+    tokio::spawn(async move {
+        let partitions = cache.export_partition_count();
+        for partition in 0..partitions {
+            let cache = cache.clone();
+            let records = match tokio::task::spawn_blocking(move || {
+                cache.export_partition(partition, partitions)
+            })
+            .await
+            {
+                Ok(records) => records,
+                Err(e) => {
+                    tracing::error!(error = %e, "Cache export partition failed");
+                    return;
+                }
+            };
+
+            for chunk in records.chunks(EXPORT_CHUNK_RECORDS) {
+                let mut buf = Vec::with_capacity(chunk.len() * 128);
+                for record in chunk {
+                    if serde_json::to_writer(&mut buf, record).is_ok() {
+                        buf.push(b'\n');
+                    }
+                }
+                if tx.send(Bytes::from(buf)).await.is_err() {
+                    // Client went away
+                    return;
+                }
+            }
+        }
+    });
+
+    let stream = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|chunk| (Ok::<_, std::convert::Infallible>(chunk), rx))
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::from_stream(stream))
+        .map_err(|e| CdnError::Internal(format!("Failed to build response: {}", e)))
+}
+
+// Cache inventory import endpoint - warms exported keys in a background job
+pub async fn import_cache(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CacheImportQuery>,
+    body: String,
+) -> CdnResult<(StatusCode, Json<CacheImportResponse>)> {
+    let mut seen = HashSet::new();
+    let mut targets = Vec::new();
+
+    for (line_no, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
             continue;
         }
 
-        let (origin, path) = if parts.len() == 2 {
-            (parts[0], parts[1])
-        } else {
-            // Try default origin if only one configured
-            let origins = state.origin.origin_names();
-            if origins.len() == 1 {
-                (origins[0], parts[0])
+        let record: CacheImportRecord = serde_json::from_str(line).map_err(|e| {
+            CdnError::InvalidRequest(format!("Invalid record on line {}: {}", line_no + 1, e))
+        })?;
+
+        // Several variants of one URL export as separate keys; warm each URL once
+        if seen.insert((record.origin.clone(), record.path.clone())) {
+            targets.push(record);
+        }
+    }
+
+    let concurrency = params
+        .concurrency
+        .unwrap_or(DEFAULT_IMPORT_CONCURRENCY)
+        .clamp(1, MAX_IMPORT_CONCURRENCY);
+
+    let response = spawn_warm_job(
+        state,
+        "cache-import",
+        targets,
+        concurrency,
+        |state, record| async move { vec![warm_record(&state, &record).await] },
+    );
+    Ok((StatusCode::ACCEPTED, Json(response)))
+}
+
+// Recently served keys, busiest first
+pub async fn recent_cache_keys(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<RecentKeysQuery>,
+) -> CdnResult<Json<RecentKeysResponse>> {
+    let window = params
+        .window
+        .as_deref()
+        .map(parse_query_window)
+        .transpose()?;
+    Ok(Json(RecentKeysResponse {
+        enabled: state.recent.is_enabled(),
+        tracked: state.recent.len(),
+        entries: state
+            .recent
+            .top(params.top.unwrap_or(DEFAULT_RECENT_TOP), window),
+    }))
+}
+
+// Cache pin endpoint - keep entries through eviction and memory pressure
+pub async fn pin_cache_entries(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PinRequest>,
+) -> CdnResult<Json<PinResponse>> {
+    if request.keys.is_empty() && request.prefix.is_none() {
+        return Err(CdnError::InvalidRequest(
+            "Pin request needs keys or a prefix".to_string(),
+        ));
+    }
+    let mut outcome = state
+        .cache
+        .pin(request.keys.iter().map(|key| canonical_key(key)));
+    if let Some(prefix) = request.prefix {
+        let by_prefix = state.cache.pin_prefix(&prefix);
+        outcome.pinned.extend(by_prefix.pinned);
+        outcome.not_cached.extend(by_prefix.not_cached);
+        outcome.over_limit.extend(by_prefix.over_limit);
+    }
+    Ok(Json(PinResponse {
+        outcome,
+        pinned_size_bytes: state.cache.stats().pinned_size_bytes,
+        max_pinned_bytes: state.cache.max_pinned_bytes(),
+    }))
+}
+
+// Cache unpin endpoint - let pinned entries be evicted again
+pub async fn unpin_cache_entries(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PinRequest>,
+) -> Json<UnpinResponse> {
+    let keys: Vec<String> = request.keys.iter().map(|key| canonical_key(key)).collect();
+    let mut unpinned = state.cache.unpin(&keys);
+    if let Some(prefix) = request.prefix {
+        unpinned += state.cache.unpin_prefix(&prefix);
+    }
+    Json(UnpinResponse { unpinned })
+}
+
+// Cache pin list endpoint - pinned keys and what's cached under them
+pub async fn list_cache_pins(State(state): State<Arc<AppState>>) -> Json<PinListResponse> {
+    let pins = state.cache.pins();
+    Json(PinListResponse {
+        pinned_size_bytes: pins.iter().filter_map(|pin| pin.size_bytes).sum(),
+        pins,
+        max_pinned_bytes: state.cache.max_pinned_bytes(),
+    })
+}
+
+// Cache key endpoint - the key a CDN request maps to and the variants cached for it
+//
+// The request goes through the same path normalization, edge processing and
+// key builder as live traffic, so external tooling can compute keys
+// identically.
+pub async fn cache_key_lookup(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<CacheKeyQuery>,
+) -> CdnResult<Json<CacheKeyResponse>> {
+    let headers: HashMap<String, String> = match params.headers.as_deref() {
+        Some(json) => serde_json::from_str(json).map_err(|e| {
+            CdnError::InvalidRequest(format!("headers must be a JSON object of strings: {}", e))
+        })?,
+        None => HashMap::new(),
+    };
+    let routed = SyntheticRequest::get(format!(
+        "/{}/{}",
+        params.origin,
+        params.path.trim_start_matches('/')
+    ))
+    .query(params.query)
+    .headers(edge_test_headers(&headers)?)
+    .route(&state)?;
+    let RoutedRequest {
+        origin,
+        path,
+        query,
+        mut headers,
+    } = routed;
+    if !state.origin.has_origin(&origin) {
+        return Err(CdnError::NotFound(format!("Unknown origin: {}", origin)));
+    }
+
+    // Parsed and re-joined like the query a CDN request is keyed on
+    let query_string = cdn_query_string(&CdnQuery {
+        params: url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .into_owned()
+            .collect(),
+    });
+    if state.config.device_detection.enabled {
+        let device = DeviceType::from_headers(&headers);
+        headers.insert(
+            DEVICE_TYPE_HEADER,
+            HeaderValue::from_static(device.as_str()),
+        );
+    }
+    let request_headers_map = extract_request_headers(&headers);
+
+    let keys = CacheKeyBuilder::new(&origin, &path, &state.config.cache.key)
+        .query(query_string.as_deref())
+        .request_headers(&request_headers_map);
+    let base_key = keys.base_key();
+    let key = keys.lookup_key();
+    let variants = state.cache.variants(&base_key);
+    Ok(Json(CacheKeyResponse {
+        cached: variants.iter().any(|record| record.key == key),
+        key_hash: key_hash(&key),
+        origin,
+        path: format!("/{}", path),
+        query: query_string,
+        base_key,
+        key,
+        variants,
+    }))
+}
+
+// Self-test endpoint - a real fetch through every stage of the request path, per origin
+//
+// Answers 503 when any stage fails for any origin, so a deploy pipeline can
+// gate on the status code alone.
+pub async fn self_test(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<SelfTestQuery>,
+) -> CdnResult<(StatusCode, Json<SelfTestResponse>)> {
+    let origins = match params.origin {
+        Some(origin) if !state.origin.has_origin(&origin) => {
+            return Err(CdnError::NotFound(format!("Unknown origin: {}", origin)));
+        }
+        Some(origin) => vec![origin],
+        None => state.origin.origin_names(),
+    };
+
+    let start = Instant::now();
+    let results = futures::future::join_all(
+        origins
+            .iter()
+            .map(|origin| self_test_origin(&state, origin)),
+    )
+    .await;
+    // A node without origins can't serve anything
+    let passed = !results.is_empty() && results.iter().all(|result| result.passed);
+    if !passed {
+        tracing::warn!(
+            failed = results.iter().filter(|result| !result.passed).count(),
+            "Self-test failed"
+        );
+    }
+
+    let status = if passed {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok((
+        status,
+        Json(SelfTestResponse {
+            passed,
+            duration_ms: elapsed_ms(start),
+            origins: results,
+        }),
+    ))
+}
+
+/// Self-test cache entries are keyed apart from anything a request can map to
+const SELFTEST_KEY_PREFIX: &str = "selftest:";
+
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+/// Stages recorded so far for one origin's self-test
+#[derive(Default)]
+struct SelfTestRun {
+    stages: Vec<SelfTestStage>,
+}
+
+impl SelfTestRun {
+    fn record(
+        &mut self,
+        stage: SelfTestStageName,
+        status: SelfTestStageStatus,
+        started: Instant,
+        message: Option<String>,
+    ) {
+        self.stages.push(SelfTestStage {
+            stage,
+            status,
+            duration_ms: elapsed_ms(started),
+            message,
+        });
+    }
+
+    fn pass(&mut self, stage: SelfTestStageName, started: Instant, message: Option<String>) {
+        self.record(stage, SelfTestStageStatus::Pass, started, message);
+    }
+
+    fn fail(&mut self, stage: SelfTestStageName, started: Instant, message: String) {
+        self.record(stage, SelfTestStageStatus::Fail, started, Some(message));
+    }
+
+    fn finish(self, origin: &str, path: &str) -> SelfTestOriginResult {
+        let failed_stage = self
+            .stages
+            .iter()
+            .find(|stage| stage.status == SelfTestStageStatus::Fail)
+            .map(|stage| stage.stage);
+        SelfTestOriginResult {
+            origin: origin.to_string(),
+            path: path.to_string(),
+            passed: failed_stage.is_none(),
+            failed_stage,
+            stages: self.stages,
+        }
+    }
+}
+
+/// Fetch an origin's test path through edge rules, the circuit breaker, the
+/// coalescer and the cache, stopping at the first stage that fails
+///
+/// The response is cached under a throwaway key, read back and removed, so
+/// live entries are neither served nor replaced.
+async fn self_test_origin(state: &Arc<AppState>, origin: &str) -> SelfTestOriginResult {
+    let target = state
+        .origin
+        .origin_config(origin)
+        .map(|config| config.selftest_target().to_string())
+        .unwrap_or_else(|| "/".to_string());
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target.as_str(), None),
+    };
+    let mut run = SelfTestRun::default();
+
+    // Edge rules see the path as a client would send it, origin segment included
+    let started = Instant::now();
+    let request =
+        SyntheticRequest::get(format!("/{}/{}", origin, path.trim_start_matches('/'))).query(query);
+    let routed = match request.route(state) {
+        Ok(routed) => routed,
+        Err(e) => {
+            run.fail(SelfTestStageName::Edge, started, e.to_string());
+            return run.finish(origin, path);
+        }
+    };
+    if !state.origin.has_origin(&routed.origin) {
+        run.fail(
+            SelfTestStageName::Edge,
+            started,
+            format!("Routed to unknown origin {}", routed.origin),
+        );
+        return run.finish(origin, path);
+    }
+    let edge_status = if state.config.edge.enabled {
+        SelfTestStageStatus::Pass
+    } else {
+        SelfTestStageStatus::Skip
+    };
+    let routed_note = (routed.origin != origin).then(|| format!("Routed to {}", routed.origin));
+    run.record(SelfTestStageName::Edge, edge_status, started, routed_note);
+
+    let started = Instant::now();
+    if !state.circuit_breaker.should_allow(&routed.origin) {
+        run.fail(
+            SelfTestStageName::CircuitBreaker,
+            started,
+            format!("Origin {} circuit breaker is open", routed.origin),
+        );
+        return run.finish(origin, path);
+    }
+    run.pass(SelfTestStageName::CircuitBreaker, started, None);
+
+    // A fresh key never joins, or is joined by, a live in-flight fetch
+    let cache_key = format!(
+        "{}{}:{}",
+        SELFTEST_KEY_PREFIX,
+        routed.origin,
+        uuid::Uuid::new_v4()
+    );
+    let started = Instant::now();
+    let (result, _) = fetch_from_origin_coalesced(
+        state,
+        &cache_key,
+        &routed.origin,
+        &routed.path,
+        routed.query.as_deref(),
+        &routed.headers,
+        &HashMap::new(),
+        RequestSource::SelfTest,
+    )
+    .await;
+    let (body, mut headers, status) = match result {
+        Ok((_, _, status)) if status.is_client_error() || status.is_server_error() => {
+            run.fail(
+                SelfTestStageName::Origin,
+                started,
+                format!("Origin answered {}", status),
+            );
+            return run.finish(origin, path);
+        }
+        Ok(response) => response,
+        Err(e) => {
+            run.fail(SelfTestStageName::Origin, started, e.to_string());
+            return run.finish(origin, path);
+        }
+    };
+    run.pass(
+        SelfTestStageName::Origin,
+        started,
+        Some(format!("{} ({} bytes)", status, body.len())),
+    );
+
+    // Stored whatever the origin's caching headers say, so the cache is always exercised
+    let started = Instant::now();
+    if state.cache.stores_paused() {
+        run.fail(
+            SelfTestStageName::Cache,
+            started,
+            "Cache stores are paused by the memory watchdog".to_string(),
+        );
+        return run.finish(origin, path);
+    }
+    headers.remove("cache-control");
+    for tag_header in &state.config.cache.tags.headers {
+        headers.remove(&tag_header.name);
+    }
+    store_in_cache(
+        state,
+        &routed.origin,
+        state.cache.reserve(&cache_key),
+        body.clone(),
+        headers,
+        status,
+    );
+    state.store_queue.flush().await;
+    match state.cache.get(&cache_key) {
+        Some((entry, _)) if entry.body == body => {
+            run.pass(SelfTestStageName::Cache, started, None);
+        }
+        Some(_) => run.fail(
+            SelfTestStageName::Cache,
+            started,
+            "Cached body differs from the origin response".to_string(),
+        ),
+        None => run.fail(
+            SelfTestStageName::Cache,
+            started,
+            "Response was not stored".to_string(),
+        ),
+    }
+
+    // Runs even after a cache failure, in case something was stored
+    let started = Instant::now();
+    state.cache.invalidate(&cache_key);
+    if state.cache.get(&cache_key).is_some() {
+        run.fail(
+            SelfTestStageName::Cleanup,
+            started,
+            "Test entry is still cached".to_string(),
+        );
+    } else {
+        run.pass(SelfTestStageName::Cleanup, started, None);
+    }
+
+    run.finish(origin, path)
+}
+
+// Replay recently served keys into cache warming as a background job
+pub async fn replay_warm(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ReplayWarmQuery>,
+) -> CdnResult<(StatusCode, Json<CacheImportResponse>)> {
+    if !state.recent.is_enabled() {
+        return Err(CdnError::InvalidRequest(
+            "Recent key recording is disabled (cache.recent.enabled)".to_string(),
+        ));
+    }
+    let window = params
+        .window
+        .as_deref()
+        .map(parse_query_window)
+        .transpose()?;
+    let targets = state
+        .recent
+        .top(params.top.unwrap_or(DEFAULT_REPLAY_TOP), window)
+        .into_iter()
+        .map(|entry| CacheImportRecord {
+            origin: entry.origin,
+            path: entry.path,
+        })
+        .collect();
+
+    let concurrency = params
+        .concurrency
+        .unwrap_or(DEFAULT_IMPORT_CONCURRENCY)
+        .clamp(1, MAX_IMPORT_CONCURRENCY);
+
+    let response = spawn_warm_job(
+        state,
+        "warm-replay",
+        targets,
+        concurrency,
+        |state, record| async move { vec![warm_record(&state, &record).await] },
+    );
+    Ok((StatusCode::ACCEPTED, Json(response)))
+}
+
+fn parse_query_window(window: &str) -> CdnResult<Duration> {
+    parse_window(window)
+        .ok_or_else(|| CdnError::InvalidRequest(format!("Invalid window: {}", window)))
+}
+
+/// Warm `items` in a background job, returning the job to poll
+///
+/// `warm` warms one item, with a result for each URL or range it covers.
+/// Once the job is cancelled no more items are started; those in flight
+/// finish.
+fn spawn_warm_job<T, F, Fut>(
+    state: Arc<AppState>,
+    kind: &'static str,
+    items: Vec<T>,
+    concurrency: usize,
+    warm: F,
+) -> CacheImportResponse
+where
+    T: Send + 'static,
+    F: Fn(Arc<AppState>, T) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Vec<WarmResult>> + Send,
+{
+    let job = state.jobs.create(kind, items.len());
+    let response = CacheImportResponse {
+        job_id: job.id().to_string(),
+        total: items.len(),
+    };
+
+    tracing::info!(
+        job_id = %job.id(),
+        kind,
+        total = items.len(),
+        concurrency,
+        "Starting cache warm job"
+    );
+
+    tokio::spawn(async move {
+        use futures::StreamExt;
+
+        futures::stream::iter(items)
+            .for_each_concurrent(concurrency, |item| {
+                let state = state.clone();
+                let job = job.clone();
+                let warm = &warm;
+                async move {
+                    if job.is_cancelled() {
+                        return;
+                    }
+                    let results = warm(state, item).await;
+                    for result in &results {
+                        job.record_result(result);
+                    }
+                    let failure = results.iter().find(|result| !result.success);
+                    match failure {
+                        None => job.record_success(),
+                        Some(result) => job.record_failure(format!(
+                            "{}: {}",
+                            result.url,
+                            result.error.as_deref().unwrap_or_default()
+                        )),
+                    }
+                }
+            })
+            .await;
+
+        job.finish();
+        tracing::info!(
+            job_id = %job.id(),
+            kind,
+            cancelled = job.is_cancelled(),
+            "Cache warm job finished"
+        );
+    });
+
+    response
+}
+
+/// Warm an exported or recently served key
+async fn warm_record(state: &Arc<AppState>, record: &CacheImportRecord) -> WarmResult {
+    let (path, query) = match record.path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (record.path.as_str(), None),
+    };
+    let url = format!("{}{}", record.origin, record.path);
+    warm_one(state, &url, &record.origin, path, query).await
+}
+
+// Warm job progress, with the result of each URL warmed so far
+pub async fn warm_job_status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> CdnResult<Json<JobStatus>> {
+    Ok(Json(warm_job(&state, &id)?.status()))
+}
+
+// Cancel a warm job; URLs already being fetched finish
+pub async fn cancel_warm_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> CdnResult<Json<JobStatus>> {
+    let job = warm_job(&state, &id)?;
+    if job.cancel() {
+        tracing::info!(job_id = %id, kind = job.kind(), "Cancelling cache warm job");
+    }
+    Ok(Json(job.status()))
+}
+
+fn warm_job(state: &AppState, id: &str) -> CdnResult<Arc<Job>> {
+    state
+        .jobs
+        .job(id)
+        .ok_or_else(|| CdnError::NotFound(format!("Unknown warm job: {}", id)))
+}
+
+// Background job status endpoint
+pub async fn job_status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> CdnResult<Json<JobStatus>> {
+    state
+        .jobs
+        .get(&id)
+        .map(Json)
+        .ok_or_else(|| CdnError::NotFound(format!("Unknown job: {}", id)))
+}
+
+/// Warm a single cache key through the same coalescer and circuit breaker as live traffic
+async fn warm_one(
+    state: &Arc<AppState>,
+    url: &str,
+    origin: &str,
+    path: &str,
+    query: Option<&str>,
+) -> WarmResult {
+    let failure = |cache_status: Option<CacheStatus>, error: String| WarmResult {
+        url: url.to_string(),
+        success: false,
+        cached: false,
+        cache_status: cache_status.map(|s| s.as_str().to_string()),
+        range: None,
+        error: Some(error),
+    };
+
+    // Check if origin exists
+    if !state.origin.has_origin(origin) {
+        return failure(None, format!("Unknown origin: {}", origin));
+    }
+
+    // Warm fetches honour the circuit breaker just like live traffic
+    if !state.circuit_breaker.should_allow(origin) {
+        return failure(None, format!("Origin {} circuit breaker is open", origin));
+    }
+
+    // Generate cache key
+    let start = Instant::now();
+    let keys = CacheKeyBuilder::new(origin, path, &state.config.cache.key).query(query);
+    let cache_key = keys.lookup_key();
+
+    // Check if already cached
+    let slot = match state
+        .cache
+        .get_or_reserve(&cache_key, false, Duration::ZERO)
+    {
+        CacheLookup::Found(entry, status) => {
+            state.metrics.record_request(
+                origin,
+                status,
+                StatusCode::from_u16(entry.status_code).unwrap_or(StatusCode::OK),
+                start.elapsed(),
+                RequestSource::Warm,
+            );
+            return WarmResult {
+                url: url.to_string(),
+                success: true,
+                cached: true,
+                cache_status: Some(status.as_str().to_string()),
+                range: None,
+                error: None,
+            };
+        }
+        CacheLookup::Vacant(slot) => slot,
+    };
+
+    // Fetch through the coalescer so we share in-flight fetches with live traffic
+    let (fetch_result, coalesced) = fetch_from_origin_coalesced(
+        state,
+        &keys.fetch_key(),
+        origin,
+        path,
+        query,
+        &HeaderMap::new(),
+        &HashMap::new(),
+        RequestSource::Warm,
+    )
+    .await;
+
+    let (body, headers, status) = match fetch_result {
+        Ok(response) => response,
+        Err(e) => return failure(None, e.to_string()),
+    };
+
+    // If we joined another request's fetch that caches the response, it
+    // fills the cache for us
+    let filled_by_other = coalesced == Some(true);
+    let cache_status = if filled_by_other {
+        CacheStatus::Hit
+    } else {
+        CacheStatus::Miss
+    };
+    state.metrics.record_request(
+        origin,
+        cache_status,
+        status,
+        start.elapsed(),
+        RequestSource::Warm,
+    );
+
+    if !filled_by_other {
+        if !is_cacheable(status, &headers) {
+            return failure(Some(cache_status), "Response not cacheable".to_string());
+        }
+
+        // Store in cache
+        let final_cache_key = keys.response_key(headers.get("vary").map(|s| s.as_str()));
+        store_in_cache(
+            state,
+            origin,
+            slot.for_key(final_cache_key),
+            body,
+            headers,
+            status,
+        );
+    }
+
+    WarmResult {
+        url: url.to_string(),
+        success: true,
+        cached: filled_by_other,
+        cache_status: Some(cache_status.as_str().to_string()),
+        range: None,
+        error: None,
+    }
+}
+
+/// Warm one byte range of a URL as a partial cache entry
+///
+/// `range` is a single range spec like "0-2097151". The origin's 206 is
+/// stored under the object's key plus the range its Content-Range reports,
+/// so Range requests inside it are answered without the rest of the object.
+/// An origin that ignores the Range has its 200 cached as the whole object.
+async fn warm_range(
+    state: &Arc<AppState>,
+    url: &str,
+    origin: &str,
+    path: &str,
+    range: &str,
+) -> WarmResult {
+    let result = |success: bool, cached: bool, cache_status: Option<CacheStatus>| WarmResult {
+        url: url.to_string(),
+        success,
+        cached,
+        cache_status: cache_status.map(|s| s.as_str().to_string()),
+        range: Some(range.to_string()),
+        error: None,
+    };
+    let failure = |cache_status: Option<CacheStatus>, error: String| WarmResult {
+        error: Some(error),
+        ..result(false, false, cache_status)
+    };
+
+    // Only the syntax is checked here; the origin knows the object's length
+    let range_header = format!("bytes={}", range.trim());
+    if !matches!(
+        parse_range_header(&range_header, u64::MAX),
+        RangeParseResult::Single(_)
+    ) {
+        return failure(None, format!("Invalid range: {}", range));
+    }
+
+    if !state.origin.has_origin(origin) {
+        return failure(None, format!("Unknown origin: {}", origin));
+    }
+    if !state.circuit_breaker.should_allow(origin) {
+        return failure(None, format!("Origin {} circuit breaker is open", origin));
+    }
+
+    let start = Instant::now();
+    let keys = CacheKeyBuilder::new(origin, path, &state.config.cache.key);
+    let cache_key = keys.lookup_key();
+    if state.cache.get_range(&cache_key, &range_header).is_some() {
+        state.metrics.record_request(
+            origin,
+            CacheStatus::Hit,
+            StatusCode::PARTIAL_CONTENT,
+            start.elapsed(),
+            RequestSource::Warm,
+        );
+        return result(true, true, Some(CacheStatus::Hit));
+    }
+
+    // Reserved under the object's key so a purge of the URL cancels the fill
+    let slot = state.cache.reserve(&cache_key);
+    let response = match state
+        .origin
+        .fetch_range(origin, path, None, &HashMap::new(), &range_header)
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            record_origin_failure(state, origin, origin_error_kind(&e));
+            return failure(None, e.to_string());
+        }
+    };
+
+    let status = StatusCode::from_u16(response.status_code).unwrap_or(StatusCode::OK);
+    record_origin_response(state, origin, status);
+    state.metrics.record_origin_request(
+        origin,
+        status,
+        RequestSource::Warm,
+        state.origin.is_proxied(origin),
+    );
+    state.metrics.record_request(
+        origin,
+        CacheStatus::Miss,
+        status,
+        start.elapsed(),
+        RequestSource::Warm,
+    );
+
+    if !is_cacheable(status, &response.headers) {
+        return failure(
+            Some(CacheStatus::Miss),
+            "Response not cacheable".to_string(),
+        );
+    }
+
+    let object_key = keys.response_key(response.headers.get("vary").map(|s| s.as_str()));
+    let store_key = if status == StatusCode::PARTIAL_CONTENT {
+        let Some((cached, _)) = response
+            .headers
+            .get("content-range")
+            .and_then(|value| parse_content_range(value))
+        else {
+            return failure(
+                Some(CacheStatus::Miss),
+                "Origin sent a 206 without a usable Content-Range".to_string(),
+            );
+        };
+        range_key(&object_key, &cached)
+    } else {
+        object_key
+    };
+    store_in_cache(
+        state,
+        origin,
+        slot.for_key(store_key),
+        response.body,
+        response.headers,
+        status,
+    );
+
+    result(true, false, Some(CacheStatus::Miss))
+}
+
+// Main CDN handler - supports both GET and HEAD methods
+pub async fn cdn_handler(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    Path((origin, path)): Path<(String, String)>,
+    Query(query): Query<CdnQuery>,
+    routed: Option<Extension<RoutedOrigin>>,
+    headers: HeaderMap,
+) -> Result<Response, CdnError> {
+    // An edge routing rule (e.g. a canary split) overrides the origin in the path
+    let origin = match routed {
+        Some(Extension(RoutedOrigin(routed))) => routed,
+        None => origin,
+    };
+
+    // Tag errors with the origin so its own error pages are rendered
+    serve_cdn_request(state, method, origin.clone(), path, query, headers)
+        .await
+        .map_err(|e| e.with_origin(origin))
+}
+
+/// Extra stale window for an origin whose recent p95 latency is over the threshold
+fn adaptive_extra_stale(state: &AppState, origin: &str) -> Duration {
+    let config = &state.config.cache.adaptive_stale;
+    if !config.enabled {
+        return Duration::ZERO;
+    }
+
+    match state.origin.latency_p95(origin) {
+        Some(p95) if p95 > Duration::from_millis(config.latency_threshold_ms) => {
+            tracing::debug!(origin = %origin, p95_ms = p95.as_millis() as u64, "Origin is slow, extending stale window");
+            Duration::from_secs(config.max_extra_stale_secs)
+        }
+        _ => Duration::ZERO,
+    }
+}
+
+// PURGE method on CDN paths - invalidate every cached variant of the resource
+pub async fn purge_resource_handler(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    Path((origin, path)): Path<(String, String)>,
+    Query(query): Query<CdnQuery>,
+    claims: Option<Extension<PurgeTokenClaims>>,
+) -> Result<Response, CdnError> {
+    if method.as_str() != PURGE_METHOD {
+        return Ok((
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(header::ALLOW, "GET,HEAD,PURGE")],
+        )
+            .into_response());
+    }
+    if !state.origin.has_origin(&origin) {
+        return Err(CdnError::NotFound(format!("Unknown origin: {}", origin)));
+    }
+
+    // Variants of the key a GET computes all start with this base key
+    let query_string = cdn_query_string(&query);
+    let base_key = CacheKeyBuilder::new(&origin, &path, &state.config.cache.key)
+        .query(query_string.as_deref())
+        .base_key();
+
+    if let Some(Extension(claims)) = claims {
+        let errors = claims.violations(std::slice::from_ref(&base_key), None, None, false);
+        if !errors.is_empty() {
+            let response = PurgeResponse {
+                success: false,
+                message: format!("{} is outside the purge token's scope", base_key),
+                purged_count: 0,
+                cancelled_fills: 0,
+                errors,
+                deduplicated: false,
+                unknown_key_hashes: Vec::new(),
+            };
+            return Ok((StatusCode::FORBIDDEN, Json(response)).into_response());
+        }
+    }
+
+    let cancelled_fills = state
+        .cache
+        .cancel_fills(|key| is_variant_of(key, &base_key));
+    let purged_count = state.cache.invalidate_variants(&base_key);
+    let success = purged_count > 0 || cancelled_fills > 0;
+    let (status, message) = if success {
+        (StatusCode::OK, purge_message(purged_count, cancelled_fills))
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Nothing cached for {}", base_key),
+        )
+    };
+    let response = PurgeResponse {
+        success,
+        message,
+        purged_count,
+        cancelled_fills,
+        errors: Vec::new(),
+        deduplicated: false,
+        unknown_key_hashes: Vec::new(),
+    };
+    Ok((status, Json(response)).into_response())
+}
+
+pub async fn root_purge_resource_handler(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    Path(path): Path<String>,
+    Query(query): Query<CdnQuery>,
+    claims: Option<Extension<PurgeTokenClaims>>,
+) -> Result<Response, CdnError> {
+    // Use default origin if only one is configured, as for GET
+    let mut origins = state.origin.origin_names();
+    if origins.len() == 1 {
+        let origin = origins.remove(0);
+        return purge_resource_handler(
+            State(state),
+            method,
+            Path((origin, path)),
+            Query(query),
+            claims,
+        )
+        .await;
+    }
+
+    Err(CdnError::InvalidRequest(
+        "Origin must be specified in path: /<origin>/<path>".to_string(),
+    ))
+}
+
+/// Handle dangerous request headers that aren't part of the cache key
+///
+/// Each configured header whose value isn't explicitly allowed is stripped,
+/// or, with the "bypass" action, left in place and `true` is returned so the
+/// request neither reads nor fills the cache. Headers in the cache key are
+/// skipped: a crafted value there only reaches its own cache entry.
+fn screen_unkeyed_headers(state: &AppState, origin: &str, headers: &mut HeaderMap) -> bool {
+    let config = &state.config.cache.unkeyed_header_protection;
+    if !config.enabled {
+        return false;
+    }
+
+    let keyed = &state.config.cache.key.include_headers;
+    let mut bypass = false;
+    for name in &config.headers {
+        if keyed.iter().any(|k| k.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        let Ok(name) = HeaderName::from_bytes(name.to_lowercase().as_bytes()) else {
+            continue;
+        };
+
+        let suspicious = headers
+            .get_all(&name)
+            .iter()
+            .find(|v| {
+                !v.to_str()
+                    .is_ok_and(|v| config.is_allowed(name.as_str(), v))
+            })
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned());
+        let Some(value) = suspicious else {
+            continue;
+        };
+
+        tracing::warn!(
+            origin = %origin,
+            header = %name,
+            value = %value,
+            action = config.action.as_str(),
+            "Unkeyed request header could poison the cache"
+        );
+        state
+            .metrics
+            .record_unkeyed_header(origin, name.as_str(), config.action.as_str());
+
+        match config.action {
+            UnkeyedHeaderAction::Strip => {
+                headers.remove(&name);
+            }
+            UnkeyedHeaderAction::Bypass => bypass = true,
+        }
+    }
+
+    bypass
+}
+
+/// Whether the origin wants this request kept out of the cache as personalized
+///
+/// An Authorization header, or Cookie headers over the origin's size limit,
+/// mark a request as personalized. Bypasses are counted by reason.
+fn personalized_bypass(state: &AppState, origin: &str, headers: &HeaderMap) -> bool {
+    let Some(origin_config) = state.origin.origin_config(origin) else {
+        return false;
+    };
+    let config = &origin_config.personalized_bypass;
+    if !config.is_enabled() {
+        return false;
+    }
+
+    let reason = if config.authorization && headers.contains_key(header::AUTHORIZATION) {
+        "authorization"
+    } else if let Some(limit) = config.cookie_max_bytes
+        && headers
+            .get_all(header::COOKIE)
+            .iter()
+            .map(|v| v.len())
+            .sum::<usize>()
+            > limit
+    {
+        "cookie"
+    } else {
+        return false;
+    };
+
+    state.metrics.record_personalized_bypass(origin, reason);
+    true
+}
+
+/// Query string as it appears in cache keys and origin requests
+///
+/// Parameters are sorted so the same query always yields the same key.
+fn cdn_query_string(query: &CdnQuery) -> Option<String> {
+    if query.params.is_empty() {
+        return None;
+    }
+    let mut params: Vec<String> = query
+        .params
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    params.sort_unstable();
+    Some(params.join("&"))
+}
+
+/// A request made up by the node itself rather than received from a client
+///
+/// Goes through path normalization, edge rules and the CDN handler the way
+/// live traffic does, so admin tooling and tests can exercise the request
+/// path without a listener.
+#[derive(Debug, Clone)]
+pub struct SyntheticRequest {
+    pub method: Method,
+    /// Full CDN path, origin segment included (e.g. "/web/index.html")
+    pub path: String,
+    /// Query string, without the leading `?`
+    pub query: Option<String>,
+    pub headers: HeaderMap,
+}
+
+/// Where a synthetic request lands once edge processing has run
+#[derive(Debug, Clone)]
+pub struct RoutedRequest {
+    pub origin: String,
+    /// Path below the origin, without a leading slash
+    pub path: String,
+    pub query: Option<String>,
+    /// Request headers after the edge header transforms
+    pub headers: HeaderMap,
+}
+
+impl SyntheticRequest {
+    pub fn get(path: impl Into<String>) -> Self {
+        Self {
+            method: Method::GET,
+            path: path.into(),
+            query: None,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    pub fn query(mut self, query: Option<String>) -> Self {
+        self.query = query.filter(|q| !q.is_empty());
+        self
+    }
+
+    pub fn headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Normalize the path and apply edge rules, as the middleware stack does
+    ///
+    /// Fails when edge processing would answer the request itself (a
+    /// redirect, fixed response, block or dictionary miss), since it then
+    /// never reaches the CDN handler.
+    pub fn route(&self, state: &AppState) -> CdnResult<RoutedRequest> {
+        let normalizer = PathNormalizer::new(state.config.path_normalization.clone());
+        let mut path = normalizer.normalize(&self.path);
+        let mut query = self.query.clone();
+        let mut headers = self.headers.clone();
+        let mut routed = None;
+        if state.config.edge.enabled {
+            if let Err(exceeded) = state.edge.check_query_limits(query.as_deref()) {
+                return Err(CdnError::InvalidRequest(format!(
+                    "Query string exceeds the {} limit",
+                    exceeded.as_str()
+                )));
+            }
+            let client_ip = edge_client_ip(&headers, None);
+            match state.edge.process_request(
+                &path,
+                query.as_deref(),
+                &self.method,
+                &headers,
+                client_ip.as_deref(),
+            ) {
+                EdgeProcessingResult::RouteAction(RoutingAction::RouteToOrigin { origin }) => {
+                    routed = Some(origin);
+                }
+                EdgeProcessingResult::RouteAction(_) => {
+                    return Err(CdnError::InvalidRequest(
+                        "Edge processing answers this request without the cache".to_string(),
+                    ));
+                }
+                EdgeProcessingResult::NotFound { .. } => {
+                    return Err(CdnError::NotFound(path));
+                }
+                EdgeProcessingResult::Continue {
+                    path: new_path,
+                    query: new_query,
+                } => {
+                    if let Some(new_path) = new_path {
+                        path = new_path;
+                    }
+                    if new_query.is_some() {
+                        query = new_query;
+                    }
+                }
+            }
+            state.edge.transform_request_headers(&mut headers);
+        }
+
+        let Some((origin, path)) = path.trim_start_matches('/').split_once('/') else {
+            return Err(CdnError::InvalidRequest(format!(
+                "No path below the origin in {}",
+                path
+            )));
+        };
+        Ok(RoutedRequest {
+            origin: routed.unwrap_or_else(|| origin.to_string()),
+            path: path.to_string(),
+            query,
+            headers,
+        })
+    }
+
+    /// Serve the request through edge rules and the full CDN handler
+    pub async fn serve(self, state: Arc<AppState>) -> CdnResult<Response> {
+        let routed = self.route(&state)?;
+        let query = CdnQuery {
+            params: url::form_urlencoded::parse(routed.query.unwrap_or_default().as_bytes())
+                .into_owned()
+                .collect(),
+        };
+        let edge_enabled = state.config.edge.enabled;
+        let edge = state.edge.clone();
+        let mut response = serve_cdn_request(
+            state,
+            self.method,
+            routed.origin,
+            routed.path,
+            query,
+            routed.headers,
+        )
+        .await?;
+        if edge_enabled {
+            edge.transform_response_headers(response.headers_mut());
+        }
+        Ok(response)
+    }
+}
+
+/// End-to-end budget for a client request (`server.request_timeout_secs`)
+#[derive(Debug, Clone, Copy)]
+struct RequestDeadline(Instant);
+
+impl RequestDeadline {
+    fn after(budget: Duration) -> Self {
+        Self(Instant::now() + budget)
+    }
+
+    /// Budget left, or a 504 once it has run out
+    fn remaining(&self) -> CdnResult<Duration> {
+        let left = self.0.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(Self::exceeded());
+        }
+        Ok(left)
+    }
+
+    /// Run `work` with whatever budget is left, answering 504 when it runs out
+    async fn run<T>(&self, work: impl Future<Output = CdnResult<T>>) -> CdnResult<T> {
+        match tokio::time::timeout(self.remaining()?, work).await {
+            Ok(result) => result,
+            Err(_) => Err(Self::exceeded()),
+        }
+    }
+
+    fn exceeded() -> CdnError {
+        CdnError::GatewayTimeout("Request timeout exceeded".to_string())
+    }
+}
+
+/// Serve a CDN request within `server.request_timeout_secs`
+///
+/// The cache lookup, any origin fetch and building the
+/// response all share the budget. Running out answers 504, but a cache fill
+/// started for the request carries on in the background.
+async fn serve_cdn_request(
+    state: Arc<AppState>,
+    method: Method,
+    origin: String,
+    path: String,
+    query: CdnQuery,
+    headers: HeaderMap,
+) -> Result<Response, CdnError> {
+    request_timing::lap(Phase::Queue);
+    let skip_compression = state.config.compression.bypasses(&origin, &path)
+        || state
+            .origin
+            .origin_config(&origin)
+            .is_some_and(|config| !config.compress_responses);
+    let deadline = RequestDeadline::after(state.config.request_timeout());
+    let mut result = deadline
+        .run(serve_cdn_request_until(
+            deadline,
+            state,
+            method,
+            origin.clone(),
+            path,
+            query,
+            headers,
+        ))
+        .await;
+    if let Err(CdnError::GatewayTimeout(_)) = &result {
+        tracing::warn!(origin = %origin, "Request exceeded its timeout budget");
+    }
+    if let Ok(response) = &mut result
+        && skip_compression
+    {
+        response.extensions_mut().insert(SkipCompression);
+    }
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn serve_cdn_request_until(
+    deadline: RequestDeadline,
+    state: Arc<AppState>,
+    method: Method,
+    origin: String,
+    path: String,
+    query: CdnQuery,
+    mut headers: HeaderMap,
+) -> Result<Response, CdnError> {
+    let start = Instant::now();
+    let is_head_request = method == Method::HEAD;
+
+    // Validate origin exists
+    if !state.origin.has_origin(&origin) {
+        return Err(CdnError::NotFound(format!("Unknown origin: {}", origin)));
+    }
+
+    // Check circuit breaker
+    if !state.circuit_breaker.should_allow(&origin) {
+        return Err(CdnError::OriginUnavailable {
+            message: format!("Origin {} circuit breaker is open", origin),
+            retry_after_secs: state
+                .circuit_breaker
+                .retry_after(&origin)
+                .map(|wait| wait.as_secs_f64().ceil().max(1.0) as u64),
+        });
+    }
+
+    // A disabled cache sends everything to the origin, with nothing to
+    // poison, key or coalesce
+    let cache_enabled = state.cache.enabled();
+
+    // Close off cache poisoning through headers the cache key doesn't cover
+    let unkeyed_bypass = cache_enabled && screen_unkeyed_headers(&state, &origin, &mut headers);
+
+    // Classify the device before keying, replacing any client-sent value
+    if state.config.device_detection.enabled {
+        let device = DeviceType::from_headers(&headers);
+        state.metrics.record_device_type(device.as_str());
+        headers.insert(
+            DEVICE_TYPE_HEADER,
+            HeaderValue::from_static(device.as_str()),
+        );
+    }
+
+    // Query rules may drop cache-busting parameters before the origin sees them
+    let query_string = state
+        .config
+        .cache
+        .key
+        .origin_query(&path, cdn_query_string(&query));
+
+    // Extract request headers for Vary-based cache keying (RFC 9111)
+    let request_headers_map = if cache_enabled {
+        extract_request_headers(&headers)
+    } else {
+        HashMap::new()
+    };
+
+    // Check request cache control
+    let client_bypass = headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.contains("no-cache") || v.contains("no-store"))
+        .unwrap_or(false);
+    let bypass_cache = !cache_enabled
+        || unkeyed_bypass
+        || personalized_bypass(&state, &origin, &headers)
+        || client_bypass;
+
+    let mut cache_status;
+    let response_body;
+    let response_headers;
+    let response_status;
+    let mut cache_age_secs: Option<u64> = None;
+    // Freshness left on the cached entry served, for Cache-Status
+    let mut cache_ttl_secs: Option<i64> = None;
+    // How the request went to the origin, for Cache-Status
+    let mut forwarded: Option<Forwarded> = None;
+    // Origin failure behind this response (a passed-on 5xx or stale-if-error)
+    let mut origin_error: Option<OriginErrorKind> = None;
+    // The Range is already answered (by the origin or a partial entry), so
+    // the body is not sliced again
+    let mut range_passthrough = false;
+
+    // Looked up by Accept-Encoding so compression variants are cached separately
+    let cache_key = CacheKeyBuilder::new(&origin, &path, &state.config.cache.key)
+        .query(query_string.as_deref())
+        .request_headers(&request_headers_map)
+        .lookup_key();
+
+    if bypass_cache {
+        // Client requested bypass
+        cache_status = CacheStatus::Bypass;
+        request_timing::lap(Phase::Cache);
+        let fetched = fetch_from_origin_with_circuit_breaker(
+            &state,
+            &origin,
+            &path,
+            query_string.as_deref(),
+            &headers,
+            RequestSource::Client,
+        )
+        .await;
+        request_timing::lap(Phase::Origin);
+        match fetched {
+            Ok(origin_response) => {
+                let reason = if client_bypass {
+                    ForwardReason::Request
+                } else {
+                    ForwardReason::Bypass
+                };
+                forwarded = Some(Forwarded::new(reason, Some(origin_response.2)));
+                origin_error = OriginErrorKind::from_status(origin_response.2.as_u16());
+                if origin_error.is_some()
+                    && let Some(err) = origin_status_error(&state, origin_response.2)
+                {
+                    return Err(err);
+                }
+                response_body = origin_response.0;
+                response_headers = origin_response.1;
+                response_status = origin_response.2;
+            }
+            Err(e) => return Err(e),
+        }
+    } else if let Some(range) = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| !is_head_request)
+        && let Some(hit) = state.content_cache.get_range(&cache_key, range)
+    {
+        // A warmed partial entry covers the Range; the whole object isn't needed
+        request_timing::lap(Phase::Cache);
+        cache_status = CacheStatus::Hit;
+        cache_age_secs = Some(hit.entry.created_at.elapsed().as_secs());
+        cache_ttl_secs = Some(state.cache.freshness_secs(&hit.entry));
+        range_passthrough = true;
+        response_body = hit.body();
+        response_headers = hit.headers();
+        response_status = StatusCode::PARTIAL_CONTENT;
+    } else {
+        // Try cache first; HEAD can also be answered from a headers-only entry.
+        // A slow origin widens the stale window so users aren't kept waiting on it.
+        let cached = state.content_cache.get_or_reserve(
+            &cache_key,
+            is_head_request,
+            adaptive_extra_stale(&state, &origin),
+        );
+        request_timing::lap(Phase::Cache);
+
+        match cached {
+            CacheLookup::Found(entry, status) => {
+                cache_status = status;
+                // Stale entries are refreshed, and fresh ones may be refreshed early
+                let refresh_slot = match status {
+                    CacheStatus::Stale | CacheStatus::StaleAdaptive => {
+                        state.cache.reserve_unless_filling(&cache_key)
+                    }
+                    CacheStatus::Hit => state.cache.reserve_early_refresh(&cache_key, &entry),
+                    _ => None,
+                };
+                // Past the cap the slot is dropped unfilled; a later hit retries
+                let refresh = refresh_slot
+                    .and_then(|slot| state.revalidations.try_start().map(|permit| (slot, permit)));
+                let headers_only = entry.headers_only;
+                // Calculate Age header value (RFC 9111)
+                cache_age_secs = Some(entry.created_at.elapsed().as_secs());
+                cache_ttl_secs = Some(state.cache.freshness_secs(&entry));
+                response_body = entry.body;
+                response_headers = entry.headers;
+                response_status = StatusCode::from_u16(entry.status_code).unwrap_or(StatusCode::OK);
+
+                // Revalidate in the background while serving the cached copy
+                if let Some((slot, permit)) = refresh {
+                    let state_clone = state.clone();
+                    let origin_clone = origin.clone();
+                    let path_clone = path.clone();
+                    let query_clone = query_string.clone();
+                    let request_headers_clone = request_headers_map.clone();
+                    let client_headers = headers.clone();
+                    let validators = conditional_headers(&response_headers);
+                    tokio::spawn(async move {
+                        let _permit = permit;
+                        // Headers-only entries are refreshed the way they were filled
+                        if headers_only {
+                            fetch_head_for_miss(
+                                &state_clone,
+                                &slot,
+                                &origin_clone,
+                                &path_clone,
+                                query_clone.as_deref(),
+                                &client_headers,
+                                &request_headers_clone,
+                            )
+                            .await;
+                            return;
+                        }
+
+                        revalidate_entry(
+                            &state_clone,
+                            slot,
+                            &origin_clone,
+                            &path_clone,
+                            query_clone.as_deref(),
+                            &client_headers,
+                            &request_headers_clone,
+                            validators,
+                        )
+                        .await;
+                    });
+                }
+            }
+            CacheLookup::Vacant(slot) => {
+                // Cache miss - fetch from origin (with optional coalescing)
+                cache_status = CacheStatus::Miss;
+
+                // Large HEAD misses are answered from an origin HEAD
+                let head_response = if is_head_request && state.config.cache.head.enabled {
+                    fetch_head_for_miss(
+                        &state,
+                        &slot,
+                        &origin,
+                        &path,
+                        query_string.as_deref(),
+                        &headers,
+                        &request_headers_map,
+                    )
+                    .await
+                } else {
+                    None
+                };
+
+                // Ranges of very large objects are forwarded instead of fetched whole
+                let range_header = headers
+                    .get(header::RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .filter(|_| !is_head_request);
+                let mut passthrough = None;
+                if let Some(range) = range_header
+                    && let Some(size) = large_object_size(
+                        &state,
+                        &slot,
+                        &origin,
+                        &path,
+                        query_string.as_deref(),
+                        &headers,
+                        &request_headers_map,
+                    )
+                    .await
+                {
+                    if matches!(parse_range_header(range, size), RangeParseResult::Invalid) {
+                        return build_range_not_satisfiable_response(size);
+                    }
+                    passthrough = Some(
+                        fetch_range_from_origin(
+                            &state,
+                            &origin,
+                            &path,
+                            query_string.as_deref(),
+                            &headers,
+                            range,
+                        )
+                        .await?,
+                    );
+                }
+
+                if let Some((head_headers, head_status)) = head_response {
+                    request_timing::lap(Phase::Origin);
+                    forwarded = Some(Forwarded::new(ForwardReason::UriMiss, Some(head_status)));
+                    response_body = Bytes::new();
+                    response_headers = head_headers;
+                    response_status = head_status;
+                } else if let Some((body, range_headers, status)) = passthrough {
+                    request_timing::lap(Phase::Origin);
+                    // Not cached: a partial body can't stand in for the object
+                    range_passthrough = status == StatusCode::PARTIAL_CONTENT;
+                    forwarded = Some(Forwarded::new(ForwardReason::UriMiss, Some(status)));
+                    response_body = body;
+                    response_headers = range_headers;
+                    response_status = status;
+                } else {
+                    // A failed HEAD or size probe may just have opened the circuit
+                    if !state.circuit_breaker.should_allow(&origin) {
+                        return Err(CdnError::OriginUnavailable {
+                            message: format!("Origin {} circuit breaker is open", origin),
+                            retry_after_secs: state
+                                .circuit_breaker
+                                .retry_after(&origin)
+                                .map(|wait| wait.as_secs_f64().ceil().max(1.0) as u64),
+                        });
+                    }
+
+                    if range_header.is_some() {
+                        state.metrics.record_range_fetch(&origin, "full_fetch");
+                    }
+
+                    // Only the wait is bounded by the request budget, not the fill
+                    let fill = spawn_miss_fill(
+                        &state,
+                        slot,
+                        &origin,
+                        &path,
+                        query_string.clone(),
+                        &headers,
+                        request_headers_map.clone(),
+                    );
+                    let MissFill {
+                        result: fetch_result,
+                        stored,
+                        collapsed,
+                    } = deadline
+                        .run(async {
+                            fill.await.map_err(|e| {
+                                CdnError::Internal(format!("Cache fill task failed: {}", e))
+                            })
+                        })
+                        .await?;
+                    // A collapsed request only waited on another's fetch
+                    request_timing::lap(if collapsed {
+                        Phase::Coalesce
+                    } else {
+                        Phase::Origin
+                    });
+
+                    match fetch_result {
+                        Ok(origin_response) => {
+                            forwarded = Some(Forwarded {
+                                reason: ForwardReason::UriMiss,
+                                status: Some(origin_response.2),
+                                stored,
+                                collapsed,
+                            });
+                            // Check if origin returned 5xx error - try stale-if-error
+                            if let Some(kind) =
+                                OriginErrorKind::from_status(origin_response.2.as_u16())
+                            {
+                                origin_error = Some(kind);
+                                // RFC 5861: Try to serve stale content on 5xx errors
+                                if let Some(stale_entry) = stale_for_error(&state, &cache_key, kind)
+                                {
+                                    cache_status = CacheStatus::StaleIfError;
+                                    cache_age_secs =
+                                        Some(stale_entry.created_at.elapsed().as_secs());
+                                    cache_ttl_secs = Some(state.cache.freshness_secs(&stale_entry));
+                                    forwarded = Some(Forwarded::new(
+                                        ForwardReason::Stale,
+                                        Some(origin_response.2),
+                                    ));
+                                    response_body = stale_entry.body;
+                                    response_headers = stale_entry.headers;
+                                    response_status = StatusCode::from_u16(stale_entry.status_code)
+                                        .unwrap_or(StatusCode::OK);
+                                    tracing::info!(
+                                        origin = %origin,
+                                        path = %path,
+                                        origin_status = %origin_response.2,
+                                        error_kind = kind.as_str(),
+                                        "Serving stale content due to origin 5xx error (stale-if-error)"
+                                    );
+                                } else if let Some(err) =
+                                    origin_status_error(&state, origin_response.2)
+                                {
+                                    return Err(err);
+                                } else {
+                                    // No stale content available, return the 5xx response
+                                    response_body = origin_response.0.clone();
+                                    response_headers = origin_response.1.clone();
+                                    response_status = origin_response.2;
+                                }
+                            } else if let Some(stale_entry) = stale_for_invalid_response(
+                                &state,
+                                &origin,
+                                &cache_key,
+                                &origin_response.1,
+                            ) {
+                                cache_status = CacheStatus::StaleIfError;
+                                cache_age_secs = Some(stale_entry.created_at.elapsed().as_secs());
+                                cache_ttl_secs = Some(state.cache.freshness_secs(&stale_entry));
+                                forwarded = Some(Forwarded::new(
+                                    ForwardReason::Stale,
+                                    Some(origin_response.2),
+                                ));
+                                response_body = stale_entry.body;
+                                response_headers = stale_entry.headers;
+                                response_status = StatusCode::from_u16(stale_entry.status_code)
+                                    .unwrap_or(StatusCode::OK);
+                                tracing::info!(
+                                    origin = %origin,
+                                    path = %path,
+                                    "Serving stale content in place of an origin response that failed validation"
+                                );
+                            } else {
+                                response_body = origin_response.0;
+                                response_headers = origin_response.1;
+                                response_status = origin_response.2;
+                            }
+                        }
+                        Err(e) => {
+                            // RFC 5861: Try stale-if-error on connection/fetch errors too
+                            let kind = origin_error_kind(&e);
+                            if let Some(stale_entry) = stale_for_error(&state, &cache_key, kind) {
+                                origin_error = Some(kind);
+                                cache_status = CacheStatus::StaleIfError;
+                                cache_age_secs = Some(stale_entry.created_at.elapsed().as_secs());
+                                cache_ttl_secs = Some(state.cache.freshness_secs(&stale_entry));
+                                // No origin response to report a status for
+                                forwarded = Some(Forwarded::new(ForwardReason::Stale, None));
+                                response_body = stale_entry.body;
+                                response_headers = stale_entry.headers;
+                                response_status = StatusCode::from_u16(stale_entry.status_code)
+                                    .unwrap_or(StatusCode::OK);
+                                tracing::info!(
+                                    origin = %origin,
+                                    path = %path,
+                                    error_kind = kind.as_str(),
+                                    error = %e,
+                                    "Serving stale content due to origin error (stale-if-error)"
+                                );
+                            } else {
+                                return Err(e);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Update metrics
+    let duration = start.elapsed();
+    state
+        .metrics
+        .record_request(
+            &origin,
+            cache_status,
+            response_status,
+            duration,
+            RequestSource::Client,
+        );
+    state
+        .metrics
+        .record_path_request(&origin, &path, response_status, duration);
+    if response_status.is_success() && !is_head_request {
+        state
+            .recent
+            .record(&origin, &format!("/{}", path), query_string.as_deref());
+    }
+
+    // RFC 9110 Section 14: Handle Range requests
+    // Only process Range header for successful responses; HEAD answers as GET would
+    let representation_length =
+        representation_length(&response_body, &response_headers, is_head_request);
+    let range_request: Option<ByteRange> = if !range_passthrough
+        && response_status.is_success()
+        && let Some(content_length) = representation_length
+    {
+        if let Some(range_header) = headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+            match parse_range_header(range_header, content_length) {
+                RangeParseResult::Single(range) => Some(range),
+                RangeParseResult::Multiple(_) => {
+                    // Multi-range not supported, serve full content
+                    None
+                }
+                RangeParseResult::Invalid => {
+                    // Return 416 Range Not Satisfiable
+                    return build_range_not_satisfiable_response(content_length);
+                }
+                RangeParseResult::None => None,
+            }
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    // X-Cache-Key-Hash names the key the response is stored under, which a
+    // Vary other than Accept-Encoding makes differ from the lookup key
+    let stored_key = state.config.cache.status_headers.key_hash.then(|| {
+        CacheKeyBuilder::new(&origin, &path, &state.config.cache.key)
+            .query(query_string.as_deref())
+            .request_headers(&request_headers_map)
+            .response_key(response_headers.get("vary").map(|s| s.as_str()))
+    });
+
+    // Client-facing Cache-Control and cookies; applied to our copy so the stored entry is untouched
+    let mut response_headers = response_headers;
+    if let Some(origin_config) = state.origin.origin_config(&origin) {
+        apply_client_cache_control(&mut response_headers, &origin_config);
+        rewrite_set_cookies(&mut response_headers, &origin_config.cookie_rewrite);
+    }
+    apply_response_header_policy(&state, &mut response_headers);
+    strip_origin_cdn_headers(&state.config.cache, &mut response_headers);
+
+    // Build response with RFC-compliant headers
+    request_timing::lap(Phase::Transform);
+    let cache_headers = cache_status_headers(
+        &state,
+        cache_status,
+        cache_ttl_secs,
+        forwarded.as_ref(),
+        &cache_key,
+        stored_key.as_deref(),
+    );
+    let mut response = build_response(
+        response_body,
+        response_headers,
+        response_status,
+        cache_headers,
+        cache_age_secs,
+        is_head_request,
+        range_request.as_ref(),
+    )?;
+
+    // Labels for byte accounting once the body is actually written
+    response.extensions_mut().insert(ServedFrom {
+        origin,
+        cache_status,
+    });
+    if let Some(kind) = origin_error {
+        response.extensions_mut().insert(kind);
+    }
+
+    Ok(response)
+}
+
+type OriginResult = CdnResult<(Bytes, ResponseHeaders, StatusCode)>;
+
+/// A cache miss's origin response and what became of it
+struct MissFill {
+    result: OriginResult,
+    /// The response went into the cache
+    stored: bool,
+    /// Another request's origin fetch answered this one
+    collapsed: bool,
+}
+
+/// A request that went to the origin, as reported in `Cache-Status`
+struct Forwarded {
+    reason: ForwardReason,
+    /// The origin's status; `None` when the fetch itself failed
+    status: Option<StatusCode>,
+    stored: bool,
+    collapsed: bool,
+}
+
+impl Forwarded {
+    fn new(reason: ForwardReason, status: Option<StatusCode>) -> Self {
+        Self {
+            reason,
+            status,
+            stored: false,
+            collapsed: false,
+        }
+    }
+}
+
+/// Cache headers a client response carries
+struct CacheHeaders {
+    x_cache: Option<&'static str>,
+    cache_status: Option<String>,
+    key_hash: Option<String>,
+}
+
+/// X-Cache, RFC 9211 Cache-Status and X-Cache-Key-Hash values for a
+/// response, as configured
+fn cache_status_headers(
+    state: &AppState,
+    cache_status: CacheStatus,
+    ttl_secs: Option<i64>,
+    forwarded: Option<&Forwarded>,
+    cache_key: &str,
+    stored_key: Option<&str>,
+) -> CacheHeaders {
+    let config = &state.config.cache.status_headers;
+    let x_cache = config.x_cache.then(|| cache_status.as_str());
+    let key_hash = stored_key.map(key_hash);
+    if !config.cache_status {
+        return CacheHeaders {
+            x_cache,
+            cache_status: None,
+            key_hash,
+        };
+    }
+
+    let value = CacheStatusValue::new(CACHE_NAME);
+    let mut value = match forwarded {
+        Some(forwarded) => {
+            let value = value
+                .fwd(forwarded.reason)
+                .stored(forwarded.stored)
+                .collapsed(forwarded.collapsed);
+            match forwarded.status {
+                Some(status) => value.fwd_status(status.as_u16()),
+                None => value,
+            }
+        }
+        None if matches!(cache_status, CacheStatus::Miss | CacheStatus::Bypass) => {
+            value.fwd(ForwardReason::Miss)
+        }
+        None => value.hit(),
+    };
+    if let Some(ttl) = ttl_secs {
+        value = value.ttl(ttl);
+    }
+    if config.debug {
+        value = value.key(cache_key);
+    }
+
+    CacheHeaders {
+        x_cache,
+        cache_status: Some(value.to_string()),
+        key_hash,
+    }
+}
+
+/// Fetch a cache miss from origin and store the response, on its own task
+///
+/// Detached from the client request, so a request that runs out of budget
+/// (or whose client goes away) neither cancels the fill nor, as coalescing
+/// leader, leaves its waiters without a response.
+#[allow(clippy::too_many_arguments)]
+fn spawn_miss_fill(
+    state: &Arc<AppState>,
+    slot: FillSlot,
+    origin: &str,
+    path: &str,
+    query: Option<String>,
+    headers: &HeaderMap,
+    request_headers_map: HashMap<String, String>,
+) -> tokio::task::JoinHandle<MissFill> {
+    let state = state.clone();
+    let origin = origin.to_string();
+    let path = path.to_string();
+    // Waiters coalesced onto this fill may have sent no validators
+    let headers = without_conditionals(headers);
+    tokio::spawn(async move {
+        let keys = CacheKeyBuilder::new(&origin, &path, &state.config.cache.key)
+            .query(query.as_deref())
+            .request_headers(&request_headers_map);
+        // Use coalescing to prevent thundering herd. Vary isn't known until
+        // the response, so misses for every variant share one fetch
+        let (result, collapsed) = fetch_from_origin_coalesced(
+            &state,
+            &keys.fetch_key(),
+            &origin,
+            &path,
+            query.as_deref(),
+            &headers,
+            &request_headers_map,
+            RequestSource::Client,
+        )
+        .await;
+
+        let mut stored = false;
+        if let Ok((body, response_headers, status)) = &result
+            && is_cacheable(*status, response_headers)
+        {
+            // Generate cache key with actual Vary header from response (RFC 9111)
+            let final_cache_key =
+                keys.response_key(response_headers.get("vary").map(|s| s.as_str()));
+            stored = store_in_cache(
+                &state,
+                &origin,
+                slot.for_key(final_cache_key),
+                body.clone(),
+                response_headers.clone(),
+                *status,
+            );
+        }
+        MissFill {
+            result,
+            stored,
+            collapsed: collapsed.is_some(),
+        }
+    })
+}
+
+/// Fetch through the request coalescer (when enabled) and circuit breaker
+///
+/// Returns the fetch result and, when it was served by another in-flight
+/// request for the same cache key rather than our own origin fetch, whether
+/// that request caches it. A shared response is only used if it doesn't vary
+/// on a header this request sent differently; otherwise the request fetches
+/// for itself.
+#[allow(clippy::too_many_arguments)]
+async fn fetch_from_origin_coalesced(
+    state: &Arc<AppState>,
+    cache_key: &str,
+    origin: &str,
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    request_headers: &HashMap<String, String>,
+    source: RequestSource,
+) -> (OriginResult, Option<bool>) {
+    if !state.coalesce_enabled {
+        let result =
+            fetch_from_origin_with_circuit_breaker(state, origin, path, query, headers, source)
+                .await;
+        return (result, None);
+    }
+
+    let keys = CacheKeyBuilder::new(origin, path, &state.config.cache.key)
+        .query(query)
+        .request_headers(request_headers);
+    let variant =
+        |headers: &ResponseHeaders| keys.fetch_variant(headers.get("vary").map(|s| s.as_str()));
+
+    match state.coalescer.try_acquire(cache_key) {
+        AcquireResult::Fetch(guard) => {
+            // We are the leader - fetch from origin
+            match fetch_from_origin_with_circuit_breaker(state, origin, path, query, headers, source)
+                .await
+            {
+                Ok((body, hdrs, status)) => {
+                    // Complete the coalesce to notify waiters, who must not
+                    // receive the leader's cookies
+                    let mut shared = hdrs.clone();
+                    shared.remove("set-cookie");
+                    guard.complete(CoalescedResponse {
+                        body: body.clone(),
+                        variant: variant(&shared),
+                        headers: shared,
+                        status_code: status.as_u16(),
+                        cacheable: is_cacheable(status, &hdrs),
+                    });
+                    (Ok((body, hdrs, status)), None)
+                }
+                Err(e) => {
+                    // Complete with error to notify waiters, who rebuild it
+                    // from the bare message so it renders as the leader's does
+                    guard.complete_error(CoalescedError {
+                        message: e.message().to_string(),
+                        kind: e.origin_error_kind(),
+                    });
+                    (Err(e), None)
+                }
+            }
+        }
+        AcquireResult::Overflow => {
+            // Too many requests already waiting on this key - fetch independently
+            let result =
+                fetch_from_origin_with_circuit_breaker(state, origin, path, query, headers, source)
+                    .await;
+            (result, None)
+        }
+        AcquireResult::Wait(mut receiver) => {
+            // Another request is already fetching - wait for result
+            tracing::debug!(cache_key = %cache_key, "Waiting for coalesced request");
+            let timeout = state.config.coalesce.waiter_timeout();
+            let Ok(received) = tokio::time::timeout(timeout, receiver.recv()).await else {
+                state.coalescer.record_waiter_timeout();
+                tracing::warn!(
+                    cache_key = %cache_key,
+                    timeout_ms = timeout.as_millis() as u64,
+                    "Coalesced request timed out waiting for in-flight fetch"
+                );
+                return match state.config.coalesce.on_waiter_timeout {
+                    WaiterTimeoutAction::Fetch => {
+                        let result = fetch_from_origin_with_circuit_breaker(
+                            state, origin, path, query, headers, source,
+                        )
+                        .await;
+                        (result, None)
+                    }
+                    WaiterTimeoutAction::GatewayTimeout => (
+                        Err(CdnError::GatewayTimeout(format!(
+                            "Timed out waiting for in-flight fetch of {}",
+                            cache_key
+                        ))),
+                        Some(false),
+                    ),
+                };
+            };
+            if let Ok(Ok(coalesced)) = &received
+                && !coalesced.serves(variant(&coalesced.headers).as_deref())
+            {
+                state.coalescer.record_variant_mismatch();
+                tracing::debug!(
+                    cache_key = %cache_key,
+                    vary = ?coalesced.headers.get("vary"),
+                    "Coalesced response is another variant, fetching independently"
+                );
+                let result = fetch_from_origin_with_circuit_breaker(
+                    state, origin, path, query, headers, source,
+                )
+                .await;
+                return (result, None);
+            }
+            let leader_caches = matches!(&received, Ok(Ok(coalesced)) if coalesced.cacheable);
+            let result = match received {
+                Ok(Ok(coalesced)) => {
+                    let status =
+                        StatusCode::from_u16(coalesced.status_code).unwrap_or(StatusCode::OK);
+                    Ok((coalesced.body, coalesced.headers, status))
+                }
+                Ok(Err(err)) => Err(match err.kind {
+                    Some(kind) => CdnError::OriginFetch {
+                        kind,
+                        message: err.message,
+                    },
+                    None => CdnError::OriginError(err.message),
+                }),
+                Err(_) => Err(CdnError::Internal(
+                    "Coalesced request was cancelled".to_string(),
+                )),
+            };
+            (result, Some(leader_caches))
+        }
+    }
+}
+
+async fn fetch_from_origin_with_circuit_breaker(
+    state: &Arc<AppState>,
+    origin: &str,
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    source: RequestSource,
+) -> OriginResult {
+    match fetch_from_origin(state, origin, path, query, headers).await {
+        Ok(result) => {
+            record_origin_response(state, origin, result.2);
+            state.metrics.record_origin_request(
+                origin,
+                result.2,
+                source,
+                state.origin.is_proxied(origin),
+            );
+            Ok(result)
+        }
+        Err(e) => {
+            record_origin_failure(state, origin, origin_error_kind(&e));
+            Err(e)
+        }
+    }
+}
+
+/// Kind of a failed origin fetch; errors raised before any fetch count as "other"
+fn origin_error_kind(err: &CdnError) -> OriginErrorKind {
+    err.origin_error_kind().unwrap_or(OriginErrorKind::Other)
+}
+
+/// Count an origin failure, tripping the circuit breaker if its kind's policy says so
+///
+/// Inside a maintenance window the breaker counts the failure but stays
+/// closed, unless the window's `trip_circuit_breaker` is set.
+fn record_origin_failure(state: &AppState, origin: &str, kind: OriginErrorKind) {
+    state.metrics.record_origin_error(origin, kind);
+    state.health_checker.availability().record(origin, false);
+    if !state
+        .config
+        .origin_errors
+        .for_kind(kind)
+        .trip_circuit_breaker
+    {
+        return;
+    }
+    let held = state.origin.origin_config(origin).is_some_and(|config| {
+        active_maintenance(&state.config.maintenance, &config.maintenance, Utc::now())
+            .is_some_and(|maintenance| !maintenance.trip_circuit_breaker)
+    });
+    if held {
+        state.circuit_breaker.record_held_failure(origin);
+    } else {
+        state.circuit_breaker.record_failure(origin);
+    }
+}
+
+/// Feed an origin response to the circuit breaker and availability; 5xx may
+/// count as failures for the breaker, and always does for availability
+fn record_origin_response(state: &AppState, origin: &str, status: StatusCode) {
+    match OriginErrorKind::from_status(status.as_u16()) {
+        Some(kind)
+            if state
+                .config
+                .origin_errors
+                .for_kind(kind)
+                .trip_circuit_breaker =>
+        {
+            record_origin_failure(state, origin, kind);
+        }
+        Some(kind) => {
+            state.metrics.record_origin_error(origin, kind);
+            state.health_checker.availability().record(origin, false);
+            record_origin_success(state, origin);
+        }
+        None => {
+            state.health_checker.availability().record(origin, true);
+            record_origin_success(state, origin);
+        }
+    }
+}
+
+/// Count a breaker success, warming connections back up if it closed the circuit
+fn record_origin_success(state: &AppState, origin: &str) {
+    if state.circuit_breaker.record_success(origin) {
+        state.origin.spawn_preconnect(origin);
+    }
+}
+
+/// The error to answer an origin 5xx with when no stale copy stands in
+///
+/// Masked statuses become 502, and with error pages enabled the status is
+/// rendered as one; otherwise `None` passes the origin's response on as is.
+fn origin_status_error(state: &AppState, status: StatusCode) -> Option<CdnError> {
+    let masked = state.config.origin_errors.mask_5xx;
+    let error_pages = get_error_pages().is_some_and(|pages| pages.is_enabled());
+    (masked || error_pages).then(|| CdnError::OriginStatus {
+        status,
+        masked,
+        message: "The origin server failed to handle the request".to_string(),
+    })
+}
+
+/// A stale copy to serve in place of an origin failure, if its kind allows it
+fn stale_for_error(state: &AppState, cache_key: &str, kind: OriginErrorKind) -> Option<CacheEntry> {
+    if !state.config.origin_errors.for_kind(kind).stale_if_error {
+        return None;
+    }
+    state.content_cache.get_stale_for_error(cache_key)
+}
+
+/// A stale copy to serve in place of an origin response that failed
+/// validation, if the origin's `on_failure` asks for one
+fn stale_for_invalid_response(
+    state: &AppState,
+    origin: &str,
+    cache_key: &str,
+    headers: &ResponseHeaders,
+) -> Option<CacheEntry> {
+    if !headers.contains_key(VALIDATION_FAILED_HEADER) {
+        return None;
+    }
+    let config = state.origin.origin_config(origin)?;
+    if config.validation.on_failure != ValidationFailureAction::Stale {
+        return None;
+    }
+    state.content_cache.get_stale_for_error(cache_key)
+}
+
+/// If-None-Match and If-Modified-Since from a cached response's origin validators
+fn conditional_headers(stored: &ResponseHeaders) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (validator, conditional) in [
+        ("etag", header::IF_NONE_MATCH),
+        ("last-modified", header::IF_MODIFIED_SINCE),
+    ] {
+        if let Some(value) = stored
+            .get(validator)
+            .and_then(|v| HeaderValue::from_str(v).ok())
+        {
+            headers.insert(conditional, value);
+        }
+    }
+    headers
+}
+
+/// Refetch a stale entry in the background and store the result
+///
+/// With origin validators the fetch is conditional, and a 304 refreshes the
+/// entry in place through [`Cache::refresh`]. A 304 that doesn't describe the
+/// cached variant is followed by an unconditional fetch.
+#[allow(clippy::too_many_arguments)]
+async fn revalidate_entry(
+    state: &Arc<AppState>,
+    slot: FillSlot,
+    origin: &str,
+    path: &str,
+    query: Option<&str>,
+    client_headers: &HeaderMap,
+    request_headers_map: &HashMap<String, String>,
+    validators: HeaderMap,
+) {
+    // The client's own conditionals are about its copy, not ours
+    let unconditional = without_conditionals(client_headers);
+    let mut conditional = unconditional.clone();
+    conditional.extend(validators);
+
+    let fetch = |headers| {
+        fetch_from_origin_with_circuit_breaker(
+            state,
+            origin,
+            path,
+            query,
+            headers,
+            RequestSource::Client,
+        )
+    };
+    let Ok(mut response) = fetch(&conditional).await else {
+        return;
+    };
+    if response.2 == StatusCode::NOT_MODIFIED {
+        let refreshed = state
+            .cache
+            .refresh(slot.for_key(slot.key().to_string()), &response.1);
+        if !matches!(refreshed, RefreshOutcome::Mismatch(_)) {
+            return;
+        }
+        match fetch(&unconditional).await {
+            Ok(full) if full.2 != StatusCode::NOT_MODIFIED => response = full,
+            _ => return,
+        }
+    }
+
+    // Generate cache key with actual Vary header from response
+    let (body, headers, status) = response;
+    let final_cache_key = CacheKeyBuilder::new(origin, path, &state.config.cache.key)
+        .query(query)
+        .request_headers(request_headers_map)
+        .response_key(headers.get("vary").map(|s| s.as_str()));
+    store_in_cache(
+        state,
+        origin,
+        slot.for_key(final_cache_key),
+        body,
+        headers,
+        status,
+    );
+}
+
+/// Answer a HEAD miss from an origin HEAD when the object is large
+///
+/// Returns `None` when the origin reports a size within the configured
+/// threshold, or when the HEAD fails, so the caller falls back to filling
+/// the cache with a GET. Otherwise the response is cached headers-only.
+async fn fetch_head_for_miss(
+    state: &Arc<AppState>,
+    slot: &FillSlot,
+    origin: &str,
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    request_headers_map: &HashMap<String, String>,
+) -> Option<(ResponseHeaders, StatusCode)> {
+    let request_headers = extract_request_headers(headers);
+    let response = match state
+        .origin_client
+        .fetch_head(origin, path, query, &request_headers)
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            record_origin_failure(state, origin, origin_error_kind(&e));
+            tracing::debug!(origin = %origin, path = %path, error = %e, "Origin HEAD failed, falling back to GET");
+            return None;
+        }
+    };
+
+    let status = StatusCode::from_u16(response.status_code).unwrap_or(StatusCode::OK);
+    record_origin_response(state, origin, status);
+    state.metrics.record_origin_request(
+        origin,
+        status,
+        RequestSource::Client,
+        state.origin.is_proxied(origin),
+    );
+
+    // Origins that reject HEAD (e.g. 405) are filled with a GET instead
+    if !status.is_success() {
+        return None;
+    }
+
+    let content_length = response
+        .headers
+        .get("content-length")
+        .and_then(|v| v.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len <= state.config.cache.head.size_threshold_bytes) {
+        return None;
+    }
+
+    let response_headers = response.headers;
+    if is_cacheable(status, &response_headers) {
+        let cache_key = CacheKeyBuilder::new(origin, path, &state.config.cache.key)
+            .query(query)
+            .request_headers(request_headers_map)
+            .response_key(response_headers.get("vary").map(|s| s.as_str()));
+        store_headers_only(
+            state,
+            origin,
+            slot.for_key(cache_key),
+            response_headers.clone(),
+            status,
+        );
+    }
+
+    Some((response_headers, status))
+}
+
+/// Size of an uncached object when it's above the range passthrough threshold
+///
+/// Uses the Content-Length of a cached headers-only entry, or asks the origin
+/// with a HEAD. Large objects are cached headers-only so the next Range miss
+/// (or HEAD) doesn't probe again. Returns `None` when passthrough is disabled,
+/// the object is small enough to fetch whole, or its size can't be learned.
+async fn large_object_size(
+    state: &Arc<AppState>,
+    slot: &FillSlot,
+    origin: &str,
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    request_headers_map: &HashMap<String, String>,
+) -> Option<u64> {
+    let config = &state.config.cache.range;
+    if !config.passthrough_enabled {
+        return None;
+    }
+
+    let size = match state.cache.headers_only_length(slot.key()) {
+        Some(size) => size,
+        None => {
+            let request_headers = extract_request_headers(headers);
+            let response = match state
+                .origin_client
+                .fetch_head(origin, path, query, &request_headers)
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    record_origin_failure(state, origin, origin_error_kind(&e));
+                    tracing::debug!(origin = %origin, path = %path, error = %e, "Origin HEAD failed, fetching whole object for range");
+                    return None;
+                }
+            };
+
+            let status = StatusCode::from_u16(response.status_code).unwrap_or(StatusCode::OK);
+            record_origin_response(state, origin, status);
+            state.metrics.record_origin_request(
+                origin,
+                status,
+                RequestSource::Client,
+                state.origin.is_proxied(origin),
+            );
+            if !status.is_success() {
+                return None;
+            }
+
+            let size = response
+                .headers
+                .get("content-length")
+                .and_then(|v| v.parse::<u64>().ok())?;
+            if size > config.passthrough_threshold_bytes && is_cacheable(status, &response.headers)
+            {
+                let cache_key = CacheKeyBuilder::new(origin, path, &state.config.cache.key)
+                    .query(query)
+                    .request_headers(request_headers_map)
+                    .response_key(response.headers.get("vary").map(|s| s.as_str()));
+                store_headers_only(
+                    state,
+                    origin,
+                    slot.for_key(cache_key),
+                    response.headers,
+                    status,
+                );
+            }
+            size
+        }
+    };
+
+    (size > config.passthrough_threshold_bytes).then_some(size)
+}
+
+/// Forward a client's Range to the origin and return its response uncached
+async fn fetch_range_from_origin(
+    state: &Arc<AppState>,
+    origin: &str,
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    range: &str,
+) -> OriginResult {
+    let request_headers = extract_request_headers(headers);
+    let response = match state
+        .origin_client
+        .fetch_range(origin, path, query, &request_headers, range)
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            record_origin_failure(state, origin, origin_error_kind(&e));
+            return Err(e);
+        }
+    };
+
+    let status = StatusCode::from_u16(response.status_code).unwrap_or(StatusCode::OK);
+    record_origin_response(state, origin, status);
+    state.metrics.record_origin_request(
+        origin,
+        status,
+        RequestSource::Client,
+        state.origin.is_proxied(origin),
+    );
+    state.metrics.record_range_fetch(origin, "passthrough");
+
+    Ok((response.body, response.headers, status))
+}
+
+async fn fetch_from_origin(
+    state: &Arc<AppState>,
+    origin: &str,
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+) -> OriginResult {
+    let request_headers = extract_request_headers(headers);
+
+    let mut response = state
+        .origin_client
+        .fetch(origin, path, query, &request_headers)
+        .await?;
+
+    // A 304 only answers validators (RFC 9110 Section 15.4.5). Origins whose
+    // own cache is confused can send one to a plain GET, which would reach
+    // browsers as a blank page, so the origin is asked once more with its
+    // caches bypassed; a second 304 fails the fetch, leaving stale-if-error
+    // or a 502
+    if response.status_code == StatusCode::NOT_MODIFIED.as_u16() && !is_conditional(headers) {
+        tracing::warn!(
+            origin = %origin,
+            path = %path,
+            "Origin answered an unconditional request with 304, refetching"
+        );
+        response = state
+            .origin_client
+            .fetch_uncached(origin, path, query, &request_headers)
+            .await?;
+        if response.status_code == StatusCode::NOT_MODIFIED.as_u16() {
+            state.metrics.record_stray_not_modified(origin, "rejected");
+            return Err(CdnError::OriginFetch {
+                kind: OriginErrorKind::Other,
+                message: "origin answered an unconditional request with 304".to_string(),
+            });
+        }
+        state.metrics.record_stray_not_modified(origin, "refetched");
+    }
+
+    let status = StatusCode::from_u16(response.status_code).unwrap_or(StatusCode::OK);
+    Ok((response.body, response.headers, status))
+}
+
+/// Whether a request carries validators an origin 304 could answer
+fn is_conditional(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::IF_NONE_MATCH) || headers.contains_key(header::IF_MODIFIED_SINCE)
+}
+
+/// A request's headers without its validators
+///
+/// Fills are for the cache, so they always ask for the whole response; a
+/// client's conditionals are about its own copy.
+fn without_conditionals(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    headers.remove(header::IF_NONE_MATCH);
+    headers.remove(header::IF_MODIFIED_SINCE);
+    headers
+}
+
+fn extract_request_headers(headers: &HeaderMap) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    for (key, value) in headers.iter() {
+        if let Ok(v) = value.to_str() {
+            map.insert(key.to_string(), v.to_string());
+        }
+    }
+    map
+}
+
+fn is_cacheable(status: StatusCode, headers: &ResponseHeaders) -> bool {
+    // Only cache successful responses; a 304 has no body of its own to store
+    if !status.is_success() {
+        return false;
+    }
+
+    // A cookie set for one client must never be replayed to another
+    if headers.contains_key("set-cookie") {
+        return false;
+    }
+
+    // Check Cache-Control header
+    if let Some(cc) = headers.get("cache-control") {
+        let directives = parse_cache_control(cc);
+        return directives.is_cacheable();
+    }
+
+    // Default to cacheable for successful responses
+    true
+}
+
+/// Cache an origin response, returning whether it was stored or queued to be
+fn store_in_cache(
+    state: &Arc<AppState>,
+    origin: &str,
+    slot: FillSlot,
+    body: Bytes,
+    headers: ResponseHeaders,
+    status: StatusCode,
+) -> bool {
+    store_entry(state, origin, slot, body, headers, status, false)
+}
+
+/// Cache an origin HEAD response so repeated HEADs don't reach the origin
+///
+/// The entry's TTL is capped at `cache.head.ttl_secs`; a later GET replaces
+/// it with a full entry.
+fn store_headers_only(
+    state: &Arc<AppState>,
+    origin: &str,
+    slot: FillSlot,
+    headers: ResponseHeaders,
+    status: StatusCode,
+) {
+    store_entry(state, origin, slot, Bytes::new(), headers, status, true);
+}
+
+fn store_entry(
+    state: &Arc<AppState>,
+    origin: &str,
+    slot: FillSlot,
+    body: Bytes,
+    mut headers: ResponseHeaders,
+    status: StatusCode,
+    headers_only: bool,
+) -> bool {
+    let config = &state.config.cache;
+
+    // Origin responses that failed validation are served but never stored
+    if headers.contains_key(VALIDATION_FAILED_HEADER) {
+        return false;
+    }
+
+    // The origin can keep a response out of the CDN without telling clients
+    if let Some(name) = &config.origin_no_cache_header
+        && headers
+            .get(name)
+            .is_some_and(|value| matches!(value.trim(), "1" | "true"))
+    {
+        tracing::debug!(origin = %origin, cache_key = %slot.key(), header = %name, "Origin asked the CDN not to cache");
+        return false;
+    }
+
+    // Nothing would ever be served from a Vary: * entry
+    if headers
+        .get("vary")
+        .is_some_and(|vary| varies_on_everything(vary))
+    {
+        tracing::debug!(origin = %origin, cache_key = %slot.key(), "Response has Vary: *, not caching");
+        state.metrics.record_vary_star_skipped(origin);
+        return false;
+    }
+
+    // Guard against origins sending pathological header blocks
+    let header_count = headers.len();
+    let header_bytes = headers.total_bytes();
+    match headers.enforce_limits(&config.header_limits) {
+        HeaderLimitOutcome::WithinLimits => {}
+        HeaderLimitOutcome::Truncated { dropped } => {
+            tracing::warn!(
+                origin = %origin,
+                cache_key = %slot.key(),
+                header_count,
+                header_bytes,
+                dropped,
+                "Response headers exceeded limits, truncated before caching"
+            );
+            state.metrics.record_header_limit(origin, "truncated");
+        }
+        HeaderLimitOutcome::Uncacheable => {
+            tracing::warn!(
+                origin = %origin,
+                cache_key = %slot.key(),
+                header_count,
+                header_bytes,
+                "Response headers exceeded limits, not caching"
+            );
+            state.metrics.record_header_limit(origin, "uncacheable");
+            return false;
+        }
+    }
+
+    // Parse Cache-Control directives
+    let directives = headers
+        .get("cache-control")
+        .map(|cc| parse_cache_control(cc))
+        .unwrap_or_default();
+
+    // Determine TTL
+    let ttl = state.cache.entry_ttl(
+        &directives,
+        &headers,
+        slot.requested_at_wall(),
+        headers_only,
+    );
+
+    // Dated from when the fetch began, so racing fills keep the newest response
+    let now = slot.requested_at();
+
+    // Generate ETag if not present (there's no body to hash for headers-only entries)
+    let etag = headers.get("etag").cloned().or_else(|| {
+        if headers_only {
+            return None;
+        }
+        let hash = xxh3_64(&body);
+        Some(format!("\"{}\"", BASE64.encode(hash.to_be_bytes())))
+    });
+
+    let entry = CacheEntry {
+        size: body.len(),
+        body,
+        headers: headers.clone(),
+        status_code: status.as_u16(),
+        content_type: headers.get("content-type").cloned(),
+        etag,
+        last_modified: headers.get("last-modified").cloned(),
+        created_at: now,
+        expires_at: now + ttl,
+        expires_at_wall: slot.requested_at_wall() + ttl,
+        stale_if_error_secs: directives.stale_if_error,
+        access_count: 0,
+        last_accessed: now,
+        // Indexed by the cache along with the entry
+        cache_tags: extract_tags(&headers, &config.tags),
+        headers_only,
+        // Measured from the slot when filled
+        fetch_duration: Duration::ZERO,
+        immutable: directives.immutable,
+    };
+
+    state.store_queue.store(slot, entry)
+}
+
+/// Drop origin response headers outside the allowlist in allowlist mode
+///
+/// Applied at serve time, so entries cached before the mode was enabled are
+/// filtered too. With debug logging on, headers outside the allowlist are
+/// counted per name in either mode, so passthrough deployments can see what
+/// allowlist mode would drop.
+fn apply_response_header_policy(state: &AppState, headers: &mut ResponseHeaders) {
+    let security = &state.config.security;
+    let mode = security.response_header_mode;
+    let track = tracing::enabled!(tracing::Level::DEBUG);
+    if mode == ResponseHeaderMode::Passthrough && !track {
+        return;
+    }
+
+    headers.retain(|name, _| {
+        if security.response_header_allowed(name) {
+            return true;
+        }
+        if track {
+            tracing::debug!(header = %name, mode = mode.as_str(), "Response header outside allowlist");
+            state
+                .metrics
+                .record_response_header_dropped(&name.to_ascii_lowercase(), mode.as_str());
+        }
+        mode == ResponseHeaderMode::Passthrough
+    });
+}
+
+/// Drop the headers an origin uses to steer the CDN's cache, which are ours alone
+///
+/// They stay on the stored entry, so a 304 refresh still sees them.
+fn strip_origin_cdn_headers(config: &CacheConfig, headers: &mut ResponseHeaders) {
+    for name in config.origin_control_headers() {
+        headers.remove(&name);
+    }
+}
+
+/// Inject the origin's configured client Cache-Control into response headers
+///
+/// Only fills in a missing Cache-Control unless `client_cache_control_override`
+/// is set. Expires is stripped whenever we inject, since it would otherwise
+/// disagree with the new max-age. This only shapes what clients see; the CDN
+/// TTL is derived from the headers stored at fill time.
+fn apply_client_cache_control(headers: &mut ResponseHeaders, origin: &OriginConfig) {
+    let Some(ref value) = origin.client_cache_control else {
+        return;
+    };
+
+    if headers.contains_key("cache-control") && !origin.client_cache_control_override {
+        return;
+    }
+
+    headers.remove("expires");
+    headers.insert("cache-control", value.clone());
+}
+
+/// Length of the full representation behind a response
+///
+/// The body's length, except for HEAD answered without one (a headers-only
+/// entry or an origin HEAD), where the origin's Content-Length stands in.
+/// `None` when a HEAD has neither.
+fn representation_length(
+    body: &Bytes,
+    headers: &ResponseHeaders,
+    is_head_request: bool,
+) -> Option<u64> {
+    if is_head_request && body.is_empty() {
+        headers
+            .get("content-length")
+            .and_then(|v| v.trim().parse().ok())
+    } else {
+        Some(body.len() as u64)
+    }
+}
+
+fn build_response(
+    body: Bytes,
+    headers: ResponseHeaders,
+    status: StatusCode,
+    cache_headers: CacheHeaders,
+    cache_age_secs: Option<u64>,
+    is_head_request: bool,
+    range_request: Option<&ByteRange>,
+) -> CdnResult<Response> {
+    // 204 and 304 describe no content of their own, so their headers pass as-is
+    let content_length = (!matches!(status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED))
+        .then(|| representation_length(&body, &headers, is_head_request))
+        .flatten();
+
+    // Determine if we're serving a range response; a range is only chosen
+    // once the representation length is known
+    let (final_status, final_body, content_range, final_length) =
+        match (range_request, content_length) {
+            (Some(range), Some(total)) => {
+                // Serve partial content (206); HEAD has nothing to slice
+                let range_body = if is_head_request {
+                    Bytes::new()
+                } else {
+                    extract_range(&body, range)
+                };
+                let content_range = range.content_range_header(total);
+                (
+                    StatusCode::PARTIAL_CONTENT,
+                    range_body,
+                    Some(content_range),
+                    Some(range.length()),
+                )
+            }
+            _ => (status, body, None, content_length),
+        };
+
+    let mut response = Response::builder().status(final_status);
+
+    // Add headers from origin/cache
+    for (key, value) in &headers {
+        // Content-Length is set below from what is actually (or, for HEAD, would be) sent
+        if final_length.is_some() && key.eq_ignore_ascii_case("content-length") {
+            continue;
+        }
+        if let Ok(header_value) = HeaderValue::from_str(value) {
+            response = response.header(key.as_str(), header_value);
+        }
+    }
+
+    // Add CDN-specific headers
+    if let Some(x_cache) = cache_headers.x_cache {
+        response = response.header("X-Cache", x_cache);
+    }
+    // RFC 9211: Cache-Status header - how each cache handled the request
+    if let Some(cache_status) = cache_headers.cache_status {
+        response = response.header("Cache-Status", cache_status);
+    }
+    if let Some(key_hash) = cache_headers.key_hash {
+        response = response.header("X-Cache-Key-Hash", key_hash);
+    }
+    response = response.header("X-CDN", "Screaming-Eagle");
+
+    // RFC 9110: Date header - indicates when the message was generated
+    let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    response = response.header(header::DATE, date);
+
+    // RFC 9110: Via header - identifies intermediate proxies
+    response = response.header(header::VIA, "1.1 screaming-eagle");
+
+    // RFC 9111: Age header - indicates time in cache (only for cached responses)
+    if let Some(age) = cache_age_secs {
+        response = response.header(header::AGE, age.to_string());
+    }
+
+    // RFC 9110: Accept-Ranges header - indicate we support byte ranges
+    response = response.header(header::ACCEPT_RANGES, "bytes");
+
+    // RFC 9110: Content-Range header for partial responses
+    if let Some(cr) = content_range {
+        response = response.header(header::CONTENT_RANGE, cr);
+    }
+
+    // RFC 9110 Section 8.6: HEAD carries the Content-Length a GET would have
+    if let Some(length) = final_length {
+        response = response.header(header::CONTENT_LENGTH, length.to_string());
+    }
+
+    // For HEAD requests, return empty body but keep the headers a GET would get
+    let response_body = if is_head_request {
+        Body::empty()
+    } else {
+        Body::from(final_body)
+    };
+
+    response
+        .body(response_body)
+        .map_err(|e| CdnError::Internal(format!("Failed to build response: {}", e)))
+}
+
+/// Build a 416 Range Not Satisfiable response
+fn build_range_not_satisfiable_response(content_length: u64) -> CdnResult<Response> {
+    let mut response = Response::builder().status(StatusCode::RANGE_NOT_SATISFIABLE);
+
+    // RFC 9110: Content-Range header with unsatisfied-range
+    response = response.header(header::CONTENT_RANGE, format!("bytes */{}", content_length));
+
+    // Add standard headers
+    let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    response = response.header(header::DATE, date);
+    response = response.header(header::VIA, "1.1 screaming-eagle");
+    response = response.header("X-CDN", "Screaming-Eagle");
+    response = response.header(header::ACCEPT_RANGES, "bytes");
+
+    response
+        .body(Body::empty())
+        .map_err(|e| CdnError::Internal(format!("Failed to build response: {}", e)))
+}
+
+/// CDN routes: `/{origin}/{*path}`, and `/{path}` for the single or routed origin
+///
+/// A root catch-all would conflict with the origin route, and anything deeper
+/// than one segment matches that route anyway. Both support GET and HEAD
+/// (RFC 9110). PURGE isn't a standard method, so when enabled it's taken
+/// from the method fallback, behind its own auth.
+pub fn cdn_routes(state: &Arc<AppState>, admin_auth: Arc<AdminAuth>) -> Router<Arc<AppState>> {
+    let origin_route = get(cdn_handler).head(cdn_handler);
+    let root_route = get(root_cdn_handler).head(root_cdn_handler);
+
+    if !state.config.admin.purge_method.enabled {
+        return Router::new()
+            .route("/{origin}/{*path}", origin_route)
+            .route("/{path}", root_route);
+    }
+
+    Router::new()
+        .route(
+            "/{origin}/{*path}",
+            origin_route.fallback(purge_resource_handler),
+        )
+        .route("/{path}", root_route.fallback(root_purge_resource_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.audit.clone(),
+            audit_middleware,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            admin_auth,
+            purge_method_auth_middleware,
+        ))
+}
+
+// Catch-all handler for root origin requests - supports both GET and HEAD
+pub async fn root_cdn_handler(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    Path(path): Path<String>,
+    Query(query): Query<CdnQuery>,
+    routed: Option<Extension<RoutedOrigin>>,
+    headers: HeaderMap,
+) -> Result<Response, CdnError> {
+    // A routing rule's origin wins; otherwise use the default if only one is configured
+    let origin = match routed {
+        Some(Extension(RoutedOrigin(origin))) => origin,
+        None => {
+            let mut origins = state.origin.origin_names();
+            if origins.len() != 1 {
+                return Err(CdnError::InvalidRequest(
+                    "Origin must be specified in path: /<origin>/<path>".to_string(),
+                ));
+            }
+            origins.remove(0)
+        }
+    };
+
+    cdn_handler(
+        State(state),
+        method,
+        Path((origin, path)),
+        Query(query),
+        None,
+        headers,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_breaker::CircuitBreakerConfig;
+    use crate::config::CacheKeyConfig;
+    use crate::dictionary::Dictionaries;
+    use crate::security::TrustedProxies;
+    use crate::test_support::{self, FakeCache, FakeOrigin};
+    use std::time::Duration;
+
+    fn test_origin(client_cache_control: Option<&str>, override_origin: bool) -> OriginConfig {
+        OriginConfig {
+            url: "http://localhost:9000".to_string(),
+            path_prefix: None,
+            strip_path_prefix: None,
+            host_header: None,
+            timeout_secs: 30,
+            total_timeout_secs: None,
+            connect_timeout_secs: None,
+            time_to_first_byte_secs: None,
+            max_retries: 0,
+            headers: HashMap::new(),
+            health_check_path: None,
+            health_check_interval_secs: 30,
+            health_check_timeout_secs: 5,
+            health_check_mode: Default::default(),
+            health_check_use_main_pool: false,
+            selftest_path: None,
+            preconnect_path: None,
+            client_cache_control: client_cache_control.map(String::from),
+            client_cache_control_override: override_origin,
+            error_pages_dir: None,
+            tls: Default::default(),
+            http: Default::default(),
+            pool: Default::default(),
+            request_compression: true,
+            compress_responses: true,
+            cookie_rewrite: Default::default(),
+            personalized_bypass: Default::default(),
+            maintenance: Default::default(),
+            validation: Default::default(),
+            proxy_url: None,
+        }
+    }
+
+    fn test_state(config: Config) -> Arc<AppState> {
+        test_state_forwarding(config, &[])
+    }
+
+    /// Test state whose origin fetcher also forwards `forwarded` request headers
+    fn test_state_forwarding(config: Config, forwarded: &[&str]) -> Arc<AppState> {
+        Arc::new(test_app_state(config, forwarded))
+    }
+
+    /// Unshared state, for tests that swap in fakes before wrapping it
+    fn test_app_state(config: Config, forwarded: &[&str]) -> AppState {
+        let cache = Arc::new(Cache::new(config.cache.clone()));
+        let metrics = Arc::new(Metrics::new().with_path_series(&config.observability.metrics));
+        let faults = Arc::new(FaultInjector::new(config.chaos.clone()));
+        let origin = Arc::new(
+            OriginFetcher::with_pool_config(config.origins.clone(), config.connection_pool.clone())
+                .unwrap()
+                .with_forwarded_headers(forwarded.iter().map(|h| h.to_string()))
+                .with_kept_response_headers(config.cache.origin_headers_read())
+                .with_error_policy(config.origin_errors.clone())
+                .with_fault_injector(faults.clone())
+                .with_metrics(metrics.clone()),
+        );
+        let circuit_breaker = Arc::new(CircuitBreakerManager::new(CircuitBreakerConfig::default()));
+        let health_checker = Arc::new(HealthChecker::new(config.origins.clone()));
+        let origins = Arc::new(
+            OriginRegistry::new(
+                &config,
+                origin.clone(),
+                health_checker.clone(),
+                circuit_breaker.clone(),
+                cache.clone(),
+            )
+            .unwrap(),
+        );
+        let purges = Arc::new(
+            PurgeDeduplicator::new(config.admin.purge_dedup.clone()).with_metrics(metrics.clone()),
+        );
+        let purge_guard = Arc::new(
+            PurgeGuard::new(config.admin.purge_safety.clone()).with_metrics(metrics.clone()),
+        );
+        let store_queue = Arc::new(
+            StoreQueue::new(config.cache.store_queue.clone(), cache.clone())
+                .with_metrics(metrics.clone()),
+        );
+        let revalidations = Arc::new(
+            RevalidationLimiter::new(config.cache.max_concurrent_revalidations)
+                .with_metrics(metrics.clone()),
+        );
+        AppState {
+            content_cache: cache.clone(),
+            origin_client: origin.clone(),
+            cache,
+            origin,
+            metrics,
+            rate_limiter: Arc::new(
+                RateLimiter::new(RateLimitConfig::default())
+                    .with_trusted_proxies(TrustedProxies::new(&config.server.trusted_proxies)),
+            ),
+            circuit_breaker,
+            health_checker,
+            coalescer: Arc::new(RequestCoalescer::new(100)),
+            coalesce_enabled: config.coalesce.enabled,
+            started_at: Utc::now(),
+            jobs: Arc::new(JobRegistry::new()),
+            edge: Arc::new(
+                EdgeProcessor::from_config(&config.edge, &config.regex).with_dictionaries(
+                    Arc::new(Dictionaries::load(&config.edge.dictionaries).unwrap()),
+                ),
+            ),
+            recent: Arc::new(RecentRequests::new(config.cache.recent.clone())),
+            origins,
+            faults,
+            audit: Arc::new(AuditLog::new(config.admin.audit.clone())),
+            purges,
+            purge_guard,
+            store_queue,
+            revalidations,
+            shutdown: tokio::sync::watch::Sender::new(false),
+            config: Arc::new(config),
+        }
+    }
+
+    #[test]
+    fn test_client_cache_control_fills_missing() {
+        let origin = test_origin(Some("public, max-age=300"), false);
+        let mut headers = ResponseHeaders::new();
+        headers.insert("content-type", "text/css");
+        headers.insert("expires", "Thu, 01 Jan 1970 00:00:00 GMT");
+
+        apply_client_cache_control(&mut headers, &origin);
+
+        assert_eq!(headers.get("cache-control").unwrap(), "public, max-age=300");
+        assert!(!headers.contains_key("expires"));
+        assert_eq!(headers.get("content-type").unwrap(), "text/css");
+    }
+
+    #[test]
+    fn test_client_cache_control_respects_origin_unless_forced() {
+        let mut headers = ResponseHeaders::new();
+        headers.insert("cache-control", "no-cache");
+
+        apply_client_cache_control(&mut headers, &test_origin(Some("max-age=300"), false));
+        assert_eq!(headers.get("cache-control").unwrap(), "no-cache");
+
+        apply_client_cache_control(&mut headers, &test_origin(Some("max-age=300"), true));
+        assert_eq!(headers.get("cache-control").unwrap(), "max-age=300");
+    }
+
+    #[test]
+    fn test_client_cache_control_unset_is_noop() {
+        let mut headers = ResponseHeaders::new();
+        apply_client_cache_control(&mut headers, &test_origin(None, true));
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn test_client_cache_control_does_not_change_cdn_ttl() {
+        let mut config = Config::default();
+        config.cache.default_ttl_secs = 3600;
+        let origin = test_origin(Some("no-store"), true);
+        config.origins.insert("assets".to_string(), origin.clone());
+        let state = test_state(config);
+
+        // Origin sent no Cache-Control; CDN should fall back to its default TTL
+        let origin_headers = ResponseHeaders::new();
+        store_in_cache(
+            &state,
+            "assets",
+            state.cache.reserve("assets/app.css"),
+            Bytes::from("body"),
+            origin_headers,
+            StatusCode::OK,
+        );
+
+        let (entry, _) = state.cache.get("assets/app.css").unwrap();
+        let mut client_headers = entry.headers.clone();
+        apply_client_cache_control(&mut client_headers, &origin);
+
+        // Clients see the override...
+        assert_eq!(client_headers.get("cache-control").unwrap(), "no-store");
+        // ...but the stored entry keeps origin headers and the CDN default TTL
+        assert!(!entry.headers.contains_key("cache-control"));
+        let ttl = entry.expires_at - entry.created_at;
+        assert_eq!(ttl, Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn test_store_headers_only_caps_ttl() {
+        let mut config = Config::default();
+        config.cache.head.ttl_secs = 30;
+        let state = test_state(config);
+
+        let mut headers = ResponseHeaders::new();
+        headers.insert("cache-control", "max-age=3600");
+        headers.insert("content-length", "5000000000");
+        store_headers_only(
+            &state,
+            "media",
+            state.cache.reserve("media/movie.mp4"),
+            headers,
+            StatusCode::OK,
+        );
+
+        // Only HEAD lookups may use it
+        assert!(state.cache.get("media/movie.mp4").is_none());
+        let (entry, _) = state.cache.get_head("media/movie.mp4").unwrap();
+        assert!(entry.headers_only);
+        assert!(entry.body.is_empty());
+        assert!(entry.etag.is_none());
+        assert_eq!(entry.headers.get("content-length").unwrap(), "5000000000");
+        assert_eq!(entry.expires_at - entry.created_at, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_origin_ttl_header_overrides_cache_control() {
+        let mut config = Config::default();
+        config.cache.max_ttl_secs = 600;
+        config.cache.origin_ttl_header = Some("X-CDN-TTL".to_string());
+        let state = test_state(config);
+        let stored_ttl = |key: &str, origin_headers: &[(&str, &str)]| {
+            let mut headers = ResponseHeaders::new();
+            for (name, value) in origin_headers {
+                headers.insert(*name, *value);
+            }
+            store_in_cache(
+                &state,
+                "web",
+                state.cache.reserve(key),
+                Bytes::from("body"),
+                headers,
+                StatusCode::OK,
+            );
+            let (entry, _) = state.cache.get(key).unwrap();
+            entry.expires_at - entry.created_at
+        };
+
+        let max_age = ("cache-control", "public, max-age=300, s-maxage=400");
+        assert_eq!(
+            stored_ttl("web/a", &[max_age, ("x-cdn-ttl", "15")]),
+            Duration::from_secs(15)
+        );
+        // Clamped to max_ttl like any other TTL
+        assert_eq!(
+            stored_ttl("web/b", &[max_age, ("x-cdn-ttl", "86400")]),
+            Duration::from_secs(600)
+        );
+        // A value that isn't a number of seconds leaves Cache-Control in charge
+        assert_eq!(
+            stored_ttl("web/c", &[max_age, ("x-cdn-ttl", "soon")]),
+            Duration::from_secs(400)
+        );
+
+        // It sets how long to cache, not whether to: no-store still wins
+        let mut no_store = ResponseHeaders::new();
+        no_store.insert("cache-control", "no-store");
+        no_store.insert("x-cdn-ttl", "15");
+        assert!(!is_cacheable(StatusCode::OK, &no_store));
+    }
+
+    #[tokio::test]
+    async fn test_origin_cdn_headers_are_stripped_and_no_cache_skips_the_store() {
+        let (addr, _requests) = spawn_test_origin(|head| {
+            let extra = if head.contains("/live") {
+                "x-cdn-no-cache: 1\r\n"
+            } else {
+                "x-cdn-ttl: 15\r\n"
+            };
+            format!(
+                "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\n{}content-length: 2\r\nconnection: close\r\n\r\nok",
+                extra
+            )
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        config.cache.origin_ttl_header = Some("X-CDN-TTL".to_string());
+        config.cache.origin_no_cache_header = Some("X-CDN-No-Cache".to_string());
+        let state = test_state(config);
+
+        for expected in ["MISS", "HIT"] {
+            let (response, _) = get(&state, "/page", HeaderMap::new()).await;
+            assert_eq!(response.headers().get("x-cache").unwrap(), expected);
+            assert!(!response.headers().contains_key("x-cdn-ttl"));
+            assert_eq!(response.headers()["cache-control"], "max-age=60");
+        }
+
+        for _ in 0..2 {
+            let (response, body) = get(&state, "/live", HeaderMap::new()).await;
+            assert_eq!(response.headers().get("x-cache").unwrap(), "MISS");
+            assert_eq!(&body[..], b"ok");
+            assert!(!response.headers().contains_key("x-cdn-no-cache"));
+            // Browsers are still told what the origin said
+            assert_eq!(response.headers()["cache-control"], "max-age=60");
+        }
+        assert_eq!(state.cache.stats().total_entries, 1);
+    }
+
+    /// Plain HTTP/1.1 origin answering each request with `respond(request head)`
+    ///
+    /// Each request's head (lowercased) is also sent on the returned channel.
+    async fn spawn_test_origin<R: AsRef<[u8]> + Send + 'static>(
+        respond: impl Fn(&str) -> R + Send + 'static,
+    ) -> (SocketAddr, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                let response = respond(&request);
+                let _ = tx.send(request);
+                stream.write_all(response.as_ref()).await.unwrap();
+            }
+        });
+        (addr, rx)
+    }
+
+    /// Config with one HTTP/1.1 origin named "web" at `addr`
+    fn config_with_origin(addr: SocketAddr) -> Config {
+        let mut config = Config::default();
+        config.connection_pool.http2_enabled = false;
+        let mut origin = test_origin(None, false);
+        origin.url = format!("http://{}", addr);
+        config.origins.insert("web".to_string(), origin);
+        config
+    }
+
+    async fn get(state: &Arc<AppState>, path: &str, headers: HeaderMap) -> (Response, Bytes) {
+        send(state, Method::GET, path, headers).await
+    }
+
+    async fn send(
+        state: &Arc<AppState>,
+        method: Method,
+        path: &str,
+        headers: HeaderMap,
+    ) -> (Response, Bytes) {
+        let response = serve_cdn_request(
+            state.clone(),
+            method,
+            "web".to_string(),
+            path.to_string(),
+            CdnQuery {
+                params: HashMap::new(),
+            },
+            headers,
+        )
+        .await
+        .unwrap();
+        let (parts, body) = response.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        (Response::from_parts(parts, Body::empty()), body)
+    }
+
+    async fn purge_json(
+        state: &Arc<AppState>,
+        claims: Option<PurgeTokenClaims>,
+        request: PurgeRequest,
+    ) -> (Response, PurgeResponse) {
+        let response =
+            purge_cache(State(state.clone()), claims.map(Extension), Json(request)).await;
+        let (parts, body) = response.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        (
+            Response::from_parts(parts, Body::empty()),
+            serde_json::from_slice(&body).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_origin_sla_counts_live_fetches() {
+        let (addr, _requests) = spawn_test_origin(|request| {
+            let status = if request.starts_with("get /bad") {
+                "503 Service Unavailable"
+            } else {
+                "200 OK"
+            };
+            format!(
+                "HTTP/1.1 {}\r\ncache-control: no-store\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok",
+                status
+            )
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+
+        for path in ["ok", "ok", "ok", "bad"] {
+            get(&state, path, HeaderMap::new()).await;
+        }
+
+        let Json(sla) = origin_sla(State(state.clone()), Path("web".to_string()))
+            .await
+            .unwrap();
+        let hour = sla.availability.windows["1h"];
+        assert_eq!((hour.successes, hour.failures), (3, 1));
+        assert_eq!(hour.availability_percent, Some(75.0));
+        assert_eq!(sla.availability.consecutive_failures, 1);
+
+        let Json(health) =
+            origin_health_status(State(state.clone()), Query(Default::default())).await;
+        assert_eq!(health.availability["web"], sla.availability);
+
+        let unknown = origin_sla(State(state), Path("nope".to_string())).await;
+        assert_eq!(unknown.unwrap_err().status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_compression_opt_outs_mark_responses() {
+        let (addr, _requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok"
+        })
+        .await;
+        let marked = |response: &Response| response.extensions().get::<SkipCompression>().is_some();
+
+        let mut config = config_with_origin(addr);
+        config.compression = toml::from_str(
+            r#"
+            [[bypass]]
+            path_pattern = "^/raw/"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let state = test_state(config.clone());
+        assert!(!marked(&get(&state, "page.txt", HeaderMap::new()).await.0));
+        assert!(marked(
+            &get(&state, "raw/page.txt", HeaderMap::new()).await.0
+        ));
+
+        config.origins.get_mut("web").unwrap().compress_responses = false;
+        let state = test_state(config);
+        assert!(marked(&get(&state, "page.txt", HeaderMap::new()).await.0));
+    }
+
+    #[tokio::test]
+    async fn test_request_phases_follow_the_cache() {
+        let (addr, _requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok"
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+        let phases = |state: Arc<AppState>| async move {
+            let timing = Arc::new(request_timing::RequestTiming::start());
+            request_timing::scope(timing.clone(), get(&state, "page", HeaderMap::new())).await;
+            timing
+                .phases()
+                .into_iter()
+                .map(|(phase, _)| phase)
+                .collect::<Vec<_>>()
+        };
+
+        let miss = [Phase::Queue, Phase::Cache, Phase::Origin, Phase::Transform];
+        assert_eq!(phases(state.clone()).await, miss);
+        let hit = [Phase::Queue, Phase::Cache, Phase::Transform];
+        assert_eq!(phases(state).await, hit);
+    }
+
+    #[tokio::test]
+    async fn test_removed_origin_finishes_in_flight_requests() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Holds its response until released, keeping a fetch in flight
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let started = Arc::new(tokio::sync::Notify::new());
+        let release = Arc::new(tokio::sync::Notify::new());
+        let (origin_started, origin_release) = (started.clone(), release.clone());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            origin_started.notify_one();
+            origin_release.notified().await;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok")
+                .await
+                .unwrap();
+        });
+
+        let state = test_state(config_with_origin(addr));
+        let in_flight = tokio::spawn({
+            let state = state.clone();
+            async move { get(&state, "slow", HeaderMap::new()).await }
+        });
+        started.notified().await;
+
+        state.origins.remove("web").unwrap();
+        let rejected = serve_cdn_request(
+            state.clone(),
+            Method::GET,
+            "web".to_string(),
+            "other".to_string(),
+            CdnQuery {
+                params: HashMap::new(),
+            },
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(rejected.status_code(), StatusCode::NOT_FOUND);
+
+        release.notify_one();
+        let (response, body) = in_flight.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body, "ok");
+    }
+
+    #[tokio::test]
+    async fn test_set_cookie_rewritten_per_origin() {
+        use crate::config::SameSite;
+
+        let (addr, mut requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\nset-cookie: sid=abc; Domain=app.internal; HttpOnly\r\nset-cookie: ab=1; Domain=partner.example\r\ncache-control: max-age=60\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok".to_string()
+        })
+        .await;
+        let set_cookies = |response: &Response| {
+            response
+                .headers()
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .map(|v| v.to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // Dropped unless the origin opts in
+        let config = config_with_origin(addr);
+        let (response, _) = get(&test_state(config.clone()), "account", HeaderMap::new()).await;
+        assert!(set_cookies(&response).is_empty());
+
+        let mut config = config;
+        let origin = config.origins.get_mut("web").unwrap();
+        origin.cookie_rewrite.pass_through = true;
+        origin
+            .cookie_rewrite
+            .domain_map
+            .insert("app.internal".to_string(), "example.com".to_string());
+        origin.cookie_rewrite.force_samesite = Some(SameSite::Strict);
+        origin.cookie_rewrite.force_secure = true;
+        let state = test_state(config);
+
+        for _ in 0..2 {
+            let (response, _) = get(&state, "account", HeaderMap::new()).await;
+            assert_eq!(
+                set_cookies(&response),
+                [
+                    "sid=abc; Domain=example.com; HttpOnly; SameSite=Strict; Secure",
+                    "ab=1; Domain=partner.example; SameSite=Strict; Secure",
+                ]
+            );
+        }
+
+        // Responses with cookies are never stored, despite max-age
+        assert_eq!(state.cache.stats().total_entries, 0);
+        let mut fetched = 0;
+        while requests.try_recv().is_ok() {
+            fetched += 1;
+        }
+        assert_eq!(fetched, 3);
+    }
+
+    #[tokio::test]
+    async fn test_stale_revalidation_refreshes_on_304() {
+        let (addr, mut requests) = spawn_test_origin(|request| {
+            if request.contains("if-none-match: \"v1\"") {
+                "HTTP/1.1 304 Not Modified\r\ncache-control: max-age=600\r\netag: \"v1\"\r\ncontent-type: application/json\r\nconnection: close\r\n\r\n".to_string()
+            } else {
+                "HTTP/1.1 200 OK\r\ncache-control: max-age=0\r\netag: \"v1\"\r\ncontent-type: text/css\r\ncontent-length: 4\r\nconnection: close\r\n\r\nbody".to_string()
+            }
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+
+        // The client's own validator must not be what reaches the origin
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"stale\""));
+        get(&state, "site.css", HeaderMap::new()).await;
+        let (response, _) = get(&state, "site.css", headers).await;
+        assert_eq!(response.headers().get("x-cache").unwrap(), "STALE");
+
+        let mut seen = Vec::new();
+        while seen.len() < 2 {
+            seen.push(requests.recv().await.unwrap());
+        }
+        assert!(!seen[0].contains("if-none-match"));
+        assert!(seen[1].contains("if-none-match: \"v1\""));
+        assert!(!seen[1].contains("stale"));
+
+        // The 304 refreshed the entry in place, without touching the body
+        let mut refreshed = None;
+        for _ in 0..50 {
+            let (response, body) = get(&state, "site.css", HeaderMap::new()).await;
+            if response.headers().get("x-cache").unwrap() == "HIT" {
+                refreshed = Some((response, body));
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let (response, body) = refreshed.expect("entry was not refreshed");
+        assert_eq!(body, Bytes::from("body"));
+        assert_eq!(
+            response.headers().get("cache-control").unwrap(),
+            "max-age=600"
+        );
+        assert_eq!(response.headers().get("content-type").unwrap(), "text/css");
+    }
+
+    #[tokio::test]
+    async fn test_early_refresh_revalidates_fresh_hit() {
+        let (addr, mut requests) = spawn_test_origin(|request| {
+            if request.contains("if-none-match: \"v1\"") {
+                "HTTP/1.1 304 Not Modified\r\ncache-control: max-age=600\r\netag: \"v1\"\r\nconnection: close\r\n\r\n".to_string()
+            } else {
+                "HTTP/1.1 200 OK\r\ncache-control: max-age=600\r\netag: \"v1\"\r\ncontent-length: 4\r\nconnection: close\r\n\r\nbody".to_string()
+            }
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        // A beta this large makes every hit's draw fire
+        config.cache.early_refresh.enabled = true;
+        config.cache.early_refresh.beta = 1e12;
+        let state = test_state(config);
+
+        get(&state, "app.js", HeaderMap::new()).await;
+        assert!(!requests.recv().await.unwrap().contains("if-none-match"));
+
+        // Served from cache while a conditional refresh goes out behind it
+        let (response, body) = get(&state, "app.js", HeaderMap::new()).await;
+        assert_eq!(response.headers().get("x-cache").unwrap(), "HIT");
+        assert_eq!(body, Bytes::from("body"));
+        assert!(
+            requests
+                .recv()
+                .await
+                .unwrap()
+                .contains("if-none-match: \"v1\"")
+        );
+        assert_eq!(state.cache.stats().early_refreshes, 1);
+    }
+
+    #[tokio::test]
+    async fn test_large_range_miss_is_passed_through() {
+        // HEAD reports a 1 GB object, GET answers with a 206
+        let (addr, mut requests) = spawn_test_origin(|request| {
+            if request.starts_with("head") {
+                "HTTP/1.1 200 OK\r\ncontent-length: 1000000000\r\ncache-control: max-age=60\r\nconnection: close\r\n\r\n".to_string()
+            } else {
+                "HTTP/1.1 206 Partial Content\r\ncontent-range: bytes 0-3/1000000000\r\ncontent-length: 4\r\nconnection: close\r\n\r\nabcd".to_string()
+            }
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_static("bytes=0-3"));
+
+        for _ in 0..2 {
+            let (response, body) = get(&state, "movie.mp4", headers.clone()).await;
+            assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(
+                response.headers().get(header::CONTENT_RANGE).unwrap(),
+                "bytes 0-3/1000000000"
+            );
+            assert_eq!(body, Bytes::from("abcd"));
+        }
+
+        // The size is probed once and remembered; the range goes upstream each time
+        let mut seen = Vec::new();
+        while let Ok(request) = requests.try_recv() {
+            seen.push(request);
+        }
+        assert_eq!(seen.len(), 3);
+        assert!(seen[0].starts_with("head /movie.mp4"));
+        for get in &seen[1..] {
+            assert!(get.starts_with("get /movie.mp4"));
+            assert!(get.contains("range: bytes=0-3"));
+            assert!(get.contains("accept-encoding: identity"));
+        }
+        assert_eq!(state.cache.stats().total_size_bytes, 0);
+    }
+
+    async fn start_warm(
+        state: &Arc<AppState>,
+        urls: &[&str],
+        params: WarmQuery,
+    ) -> (StatusCode, Bytes) {
+        let request = WarmCacheRequest {
+            urls: urls
+                .iter()
+                .map(|url| WarmTarget::from(url.to_string()))
+                .collect(),
+        };
+        let response = warm_cache(State(state.clone()), Query(params), Json(request)).await;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, body)
+    }
+
+    async fn finished_warm_job(state: &Arc<AppState>, id: &str) -> JobStatus {
+        for _ in 0..100 {
+            let Json(job) = warm_job_status(State(state.clone()), Path(id.to_string()))
+                .await
+                .unwrap();
+            if job.finished_at.is_some() {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("warm job {} didn't finish", id);
+    }
+
+    #[tokio::test]
+    async fn test_warm_runs_as_a_job_unless_sync() {
+        let (addr, _requests) = spawn_test_origin(|request| {
+            if request.starts_with("get /missing ") {
+                "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+            } else {
+                "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok"
+            }
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+
+        let (status, body) =
+            start_warm(&state, &["/web/a", "/web/missing"], WarmQuery::default()).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let accepted: CacheImportResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(accepted.total, 2);
+
+        let job = finished_warm_job(&state, &accepted.job_id).await;
+        assert_eq!(job.kind, "warm");
+        assert_eq!(job.state, "completed");
+        assert_eq!((job.succeeded, job.failed, job.remaining), (1, 1, 0));
+        assert_eq!(job.results.len(), 2);
+        let failed = job
+            .results
+            .iter()
+            .find(|result| result["url"] == "web/missing")
+            .unwrap();
+        assert_eq!(failed["success"], false);
+        let (response, _) = get(&state, "a", HeaderMap::new()).await;
+        assert_eq!(response.headers().get("x-cache").unwrap(), "HIT");
+
+        // The old inline behaviour, for scripts that want the results at once
+        let sync = WarmQuery {
+            sync: true,
+            concurrency: None,
+        };
+        let (status, body) = start_warm(&state, &["/web/b"], sync).await;
+        assert_eq!(status, StatusCode::OK);
+        let response: WarmCacheResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!((response.warmed, response.failed), (1, 0));
+        assert_eq!(response.results[0].url, "web/b");
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_warm_job_starts_no_more_urls() {
+        let (addr, mut requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok"
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+
+        // The job can't start until this task yields, so it's cancelled first
+        let params = WarmQuery {
+            sync: false,
+            concurrency: Some(1),
+        };
+        let (_, body) = start_warm(&state, &["/web/a", "/web/b", "/web/c"], params).await;
+        let accepted: CacheImportResponse = serde_json::from_slice(&body).unwrap();
+        let Json(cancelling) = cancel_warm_job(State(state.clone()), Path(accepted.job_id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(cancelling.state, "cancelling");
+
+        let job = finished_warm_job(&state, &accepted.job_id).await;
+        assert_eq!(job.state, "cancelled");
+        assert_eq!((job.completed, job.remaining), (0, 3));
+        assert!(requests.try_recv().is_err());
+
+        // Cancelling again changes nothing
+        let Json(again) = cancel_warm_job(State(state.clone()), Path(accepted.job_id))
+            .await
+            .unwrap();
+        assert_eq!(again.state, "cancelled");
+
+        let unknown = warm_job_status(State(state.clone()), Path("missing".to_string())).await;
+        assert!(matches!(unknown, Err(CdnError::NotFound(_))));
+        let unknown = cancel_warm_job(State(state), Path("missing".to_string())).await;
+        assert!(matches!(unknown, Err(CdnError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_range_warming_serves_ranges_without_the_whole_object() {
+        // Ranges get a 206 of a 10-byte object; plain GETs get all of it
+        let (addr, mut requests) = spawn_test_origin(|request| {
+            if request.contains("range: bytes=0-3") {
+                "HTTP/1.1 206 Partial Content\r\ncontent-range: bytes 0-3/10\r\ncontent-length: 4\r\ncache-control: max-age=60\r\nconnection: close\r\n\r\nabcd".to_string()
+            } else {
+                "HTTP/1.1 200 OK\r\ncontent-length: 10\r\ncache-control: max-age=60\r\nconnection: close\r\n\r\nabcdefghij".to_string()
+            }
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+
+        let request: WarmCacheRequest = serde_json::from_value(serde_json::json!({
+            "urls": [{"url": "/web/video.mp4", "ranges": ["0-3"]}, "/web/poster.jpg"]
+        }))
+        .unwrap();
+        let response = warm_urls(&state, &request.urls).await;
+        assert_eq!((response.warmed, response.failed), (2, 0));
+        assert_eq!(response.results[0].range.as_deref(), Some("0-3"));
+        assert_eq!(response.results[1].range, None);
+        let stats = state.cache.stats();
+        assert_eq!(stats.range_entries, 1);
+        assert_eq!(stats.range_size_bytes, 4);
+
+        // Warming the range again finds it cached
+        let request = WarmCacheRequest {
+            urls: vec![WarmTarget::Ranges {
+                url: "/web/video.mp4".to_string(),
+                ranges: vec!["1-2".to_string()],
+            }],
+        };
+        let response = warm_urls(&state, &request.urls).await;
+        assert!(response.results[0].cached);
+
+        // A Range inside the warmed part is a hit
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_static("bytes=1-2"));
+        let (response, body) = get(&state, "video.mp4", headers).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers().get("x-cache").unwrap(), "HIT");
+        assert_eq!(
+            response.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes 1-2/10"
+        );
+        assert_eq!(body, Bytes::from("bc"));
+
+        // One outside it falls back to the whole object
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_static("bytes=2-6"));
+        let (response, body) = get(&state, "video.mp4", headers).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers().get("x-cache").unwrap(), "MISS");
+        assert_eq!(body, Bytes::from("cdefg"));
+
+        let mut seen = Vec::new();
+        while let Ok(request) = requests.try_recv() {
+            seen.push(request);
+        }
+        // The fallback probes the object's size before fetching it whole
+        assert_eq!(seen.len(), 4);
+        assert!(seen[0].contains("range: bytes=0-3"));
+        assert!(seen[2].starts_with("head /video.mp4"));
+        assert!(seen[3].starts_with("get /video.mp4"));
+        assert!(!seen[3].contains("range:"));
+
+        // Purging the object's key removes its parts too
+        let key = CacheKeyBuilder::new("web", "video.mp4", &state.config.cache.key).lookup_key();
+        let (_, purged) = purge_json(
+            &state,
+            None,
+            PurgeRequest {
+                keys: vec![key],
+                prefix: None,
+                all: false,
+                tag: None,
+                key_hashes: Vec::new(),
+                force: false,
+            },
+        )
+        .await;
+        assert_eq!(purged.purged_count, 1);
+        assert_eq!(state.cache.stats().range_entries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_device_type_keys_cache_and_reaches_origin() {
+        // The origin echoes the device type it was told about
+        let (addr, mut requests) = spawn_test_origin(|request| {
+            let device = request
+                .lines()
+                .find_map(|line| line.strip_prefix("x-device-type: "))
+                .unwrap_or("none")
+                .trim()
+                .to_string();
+            format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\ncache-control: max-age=60\r\nconnection: close\r\n\r\n{}",
+                device.len(),
+                device
+            )
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        config.device_detection.enabled = true;
+        config.cache.key.include_headers = vec!["X-Device-Type".to_string()];
+        let state = test_state_forwarding(config.clone(), &[DEVICE_TYPE_HEADER]);
+        assert!(
+            config
+                .forwarded_headers()
+                .contains(&DEVICE_TYPE_HEADER.to_string())
+        );
+
+        let request = |ua: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::USER_AGENT, ua.parse().unwrap());
+            // Clients can't pick their own variant
+            headers.insert(DEVICE_TYPE_HEADER, HeaderValue::from_static("desktop"));
+            headers
+        };
+        let iphone = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) Mobile/15E148";
+        let pixel =
+            "Mozilla/5.0 (Linux; Android 14; Pixel 8) Chrome/124.0.0.0 Mobile Safari/537.36";
+        let googlebot = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+
+        let (_, body) = get(&state, "index.html", request(iphone)).await;
+        assert_eq!(body, Bytes::from("mobile"));
+        // A different phone UA shares the mobile entry
+        let (response, body) = get(&state, "index.html", request(pixel)).await;
+        assert_eq!(body, Bytes::from("mobile"));
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        let (_, body) = get(&state, "index.html", request(googlebot)).await;
+        assert_eq!(body, Bytes::from("bot"));
+
+        let mut fetched = 0;
+        while requests.try_recv().is_ok() {
+            fetched += 1;
+        }
+        assert_eq!(fetched, 2);
+        let output = state.metrics.gather();
+        assert!(output.contains(r#"cdn_device_requests_total{device="mobile"} 2"#));
+        assert!(output.contains(r#"cdn_device_requests_total{device="bot"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_endpoints() {
+        let state = test_state(Config::default());
+
+        let update = RateLimitUpdate {
+            burst_size: Some(500),
+            ..Default::default()
+        };
+        let Json(status) = update_rate_limit(State(state.clone()), Json(update))
+            .await
+            .unwrap();
+        assert_eq!(status.config.burst_size, 500);
+        assert_eq!(status.stats.tracked_clients, 0);
+
+        let update = RateLimitUpdate {
+            requests_per_window: Some(0),
+            ..Default::default()
+        };
+        let result = update_rate_limit(State(state.clone()), Json(update)).await;
+        assert!(matches!(result, Err(CdnError::InvalidRequest(_))));
+
+        let Json(status) = rate_limit_status(State(state.clone())).await;
+        assert_eq!(status.config.burst_size, 500);
+        let Json(info) = info(State(state)).await;
+        assert_eq!(info.config.rate_limit.burst_size, 500);
+    }
+
+    #[tokio::test]
+    async fn test_quota_endpoint_reports_the_forwarded_client() {
+        let mut config = Config::default();
+        config.server.trusted_proxies = vec!["127.0.0.1/32".to_string()];
+        let state = test_state(config);
+        let client: std::net::IpAddr = "203.0.113.9".parse().unwrap();
+        state.rate_limiter.check(client);
+        state.rate_limiter.check(client);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.9"));
+        let peer = ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000)));
+        for _ in 0..2 {
+            let Json(quota) = quota_status(State(state.clone()), peer, headers.clone()).await;
+            assert_eq!(quota.client.to_string(), "203.0.113.9");
+            assert_eq!((quota.limit, quota.remaining, quota.used), (1000, 1048, 2));
+        }
+        assert_eq!(state.rate_limiter.tracked_clients(), 1);
+
+        // From an untrusted peer the header is ignored
+        let outsider = ConnectInfo(SocketAddr::from(([192, 0, 2, 1], 40000)));
+        let Json(quota) = quota_status(State(state.clone()), outsider, headers).await;
+        assert_eq!(quota.client.to_string(), "192.0.2.1");
+    }
+
+    #[tokio::test]
+    async fn test_edge_test_endpoint() {
+        use crate::config::{
+            RewriteActionConfig, RewriteRuleConfig, RoutingActionConfig, RoutingConditionConfig,
+            RoutingRuleConfig,
+        };
+
+        let mut config = Config::default();
+        config.edge.rewrite_rules.push(RewriteRuleConfig {
+            name: "images".to_string(),
+            pattern: r"^/img/(.*)$".to_string(),
+            replacement: "/static/images/$1".to_string(),
+            stop: true,
+            condition: None,
+            action: RewriteActionConfig::Replace,
+        });
+        config.edge.routing_rules.push(RoutingRuleConfig {
+            name: "office".to_string(),
+            conditions: vec![RoutingConditionConfig::ClientIp {
+                cidrs: vec!["10.0.0.0/8".to_string()],
+            }],
+            action: RoutingActionConfig::RouteToOrigin {
+                origin: "staging".to_string(),
+            },
+            priority: 0,
+        });
+        let state = test_state(config);
+        let request =
+            |body: serde_json::Value| serde_json::from_value::<EdgeTestRequest>(body).unwrap();
+
+        let Json(response) = test_edge_rules(
+            State(state.clone()),
+            Json(request(serde_json::json!({
+                "path": "/img/a.png",
+                "query": "utm_source=x&w=1",
+                "client_ip": "192.0.2.1",
+            }))),
+        )
+        .await
+        .unwrap();
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["enabled"], true);
+        assert_eq!(json["rewrites"][0]["rule"], "images");
+        assert_eq!(json["final_path"], "/static/images/a.png");
+        assert_eq!(json["final_query"], "w=1");
+        assert!(json["routing_rule"].is_null());
+
+        // X-Forwarded-For wins over client_ip, as it does on live traffic
+        let Json(response) = test_edge_rules(
+            State(state.clone()),
+            Json(request(serde_json::json!({
+                "path": "/img/a.png",
+                "client_ip": "192.0.2.1",
+                "headers": {"x-forwarded-for": "10.1.2.3"},
+            }))),
+        )
+        .await
+        .unwrap();
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["routing_rule"]["name"], "office");
+        assert_eq!(json["routing_rule"]["action"]["type"], "origin");
+        assert_eq!(json["routing_rule"]["action"]["origin"], "staging");
+        assert_eq!(json["forwarded"], true);
+        assert!(json["rewrites"].as_array().unwrap().is_empty());
+
+        for bad in [
+            serde_json::json!({"path": "/a", "method": "G T"}),
+            serde_json::json!({"path": "a"}),
+            serde_json::json!({"path": "/a", "client_ip": "nope"}),
+            serde_json::json!({"path": "/a", "headers": {"bad header": "x"}}),
+        ] {
+            let result = test_edge_rules(State(state.clone()), Json(request(bad))).await;
+            assert!(matches!(result, Err(CdnError::InvalidRequest(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drain_starts_shutdown_once() {
+        let state = test_state(Config::default());
+        let mut shutdown = state.shutdown.subscribe();
+
+        let (status, Json(response)) = drain(State(state.clone())).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(!response.already_draining);
+        assert_eq!(response.pid, std::process::id());
+        assert!(*shutdown.borrow_and_update());
+
+        let (_, Json(response)) = drain(State(state)).await;
+        assert!(response.already_draining);
+    }
+
+    #[tokio::test]
+    async fn test_dictionary_endpoints() {
+        use crate::config::{
+            DictionaryConfig, LookupMiss, LookupRewriteConfig, RewriteActionConfig,
+            RewriteRuleConfig,
+        };
+
+        let path = std::env::temp_dir().join(format!(
+            "screaming-eagle-handlers-dictionary-{}.csv",
+            std::process::id()
+        ));
+        std::fs::write(&path, "old,new\n").unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let mut config = Config::default();
+        config.edge.dictionaries.insert(
+            "pages".to_string(),
+            DictionaryConfig {
+                path: path.clone(),
+                format: None,
+            },
+        );
+        config.edge.rewrite_rules.push(RewriteRuleConfig {
+            name: "pages".to_string(),
+            pattern: r"^/web/([^/]+)$".to_string(),
+            replacement: String::new(),
+            stop: true,
+            condition: None,
+            action: RewriteActionConfig::Lookup(LookupRewriteConfig {
+                dictionary: "pages".to_string(),
+                group: Some(1),
+                on_miss: LookupMiss::NotFound,
+                fallback: None,
+            }),
+        });
+        let state = test_state(config);
+        let lookup = |key: &str| {
+            dictionary_lookup(
+                State(state.clone()),
+                Path("pages".to_string()),
+                Query(DictionaryQuery {
+                    key: Some(key.to_string()),
+                }),
+            )
+        };
+
+        let Json(response) = lookup("old").await.unwrap();
+        assert_eq!(response.entries, 1);
+        assert_eq!(response.path, path);
+        assert_eq!(response.value.as_deref(), Some("new"));
+        let routed = SyntheticRequest::get("/web/old").route(&state).unwrap();
+        assert_eq!(routed.path, "new");
+        assert!(matches!(
+            SyntheticRequest::get("/web/newer").route(&state),
+            Err(CdnError::NotFound(_))
+        ));
+
+        // Reloading picks up new keys without a restart
+        std::fs::write(&path, "old,new\nnewer,newest\n").unwrap();
+        let Json(response) = reload_dictionary(State(state.clone()), Path("pages".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.entries, 2);
+        let Json(response) = lookup("newer").await.unwrap();
+        assert_eq!(response.value.as_deref(), Some("newest"));
+        assert_eq!(
+            SyntheticRequest::get("/web/newer")
+                .route(&state)
+                .unwrap()
+                .path,
+            "newest"
+        );
+
+        // A broken file is reported and the loaded entries stay
+        std::fs::write(&path, "broken\n").unwrap();
+        assert!(
+            reload_dictionary(State(state.clone()), Path("pages".to_string()))
+                .await
+                .is_err()
+        );
+        assert_eq!(lookup("newer").await.unwrap().0.entries, 2);
+
+        assert!(matches!(
+            reload_dictionary(State(state.clone()), Path("missing".to_string())).await,
+            Err(CdnError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pin_endpoints() {
+        let (addr, _requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncontent-length: 2\r\ncache-control: max-age=60\r\nconnection: close\r\n\r\nok".to_string()
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+        get(&state, "app.js", HeaderMap::new()).await;
+        get(&state, "home", HeaderMap::new()).await;
+
+        let request = |keys: &[&str], prefix: Option<&str>| {
+            Json(PinRequest {
+                keys: keys.iter().map(|k| k.to_string()).collect(),
+                prefix: prefix.map(str::to_string),
+            })
+        };
+        assert!(
+            pin_cache_entries(State(state.clone()), request(&[], None))
+                .await
+                .is_err()
+        );
+        let Json(response) = pin_cache_entries(
+            State(state.clone()),
+            request(&["web/gone"], Some("web/home")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.outcome.pinned.len(), 1);
+        assert!(response.outcome.pinned[0].starts_with("web/home"));
+        assert_eq!(response.outcome.not_cached, ["web/gone"]);
+        assert_eq!(response.pinned_size_bytes, 2);
+
+        let Json(list) = list_cache_pins(State(state.clone())).await;
+        assert_eq!(list.pins.len(), 1);
+        assert_eq!(list.max_pinned_bytes, 64 * 1024 * 1024);
+
+        let Json(response) =
+            unpin_cache_entries(State(state.clone()), request(&[], Some("web/"))).await;
+        assert_eq!(response.unpinned, 1);
+        assert_eq!(state.cache.stats().pinned_entries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_origin_responses_are_served_but_not_cached() {
+        let png = b"\x89PNG\r\n\x1a\nimage";
+        let (addr, mut requests) = spawn_test_origin(move |request| {
+            let head = "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\nconnection: close\r\n";
+            let mut response = if request.starts_with("get /truncated ") {
+                // A proxy that lost part of the body but kept the length
+                format!("{head}content-length: 10\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n")
+            } else if request.starts_with("get /digest ") {
+                format!("{head}content-md5: XUFAKrxLKna5cZ2REBfFkg==\r\ncontent-length: 5\r\n\r\nhellp")
+            } else if request.starts_with("get /empty.png ") {
+                format!("{head}content-type: image/png\r\ncontent-length: 0\r\n\r\n")
+            } else if request.starts_with("get /error.png ") {
+                format!("{head}content-type: image/png\r\ncontent-length: 6\r\n\r\n<html>")
+            } else {
+                format!("{head}content-type: image/png\r\ncontent-length: {}\r\n\r\n", png.len())
+            }
+            .into_bytes();
+            if request.starts_with("get /ok.png ") {
+                response.extend_from_slice(png);
+            }
+            response
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        let origin = config.origins.get_mut("web").unwrap();
+        origin.validation.verify_digest = true;
+        origin.validation.reject_empty = vec!["image/*".to_string()];
+        origin.validation.magic_bytes = true;
+        let state = test_state(config);
+
+        for (path, reason) in [
+            ("truncated", "content_length"),
+            ("digest", "digest"),
+            ("empty.png", "empty_body"),
+            ("error.png", "magic_bytes"),
+        ] {
+            // Served both times, but each one goes to the origin
+            for _ in 0..2 {
+                let (response, _) = get(&state, path, HeaderMap::new()).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers().get("x-cache").unwrap(), "MISS");
+                assert_eq!(
+                    response.headers().get(VALIDATION_FAILED_HEADER).unwrap(),
+                    reason
+                );
+                requests.recv().await.unwrap();
+            }
+            assert!(state.metrics.gather().contains(&format!(
+                r#"cdn_origin_validation_failures_total{{origin="web",reason="{}"}} 2"#,
+                reason
+            )));
+        }
+        assert_eq!(state.cache.stats().total_entries, 0);
+
+        // Digests are checked against the body as sent
+        let (response, body) = get(&state, "ok.png", HeaderMap::new()).await;
+        assert!(response.headers().get(VALIDATION_FAILED_HEADER).is_none());
+        assert_eq!(&body[..], png);
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("accept-encoding: identity"));
+        assert_eq!(state.cache.stats().total_entries, 1);
+    }
+
+    #[tokio::test]
+    async fn test_origin_304_to_unconditional_request() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        const NOT_MODIFIED: &str =
+            "HTTP/1.1 304 Not Modified\r\ncache-control: max-age=60\r\nconnection: close\r\n\r\n";
+
+        // Only a request telling its cache to revalidate gets the body
+        let (addr, mut requests) = spawn_test_origin(|request| {
+            if request.contains("cache-control: no-cache") {
+                "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: 5\r\nconnection: close\r\n\r\nfresh"
+            } else {
+                NOT_MODIFIED
+            }
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+        let (response, body) = get(&state, "page", HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(&body[..], b"fresh");
+        assert!(!requests.recv().await.unwrap().contains("pragma"));
+        assert!(requests.recv().await.unwrap().contains("pragma: no-cache"));
+        let (response, body) = get(&state, "page", HeaderMap::new()).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(&body[..], b"fresh");
+
+        // Two 304s in a row are a bad gateway, and nothing is cached
+        let (addr, _requests) = spawn_test_origin(|_| NOT_MODIFIED).await;
+        let state = test_state(config_with_origin(addr));
+        let request = |headers: HeaderMap| {
+            serve_cdn_request(
+                state.clone(),
+                Method::GET,
+                "web".to_string(),
+                "page".to_string(),
+                CdnQuery {
+                    params: HashMap::new(),
+                },
+                headers,
+            )
+        };
+        let err = request(HeaderMap::new()).await.unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(state.cache.stats().total_entries, 0);
+
+        // A client with validators of its own may be answered 304, but the
+        // cache's fill never sends them
+        let mut conditional = HeaderMap::new();
+        conditional.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"v1\""));
+        assert!(request(conditional.clone()).await.is_err());
+        conditional.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        let response = request(conditional).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(state.cache.stats().total_entries, 0);
+
+        // A cached copy stands in, as for any other origin failure
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let (addr, _requests) = spawn_test_origin(move |_| {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                "HTTP/1.1 200 OK\r\ncache-control: max-age=0, stale-if-error=600\r\ncontent-length: 4\r\nconnection: close\r\n\r\ngood"
+            } else {
+                NOT_MODIFIED
+            }
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        config.cache.stale_while_revalidate_secs = 0;
+        let state = test_state(config);
+        get(&state, "page", HeaderMap::new()).await;
+        let (response, body) = get(&state, "page", HeaderMap::new()).await;
+        assert_eq!(response.headers()["x-cache"], "STALE-IF-ERROR");
+        assert_eq!(&body[..], b"good");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_origin_response_can_serve_stale() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let (addr, _requests) = spawn_test_origin(move |_| {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                "HTTP/1.1 200 OK\r\ncache-control: max-age=0, stale-if-error=600\r\ncontent-length: 4\r\nconnection: close\r\n\r\ngood".to_string()
+            } else {
+                "HTTP/1.1 200 OK\r\ncontent-length: 10\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n3\r\nbad\r\n0\r\n\r\n".to_string()
+            }
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        config.cache.stale_while_revalidate_secs = 0;
+        config.origins.get_mut("web").unwrap().validation.on_failure =
+            ValidationFailureAction::Stale;
+        let state = test_state(config);
+
+        get(&state, "page", HeaderMap::new()).await;
+        let (response, body) = get(&state, "page", HeaderMap::new()).await;
+        assert_eq!(response.headers().get("x-cache").unwrap(), "STALE-IF-ERROR");
+        assert_eq!(&body[..], b"good");
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // Without a stale copy the origin's response is served
+        state.cache.purge_all();
+        let (response, body) = get(&state, "page", HeaderMap::new()).await;
+        assert_eq!(response.headers().get("x-cache").unwrap(), "MISS");
+        assert_eq!(&body[..], b"bad");
+    }
+
+    #[tokio::test]
+    async fn test_injected_faults_replace_origin_fetches() {
+        let (addr, mut requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok".to_string()
+        })
+        .await;
+
+        // Without the switch the endpoints don't exist
+        let state = test_state(config_with_origin(addr));
+        assert!(matches!(
+            list_faults(State(state.clone())).await,
+            Err(CdnError::NotFound(_))
+        ));
+
+        let mut config = config_with_origin(addr);
+        config.chaos.enabled = true;
+        let state = test_state(config);
+        let request: FaultRuleRequest = serde_json::from_value(serde_json::json!({
+            "origin": "web",
+            "path": "^/broken/",
+            "fault": {"type": "error", "status": 404},
+            "ttl_secs": 60
+        }))
+        .unwrap();
+        let Json(rule) = add_fault(State(state.clone()), Json(request))
+            .await
+            .unwrap();
+
+        let (response, _) = get(&state, "broken/page", HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers().get("x-cdn-fault").unwrap(), &rule.id);
+        assert!(requests.try_recv().is_err());
+
+        // Other paths still reach the origin
+        let (response, body) = get(&state, "fine", HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body, Bytes::from("ok"));
+        assert!(requests.try_recv().is_ok());
+
+        let Json(listed) = list_faults(State(state.clone())).await.unwrap();
+        assert_eq!(listed.faults.len(), 1);
+        assert_eq!(listed.faults[0].injected, 1);
+
+        let Json(removed) = remove_fault(State(state.clone()), Path(rule.id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(removed.removed, 1);
+        let (response, _) = get(&state, "broken/page", HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(matches!(
+            remove_fault(State(state.clone()), Path(rule.id)).await,
+            Err(CdnError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_health_gossip_endpoint() {
+        let snapshot = || HealthGossip {
+            node_id: "edge-2".to_string(),
+            sent_at: 100,
+            origins: HashMap::from([("web".to_string(), OriginHealth::default())]),
+        };
+
+        let state = test_state(Config::default());
+        let status = receive_health_gossip(State(state.clone()), Json(snapshot())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let mut config = Config::default();
+        config.cluster.health_gossip.enabled = true;
+        let state = test_state(config);
+        let status = receive_health_gossip(State(state.clone()), Json(snapshot())).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let query = OriginHealthQuery::default();
+        let Json(local) = origin_health_status(State(state.clone()), Query(query)).await;
+        assert!(local.cluster.is_none());
+
+        let query = OriginHealthQuery {
+            include_peers: true,
+        };
+        let Json(combined) = origin_health_status(State(state), Query(query)).await;
+        assert!(combined.cluster.unwrap().peers.contains_key("edge-2"));
+    }
+
+    const PAGE: &str = "<html><body>The quick brown fox jumps over the lazy dog</body></html>";
+
+    /// Origin that gzips its response whenever the request allows it
+    async fn spawn_gzip_origin() -> (SocketAddr, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        use std::io::Write;
+
+        spawn_test_origin(|request| {
+            let gzip = request
+                .lines()
+                .any(|line| line.starts_with("accept-encoding:") && line.contains("gzip"));
+            let (encoding, body) = if gzip {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(PAGE.as_bytes()).unwrap();
+                ("content-encoding: gzip\r\n", encoder.finish().unwrap())
+            } else {
+                ("", PAGE.as_bytes().to_vec())
+            };
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: text/html\r\ncache-control: max-age=60\r\nvary: accept-encoding\r\n{}content-length: {}\r\nconnection: close\r\n\r\n",
+                encoding,
+                body.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(&body);
+            response
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_root_paths_are_served_with_or_without_purge_method() {
+        use tower::ServiceExt;
+
+        let (addr, _requests) = spawn_test_origin(
+            |_| "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok",
+        )
+        .await;
+        for purge_method in [false, true] {
+            let mut config = config_with_origin(addr);
+            config.admin.purge_method.enabled = purge_method;
+            let state = test_state(config);
+            let admin_auth = Arc::new(AdminAuth::new(state.config.admin.clone()));
+            let app = cdn_routes(&state, admin_auth)
+                .layer(axum::extract::connect_info::MockConnectInfo(
+                    SocketAddr::from(([127, 0, 0, 1], 40000)),
+                ))
+                .with_state(state.clone());
+
+            // The only origin serves root paths as well as its own
+            for uri in ["/file.js", "/web/file.js"] {
+                let request = axum::http::Request::builder()
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap();
+                let response = app.clone().oneshot(request).await.unwrap();
+                assert_eq!(
+                    response.status(),
+                    StatusCode::OK,
+                    "{} with purge_method {}",
+                    uri,
+                    purge_method
+                );
+            }
+        }
+
+        // With several origins, a root path needs an edge routing rule's origin
+        let mut config = config_with_origin(addr);
+        let other = config.origins["web"].clone();
+        config.origins.insert("other".to_string(), other);
+        let state = test_state(config);
+        let admin_auth = Arc::new(AdminAuth::new(state.config.admin.clone()));
+        let app = cdn_routes(&state, admin_auth)
+            .layer(Extension(RoutedOrigin("web".to_string())))
+            .layer(axum::extract::connect_info::MockConnectInfo(
+                SocketAddr::from(([127, 0, 0, 1], 40000)),
+            ))
+            .with_state(state.clone());
+        let request = axum::http::Request::builder()
+            .uri("/file.js")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// The CDN route behind encoding negotiation and compression, as in main
+    fn compressing_app(state: &Arc<AppState>) -> axum::Router {
+        axum::Router::new()
+            .route("/{origin}/{*path}", axum::routing::get(cdn_handler))
+            .layer(tower_http::compression::CompressionLayer::new())
+            .layer(axum::middleware::from_fn(
+                crate::encoding::negotiate_encoding_middleware,
+            ))
+            .layer(axum::extract::connect_info::MockConnectInfo(
+                SocketAddr::from(([127, 0, 0, 1], 40000)),
+            ))
+            .with_state(state.clone())
+    }
+
+    /// GET through the router with client-side compression, returning the decoded body
+    async fn get_compressed(state: &Arc<AppState>, accept_encoding: Option<&str>) -> String {
+        use std::io::Read;
+        use tower::ServiceExt;
+
+        let app = compressing_app(state);
+        let mut request = axum::http::Request::builder().uri("/web/index.html");
+        if let Some(value) = accept_encoding {
+            request = request.header(header::ACCEPT_ENCODING, value);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let encoding = response.headers().get(header::CONTENT_ENCODING).cloned();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let mut decoded = String::new();
+        match encoding {
+            None => decoded = String::from_utf8(body.to_vec()).unwrap(),
+            Some(encoding) => {
+                // A client only ever gets an encoding it asked for, applied once
+                assert_eq!(encoding, "gzip");
+                assert_eq!(accept_encoding, Some("gzip"));
+                flate2::read::GzDecoder::new(&body[..])
+                    .read_to_string(&mut decoded)
+                    .unwrap();
+            }
+        }
+        decoded
+    }
+
+    #[tokio::test]
+    async fn test_origin_compression_is_decoded_before_caching() {
+        let (addr, mut requests) = spawn_gzip_origin().await;
+        let state = test_state(config_with_origin(addr));
+
+        // Compressed and plain clients, on a miss and on a hit, in either order
+        for accept_encoding in [Some("gzip"), None, Some("gzip"), None] {
+            assert_eq!(get_compressed(&state, accept_encoding).await, PAGE);
+        }
+
+        // The origin is asked for compression regardless of the client
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("accept-encoding: gzip, br\r\n"));
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("accept-encoding: gzip, br\r\n"));
+        assert!(requests.try_recv().is_err());
+
+        // What's stored is the identity body, with nothing claiming otherwise
+        let (entry, _) = state
+            .cache
+            .get("web/index.html|vary:accept-encoding=gzip")
+            .unwrap();
+        assert_eq!(entry.body, Bytes::from(PAGE));
+        assert!(!entry.headers.contains_key("content-encoding"));
+    }
+
+    #[tokio::test]
+    async fn test_accept_encoding_q_values_choose_the_coding() {
+        use tower::ServiceExt;
+
+        let (addr, _requests) = spawn_gzip_origin().await;
+        let state = test_state(config_with_origin(addr));
+
+        for (accept_encoding, status, encoding) in [
+            ("gzip;q=0, br", StatusCode::OK, Some("br")),
+            ("br;q=0.5, gzip", StatusCode::OK, Some("gzip")),
+            ("gzip;q=0", StatusCode::OK, None),
+            ("*;q=0, gzip", StatusCode::OK, Some("gzip")),
+            ("identity;q=0, deflate", StatusCode::NOT_ACCEPTABLE, None),
+            ("*;q=0", StatusCode::NOT_ACCEPTABLE, None),
+        ] {
+            let request = axum::http::Request::builder()
+                .uri("/web/index.html")
+                .header(header::ACCEPT_ENCODING, accept_encoding)
+                .body(Body::empty())
+                .unwrap();
+            let response = compressing_app(&state).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status, "{}", accept_encoding);
+            assert_eq!(
+                response
+                    .headers()
+                    .get(header::CONTENT_ENCODING)
+                    .map(|v| v.to_str().unwrap()),
+                encoding,
+                "{}",
+                accept_encoding
+            );
+        }
+
+        // Those were cached once per negotiated coding
+        for coding in ["br", "gzip", "identity"] {
+            let key = format!("web/index.html|vary:accept-encoding={}", coding);
+            assert!(state.cache.get(&key).is_some(), "{}", key);
+        }
+        assert_eq!(state.cache.stats().total_entries, 3);
+    }
+
+    #[tokio::test]
+    async fn test_origin_compression_can_be_disabled() {
+        let (addr, mut requests) = spawn_gzip_origin().await;
+        let mut config = config_with_origin(addr);
+        config.origins.get_mut("web").unwrap().request_compression = false;
+        let state = test_state(config);
+
+        assert_eq!(get_compressed(&state, Some("gzip")).await, PAGE);
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("accept-encoding: identity\r\n"));
+    }
+
+    async fn purge(
+        state: &Arc<AppState>,
+        path: &str,
+        claims: Option<PurgeTokenClaims>,
+    ) -> (StatusCode, serde_json::Value) {
+        let response = purge_resource_handler(
+            State(state.clone()),
+            Method::from_bytes(b"PURGE").unwrap(),
+            Path(("web".to_string(), path.to_string())),
+            Query(CdnQuery {
+                params: HashMap::new(),
+            }),
+            claims.map(Extension),
+        )
+        .await
+        .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_purge_method_invalidates_all_variants() {
+        let (addr, _) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello".to_string()
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+        let encoding = |value| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+            headers
+        };
+
+        get(&state, "index.html", encoding("gzip")).await;
+        get(&state, "index.html", encoding("br")).await;
+        let (response, _) = get(&state, "index.html", encoding("gzip")).await;
+        assert_eq!(response.headers().get("x-cache").unwrap(), "HIT");
+
+        let (status, body) = purge(&state, "index.html", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["purged_count"], 2);
+
+        let (response, _) = get(&state, "index.html", encoding("gzip")).await;
+        assert_eq!(response.headers().get("x-cache").unwrap(), "MISS");
+
+        let (status, _) = purge(&state, "missing.html", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_purge_cancels_in_flight_fill() {
+        // The origin holds each response until the test releases it
+        let (started_tx, mut started) = tokio::sync::mpsc::unbounded_channel();
+        let (release, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = std::sync::Mutex::new(release_rx);
+        let (addr, _) = spawn_test_origin(move |_| {
+            started_tx.send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: 3\r\nconnection: close\r\n\r\nold".to_string()
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+
+        let fill = tokio::spawn({
+            let state = state.clone();
+            async move { get(&state, "index.html", HeaderMap::new()).await }
+        });
+        started.recv().await.unwrap();
+
+        let (status, body) = purge(&state, "index.html", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["purged_count"], 0);
+        assert_eq!(body["cancelled_fills"], 1);
+
+        // The in-flight request is still answered, but its response isn't stored
+        release.send(()).unwrap();
+        let (response, body) = fill.await.unwrap();
+        assert_eq!(response.headers().get("x-cache").unwrap(), "MISS");
+        assert_eq!(body, Bytes::from("old"));
+
+        release.send(()).unwrap();
+        let (response, _) = get(&state, "index.html", HeaderMap::new()).await;
+        assert_eq!(response.headers().get("x-cache").unwrap(), "MISS");
+        let (response, _) = get(&state, "index.html", HeaderMap::new()).await;
+        assert_eq!(response.headers().get("x-cache").unwrap(), "HIT");
+    }
+
+    #[tokio::test]
+    async fn test_purge_method_enforces_token_scope() {
+        let state = test_state(config_with_origin("127.0.0.1:9".parse().unwrap()));
+        let claims = PurgeTokenClaims {
+            prefixes: vec!["web/assets/".to_string()],
+            tags: Vec::new(),
+            purge_all: false,
+            expires_at: u64::MAX,
+        };
+
+        let (status, body) = purge(&state, "index.html", Some(claims.clone())).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["errors"][0]["item"], "web/index.html");
+
+        let (status, _) = purge(&state, "assets/app.js", Some(claims)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let response = purge_resource_handler(
+            State(state),
+            Method::POST,
+            Path(("web".to_string(), "index.html".to_string())),
+            Query(CdnQuery {
+                params: HashMap::new(),
+            }),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    /// Origin that reflects X-Forwarded-Host into a cacheable page, like the real one
+    async fn spawn_reflecting_origin() -> SocketAddr {
+        let (addr, _) = spawn_test_origin(|request| {
+            let host = request
+                .lines()
+                .find_map(|line| line.strip_prefix("x-forwarded-host: "))
+                .unwrap_or("www.example.com");
+            let body = format!("<a href=\"https://{}/login\">", host.trim());
+            format!(
+                "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        })
+        .await;
+        addr
+    }
+
+    fn attacker_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-host", HeaderValue::from_static("evil.example"));
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_unkeyed_header_poisoning() {
+        for (action, attacker_sees_evil) in [
+            (UnkeyedHeaderAction::Strip, false),
+            (UnkeyedHeaderAction::Bypass, true),
+        ] {
+            let mut config = config_with_origin(spawn_reflecting_origin().await);
+            config.cache.unkeyed_header_protection.action = action;
+            let state = test_state_forwarding(config, &["x-forwarded-host"]);
+
+            let (response, body) = get(&state, "index.html", attacker_headers()).await;
+            assert_eq!(
+                String::from_utf8_lossy(&body).contains("evil.example"),
+                attacker_sees_evil
+            );
+            if action == UnkeyedHeaderAction::Bypass {
+                assert_eq!(response.headers().get("x-cache").unwrap(), "BYPASS");
+            }
+
+            // Everyone else gets the clean page
+            let (_, body) = get(&state, "index.html", HeaderMap::new()).await;
+            assert_eq!(
+                body,
+                Bytes::from("<a href=\"https://www.example.com/login\">")
+            );
+        }
+    }
+
+    /// Collects formatted log lines for tests that check what was logged
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disabled_cache_passes_through_quietly() {
+        let (addr, mut requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\nvary: accept-encoding\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok"
+        })
+        .await;
+
+        let logs = LogCapture::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::WARN)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mut disabled = config_with_origin(addr);
+        disabled.cache.enabled = false;
+        let mut zero_size = config_with_origin(addr);
+        zero_size.cache.max_size_mb = 0;
+        for config in [disabled, zero_size] {
+            let state = test_state(config);
+            for _ in 0..3 {
+                let (response, body) = get(&state, "/a.js", HeaderMap::new()).await;
+                assert_eq!(response.headers()["x-cache"], "BYPASS");
+                assert_eq!(body, "ok");
+                assert!(requests.recv().await.is_some());
+            }
+
+            let stats = state.cache.stats();
+            assert!(!stats.enabled);
+            assert_eq!((stats.hits, stats.misses, stats.total_entries), (0, 0, 0));
+        }
+
+        let logs = logs.0.lock().unwrap();
+        assert!(logs.is_empty(), "{}", String::from_utf8_lossy(&logs));
+    }
+
+    #[tokio::test]
+    async fn test_cache_status_header() {
+        let (addr, _requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok"
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+        let cache_status = |response: &Response| {
+            response.headers()["cache-status"]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let (response, _) = get(&state, "/a.js", HeaderMap::new()).await;
+        assert_eq!(
+            cache_status(&response),
+            "Screaming-Eagle; fwd=uri-miss; fwd-status=200; stored"
+        );
+        assert_eq!(response.headers()["x-cache"], "MISS");
+
+        let (response, _) = get(&state, "/a.js", HeaderMap::new()).await;
+        let value = cache_status(&response);
+        assert!(
+            value == "Screaming-Eagle; hit; ttl=60" || value == "Screaming-Eagle; hit; ttl=59",
+            "{}",
+            value
+        );
+
+        // The client's own no-cache sent it forward
+        let mut no_cache = HeaderMap::new();
+        no_cache.insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+        let (response, _) = get(&state, "/a.js", no_cache).await;
+        assert_eq!(
+            cache_status(&response),
+            "Screaming-Eagle; fwd=request; fwd-status=200"
+        );
+
+        // The key only with debug on, and X-Cache can be turned off
+        let mut config = config_with_origin(addr);
+        config.cache.status_headers.debug = true;
+        config.cache.status_headers.x_cache = false;
+        let state = test_state(config);
+        let (response, _) = get(&state, "/a.js", HeaderMap::new()).await;
+        assert!(cache_status(&response).contains("; stored; key=\""));
+        assert!(!response.headers().contains_key("x-cache"));
+
+        let mut config = config_with_origin(addr);
+        config.cache.status_headers.cache_status = false;
+        let state = test_state(config);
+        let (response, _) = get(&state, "/a.js", HeaderMap::new()).await;
+        assert!(!response.headers().contains_key("cache-status"));
+        assert_eq!(response.headers()["x-cache"], "MISS");
+    }
+
+    #[tokio::test]
+    async fn test_tag_header_formats_index_the_same_tags() {
+        let (addr, _requests) = spawn_test_origin(|request| {
+            let tags = if request.contains("/surrogate") {
+                "surrogate-key: product-123 Category-Shoes  brand-nike\r\n"
+            } else if request.contains("/comma") {
+                "cache-tag: product-123,category-shoes, brand-nike\r\n"
             } else {
-                results.push(WarmResult {
-                    url: url.to_string(),
-                    success: false,
-                    cached: false,
-                    error: Some("Origin must be specified: /origin/path".to_string()),
+                "x-cache-tags: product-123\r\nx-cache-tags: category-shoes\r\nx-cache-tags: brand-nike\r\n"
+            };
+            format!(
+                "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\n{}content-length: 2\r\nconnection: close\r\n\r\nok",
+                tags
+            )
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        config.cache.tags = toml::from_str(
+            r#"
+            lowercase = true
+
+            [[headers]]
+            name = "surrogate-key"
+            delimiter = " "
+
+            [[headers]]
+            name = "cache-tag"
+            strip = false
+
+            [[headers]]
+            name = "x-cache-tags"
+            delimiter = ""
+            "#,
+        )
+        .unwrap();
+        let state = test_state(config);
+
+        let (response, _) = get(&state, "/surrogate", HeaderMap::new()).await;
+        assert!(!response.headers().contains_key("surrogate-key"));
+        let (response, _) = get(&state, "/comma", HeaderMap::new()).await;
+        assert_eq!(
+            response.headers()["cache-tag"],
+            "product-123,category-shoes, brand-nike"
+        );
+        let (response, _) = get(&state, "/repeated", HeaderMap::new()).await;
+        assert!(!response.headers().contains_key("x-cache-tags"));
+
+        let mut tags = state.cache.get_all_tags();
+        tags.sort();
+        assert_eq!(tags, vec!["brand-nike", "category-shoes", "product-123"]);
+        for tag in &tags {
+            assert_eq!(state.cache.get_tag_stats(tag).unwrap().entry_count, 3);
+        }
+
+        let (response, purged) = purge_json(
+            &state,
+            None,
+            PurgeRequest {
+                tag: Some("category-shoes".to_string()),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(purged.purged_count, 3);
+    }
+
+    #[tokio::test]
+    async fn test_key_hash_header_purges_what_was_served() {
+        let (addr, _requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\nvary: accept-encoding\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok"
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        config.cache.key.include_headers = vec!["accept-language".to_string()];
+        config.cache.status_headers.key_hash = true;
+        config.cache.key_hash_index.enabled = true;
+        let state = test_state(config);
+        let language = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_LANGUAGE, HeaderValue::from_static(value));
+            headers
+        };
+        let hash_of = |response: &Response| {
+            response.headers()["x-cache-key-hash"]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let (response, _) = get(&state, "/a.js", language("de")).await;
+        let de = hash_of(&response);
+        assert_eq!(de.len(), 16);
+        let (response, _) = get(&state, "/a.js", language("de")).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(hash_of(&response), de);
+        let (response, _) = get(&state, "/a.js", language("fr")).await;
+        assert_ne!(hash_of(&response), de);
+
+        // Only the variant served under the hash goes
+        let unknown = "0000000000000000".to_string();
+        let (response, purged) = purge_json(
+            &state,
+            None,
+            PurgeRequest {
+                key_hashes: vec![de.clone(), unknown.clone()],
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(purged.purged_count, 1);
+        assert_eq!(purged.unknown_key_hashes, vec![unknown]);
+        let (response, _) = get(&state, "/a.js", language("de")).await;
+        assert_eq!(response.headers()["x-cache"], "MISS");
+        let (response, _) = get(&state, "/a.js", language("fr")).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+
+        // Without the index there's nothing to resolve hashes against
+        let state = test_state(config_with_origin(addr));
+        let (response, _) = get(&state, "/a.js", language("de")).await;
+        assert!(!response.headers().contains_key("x-cache-key-hash"));
+        let (response, purged) = purge_json(
+            &state,
+            None,
+            PurgeRequest {
+                key_hashes: vec![de],
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(!purged.success);
+    }
+
+    #[tokio::test]
+    async fn test_purge_all_cooldown_and_token() {
+        let mut config = config_with_origin("127.0.0.1:9".parse().unwrap());
+        config.admin.purge_safety.cooldown_secs = 600;
+        let state = test_state(config.clone());
+        let all = || PurgeRequest {
+            all: true,
+            ..Default::default()
+        };
+        let action = |response: &Response| {
+            response
+                .extensions()
+                .get::<AuditAction>()
+                .map(|action| action.0.clone())
+        };
+
+        let (response, purged) = purge_json(&state, None, all()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(purged.success);
+        assert_eq!(action(&response).as_deref(), Some("purge_all"));
+
+        // A second purge-all inside the cooldown waits, unless forced
+        let (response, purged) = purge_json(&state, None, all()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(!purged.success && purged.message.contains("\"force\": true"));
+        let retry_after: u64 = response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((599..=600).contains(&retry_after));
+        assert!(action(&response).is_none());
+
+        let (response, _) = purge_json(
+            &state,
+            None,
+            PurgeRequest {
+                force: true,
+                ..all()
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            action(&response).as_deref(),
+            Some("purge_all (forced during cooldown)")
+        );
+
+        // Other purges aren't held up
+        let (response, _) = purge_json(
+            &state,
+            None,
+            PurgeRequest {
+                tag: Some("t".to_string()),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(action(&response).is_none());
+
+        // With a token required, only purge tokens minted for it may purge all
+        config.admin.purge_safety.require_purge_all_token = true;
+        let state = test_state(config);
+        let mut claims = PurgeTokenClaims {
+            prefixes: vec!["web/".to_string()],
+            tags: Vec::new(),
+            purge_all: false,
+            expires_at: u64::MAX,
+        };
+        let (response, _) = purge_json(&state, None, all()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let (response, _) = purge_json(&state, Some(claims.clone()), all()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        claims.purge_all = true;
+        let (response, _) = purge_json(&state, Some(claims), all()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_query_rules_key_and_forward_the_configured_query() {
+        let (addr, mut requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok"
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        config.cache.key.query_rules = toml::from_str::<CacheKeyConfig>(
+            r#"
+            [[query_rules]]
+            path_pattern = "^/assets/"
+            ignore_query_except = ["v"]
+            apply_to_origin = true
+
+            [[query_rules]]
+            path_pattern = "^/img/"
+            ignore_query = true
+            "#,
+        )
+        .unwrap()
+        .query_rules;
+        let state = test_state(config);
+        let fetch = |path: &'static str, params: &[(&str, &str)]| {
+            let query = CdnQuery {
+                params: params
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            };
+            let state = state.clone();
+            async move {
+                let response = serve_cdn_request(
+                    state,
+                    Method::GET,
+                    "web".to_string(),
+                    path.to_string(),
+                    query,
+                    HeaderMap::new(),
+                )
+                .await
+                .unwrap();
+                response.headers()["x-cache"].to_str().unwrap().to_string()
+            }
+        };
+        let request_line = |requests: &mut tokio::sync::mpsc::UnboundedReceiver<String>| {
+            let request = requests.try_recv().unwrap();
+            request.lines().next().unwrap().to_string()
+        };
+
+        // Cache-busting values share one entry; the origin sees only `v`
+        assert_eq!(
+            fetch("assets/app.js", &[("cb", "1"), ("v", "2")]).await,
+            "MISS"
+        );
+        assert_eq!(
+            request_line(&mut requests),
+            "get /assets/app.js?v=2 http/1.1"
+        );
+        assert_eq!(
+            fetch("assets/app.js", &[("cb", "9"), ("v", "2")]).await,
+            "HIT"
+        );
+        // The kept parameter still tells entries apart
+        assert_eq!(
+            fetch("assets/app.js", &[("cb", "1"), ("v", "3")]).await,
+            "MISS"
+        );
+        assert_eq!(
+            request_line(&mut requests),
+            "get /assets/app.js?v=3 http/1.1"
+        );
+
+        // Without apply_to_origin the origin gets the query as requested
+        assert_eq!(fetch("img/logo.png", &[("cb", "1")]).await, "MISS");
+        assert_eq!(
+            request_line(&mut requests),
+            "get /img/logo.png?cb=1 http/1.1"
+        );
+        assert_eq!(fetch("img/logo.png", &[("cb", "2")]).await, "HIT");
+
+        // Paths no rule matches are keyed on their whole query
+        assert_eq!(fetch("page", &[("cb", "1")]).await, "MISS");
+        assert_eq!(fetch("page", &[("cb", "2")]).await, "MISS");
+        assert!(requests.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_vary_star_is_never_stored() {
+        let (addr, mut requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\nvary: *\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok".to_string()
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+
+        for _ in 0..3 {
+            let (response, body) = get(&state, "/any", HeaderMap::new()).await;
+            assert_eq!(response.headers().get("x-cache").unwrap(), "MISS");
+            assert_eq!(&body[..], b"ok");
+            assert_eq!(state.cache.stats().total_entries, 0);
+        }
+        for _ in 0..3 {
+            assert!(requests.try_recv().is_ok());
+        }
+        assert!(
+            state
+                .metrics
+                .gather()
+                .contains(r#"cdn_cache_vary_star_skipped_total{origin="web"} 3"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_large_response_is_stored_off_the_request() {
+        let (addr, _requests) = spawn_test_origin(|_| {
+            format!(
+                "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: 4096\r\nconnection: close\r\n\r\n{}",
+                "x".repeat(4096)
+            )
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        config.cache.store_queue.min_body_bytes = 1024;
+        let state = test_state(config);
+
+        let (response, body) = get(&state, "/large.bin", HeaderMap::new()).await;
+        assert_eq!(response.headers().get("x-cache").unwrap(), "MISS");
+        assert_eq!(body.len(), 4096);
+
+        state.store_queue.flush().await;
+        let (response, body) = get(&state, "/large.bin", HeaderMap::new()).await;
+        assert_eq!(response.headers().get("x-cache").unwrap(), "HIT");
+        assert_eq!(body.len(), 4096);
+        assert!(
+            state
+                .metrics
+                .gather()
+                .contains(r#"cdn_cache_store_queue_total{result="queued"} 1"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_preconnect_warms_the_pool() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Keep-alive origin counting the connections it accepts
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let (tx, mut requests) = tokio::sync::mpsc::unbounded_channel();
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n) = stream.read(&mut buf).await
+                        && n > 0
+                    {
+                        let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                        let _ = tx.send(request.lines().next().unwrap_or("").to_string());
+                        let head = "HTTP/1.1 200 OK\r\ncache-control: no-store\r\ncontent-length: 2\r\n\r\n";
+                        let body = if request.starts_with("head ") {
+                            ""
+                        } else {
+                            "ok"
+                        };
+                        let response = format!("{}{}", head, body);
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut config = config_with_origin(addr);
+        config.connection_pool.preconnect_count = 3;
+        config.origins.get_mut("web").unwrap().health_check_path = Some("/health".to_string());
+        let state = test_state(config);
+
+        assert_eq!(state.origin.preconnect("web").await, 3);
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+        for _ in 0..3 {
+            assert_eq!(requests.recv().await.unwrap(), "head /health http/1.1");
+        }
+        let output = state.metrics.gather();
+        assert!(
+            output.contains(r#"cdn_origin_preconnects_total{origin="web",result="success"} 3"#)
+        );
+        assert!(output.contains(r#"cdn_origin_preconnect_duration_seconds_count{origin="web"} 3"#));
+
+        // The first real request reuses a warmed connection
+        let (response, body) = get(&state, "/a.js", HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body, "ok");
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+
+        // A preconnect path wins over the health check path, and failures only count
+        let mut config = config_with_origin("127.0.0.1:1".parse().unwrap());
+        config.connection_pool.preconnect_count = 2;
+        config.origins.get_mut("web").unwrap().preconnect_path = Some("/warm".to_string());
+        assert_eq!(config.origins["web"].preconnect_target(), "/warm");
+        let state = test_state(config);
+        assert_eq!(state.origin.preconnect("web").await, 0);
+        assert_eq!(state.origin.preconnect("missing").await, 0);
+        assert!(
+            state
+                .metrics
+                .gather()
+                .contains(r#"cdn_origin_preconnects_total{origin="web",result="failure"} 2"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_head_probe_that_opens_the_circuit_skips_the_get() {
+        let (addr, mut requests) = spawn_test_origin(|request| {
+            if request.starts_with("head ") {
+                "garbage\r\n\r\n"
+            } else {
+                "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok"
+            }
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+        // One failure short of opening the circuit
+        for _ in 1..CircuitBreakerConfig::default().failure_threshold {
+            state.circuit_breaker.record_failure("web");
+        }
+
+        let result = serve_cdn_request(
+            state.clone(),
+            Method::HEAD,
+            "web".to_string(),
+            "/a.bin".to_string(),
+            CdnQuery {
+                params: HashMap::new(),
+            },
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(result, Err(CdnError::OriginUnavailable { .. })));
+        assert_eq!(state.circuit_breaker.state("web"), CircuitState::Open);
+
+        // Only the HEAD reached the origin
+        assert!(requests.recv().await.unwrap().starts_with("head /a.bin "));
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_head_matches_get_headers() {
+        let (addr, _requests) = spawn_test_origin(|request| {
+            let body = if request.starts_with("head ") { "" } else { "0123456789" };
+            format!(
+                "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: 10\r\nconnection: close\r\n\r\n{}",
+                body
+            )
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
+        let framing = |response: &Response| {
+            let headers = response.headers();
+            (
+                response.status(),
+                ["content-length", "content-range", "accept-ranges"]
+                    .map(|name| headers.get(name).map(|v| v.to_str().unwrap().to_string())),
+            )
+        };
+        let with = |pairs: &[(header::HeaderName, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(name.clone(), value.parse().unwrap());
+            }
+            headers
+        };
+        let range = (header::RANGE, "bytes=2-5");
+        let no_cache = (header::CACHE_CONTROL, "no-cache");
+
+        // HEAD of a miss first, then every form against the cached copy and bypassing it
+        let (response, body) = send(&state, Method::HEAD, "/a.bin", HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-length"], "10");
+        assert!(body.is_empty());
+
+        for pairs in [
+            vec![],
+            vec![range.clone()],
+            vec![no_cache.clone()],
+            vec![range.clone(), no_cache.clone()],
+        ] {
+            let (get_response, get_body) = get(&state, "/a.bin", with(&pairs)).await;
+            let (head_response, head_body) = send(&state, Method::HEAD, "/a.bin", with(&pairs)).await;
+            assert_eq!(framing(&head_response), framing(&get_response), "{:?}", pairs);
+            assert_eq!(head_response.headers()["x-cache"], get_response.headers()["x-cache"]);
+            assert!(head_body.is_empty());
+            assert_eq!(
+                get_response.headers()["content-length"],
+                get_body.len().to_string().as_str()
+            );
+        }
+
+        let (response, _) = send(&state, Method::HEAD, "/a.bin", with(&[range])).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["content-range"], "bytes 2-5/10");
+        assert_eq!(response.headers()["content-length"], "4");
+        assert_eq!(response.headers()["accept-ranges"], "bytes");
+
+        // An unsatisfiable range is refused for HEAD as for GET
+        let (response, _) = send(
+            &state,
+            Method::HEAD,
+            "/a.bin",
+            with(&[(header::RANGE, "bytes=20-30")]),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()["content-range"], "bytes */10");
+    }
+
+    #[tokio::test]
+    async fn test_personalized_requests_bypass_the_cache() {
+        let (addr, _requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok"
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        let bypass = &mut config.origins.get_mut("web").unwrap().personalized_bypass;
+        bypass.authorization = true;
+        bypass.cookie_max_bytes = Some(16);
+        let state = test_state(config);
+
+        let with = |name: header::HeaderName, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, value.parse().unwrap());
+            headers
+        };
+        let x_cache = |response: &Response| response.headers()["x-cache"].clone();
+
+        // Credentialed requests neither fill nor read the cache
+        let (response, _) = get(&state, "/a.js", with(header::AUTHORIZATION, "Bearer t")).await;
+        assert_eq!(x_cache(&response), "BYPASS");
+        let (response, _) = get(&state, "/a.js", HeaderMap::new()).await;
+        assert_eq!(x_cache(&response), "MISS");
+        let (response, _) = get(&state, "/a.js", with(header::AUTHORIZATION, "Bearer t")).await;
+        assert_eq!(x_cache(&response), "BYPASS");
+
+        // Only cookies over the limit count as personalized
+        let (response, _) = get(&state, "/a.js", with(header::COOKIE, "theme=dark")).await;
+        assert_eq!(x_cache(&response), "HIT");
+        let session = with(header::COOKIE, "session=0123456789abcdef");
+        let (response, _) = get(&state, "/a.js", session).await;
+        assert_eq!(x_cache(&response), "BYPASS");
+
+        let metrics = state.metrics.gather();
+        assert!(
+            metrics.contains(
+                r#"cdn_personalized_bypass_total{origin="web",reason="authorization"} 2"#
+            )
+        );
+        assert!(
+            metrics.contains(r#"cdn_personalized_bypass_total{origin="web",reason="cookie"} 1"#)
+        );
+
+        // Off by default
+        let state = test_state(config_with_origin(addr));
+        get(&state, "/a.js", HeaderMap::new()).await;
+        let (response, _) = get(&state, "/a.js", with(header::AUTHORIZATION, "Bearer t")).await;
+        assert_eq!(x_cache(&response), "HIT");
+    }
+
+    #[tokio::test]
+    async fn test_unkeyed_header_poisoning_without_protection() {
+        let mut config = config_with_origin(spawn_reflecting_origin().await);
+        config.cache.unkeyed_header_protection.enabled = false;
+        let state = test_state_forwarding(config, &["x-forwarded-host"]);
+
+        get(&state, "index.html", attacker_headers()).await;
+        let (response, body) = get(&state, "index.html", HeaderMap::new()).await;
+        assert_eq!(response.headers().get("x-cache").unwrap(), "HIT");
+        assert!(String::from_utf8_lossy(&body).contains("evil.example"));
+    }
+
+    #[test]
+    fn test_unkeyed_headers_allowed_and_keyed_values_pass() {
+        let mut config = Config::default();
+        config
+            .cache
+            .unkeyed_header_protection
+            .allowed_values
+            .insert(
+                "X-Forwarded-Host".to_string(),
+                vec!["www.example.com".to_string()],
+            );
+        config.cache.key.include_headers = vec!["X-Original-URL".to_string()];
+        let state = test_state(config);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-host",
+            HeaderValue::from_static("www.example.com"),
+        );
+        headers.insert("x-original-url", HeaderValue::from_static("/admin"));
+        headers.insert("x-rewrite-url", HeaderValue::from_static("/admin"));
+
+        assert!(!screen_unkeyed_headers(&state, "web", &mut headers));
+        assert!(headers.contains_key("x-forwarded-host"));
+        assert!(headers.contains_key("x-original-url"));
+        assert!(!headers.contains_key("x-rewrite-url"));
+    }
+
+    #[tokio::test]
+    async fn test_small_objects_are_not_passed_through() {
+        let mut config = Config::default();
+        config.cache.range.passthrough_threshold_bytes = 1024;
+        let state = test_state(config);
+
+        let mut headers = ResponseHeaders::new();
+        headers.insert("content-length", "1024");
+        store_headers_only(
+            &state,
+            "media",
+            state.cache.reserve("media/small.mp4"),
+            headers,
+            StatusCode::OK,
+        );
+        let mut headers = ResponseHeaders::new();
+        headers.insert("content-length", "1025");
+        store_headers_only(
+            &state,
+            "media",
+            state.cache.reserve("media/large.mp4"),
+            headers,
+            StatusCode::OK,
+        );
+
+        let size = |key: &'static str| {
+            let state = state.clone();
+            async move {
+                large_object_size(
+                    &state,
+                    &state.cache.reserve(key),
+                    "media",
+                    "unused",
+                    None,
+                    &HeaderMap::new(),
+                    &HashMap::new(),
+                )
+                .await
+            }
+        };
+        assert_eq!(size("media/small.mp4").await, None);
+        assert_eq!(size("media/large.mp4").await, Some(1025));
+    }
+
+    #[tokio::test]
+    async fn test_warm_joining_a_caching_fetch_reports_it_cached() {
+        let state = test_state(config_with_origin("127.0.0.1:1".parse().unwrap()));
+        let keys = CacheKeyBuilder::new("web", "/app.js", &state.config.cache.key);
+        let (fetch_key, variant) = (keys.fetch_key(), keys.fetch_variant(None));
+        let leader = |cacheable: bool| {
+            let AcquireResult::Fetch(guard) = state.coalescer.try_acquire(&fetch_key) else {
+                panic!("Should have acquired fetch lock");
+            };
+            let state = state.clone();
+            let variant = variant.clone();
+            tokio::spawn(async move {
+                // Let the warm join before answering
+                while state.coalescer.stats().total_waiters == 0 {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                guard.complete(CoalescedResponse {
+                    body: Bytes::from("ok"),
+                    headers: ResponseHeaders::new(),
+                    status_code: 200,
+                    variant,
+                    cacheable,
                 });
-                failed += 1;
-                continue;
-            }
+            })
         };
 
-        // Check if origin exists
-        if !state.origin.has_origin(origin) {
-            results.push(WarmResult {
-                url: url.to_string(),
-                success: false,
-                cached: false,
-                error: Some(format!("Unknown origin: {}", origin)),
-            });
-            failed += 1;
-            continue;
-        }
+        // The leader stores after waking the warm, so it's reported cached
+        // without looking, and without counting a cache hit
+        let answered = leader(true);
+        let result = warm_one(&state, "/web/app.js", "web", "/app.js", None).await;
+        answered.await.unwrap();
+        assert!(result.success && result.cached);
+        assert_eq!(result.cache_status.as_deref(), Some("HIT"));
+        assert_eq!(state.cache.stats().hits, 0);
 
-        // Generate cache key
-        let cache_key = generate_cache_key_with_vary(
-            origin,
-            &format!("/{}", path),
+        // A response the leader won't cache is still the warm's to store
+        let answered = leader(false);
+        let result = warm_one(&state, "/web/app.js", "web", "/app.js", None).await;
+        answered.await.unwrap();
+        assert!(result.success && !result.cached);
+        assert_eq!(result.cache_status.as_deref(), Some("MISS"));
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_waiter_times_out_with_gateway_timeout() {
+        let mut config = Config::default();
+        config.coalesce.waiter_timeout_ms = 20;
+        config.coalesce.on_waiter_timeout = WaiterTimeoutAction::GatewayTimeout;
+        let state = test_state(config);
+
+        // A leader that never completes
+        let _guard = match state.coalescer.try_acquire("web/slow") {
+            AcquireResult::Fetch(guard) => guard,
+            _ => panic!("Should have acquired fetch lock"),
+        };
+
+        let (result, coalesced) = fetch_from_origin_coalesced(
+            &state,
+            "web/slow",
+            "web",
+            "slow",
             None,
-            Some("accept-encoding"),
+            &HeaderMap::new(),
             &HashMap::new(),
+            RequestSource::Client,
+        )
+        .await;
+
+        assert_eq!(coalesced, Some(false));
+        assert_eq!(
+            result.unwrap_err().status_code(),
+            StatusCode::GATEWAY_TIMEOUT
         );
+        let stats = state.coalescer.stats();
+        assert_eq!(stats.waiter_timeouts, 1);
+        assert_eq!(stats.total_waiters, 0);
+    }
 
-        // Check if already cached
-        if state.cache.get(&cache_key).is_some() {
-            results.push(WarmResult {
-                url: url.to_string(),
-                success: true,
-                cached: true,
-                error: None,
-            });
-            warmed += 1;
-            continue;
-        }
+    /// Origin answering every request after `delay`, counting requests on the channel
+    async fn spawn_slow_origin(
+        delay: Duration,
+    ) -> (SocketAddr, tokio::sync::mpsc::UnboundedReceiver<()>) {
+        spawn_slow_origin_with(delay, "").await
+    }
 
-        // Fetch from origin
-        match fetch_from_origin(&state, origin, path, None, &HeaderMap::new()).await {
-            Ok((body, headers, status)) => {
-                if is_cacheable(status, &headers) {
-                    // Store in cache
-                    let vary_header = headers.get("vary").map(|s| s.as_str());
-                    let final_cache_key = generate_cache_key_with_vary(
-                        origin,
-                        &format!("/{}", path),
-                        None,
-                        vary_header.or(Some("accept-encoding")),
-                        &HashMap::new(),
-                    );
-                    store_in_cache(&state, &final_cache_key, body, headers, status);
+    /// [`spawn_slow_origin`], with extra response header lines
+    async fn spawn_slow_origin_with(
+        delay: Duration,
+        extra_headers: &'static str,
+    ) -> (SocketAddr, tokio::sync::mpsc::UnboundedReceiver<()>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-                    results.push(WarmResult {
-                        url: url.to_string(),
-                        success: true,
-                        cached: false,
-                        error: None,
-                    });
-                    warmed += 1;
-                } else {
-                    results.push(WarmResult {
-                        url: url.to_string(),
-                        success: false,
-                        cached: false,
-                        error: Some("Response not cacheable".to_string()),
-                    });
-                    failed += 1;
-                }
-            }
-            Err(e) => {
-                results.push(WarmResult {
-                    url: url.to_string(),
-                    success: false,
-                    cached: false,
-                    error: Some(e.to_string()),
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 4096];
+                    let _ = stream.read(&mut buf).await;
+                    let _ = tx.send(());
+                    tokio::time::sleep(delay).await;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncache-control: max-age=60\r\n{}content-length: 4\r\nconnection: close\r\n\r\nslow",
+                        extra_headers
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
                 });
-                failed += 1;
             }
+        });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout_answers_504_but_fill_completes() {
+        let (addr, mut requests) = spawn_slow_origin(Duration::from_millis(1500)).await;
+        let mut config = config_with_origin(addr);
+        config.server.request_timeout_secs = 1;
+        let state = test_state(config);
+
+        // Two clients miss at once; both give up when the budget runs out
+        let started = Instant::now();
+        let request = || {
+            serve_cdn_request(
+                state.clone(),
+                Method::GET,
+                "web".to_string(),
+                "slow.txt".to_string(),
+                CdnQuery {
+                    params: HashMap::new(),
+                },
+                HeaderMap::new(),
+            )
+        };
+        let (first, second) = tokio::join!(request(), request());
+        for result in [first, second] {
+            let err = result.unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::GATEWAY_TIMEOUT);
         }
+        assert!(started.elapsed() < Duration::from_millis(1400));
+        assert_eq!(state.cache.stats().total_entries, 0);
+
+        // The coalesced fill carries on and caches the response
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        assert_eq!(state.cache.stats().total_entries, 1);
+        let (response, body) = get(&state, "slow.txt", HeaderMap::new()).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(body, Bytes::from("slow"));
+
+        let mut fetched = 0;
+        while requests.try_recv().is_ok() {
+            fetched += 1;
+        }
+        assert_eq!(fetched, 1);
     }
 
-    Json(WarmCacheResponse {
-        success: failed == 0,
-        message: format!("Warmed {} URLs, {} failed", warmed, failed),
-        warmed,
-        failed,
-        results,
-    })
-}
+    fn header(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
 
-// Main CDN handler - supports both GET and HEAD methods
-pub async fn cdn_handler(
-    State(state): State<Arc<AppState>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    method: Method,
-    Path((origin, path)): Path<(String, String)>,
-    Query(query): Query<CdnQuery>,
-    headers: HeaderMap,
-) -> Result<Response, CdnError> {
-    let start = Instant::now();
-    let is_head_request = method == Method::HEAD;
+    #[tokio::test]
+    async fn test_misses_across_encodings_share_one_fetch() {
+        let (addr, mut requests) = spawn_slow_origin(Duration::from_millis(200)).await;
+        let state = test_state(config_with_origin(addr));
 
-    // Check rate limit
-    let client_ip = extract_client_ip(&headers, addr.ip());
-    match state.rate_limiter.check(client_ip) {
-        RateLimitResult::Limited { retry_after } => {
-            let mut response = (
-                StatusCode::TOO_MANY_REQUESTS,
-                format!("Rate limit exceeded. Retry after {} seconds.", retry_after),
-            )
-                .into_response();
+        let gzip = header("accept-encoding", "gzip");
+        let br = header("accept-encoding", "br");
+        let (first, second, third) = tokio::join!(
+            get(&state, "slow.txt", gzip.clone()),
+            get(&state, "slow.txt", br.clone()),
+            get(&state, "slow.txt", HeaderMap::new()),
+        );
+        for (response, body) in [first, second, third] {
+            assert_eq!(response.headers()["x-cache"], "MISS");
+            assert_eq!(body, Bytes::from("slow"));
+        }
+        let mut fetched = 0;
+        while requests.try_recv().is_ok() {
+            fetched += 1;
+        }
+        assert_eq!(fetched, 1);
+        assert_eq!(state.coalescer.stats().variant_mismatches, 0);
 
-            response
-                .headers_mut()
-                .insert("Retry-After", retry_after.to_string().parse().unwrap());
-            response
-                .headers_mut()
-                .insert("X-RateLimit-Remaining", "0".parse().unwrap());
+        // Each encoding stored its own copy of the shared response
+        for headers in [gzip, br, HeaderMap::new()] {
+            let (response, _) = get(&state, "slow.txt", headers).await;
+            assert_eq!(response.headers()["x-cache"], "HIT");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_waiter_refetches_a_variant_it_did_not_ask_for() {
+        let (addr, mut requests) =
+            spawn_slow_origin_with(Duration::from_millis(200), "vary: Accept-Language\r\n").await;
+        let state = test_state(config_with_origin(addr));
 
-            return Ok(response);
+        let en = header("accept-language", "en");
+        let fr = header("accept-language", "fr");
+        let (first, second, third) = tokio::join!(
+            get(&state, "slow.txt", en.clone()),
+            get(&state, "slow.txt", en),
+            get(&state, "slow.txt", fr),
+        );
+        for (response, body) in [first, second, third] {
+            assert_eq!(response.headers()["x-cache"], "MISS");
+            assert_eq!(body, Bytes::from("slow"));
         }
-        RateLimitResult::Allowed { remaining, .. } => {
-            // Will add header to response later
-            let _ = remaining;
+        // The second English request shared the first's fetch; French fetched its own
+        let mut fetched = 0;
+        while requests.try_recv().is_ok() {
+            fetched += 1;
         }
+        assert_eq!(fetched, 2);
+        assert_eq!(state.coalescer.stats().variant_mismatches, 1);
+        // One copy per language
+        assert_eq!(state.cache.stats().total_entries, 2);
     }
 
-    // Validate origin exists
-    if !state.origin.has_origin(&origin) {
-        return Err(CdnError::NotFound(format!("Unknown origin: {}", origin)));
+    #[test]
+    fn test_store_in_cache_keeps_newer_fill() {
+        let state = test_state(Config::default());
+
+        // The slower fetch started first, so its response is the older one
+        let slow = state.cache.reserve("web/index");
+        std::thread::sleep(Duration::from_millis(2));
+        let fast = state.cache.reserve("web/index");
+
+        for (slot, body) in [(fast, "fresh"), (slow, "stale")] {
+            store_in_cache(
+                &state,
+                "web",
+                slot,
+                Bytes::from(body),
+                ResponseHeaders::new(),
+                StatusCode::OK,
+            );
+        }
+        let (entry, _) = state.cache.get("web/index").unwrap();
+        assert_eq!(entry.body, Bytes::from("fresh"));
     }
 
-    // Check circuit breaker
-    if !state.circuit_breaker.should_allow(&origin) {
-        return Err(CdnError::OriginUnreachable(format!(
-            "Origin {} circuit breaker is open",
-            origin
-        )));
+    #[tokio::test]
+    async fn test_response_header_allowlist() {
+        let (addr, _requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncache-control: max-age=600\r\netag: \"v1\"\r\ncontent-language: en\r\naccess-control-allow-origin: *\r\nvary: Accept-Encoding\r\ncontent-length: 4\r\nconnection: close\r\n\r\nbody".to_string()
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        config.security.response_header_mode = ResponseHeaderMode::Allowlist;
+        config.security.response_header_allowlist = vec!["Vary".to_string()];
+        let state = test_state(config);
+
+        for expected_cache in ["MISS", "HIT"] {
+            let (response, body) = get(&state, "doc.txt", HeaderMap::new()).await;
+            assert_eq!(response.headers().get("x-cache").unwrap(), expected_cache);
+            assert_eq!(body, Bytes::from("body"));
+            let headers = response.headers();
+            assert_eq!(headers.get("content-type").unwrap(), "text/plain");
+            assert_eq!(headers.get("etag").unwrap(), "\"v1\"");
+            assert_eq!(headers.get("vary").unwrap(), "Accept-Encoding");
+            assert!(headers.get("content-language").is_none());
+            assert!(headers.get("access-control-allow-origin").is_none());
+            // The CDN's own headers aren't origin headers
+            assert!(headers.get("via").is_some());
+        }
+
+        // The entry keeps everything, so the filter also covers older entries
+        let key = CacheKeyBuilder::new("web", "doc.txt", &state.config.cache.key).lookup_key();
+        let (entry, _) = state.cache.get(&key).unwrap();
+        assert_eq!(entry.headers.get("content-language").unwrap(), "en");
     }
 
-    // Build query string
-    let query_string = if query.params.is_empty() {
-        None
-    } else {
-        Some(
-            query
-                .params
-                .iter()
-                .map(|(k, v)| format!("{}={}", k, v))
-                .collect::<Vec<_>>()
-                .join("&"),
-        )
-    };
+    #[test]
+    fn test_response_header_policy_counts_at_debug_level() {
+        let mut headers = ResponseHeaders::new();
+        headers.insert("content-type", "text/plain");
+        headers.insert("X-Powered-By", "php");
 
-    // Extract request headers for Vary-based cache keying (RFC 9111)
-    let request_headers_map = extract_request_headers(&headers);
+        let state = test_state(Config::default());
+        let mut passthrough = headers.clone();
+        apply_response_header_policy(&state, &mut passthrough);
+        assert!(
+            !state
+                .metrics
+                .gather()
+                .contains("cdn_response_headers_dropped_total")
+        );
 
-    // Check request cache control
-    let bypass_cache = headers
-        .get(header::CACHE_CONTROL)
-        .and_then(|v| v.to_str().ok())
-        .map(|v| v.contains("no-cache") || v.contains("no-store"))
-        .unwrap_or(false);
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(std::io::sink)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            apply_response_header_policy(&state, &mut passthrough);
+        });
+        assert_eq!(passthrough.len(), 2);
+        assert!(state.metrics.gather().contains(
+            r#"cdn_response_headers_dropped_total{header="x-powered-by",mode="passthrough"} 1"#
+        ));
 
-    let mut cache_status;
-    let response_body;
-    let response_headers;
-    let response_status;
-    let mut cache_age_secs: Option<u64> = None;
+        let mut config = Config::default();
+        config.security.response_header_mode = ResponseHeaderMode::Allowlist;
+        let state = test_state(config);
+        let mut allowlisted = headers.clone();
+        apply_response_header_policy(&state, &mut allowlisted);
+        assert_eq!(allowlisted.len(), 1);
+        assert!(allowlisted.get("content-type").is_some());
+    }
 
-    if bypass_cache {
-        // Client requested bypass
-        cache_status = CacheStatus::Bypass;
-        match fetch_from_origin_with_circuit_breaker(
+    #[test]
+    fn test_store_in_cache_enforces_header_limits() {
+        let mut config = Config::default();
+        config.cache.header_limits.max_count = 10;
+        config.cache.header_limits.action = crate::config::HeaderLimitAction::Uncacheable;
+        let state = test_state(config);
+
+        let mut headers = ResponseHeaders::new();
+        headers.append("content-type", "text/html");
+        for i in 0..4000 {
+            headers.append("x-dup", format!("v{}", i));
+        }
+
+        store_in_cache(
             &state,
-            &origin,
-            &path,
-            query_string.as_deref(),
-            &headers,
-        )
-        .await
-        {
-            Ok(origin_response) => {
-                response_body = origin_response.0;
-                response_headers = origin_response.1;
-                response_status = origin_response.2;
-            }
-            Err(e) => return Err(e),
+            "web",
+            state.cache.reserve("web/index"),
+            Bytes::from("x"),
+            headers,
+            StatusCode::OK,
+        );
+
+        assert!(state.cache.get("web/index").is_none());
+        assert!(
+            state
+                .metrics
+                .gather()
+                .contains(r#"cdn_cache_header_limit_total{action="uncacheable",origin="web"} 1"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_streams_ndjson() {
+        let state = test_state(Config::default());
+        let mut headers = ResponseHeaders::new();
+        headers.insert("cache-control", "max-age=600");
+        for i in 0..3 {
+            store_in_cache(
+                &state,
+                "web",
+                state.cache.reserve(&format!("web/page{}", i)),
+                Bytes::from("body"),
+                headers.clone(),
+                StatusCode::OK,
+            );
         }
-    } else {
-        // Generate Vary-aware cache key using common Vary headers (Accept-Encoding)
-        // This ensures different compression variants are cached separately
-        let cache_key = generate_cache_key_with_vary(
-            &origin,
-            &format!("/{}", path),
-            query_string.as_deref(),
-            Some("accept-encoding"), // Default Vary for compression support
-            &request_headers_map,
+
+        let response = export_cache(State(state)).await.unwrap();
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/x-ndjson"
         );
 
-        // Try cache first
-        match state.cache.get(&cache_key) {
-            Some((entry, status)) => {
-                cache_status = status;
-                // Calculate Age header value (RFC 9111)
-                cache_age_secs = Some(entry.created_at.elapsed().as_secs());
-                response_body = entry.body;
-                response_headers = entry.headers;
-                response_status = StatusCode::from_u16(entry.status_code).unwrap_or(StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let records: Vec<crate::cache::CacheKeyRecord> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
 
-                // If stale, trigger background revalidation
-                if status == CacheStatus::Stale {
-                    let state_clone = state.clone();
-                    let origin_clone = origin.clone();
-                    let path_clone = path.clone();
-                    let query_clone = query_string.clone();
-                    let request_headers_clone = request_headers_map.clone();
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|r| r.origin == "web" && r.size == 4));
+    }
 
-                    tokio::spawn(async move {
-                        if let Ok((body, headers, status)) = fetch_from_origin_with_circuit_breaker(
-                            &state_clone,
-                            &origin_clone,
-                            &path_clone,
-                            query_clone.as_deref(),
-                            &HeaderMap::new(),
-                        )
-                        .await
-                        {
-                            // Generate cache key with actual Vary header from response
-                            let vary_header = headers.get("vary").map(|s| s.as_str());
-                            let final_cache_key = generate_cache_key_with_vary(
-                                &origin_clone,
-                                &format!("/{}", path_clone),
-                                query_clone.as_deref(),
-                                vary_header.or(Some("accept-encoding")),
-                                &request_headers_clone,
-                            );
-                            store_in_cache(&state_clone, &final_cache_key, body, headers, status);
-                        }
-                    });
-                }
-            }
-            None => {
-                // Cache miss - fetch from origin (with optional coalescing)
-                cache_status = CacheStatus::Miss;
+    #[tokio::test]
+    async fn test_import_rejects_invalid_lines() {
+        let state = test_state(Config::default());
+        let body = "{\"origin\":\"web\",\"path\":\"/a\"}\nnot json\n".to_string();
 
-                // Use coalescing to prevent thundering herd
-                let fetch_result = if state.coalesce_enabled {
-                    match state.coalescer.try_acquire(&cache_key) {
-                        AcquireResult::Fetch(guard) => {
-                            // We are the leader - fetch from origin
-                            match fetch_from_origin_with_circuit_breaker(
-                                &state,
-                                &origin,
-                                &path,
-                                query_string.as_deref(),
-                                &headers,
-                            )
-                            .await
-                            {
-                                Ok((body, hdrs, status)) => {
-                                    // Complete the coalesce to notify waiters
-                                    guard.complete(CoalescedResponse {
-                                        body: body.clone(),
-                                        headers: hdrs.clone(),
-                                        status_code: status.as_u16(),
-                                    });
-                                    Ok((body, hdrs, status))
-                                }
-                                Err(e) => {
-                                    // Complete with error to notify waiters
-                                    guard.complete_error(e.to_string());
-                                    Err(e)
-                                }
-                            }
-                        }
-                        AcquireResult::Wait(mut receiver) => {
-                            // Another request is already fetching - wait for result
-                            tracing::debug!(cache_key = %cache_key, "Waiting for coalesced request");
-                            match receiver.recv().await {
-                                Ok(Ok(coalesced)) => {
-                                    let status = StatusCode::from_u16(coalesced.status_code)
-                                        .unwrap_or(StatusCode::OK);
-                                    Ok((coalesced.body, coalesced.headers, status))
-                                }
-                                Ok(Err(err)) => Err(CdnError::OriginError(err)),
-                                Err(_) => Err(CdnError::Internal(
-                                    "Coalesced request was cancelled".to_string(),
-                                )),
-                            }
-                        }
-                    }
-                } else {
-                    // Coalescing disabled - direct fetch
-                    fetch_from_origin_with_circuit_breaker(
-                        &state,
-                        &origin,
-                        &path,
-                        query_string.as_deref(),
-                        &headers,
-                    )
-                    .await
-                };
+        let result = import_cache(
+            State(state.clone()),
+            Query(CacheImportQuery { concurrency: None }),
+            body,
+        )
+        .await;
 
-                match fetch_result {
-                    Ok(origin_response) => {
-                        // Check if origin returned 5xx error - try stale-if-error
-                        if origin_response.2.is_server_error() {
-                            // RFC 5861: Try to serve stale content on 5xx errors
-                            if let Some(stale_entry) = state.cache.get_stale_for_error(&cache_key) {
-                                cache_status = CacheStatus::StaleIfError;
-                                cache_age_secs = Some(stale_entry.created_at.elapsed().as_secs());
-                                response_body = stale_entry.body;
-                                response_headers = stale_entry.headers;
-                                response_status = StatusCode::from_u16(stale_entry.status_code)
-                                    .unwrap_or(StatusCode::OK);
-                                tracing::info!(
-                                    origin = %origin,
-                                    path = %path,
-                                    origin_status = %origin_response.2,
-                                    "Serving stale content due to origin 5xx error (stale-if-error)"
-                                );
-                            } else {
-                                // No stale content available, return the 5xx response
-                                response_body = origin_response.0.clone();
-                                response_headers = origin_response.1.clone();
-                                response_status = origin_response.2;
-                            }
-                        } else {
-                            response_body = origin_response.0.clone();
-                            response_headers = origin_response.1.clone();
-                            response_status = origin_response.2;
-
-                            // Store in cache if cacheable
-                            if is_cacheable(response_status, &response_headers) {
-                                // Generate cache key with actual Vary header from response (RFC 9111)
-                                let vary_header = response_headers.get("vary").map(|s| s.as_str());
-                                let final_cache_key = generate_cache_key_with_vary(
-                                    &origin,
-                                    &format!("/{}", path),
-                                    query_string.as_deref(),
-                                    vary_header.or(Some("accept-encoding")),
-                                    &request_headers_map,
-                                );
-                                store_in_cache(
-                                    &state,
-                                    &final_cache_key,
-                                    origin_response.0,
-                                    origin_response.1,
-                                    response_status,
-                                );
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        // RFC 5861: Try stale-if-error on connection/fetch errors too
-                        if let Some(stale_entry) = state.cache.get_stale_for_error(&cache_key) {
-                            cache_status = CacheStatus::StaleIfError;
-                            cache_age_secs = Some(stale_entry.created_at.elapsed().as_secs());
-                            response_body = stale_entry.body;
-                            response_headers = stale_entry.headers;
-                            response_status = StatusCode::from_u16(stale_entry.status_code)
-                                .unwrap_or(StatusCode::OK);
-                            tracing::info!(
-                                origin = %origin,
-                                path = %path,
-                                error = %e,
-                                "Serving stale content due to origin error (stale-if-error)"
-                            );
-                        } else {
-                            return Err(e);
-                        }
-                    }
-                }
+        assert!(matches!(result, Err(CdnError::InvalidRequest(msg)) if msg.contains("line 2")));
+    }
+
+    #[tokio::test]
+    async fn test_import_dedupes_and_reports_job() {
+        let state = test_state(Config::default());
+        // Two variants of the same URL plus an unknown origin
+        let body = [
+            r#"{"key":"web/a|vary:accept-encoding=gzip","origin":"web","path":"/a","size":1}"#,
+            r#"{"key":"web/a|vary:accept-encoding=br","origin":"web","path":"/a","size":1}"#,
+            r#"{"origin":"web","path":"/b?x=1"}"#,
+        ]
+        .join("\n");
+
+        let (status, Json(response)) = import_cache(
+            State(state.clone()),
+            Query(CacheImportQuery {
+                concurrency: Some(2),
+            }),
+            body,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(response.total, 2);
+
+        // "web" isn't a configured origin, so both items fail quickly
+        let mut job = state.jobs.get(&response.job_id).unwrap();
+        for _ in 0..100 {
+            if job.state == "completed" {
+                break;
             }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            job = state.jobs.get(&response.job_id).unwrap();
         }
+        assert_eq!(job.state, "completed");
+        assert_eq!(job.failed, 2);
     }
 
-    // Update metrics
-    let duration = start.elapsed();
-    state
-        .metrics
-        .record_request(&origin, cache_status, response_status, duration);
+    #[tokio::test]
+    async fn test_replay_warms_recently_served_keys() {
+        let (addr, mut requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\ncache-control: max-age=60\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok"
+        })
+        .await;
+        let state = test_state(config_with_origin(addr));
 
-    // RFC 9110 Section 14: Handle Range requests
-    // Only process Range header for successful responses and GET requests
-    let range_request: Option<ByteRange> = if !is_head_request && response_status.is_success() {
-        if let Some(range_header) = headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
-            let content_length = response_body.len() as u64;
-            match parse_range_header(range_header, content_length) {
-                RangeParseResult::Single(range) => Some(range),
-                RangeParseResult::Multiple(_) => {
-                    // Multi-range not supported, serve full content
-                    None
-                }
-                RangeParseResult::Invalid => {
-                    // Return 416 Range Not Satisfiable
-                    return build_range_not_satisfiable_response(content_length);
-                }
-                RangeParseResult::None => None,
+        for path in ["hot.js", "hot.js", "hot.js", "cold.js"] {
+            let (response, _) = get(&state, path, HeaderMap::new()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let Json(recent) = recent_cache_keys(
+            State(state.clone()),
+            Query(RecentKeysQuery {
+                top: None,
+                window: Some("1h".to_string()),
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(recent.enabled);
+        let served: Vec<_> = recent
+            .entries
+            .iter()
+            .map(|e| (e.path.as_str(), e.hits))
+            .collect();
+        assert_eq!(served, vec![("/hot.js", 3), ("/cold.js", 1)]);
+        assert!(
+            recent_cache_keys(
+                State(state.clone()),
+                Query(RecentKeysQuery {
+                    top: None,
+                    window: Some("soon".to_string()),
+                }),
+            )
+            .await
+            .is_err()
+        );
+
+        // After a purge, replaying the busiest key refills only that key
+        state.cache.purge_all();
+        while requests.try_recv().is_ok() {}
+        let (status, Json(response)) = replay_warm(
+            State(state.clone()),
+            Query(ReplayWarmQuery {
+                top: Some(1),
+                window: None,
+                concurrency: None,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(response.total, 1);
+
+        let mut job = state.jobs.get(&response.job_id).unwrap();
+        for _ in 0..100 {
+            if job.state == "completed" {
+                break;
             }
-        } else {
-            None
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            job = state.jobs.get(&response.job_id).unwrap();
+        }
+        assert_eq!(job.kind, "warm-replay");
+        assert_eq!((job.state.as_str(), job.succeeded), ("completed", 1));
+        assert!(requests.recv().await.unwrap().starts_with("get /hot.js "));
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_window_holds_the_circuit_breaker() {
+        use crate::config::{MaintenanceConfig, MaintenanceWindowConfig};
+
+        let threshold = CircuitBreakerConfig::default().failure_threshold;
+        let (addr, _requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 4\r\nconnection: close\r\n\r\nboot"
+        })
+        .await;
+        // Midnight to midnight every day, so always open
+        let always = || MaintenanceConfig {
+            windows: vec![MaintenanceWindowConfig {
+                days: Vec::new(),
+                start: chrono::NaiveTime::MIN,
+                end: chrono::NaiveTime::MIN,
+                timezone: chrono_tz::Tz::UTC,
+            }],
+            trip_circuit_breaker: false,
+        };
+
+        let mut config = config_with_origin(addr);
+        config.origin_errors.http_5xx.trip_circuit_breaker = true;
+        config.maintenance = always();
+        let state = test_state(config);
+        for _ in 0..threshold * 2 {
+            let (response, _) = get(&state, "/app.js", HeaderMap::new()).await;
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
         }
-    } else {
-        None
-    };
+        assert_eq!(state.circuit_breaker.state("web"), CircuitState::Closed);
 
-    // Build response with RFC-compliant headers
-    build_response(
-        response_body,
-        response_headers,
-        response_status,
-        cache_status,
-        cache_age_secs,
-        is_head_request,
-        range_request.as_ref(),
-    )
-}
+        // An origin's own window can let failures trip the breaker
+        let mut config = config_with_origin(addr);
+        config.origin_errors.http_5xx.trip_circuit_breaker = true;
+        let origin = config.origins.get_mut("web").unwrap();
+        origin.maintenance = always();
+        origin.maintenance.trip_circuit_breaker = true;
+        let state = test_state(config);
+        for _ in 0..threshold {
+            get(&state, "/app.js", HeaderMap::new()).await;
+        }
+        assert_eq!(state.circuit_breaker.state("web"), CircuitState::Open);
+    }
 
-async fn fetch_from_origin_with_circuit_breaker(
-    state: &Arc<AppState>,
-    origin: &str,
-    path: &str,
-    query: Option<&str>,
-    headers: &HeaderMap,
-) -> CdnResult<(Bytes, HashMap<String, String>, StatusCode)> {
-    match fetch_from_origin(state, origin, path, query, headers).await {
-        Ok(result) => {
-            state.circuit_breaker.record_success(origin);
-            Ok(result)
+    #[tokio::test]
+    async fn test_origin_error_policy_per_kind() {
+        let threshold = CircuitBreakerConfig::default().failure_threshold;
+        let (addr, _requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 4\r\nconnection: close\r\n\r\nboom"
+        })
+        .await;
+
+        // A 5xx is passed on and labelled, but doesn't trip the breaker by default
+        let state = test_state(config_with_origin(addr));
+        for _ in 0..threshold {
+            let (response, body) = get(&state, "/app.js", HeaderMap::new()).await;
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(body, Bytes::from("boom"));
+            assert_eq!(
+                response.extensions().get::<OriginErrorKind>(),
+                Some(&OriginErrorKind::Http5xx(500))
+            );
         }
-        Err(e) => {
-            state.circuit_breaker.record_failure(origin);
-            Err(e)
+        assert_eq!(state.circuit_breaker.state("web"), CircuitState::Closed);
+
+        let mut config = config_with_origin(addr);
+        config.origin_errors.http_5xx.trip_circuit_breaker = true;
+        let state = test_state(config);
+        for _ in 0..threshold {
+            get(&state, "/app.js", HeaderMap::new()).await;
         }
+        assert_eq!(state.circuit_breaker.state("web"), CircuitState::Open);
+        assert!(
+            state
+                .metrics
+                .gather()
+                .contains(r#"cdn_origin_errors_total{kind="http_5xx",origin="web"} 5"#)
+        );
+
+        // Refused connections are their own kind with their own policy
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+        let mut config = config_with_origin(closed);
+        config.origin_errors.connect.trip_circuit_breaker = false;
+        let state = test_state(config);
+        for _ in 0..threshold {
+            let err = serve_cdn_request(
+                state.clone(),
+                Method::GET,
+                "web".to_string(),
+                "/app.js".to_string(),
+                CdnQuery {
+                    params: HashMap::new(),
+                },
+                HeaderMap::new(),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.origin_error_kind(), Some(OriginErrorKind::ConnectError));
+            assert_eq!(err.status_code(), StatusCode::BAD_GATEWAY);
+        }
+        assert_eq!(state.circuit_breaker.state("web"), CircuitState::Closed);
     }
-}
 
-async fn fetch_from_origin(
-    state: &Arc<AppState>,
-    origin: &str,
-    path: &str,
-    query: Option<&str>,
-    headers: &HeaderMap,
-) -> CdnResult<(Bytes, HashMap<String, String>, StatusCode)> {
-    let request_headers = extract_request_headers(headers);
+    /// Serve a request expected to fail, rendered the way a client sees it
+    async fn get_error(state: &Arc<AppState>, path: &str, headers: HeaderMap) -> Response {
+        serve_cdn_request(
+            state.clone(),
+            Method::GET,
+            "web".to_string(),
+            path.to_string(),
+            CdnQuery {
+                params: HashMap::new(),
+            },
+            headers,
+        )
+        .await
+        .unwrap_err()
+        .with_origin("web")
+        .into_response()
+    }
 
-    let response = state
-        .origin
-        .fetch(origin, path, query, &request_headers)
-        .await?;
+    #[tokio::test]
+    async fn test_origin_failure_status_per_mode() {
+        // Nothing listening: a bad gateway, not an unavailable service
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+        let state = test_state(config_with_origin(closed));
+        let response = get_error(&state, "/refused.js", HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            response.extensions().get::<OriginErrorKind>(),
+            Some(&OriginErrorKind::ConnectError)
+        );
 
-    let status = StatusCode::from_u16(response.status_code).unwrap_or(StatusCode::OK);
-    Ok((response.body, response.headers, status))
-}
+        // An origin slower than its timeout is a gateway timeout
+        let (slow, _accepted) = spawn_slow_origin(Duration::from_secs(3)).await;
+        let mut config = config_with_origin(slow);
+        config.origins.get_mut("web").unwrap().timeout_secs = 1;
+        let state = test_state(config);
+        let response = get_error(&state, "/slow.js", HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(
+            response.extensions().get::<OriginErrorKind>(),
+            Some(&OriginErrorKind::Timeout)
+        );
 
-fn extract_request_headers(headers: &HeaderMap) -> HashMap<String, String> {
-    let mut map = HashMap::new();
-    for (key, value) in headers.iter() {
-        if let Ok(v) = value.to_str() {
-            map.insert(key.to_string(), v.to_string());
+        // An open breaker answers 503 and says when to come back
+        for _ in 0..CircuitBreakerConfig::default().failure_threshold {
+            state.circuit_breaker.record_failure("web");
         }
+        let response = get_error(&state, "/slow.js", HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let retry_after: u64 = response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let reset = CircuitBreakerConfig::default().reset_timeout_secs;
+        assert!(
+            (1..=reset).contains(&retry_after),
+            "retry-after {retry_after}"
+        );
     }
-    map
-}
 
-fn extract_client_ip(headers: &HeaderMap, fallback: std::net::IpAddr) -> std::net::IpAddr {
-    // Check X-Forwarded-For header
-    if let Some(forwarded) = headers.get("X-Forwarded-For")
-        && let Ok(value) = forwarded.to_str()
-            && let Some(first_ip) = value.split(',').next()
-                && let Ok(ip) = first_ip.trim().parse() {
-                    return ip;
-                }
+    #[tokio::test]
+    async fn test_origin_5xx_can_be_masked() {
+        let (addr, _requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 4\r\nconnection: close\r\n\r\nbusy"
+        })
+        .await;
 
-    // Check X-Real-IP header
-    if let Some(real_ip) = headers.get("X-Real-IP")
-        && let Ok(value) = real_ip.to_str()
-            && let Ok(ip) = value.trim().parse() {
-                return ip;
-            }
+        // By default the origin's status and body are passed on
+        let state = test_state(config_with_origin(addr));
+        let (response, body) = get(&state, "/app.js", HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, Bytes::from("busy"));
 
-    fallback
-}
+        let mut config = config_with_origin(addr);
+        config.origin_errors.mask_5xx = true;
+        let state = test_state(config);
+        let mut no_cache = HeaderMap::new();
+        no_cache.insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+        for headers in [HeaderMap::new(), no_cache] {
+            let response = get_error(&state, "/app.js", headers).await;
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+            assert_eq!(
+                response.extensions().get::<OriginErrorKind>(),
+                Some(&OriginErrorKind::Http5xx(503))
+            );
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["status"], 502);
+        }
+    }
 
-fn is_cacheable(status: StatusCode, headers: &HashMap<String, String>) -> bool {
-    // Only cache successful responses
-    if !status.is_success() && status != StatusCode::NOT_MODIFIED {
-        return false;
+    #[tokio::test]
+    async fn test_metrics_serves_openmetrics_when_accepted() {
+        let openmetrics = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+            headers
+        };
+        let content_type = |response: &Response| {
+            response.headers()[header::CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        let accept = "application/openmetrics-text;version=1.0.0,text/plain;q=0.5";
+
+        let state = test_state(Config::default());
+        let response = metrics(State(state), openmetrics(accept))
+            .await
+            .into_response();
+        assert_eq!(content_type(&response), "text/plain; version=0.0.4");
+
+        let mut config = Config::default();
+        config.observability.tracing.enabled = true;
+        config.observability.metrics.exemplars = true;
+        let state = test_state(config);
+        let response = metrics(State(state.clone()), HeaderMap::new())
+            .await
+            .into_response();
+        assert_eq!(content_type(&response), "text/plain; version=0.0.4");
+
+        let response = metrics(State(state), openmetrics(accept))
+            .await
+            .into_response();
+        assert_eq!(content_type(&response), OPENMETRICS_CONTENT_TYPE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.ends_with(b"# EOF\n"));
     }
 
-    // Check Cache-Control header
-    if let Some(cc) = headers.get("cache-control") {
-        let directives = parse_cache_control(cc);
-        return directives.is_cacheable();
+    #[tokio::test]
+    async fn test_cache_key_lookup_matches_live_keys() {
+        let (addr, _requests) = spawn_gzip_origin().await;
+        let state = test_state(config_with_origin(addr));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        get(&state, "index.html", headers).await;
+
+        let lookup = |path: &str, query: Option<&str>, headers: Option<&str>| {
+            cache_key_lookup(
+                State(state.clone()),
+                Query(CacheKeyQuery {
+                    origin: "web".to_string(),
+                    path: path.to_string(),
+                    query: query.map(String::from),
+                    headers: headers.map(String::from),
+                }),
+            )
+        };
+
+        let Json(found) = lookup("/index.html", None, Some(r#"{"Accept-Encoding":"gzip"}"#))
+            .await
+            .unwrap();
+        assert_eq!(found.base_key, "web/index.html");
+        assert_eq!(found.key, "web/index.html|vary:accept-encoding=gzip");
+        assert!(found.cached);
+        let variants: Vec<&str> = found.variants.iter().map(|r| r.key.as_str()).collect();
+        assert_eq!(variants, [found.key.as_str()]);
+
+        // Another coding is a different variant of the same URL
+        let Json(other) = lookup("index.html", None, Some(r#"{"accept-encoding":"br"}"#))
+            .await
+            .unwrap();
+        assert_eq!(other.key, "web/index.html|vary:accept-encoding=br");
+        assert!(!other.cached);
+        assert_eq!(other.variants.len(), 1);
+
+        // Query parameters are keyed in a stable order
+        let Json(queried) = lookup("index.html", Some("b=2&a=1"), None).await.unwrap();
+        assert_eq!(queried.query.as_deref(), Some("a=1&b=2"));
+        assert_eq!(
+            queried.key,
+            "web/index.html?a=1&b=2|vary:accept-encoding=identity"
+        );
+        assert!(queried.variants.is_empty());
+
+        let err = lookup("index.html", None, Some("gzip")).await.unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        let err = cache_key_lookup(
+            State(state.clone()),
+            Query(CacheKeyQuery {
+                origin: "missing".to_string(),
+                path: "index.html".to_string(),
+                query: None,
+                headers: None,
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
     }
 
-    // Default to cacheable for successful responses
-    true
-}
+    #[tokio::test]
+    async fn test_self_test_runs_every_stage_and_cleans_up() {
+        let (addr, mut requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nCache-Control: no-store\r\n\r\nok"
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        config.origins.get_mut("web").unwrap().health_check_path = Some("/healthz".to_string());
+        let state = test_state(config);
 
-fn store_in_cache(
-    state: &Arc<AppState>,
-    cache_key: &str,
-    body: Bytes,
-    headers: HashMap<String, String>,
-    status: StatusCode,
-) {
-    let config = &state.config.cache;
+        let (status, Json(report)) = self_test(
+            State(state.clone()),
+            Query(SelfTestQuery { origin: None }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(report.passed);
+        let result = &report.origins[0];
+        assert_eq!(result.path, "/healthz");
+        assert_eq!(result.failed_stage, None);
+        let stages: Vec<_> = result.stages.iter().map(|s| (s.stage, s.status)).collect();
+        assert_eq!(
+            stages,
+            [
+                (SelfTestStageName::Edge, SelfTestStageStatus::Pass),
+                (SelfTestStageName::CircuitBreaker, SelfTestStageStatus::Pass),
+                (SelfTestStageName::Origin, SelfTestStageStatus::Pass),
+                (SelfTestStageName::Cache, SelfTestStageStatus::Pass),
+                (SelfTestStageName::Cleanup, SelfTestStageStatus::Pass),
+            ]
+        );
+        assert!(requests.recv().await.unwrap().starts_with("get /healthz "));
+        // Nothing is left behind, despite the response being cached for the test
+        assert_eq!(state.cache.stats().total_entries, 0);
+    }
 
-    // Parse Cache-Control directives
-    let directives = headers
-        .get("cache-control")
-        .map(|cc| parse_cache_control(cc))
-        .unwrap_or_default();
+    #[tokio::test]
+    async fn test_self_test_pinpoints_failing_stage() {
+        let (addr, _requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n"
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        config.origins.get_mut("web").unwrap().selftest_path = Some("/probe".to_string());
+        let state = test_state(config);
 
-    // Determine TTL
-    let ttl = directives.ttl(config.default_ttl(), config.max_ttl());
+        let (status, Json(report)) = self_test(
+            State(state.clone()),
+            Query(SelfTestQuery {
+                origin: Some("web".to_string()),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!report.passed);
+        let result = &report.origins[0];
+        assert_eq!(result.path, "/probe");
+        assert_eq!(result.failed_stage, Some(SelfTestStageName::Origin));
+        assert_eq!(result.stages.len(), 3);
+        assert_eq!(
+            result.stages[2].message.as_deref(),
+            Some("Origin answered 503 Service Unavailable")
+        );
 
-    let now = Instant::now();
+        let err = self_test(
+            State(state),
+            Query(SelfTestQuery {
+                origin: Some("missing".to_string()),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+    }
 
-    // Generate ETag if not present
-    let etag = headers.get("etag").cloned().or_else(|| {
-        let hash = xxh3_64(&body);
-        Some(format!("\"{}\"", BASE64.encode(hash.to_be_bytes())))
-    });
+    #[tokio::test]
+    async fn test_synthetic_request_goes_through_edge_rules() {
+        use crate::config::{RewriteActionConfig, RewriteRuleConfig};
 
-    let entry = CacheEntry {
-        size: body.len(),
-        body,
-        headers: headers.clone(),
-        status_code: status.as_u16(),
-        content_type: headers.get("content-type").cloned(),
-        etag,
-        last_modified: headers.get("last-modified").cloned(),
-        created_at: now,
-        expires_at: now + ttl,
-        stale_if_error_secs: directives.stale_if_error,
-        access_count: 0,
-        last_accessed: now,
-        cache_tags: Vec::new(), // Tags will be added separately
-    };
+        let (addr, mut requests) = spawn_test_origin(|_| {
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nCache-Control: max-age=60\r\n\r\nok"
+        })
+        .await;
+        let mut config = config_with_origin(addr);
+        config.edge.rewrite_rules.push(RewriteRuleConfig {
+            name: "legacy".to_string(),
+            pattern: r"^/web/old/(.*)$".to_string(),
+            replacement: "/web/new/$1".to_string(),
+            stop: true,
+            condition: None,
+            action: RewriteActionConfig::Replace,
+        });
+        let state = test_state(config);
 
-    state.cache.set(cache_key.to_string(), entry.clone());
+        let response = SyntheticRequest::get("/web/old/page.html")
+            .serve(state.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-cache"], "MISS");
+        assert!(requests.recv().await.unwrap().starts_with("get /new/page.html "));
 
-    // Extract and add cache tags from Cache-Tag header if present
-    if let Some(cache_tag_header) = headers.get("cache-tag") {
-        let tags: Vec<String> = cache_tag_header
-            .split(',')
-            .map(|tag| tag.trim().to_string())
-            .filter(|tag| !tag.is_empty())
-            .collect();
+        // The rewritten path is what gets cached
+        let (response, _) = get(&state, "new/page.html", HeaderMap::new()).await;
+        assert_eq!(response.headers()["x-cache"], "HIT");
+    }
+
+    #[tokio::test]
+    async fn test_metrics_report_cache_by_content_type() {
+        async fn scrape(state: &Arc<AppState>) -> String {
+            let response = metrics(State(state.clone()), HeaderMap::new())
+                .await
+                .into_response();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
 
-        if !tags.is_empty() {
-            state.cache.add_tags(cache_key, tags);
+        let state = test_state(Config::default());
+        for (key, content_type) in [
+            ("assets/app.css", "text/css; charset=utf-8"),
+            ("assets/site.css", "Text/CSS"),
+            ("assets/logo.png", "image/png"),
+        ] {
+            let mut headers = ResponseHeaders::new();
+            headers.insert("content-type", content_type);
+            store_in_cache(
+                &state,
+                "assets",
+                state.cache.reserve(key),
+                Bytes::from("body"),
+                headers,
+                StatusCode::OK,
+            );
         }
+
+        let by_type = state.cache.stats().by_content_type;
+        assert_eq!(by_type.len(), 2);
+        assert_eq!(by_type["text/css"].entry_count, 2);
+        let output = scrape(&state).await;
+        assert!(output.contains(r#"cdn_cache_content_type_entries{content_type="text/css"} 2"#));
+        assert!(output.contains(r#"cdn_cache_content_type_entries{content_type="image/png"} 1"#));
+        assert!(output.contains(&format!(
+            r#"cdn_cache_content_type_bytes{{content_type="image/png"}} {}"#,
+            by_type["image/png"].total_size_bytes
+        )));
+
+        // Types no longer cached drop out of the next scrape
+        state.cache.invalidate("assets/logo.png");
+        let output = scrape(&state).await;
+        assert!(!output.contains(r#"content_type="image/png""#));
+        assert!(output.contains(r#"cdn_cache_content_type_entries{content_type="text/css"} 2"#));
     }
-}
 
-fn build_response(
-    body: Bytes,
-    headers: HashMap<String, String>,
-    status: StatusCode,
-    cache_status: CacheStatus,
-    cache_age_secs: Option<u64>,
-    is_head_request: bool,
-    range_request: Option<&ByteRange>,
-) -> CdnResult<Response> {
-    let content_length = body.len() as u64;
-
-    // Determine if we're serving a range response
-    let (final_status, final_body, content_range) = if let Some(range) = range_request {
-        // Serve partial content (206)
-        let range_body = extract_range(&body, range);
-        let content_range = range.content_range_header(content_length);
-        (StatusCode::PARTIAL_CONTENT, range_body, Some(content_range))
-    } else {
-        (status, body, None)
-    };
+    #[tokio::test]
+    async fn test_metrics_cap_per_path_series() {
+        async fn scrape(state: &Arc<AppState>) -> String {
+            let response = metrics(State(state.clone()), HeaderMap::new())
+                .await
+                .into_response();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+        let prefixes = |output: &str| -> HashSet<String> {
+            output
+                .lines()
+                .filter(|l| l.starts_with("cdn_requests_by_path_total{"))
+                .filter_map(|l| l.split("path_prefix=\"").nth(1)?.split('"').next())
+                .map(str::to_string)
+                .collect()
+        };
 
-    let mut response = Response::builder().status(final_status);
+        let (addr, _requests) = spawn_test_origin(
+            |_| "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+        )
+        .await;
+        let mut config = config_with_origin(addr);
+        config.observability.metrics.max_path_prefixes = 3;
+        config.observability.metrics.path_prefix_idle_secs = 3600;
+        let state = test_state(config.clone());
+        for i in 0..50 {
+            let (response, _) = get(&state, &format!("section{}/page", i), HeaderMap::new()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
 
-    // Add headers from origin/cache
-    for (key, value) in &headers {
-        // Skip Content-Length for range responses - we'll set it correctly
-        if range_request.is_some() && key.to_lowercase() == "content-length" {
-            continue;
+        let output = scrape(&state).await;
+        assert_eq!(
+            prefixes(&output),
+            HashSet::from(
+                [
+                    "/section0/page",
+                    "/section1/page",
+                    "/section2/page",
+                    "other"
+                ]
+                .map(str::to_string)
+            )
+        );
+        assert!(output.contains(
+            r#"cdn_requests_by_path_total{origin="web",path_prefix="other",status="200"} 47"#
+        ));
+
+        // Idle prefixes give their series up to new ones
+        config.observability.metrics.path_prefix_idle_secs = 0;
+        let state = test_state(config.clone());
+        for path in ["a", "b", "c", "d"] {
+            get(&state, path, HeaderMap::new()).await;
         }
-        if let Ok(header_value) = HeaderValue::from_str(value) {
-            response = response.header(key.as_str(), header_value);
+        let output = scrape(&state).await;
+        assert!(!prefixes(&output).contains("/a"));
+        assert!(prefixes(&output).contains("/d"));
+
+        config.observability.metrics.per_path_metrics = false;
+        let state = test_state(config);
+        get(&state, "a", HeaderMap::new()).await;
+        assert!(!scrape(&state).await.contains("cdn_requests_by_path_total"));
+    }
+
+    /// State over a fake origin and cache; the configured origin is never dialed
+    fn fake_state(origin: &Arc<FakeOrigin>, stale: Option<CacheEntry>) -> Arc<AppState> {
+        let mut state = test_app_state(config_with_origin("127.0.0.1:9".parse().unwrap()), &[]);
+        let mut cache = FakeCache::new(state.cache.clone());
+        if let Some(entry) = stale {
+            cache = cache.with_stale(entry);
         }
+        state.content_cache = Arc::new(cache);
+        state.origin_client = origin.clone();
+        Arc::new(state)
     }
 
-    // Add CDN-specific headers
-    response = response.header("X-Cache", cache_status.as_str());
-    response = response.header("X-CDN", "Screaming-Eagle");
+    /// Like [`get`], but an error comes back as the response it renders to
+    async fn get_or_error(state: Arc<AppState>, path: &str) -> (StatusCode, Bytes) {
+        let response = serve_cdn_request(
+            state,
+            Method::GET,
+            "web".to_string(),
+            path.to_string(),
+            CdnQuery {
+                params: HashMap::new(),
+            },
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap_or_else(|e| e.into_response());
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, body)
+    }
 
-    // RFC 9110: Date header - indicates when the message was generated
-    let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
-    response = response.header(header::DATE, date);
+    #[tokio::test]
+    async fn test_origin_5xx_without_stale_copy_is_passed_through() {
+        let origin = Arc::new(FakeOrigin::new(|_| Ok(test_support::response(503, "down"))));
+        let state = fake_state(&origin, None);
 
-    // RFC 9110: Via header - identifies intermediate proxies
-    response = response.header(header::VIA, "1.1 screaming-eagle");
+        let (response, body) = get(&state, "/page", HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["x-cache"], "MISS");
+        assert_eq!(&body[..], b"down");
+        assert_eq!(origin.calls().len(), 1);
+    }
 
-    // RFC 9111: Age header - indicates time in cache (only for cached responses)
-    if let Some(age) = cache_age_secs {
-        response = response.header(header::AGE, age.to_string());
+    #[tokio::test]
+    async fn test_origin_5xx_with_stale_copy_serves_it() {
+        let origin = Arc::new(FakeOrigin::new(|_| Ok(test_support::response(503, "down"))));
+        let state = fake_state(&origin, Some(test_support::expired_entry("old")));
+
+        let (response, body) = get(&state, "/page", HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-cache"], "STALE-IF-ERROR");
+        assert_eq!(&body[..], b"old");
     }
 
-    // RFC 9110: Accept-Ranges header - indicate we support byte ranges
-    response = response.header(header::ACCEPT_RANGES, "bytes");
+    #[tokio::test]
+    async fn test_coalesced_waiter_receives_the_leaders_error() {
+        let origin = Arc::new(
+            FakeOrigin::new(|_| {
+                Err(CdnError::OriginFetch {
+                    kind: OriginErrorKind::ConnectError,
+                    message: "connection refused".to_string(),
+                })
+            })
+            .held(),
+        );
+        let state = fake_state(&origin, None);
 
-    // RFC 9110: Content-Range header for partial responses
-    if let Some(cr) = content_range {
-        response = response.header(header::CONTENT_RANGE, cr);
-        // Set correct Content-Length for the partial response
-        response = response.header(header::CONTENT_LENGTH, final_body.len().to_string());
+        let leader = tokio::spawn(get_or_error(state.clone(), "/page"));
+        while origin.calls().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let waiter = tokio::spawn(get_or_error(state.clone(), "/page"));
+        while state.coalescer.stats().total_waiters == 0 {
+            tokio::task::yield_now().await;
+        }
+        origin.release();
+
+        let (leader_status, leader_body) = leader.await.unwrap();
+        let (waiter_status, waiter_body) = waiter.await.unwrap();
+        assert!(leader_status.is_server_error());
+        assert_eq!(waiter_status, leader_status);
+        assert_eq!(waiter_body, leader_body);
+        // Only the leader reached the origin
+        assert_eq!(origin.calls().len(), 1);
     }
 
-    // For HEAD requests, return empty body but keep Content-Length from original
-    let response_body = if is_head_request {
-        Body::empty()
-    } else {
-        Body::from(final_body)
-    };
+    #[tokio::test]
+    async fn test_bypass_with_range_slices_a_full_fetch() {
+        let origin = Arc::new(FakeOrigin::new(|_| {
+            Ok(test_support::response(200, "abcdefgh"))
+        }));
+        let state = fake_state(&origin, None);
 
-    response
-        .body(response_body)
-        .map_err(|e| CdnError::Internal(format!("Failed to build response: {}", e)))
-}
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
+        headers.insert(header::RANGE, "bytes=0-3".parse().unwrap());
+        let (response, body) = get(&state, "/page", headers).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["x-cache"], "BYPASS");
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-3/8");
+        assert_eq!(&body[..], b"abcd");
 
-/// Build a 416 Range Not Satisfiable response
-fn build_range_not_satisfiable_response(content_length: u64) -> CdnResult<Response> {
-    let mut response = Response::builder().status(StatusCode::RANGE_NOT_SATISFIABLE);
+        // The whole object is fetched, not just the range
+        let calls = origin.calls();
+        assert_eq!(calls.len(), 1);
+        assert!(calls[0].starts_with("GET "), "{:?}", calls);
+    }
 
-    // RFC 9110: Content-Range header with unsatisfied-range
-    response = response.header(header::CONTENT_RANGE, format!("bytes */{}", content_length));
+    #[tokio::test]
+    async fn test_background_revalidations_are_capped() {
+        let origin = Arc::new(FakeOrigin::new(|_| Ok(test_support::response(200, "new"))).held());
+        let mut config = config_with_origin("127.0.0.1:9".parse().unwrap());
+        config.cache.max_concurrent_revalidations = 4;
+        let mut state = test_app_state(config, &[]);
+        state.origin_client = origin.clone();
+        let state = Arc::new(state);
+        for i in 0..40 {
+            let path = format!("page-{}", i);
+            let key = CacheKeyBuilder::new("web", &path, &state.config.cache.key).lookup_key();
+            state.cache.set(key, test_support::expired_entry("old"));
+        }
 
-    // Add standard headers
-    let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
-    response = response.header(header::DATE, date);
-    response = response.header(header::VIA, "1.1 screaming-eagle");
-    response = response.header("X-CDN", "Screaming-Eagle");
-    response = response.header(header::ACCEPT_RANGES, "bytes");
+        // An expiry storm: two stale hits on each of 40 keys
+        let requests: Vec<_> = (0..80)
+            .map(|i| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    get(&state, &format!("page-{}", i % 40), HeaderMap::new()).await
+                })
+            })
+            .collect();
+        for request in requests {
+            let (response, body) = request.await.unwrap();
+            assert_eq!(response.headers()["x-cache"], "STALE");
+            assert_eq!(&body[..], b"old");
+            assert!(state.revalidations.in_flight() <= 4);
+        }
 
-    response
-        .body(Body::empty())
-        .map_err(|e| CdnError::Internal(format!("Failed to build response: {}", e)))
-}
+        // The second hit on a key being revalidated starts nothing; the rest
+        // past the cap are skipped
+        assert_eq!(state.revalidations.in_flight(), 4);
+        let text = state.metrics.gather();
+        assert!(text.contains("cdn_cache_revalidations_in_flight 4"));
+        assert!(text.contains("cdn_cache_revalidations_skipped_total 72"));
 
-// Catch-all handler for root origin requests - supports both GET and HEAD
-pub async fn root_cdn_handler(
-    State(state): State<Arc<AppState>>,
-    connect_info: ConnectInfo<SocketAddr>,
-    method: Method,
-    Path(path): Path<String>,
-    Query(query): Query<CdnQuery>,
-    headers: HeaderMap,
-) -> Result<Response, CdnError> {
-    // Use default origin if only one is configured
-    let origins = state.origin.origin_names();
-    if origins.len() == 1 {
-        let origin = origins[0].to_string();
-        return cdn_handler(
-            State(state),
-            connect_info,
-            method,
-            Path((origin, path)),
-            Query(query),
-            headers,
-        )
-        .await;
+        origin.release();
+        while state.revalidations.in_flight() > 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(origin.calls().len(), 4);
+        assert!(
+            state
+                .metrics
+                .gather()
+                .contains("cdn_cache_revalidations_in_flight 0")
+        );
     }
-
-    Err(CdnError::InvalidRequest(
-        "Origin must be specified in path: /<origin>/<path>".to_string(),
-    ))
 }
@@ -0,0 +1,554 @@
+//! Response header storage
+//!
+//! Origin response headers are kept as an ordered multimap so repeated headers
+//! (multiple `Set-Cookie`, `Vary`, `Link`, ...) survive the trip through the
+//! cache instead of being collapsed by a `HashMap`. Names are stored lowercase.
+
+use crate::config::{HeaderLimitAction, HeaderLimitsConfig};
+
+/// Headers that are always kept when truncating, since dropping them would
+/// change how the body is interpreted or revalidated
+const ESSENTIAL_HEADERS: &[&str] = &[
+    "content-type",
+    "content-encoding",
+    "cache-control",
+    "etag",
+    "last-modified",
+    "vary",
+];
+
+/// Headers a 304 may update on the stored response (RFC 9111 Section 3.2)
+///
+/// Everything else describes the stored body, which a 304 never replaces. A
+/// 304 answered for another variant could otherwise relabel the stored body
+/// with that variant's Content-Type, Content-Encoding or Content-Length.
+const NOT_MODIFIED_UPDATES: &[&str] = &[
+    "cache-control",
+    "content-location",
+    "date",
+    "etag",
+    "expires",
+    "last-modified",
+    "vary",
+];
+
+/// Ordered multimap of response headers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResponseHeaders {
+    entries: Vec<(String, String)>,
+}
+
+/// Why a 304 can't be applied to a stored response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotModifiedMismatch {
+    /// Its ETag or Last-Modified identifies a different representation
+    Validator,
+    /// It varies on different request headers, so the stored variant is keyed wrongly
+    Vary,
+}
+
+/// Result of enforcing header limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderLimitOutcome {
+    /// Headers were within limits and left untouched
+    WithinLimits,
+    /// Non-essential headers were dropped to fit the limits
+    Truncated { dropped: usize },
+    /// Limits were exceeded and the response must not be cached
+    Uncacheable,
+}
+
+impl ResponseHeaders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the first value for a header
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// Get every value for a header, in order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a String> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Replace all values of a header with a single value
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into().to_ascii_lowercase();
+        self.entries.retain(|(k, _)| *k != name);
+        self.entries.push((name, value.into()));
+    }
+
+    /// Add a value, keeping any existing values for the header
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries
+            .push((name.into().to_ascii_lowercase(), value.into()));
+    }
+
+    /// Remove every value of a header, returning whether any were present
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        self.entries.len() != before
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&str, &str) -> bool) {
+        self.entries.retain(|(k, v)| f(k, v));
+    }
+
+    /// Replace each value of a header, one at a time, keeping their order
+    pub fn map_values(&mut self, name: &str, mut f: impl FnMut(&str) -> String) {
+        for (k, v) in &mut self.entries {
+            if k.eq_ignore_ascii_case(name) {
+                *v = f(v);
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    /// Number of header lines (a repeated header counts once per value)
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Approximate wire size: name + value per line
+    pub fn total_bytes(&self) -> usize {
+        self.entries.iter().map(|(k, v)| k.len() + v.len()).sum()
+    }
+
+    /// Enforce count and size limits before the headers are stored
+    pub fn enforce_limits(&mut self, limits: &HeaderLimitsConfig) -> HeaderLimitOutcome {
+        if !limits.enabled {
+            return HeaderLimitOutcome::WithinLimits;
+        }
+
+        let exceeds = self.len() > limits.max_count
+            || self.total_bytes() > limits.max_total_bytes
+            || self.entries.iter().any(|(_, v)| v.len() > limits.max_value_bytes);
+
+        if !exceeds {
+            return HeaderLimitOutcome::WithinLimits;
+        }
+
+        if limits.action == HeaderLimitAction::Uncacheable {
+            return HeaderLimitOutcome::Uncacheable;
+        }
+
+        let original_len = self.len();
+        let (essential, optional): (Vec<_>, Vec<_>) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|(k, _)| ESSENTIAL_HEADERS.contains(&k.as_str()));
+
+        let mut total: usize = essential.iter().map(|(k, v)| k.len() + v.len()).sum();
+        self.entries = essential;
+
+        for (k, v) in optional {
+            let size = k.len() + v.len();
+            if v.len() > limits.max_value_bytes
+                || self.entries.len() >= limits.max_count
+                || total + size > limits.max_total_bytes
+            {
+                continue;
+            }
+            total += size;
+            self.entries.push((k, v));
+        }
+
+        HeaderLimitOutcome::Truncated {
+            dropped: original_len - self.len(),
+        }
+    }
+
+    /// Merge the headers of a 304 into these stored headers
+    ///
+    /// Only [`NOT_MODIFIED_UPDATES`] are taken from the 304, each replacing every
+    /// stored value of that header; headers the 304 omits are kept. Nothing is
+    /// changed when the 304's validators or Vary don't match the stored
+    /// response: it then describes another representation, and the caller
+    /// should fetch in full. ETags are compared weakly, and Last-Modified only
+    /// when the 304 has no ETag.
+    pub fn merge_not_modified(
+        &mut self,
+        not_modified: &ResponseHeaders,
+    ) -> Result<(), NotModifiedMismatch> {
+        match (self.get("etag"), not_modified.get("etag")) {
+            (Some(stored), Some(new)) if !etags_match_weak(stored, new) => {
+                return Err(NotModifiedMismatch::Validator);
+            }
+            (_, None) => {
+                if let (Some(stored), Some(new)) =
+                    (self.get("last-modified"), not_modified.get("last-modified"))
+                    && stored.trim() != new.trim()
+                {
+                    return Err(NotModifiedMismatch::Validator);
+                }
+            }
+            _ => {}
+        }
+
+        if not_modified.contains_key("vary")
+            && vary_fields(self.get_all("vary")) != vary_fields(not_modified.get_all("vary"))
+        {
+            return Err(NotModifiedMismatch::Vary);
+        }
+
+        for name in NOT_MODIFIED_UPDATES {
+            let values: Vec<String> = not_modified.get_all(name).cloned().collect();
+            if values.is_empty() {
+                continue;
+            }
+            self.remove(name);
+            for value in values {
+                self.append(*name, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// ETag weak comparison (RFC 9110 Section 8.8.3.2): equal once `W/` is ignored
+fn etags_match_weak(a: &str, b: &str) -> bool {
+    let opaque = |tag: &str| {
+        let tag = tag.trim();
+        tag.strip_prefix("W/").unwrap_or(tag).to_string()
+    };
+    opaque(a) == opaque(b)
+}
+
+/// Field names listed across Vary values, lowercased, sorted and deduplicated
+fn vary_fields<'a>(values: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let mut fields: Vec<String> = values
+        .flat_map(|value| value.split(','))
+        .map(|field| field.trim().to_ascii_lowercase())
+        .filter(|field| !field.is_empty())
+        .collect();
+    fields.sort_unstable();
+    fields.dedup();
+    fields
+}
+
+impl<'a> IntoIterator for &'a ResponseHeaders {
+    type Item = (&'a String, &'a String);
+    type IntoIter = std::iter::Map<
+        std::slice::Iter<'a, (String, String)>,
+        fn(&'a (String, String)) -> (&'a String, &'a String),
+    >;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for ResponseHeaders {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut headers = Self::new();
+        for (k, v) in iter {
+            headers.append(k, v);
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(action: HeaderLimitAction) -> HeaderLimitsConfig {
+        HeaderLimitsConfig {
+            enabled: true,
+            max_count: 4,
+            max_value_bytes: 64,
+            max_total_bytes: 256,
+            action,
+        }
+    }
+
+    #[test]
+    fn test_preserves_repeated_headers() {
+        let mut headers = ResponseHeaders::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("set-cookie", "b=2");
+        headers.append("Content-Type", "text/html");
+
+        let cookies: Vec<_> = headers.get_all("set-cookie").collect();
+        assert_eq!(cookies, vec!["a=1", "b=2"]);
+        assert_eq!(headers.get("SET-COOKIE").unwrap(), "a=1");
+        assert_eq!(headers.len(), 3);
+    }
+
+    #[test]
+    fn test_insert_replaces_all_values() {
+        let mut headers = ResponseHeaders::new();
+        headers.append("vary", "accept");
+        headers.append("vary", "origin");
+        headers.insert("Vary", "accept-encoding");
+
+        let values: Vec<_> = headers.get_all("vary").collect();
+        assert_eq!(values, vec!["accept-encoding"]);
+    }
+
+    #[test]
+    fn test_within_limits_untouched() {
+        let mut headers: ResponseHeaders =
+            [("content-type", "text/plain"), ("x-id", "1")].into_iter().collect();
+        let outcome = headers.enforce_limits(&limits(HeaderLimitAction::Truncate));
+        assert_eq!(outcome, HeaderLimitOutcome::WithinLimits);
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn test_truncate_keeps_essential_headers() {
+        let mut headers = ResponseHeaders::new();
+        for i in 0..4000 {
+            headers.append("x-junk", format!("value-{}", i));
+        }
+        headers.append("content-type", "text/css");
+        headers.append("cache-control", "max-age=60");
+        headers.append("etag", "\"abc\"");
+
+        let outcome = headers.enforce_limits(&limits(HeaderLimitAction::Truncate));
+
+        assert!(matches!(outcome, HeaderLimitOutcome::Truncated { dropped } if dropped == 3999));
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers.get("content-type").unwrap(), "text/css");
+        assert_eq!(headers.get("cache-control").unwrap(), "max-age=60");
+        assert_eq!(headers.get("etag").unwrap(), "\"abc\"");
+    }
+
+    #[test]
+    fn test_truncate_drops_oversized_values() {
+        let mut headers = ResponseHeaders::new();
+        headers.append("content-type", "text/plain");
+        headers.append("x-huge", "x".repeat(1000));
+        headers.append("x-small", "ok");
+
+        let outcome = headers.enforce_limits(&limits(HeaderLimitAction::Truncate));
+
+        assert_eq!(outcome, HeaderLimitOutcome::Truncated { dropped: 1 });
+        assert!(!headers.contains_key("x-huge"));
+        assert!(headers.contains_key("x-small"));
+        assert!(headers.total_bytes() <= 256);
+    }
+
+    #[test]
+    fn test_uncacheable_leaves_headers_alone() {
+        let mut headers = ResponseHeaders::new();
+        for _ in 0..10 {
+            headers.append("x-junk", "v");
+        }
+
+        let outcome = headers.enforce_limits(&limits(HeaderLimitAction::Uncacheable));
+        assert_eq!(outcome, HeaderLimitOutcome::Uncacheable);
+        assert_eq!(headers.len(), 10);
+    }
+
+    fn stored() -> ResponseHeaders {
+        [
+            ("content-type", "text/html"),
+            ("content-length", "5120"),
+            ("cache-control", "max-age=60"),
+            ("etag", "\"v1\""),
+            ("last-modified", "Mon, 06 Jan 2025 10:00:00 GMT"),
+            ("vary", "Accept-Encoding"),
+            ("access-control-allow-origin", "*"),
+            ("cache-tag", "home"),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn test_not_modified_updates_listed_headers() {
+        let mut headers = stored();
+        let not_modified: ResponseHeaders = [
+            ("cache-control", "max-age=600"),
+            ("date", "Tue, 07 Jan 2025 10:00:00 GMT"),
+            ("etag", "\"v1\""),
+            ("expires", "Tue, 07 Jan 2025 10:10:00 GMT"),
+            ("content-location", "/index.html"),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(headers.merge_not_modified(&not_modified), Ok(()));
+        for (name, value) in &not_modified {
+            assert_eq!(headers.get(name).unwrap(), value, "{}", name);
+        }
+        // Headers the 304 didn't send are kept
+        assert_eq!(headers.get("vary").unwrap(), "Accept-Encoding");
+        assert_eq!(
+            headers.get("last-modified").unwrap(),
+            "Mon, 06 Jan 2025 10:00:00 GMT"
+        );
+        assert_eq!(headers.get("cache-tag").unwrap(), "home");
+    }
+
+    #[test]
+    fn test_not_modified_never_touches_body_metadata() {
+        let mut headers = stored();
+        let not_modified: ResponseHeaders = [
+            ("content-type", "application/json"),
+            ("content-length", "0"),
+            ("content-encoding", "br"),
+            ("content-range", "bytes 0-0/1"),
+            ("access-control-allow-origin", "https://evil.example"),
+            ("cache-tag", "other"),
+            ("set-cookie", "sid=1"),
+            ("x-custom", "1"),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(headers.merge_not_modified(&not_modified), Ok(()));
+        assert_eq!(headers, stored());
+    }
+
+    #[test]
+    fn test_not_modified_empty_is_noop() {
+        let mut headers = stored();
+        assert_eq!(headers.merge_not_modified(&ResponseHeaders::new()), Ok(()));
+        assert_eq!(headers, stored());
+    }
+
+    #[test]
+    fn test_not_modified_replaces_every_value() {
+        let mut headers = stored();
+        headers.append("Cache-Control", "must-revalidate");
+        let not_modified: ResponseHeaders = [
+            ("Cache-Control", "max-age=300"),
+            ("cache-control", "stale-if-error=60"),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(headers.merge_not_modified(&not_modified), Ok(()));
+        let values: Vec<_> = headers.get_all("cache-control").collect();
+        assert_eq!(values, vec!["max-age=300", "stale-if-error=60"]);
+    }
+
+    #[test]
+    fn test_not_modified_etag_mismatch() {
+        // A 304 for the gzip variant must not refresh the identity variant
+        let mut headers = stored();
+        let not_modified: ResponseHeaders =
+            [("etag", "\"v1-gzip\""), ("cache-control", "max-age=600")]
+                .into_iter()
+                .collect();
+
+        assert_eq!(
+            headers.merge_not_modified(&not_modified),
+            Err(NotModifiedMismatch::Validator)
+        );
+        assert_eq!(headers, stored());
+    }
+
+    #[test]
+    fn test_not_modified_etag_weak_comparison() {
+        for (stored_tag, new_tag) in [
+            ("\"v1\"", "W/\"v1\""),
+            ("W/\"v1\"", "\"v1\""),
+            ("W/\"v1\"", "W/\"v1\""),
+        ] {
+            let mut headers = stored();
+            headers.insert("etag", stored_tag);
+            let not_modified: ResponseHeaders = [("etag", new_tag)].into_iter().collect();
+            assert_eq!(
+                headers.merge_not_modified(&not_modified),
+                Ok(()),
+                "{} {}",
+                stored_tag,
+                new_tag
+            );
+            assert_eq!(headers.get("etag").unwrap(), new_tag);
+        }
+    }
+
+    #[test]
+    fn test_not_modified_last_modified() {
+        // Without an ETag, Last-Modified has to match
+        let mut headers = stored();
+        let changed: ResponseHeaders = [("last-modified", "Tue, 07 Jan 2025 09:00:00 GMT")]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            headers.merge_not_modified(&changed),
+            Err(NotModifiedMismatch::Validator)
+        );
+        assert_eq!(headers, stored());
+
+        let same: ResponseHeaders = [
+            ("last-modified", "Mon, 06 Jan 2025 10:00:00 GMT"),
+            ("cache-control", "max-age=10"),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(headers.merge_not_modified(&same), Ok(()));
+        assert_eq!(headers.get("cache-control").unwrap(), "max-age=10");
+
+        // A matching ETag wins over a differing Last-Modified
+        let mut headers = stored();
+        let mut with_etag = changed.clone();
+        with_etag.append("etag", "\"v1\"");
+        assert_eq!(headers.merge_not_modified(&with_etag), Ok(()));
+        assert_eq!(
+            headers.get("last-modified").unwrap(),
+            "Tue, 07 Jan 2025 09:00:00 GMT"
+        );
+
+        // Validators missing on either side can't disagree
+        let mut bare: ResponseHeaders = [("cache-control", "max-age=60")].into_iter().collect();
+        assert_eq!(headers.merge_not_modified(&bare.clone()), Ok(()));
+        assert_eq!(bare.merge_not_modified(&with_etag), Ok(()));
+        assert_eq!(bare.get("etag").unwrap(), "\"v1\"");
+    }
+
+    #[test]
+    fn test_not_modified_vary() {
+        let mut headers = stored();
+        headers.append("vary", "Origin");
+
+        // The same fields in another order, case and layout are the same Vary
+        let same: ResponseHeaders = [("Vary", "origin, accept-encoding, Origin")]
+            .into_iter()
+            .collect();
+        assert_eq!(headers.merge_not_modified(&same), Ok(()));
+        let values: Vec<_> = headers.get_all("vary").collect();
+        assert_eq!(values, vec!["origin, accept-encoding, Origin"]);
+
+        // The stored variant was keyed on different headers
+        let before = headers.clone();
+        let changed: ResponseHeaders = [("vary", "Accept-Encoding, Accept-Language")]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            headers.merge_not_modified(&changed),
+            Err(NotModifiedMismatch::Vary)
+        );
+        assert_eq!(headers, before);
+
+        let mut unvaried: ResponseHeaders = [("etag", "\"v1\"")].into_iter().collect();
+        let varies: ResponseHeaders = [("vary", "accept")].into_iter().collect();
+        assert_eq!(
+            unvaried.merge_not_modified(&varies),
+            Err(NotModifiedMismatch::Vary)
+        );
+    }
+}
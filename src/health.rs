@@ -1,17 +1,27 @@
 //! Origin health checking module
 //!
 //! Provides periodic health checks for configured origins and tracks their status.
+//! In a cluster, nodes also gossip their observations so a freshly started node
+//! learns which origins its peers already know are down.
 
 use dashmap::DashMap;
-use reqwest::Client;
+use hyper_util::client::legacy::connect::HttpInfo;
+use reqwest::{Client, Method, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 use tokio::sync::watch;
+use tokio::task::AbortHandle;
 use tracing::{debug, error, info, warn};
 
-use crate::config::OriginConfig;
+use crate::availability::AvailabilityTracker;
+use crate::config::{
+    AvailabilityConfig, HealthCheckMode, HealthGossipConfig, MaintenanceConfig, OriginConfig,
+    PeerHealthPolicy,
+};
+use crate::maintenance::active_maintenance;
+use crate::origin::{OriginFetcher, configure_origin_client, join_origin_url};
 
 /// Health status of an origin
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -23,6 +33,8 @@ pub enum HealthStatus {
     Unhealthy,
     /// Health status is unknown (no checks yet)
     Unknown,
+    /// Origin is in a maintenance window; failed checks don't count against it
+    Maintenance,
 }
 
 impl HealthStatus {
@@ -31,6 +43,7 @@ impl HealthStatus {
             HealthStatus::Healthy => "healthy",
             HealthStatus::Unhealthy => "unhealthy",
             HealthStatus::Unknown => "unknown",
+            HealthStatus::Maintenance => "maintenance",
         }
     }
 }
@@ -45,6 +58,20 @@ pub struct OriginHealth {
     pub consecutive_failures: u32,
     pub response_time_ms: Option<u64>,
     pub error_message: Option<String>,
+    /// How the last check reached the origin: the negotiated HTTP version,
+    /// or "tcp" for a connect-only check
+    #[serde(default)]
+    pub protocol: Option<String>,
+    /// The last check went over the connection pool real traffic uses
+    #[serde(default)]
+    pub main_pool: bool,
+    /// Local address of the connection the last check went over
+    #[serde(default)]
+    pub local_addr: Option<String>,
+    /// Whether the last check went over the same connection as the one
+    /// before it, when both are known
+    #[serde(default)]
+    pub connection_reused: Option<bool>,
 }
 
 impl Default for OriginHealth {
@@ -57,38 +84,341 @@ impl Default for OriginHealth {
             consecutive_failures: 0,
             response_time_ms: None,
             error_message: None,
+            protocol: None,
+            main_pool: false,
+            local_addr: None,
+            connection_reused: None,
         }
     }
 }
 
+/// Snapshot of one node's origin health, as sent to its peers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthGossip {
+    pub node_id: String,
+    /// Unix timestamp when the snapshot was taken
+    pub sent_at: u64,
+    pub origins: HashMap<String, OriginHealth>,
+}
+
+/// The latest snapshot received from a peer
+#[derive(Debug, Clone)]
+struct PeerSnapshot {
+    sent_at: u64,
+    received_at: Instant,
+    origins: HashMap<String, OriginHealth>,
+}
+
+/// A peer's view of origin health, as reported by the health endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerHealthView {
+    /// Unix timestamp the peer took its snapshot
+    pub reported_at: u64,
+    /// Seconds since the snapshot arrived
+    pub age_secs: u64,
+    /// Older than the peer TTL, so ignored when deciding origin health
+    pub stale: bool,
+    pub origins: HashMap<String, OriginHealth>,
+}
+
+/// Origin health across the cluster
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterHealthView {
+    pub node_id: String,
+    pub policy: PeerHealthPolicy,
+    pub peers: HashMap<String, PeerHealthView>,
+    /// Status of each origin once fresh peer observations are merged in
+    pub effective: HashMap<String, HealthStatus>,
+}
+
 /// Health checker for all origins
 pub struct HealthChecker {
     client: Client,
-    origins: HashMap<String, OriginConfig>,
+    /// Fetcher whose pool checks go over when `health_check_use_main_pool` is set
+    fetcher: Option<Arc<OriginFetcher>>,
+    /// Registered origins; replaced or removed at runtime through the admin API
+    targets: RwLock<HashMap<String, HealthTarget>>,
+    /// Set once periodic checks start, so origins added later get a task too
+    shutdown: Mutex<Option<watch::Receiver<bool>>>,
     health_status: Arc<DashMap<String, OriginHealth>>,
+    /// Rolling availability from health checks and live fetches
+    availability: AvailabilityTracker,
     unhealthy_threshold: u32,
+    /// Maintenance windows for every origin, on top of each origin's own
+    maintenance: MaintenanceConfig,
+    node_id: String,
+    /// Latest snapshot from each peer, keyed by node ID
+    peers: DashMap<String, PeerSnapshot>,
+    peer_policy: PeerHealthPolicy,
+    peer_ttl: Duration,
+}
+
+/// An origin being health checked
+struct HealthTarget {
+    config: OriginConfig,
+    /// Dedicated client for unix socket and custom TLS origins
+    client: Option<Client>,
+    /// Periodic check task, once checks have started
+    task: Option<AbortHandle>,
+}
+
+fn health_client_builder() -> reqwest::ClientBuilder {
+    Client::builder()
+        .pool_max_idle_per_host(10)
+        .pool_idle_timeout(Duration::from_secs(30))
 }
 
 impl HealthChecker {
     pub fn new(origins: HashMap<String, OriginConfig>) -> Self {
-        let client = Client::builder()
-            .pool_max_idle_per_host(10)
-            .pool_idle_timeout(Duration::from_secs(30))
+        let client = health_client_builder()
             .build()
             .expect("Failed to create health check HTTP client");
 
-        let health_status = Arc::new(DashMap::new());
-
-        // Initialize health status for all origins
-        for name in origins.keys() {
-            health_status.insert(name.clone(), OriginHealth::default());
+        let checker = Self {
+            client,
+            fetcher: None,
+            targets: RwLock::new(HashMap::new()),
+            shutdown: Mutex::new(None),
+            health_status: Arc::new(DashMap::new()),
+            availability: AvailabilityTracker::new(AvailabilityConfig::default()),
+            unhealthy_threshold: 3, // 3 consecutive failures = unhealthy
+            maintenance: MaintenanceConfig::default(),
+            node_id: String::new(),
+            peers: DashMap::new(),
+            peer_policy: PeerHealthPolicy::Local,
+            peer_ttl: Duration::ZERO,
+        };
+        for (name, origin) in origins {
+            checker.register(&name, origin);
         }
+        checker
+    }
 
-        Self {
+    /// Let origins with `health_check_use_main_pool` be checked over the
+    /// fetcher's client and connection pool
+    pub fn with_origin_fetcher(mut self, fetcher: Arc<OriginFetcher>) -> Self {
+        self.fetcher = Some(fetcher);
+        self
+    }
+
+    /// Add an origin with fresh health status, replacing any of the same name
+    fn register(&self, name: &str, origin: OriginConfig) -> Option<HealthTarget> {
+        // Probe each origin over the same transport and TLS settings as real requests
+        let client = if origin.needs_dedicated_client() {
+            match configure_origin_client(health_client_builder(), name, &origin) {
+                Ok(builder) => Some(
+                    builder
+                        .build()
+                        .expect("Failed to create health check HTTP client"),
+                ),
+                Err(e) => {
+                    error!(origin = %name, error = %e, "Failed to configure health check client");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        self.health_status
+            .insert(name.to_string(), OriginHealth::default());
+        self.availability.register(name);
+        let target = HealthTarget {
+            config: origin,
             client,
-            origins,
-            health_status,
-            unhealthy_threshold: 3, // 3 consecutive failures = unhealthy
+            task: None,
+        };
+        let previous = self
+            .targets
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name.to_string(), target);
+        if let Some(task) = previous.as_ref().and_then(|target| target.task.as_ref()) {
+            task.abort();
+        }
+        previous
+    }
+
+    /// Start checking an origin, replacing any of the same name
+    ///
+    /// Its status starts over as unknown. If periodic checks are running,
+    /// the origin gets its own check task straight away.
+    pub fn upsert_origin(self: &Arc<Self>, name: &str, origin: OriginConfig) {
+        self.register(name, origin);
+        let shutdown = self
+            .shutdown
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        if let Some(shutdown) = shutdown {
+            self.start_checks(name, shutdown);
+        }
+    }
+
+    /// Stop checking an origin and forget its status
+    pub fn remove_origin(&self, name: &str) -> bool {
+        let removed = self
+            .targets
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(name);
+        self.health_status.remove(name);
+        self.availability.remove(name);
+        match removed {
+            Some(target) => {
+                if let Some(task) = target.task {
+                    task.abort();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Track availability with these bucket and retention settings
+    pub fn with_availability(mut self, config: AvailabilityConfig) -> Self {
+        self.availability = AvailabilityTracker::new(config);
+        for name in self.origin_names() {
+            self.availability.register(&name);
+        }
+        self
+    }
+
+    /// Maintenance windows that apply to every origin
+    pub fn with_maintenance(mut self, maintenance: MaintenanceConfig) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
+
+    /// Rolling availability per origin
+    pub fn availability(&self) -> &AvailabilityTracker {
+        &self.availability
+    }
+
+    /// Merge origin health gossiped by cluster peers into this node's view
+    pub fn with_peer_gossip(
+        mut self,
+        node_id: impl Into<String>,
+        policy: PeerHealthPolicy,
+        peer_ttl: Duration,
+    ) -> Self {
+        self.node_id = node_id.into();
+        self.peer_policy = policy;
+        self.peer_ttl = peer_ttl;
+        self
+    }
+
+    /// This node's ID as reported to peers
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Snapshot of local observations to send to peers
+    pub fn gossip_snapshot(&self) -> HealthGossip {
+        HealthGossip {
+            node_id: self.node_id.clone(),
+            sent_at: unix_now(),
+            origins: self.get_all_statuses(),
+        }
+    }
+
+    /// Record a snapshot received from a peer, ignoring out-of-order deliveries
+    pub fn merge_peer_snapshot(&self, gossip: HealthGossip) {
+        if gossip.node_id == self.node_id {
+            return;
+        }
+        if let Some(existing) = self.peers.get(&gossip.node_id)
+            && existing.sent_at > gossip.sent_at
+        {
+            return;
+        }
+
+        debug!(
+            peer = %gossip.node_id,
+            origins = gossip.origins.len(),
+            "Received origin health from peer"
+        );
+        self.peers.insert(
+            gossip.node_id,
+            PeerSnapshot {
+                sent_at: gossip.sent_at,
+                received_at: Instant::now(),
+                origins: gossip.origins,
+            },
+        );
+    }
+
+    /// Status of an origin once fresh peer observations are merged per the policy
+    pub fn effective_status(&self, origin_name: &str) -> HealthStatus {
+        let local = self
+            .health_status
+            .get(origin_name)
+            .map(|h| h.status)
+            .unwrap_or(HealthStatus::Unknown);
+        if self.peer_policy == PeerHealthPolicy::Local {
+            return local;
+        }
+
+        // Peers that haven't checked the origin yet have no opinion
+        let votes: Vec<HealthStatus> = self
+            .peers
+            .iter()
+            .filter(|peer| peer.received_at.elapsed() <= self.peer_ttl)
+            .filter_map(|peer| peer.origins.get(origin_name).map(|h| h.status))
+            .chain(std::iter::once(local))
+            .filter(|status| *status != HealthStatus::Unknown)
+            .collect();
+        if votes.is_empty() {
+            return HealthStatus::Unknown;
+        }
+
+        let unhealthy = votes
+            .iter()
+            .filter(|s| **s == HealthStatus::Unhealthy)
+            .count();
+        let is_unhealthy = if self.peer_policy == PeerHealthPolicy::Majority {
+            unhealthy * 2 > votes.len()
+        } else {
+            unhealthy > 0
+        };
+
+        if is_unhealthy {
+            HealthStatus::Unhealthy
+        } else {
+            HealthStatus::Healthy
+        }
+    }
+
+    /// Local, peer, and merged origin health for the cluster view
+    pub fn cluster_view(&self) -> ClusterHealthView {
+        let peers = self
+            .peers
+            .iter()
+            .map(|peer| {
+                let age = peer.received_at.elapsed();
+                let view = PeerHealthView {
+                    reported_at: peer.sent_at,
+                    age_secs: age.as_secs(),
+                    stale: age > self.peer_ttl,
+                    origins: peer.origins.clone(),
+                };
+                (peer.key().clone(), view)
+            })
+            .collect();
+        let effective = self
+            .origin_names()
+            .into_iter()
+            .map(|name| {
+                let status = self.effective_status(&name);
+                (name, status)
+            })
+            .collect();
+
+        ClusterHealthView {
+            node_id: self.node_id.clone(),
+            policy: self.peer_policy,
+            peers,
+            effective,
         }
     }
 
@@ -105,18 +435,24 @@ impl HealthChecker {
             .collect()
     }
 
-    /// Check if an origin is healthy
+    /// Check if an origin is healthy, taking fresh peer observations into account
     pub fn is_healthy(&self, origin_name: &str) -> bool {
-        self.health_status
-            .get(origin_name)
-            .map(|h| h.status == HealthStatus::Healthy || h.status == HealthStatus::Unknown)
-            .unwrap_or(true) // Default to healthy if unknown origin
+        // Unknown origins default to healthy
+        self.effective_status(origin_name) != HealthStatus::Unhealthy
     }
 
     /// Perform a health check for a specific origin
     pub async fn check_origin(&self, origin_name: &str) -> HealthStatus {
-        let origin = match self.origins.get(origin_name) {
-            Some(o) => o,
+        let (origin, client) = match self
+            .targets
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(origin_name)
+        {
+            Some(target) => (
+                target.config.clone(),
+                target.client.clone().unwrap_or_else(|| self.client.clone()),
+            ),
             None => {
                 warn!(origin = %origin_name, "Unknown origin for health check");
                 return HealthStatus::Unknown;
@@ -124,31 +460,24 @@ impl HealthChecker {
         };
 
         // If no health check path configured, skip
-        let health_path = match &origin.health_check_path {
-            Some(p) => p,
-            None => {
-                debug!(origin = %origin_name, "No health check path configured");
-                return HealthStatus::Unknown;
-            }
-        };
+        if !origin.is_health_checked() {
+            debug!(origin = %origin_name, "No health check path configured");
+            return HealthStatus::Unknown;
+        }
 
-        let url = format!("{}{}", origin.url.trim_end_matches('/'), health_path);
         let start = Instant::now();
-
-        debug!(origin = %origin_name, url = %url, "Performing health check");
-
-        let result = self
-            .client
-            .get(&url)
-            .timeout(origin.health_check_timeout())
-            .send()
-            .await;
+        let result = match (&origin.health_check_path, origin.health_check_mode) {
+            (Some(path), HealthCheckMode::Http) => {
+                self.http_probe(origin_name, &origin, client, path).await
+            }
+            _ => tcp_probe(origin_name, &origin).await,
+        };
 
         let response_time = start.elapsed();
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
+        let now = unix_now();
+        let in_maintenance =
+            active_maintenance(&self.maintenance, &origin.maintenance, chrono::Utc::now())
+                .is_some();
 
         let mut health = self
             .health_status
@@ -158,46 +487,56 @@ impl HealthChecker {
 
         health.last_check = Some(now);
         health.response_time_ms = Some(response_time.as_millis() as u64);
+        if let Ok(probe) = &result {
+            health.connection_reused = health
+                .local_addr
+                .as_ref()
+                .zip(probe.local_addr.as_ref())
+                .map(|(previous, current)| previous == current)
+                .filter(|_| probe.protocol != TCP_PROTOCOL);
+            health.protocol = Some(probe.protocol.clone());
+            health.main_pool = probe.main_pool;
+            health.local_addr = probe.local_addr.clone();
+        }
 
         match result {
-            Ok(response) if response.status().is_success() => {
-                health.status = HealthStatus::Healthy;
+            Ok(probe) if probe.status.is_none_or(|status| status.is_success()) => {
+                health.status = if in_maintenance {
+                    HealthStatus::Maintenance
+                } else {
+                    HealthStatus::Healthy
+                };
                 health.last_success = Some(now);
                 health.consecutive_failures = 0;
                 health.error_message = None;
 
                 info!(
                     origin = %origin_name,
-                    status = response.status().as_u16(),
+                    status = probe.status.map(|status| status.as_u16()),
+                    protocol = %probe.protocol,
+                    main_pool = probe.main_pool,
                     response_time_ms = response_time.as_millis(),
                     "Health check passed"
                 );
             }
-            Ok(response) => {
-                health.consecutive_failures += 1;
+            Ok(probe) => {
+                let status = probe.status.unwrap_or_default();
+                self.record_check_failure(&mut health, in_maintenance);
                 health.last_failure = Some(now);
-                health.error_message = Some(format!("HTTP {}", response.status()));
-
-                if health.consecutive_failures >= self.unhealthy_threshold {
-                    health.status = HealthStatus::Unhealthy;
-                }
+                health.error_message = Some(format!("HTTP {}", status));
 
                 warn!(
                     origin = %origin_name,
-                    status = response.status().as_u16(),
+                    status = status.as_u16(),
                     consecutive_failures = health.consecutive_failures,
                     "Health check failed: non-success status"
                 );
             }
             Err(e) => {
-                health.consecutive_failures += 1;
+                self.record_check_failure(&mut health, in_maintenance);
                 health.last_failure = Some(now);
                 health.error_message = Some(e.to_string());
 
-                if health.consecutive_failures >= self.unhealthy_threshold {
-                    health.status = HealthStatus::Unhealthy;
-                }
-
                 error!(
                     origin = %origin_name,
                     error = %e,
@@ -207,38 +546,98 @@ impl HealthChecker {
             }
         }
 
+        // Checks inside a maintenance window don't count toward availability
+        if !in_maintenance {
+            self.availability
+                .record(origin_name, health.consecutive_failures == 0);
+        }
+
+        // The origin may have been removed while the check was in flight
         let status = health.status;
-        self.health_status.insert(origin_name.to_string(), health);
+        if let Some(mut entry) = self.health_status.get_mut(origin_name) {
+            *entry = health;
+        }
         status
     }
 
+    /// GET the health check path, over the main pool if the origin asks for it
+    async fn http_probe(
+        &self,
+        origin_name: &str,
+        origin: &OriginConfig,
+        client: Client,
+        path: &str,
+    ) -> Result<Probe, String> {
+        let timeout = origin.health_check_timeout();
+        let (result, main_pool) = match &self.fetcher {
+            Some(fetcher) if origin.health_check_use_main_pool => {
+                debug!(origin = %origin_name, path = %path, "Performing health check over the main pool");
+                let result = fetcher
+                    .send_request(origin_name, Method::GET, path, timeout)
+                    .await
+                    .map_err(|e| e.to_string());
+                (result, true)
+            }
+            _ => {
+                let url = join_origin_url(&origin.base_url(), path, None);
+                debug!(origin = %origin_name, url = %url, "Performing health check");
+                let result = client
+                    .get(&url)
+                    .timeout(timeout)
+                    .send()
+                    .await
+                    .map_err(|e| e.to_string());
+                (result, false)
+            }
+        };
+        result.map(|response| Probe::http(&response, main_pool))
+    }
+
+    /// Count a failed check, unless the origin is in a maintenance window
+    fn record_check_failure(&self, health: &mut OriginHealth, in_maintenance: bool) {
+        if in_maintenance {
+            health.status = HealthStatus::Maintenance;
+            return;
+        }
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= self.unhealthy_threshold {
+            health.status = HealthStatus::Unhealthy;
+        }
+    }
+
     /// Check all origins
     pub async fn check_all(&self) {
-        for origin_name in self.origins.keys() {
-            self.check_origin(origin_name).await;
+        for origin_name in self.origin_names() {
+            self.check_origin(&origin_name).await;
         }
     }
 
-    /// Get a clone of the health status map for sharing
-    pub fn health_status_handle(&self) -> Arc<DashMap<String, OriginHealth>> {
-        Arc::clone(&self.health_status)
+    /// Names of all origins being checked
+    pub fn origin_names(&self) -> Vec<String> {
+        self.targets
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .keys()
+            .cloned()
+            .collect()
     }
-}
 
-/// Spawn background health check tasks for all origins
-pub fn spawn_health_checks(checker: Arc<HealthChecker>, shutdown: watch::Receiver<bool>) {
-    for (origin_name, origin_config) in &checker.origins {
-        if origin_config.health_check_path.is_none() {
+    /// Spawn the periodic check task for one origin
+    fn start_checks(self: &Arc<Self>, origin_name: &str, mut shutdown: watch::Receiver<bool>) {
+        let mut targets = self.targets.write().unwrap_or_else(|e| e.into_inner());
+        let Some(target) = targets.get_mut(origin_name) else {
+            return;
+        };
+        if !target.config.is_health_checked() {
             debug!(origin = %origin_name, "Skipping health checks (no path configured)");
-            continue;
+            return;
         }
 
-        let checker = Arc::clone(&checker);
-        let origin_name = origin_name.clone();
-        let interval = origin_config.health_check_interval();
-        let mut shutdown = shutdown.clone();
+        let checker = Arc::clone(self);
+        let origin_name = origin_name.to_string();
+        let interval = target.config.health_check_interval();
 
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             info!(
                 origin = %origin_name,
                 interval_secs = interval.as_secs(),
@@ -265,6 +664,188 @@ pub fn spawn_health_checks(checker: Arc<HealthChecker>, shutdown: watch::Receive
                 }
             }
         });
+        target.task = Some(task.abort_handle());
+    }
+
+    /// Get a clone of the health status map for sharing
+    pub fn health_status_handle(&self) -> Arc<DashMap<String, OriginHealth>> {
+        Arc::clone(&self.health_status)
+    }
+}
+
+/// `OriginHealth::protocol` for a connect-only check
+const TCP_PROTOCOL: &str = "tcp";
+
+/// What a check that reached the origin saw
+struct Probe {
+    /// Response status; `None` for a connect-only check
+    status: Option<StatusCode>,
+    protocol: String,
+    main_pool: bool,
+    local_addr: Option<String>,
+}
+
+impl Probe {
+    fn http(response: &Response, main_pool: bool) -> Self {
+        Self {
+            status: Some(response.status()),
+            protocol: format!("{:?}", response.version()),
+            main_pool,
+            local_addr: response
+                .extensions()
+                .get::<HttpInfo>()
+                .map(|info| info.local_addr().to_string()),
+        }
+    }
+}
+
+/// Open a connection to the origin and close it again
+async fn tcp_probe(origin_name: &str, origin: &OriginConfig) -> Result<Probe, String> {
+    let timeout = origin.health_check_timeout();
+    let local_addr = match origin.unix_socket_path() {
+        Some(socket) => {
+            debug!(origin = %origin_name, socket = %socket, "Performing TCP health check");
+            connect_unix(socket, timeout).await?;
+            None
+        }
+        None => {
+            let url = reqwest::Url::parse(&origin.url).map_err(|e| e.to_string())?;
+            let host = url
+                .host_str()
+                .ok_or_else(|| format!("no host in {}", origin.url))?;
+            let port = url
+                .port_or_known_default()
+                .ok_or_else(|| format!("no port for {}", origin.url))?;
+            debug!(origin = %origin_name, host = %host, port, "Performing TCP health check");
+            let stream =
+                tokio::time::timeout(timeout, tokio::net::TcpStream::connect((host, port)))
+                    .await
+                    .map_err(|_| "connect timed out".to_string())?
+                    .map_err(|e| e.to_string())?;
+            stream.local_addr().ok().map(|addr| addr.to_string())
+        }
+    };
+    Ok(Probe {
+        status: None,
+        protocol: TCP_PROTOCOL.to_string(),
+        main_pool: false,
+        local_addr,
+    })
+}
+
+#[cfg(unix)]
+async fn connect_unix(socket: &str, timeout: Duration) -> Result<(), String> {
+    tokio::time::timeout(timeout, tokio::net::UnixStream::connect(socket))
+        .await
+        .map_err(|_| "connect timed out".to_string())?
+        .map(drop)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_socket: &str, _timeout: Duration) -> Result<(), String> {
+    Err("unix sockets are not supported on this platform".to_string())
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Spawn a background task per peer that sends it this node's health snapshot
+///
+/// Snapshots go to the peer's `/_cdn/cluster/health` endpoint, authenticated
+/// with the shared admin token. A peer that can't be reached only costs the
+/// cluster its view of us; local health checking carries on regardless.
+pub fn spawn_health_gossip(
+    checker: Arc<HealthChecker>,
+    peers: &[String],
+    config: &HealthGossipConfig,
+    auth_token: Option<String>,
+    shutdown: watch::Receiver<bool>,
+) {
+    let client = Client::builder()
+        .timeout(config.timeout())
+        .build()
+        .expect("Failed to create health gossip HTTP client");
+
+    for peer in peers {
+        let checker = Arc::clone(&checker);
+        let client = client.clone();
+        let auth_token = auth_token.clone();
+        let url = format!("{}/_cdn/cluster/health", peer.trim_end_matches('/'));
+        let peer = peer.clone();
+        let interval = config.interval();
+        let mut shutdown = shutdown.clone();
+
+        tokio::spawn(async move {
+            info!(peer = %peer, interval_secs = interval.as_secs(), "Starting health gossip task");
+
+            let mut interval_timer = tokio::time::interval(interval);
+            let mut consecutive_failures = 0u32;
+
+            loop {
+                tokio::select! {
+                    _ = interval_timer.tick() => {
+                        let body = serde_json::to_vec(&checker.gossip_snapshot())
+                            .expect("Health snapshot serializes to JSON");
+                        let mut request = client
+                            .post(&url)
+                            .header(reqwest::header::CONTENT_TYPE, "application/json")
+                            .body(body);
+                        if let Some(token) = &auth_token {
+                            request = request.bearer_auth(token);
+                        }
+                        let error = match request.send().await {
+                            Ok(response) if response.status().is_success() => None,
+                            Ok(response) => Some(format!("HTTP {}", response.status())),
+                            Err(e) => Some(e.to_string()),
+                        };
+
+                        match error {
+                            None => {
+                                if consecutive_failures > 0 {
+                                    info!(peer = %peer, "Health gossip to peer recovered");
+                                }
+                                consecutive_failures = 0;
+                            }
+                            Some(error) => {
+                                consecutive_failures += 1;
+                                // Warn once per outage rather than every interval
+                                if consecutive_failures == 1 {
+                                    warn!(peer = %peer, error = %error, "Health gossip to peer failed");
+                                } else {
+                                    debug!(
+                                        peer = %peer,
+                                        error = %error,
+                                        consecutive_failures,
+                                        "Health gossip to peer failed"
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    _ = shutdown.changed() => {
+                        if *shutdown.borrow() {
+                            info!(peer = %peer, "Shutting down health gossip task");
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Spawn background health check tasks for all origins
+///
+/// Origins added later through the admin API get their own task as well.
+pub fn spawn_health_checks(checker: Arc<HealthChecker>, shutdown: watch::Receiver<bool>) {
+    *checker.shutdown.lock().unwrap_or_else(|e| e.into_inner()) = Some(shutdown.clone());
+    for origin_name in checker.origin_names() {
+        checker.start_checks(&origin_name, shutdown.clone());
     }
 }
 
@@ -286,13 +867,35 @@ mod tests {
             "test".to_string(),
             OriginConfig {
                 url: "http://localhost:8080".to_string(),
+                path_prefix: None,
+                strip_path_prefix: None,
                 host_header: None,
                 timeout_secs: 30,
+                total_timeout_secs: None,
+                connect_timeout_secs: None,
+                time_to_first_byte_secs: None,
                 max_retries: 3,
                 headers: HashMap::new(),
                 health_check_path: Some("/health".to_string()),
                 health_check_interval_secs: 30,
                 health_check_timeout_secs: 5,
+                health_check_mode: Default::default(),
+                health_check_use_main_pool: false,
+                selftest_path: None,
+                preconnect_path: None,
+                client_cache_control: None,
+                client_cache_control_override: false,
+                error_pages_dir: None,
+                tls: Default::default(),
+                http: Default::default(),
+                pool: Default::default(),
+                request_compression: true,
+                compress_responses: true,
+                cookie_rewrite: Default::default(),
+                personalized_bypass: Default::default(),
+                maintenance: Default::default(),
+                validation: Default::default(),
+                proxy_url: None,
             },
         );
 
@@ -302,6 +905,221 @@ mod tests {
         assert_eq!(status.unwrap().status, HealthStatus::Unknown);
     }
 
+    #[tokio::test]
+    async fn test_failed_checks_in_maintenance_dont_count() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+        let origin: OriginConfig = toml::from_str(&format!(
+            "url = \"http://{}\"\nhealth_check_path = \"/health\"",
+            closed
+        ))
+        .unwrap();
+        let always: MaintenanceConfig = toml::from_str(
+            r#"
+            [[windows]]
+            start = "00:00"
+            end = "00:00"
+            "#,
+        )
+        .unwrap();
+
+        let origins = HashMap::from([("api".to_string(), origin)]);
+        let checker = HealthChecker::new(origins.clone()).with_maintenance(always);
+        for _ in 0..5 {
+            assert_eq!(checker.check_origin("api").await, HealthStatus::Maintenance);
+        }
+        let health = checker.get_status("api").unwrap();
+        assert_eq!(health.consecutive_failures, 0);
+        assert!(health.error_message.is_some());
+        assert!(checker.is_healthy("api"));
+
+        // The same failures outside a window take the origin down
+        let checker = HealthChecker::new(origins);
+        for _ in 0..3 {
+            checker.check_origin("api").await;
+        }
+        assert_eq!(checker.effective_status("api"), HealthStatus::Unhealthy);
+    }
+
+    /// An origin answering `/health`, and `/peer` with the client's address
+    async fn spawn_origin() -> String {
+        use axum::extract::ConnectInfo;
+        use axum::routing::get;
+        use std::net::SocketAddr;
+
+        let app = axum::Router::new()
+            .route("/health", get(|| async { "ok" }))
+            .route(
+                "/peer",
+                get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.to_string() }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_checks_over_the_main_pool() {
+        let url = spawn_origin().await;
+        let origin = |main_pool: bool| -> OriginConfig {
+            toml::from_str(&format!(
+                "url = \"{}\"\nhealth_check_path = \"/health\"\nhealth_check_use_main_pool = {}",
+                url, main_pool
+            ))
+            .unwrap()
+        };
+        let origins = HashMap::from([
+            ("pooled".to_string(), origin(true)),
+            ("own".to_string(), origin(false)),
+        ]);
+        let fetcher = Arc::new(OriginFetcher::new(origins.clone()).unwrap());
+        let checker = HealthChecker::new(origins).with_origin_fetcher(fetcher.clone());
+
+        // The connection real traffic opened is the one the check goes over
+        let served = fetcher
+            .fetch("pooled", "/peer", None, &HashMap::new())
+            .await
+            .unwrap();
+        let traffic_addr = String::from_utf8(served.body.to_vec()).unwrap();
+        assert_eq!(checker.check_origin("pooled").await, HealthStatus::Healthy);
+        let health = checker.get_status("pooled").unwrap();
+        assert!(health.main_pool);
+        assert_eq!(health.protocol.as_deref(), Some("HTTP/2.0"));
+        assert_eq!(health.local_addr.as_deref(), Some(traffic_addr.as_str()));
+        assert_eq!(health.connection_reused, None);
+
+        checker.check_origin("pooled").await;
+        let health = checker.get_status("pooled").unwrap();
+        assert_eq!(health.connection_reused, Some(true));
+
+        // Without the option the checker's own client opens its own
+        // connection, and speaks HTTP/1.1 where the main pool speaks h2
+        assert_eq!(checker.check_origin("own").await, HealthStatus::Healthy);
+        let health = checker.get_status("own").unwrap();
+        assert!(!health.main_pool);
+        assert_eq!(health.protocol.as_deref(), Some("HTTP/1.1"));
+        assert!(health.local_addr.is_some());
+        assert_ne!(health.local_addr.as_deref(), Some(traffic_addr.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_checks_need_no_path() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let origin = |addr: std::net::SocketAddr| -> OriginConfig {
+            toml::from_str(&format!(
+                "url = \"http://{}\"\nhealth_check_mode = \"tcp\"",
+                addr
+            ))
+            .unwrap()
+        };
+        let checker = HealthChecker::new(HashMap::from([
+            ("up".to_string(), origin(open)),
+            ("down".to_string(), origin(closed)),
+        ]));
+
+        assert_eq!(checker.check_origin("up").await, HealthStatus::Healthy);
+        let health = checker.get_status("up").unwrap();
+        assert_eq!(health.protocol.as_deref(), Some("tcp"));
+        assert!(health.local_addr.is_some());
+
+        for _ in 0..3 {
+            checker.check_origin("down").await;
+        }
+        let health = checker.get_status("down").unwrap();
+        assert_eq!(health.status, HealthStatus::Unhealthy);
+        assert!(health.error_message.is_some());
+        drop(listener);
+    }
+
+    fn gossip(node_id: &str, sent_at: u64, status: HealthStatus) -> HealthGossip {
+        let health = OriginHealth {
+            status,
+            ..Default::default()
+        };
+        HealthGossip {
+            node_id: node_id.to_string(),
+            sent_at,
+            origins: HashMap::from([("api".to_string(), health)]),
+        }
+    }
+
+    fn cluster_checker(policy: PeerHealthPolicy, peer_ttl: Duration) -> HealthChecker {
+        let checker =
+            HealthChecker::new(HashMap::new()).with_peer_gossip("edge-1", policy, peer_ttl);
+        checker
+            .health_status
+            .insert("api".to_string(), OriginHealth::default());
+        checker
+    }
+
+    fn set_local(checker: &HealthChecker, status: HealthStatus) {
+        checker.health_status.get_mut("api").unwrap().status = status;
+    }
+
+    #[test]
+    fn test_peer_health_policies() {
+        let ttl = Duration::from_secs(30);
+
+        // A fresh node with no checks yet trusts a peer that saw the origin fail
+        let any = cluster_checker(PeerHealthPolicy::Any, ttl);
+        assert_eq!(any.effective_status("api"), HealthStatus::Unknown);
+        any.merge_peer_snapshot(gossip("edge-2", 100, HealthStatus::Unhealthy));
+        assert!(!any.is_healthy("api"));
+        set_local(&any, HealthStatus::Healthy);
+        assert!(!any.is_healthy("api"));
+
+        let majority = cluster_checker(PeerHealthPolicy::Majority, ttl);
+        set_local(&majority, HealthStatus::Healthy);
+        majority.merge_peer_snapshot(gossip("edge-2", 100, HealthStatus::Unhealthy));
+        assert!(majority.is_healthy("api"));
+        majority.merge_peer_snapshot(gossip("edge-3", 100, HealthStatus::Unhealthy));
+        assert!(!majority.is_healthy("api"));
+
+        let local = cluster_checker(PeerHealthPolicy::Local, ttl);
+        local.merge_peer_snapshot(gossip("edge-2", 100, HealthStatus::Unhealthy));
+        assert!(local.is_healthy("api"));
+        assert_eq!(local.cluster_view().peers.len(), 1);
+    }
+
+    #[test]
+    fn test_peer_snapshots_go_stale() {
+        let checker = cluster_checker(PeerHealthPolicy::Any, Duration::ZERO);
+        checker.merge_peer_snapshot(gossip("edge-2", 100, HealthStatus::Unhealthy));
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert!(checker.is_healthy("api"));
+        let view = checker.cluster_view();
+        assert!(view.peers["edge-2"].stale);
+        assert_eq!(checker.effective_status("api"), HealthStatus::Unknown);
+    }
+
+    #[test]
+    fn test_merge_peer_snapshot_ordering() {
+        let checker = cluster_checker(PeerHealthPolicy::Any, Duration::from_secs(30));
+        checker.merge_peer_snapshot(gossip("edge-2", 200, HealthStatus::Healthy));
+        // A delayed older snapshot doesn't replace a newer one
+        checker.merge_peer_snapshot(gossip("edge-2", 100, HealthStatus::Unhealthy));
+        assert!(checker.is_healthy("api"));
+
+        // Our own snapshot echoed back is ignored
+        checker.merge_peer_snapshot(gossip("edge-1", 300, HealthStatus::Unhealthy));
+        assert!(checker.is_healthy("api"));
+        assert_eq!(checker.gossip_snapshot().node_id, "edge-1");
+    }
+
     #[test]
     fn test_is_healthy_unknown_origin() {
         let checker = HealthChecker::new(HashMap::new());
@@ -0,0 +1,334 @@
+//! Background admin jobs
+//!
+//! Long-running admin operations (cache import, bulk warming) run in the
+//! background and report progress through a job id that can be polled via
+//! `GET /_cdn/jobs/{id}`. A job can be cancelled, which stops it from
+//! starting any more items; finished jobs are kept for a retention period.
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Maximum number of jobs retained; the oldest finished jobs are dropped first
+const MAX_RETAINED_JOBS: usize = 100;
+
+/// Maximum number of error messages kept per job
+const MAX_JOB_ERRORS: usize = 100;
+
+/// Maximum number of per-item results kept per job
+const MAX_JOB_RESULTS: usize = 2000;
+
+/// How long finished jobs are kept by default
+const DEFAULT_RETENTION: Duration = Duration::from_secs(3600);
+
+/// Progress of a single background job
+pub struct Job {
+    id: String,
+    kind: &'static str,
+    created_at: DateTime<Utc>,
+    finished_at: Mutex<Option<DateTime<Utc>>>,
+    total: usize,
+    completed: AtomicUsize,
+    succeeded: AtomicUsize,
+    failed: AtomicUsize,
+    finished: AtomicBool,
+    cancelled: AtomicBool,
+    errors: Mutex<Vec<String>>,
+    /// Per-item results, for jobs that report them
+    results: Mutex<Vec<serde_json::Value>>,
+}
+
+/// Serializable snapshot of a job's progress
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub kind: String,
+    pub state: String,
+    pub total: usize,
+    pub completed: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Items not yet processed; left unprocessed by a cancelled job
+    pub remaining: usize,
+    pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    pub errors: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub results: Vec<serde_json::Value>,
+}
+
+impl Job {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    /// Record one successfully processed item
+    pub fn record_success(&self) {
+        self.succeeded.fetch_add(1, Ordering::Relaxed);
+        self.completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record one failed item with a reason
+    pub fn record_failure(&self, error: String) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        self.completed.fetch_add(1, Ordering::Relaxed);
+
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() < MAX_JOB_ERRORS {
+            errors.push(error);
+        }
+    }
+
+    /// Keep the result of one item, to be reported with the job's status
+    pub fn record_result<T: Serialize>(&self, result: &T) {
+        let mut results = self.results.lock().unwrap();
+        if results.len() < MAX_JOB_RESULTS
+            && let Ok(value) = serde_json::to_value(result)
+        {
+            results.push(value);
+        }
+    }
+
+    /// Ask the job to stop starting items, returning false if it had finished
+    ///
+    /// Items already in progress run to completion.
+    pub fn cancel(&self) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+        self.cancelled.store(true, Ordering::Release);
+        true
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Mark the job as finished
+    pub fn finish(&self) {
+        *self.finished_at.lock().unwrap() = Some(Utc::now());
+        self.finished.store(true, Ordering::Release);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    pub fn status(&self) -> JobStatus {
+        let state = match (self.is_finished(), self.is_cancelled()) {
+            (false, false) => "running",
+            (false, true) => "cancelling",
+            (true, false) => "completed",
+            (true, true) => "cancelled",
+        };
+        let completed = self.completed.load(Ordering::Relaxed);
+        JobStatus {
+            id: self.id.clone(),
+            kind: self.kind.to_string(),
+            state: state.to_string(),
+            total: self.total,
+            completed,
+            succeeded: self.succeeded.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            remaining: self.total.saturating_sub(completed),
+            created_at: self.created_at.to_rfc3339(),
+            finished_at: self.finished_at.lock().unwrap().map(|t| t.to_rfc3339()),
+            errors: self.errors.lock().unwrap().clone(),
+            results: self.results.lock().unwrap().clone(),
+        }
+    }
+
+    /// Whether the job finished longer than `retention` ago
+    fn expired(&self, retention: Duration) -> bool {
+        let Some(finished_at) = *self.finished_at.lock().unwrap() else {
+            return false;
+        };
+        Utc::now()
+            .signed_duration_since(finished_at)
+            .to_std()
+            .is_ok_and(|age| age >= retention)
+    }
+}
+
+/// Registry of background jobs
+pub struct JobRegistry {
+    jobs: DashMap<String, Arc<Job>>,
+    /// How long a finished job is kept
+    retention: Duration,
+}
+
+impl Default for JobRegistry {
+    fn default() -> Self {
+        Self {
+            jobs: DashMap::new(),
+            retention: DEFAULT_RETENTION,
+        }
+    }
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep finished jobs for `retention` (default: one hour)
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Register a new job of `kind` that will process `total` items
+    pub fn create(&self, kind: &'static str, total: usize) -> Arc<Job> {
+        self.prune();
+
+        let job = Arc::new(Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            kind,
+            created_at: Utc::now(),
+            finished_at: Mutex::new(None),
+            total,
+            completed: AtomicUsize::new(0),
+            succeeded: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            finished: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            errors: Mutex::new(Vec::new()),
+            results: Mutex::new(Vec::new()),
+        });
+
+        self.jobs.insert(job.id.clone(), Arc::clone(&job));
+        job
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobStatus> {
+        self.job(id).map(|job| job.status())
+    }
+
+    /// The job itself, for cancelling it; `None` once it has expired
+    pub fn job(&self, id: &str) -> Option<Arc<Job>> {
+        let job = self.jobs.get(id).map(|job| Arc::clone(&job))?;
+        if job.expired(self.retention) {
+            self.jobs.remove(id);
+            return None;
+        }
+        Some(job)
+    }
+
+    /// Drop jobs past their retention, then the oldest finished jobs once the
+    /// registry is full
+    fn prune(&self) {
+        self.jobs.retain(|_, job| !job.expired(self.retention));
+        if self.jobs.len() < MAX_RETAINED_JOBS {
+            return;
+        }
+
+        let mut finished: Vec<(DateTime<Utc>, String)> = self
+            .jobs
+            .iter()
+            .filter(|j| j.is_finished())
+            .map(|j| (j.created_at, j.id.clone()))
+            .collect();
+        finished.sort();
+
+        let excess = self.jobs.len() + 1 - MAX_RETAINED_JOBS;
+        for (_, id) in finished.into_iter().take(excess) {
+            self.jobs.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_progress() {
+        let registry = JobRegistry::new();
+        let job = registry.create("import", 3);
+
+        job.record_success();
+        job.record_failure("boom".to_string());
+
+        let status = registry.get(job.id()).unwrap();
+        assert_eq!(status.state, "running");
+        assert_eq!(status.completed, 2);
+        assert_eq!(status.succeeded, 1);
+        assert_eq!(status.failed, 1);
+        assert_eq!(status.errors, vec!["boom".to_string()]);
+
+        job.record_success();
+        job.finish();
+
+        let status = registry.get(job.id()).unwrap();
+        assert_eq!(status.state, "completed");
+        assert_eq!(status.completed, 3);
+        assert!(status.finished_at.is_some());
+    }
+
+    #[test]
+    fn test_cancelled_job() {
+        let registry = JobRegistry::new();
+        let job = registry.create("warm", 3);
+        job.record_success();
+        job.record_result(&serde_json::json!({"url": "web/a", "success": true}));
+
+        assert!(job.cancel());
+        assert!(job.is_cancelled());
+        assert_eq!(registry.get(job.id()).unwrap().state, "cancelling");
+
+        job.finish();
+        let status = registry.get(job.id()).unwrap();
+        assert_eq!(status.state, "cancelled");
+        assert_eq!(status.completed, 1);
+        assert_eq!(status.remaining, 2);
+        assert_eq!(status.results.len(), 1);
+        assert!(!job.cancel());
+
+        // A job that finished can't be cancelled after the fact
+        let done = registry.create("warm", 0);
+        done.finish();
+        assert!(!done.cancel());
+        assert_eq!(registry.get(done.id()).unwrap().state, "completed");
+    }
+
+    #[test]
+    fn test_finished_jobs_expire_after_retention() {
+        let registry = JobRegistry::new().with_retention(Duration::ZERO);
+        let running = registry.create("warm", 1);
+        let finished = registry.create("warm", 0);
+        finished.finish();
+
+        assert!(registry.get(finished.id()).is_none());
+        assert!(registry.get(running.id()).is_some());
+
+        running.finish();
+        registry.create("warm", 0);
+        assert!(!registry.jobs.contains_key(running.id()));
+    }
+
+    #[test]
+    fn test_unknown_job() {
+        let registry = JobRegistry::new();
+        assert!(registry.get("missing").is_none());
+    }
+
+    #[test]
+    fn test_prune_keeps_running_jobs() {
+        let registry = JobRegistry::new();
+        let running = registry.create("import", 1);
+        for _ in 0..MAX_RETAINED_JOBS + 10 {
+            registry.create("import", 0).finish();
+        }
+
+        assert!(registry.jobs.len() <= MAX_RETAINED_JOBS);
+        assert!(registry.get(running.id()).is_some());
+    }
+}
@@ -1,18 +1,52 @@
 //! Screaming Eagle CDN - A high-performance CDN written in Rust
 
+pub mod accept;
+pub mod audit;
 pub mod auth;
+pub mod availability;
+pub mod bandwidth;
 pub mod cache;
+pub mod cache_key;
+pub mod cache_status;
+pub mod cache_tags;
+pub mod chaos;
 pub mod circuit_breaker;
+pub mod cli;
 pub mod coalesce;
+pub mod compression;
 pub mod config;
+pub mod connections;
+pub mod cookies;
+pub mod device;
+pub mod dictionary;
 pub mod edge;
+pub mod encoding;
 pub mod error;
 pub mod error_pages;
+pub mod freshness;
 pub mod handlers;
+pub mod headers;
 pub mod health;
+pub mod jobs;
+pub mod maintenance;
+pub mod memory;
 pub mod metrics;
+pub mod normalize;
 pub mod observability;
 pub mod origin;
+pub mod origin_registry;
+pub mod pool_sizing;
+pub mod purge;
 pub mod range;
 pub mod rate_limit;
+pub mod recent;
+pub mod request_limits;
+pub mod request_timing;
+pub mod revalidation;
 pub mod security;
+pub mod store_queue;
+#[cfg(test)]
+mod test_support;
+pub mod trace_sampling;
+pub mod units;
+pub mod validation;
@@ -1,47 +1,94 @@
 use axum::{
-    Router, middleware,
-    routing::{get, post},
+    Router, ServiceExt,
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{delete, get, post, put},
 };
+use axum_server::tls_rustls::RustlsConfig;
+use clap::Parser;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::process::ExitCode;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::signal;
-use tower::ServiceBuilder;
+use tower::{Layer, ServiceBuilder};
 use tower_http::{
     compression::CompressionLayer,
     cors::{Any, CorsLayer},
     trace::TraceLayer,
 };
-use tracing::info;
+use tracing::{info, warn};
+use opentelemetry_sdk::trace::SdkTracer;
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
-use screaming_eagle::auth::{AdminAuth, admin_auth_middleware};
+use screaming_eagle::audit::{AuditLog, audit_middleware};
+use screaming_eagle::auth::{AdminAuth, admin_auth_middleware, purge_auth_middleware};
+use screaming_eagle::bandwidth::bytes_sent_middleware;
 use screaming_eagle::cache::Cache;
+use screaming_eagle::chaos::FaultInjector;
 use screaming_eagle::circuit_breaker::{self, CircuitBreakerManager};
+use screaming_eagle::cli::{self, Cli};
 use screaming_eagle::coalesce::RequestCoalescer;
-use screaming_eagle::config::{self, Config};
+use screaming_eagle::compression::CompressionPolicy;
+use screaming_eagle::config::{self, Config, ServerConfig};
+use screaming_eagle::connections::{ConnectionTracker, set_client_socket_options};
+use screaming_eagle::dictionary::Dictionaries;
 use screaming_eagle::edge::{EdgeProcessor, edge_processing_middleware};
+use screaming_eagle::encoding::negotiate_encoding_middleware;
 use screaming_eagle::error::init_error_pages;
 use screaming_eagle::error_pages::ErrorPages;
 use screaming_eagle::handlers::{
-    self, AppState, cache_stats, cdn_handler, circuit_breaker_status, coalesce_stats, health,
-    metrics as metrics_handler, origin_health_status, purge_cache, warm_cache,
+    self, AppState, add_fault, add_origins, audit_log, cache_key_lookup, cache_stats,
+    cancel_warm_job, circuit_breaker_status, clear_faults, coalesce_stats,
+    dictionary_lookup, drain, export_cache, health, import_cache, info, job_status,
+    list_cache_pins, list_faults, list_origins, metrics as metrics_handler,
+    mint_purge_token_handler, origin_health_status, origin_sla, pin_cache_entries, purge_cache,
+    quota_status, rate_limit_status, receive_health_gossip, recent_cache_keys, reload_dictionary,
+    reload_error_pages, remove_fault, remove_origin, replay_warm, self_test, test_edge_rules,
+    unpin_cache_entries, update_origin, update_rate_limit, warm_cache, warm_job_status,
 };
-use screaming_eagle::health::{HealthChecker, spawn_health_checks};
+use screaming_eagle::health::{HealthChecker, spawn_health_checks, spawn_health_gossip};
+use screaming_eagle::jobs::JobRegistry;
+use screaming_eagle::memory::{MemoryWatchdog, spawn_memory_watchdog};
 use screaming_eagle::metrics::Metrics;
+use screaming_eagle::normalize::{PathNormalization, PathNormalizationLayer, PathNormalizer};
+use screaming_eagle::observability::{
+    RequestLogging, init_tracing, request_id_middleware, request_logging_middleware,
+};
 use screaming_eagle::origin::OriginFetcher;
-use screaming_eagle::rate_limit::{RateLimitConfig, RateLimiter};
+use screaming_eagle::origin_registry::OriginRegistry;
+use screaming_eagle::pool_sizing::spawn_pool_sizing;
+use screaming_eagle::purge::{PurgeDeduplicator, PurgeGuard};
+use screaming_eagle::rate_limit::{
+    RateLimitConfig, RateLimitExemptions, RateLimiter, rate_limit_middleware,
+};
+use screaming_eagle::recent::RecentRequests;
+use screaming_eagle::request_limits::{RequestLimits, request_limits_middleware};
+use screaming_eagle::request_timing::{RequestTimingRecorder, request_timing_middleware};
+use screaming_eagle::revalidation::RevalidationLimiter;
 use screaming_eagle::security::{
-    Security, ip_access_control_middleware, request_signing_middleware, security_headers_middleware,
+    Security, TrustedProxies, ip_access_control_middleware, request_signing_middleware,
+    security_headers_middleware,
 };
+use screaming_eagle::store_queue::StoreQueue;
+
+/// Upper bound for cache import uploads (an export of millions of keys runs to hundreds of MB)
+const MAX_IMPORT_BODY_BYTES: usize = 512 * 1024 * 1024;
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> anyhow::Result<ExitCode> {
+    // Operator subcommands talk to a running node instead of starting one
+    if let Some(command) = Cli::parse().command {
+        return Ok(ExitCode::from(cli::run(command).await));
+    }
+
     // Load configuration
     let config = load_config()?;
 
-    // Initialize logging
-    init_logging(&config.logging);
+    // Initialize tracing and logging
+    let tracer = init_tracing(&config.observability, &config.regex)?;
+    init_logging(&config.logging, tracer);
 
     info!(
         "Starting Screaming Eagle CDN v{}",
@@ -49,21 +96,19 @@ async fn main() -> anyhow::Result<()> {
     );
 
     // Initialize error pages
-    let error_pages = ErrorPages::new(&config.error_pages);
+    let origin_error_dirs = config.origins.iter().filter_map(|(name, origin)| {
+        origin
+            .error_pages_dir
+            .clone()
+            .map(|dir| (name.clone(), dir))
+    });
+    let error_pages = ErrorPages::new(&config.error_pages).with_origin_dirs(origin_error_dirs);
     if error_pages.is_enabled() {
         let pages = error_pages.available_pages();
         info!("Custom error pages enabled ({} pages loaded)", pages.len());
     }
     init_error_pages(error_pages);
 
-    // Initialize rate limiter
-    let rate_limiter = Arc::new(RateLimiter::new(RateLimitConfig {
-        requests_per_window: config.rate_limit.requests_per_window,
-        window_secs: config.rate_limit.window_secs,
-        burst_size: config.rate_limit.burst_size,
-        enabled: config.rate_limit.enabled,
-    }));
-
     // Initialize circuit breaker manager
     let circuit_breaker = Arc::new(CircuitBreakerManager::new(
         circuit_breaker::CircuitBreakerConfig {
@@ -75,13 +120,79 @@ async fn main() -> anyhow::Result<()> {
     ));
 
     // Initialize other components
+    if config.cache.enabled && config.cache.max_size_mb == 0 {
+        warn!("cache.max_size_mb is 0; caching is disabled");
+    }
     let cache = Arc::new(Cache::new(config.cache.clone()));
-    let origin = Arc::new(OriginFetcher::with_pool_config(
-        config.origins.clone(),
-        config.connection_pool.clone(),
-    )?);
-    let metrics = Arc::new(Metrics::new());
-    let health_checker = Arc::new(HealthChecker::new(config.origins.clone()));
+    let metrics = Arc::new(
+        Metrics::new()
+            .with_exemplars(config.observability.exemplars_enabled())
+            .with_path_series(&config.observability.metrics),
+    );
+    metrics.start_recorder(config.observability.metrics.event_queue_capacity);
+    let faults = Arc::new(
+        FaultInjector::new(config.chaos.clone())
+            .with_regex_limits(config.regex.clone())
+            .with_metrics(metrics.clone()),
+    );
+    if faults.is_enabled() {
+        warn!("Fault injection is enabled; admins can make origin fetches fail on purpose");
+    }
+    let audit = Arc::new(
+        AuditLog::new(config.admin.audit.clone())
+            .with_trusted_proxies(TrustedProxies::new(&config.server.trusted_proxies))
+            .with_metrics(metrics.clone()),
+    );
+    audit.start_writer();
+    let origin = Arc::new(
+        OriginFetcher::with_pool_config(config.origins.clone(), config.connection_pool.clone())?
+            .with_forwarded_headers(config.forwarded_headers())
+            .with_kept_response_headers(config.cache.origin_headers_read())
+            .with_latency_window(config.cache.adaptive_stale.window_size)
+            .with_error_policy(config.origin_errors.clone())
+            .with_fault_injector(faults.clone())
+            .with_metrics(metrics.clone()),
+    );
+
+    // Initialize rate limiter
+    let rate_limiter = Arc::new(
+        RateLimiter::new(RateLimitConfig {
+            requests_per_window: config.rate_limit.requests_per_window,
+            window_secs: config.rate_limit.window_secs,
+            burst_size: config.rate_limit.burst_size,
+            enabled: config.rate_limit.enabled,
+        })
+        .with_max_tracked_clients(config.rate_limit.max_tracked_clients)
+        .with_ipv6_prefix_len(config.rate_limit.ipv6_prefix_len)
+        .with_api_keys(
+            config.rate_limit.api_key_header.as_deref(),
+            &config.rate_limit.api_keys,
+        )
+        .with_trusted_proxies(TrustedProxies::new(&config.server.trusted_proxies))
+        .with_exemptions(RateLimitExemptions::new(
+            &config.rate_limit.exempt_cidrs,
+            &config.rate_limit.exempt_user_agents,
+            &config.regex,
+        ))
+        .with_metrics(metrics.clone()),
+    );
+
+    let gossip = &config.cluster.health_gossip;
+    let health_checker = Arc::new(
+        HealthChecker::new(config.origins.clone())
+            .with_peer_gossip(
+                config.cluster.resolve_node_id(),
+                if gossip.enabled {
+                    gossip.policy
+                } else {
+                    config::PeerHealthPolicy::Local
+                },
+                gossip.peer_ttl(),
+            )
+            .with_availability(config.availability.clone())
+            .with_maintenance(config.maintenance.clone())
+            .with_origin_fetcher(origin.clone()),
+    );
     let coalescer = Arc::new(RequestCoalescer::new(config.coalesce.max_waiters));
 
     if config.coalesce.enabled {
@@ -91,8 +202,76 @@ async fn main() -> anyhow::Result<()> {
         );
     }
 
+    // Initialize edge processor
+    let dictionaries =
+        Dictionaries::load(&config.edge.dictionaries).map_err(|e| anyhow::anyhow!("{}", e))?;
+    let edge_processor = Arc::new(
+        EdgeProcessor::from_config(&config.edge, &config.regex)
+            .with_dictionaries(Arc::new(dictionaries))
+            .with_metrics(metrics.clone()),
+    );
+    if config.edge.enabled {
+        info!(
+            "Edge processing enabled ({} rewrite rules, {} routing rules, {} dictionaries)",
+            config.edge.rewrite_rules.len(),
+            config.edge.routing_rules.len(),
+            config.edge.dictionaries.len()
+        );
+    }
+
+    // Recently served keys, restored from the last shutdown if persisted
+    let recent = Arc::new(RecentRequests::new(config.cache.recent.clone()));
+    let recent_persist_path = config
+        .cache
+        .recent
+        .persist_path
+        .as_ref()
+        .filter(|_| recent.is_enabled())
+        .map(std::path::PathBuf::from);
+    if let Some(path) = recent_persist_path.as_deref().filter(|path| path.exists()) {
+        match recent.load(path) {
+            Ok(loaded) => info!(
+                "Loaded {} recently served keys from {}",
+                loaded,
+                path.display()
+            ),
+            Err(e) => warn!(
+                "Failed to load recently served keys from {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    // Origins can be added, replaced and removed at runtime by the admin API
+    let origins = Arc::new(OriginRegistry::new(
+        &config,
+        origin.clone(),
+        health_checker.clone(),
+        circuit_breaker.clone(),
+        cache.clone(),
+    )?);
+
+    // Signals and the admin drain both stop the listeners and health checks
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+
+    let purges = Arc::new(
+        PurgeDeduplicator::new(config.admin.purge_dedup.clone()).with_metrics(metrics.clone()),
+    );
+    let purge_guard =
+        Arc::new(PurgeGuard::new(config.admin.purge_safety.clone()).with_metrics(metrics.clone()));
+    let store_queue = Arc::new(
+        StoreQueue::new(config.cache.store_queue.clone(), cache.clone())
+            .with_metrics(metrics.clone()),
+    );
+    let revalidations = Arc::new(
+        RevalidationLimiter::new(config.cache.max_concurrent_revalidations)
+            .with_metrics(metrics.clone()),
+    );
     let state = Arc::new(AppState {
         cache: cache.clone(),
+        content_cache: cache.clone(),
+        origin_client: origin.clone(),
         origin,
         config: Arc::new(config.clone()),
         metrics,
@@ -101,40 +280,109 @@ async fn main() -> anyhow::Result<()> {
         health_checker: health_checker.clone(),
         coalescer,
         coalesce_enabled: config.coalesce.enabled,
+        started_at: chrono::Utc::now(),
+        jobs: Arc::new(JobRegistry::new().with_retention(config.admin.jobs.retention())),
+        edge: edge_processor,
+        recent: recent.clone(),
+        origins,
+        faults,
+        audit: audit.clone(),
+        purges,
+        purge_guard,
+        store_queue,
+        revalidations,
+        shutdown: shutdown_tx.clone(),
     });
 
-    // Start background cache cleanup task
-    let cache_clone = cache.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(60));
-        loop {
-            interval.tick().await;
-            cache_clone.cleanup_expired();
-        }
-    });
+    // Start background cache cleanup task, a few shards per tick
+    if cache.enabled() {
+        let cache_clone = cache.clone();
+        let metrics = state.metrics.clone();
+        let cleanup_interval = config.cache.cleanup.interval();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(cleanup_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let started = Instant::now();
+                let stats = cache_clone.sweep_expired();
+                metrics.record_cache_sweep(&stats, started.elapsed());
+            }
+        });
+    }
+
+    // Shed cache entries before the process outgrows its memory limit
+    if config.memory_watchdog.enabled {
+        spawn_memory_watchdog(MemoryWatchdog::new(
+            config.memory_watchdog.clone(),
+            cache.clone(),
+            state.metrics.clone(),
+        ));
+    }
 
     // Start background rate limiter cleanup task
     let rate_limiter_clone = rate_limiter.clone();
+    let rate_limit_cleanup_interval = config.rate_limit.cleanup_interval();
+    let rate_limit_idle_timeout = config.rate_limit.idle_timeout();
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(300));
+        let mut interval = tokio::time::interval(rate_limit_cleanup_interval);
         loop {
             interval.tick().await;
-            rate_limiter_clone.cleanup(Duration::from_secs(600));
+            rate_limiter_clone.cleanup(rate_limit_idle_timeout);
         }
     });
 
+    // Warm origin connections in the background; readiness doesn't wait on them
+    for name in config.origins.keys() {
+        state.origin.spawn_preconnect(name);
+    }
+
+    // Sample origin concurrency and resize adaptive connection pools
+    spawn_pool_sizing(
+        state.origin.clone(),
+        config.connection_pool.adaptive_interval(),
+    );
+
     // Start origin health check tasks
-    let (health_shutdown_tx, health_shutdown_rx) = tokio::sync::watch::channel(false);
-    spawn_health_checks(health_checker.clone(), health_shutdown_rx);
+    spawn_health_checks(health_checker.clone(), shutdown_rx.clone());
+    if gossip.enabled {
+        info!(
+            "Cluster health gossip enabled (node {}, {} peers, policy {})",
+            health_checker.node_id(),
+            config.cluster.peers.len(),
+            gossip.policy.as_str()
+        );
+        spawn_health_gossip(
+            health_checker.clone(),
+            &config.cluster.peers,
+            gossip,
+            config.admin.auth_token.clone(),
+            shutdown_rx.clone(),
+        );
+    }
 
     // Initialize admin authentication
-    let admin_auth = Arc::new(AdminAuth::new(config.admin.clone()));
+    let admin_auth = Arc::new(
+        AdminAuth::new(config.admin.clone())
+            .with_trusted_proxies(TrustedProxies::new(&config.server.trusted_proxies))
+            .with_metrics(state.metrics.clone()),
+    );
     if config.admin.auth_enabled {
         info!("Admin API authentication enabled");
     }
 
+    // Start background admin lockout cleanup task
+    let admin_auth_clone = admin_auth.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(300));
+        loop {
+            interval.tick().await;
+            admin_auth_clone.cleanup(Duration::from_secs(3600));
+        }
+    });
+
     // Initialize security
-    let security = Arc::new(Security::new(config.security.clone()));
+    let security = Arc::new(Security::new(config.security.clone(), &config.regex));
     if security.headers_enabled() {
         info!("Security headers enabled");
     }
@@ -145,76 +393,225 @@ async fn main() -> anyhow::Result<()> {
         info!("IP-based access control enabled");
     }
 
-    // Initialize edge processor
-    let edge_processor = Arc::new(EdgeProcessor::from_config(&config.edge));
-    if config.edge.enabled {
-        info!(
-            "Edge processing enabled ({} rewrite rules, {} routing rules)",
-            config.edge.rewrite_rules.len(),
-            config.edge.routing_rules.len()
+    let connections = Arc::new(ConnectionTracker::new(state.metrics.clone()));
+
+    // Build router
+    let app = build_router(state, admin_auth, security, config.edge.enabled);
+
+    // Normalize paths ahead of routing so every layer sees the same canonical path
+    let path_normalizer = Arc::new(PathNormalizer::new(config.path_normalization.clone()));
+    if path_normalizer.is_enabled() {
+        info!("Request path normalization enabled");
+    }
+    let app = PathNormalizationLayer::new(path_normalizer).layer(app);
+
+    // Bind every listener before serving, so one bad address stops startup
+    let listeners = config.listeners()?;
+    let rustls_config = match &config.tls {
+        Some(tls_config) if listeners.iter().any(|listener| listener.tls) => {
+            info!("TLS enabled, loading certificates");
+            Some(RustlsConfig::from_pem_file(&tls_config.cert_path, &tls_config.key_path).await?)
+        }
+        _ => None,
+    };
+    // Sockets passed in by systemd are used in place of binding our own
+    let mut activated = activated_listeners();
+    let mut bound = Vec::with_capacity(listeners.len());
+    for listener in &listeners {
+        let socket = match activated.remove(&listener.addr) {
+            Some(socket) => {
+                info!("Using socket-activated listener for {}", listener.addr);
+                socket.set_nonblocking(true)?;
+                socket
+            }
+            None => bind_listener(listener.addr, &config.server)
+                .map_err(|e| anyhow::anyhow!("Failed to bind {}: {}", listener.addr, e))?,
+        };
+        set_client_socket_options(&socket, &config.server).map_err(|e| {
+            anyhow::anyhow!("Failed to set socket options on {}: {}", listener.addr, e)
+        })?;
+        bound.push((*listener, socket));
+    }
+    for addr in activated.keys() {
+        warn!(
+            "Ignoring socket-activated listener {}: not a configured address",
+            addr
         );
     }
+    if config.server.reuse_port {
+        info!("Listeners bound with SO_REUSEPORT");
+    }
 
-    // Build router
-    let app = build_router(
-        state,
-        admin_auth,
-        security,
-        edge_processor,
-        config.edge.enabled,
-    );
+    // One shutdown signal stops the health checks and drains every listener
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = shutdown_tx.send(true);
+    });
 
-    // Start server
-    let addr: SocketAddr = config.server_addr().parse()?;
+    let mut servers = tokio::task::JoinSet::new();
+    for (listener, socket) in bound {
+        let app = app.clone();
+        let connections = connections.clone();
+        let shutdown = shutdown_rx.clone();
+        match rustls_config.clone().filter(|_| listener.tls) {
+            Some(rustls_config) => {
+                info!("Listening on https://{}", listener.addr);
+                servers.spawn(serve_tls(socket, app, rustls_config, connections, shutdown));
+            }
+            None => {
+                info!("Listening on http://{}", listener.addr);
+                servers.spawn(serve_plain(socket, app, connections, shutdown));
+            }
+        }
+    }
 
-    // Check for TLS configuration
-    if let Some(ref tls_config) = config.tls {
-        info!("TLS enabled, loading certificates");
-        start_tls_server(addr, app, tls_config, health_shutdown_tx).await?;
-    } else {
-        info!("Listening on http://{}", addr);
-        let listener = tokio::net::TcpListener::bind(addr).await?;
-        axum::serve(
-            listener,
-            app.into_make_service_with_connect_info::<SocketAddr>(),
-        )
-        .with_graceful_shutdown(shutdown_signal_with_health(health_shutdown_tx))
-        .await?;
+    // Listeners drain open connections after a shutdown signal, until the deadline
+    let shutdown_timeout = config.server.shutdown_timeout();
+    let drain_deadline = async {
+        wait_for_shutdown(shutdown_rx.clone()).await;
+        tokio::time::sleep(shutdown_timeout).await;
+    };
+    tokio::pin!(drain_deadline);
+    loop {
+        tokio::select! {
+            result = servers.join_next() => match result {
+                Some(result) => result??,
+                None => break,
+            },
+            _ = &mut drain_deadline => {
+                let open = connections.open();
+                warn!(
+                    open_connections = open,
+                    "Shutdown deadline of {}s reached with {} client connections still open; closing them",
+                    shutdown_timeout.as_secs(),
+                    open
+                );
+                servers.shutdown().await;
+                break;
+            }
+        }
     }
 
+    if let Some(path) = recent_persist_path.as_deref() {
+        match recent.save(path) {
+            Ok(saved) => info!("Saved {} recently served keys to {}", saved, path.display()),
+            Err(e) => warn!(
+                "Failed to save recently served keys to {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    // Entries still queued for the audit file would otherwise be lost
+    audit.flush().await;
+
     info!("Server shutdown complete");
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
-async fn start_tls_server(
+/// Bind a listening socket; IPv6 sockets are v6-only so `[::]` and `0.0.0.0`
+/// can both listen on the same port
+///
+/// With `reuse_port`, other processes binding the same address with it share
+/// the port, and the kernel spreads new connections across them.
+fn bind_listener(
     addr: SocketAddr,
-    app: Router,
-    tls_config: &config::TlsConfig,
-    health_shutdown_tx: tokio::sync::watch::Sender<bool>,
-) -> anyhow::Result<()> {
-    use axum_server::tls_rustls::RustlsConfig;
+    server: &ServerConfig,
+) -> std::io::Result<std::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let reuse_port = server.reuse_port;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(unix))]
+    if reuse_port {
+        warn!("server.reuse_port is only supported on Unix; ignoring it");
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(server.listen_backlog.min(i32::MAX as u32) as i32)?;
+    Ok(socket.into())
+}
+
+/// TCP listeners inherited through systemd socket activation (`LISTEN_FDS`),
+/// by the address they are bound to
+fn activated_listeners() -> HashMap<SocketAddr, std::net::TcpListener> {
+    let mut fds = listenfd::ListenFd::from_env();
+    let mut listeners = HashMap::new();
+    for index in 0..fds.len() {
+        let socket = match fds.take_tcp_listener(index) {
+            Ok(Some(socket)) => socket,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Ignoring inherited socket {}: {}", index, e);
+                continue;
+            }
+        };
+        match socket.local_addr() {
+            Ok(addr) => {
+                listeners.insert(addr, socket);
+            }
+            Err(e) => warn!("Ignoring inherited socket {}: {}", index, e),
+        }
+    }
+    listeners
+}
 
-    let rustls_config =
-        RustlsConfig::from_pem_file(&tls_config.cert_path, &tls_config.key_path).await?;
+/// Resolves once shutdown has been signalled
+async fn wait_for_shutdown(mut shutdown: tokio::sync::watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
 
-    info!("Listening on https://{}", addr);
+async fn serve_plain(
+    socket: std::net::TcpListener,
+    app: PathNormalization<Router>,
+    connections: Arc<ConnectionTracker>,
+    shutdown: tokio::sync::watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    axum::serve(
+        tokio::net::TcpListener::from_std(socket)?,
+        connections.track(
+            ServiceExt::<axum::extract::Request>::into_make_service_with_connect_info::<SocketAddr>(
+                app,
+            ),
+        ),
+    )
+    .with_graceful_shutdown(wait_for_shutdown(shutdown))
+    .await?;
+    Ok(())
+}
 
+async fn serve_tls(
+    socket: std::net::TcpListener,
+    app: PathNormalization<Router>,
+    rustls_config: RustlsConfig,
+    connections: Arc<ConnectionTracker>,
+    shutdown: tokio::sync::watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    // No deadline here: main stops every listener once server.shutdown_timeout_secs is up
     let handle = axum_server::Handle::new();
-    let handle_clone = handle.clone();
-
-    // Spawn shutdown handler
+    let shutdown_handle = handle.clone();
     tokio::spawn(async move {
-        shutdown_signal().await;
-        // Signal health checks to stop
-        let _ = health_shutdown_tx.send(true);
-        handle_clone.graceful_shutdown(Some(Duration::from_secs(30)));
+        wait_for_shutdown(shutdown).await;
+        shutdown_handle.graceful_shutdown(None);
     });
 
-    axum_server::bind_rustls(addr, rustls_config)
+    axum_server::from_tcp_rustls(socket, rustls_config)?
+        .map(|acceptor| connections.count_handshake_failures(acceptor))
         .handle(handle)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .serve(connections.track(
+            ServiceExt::<axum::extract::Request>::into_make_service_with_connect_info::<SocketAddr>(
+                app,
+            ),
+        ))
         .await?;
-
     Ok(())
 }
 
@@ -231,18 +628,21 @@ fn load_config() -> anyhow::Result<Config> {
     }
 }
 
-fn init_logging(config: &config::LoggingConfig) {
+fn init_logging(config: &config::LoggingConfig, tracer: Option<SdkTracer>) {
     let filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.level));
+    let otel = tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
 
     if config.json_format {
         tracing_subscriber::registry()
             .with(filter)
+            .with(otel)
             .with(fmt::layer().json())
             .init();
     } else {
         tracing_subscriber::registry()
             .with(filter)
+            .with(otel)
             .with(fmt::layer())
             .init();
     }
@@ -252,48 +652,94 @@ fn build_router(
     state: Arc<AppState>,
     admin_auth: Arc<AdminAuth>,
     security: Arc<Security>,
-    edge_processor: Arc<EdgeProcessor>,
     edge_enabled: bool,
 ) -> Router {
     // Public API routes (no auth required)
     let public_api_routes = Router::new()
         .route("/health", get(health))
-        .route("/metrics", get(metrics_handler));
+        .route("/metrics", get(metrics_handler))
+        .route("/quota", get(quota_status));
 
     // Protected admin routes (auth required when enabled)
     let protected_api_routes = Router::new()
+        .route("/info", get(info))
         .route("/stats", get(cache_stats))
-        .route("/purge", post(purge_cache))
         .route("/warm", post(warm_cache))
+        .route("/cache/export", get(export_cache))
+        .route(
+            "/cache/import",
+            post(import_cache).layer(DefaultBodyLimit::max(MAX_IMPORT_BODY_BYTES)),
+        )
+        .route("/cache/recent", get(recent_cache_keys))
+        .route(
+            "/cache/pin",
+            post(pin_cache_entries).delete(unpin_cache_entries),
+        )
+        .route("/cache/pins", get(list_cache_pins))
+        .route("/cache/key", get(cache_key_lookup))
+        .route("/selftest", post(self_test))
+        .route("/warm/replay", post(replay_warm))
+        .route("/warm/{id}", get(warm_job_status).delete(cancel_warm_job))
+        .route("/jobs/{id}", get(job_status))
         .route("/circuit-breakers", get(circuit_breaker_status))
+        .route("/rate-limit", get(rate_limit_status).put(update_rate_limit))
+        .route("/origins", get(list_origins).post(add_origins))
+        .route("/origins/{name}", put(update_origin).delete(remove_origin))
         .route("/origins/health", get(origin_health_status))
+        .route("/origins/{name}/sla", get(origin_sla))
         .route("/coalesce", get(coalesce_stats))
+        .route("/error-pages/reload", post(reload_error_pages))
+        .route("/drain", post(drain))
+        .route("/tokens/purge", post(mint_purge_token_handler))
+        .route("/edge/dictionaries/{name}", get(dictionary_lookup))
+        .route("/edge/dictionaries/{name}/reload", post(reload_dictionary))
+        .route(
+            "/faults",
+            get(list_faults).post(add_fault).delete(clear_faults),
+        )
+        .route("/faults/{id}", delete(remove_fault))
+        .route("/audit", get(audit_log))
+        .route_layer(middleware::from_fn_with_state(
+            state.audit.clone(),
+            audit_middleware,
+        ))
+        // Peer gossip and dry runs change nothing, so aren't audited
+        .route("/cluster/health", post(receive_health_gossip))
+        .route("/edge/test", post(test_edge_rules))
         .route_layer(middleware::from_fn_with_state(
             admin_auth.clone(),
             admin_auth_middleware,
         ));
 
+    // Purge also accepts scoped purge tokens in place of the admin token
+    let purge_routes = Router::new()
+        .route("/purge", post(purge_cache))
+        .route_layer(middleware::from_fn_with_state(
+            state.audit.clone(),
+            audit_middleware,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            admin_auth.clone(),
+            purge_auth_middleware,
+        ));
+
     // Combined API routes
     let api_routes = Router::new()
         .merge(public_api_routes)
-        .merge(protected_api_routes);
-
-    // CDN routes - support both GET and HEAD methods (RFC 9110)
-    let cdn_routes = Router::new()
-        .route("/{origin}/{*path}", get(cdn_handler).head(cdn_handler))
-        .route(
-            "/{*path}",
-            get(handlers::root_cdn_handler).head(handlers::root_cdn_handler),
-        );
+        .merge(protected_api_routes)
+        .merge(purge_routes);
 
     // Build router with middleware layers
     let mut router = Router::new()
         .nest("/_cdn", api_routes)
-        .merge(cdn_routes)
+        .merge(handlers::cdn_routes(&state, admin_auth.clone()))
         .layer(
             ServiceBuilder::new()
                 .layer(TraceLayer::new_for_http())
-                .layer(CompressionLayer::new())
+                .layer(
+                    CompressionLayer::new()
+                        .compress_when(CompressionPolicy::new(state.config.compression.clone())),
+                )
                 .layer(
                     CorsLayer::new()
                         .allow_origin(Any)
@@ -301,6 +747,13 @@ fn build_router(
                         .allow_headers(Any),
                 ),
         )
+        // Settles the response coding before compression and the cache key see it
+        .layer(middleware::from_fn(negotiate_encoding_middleware))
+        // Outside compression so bytes are counted as sent on the wire
+        .layer(middleware::from_fn_with_state(
+            state.metrics.clone(),
+            bytes_sent_middleware,
+        ))
         // Security middleware layers (applied to all routes)
         .layer(middleware::from_fn_with_state(
             security.clone(),
@@ -313,16 +766,51 @@ fn build_router(
         .layer(middleware::from_fn_with_state(
             security.clone(),
             ip_access_control_middleware,
+        ))
+        // Exemptions for probes and peers are settled inside, before any tokens are spent
+        .layer(middleware::from_fn_with_state(
+            state.rate_limiter.clone(),
+            rate_limit_middleware,
         ));
 
     // Add edge processing middleware if enabled
     if edge_enabled {
         router = router.layer(middleware::from_fn_with_state(
-            edge_processor,
+            state.edge.clone(),
             edge_processing_middleware,
         ));
     }
 
+    // URL length and GET body limits run before any other request processing
+    let request_limits =
+        RequestLimits::new(&state.config.server).with_metrics(state.metrics.clone());
+    router = router.layer(middleware::from_fn_with_state(
+        Arc::new(request_limits),
+        request_limits_middleware,
+    ));
+
+    // Starts the clock on every phase the rest of the stack goes through
+    router = router.layer(middleware::from_fn_with_state(
+        RequestTimingRecorder::new(
+            state.metrics.clone(),
+            state.config.observability.server_timing,
+        ),
+        request_timing_middleware,
+    ));
+
+    // Access logging goes outermost so it times and sees the final response
+    let logging = &state.config.observability.request_logging;
+    if logging.enabled {
+        router = router.layer(middleware::from_fn_with_state(
+            RequestLogging::new(logging.clone(), state.metrics.clone())
+                .with_trace_propagation(state.config.observability.tracing.propagate_context),
+            request_logging_middleware,
+        ));
+    }
+
+    // Outside logging so the access log and error responses share one ID
+    router = router.layer(middleware::from_fn(request_id_middleware));
+
     router.with_state(state)
 }
 
@@ -351,9 +839,3 @@ async fn shutdown_signal() {
 
     info!("Shutdown signal received, starting graceful shutdown");
 }
-
-async fn shutdown_signal_with_health(health_shutdown_tx: tokio::sync::watch::Sender<bool>) {
-    shutdown_signal().await;
-    // Signal health checks to stop
-    let _ = health_shutdown_tx.send(true);
-}
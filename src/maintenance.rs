@@ -0,0 +1,187 @@
+//! Maintenance windows
+//!
+//! Weekly windows, in an origin's local time, during which its health check
+//! failures don't count toward marking it unhealthy and its fetch failures
+//! don't open the circuit breaker.
+//!
+//! Windows follow the wall clock across DST changes: a window opens the
+//! first time the local clock reads its start and closes the last time it
+//! reads its end. A window that falls entirely inside a skipped hour opens
+//! when the clocks jump and lasts its usual length.
+
+use chrono::{DateTime, Datelike, LocalResult, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use crate::config::{MaintenanceConfig, MaintenanceWindowConfig};
+
+/// Whether the window is open at `now`
+pub fn window_open_at(window: &MaintenanceWindowConfig, now: DateTime<Utc>) -> bool {
+    let today = now.with_timezone(&window.timezone).date_naive();
+    let length = window_length(window);
+
+    // A window that crosses midnight may have started yesterday
+    [today.pred_opt(), Some(today)]
+        .into_iter()
+        .flatten()
+        .filter(|day| window.days.is_empty() || window.days.contains(&day.weekday()))
+        .any(|day| {
+            let start_local = day.and_time(window.start);
+            let Some(start) = resolve(window.timezone, start_local, Occurrence::First) else {
+                return false;
+            };
+            let end = resolve(window.timezone, start_local + length, Occurrence::Last)
+                .filter(|end| *end > start)
+                .unwrap_or(start + length);
+            start <= now && now < end
+        })
+}
+
+/// The settings of the first open window for an origin, its own before the global ones
+pub fn active_maintenance<'a>(
+    global: &'a MaintenanceConfig,
+    origin: &'a MaintenanceConfig,
+    now: DateTime<Utc>,
+) -> Option<&'a MaintenanceConfig> {
+    [origin, global]
+        .into_iter()
+        .find(|config| config.windows.iter().any(|w| window_open_at(w, now)))
+}
+
+/// Wall-clock length of a window; an end at or before the start is the next day
+fn window_length(window: &MaintenanceWindowConfig) -> TimeDelta {
+    let length = window.end.signed_duration_since(window.start);
+    if length > TimeDelta::zero() {
+        length
+    } else {
+        length + TimeDelta::days(1)
+    }
+}
+
+/// Which instant a local time repeated by a DST change refers to
+#[derive(Clone, Copy)]
+enum Occurrence {
+    First,
+    Last,
+}
+
+/// The instant the local clock reads `local`
+///
+/// A time skipped by a DST change resolves to the moment the clocks jump.
+fn resolve(tz: Tz, local: NaiveDateTime, occurrence: Occurrence) -> Option<DateTime<Utc>> {
+    // Gaps are at most a couple of hours; walk forward a minute at a time
+    (0..=180).find_map(|minutes| {
+        match tz.from_local_datetime(&(local + TimeDelta::minutes(minutes))) {
+            LocalResult::Single(time) => Some(time.with_timezone(&Utc)),
+            LocalResult::Ambiguous(first, last) => Some(match occurrence {
+                Occurrence::First => first.with_timezone(&Utc),
+                Occurrence::Last => last.with_timezone(&Utc),
+            }),
+            LocalResult::None => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Weekday;
+
+    fn window(days: &[Weekday], start: &str, end: &str, timezone: Tz) -> MaintenanceWindowConfig {
+        MaintenanceWindowConfig {
+            days: days.to_vec(),
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            timezone,
+        }
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn test_daily_window() {
+        let nightly = window(&[], "02:00", "02:30", Tz::UTC);
+        assert!(!window_open_at(&nightly, utc("2026-06-10T01:59:59Z")));
+        assert!(window_open_at(&nightly, utc("2026-06-10T02:00:00Z")));
+        assert!(window_open_at(&nightly, utc("2026-06-10T02:29:59Z")));
+        assert!(!window_open_at(&nightly, utc("2026-06-10T02:30:00Z")));
+    }
+
+    #[test]
+    fn test_window_across_midnight() {
+        // Starts Saturday night, so it's open into Sunday morning only
+        let weekend = window(&[Weekday::Sat], "23:30", "00:30", Tz::UTC);
+        assert!(window_open_at(&weekend, utc("2026-06-13T23:45:00Z")));
+        assert!(window_open_at(&weekend, utc("2026-06-14T00:15:00Z")));
+        assert!(!window_open_at(&weekend, utc("2026-06-14T00:30:00Z")));
+        assert!(!window_open_at(&weekend, utc("2026-06-13T00:15:00Z")));
+        assert!(!window_open_at(&weekend, utc("2026-06-14T23:45:00Z")));
+
+        // Midnight to midnight is the whole day
+        let all_day = window(&[Weekday::Wed], "00:00", "00:00", Tz::UTC);
+        assert!(window_open_at(&all_day, utc("2026-06-10T00:00:00Z")));
+        assert!(window_open_at(&all_day, utc("2026-06-10T23:59:59Z")));
+        assert!(!window_open_at(&all_day, utc("2026-06-11T00:00:00Z")));
+    }
+
+    #[test]
+    fn test_days_are_local() {
+        // Monday 01:00 in Tokyo is still Sunday in UTC
+        let tokyo = window(&[Weekday::Mon], "01:00", "02:00", Tz::Asia__Tokyo);
+        assert!(window_open_at(&tokyo, utc("2026-06-14T16:30:00Z")));
+        assert!(!window_open_at(&tokyo, utc("2026-06-15T16:30:00Z")));
+    }
+
+    #[test]
+    fn test_spring_forward() {
+        // New York skips 02:00-03:00 on 2026-03-08; 03:00 EDT is 07:00 UTC
+        let nightly = window(&[], "02:00", "02:30", Tz::America__New_York);
+        assert!(!window_open_at(&nightly, utc("2026-03-08T06:59:00Z")));
+        assert!(window_open_at(&nightly, utc("2026-03-08T07:00:00Z")));
+        assert!(window_open_at(&nightly, utc("2026-03-08T07:29:00Z")));
+        assert!(!window_open_at(&nightly, utc("2026-03-08T07:30:00Z")));
+        // Back to the usual time the next night, now at UTC-4
+        assert!(window_open_at(&nightly, utc("2026-03-09T06:10:00Z")));
+        assert!(!window_open_at(&nightly, utc("2026-03-09T07:10:00Z")));
+
+        // A window across the jump closes when the clock reads its end
+        let across = window(&[], "01:30", "03:30", Tz::America__New_York);
+        assert!(window_open_at(&across, utc("2026-03-08T06:45:00Z")));
+        assert!(window_open_at(&across, utc("2026-03-08T07:15:00Z")));
+        assert!(!window_open_at(&across, utc("2026-03-08T07:30:00Z")));
+    }
+
+    #[test]
+    fn test_fall_back() {
+        // New York repeats 01:00-02:00 on 2026-11-01: EDT from 05:00 UTC, EST from 06:00 UTC
+        let repeated = window(&[], "01:00", "02:00", Tz::America__New_York);
+        assert!(!window_open_at(&repeated, utc("2026-11-01T04:59:00Z")));
+        assert!(window_open_at(&repeated, utc("2026-11-01T05:30:00Z")));
+        assert!(window_open_at(&repeated, utc("2026-11-01T06:30:00Z")));
+        assert!(!window_open_at(&repeated, utc("2026-11-01T07:00:00Z")));
+
+        // Across midnight and the change: 23:00 EDT to 03:00 EST is five hours
+        let overnight = window(&[Weekday::Sat], "23:00", "03:00", Tz::America__New_York);
+        assert!(window_open_at(&overnight, utc("2026-11-01T03:00:00Z")));
+        assert!(window_open_at(&overnight, utc("2026-11-01T07:59:00Z")));
+        assert!(!window_open_at(&overnight, utc("2026-11-01T08:00:00Z")));
+    }
+
+    #[test]
+    fn test_origin_windows_come_first() {
+        let config = |start: &str, trip_circuit_breaker| MaintenanceConfig {
+            windows: vec![window(&[], start, "05:00", Tz::UTC)],
+            trip_circuit_breaker,
+        };
+        let global = config("03:00", false);
+        let origin = config("04:00", true);
+
+        let at = |time: &str| active_maintenance(&global, &origin, utc(time));
+        assert!(at("2026-06-10T02:00:00Z").is_none());
+        assert!(!at("2026-06-10T03:30:00Z").unwrap().trip_circuit_breaker);
+        assert!(at("2026-06-10T04:30:00Z").unwrap().trip_circuit_breaker);
+        let none = MaintenanceConfig::default();
+        assert!(active_maintenance(&global, &none, utc("2026-06-10T03:30:00Z")).is_some());
+    }
+}
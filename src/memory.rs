@@ -0,0 +1,345 @@
+//! Process memory watchdog
+//!
+//! `cache.max_size_mb` bounds body bytes only; headers, the tag index,
+//! coalescer buffers and metrics all come on top. The watchdog samples the
+//! process RSS and sheds the coldest cache entries when it crosses a
+//! high-water mark, so the process degrades to a colder cache instead of being
+//! OOM-killed and losing everything. Above a critical mark it also refuses new
+//! cache stores until RSS falls back below the low-water mark.
+
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use crate::cache::Cache;
+use crate::config::MemoryWatchdogConfig;
+use crate::metrics::Metrics;
+
+/// Source of the process's resident set size
+pub trait RssProvider: Send + Sync {
+    /// Current RSS in bytes, or `None` if it can't be read on this platform
+    fn rss_bytes(&self) -> Option<u64>;
+}
+
+/// Reads RSS from `/proc/self/statm` (Linux only)
+pub struct ProcStatm {
+    page_size: u64,
+}
+
+impl ProcStatm {
+    pub fn new() -> Self {
+        Self {
+            page_size: page_size(),
+        }
+    }
+}
+
+impl Default for ProcStatm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RssProvider for ProcStatm {
+    #[cfg(target_os = "linux")]
+    fn rss_bytes(&self) -> Option<u64> {
+        // Fields are in pages: size resident shared text lib data dt
+        let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+        let resident: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+        Some(resident * self.page_size)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn rss_bytes(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Page size from the ELF auxiliary vector, without pulling in libc
+#[cfg(target_os = "linux")]
+fn page_size() -> u64 {
+    const AT_PAGESZ: usize = 6;
+    const WORD: usize = std::mem::size_of::<usize>();
+
+    let word = |bytes: &[u8]| usize::from_ne_bytes(bytes.try_into().unwrap_or([0; WORD]));
+    std::fs::read("/proc/self/auxv")
+        .ok()
+        .and_then(|auxv| {
+            auxv.chunks_exact(2 * WORD)
+                .map(|pair| (word(&pair[..WORD]), word(&pair[WORD..])))
+                .find(|(key, _)| *key == AT_PAGESZ)
+                .map(|(_, value)| value as u64)
+        })
+        .unwrap_or(4096)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn page_size() -> u64 {
+    4096
+}
+
+/// Memory pressure level of one RSS sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemoryPressure {
+    Normal,
+    /// Above the high-water mark: shed
+    High,
+    /// Above the critical mark: shed and refuse stores
+    Critical,
+}
+
+impl MemoryPressure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MemoryPressure::Normal => "normal",
+            MemoryPressure::High => "high",
+            MemoryPressure::Critical => "critical",
+        }
+    }
+}
+
+/// Samples RSS and sheds cache entries under pressure
+pub struct MemoryWatchdog {
+    config: MemoryWatchdogConfig,
+    cache: Arc<Cache>,
+    metrics: Arc<Metrics>,
+    rss: Box<dyn RssProvider>,
+}
+
+impl MemoryWatchdog {
+    pub fn new(config: MemoryWatchdogConfig, cache: Arc<Cache>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            config,
+            cache,
+            metrics,
+            rss: Box::new(ProcStatm::new()),
+        }
+    }
+
+    /// Read RSS from `provider` instead of `/proc/self/statm`
+    pub fn with_rss_provider(mut self, provider: impl RssProvider + 'static) -> Self {
+        self.rss = Box::new(provider);
+        self
+    }
+
+    fn pressure(&self, rss: u64) -> MemoryPressure {
+        if self
+            .config
+            .critical_bytes()
+            .is_some_and(|critical| rss >= critical)
+        {
+            MemoryPressure::Critical
+        } else if rss >= self.config.high_water_bytes() {
+            MemoryPressure::High
+        } else {
+            MemoryPressure::Normal
+        }
+    }
+
+    /// Take one RSS sample and act on it
+    ///
+    /// Every sample above the high-water mark sheds `shed_percent` of the
+    /// cache. Stores are refused from the first critical sample until one
+    /// falls below the low-water mark. Returns `None` when RSS is unavailable.
+    pub fn check(&self) -> Option<MemoryPressure> {
+        let rss = self.rss.rss_bytes()?;
+        self.metrics.set_memory_rss(rss);
+        let pressure = self.pressure(rss);
+        let rss_mb = rss / (1024 * 1024);
+
+        if pressure == MemoryPressure::Critical && !self.cache.stores_paused() {
+            self.cache.set_stores_paused(true);
+            self.metrics.set_cache_stores_paused(true);
+            error!(
+                rss_mb,
+                critical_mb = self.config.critical_mb,
+                "Memory critical, refusing new cache stores"
+            );
+        } else if self.cache.stores_paused() && rss < self.config.low_water_bytes() {
+            self.cache.set_stores_paused(false);
+            self.metrics.set_cache_stores_paused(false);
+            info!(rss_mb, "Memory below low-water mark, resuming cache stores");
+        }
+
+        if pressure >= MemoryPressure::High {
+            let shed = self.cache.shed(self.config.shed_percent);
+            self.metrics.record_memory_shed(pressure.as_str());
+            error!(
+                rss_mb,
+                high_water_mb = self.config.high_water_mb,
+                level = pressure.as_str(),
+                shed_entries = shed,
+                remaining_entries = self.cache.stats().total_entries,
+                "Memory above high-water mark, shed coldest cache entries"
+            );
+        }
+
+        Some(pressure)
+    }
+}
+
+/// Run the watchdog every `check_interval_secs` for the life of the process
+pub fn spawn_memory_watchdog(watchdog: MemoryWatchdog) {
+    let interval = watchdog.config.check_interval();
+    tokio::spawn(async move {
+        if watchdog.check().is_none() {
+            warn!("Process RSS unavailable on this platform, memory watchdog disabled");
+            return;
+        }
+        info!(
+            interval_secs = interval.as_secs(),
+            high_water_mb = watchdog.config.high_water_mb,
+            "Started memory watchdog"
+        );
+
+        let mut timer = tokio::time::interval(interval);
+        timer.tick().await; // Skip first tick
+        loop {
+            timer.tick().await;
+            watchdog.check();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CacheEntry;
+    use crate::config::CacheConfig;
+    use crate::headers::ResponseHeaders;
+    use bytes::Bytes;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, Instant, SystemTime};
+
+    const MB: u64 = 1024 * 1024;
+
+    /// RSS provider the test sets by hand
+    #[derive(Clone, Default)]
+    struct FakeRss(Arc<AtomicU64>);
+
+    impl FakeRss {
+        fn set_mb(&self, mb: u64) {
+            self.0.store(mb * MB, Ordering::Relaxed);
+        }
+    }
+
+    impl RssProvider for FakeRss {
+        fn rss_bytes(&self) -> Option<u64> {
+            Some(self.0.load(Ordering::Relaxed))
+        }
+    }
+
+    fn entry() -> CacheEntry {
+        CacheEntry {
+            body: Bytes::from_static(b"0123456789"),
+            headers: ResponseHeaders::default(),
+            status_code: 200,
+            content_type: None,
+            etag: None,
+            last_modified: None,
+            created_at: Instant::now(),
+            expires_at: Instant::now() + Duration::from_secs(3600),
+            expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
+            size: 10,
+            stale_if_error_secs: None,
+            access_count: 0,
+            last_accessed: Instant::now(),
+            cache_tags: Vec::new(),
+            headers_only: false,
+            fetch_duration: Duration::ZERO,
+            immutable: false,
+        }
+    }
+
+    fn watchdog(rss: &FakeRss) -> (MemoryWatchdog, Arc<Cache>, Arc<Metrics>) {
+        let cache = Arc::new(Cache::new(CacheConfig::default()));
+        for i in 0..100 {
+            cache.set(format!("key-{}", i), entry());
+        }
+        let metrics = Arc::new(Metrics::new());
+        let config = MemoryWatchdogConfig {
+            enabled: true,
+            high_water_mb: 1000,
+            critical_mb: 1200,
+            low_water_mb: 800,
+            shed_percent: 10,
+            ..Default::default()
+        };
+        let watchdog = MemoryWatchdog::new(config, cache.clone(), metrics.clone())
+            .with_rss_provider(rss.clone());
+        (watchdog, cache, metrics)
+    }
+
+    #[test]
+    fn test_sheds_above_high_water() {
+        let rss = FakeRss::default();
+        let (watchdog, cache, metrics) = watchdog(&rss);
+
+        rss.set_mb(900);
+        assert_eq!(watchdog.check(), Some(MemoryPressure::Normal));
+        assert_eq!(cache.stats().total_entries, 100);
+
+        // Each sample above the mark sheds again
+        rss.set_mb(1000);
+        assert_eq!(watchdog.check(), Some(MemoryPressure::High));
+        assert_eq!(cache.stats().total_entries, 90);
+        assert_eq!(watchdog.check(), Some(MemoryPressure::High));
+        assert_eq!(cache.stats().total_entries, 81);
+        assert!(!cache.stores_paused());
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"cdn_memory_sheds_total{level="high"} 2"#));
+        assert!(output.contains(&format!("cdn_memory_rss_bytes {}", 1000 * MB)));
+    }
+
+    #[test]
+    fn test_critical_pauses_stores_until_low_water() {
+        let rss = FakeRss::default();
+        let (watchdog, cache, metrics) = watchdog(&rss);
+
+        rss.set_mb(1300);
+        assert_eq!(watchdog.check(), Some(MemoryPressure::Critical));
+        assert!(cache.stores_paused());
+        assert_eq!(cache.stats().total_entries, 90);
+        assert!(metrics.gather().contains("cdn_cache_stores_paused 1"));
+
+        // Between the low-water and high-water marks stores stay refused
+        rss.set_mb(900);
+        assert_eq!(watchdog.check(), Some(MemoryPressure::Normal));
+        assert!(cache.stores_paused());
+        assert_eq!(cache.stats().total_entries, 90);
+
+        rss.set_mb(700);
+        assert_eq!(watchdog.check(), Some(MemoryPressure::Normal));
+        assert!(!cache.stores_paused());
+        assert!(metrics.gather().contains("cdn_cache_stores_paused 0"));
+        assert!(
+            metrics
+                .gather()
+                .contains(r#"cdn_memory_sheds_total{level="critical"} 1"#)
+        );
+    }
+
+    #[test]
+    fn test_no_critical_mark_never_pauses() {
+        let rss = FakeRss::default();
+        let (watchdog, cache, _) = watchdog(&rss);
+        let watchdog = MemoryWatchdog {
+            config: MemoryWatchdogConfig {
+                critical_mb: 0,
+                ..watchdog.config.clone()
+            },
+            ..watchdog
+        };
+
+        rss.set_mb(5000);
+        assert_eq!(watchdog.check(), Some(MemoryPressure::High));
+        assert!(!cache.stores_paused());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_proc_statm_reads_rss() {
+        assert!(ProcStatm::new().rss_bytes().is_some_and(|rss| rss > 0));
+        assert_eq!(page_size() % 4096, 0);
+    }
+}
@@ -1,17 +1,190 @@
 use axum::http::StatusCode;
-use prometheus::{CounterVec, Encoder, HistogramOpts, HistogramVec, Opts, Registry, TextEncoder};
-use std::time::Duration;
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures::Stream;
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use prometheus::{
+    Counter, CounterVec, Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, Opts,
+    Registry, TextEncoder,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::debug;
 
-use crate::cache::CacheStatus;
+use crate::cache::{CacheStatus, ContentTypeStats, SweepStats};
+use crate::config::MetricsConfig;
+use crate::observability::{current_trace_id, extract_path_prefix};
+use crate::origin::OriginErrorKind;
+use crate::request_timing::Phase;
 
+/// Content type of the OpenMetrics text exposition format
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Bucket bounds for request duration histograms, in seconds
+pub const REQUEST_DURATION_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Bucket bounds for origin latency histograms, in seconds
+pub const ORIGIN_LATENCY_BUCKETS: [f64; 10] =
+    [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Request phase buckets, down to the sub-millisecond phases of a cache hit
+const REQUEST_PHASE_BUCKETS: [f64; 14] = [
+    0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+/// Cache cleanup sweep tick buckets; a tick should stay in the low milliseconds
+const CACHE_SWEEP_BUCKETS: [f64; 10] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1,
+];
+
+/// Client connection lifetime buckets, from single requests to long-lived
+/// keep-alive connections
+const CONNECTION_DURATION_BUCKETS: [f64; 9] =
+    [0.1, 0.5, 1.0, 5.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// Where a request came from, so dashboards can separate warm traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestSource {
+    /// Live client traffic
+    Client,
+    /// Cache warming via the admin API
+    Warm,
+    /// Node self-test via the admin API
+    SelfTest,
+}
+
+impl RequestSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestSource::Client => "client",
+            RequestSource::Warm => "warm",
+            RequestSource::SelfTest => "selftest",
+        }
+    }
+}
+
+/// A per-request metric update, applied by the recorder task
+#[derive(Debug)]
+enum MetricEvent {
+    Request {
+        origin: String,
+        cache_status: CacheStatus,
+        status: StatusCode,
+        duration: Duration,
+        source: RequestSource,
+        trace_id: Option<String>,
+    },
+    OriginRequest {
+        origin: String,
+        status: StatusCode,
+        source: RequestSource,
+        proxied: bool,
+    },
+    OriginLatency {
+        origin: String,
+        duration: Duration,
+        trace_id: Option<String>,
+    },
+    BytesServed {
+        origin: String,
+        cache_status: CacheStatus,
+        bytes: u64,
+    },
+    Delivery {
+        origin: String,
+        completed: bool,
+    },
+    Phases {
+        origin: String,
+        phases: Vec<(Phase, Duration)>,
+    },
+    PathRequest {
+        origin: String,
+        path: String,
+        status: StatusCode,
+        duration: Duration,
+    },
+    /// Acknowledged once every earlier event has been applied
+    Flush(oneshot::Sender<()>),
+}
+
+/// Prometheus metrics for CDN traffic
+///
+/// Per-request counters are recorded inline until [`Metrics::start_recorder`]
+/// is called; after that they're queued to a background task so request
+/// handling never contends on metric locks. When the queue is full the event
+/// is dropped and counted in `cdn_metric_events_dropped_total`.
 pub struct Metrics {
     registry: Registry,
+    events: OnceLock<mpsc::Sender<MetricEvent>>,
+    events_dropped: Counter,
     requests_total: CounterVec,
     cache_hits: CounterVec,
     cache_misses: CounterVec,
     request_duration: HistogramVec,
+    request_phase_duration: HistogramVec,
     origin_requests: CounterVec,
+    origin_latency: HistogramVec,
     bytes_served: CounterVec,
+    response_deliveries: CounterVec,
+    header_limit_actions: CounterVec,
+    admin_auth_failures: CounterVec,
+    audit_log_failures: CounterVec,
+    range_fetches: CounterVec,
+    unkeyed_headers: CounterVec,
+    personalized_bypasses: CounterVec,
+    origin_errors: CounterVec,
+    injected_faults: CounterVec,
+    origin_validation_failures: CounterVec,
+    stray_not_modified: CounterVec,
+    vary_star_skipped: CounterVec,
+    preconnects: CounterVec,
+    preconnect_duration: HistogramVec,
+    origin_pool_max_idle: GaugeVec,
+    origin_concurrency_watermark: GaugeVec,
+    origin_pool_resizes: CounterVec,
+    purges_saved: CounterVec,
+    destructive_purges: CounterVec,
+    access_logs: CounterVec,
+    device_requests: CounterVec,
+    query_limit_rejections: CounterVec,
+    url_length_rejections: Counter,
+    edge_regex_budget_exceeded: Counter,
+    get_bodies: CounterVec,
+    response_headers_dropped: CounterVec,
+    memory_rss_bytes: Gauge,
+    memory_sheds: CounterVec,
+    cache_stores_paused: Gauge,
+    store_queue: CounterVec,
+    store_queue_depth: Gauge,
+    store_queue_wait: Histogram,
+    revalidations_in_flight: Gauge,
+    revalidations_skipped: Counter,
+    active_connections: Gauge,
+    connections: CounterVec,
+    connection_duration: Histogram,
+    tls_handshake_failures: Counter,
+    cache_content_type_entries: GaugeVec,
+    cache_content_type_bytes: GaugeVec,
+    rate_limit_tracked_clients: Gauge,
+    rate_limit_evictions: Counter,
+    rate_limit_exempt: CounterVec,
+    cache_sweep_duration: Histogram,
+    cache_sweep_items: CounterVec,
+    /// Series in the last scrape, set as each scrape is gathered
+    series: Gauge,
+    /// Trace exemplars for the request and origin latency histograms, when enabled
+    exemplars: Option<Exemplars>,
+    /// Per-path request series, when enabled
+    path_series: Option<PathSeries>,
 }
 
 impl Metrics {
@@ -21,21 +194,21 @@ impl Metrics {
         // Total requests counter
         let requests_total = CounterVec::new(
             Opts::new("cdn_requests_total", "Total number of CDN requests"),
-            &["origin", "status", "cache_status"],
+            &["origin", "status", "cache_status", "source"],
         )
         .unwrap();
 
         // Cache hit counter
         let cache_hits = CounterVec::new(
             Opts::new("cdn_cache_hits_total", "Total cache hits"),
-            &["origin"],
+            &["origin", "source"],
         )
         .unwrap();
 
         // Cache miss counter
         let cache_misses = CounterVec::new(
             Opts::new("cdn_cache_misses_total", "Total cache misses"),
-            &["origin"],
+            &["origin", "source"],
         )
         .unwrap();
 
@@ -45,10 +218,19 @@ impl Metrics {
                 "cdn_request_duration_seconds",
                 "Request duration in seconds",
             )
-            .buckets(vec![
-                0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
-            ]),
-            &["origin", "cache_status"],
+            .buckets(REQUEST_DURATION_BUCKETS.to_vec()),
+            &["origin", "cache_status", "source"],
+        )
+        .unwrap();
+
+        // Where each client request's time went, phase by phase
+        let request_phase_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "cdn_request_phase_seconds",
+                "Time client requests spent in each phase, from arrival to the last body byte",
+            )
+            .buckets(REQUEST_PHASE_BUCKETS.to_vec()),
+            &["origin", "phase"],
         )
         .unwrap();
 
@@ -58,7 +240,18 @@ impl Metrics {
                 "cdn_origin_requests_total",
                 "Total requests to origin servers",
             ),
-            &["origin", "status"],
+            &["origin", "status", "source", "proxied"],
+        )
+        .unwrap();
+
+        // Time each origin fetch attempt took, failed ones included
+        let origin_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "cdn_origin_latency_seconds",
+                "Origin fetch attempt duration in seconds",
+            )
+            .buckets(ORIGIN_LATENCY_BUCKETS.to_vec()),
+            &["origin"],
         )
         .unwrap();
 
@@ -69,6 +262,410 @@ impl Metrics {
         )
         .unwrap();
 
+        // Response bodies fully delivered vs. aborted by the client
+        let response_deliveries = CounterVec::new(
+            Opts::new(
+                "cdn_response_deliveries_total",
+                "Response bodies by delivery outcome",
+            ),
+            &["origin", "outcome"],
+        )
+        .unwrap();
+
+        // Responses whose headers exceeded the configured limits
+        let header_limit_actions = CounterVec::new(
+            Opts::new(
+                "cdn_cache_header_limit_total",
+                "Responses whose headers exceeded cache header limits",
+            ),
+            &["origin", "action"],
+        )
+        .unwrap();
+
+        // Rejected admin API authentication attempts
+        let admin_auth_failures = CounterVec::new(
+            Opts::new(
+                "cdn_admin_auth_failures_total",
+                "Rejected admin API authentication attempts",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+
+        // Audit log entries that couldn't be written to the audit file
+        let audit_log_failures = CounterVec::new(
+            Opts::new(
+                "cdn_audit_log_failures_total",
+                "Admin audit log entries not written to the audit file",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+
+        // Metric events lost because the recorder queue was full
+        let events_dropped = Counter::new(
+            "cdn_metric_events_dropped_total",
+            "Metric events dropped because the recorder queue was full",
+        )
+        .unwrap();
+
+        // Range misses by how they were filled from the origin
+        let range_fetches = CounterVec::new(
+            Opts::new(
+                "cdn_range_fetches_total",
+                "Range request cache misses by origin fetch strategy",
+            ),
+            &["origin", "strategy"],
+        )
+        .unwrap();
+
+        // Dangerous unkeyed request headers seen, by action taken
+        let unkeyed_headers = CounterVec::new(
+            Opts::new(
+                "cdn_unkeyed_headers_total",
+                "Requests carrying dangerous headers that aren't part of the cache key",
+            ),
+            &["origin", "header", "action"],
+        )
+        .unwrap();
+
+        // Requests sent past the cache because they look personalized
+        let personalized_bypasses = CounterVec::new(
+            Opts::new(
+                "cdn_personalized_bypass_total",
+                "Requests that bypassed the cache for carrying credentials, by reason",
+            ),
+            &["origin", "reason"],
+        )
+        .unwrap();
+
+        // Failed origin fetches by kind ("connect", "timeout", "http_5xx", ...)
+        let origin_errors = CounterVec::new(
+            Opts::new("cdn_origin_errors_total", "Origin fetch failures by kind"),
+            &["origin", "kind"],
+        )
+        .unwrap();
+
+        // Origin fetches hit by a fault injection rule, by fault type
+        let injected_faults = CounterVec::new(
+            Opts::new(
+                "cdn_injected_faults_total",
+                "Origin fetches affected by an injected fault",
+            ),
+            &["origin", "fault"],
+        )
+        .unwrap();
+
+        // Origin responses refused by validation, by the check that failed
+        let origin_validation_failures = CounterVec::new(
+            Opts::new(
+                "cdn_origin_validation_failures_total",
+                "Origin responses that failed validation and weren't cached",
+            ),
+            &["origin", "reason"],
+        )
+        .unwrap();
+
+        // Origin 304s to requests without validators, by what was done
+        // ("refetched", "rejected")
+        let stray_not_modified = CounterVec::new(
+            Opts::new(
+                "cdn_origin_stray_not_modified_total",
+                "Origin 304 responses to requests that sent no validators",
+            ),
+            &["origin", "outcome"],
+        )
+        .unwrap();
+
+        // Origin responses not stored because they carry Vary: *
+        let vary_star_skipped = CounterVec::new(
+            Opts::new(
+                "cdn_cache_vary_star_skipped_total",
+                "Origin responses not cached because they vary on every request header",
+            ),
+            &["origin"],
+        )
+        .unwrap();
+
+        // Connections opened ahead of traffic ("success", "failure"), and how
+        // long each took to connect and answer
+        let preconnects = CounterVec::new(
+            Opts::new(
+                "cdn_origin_preconnects_total",
+                "Origin connections opened ahead of traffic",
+            ),
+            &["origin", "result"],
+        )
+        .unwrap();
+        let preconnect_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "cdn_origin_preconnect_duration_seconds",
+                "Time to connect to an origin and get its answer to a preconnect request",
+            )
+            .buckets(REQUEST_DURATION_BUCKETS.to_vec()),
+            &["origin"],
+        )
+        .unwrap();
+
+        // Each origin's idle connection limit and peak concurrent fetches over
+        // the adaptive window, and adaptive resizes ("grow", "shrink")
+        let origin_pool_max_idle = GaugeVec::new(
+            Opts::new(
+                "cdn_origin_pool_max_idle",
+                "Idle connections kept to each origin",
+            ),
+            &["origin"],
+        )
+        .unwrap();
+        let origin_concurrency_watermark = GaugeVec::new(
+            Opts::new(
+                "cdn_origin_concurrency_watermark",
+                "Most concurrent fetches to each origin over the adaptive window",
+            ),
+            &["origin"],
+        )
+        .unwrap();
+        let origin_pool_resizes = CounterVec::new(
+            Opts::new(
+                "cdn_origin_pool_resizes_total",
+                "Origin connection pools rebuilt with a new idle limit",
+            ),
+            &["origin", "direction"],
+        )
+        .unwrap();
+
+        // Purges answered without running their own pass over the cache
+        // ("deduplicated", "batched")
+        let purges_saved = CounterVec::new(
+            Opts::new(
+                "cdn_purge_executions_saved_total",
+                "Purge requests answered from an identical purge or run in a batch",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+
+        // Purge-alls and large prefix purges, by kind ("all", "prefix") and
+        // outcome ("executed", "forced", "cooldown", "forbidden")
+        let destructive_purges = CounterVec::new(
+            Opts::new(
+                "cdn_destructive_purges_total",
+                "Purges emptying all or most of the cache, run or refused",
+            ),
+            &["kind", "outcome"],
+        )
+        .unwrap();
+
+        // Requests rejected for an oversized query, by the limit exceeded
+        let query_limit_rejections = CounterVec::new(
+            Opts::new(
+                "cdn_query_limit_rejections_total",
+                "Requests rejected for exceeding a query string limit",
+            ),
+            &["limit"],
+        )
+        .unwrap();
+
+        // Requests rejected for an over-long URL
+        let url_length_rejections = Counter::new(
+            "cdn_url_length_rejections_total",
+            "Requests rejected for exceeding the maximum URL length",
+        )
+        .unwrap();
+
+        // Requests that ran out of edge rule evaluations
+        let edge_regex_budget_exceeded = Counter::new(
+            "cdn_edge_regex_budget_exceeded_total",
+            "Requests that hit the per-request edge rule evaluation budget",
+        )
+        .unwrap();
+
+        // Bodies sent on GET/HEAD requests, by what was done ("stripped", "rejected")
+        let get_bodies = CounterVec::new(
+            Opts::new(
+                "cdn_get_bodies_total",
+                "GET and HEAD requests that carried a body",
+            ),
+            &["action"],
+        )
+        .unwrap();
+
+        // Origin response headers outside the allowlist, by name and mode; in
+        // passthrough mode these are the headers allowlist mode would drop
+        let response_headers_dropped = CounterVec::new(
+            Opts::new(
+                "cdn_response_headers_dropped_total",
+                "Origin response headers outside the response header allowlist",
+            ),
+            &["header", "mode"],
+        )
+        .unwrap();
+
+        // Access log decisions ("forced", "sampled", "suppressed")
+        let access_logs = CounterVec::new(
+            Opts::new(
+                "cdn_access_logs_total",
+                "Requests by access log sampling decision",
+            ),
+            &["decision"],
+        )
+        .unwrap();
+
+        // Requests by classified device type ("mobile", "tablet", "desktop", "bot")
+        let device_requests = CounterVec::new(
+            Opts::new("cdn_device_requests_total", "Requests by device type"),
+            &["device"],
+        )
+        .unwrap();
+
+        // Memory watchdog: sampled RSS, sheds by level, and whether stores are refused
+        let memory_rss_bytes = Gauge::new(
+            "cdn_memory_rss_bytes",
+            "Process resident set size at the last watchdog sample",
+        )
+        .unwrap();
+        let memory_sheds = CounterVec::new(
+            Opts::new(
+                "cdn_memory_sheds_total",
+                "Emergency cache sheds by memory pressure level",
+            ),
+            &["level"],
+        )
+        .unwrap();
+        let cache_stores_paused = Gauge::new(
+            "cdn_cache_stores_paused",
+            "1 while cache stores are refused under critical memory pressure",
+        )
+        .unwrap();
+
+        // Large-body stores handed to the writer tasks ("queued") or dropped
+        // for a full queue ("dropped"), how many wait, and for how long
+        let store_queue = CounterVec::new(
+            Opts::new(
+                "cdn_cache_store_queue_total",
+                "Large-body cache stores queued for a writer or dropped",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let store_queue_depth = Gauge::new(
+            "cdn_cache_store_queue_depth",
+            "Queued cache stores not yet written",
+        )
+        .unwrap();
+        let store_queue_wait = Histogram::with_opts(
+            HistogramOpts::new(
+                "cdn_cache_store_queue_wait_seconds",
+                "Time a queued cache store waited for a writer",
+            )
+            .buckets(REQUEST_DURATION_BUCKETS.to_vec()),
+        )
+        .unwrap();
+
+        // Background revalidations running, and those skipped at the cap
+        let revalidations_in_flight = Gauge::new(
+            "cdn_cache_revalidations_in_flight",
+            "Background revalidations of stale or early-refreshed entries running",
+        )
+        .unwrap();
+        let revalidations_skipped = Counter::new(
+            "cdn_cache_revalidations_skipped_total",
+            "Background revalidations skipped at cache.max_concurrent_revalidations",
+        )
+        .unwrap();
+
+        // Client connections across every listener
+        let active_connections = Gauge::new(
+            "cdn_active_connections",
+            "Client connections currently open",
+        )
+        .unwrap();
+        let connections = CounterVec::new(
+            Opts::new(
+                "cdn_connections_total",
+                "Client connections by event: accepted, closed, or aborted mid-response",
+            ),
+            &["event"],
+        )
+        .unwrap();
+        let connection_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "cdn_connection_duration_seconds",
+                "How long client connections stayed open",
+            )
+            .buckets(CONNECTION_DURATION_BUCKETS.to_vec()),
+        )
+        .unwrap();
+        let tls_handshake_failures = Counter::new(
+            "cdn_tls_handshake_failures_total",
+            "Client connections dropped because the TLS handshake failed or timed out",
+        )
+        .unwrap();
+
+        // Cache contents by normalized content type, set from the cache at scrape time
+        let cache_content_type_entries = GaugeVec::new(
+            Opts::new(
+                "cdn_cache_content_type_entries",
+                "Cached entries by content type",
+            ),
+            &["content_type"],
+        )
+        .unwrap();
+        let cache_content_type_bytes = GaugeVec::new(
+            Opts::new(
+                "cdn_cache_content_type_bytes",
+                "Cached bytes by content type",
+            ),
+            &["content_type"],
+        )
+        .unwrap();
+
+        // Rate limiter state: clients tracked, and those evicted at the cap
+        let rate_limit_tracked_clients = Gauge::new(
+            "cdn_rate_limit_tracked_clients",
+            "Clients with rate limiter state",
+        )
+        .unwrap();
+        let rate_limit_evictions = Counter::new(
+            "cdn_rate_limit_evictions_total",
+            "Clients evicted because the rate limiter reached max_tracked_clients",
+        )
+        .unwrap();
+        let rate_limit_exempt = CounterVec::new(
+            Opts::new(
+                "cdn_rate_limit_exempt_total",
+                "Requests let through without touching the rate limiter, by reason",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+
+        // Incremental cache cleanup: time per tick, and what each tick examined
+        // and removed by index ("entries", "tags") and result ("examined", "removed")
+        let cache_sweep_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "cdn_cache_sweep_duration_seconds",
+                "Time taken by each cache cleanup sweep tick",
+            )
+            .buckets(CACHE_SWEEP_BUCKETS.to_vec()),
+        )
+        .unwrap();
+        let cache_sweep_items = CounterVec::new(
+            Opts::new(
+                "cdn_cache_sweep_items_total",
+                "Cache entries and tags examined and removed by the cleanup sweep",
+            ),
+            &["index", "result"],
+        )
+        .unwrap();
+
+        // Series exposed per scrape, to alert on cardinality before Prometheus does
+        let series = Gauge::new(
+            SERIES_METRIC,
+            "Time series exposed by this endpoint, counting each histogram bucket",
+        )
+        .unwrap();
+
         // Register all metrics
         registry.register(Box::new(requests_total.clone())).unwrap();
         registry.register(Box::new(cache_hits.clone())).unwrap();
@@ -76,69 +673,1249 @@ impl Metrics {
         registry
             .register(Box::new(request_duration.clone()))
             .unwrap();
+        registry
+            .register(Box::new(request_phase_duration.clone()))
+            .unwrap();
         registry
             .register(Box::new(origin_requests.clone()))
             .unwrap();
+        registry.register(Box::new(origin_latency.clone())).unwrap();
         registry.register(Box::new(bytes_served.clone())).unwrap();
+        registry
+            .register(Box::new(response_deliveries.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(header_limit_actions.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(admin_auth_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(audit_log_failures.clone()))
+            .unwrap();
+        registry.register(Box::new(events_dropped.clone())).unwrap();
+        registry.register(Box::new(range_fetches.clone())).unwrap();
+        registry
+            .register(Box::new(unkeyed_headers.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(personalized_bypasses.clone()))
+            .unwrap();
+        registry.register(Box::new(origin_errors.clone())).unwrap();
+        registry
+            .register(Box::new(injected_faults.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(origin_validation_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(stray_not_modified.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(vary_star_skipped.clone()))
+            .unwrap();
+        registry.register(Box::new(preconnects.clone())).unwrap();
+        registry
+            .register(Box::new(preconnect_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(origin_pool_max_idle.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(origin_concurrency_watermark.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(origin_pool_resizes.clone()))
+            .unwrap();
+        registry.register(Box::new(purges_saved.clone())).unwrap();
+        registry
+            .register(Box::new(destructive_purges.clone()))
+            .unwrap();
+        registry.register(Box::new(access_logs.clone())).unwrap();
+        registry
+            .register(Box::new(device_requests.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(query_limit_rejections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(url_length_rejections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(edge_regex_budget_exceeded.clone()))
+            .unwrap();
+        registry.register(Box::new(get_bodies.clone())).unwrap();
+        registry
+            .register(Box::new(response_headers_dropped.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(memory_rss_bytes.clone()))
+            .unwrap();
+        registry.register(Box::new(memory_sheds.clone())).unwrap();
+        registry
+            .register(Box::new(cache_stores_paused.clone()))
+            .unwrap();
+        registry.register(Box::new(store_queue.clone())).unwrap();
+        registry
+            .register(Box::new(store_queue_depth.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(store_queue_wait.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(revalidations_in_flight.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(revalidations_skipped.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(active_connections.clone()))
+            .unwrap();
+        registry.register(Box::new(connections.clone())).unwrap();
+        registry
+            .register(Box::new(connection_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(tls_handshake_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(cache_content_type_entries.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(cache_content_type_bytes.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(rate_limit_tracked_clients.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(rate_limit_evictions.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(rate_limit_exempt.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(cache_sweep_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(cache_sweep_items.clone()))
+            .unwrap();
+        registry.register(Box::new(series.clone())).unwrap();
+
+        Self {
+            registry,
+            events: OnceLock::new(),
+            events_dropped,
+            requests_total,
+            cache_hits,
+            cache_misses,
+            request_duration,
+            request_phase_duration,
+            origin_requests,
+            origin_latency,
+            bytes_served,
+            response_deliveries,
+            header_limit_actions,
+            admin_auth_failures,
+            audit_log_failures,
+            range_fetches,
+            unkeyed_headers,
+            personalized_bypasses,
+            origin_errors,
+            injected_faults,
+            origin_validation_failures,
+            stray_not_modified,
+            vary_star_skipped,
+            preconnects,
+            preconnect_duration,
+            origin_pool_max_idle,
+            origin_concurrency_watermark,
+            origin_pool_resizes,
+            purges_saved,
+            destructive_purges,
+            access_logs,
+            device_requests,
+            query_limit_rejections,
+            url_length_rejections,
+            edge_regex_budget_exceeded,
+            get_bodies,
+            response_headers_dropped,
+            memory_rss_bytes,
+            memory_sheds,
+            cache_stores_paused,
+            store_queue,
+            store_queue_depth,
+            store_queue_wait,
+            revalidations_in_flight,
+            revalidations_skipped,
+            active_connections,
+            connections,
+            connection_duration,
+            tls_handshake_failures,
+            cache_content_type_entries,
+            cache_content_type_bytes,
+            rate_limit_tracked_clients,
+            rate_limit_evictions,
+            rate_limit_exempt,
+            cache_sweep_duration,
+            cache_sweep_items,
+            series,
+            exemplars: None,
+            path_series: None,
+        }
+    }
+
+    /// Attach the active trace ID to request and origin latency observations as exemplars
+    ///
+    /// Exemplars only appear in [`Metrics::gather_openmetrics`] output.
+    pub fn with_exemplars(mut self, enabled: bool) -> Self {
+        self.exemplars = enabled.then(Exemplars::default);
+        self
+    }
+
+    /// Record client requests by path prefix as well, if `per_path_metrics` is set
+    ///
+    /// At most `max_path_prefixes` prefixes get their own series; see
+    /// [`PathPrefixLabels`] for how the rest are counted.
+    pub fn with_path_series(mut self, config: &MetricsConfig) -> Self {
+        if !config.per_path_metrics {
+            return self;
+        }
+        let series = PathSeries::new(
+            config.max_path_prefixes,
+            Duration::from_secs(config.path_prefix_idle_secs),
+        );
+        self.registry
+            .register(Box::new(series.requests.clone()))
+            .unwrap();
+        self.registry
+            .register(Box::new(series.duration.clone()))
+            .unwrap();
+        self.path_series = Some(series);
+        self
+    }
+
+    /// Move per-request recording onto a background task with a bounded queue
+    ///
+    /// Has no effect if a recorder is already running.
+    pub fn start_recorder(self: &Arc<Self>, capacity: usize) -> Option<JoinHandle<()>> {
+        let mut receiver = self.attach_queue(capacity)?;
+        let metrics = Arc::clone(self);
+        Some(tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                metrics.apply(event);
+            }
+        }))
+    }
+
+    fn attach_queue(&self, capacity: usize) -> Option<mpsc::Receiver<MetricEvent>> {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        self.events.set(sender).ok()?;
+        Some(receiver)
+    }
+
+    /// Wait until every event queued so far has been applied
+    pub async fn flush(&self) {
+        let Some(sender) = self.events.get() else {
+            return;
+        };
+
+        let (ack, done) = oneshot::channel();
+        if sender.send(MetricEvent::Flush(ack)).await.is_ok() {
+            let _ = done.await;
+        }
+    }
+
+    /// Queue an event without waiting, or apply it inline if no recorder runs
+    fn emit(&self, event: MetricEvent) {
+        let Some(sender) = self.events.get() else {
+            self.apply(event);
+            return;
+        };
+
+        match sender.try_send(event) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.events_dropped.inc();
+                debug!("Metric event queue full, dropping event");
+            }
+            Err(mpsc::error::TrySendError::Closed(event)) => self.apply(event),
+        }
+    }
+
+    fn apply(&self, event: MetricEvent) {
+        match event {
+            MetricEvent::Request {
+                origin,
+                cache_status,
+                status,
+                duration,
+                source,
+                trace_id,
+            } => {
+                self.apply_request(&origin, cache_status, status, duration, source);
+                if let (Some(exemplars), Some(trace_id)) = (&self.exemplars, trace_id) {
+                    exemplars.observe(
+                        "cdn_request_duration_seconds",
+                        &[
+                            ("origin", &origin),
+                            ("cache_status", cache_status.as_str()),
+                            ("source", source.as_str()),
+                        ],
+                        &REQUEST_DURATION_BUCKETS,
+                        duration.as_secs_f64(),
+                        trace_id,
+                    );
+                }
+            }
+            MetricEvent::OriginRequest {
+                origin,
+                status,
+                source,
+                proxied,
+            } => {
+                let status = status.as_u16().to_string();
+                let proxied = if proxied { "true" } else { "false" };
+                self.origin_requests
+                    .with_label_values(&[&origin, &status, source.as_str(), proxied])
+                    .inc();
+            }
+            MetricEvent::OriginLatency {
+                origin,
+                duration,
+                trace_id,
+            } => {
+                self.origin_latency
+                    .with_label_values(&[&origin])
+                    .observe(duration.as_secs_f64());
+                if let (Some(exemplars), Some(trace_id)) = (&self.exemplars, trace_id) {
+                    exemplars.observe(
+                        "cdn_origin_latency_seconds",
+                        &[("origin", &origin)],
+                        &ORIGIN_LATENCY_BUCKETS,
+                        duration.as_secs_f64(),
+                        trace_id,
+                    );
+                }
+            }
+            MetricEvent::BytesServed {
+                origin,
+                cache_status,
+                bytes,
+            } => {
+                self.bytes_served
+                    .with_label_values(&[&origin, cache_status.as_str()])
+                    .inc_by(bytes as f64);
+            }
+            MetricEvent::Delivery { origin, completed } => {
+                let outcome = if completed { "complete" } else { "aborted" };
+                self.response_deliveries
+                    .with_label_values(&[&origin, outcome])
+                    .inc();
+            }
+            MetricEvent::Phases { origin, phases } => {
+                for (phase, duration) in phases {
+                    self.request_phase_duration
+                        .with_label_values(&[&origin, phase.as_str()])
+                        .observe(duration.as_secs_f64());
+                }
+            }
+            MetricEvent::PathRequest {
+                origin,
+                path,
+                status,
+                duration,
+            } => {
+                if let Some(series) = &self.path_series {
+                    series.record(&origin, &path, status, duration);
+                }
+            }
+            MetricEvent::Flush(ack) => {
+                let _ = ack.send(());
+            }
+        }
+    }
+
+    fn apply_request(
+        &self,
+        origin: &str,
+        cache_status: CacheStatus,
+        status: StatusCode,
+        duration: Duration,
+        source: RequestSource,
+    ) {
+        let status_str = status.as_u16().to_string();
+        let cache_str = cache_status.as_str();
+        let source_str = source.as_str();
+
+        self.requests_total
+            .with_label_values(&[origin, &status_str, cache_str, source_str])
+            .inc();
 
-        Self {
-            registry,
-            requests_total,
-            cache_hits,
-            cache_misses,
-            request_duration,
-            origin_requests,
-            bytes_served,
-        }
-    }
-
-    pub fn record_request(
-        &self,
-        origin: &str,
-        cache_status: CacheStatus,
-        status: StatusCode,
-        duration: Duration,
-    ) {
-        let status_str = status.as_u16().to_string();
-        let cache_str = cache_status.as_str();
-
-        self.requests_total
-            .with_label_values(&[origin, &status_str, cache_str])
-            .inc();
-
         self.request_duration
-            .with_label_values(&[origin, cache_str])
+            .with_label_values(&[origin, cache_str, source_str])
             .observe(duration.as_secs_f64());
 
         match cache_status {
-            CacheStatus::Hit | CacheStatus::Stale | CacheStatus::StaleIfError => {
-                self.cache_hits.with_label_values(&[origin]).inc();
+            CacheStatus::Hit
+            | CacheStatus::Stale
+            | CacheStatus::StaleAdaptive
+            | CacheStatus::StaleIfError => {
+                self.cache_hits.with_label_values(&[origin, source_str]).inc();
             }
             CacheStatus::Miss | CacheStatus::Bypass => {
-                self.cache_misses.with_label_values(&[origin]).inc();
+                self.cache_misses
+                    .with_label_values(&[origin, source_str])
+                    .inc();
             }
         }
     }
 
-    pub fn record_origin_request(&self, origin: &str, status: StatusCode) {
-        self.origin_requests
-            .with_label_values(&[origin, &status.as_u16().to_string()])
-            .inc();
+    pub fn record_request(
+        &self,
+        origin: &str,
+        cache_status: CacheStatus,
+        status: StatusCode,
+        duration: Duration,
+        source: RequestSource,
+    ) {
+        self.emit(MetricEvent::Request {
+            origin: origin.to_string(),
+            cache_status,
+            status,
+            duration,
+            source,
+            trace_id: self.exemplars.as_ref().and_then(|_| current_trace_id()),
+        });
+    }
+
+    /// Count a client request under its path prefix, if per-path series are on
+    pub fn record_path_request(
+        &self,
+        origin: &str,
+        path: &str,
+        status: StatusCode,
+        duration: Duration,
+    ) {
+        if self.path_series.is_none() {
+            return;
+        }
+        self.emit(MetricEvent::PathRequest {
+            origin: origin.to_string(),
+            path: path.to_string(),
+            status,
+            duration,
+        });
+    }
+
+    pub fn record_origin_request(
+        &self,
+        origin: &str,
+        status: StatusCode,
+        source: RequestSource,
+        proxied: bool,
+    ) {
+        self.emit(MetricEvent::OriginRequest {
+            origin: origin.to_string(),
+            status,
+            source,
+            proxied,
+        });
+    }
+
+    /// Time one origin fetch attempt took
+    pub fn record_origin_latency(&self, origin: &str, duration: Duration) {
+        self.emit(MetricEvent::OriginLatency {
+            origin: origin.to_string(),
+            duration,
+            trace_id: self.exemplars.as_ref().and_then(|_| current_trace_id()),
+        });
     }
 
     pub fn record_bytes_served(&self, origin: &str, cache_status: CacheStatus, bytes: u64) {
-        self.bytes_served
-            .with_label_values(&[origin, cache_status.as_str()])
-            .inc_by(bytes as f64);
+        self.emit(MetricEvent::BytesServed {
+            origin: origin.to_string(),
+            cache_status,
+            bytes,
+        });
+    }
+
+    /// Count a finished response body ("complete" or "aborted")
+    pub fn record_delivery(&self, origin: &str, completed: bool) {
+        self.emit(MetricEvent::Delivery {
+            origin: origin.to_string(),
+            completed,
+        });
+    }
+
+    /// Observe the phases a client request went through
+    pub fn record_request_phases(&self, origin: &str, phases: Vec<(Phase, Duration)>) {
+        self.emit(MetricEvent::Phases {
+            origin: origin.to_string(),
+            phases,
+        });
+    }
+
+    /// Count a header limit enforcement ("truncated" or "uncacheable")
+    pub fn record_header_limit(&self, origin: &str, action: &str) {
+        self.header_limit_actions
+            .with_label_values(&[origin, action])
+            .inc();
+    }
+
+    /// Count how a Range miss was filled: "full_fetch" or "passthrough"
+    pub fn record_range_fetch(&self, origin: &str, strategy: &str) {
+        self.range_fetches
+            .with_label_values(&[origin, strategy])
+            .inc();
+    }
+
+    /// Count a dangerous unkeyed request header and the action taken ("strip", "bypass")
+    pub fn record_unkeyed_header(&self, origin: &str, header: &str, action: &str) {
+        self.unkeyed_headers
+            .with_label_values(&[origin, header, action])
+            .inc();
+    }
+
+    /// Count a request that bypassed the cache as personalized ("authorization", "cookie")
+    pub fn record_personalized_bypass(&self, origin: &str, reason: &str) {
+        self.personalized_bypasses
+            .with_label_values(&[origin, reason])
+            .inc();
+    }
+
+    /// Count a failed origin fetch by kind
+    pub fn record_origin_error(&self, origin: &str, kind: OriginErrorKind) {
+        self.origin_errors
+            .with_label_values(&[origin, kind.as_str()])
+            .inc();
+    }
+
+    /// Count an origin fetch affected by an injected fault ("delay", "error", ...)
+    pub fn record_injected_fault(&self, origin: &str, fault: &str) {
+        self.injected_faults
+            .with_label_values(&[origin, fault])
+            .inc();
+    }
+
+    /// Count an origin response refused by validation ("content_length", "digest", ...)
+    pub fn record_origin_validation_failure(&self, origin: &str, reason: &str) {
+        self.origin_validation_failures
+            .with_label_values(&[origin, reason])
+            .inc();
+    }
+
+    /// Count an origin 304 to an unconditional request ("refetched", "rejected")
+    pub fn record_stray_not_modified(&self, origin: &str, outcome: &str) {
+        self.stray_not_modified
+            .with_label_values(&[origin, outcome])
+            .inc();
+    }
+
+    /// Count an origin response not cached for its `Vary: *`
+    pub fn record_vary_star_skipped(&self, origin: &str) {
+        self.vary_star_skipped.with_label_values(&[origin]).inc();
+    }
+
+    /// Record a preconnect attempt; only successful ones are timed
+    pub fn record_preconnect(&self, origin: &str, success: bool, duration: Duration) {
+        let result = if success { "success" } else { "failure" };
+        self.preconnects.with_label_values(&[origin, result]).inc();
+        if success {
+            self.preconnect_duration
+                .with_label_values(&[origin])
+                .observe(duration.as_secs_f64());
+        }
+    }
+
+    /// Set an origin's idle connection limit and concurrency watermark
+    pub fn set_origin_pool(&self, origin: &str, max_idle: usize, watermark: usize) {
+        self.origin_pool_max_idle
+            .with_label_values(&[origin])
+            .set(max_idle as f64);
+        self.origin_concurrency_watermark
+            .with_label_values(&[origin])
+            .set(watermark as f64);
+    }
+
+    /// Count an adaptive pool rebuilt larger ("grow") or smaller ("shrink")
+    pub fn record_origin_pool_resize(&self, origin: &str, direction: &str) {
+        self.origin_pool_resizes
+            .with_label_values(&[origin, direction])
+            .inc();
+    }
+
+    /// Count a purge that didn't need its own execution ("deduplicated", "batched")
+    pub fn record_purge_saved(&self, reason: &str) {
+        self.purges_saved.with_label_values(&[reason]).inc();
+    }
+
+    /// Count a destructive purge run or refused
+    pub fn record_destructive_purge(&self, kind: &str, outcome: &str) {
+        self.destructive_purges
+            .with_label_values(&[kind, outcome])
+            .inc();
+    }
+
+    /// Count an access log sampling decision ("forced", "sampled", "suppressed")
+    pub fn record_access_log(&self, decision: &str) {
+        self.access_logs.with_label_values(&[decision]).inc();
+    }
+
+    /// Count a request rejected by a query limit ("params", "length", "value_length")
+    pub fn record_query_limit_rejection(&self, limit: &str) {
+        self.query_limit_rejections
+            .with_label_values(&[limit])
+            .inc();
+    }
+
+    /// Count a request rejected for an over-long URL
+    pub fn record_url_length_rejection(&self) {
+        self.url_length_rejections.inc();
+    }
+
+    /// Count a request that skipped edge rules after exhausting its evaluation budget
+    pub fn record_edge_regex_budget_exceeded(&self) {
+        self.edge_regex_budget_exceeded.inc();
+    }
+
+    /// Count a GET/HEAD request that carried a body ("stripped" or "rejected")
+    pub fn record_get_body(&self, action: &str) {
+        self.get_bodies.with_label_values(&[action]).inc();
+    }
+
+    /// Count an origin response header outside the allowlist
+    pub fn record_response_header_dropped(&self, header: &str, mode: &str) {
+        self.response_headers_dropped
+            .with_label_values(&[header, mode])
+            .inc();
+    }
+
+    /// Count a request by device type; the "bot" share is crawler traffic
+    pub fn record_device_type(&self, device: &str) {
+        self.device_requests.with_label_values(&[device]).inc();
+    }
+
+    /// Record a memory watchdog RSS sample
+    pub fn set_memory_rss(&self, bytes: u64) {
+        self.memory_rss_bytes.set(bytes as f64);
+    }
+
+    /// Count an emergency cache shed ("high", "critical")
+    pub fn record_memory_shed(&self, level: &str) {
+        self.memory_sheds.with_label_values(&[level]).inc();
+    }
+
+    pub fn set_cache_stores_paused(&self, paused: bool) {
+        self.cache_stores_paused.set(if paused { 1.0 } else { 0.0 });
+    }
+
+    /// Count a large-body store handed to the queue ("queued", "dropped")
+    pub fn record_store_queue(&self, result: &str) {
+        self.store_queue.with_label_values(&[result]).inc();
+    }
+
+    pub fn set_store_queue_depth(&self, depth: usize) {
+        self.store_queue_depth.set(depth as f64);
+    }
+
+    /// Record how long a queued store waited for a writer
+    pub fn record_store_queue_wait(&self, wait: Duration) {
+        self.store_queue_wait.observe(wait.as_secs_f64());
+    }
+
+    pub fn set_revalidations_in_flight(&self, in_flight: usize) {
+        self.revalidations_in_flight.set(in_flight as f64);
+    }
+
+    pub fn record_revalidation_skipped(&self) {
+        self.revalidations_skipped.inc();
+    }
+
+    pub fn record_connection_accepted(&self, open: usize) {
+        self.connections.with_label_values(&["accepted"]).inc();
+        self.active_connections.set(open as f64);
+    }
+
+    /// Record a connection ending, `aborted` when a response was still being
+    /// sent on it
+    pub fn record_connection_closed(&self, open: usize, lifetime: Duration, aborted: bool) {
+        let event = if aborted { "aborted" } else { "closed" };
+        self.connections.with_label_values(&[event]).inc();
+        self.active_connections.set(open as f64);
+        self.connection_duration.observe(lifetime.as_secs_f64());
+    }
+
+    pub fn record_tls_handshake_failure(&self) {
+        self.tls_handshake_failures.inc();
+    }
+
+    /// Replace the per-content-type cache gauges, dropping types no longer cached
+    pub fn set_cache_content_types(&self, by_content_type: &BTreeMap<String, ContentTypeStats>) {
+        self.cache_content_type_entries.reset();
+        self.cache_content_type_bytes.reset();
+        for (content_type, stats) in by_content_type {
+            self.cache_content_type_entries
+                .with_label_values(&[content_type])
+                .set(stats.entry_count as f64);
+            self.cache_content_type_bytes
+                .with_label_values(&[content_type])
+                .set(stats.total_size_bytes as f64);
+        }
+    }
+
+    pub fn set_rate_limit_tracked_clients(&self, clients: usize) {
+        self.rate_limit_tracked_clients.set(clients as f64);
+    }
+
+    /// Count clients evicted from a full rate limiter
+    pub fn record_rate_limit_evictions(&self, evicted: usize) {
+        self.rate_limit_evictions.inc_by(evicted as f64);
+    }
+
+    /// Count a request the rate limiter let through unchecked
+    pub fn record_rate_limit_exempt(&self, reason: &str) {
+        self.rate_limit_exempt.with_label_values(&[reason]).inc();
+    }
+
+    /// Record one tick of the cache cleanup sweep
+    pub fn record_cache_sweep(&self, stats: &SweepStats, duration: Duration) {
+        self.cache_sweep_duration.observe(duration.as_secs_f64());
+        for (index, result, count) in [
+            ("entries", "examined", stats.entries_examined),
+            ("entries", "removed", stats.entries_removed),
+            ("tags", "examined", stats.tags_examined),
+            ("tags", "removed", stats.tag_keys_removed),
+        ] {
+            self.cache_sweep_items
+                .with_label_values(&[index, result])
+                .inc_by(count as f64);
+        }
+    }
+
+    /// Count a rejected admin authentication ("invalid_token", "locked_out", ...)
+    pub fn record_admin_auth_failure(&self, reason: &str) {
+        self.admin_auth_failures.with_label_values(&[reason]).inc();
+    }
+
+    /// Count an audit entry that didn't reach the audit file ("queue_full", "write")
+    pub fn record_audit_log_failure(&self, reason: &str) {
+        self.audit_log_failures.with_label_values(&[reason]).inc();
     }
 
     pub fn gather(&self) -> String {
         let encoder = TextEncoder::new();
-        let metric_families = self.registry.gather();
+        let metric_families = gather_counted(&self.registry, &self.series);
         let mut buffer = Vec::new();
         encoder.encode(&metric_families, &mut buffer).unwrap();
         String::from_utf8(buffer).unwrap_or_default()
     }
+
+    /// Metrics in the OpenMetrics format, with exemplars if enabled
+    pub fn gather_openmetrics(&self) -> String {
+        encode_openmetrics(
+            &gather_counted(&self.registry, &self.series),
+            self.exemplars.as_ref(),
+        )
+    }
+
+    /// [`Metrics::gather`] or [`Metrics::gather_openmetrics`], encoded one
+    /// metric family per chunk so the whole exposition is never held at once
+    pub fn gather_stream(
+        self: &Arc<Self>,
+        openmetrics: bool,
+    ) -> impl Stream<Item = Result<Bytes, Infallible>> + Send + 'static {
+        let metrics = self.clone();
+        let families = gather_counted(&self.registry, &self.series);
+        let eof = openmetrics.then_some(Bytes::from_static(b"# EOF\n"));
+        futures::stream::iter(
+            families
+                .into_iter()
+                .map(move |family| {
+                    if openmetrics {
+                        let mut out = String::new();
+                        encode_openmetrics_family(&mut out, &family, metrics.exemplars.as_ref());
+                        Bytes::from(out)
+                    } else {
+                        let mut buffer = Vec::new();
+                        TextEncoder::new()
+                            .encode(std::slice::from_ref(&family), &mut buffer)
+                            .unwrap();
+                        Bytes::from(buffer)
+                    }
+                })
+                .chain(eof)
+                .map(Ok),
+        )
+    }
+}
+
+/// Per-request series labelled by path prefix, see [`Metrics::with_path_series`]
+struct PathSeries {
+    requests: CounterVec,
+    duration: HistogramVec,
+    prefixes: PathPrefixLabels,
+}
+
+impl PathSeries {
+    fn new(max_prefixes: usize, idle: Duration) -> Self {
+        Self {
+            requests: CounterVec::new(
+                Opts::new(
+                    "cdn_requests_by_path_total",
+                    "Client requests by origin, path prefix and status",
+                ),
+                &["origin", "path_prefix", "status"],
+            )
+            .unwrap(),
+            duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "cdn_request_duration_by_path_seconds",
+                    "Client request duration by origin and path prefix",
+                )
+                .buckets(REQUEST_DURATION_BUCKETS.to_vec()),
+                &["origin", "path_prefix"],
+            )
+            .unwrap(),
+            prefixes: PathPrefixLabels::new(max_prefixes, idle),
+        }
+    }
+
+    fn record(&self, origin: &str, path: &str, status: StatusCode, duration: Duration) {
+        let status = status.as_u16().to_string();
+        let label = self
+            .prefixes
+            .label(extract_path_prefix(path), origin, &status, Instant::now());
+        if let Some(evicted) = &label.evicted {
+            self.remove(evicted);
+        }
+
+        self.requests
+            .with_label_values(&[origin, &label.value, &status])
+            .inc();
+        self.duration
+            .with_label_values(&[origin, &label.value])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Drop the series of a prefix that gave up its label
+    fn remove(&self, evicted: &EvictedPrefix) {
+        let mut origins = HashSet::new();
+        for (origin, status) in &evicted.series {
+            let _ = self
+                .requests
+                .remove_label_values(&[origin, &evicted.prefix, status]);
+            origins.insert(origin);
+        }
+        for origin in origins {
+            let _ = self
+                .duration
+                .remove_label_values(&[origin, &evicted.prefix]);
+        }
+        debug!(prefix = %evicted.prefix, "Dropped series of idle path prefix");
+    }
+}
+
+/// Label value for requests whose path prefix has no series of its own
+pub const OTHER_PATH_PREFIX: &str = "other";
+
+/// Caps the distinct `path_prefix` label values on the per-path series
+///
+/// Up to `max` prefixes keep their own series. A new prefix takes the slot
+/// of the least recently used one once that has gone unused for `idle`,
+/// and the old prefix's series are dropped; otherwise it's counted under
+/// [`OTHER_PATH_PREFIX`]. Waiting out `idle` keeps a steady stream of new
+/// prefixes from churning the series of ones still in use.
+struct PathPrefixLabels {
+    max: usize,
+    idle: Duration,
+    state: std::sync::Mutex<PrefixSlots>,
+}
+
+#[derive(Default)]
+struct PrefixSlots {
+    next_tick: u64,
+    prefixes: HashMap<String, PrefixSlot>,
+    /// Prefixes by the tick of their last use, least recent first
+    by_use: BTreeMap<u64, String>,
+}
+
+struct PrefixSlot {
+    tick: u64,
+    last_used: Instant,
+    /// (origin, status) pairs the prefix has series for
+    series: HashSet<(String, String)>,
+}
+
+/// A prefix that gave up its label, with the series to drop
+struct EvictedPrefix {
+    prefix: String,
+    series: HashSet<(String, String)>,
+}
+
+struct PrefixLabel {
+    value: String,
+    evicted: Option<EvictedPrefix>,
+}
+
+impl PathPrefixLabels {
+    fn new(max: usize, idle: Duration) -> Self {
+        Self {
+            max,
+            idle,
+            state: std::sync::Mutex::new(PrefixSlots::default()),
+        }
+    }
+
+    /// The label to record a request under `prefix` with
+    fn label(&self, prefix: String, origin: &str, status: &str, now: Instant) -> PrefixLabel {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let tick = state.next_tick;
+        state.next_tick += 1;
+
+        let mut evicted = None;
+        if !state.prefixes.contains_key(&prefix) {
+            if state.prefixes.len() >= self.max {
+                let Some((_, oldest)) = state.by_use.first_key_value() else {
+                    return PrefixLabel::other();
+                };
+                let oldest = oldest.clone();
+                if now.saturating_duration_since(state.prefixes[&oldest].last_used) < self.idle {
+                    return PrefixLabel::other();
+                }
+                let slot = state.prefixes.remove(&oldest).expect("indexed prefix");
+                state.by_use.remove(&slot.tick);
+                evicted = Some(EvictedPrefix {
+                    prefix: oldest,
+                    series: slot.series,
+                });
+            }
+            state.prefixes.insert(
+                prefix.clone(),
+                PrefixSlot {
+                    tick,
+                    last_used: now,
+                    series: HashSet::new(),
+                },
+            );
+        }
+
+        let slot = state.prefixes.get_mut(&prefix).expect("inserted above");
+        let previous = std::mem::replace(&mut slot.tick, tick);
+        slot.last_used = now;
+        if !slot.series.iter().any(|(o, s)| o == origin && s == status) {
+            slot.series.insert((origin.to_string(), status.to_string()));
+        }
+        state.by_use.remove(&previous);
+        state.by_use.insert(tick, prefix.clone());
+
+        PrefixLabel {
+            value: prefix,
+            evicted,
+        }
+    }
+}
+
+impl PrefixLabel {
+    fn other() -> Self {
+        Self {
+            value: OTHER_PATH_PREFIX.to_string(),
+            evicted: None,
+        }
+    }
+}
+
+/// Name of the gauge counting the series a registry exposes
+pub const SERIES_METRIC: &str = "cdn_metrics_series";
+
+/// Gather a registry, setting `series` to the number of series gathered
+///
+/// The gauge's own sample is patched in the gathered families, so a scrape
+/// reports its own series count rather than the previous one's.
+pub(crate) fn gather_counted(registry: &Registry, series: &Gauge) -> Vec<MetricFamily> {
+    let mut families = registry.gather();
+    let count = count_series(&families);
+    series.set(count as f64);
+    let name = prometheus::core::Collector::desc(series)[0].fq_name.clone();
+    for family in families.iter_mut().filter(|family| family.name() == name) {
+        for metric in family.mut_metric() {
+            let mut gauge = prometheus::proto::Gauge::default();
+            gauge.set_value(count as f64);
+            metric.set_gauge(gauge);
+        }
+    }
+    families
+}
+
+/// Series the text formats write for these families; a histogram writes
+/// one per bucket, `+Inf` included, plus its sum and count
+pub fn count_series(families: &[MetricFamily]) -> usize {
+    families
+        .iter()
+        .flat_map(|family| {
+            let metric_type = family.get_field_type();
+            family.get_metric().iter().map(move |m| match metric_type {
+                MetricType::HISTOGRAM => {
+                    let finite = m
+                        .get_histogram()
+                        .get_bucket()
+                        .iter()
+                        .filter(|b| b.upper_bound().is_finite())
+                        .count();
+                    finite + 3
+                }
+                MetricType::SUMMARY => m.get_summary().get_quantile().len() + 2,
+                _ => 1,
+            })
+        })
+        .sum()
+}
+
+/// A trace behind one histogram observation, exposed as an OpenMetrics exemplar
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub value: f64,
+    /// Seconds since the Unix epoch
+    pub timestamp: f64,
+}
+
+/// The latest exemplar for each bucket of each histogram series
+#[derive(Debug, Default)]
+pub struct Exemplars {
+    /// One slot per bucket, `+Inf` last
+    series: DashMap<SeriesKey, Vec<Option<Exemplar>>>,
+}
+
+impl Exemplars {
+    /// Keep `trace_id` as the example for the bucket `value` falls in
+    pub fn observe(
+        &self,
+        metric: &str,
+        labels: &[(&str, &str)],
+        buckets: &[f64],
+        value: f64,
+        trace_id: String,
+    ) {
+        let key = series_key(
+            metric,
+            labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        );
+        let bucket = buckets
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(buckets.len());
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+
+        let mut slots = self
+            .series
+            .entry(key)
+            .or_insert_with(|| vec![None; buckets.len() + 1]);
+        if let Some(slot) = slots.get_mut(bucket) {
+            *slot = Some(Exemplar {
+                trace_id,
+                value,
+                timestamp,
+            });
+        }
+    }
+
+    fn for_series(&self, metric: &str, labels: &[LabelPair]) -> Vec<Option<Exemplar>> {
+        let key = series_key(
+            metric,
+            labels
+                .iter()
+                .map(|lp| (lp.name().to_string(), lp.value().to_string())),
+        );
+        self.series
+            .get(&key)
+            .map(|slots| slots.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Metric name and labels sorted by name
+type SeriesKey = (String, Vec<(String, String)>);
+
+fn series_key(metric: &str, labels: impl Iterator<Item = (String, String)>) -> SeriesKey {
+    let mut labels: Vec<_> = labels.collect();
+    labels.sort();
+    (metric.to_string(), labels)
+}
+
+/// Encode metric families in the OpenMetrics text format
+///
+/// Histogram buckets carry the matching exemplar, if any. Counter families are
+/// named without their `_total` suffix, as OpenMetrics requires.
+pub fn encode_openmetrics(families: &[MetricFamily], exemplars: Option<&Exemplars>) -> String {
+    let mut out = String::new();
+    for mf in families {
+        encode_openmetrics_family(&mut out, mf, exemplars);
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Append one metric family in the OpenMetrics text format, without `# EOF`
+fn encode_openmetrics_family(out: &mut String, mf: &MetricFamily, exemplars: Option<&Exemplars>) {
+    let name = mf.name();
+    let metric_type = mf.get_field_type();
+    let (family, type_name) = match metric_type {
+        MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+        MetricType::GAUGE => (name, "gauge"),
+        MetricType::HISTOGRAM => (name, "histogram"),
+        MetricType::SUMMARY => (name, "summary"),
+        MetricType::UNTYPED => (name, "unknown"),
+    };
+    if !mf.help().is_empty() {
+        let _ = writeln!(out, "# HELP {} {}", family, escape_openmetrics(mf.help()));
+    }
+    let _ = writeln!(out, "# TYPE {} {}", family, type_name);
+
+    for m in mf.get_metric() {
+        let labels = m.get_label();
+        match metric_type {
+            MetricType::COUNTER => {
+                let name = format!("{}_total", family);
+                write_openmetrics_sample(out, &name, labels, None, m.get_counter().value(), None);
+            }
+            MetricType::GAUGE => {
+                write_openmetrics_sample(out, name, labels, None, m.get_gauge().value(), None);
+            }
+            MetricType::HISTOGRAM => {
+                let h = m.get_histogram();
+                let examples = exemplars
+                    .map(|e| e.for_series(name, labels))
+                    .unwrap_or_default();
+                let bucket_name = format!("{}_bucket", name);
+                let buckets = h
+                    .get_bucket()
+                    .iter()
+                    .filter(|b| b.upper_bound().is_finite())
+                    .map(|b| (b.upper_bound(), b.cumulative_count()))
+                    .chain(std::iter::once((f64::INFINITY, h.get_sample_count())));
+                for (i, (bound, count)) in buckets.enumerate() {
+                    write_openmetrics_sample(
+                        out,
+                        &bucket_name,
+                        labels,
+                        Some(("le", &format_openmetrics_value(bound))),
+                        count as f64,
+                        examples.get(i).and_then(Option::as_ref),
+                    );
+                }
+                write_openmetrics_sample(
+                    out,
+                    &format!("{}_sum", name),
+                    labels,
+                    None,
+                    h.get_sample_sum(),
+                    None,
+                );
+                write_openmetrics_sample(
+                    out,
+                    &format!("{}_count", name),
+                    labels,
+                    None,
+                    h.get_sample_count() as f64,
+                    None,
+                );
+            }
+            MetricType::SUMMARY => {
+                let s = m.get_summary();
+                for q in s.get_quantile() {
+                    write_openmetrics_sample(
+                        out,
+                        name,
+                        labels,
+                        Some(("quantile", &format_openmetrics_value(q.quantile()))),
+                        q.value(),
+                        None,
+                    );
+                }
+                write_openmetrics_sample(
+                    out,
+                    &format!("{}_sum", name),
+                    labels,
+                    None,
+                    s.sample_sum(),
+                    None,
+                );
+                write_openmetrics_sample(
+                    out,
+                    &format!("{}_count", name),
+                    labels,
+                    None,
+                    s.sample_count() as f64,
+                    None,
+                );
+            }
+            MetricType::UNTYPED => {}
+        }
+    }
+}
+
+fn write_openmetrics_sample(
+    out: &mut String,
+    name: &str,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, &str)>,
+    value: f64,
+    exemplar: Option<&Exemplar>,
+) {
+    out.push_str(name);
+    let pairs: Vec<(&str, &str)> = labels
+        .iter()
+        .map(|lp| (lp.name(), lp.value()))
+        .chain(extra_label)
+        .collect();
+    if !pairs.is_empty() {
+        out.push('{');
+        for (i, (label, label_value)) in pairs.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}=\"{}\"", label, escape_openmetrics(label_value));
+        }
+        out.push('}');
+    }
+    let _ = write!(out, " {}", format_openmetrics_value(value));
+    if let Some(exemplar) = exemplar {
+        let _ = write!(
+            out,
+            " # {{trace_id=\"{}\"}} {} {:.3}",
+            escape_openmetrics(&exemplar.trace_id),
+            format_openmetrics_value(exemplar.value),
+            exemplar.timestamp
+        );
+    }
+    out.push('\n');
+}
+
+fn format_openmetrics_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else if value.is_nan() {
+        "NaN".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape_openmetrics(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('"', "\\\"")
 }
 
 impl Default for Metrics {
@@ -146,3 +1923,279 @@ impl Default for Metrics {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_label_separates_warm_traffic() {
+        let metrics = Metrics::new();
+        metrics.record_request(
+            "example",
+            CacheStatus::Miss,
+            StatusCode::OK,
+            Duration::from_millis(5),
+            RequestSource::Warm,
+        );
+        metrics.record_request(
+            "example",
+            CacheStatus::Hit,
+            StatusCode::OK,
+            Duration::from_millis(1),
+            RequestSource::Client,
+        );
+        metrics.record_origin_request("example", StatusCode::OK, RequestSource::Warm, false);
+        metrics.record_origin_request("corp", StatusCode::OK, RequestSource::Client, true);
+
+        let output = metrics.gather();
+        assert!(output.contains(
+            r#"cdn_requests_total{cache_status="MISS",origin="example",source="warm",status="200"} 1"#
+        ));
+        assert!(output.contains(
+            r#"cdn_requests_total{cache_status="HIT",origin="example",source="client",status="200"} 1"#
+        ));
+        assert!(output.contains(
+            r#"cdn_origin_requests_total{origin="example",proxied="false",source="warm",status="200"} 1"#
+        ));
+        assert!(output.contains(
+            r#"cdn_origin_requests_total{origin="corp",proxied="true",source="client",status="200"} 1"#
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_recorder_totals_match_under_concurrency() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.start_recorder(100_000).unwrap();
+
+        let tasks: Vec<_> = (0..16)
+            .map(|_| {
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    for _ in 0..500 {
+                        metrics.record_request(
+                            "example",
+                            CacheStatus::Hit,
+                            StatusCode::OK,
+                            Duration::from_millis(1),
+                            RequestSource::Client,
+                        );
+                        metrics.record_bytes_served("example", CacheStatus::Hit, 10);
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        metrics.flush().await;
+
+        let output = metrics.gather();
+        assert!(output.contains(
+            r#"cdn_requests_total{cache_status="HIT",origin="example",source="client",status="200"} 8000"#
+        ));
+        assert!(
+            output.contains(r#"cdn_bytes_served_total{cache_status="HIT",origin="example"} 80000"#)
+        );
+        assert!(output.contains("cdn_metric_events_dropped_total 0"));
+    }
+
+    #[test]
+    fn test_full_queue_drops_without_blocking() {
+        let metrics = Metrics::new();
+        // Nothing drains this queue, so it fills after one event
+        let _receiver = metrics.attach_queue(1).unwrap();
+
+        for _ in 0..100 {
+            metrics.record_request(
+                "example",
+                CacheStatus::Miss,
+                StatusCode::OK,
+                Duration::from_millis(1),
+                RequestSource::Client,
+            );
+        }
+
+        assert!(
+            metrics
+                .gather()
+                .contains("cdn_metric_events_dropped_total 99")
+        );
+    }
+
+    #[test]
+    fn test_openmetrics_names_counters_and_terminates() {
+        let metrics = Metrics::new();
+        metrics.record_request(
+            "example",
+            CacheStatus::Hit,
+            StatusCode::OK,
+            Duration::from_millis(1),
+            RequestSource::Client,
+        );
+
+        let output = metrics.gather_openmetrics();
+        assert!(output.contains("# TYPE cdn_requests counter\n"));
+        assert!(output.contains(
+            r#"cdn_requests_total{cache_status="HIT",origin="example",source="client",status="200"} 1"#
+        ));
+        assert!(output.contains("# TYPE cdn_request_duration_seconds histogram\n"));
+        assert!(output.contains(
+            r#"cdn_request_duration_seconds_bucket{cache_status="HIT",origin="example",source="client",le="+Inf"} 1"#
+        ));
+        assert!(!output.contains(" # {"));
+        assert!(output.ends_with("# EOF\n"));
+    }
+
+    #[tokio::test]
+    async fn test_streamed_output_matches_gathered() {
+        use futures::StreamExt;
+
+        let metrics = Arc::new(Metrics::new());
+        metrics.record_request(
+            "example",
+            CacheStatus::Miss,
+            StatusCode::OK,
+            Duration::from_millis(3),
+            RequestSource::Client,
+        );
+
+        for openmetrics in [false, true] {
+            let chunks: Vec<Bytes> = metrics
+                .gather_stream(openmetrics)
+                .map(|chunk| chunk.unwrap())
+                .collect()
+                .await;
+            assert!(chunks.len() > 1);
+            let streamed = String::from_utf8(chunks.concat()).unwrap();
+            let gathered = if openmetrics {
+                metrics.gather_openmetrics()
+            } else {
+                metrics.gather()
+            };
+            assert_eq!(streamed, gathered);
+        }
+    }
+
+    #[test]
+    fn test_series_count_includes_itself() {
+        let metrics = Metrics::new();
+        metrics.record_request(
+            "example",
+            CacheStatus::Hit,
+            StatusCode::OK,
+            Duration::from_millis(1),
+            RequestSource::Client,
+        );
+
+        let output = metrics.gather();
+        let samples = output.lines().filter(|l| !l.starts_with('#')).count();
+        assert!(output.contains(&format!("\n{} {}\n", SERIES_METRIC, samples)));
+
+        metrics.record_request(
+            "other",
+            CacheStatus::Hit,
+            StatusCode::OK,
+            Duration::from_millis(1),
+            RequestSource::Client,
+        );
+        let output = metrics.gather();
+        let more = output.lines().filter(|l| !l.starts_with('#')).count();
+        assert!(more > samples);
+        assert!(output.contains(&format!("\n{} {}\n", SERIES_METRIC, more)));
+    }
+
+    #[test]
+    fn test_exemplar_attached_to_matching_bucket() {
+        let metrics = Metrics::new().with_exemplars(true);
+        metrics.record_request(
+            "example",
+            CacheStatus::Miss,
+            StatusCode::OK,
+            Duration::from_millis(30),
+            RequestSource::Client,
+        );
+        // Tests run without an OpenTelemetry span, so supply the trace ID directly
+        metrics.exemplars.as_ref().unwrap().observe(
+            "cdn_request_duration_seconds",
+            &[
+                ("source", "client"),
+                ("origin", "example"),
+                ("cache_status", "MISS"),
+            ],
+            &REQUEST_DURATION_BUCKETS,
+            0.03,
+            "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+        );
+
+        let output = metrics.gather_openmetrics();
+        let with_exemplar: Vec<_> = output
+            .lines()
+            .filter(|line| {
+                line.contains(r#"# {trace_id="4bf92f3577b34da6a3ce929d0e0e4736"} 0.03 "#)
+            })
+            .collect();
+        assert_eq!(with_exemplar.len(), 1);
+        assert!(with_exemplar[0].starts_with(
+            r#"cdn_request_duration_seconds_bucket{cache_status="MISS",origin="example",source="client",le="0.05"} 1 "#
+        ));
+    }
+
+    #[test]
+    fn test_origin_latency_exemplar() {
+        let metrics = Metrics::new().with_exemplars(true);
+        metrics.record_origin_latency("example", Duration::from_millis(300));
+        // Tests run without an OpenTelemetry span, so queue the trace ID directly
+        metrics.apply(MetricEvent::OriginLatency {
+            origin: "example".to_string(),
+            duration: Duration::from_millis(80),
+            trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736".to_string()),
+        });
+
+        let output = encode_openmetrics(&metrics.registry.gather(), metrics.exemplars.as_ref());
+        assert!(output.contains(r#"cdn_origin_latency_seconds_count{origin="example"} 2"#));
+        let with_exemplar: Vec<_> = output
+            .lines()
+            .filter(|line| line.contains(r#"trace_id="4bf92f3577b34da6a3ce929d0e0e4736""#))
+            .collect();
+        assert_eq!(with_exemplar.len(), 1);
+        assert!(with_exemplar[0].starts_with(
+            r#"cdn_origin_latency_seconds_bucket{origin="example",le="0.1"} 1 # {trace_id="4bf92f3577b34da6a3ce929d0e0e4736"} 0.08 "#
+        ));
+    }
+
+    #[test]
+    fn test_path_prefix_labels_are_capped() {
+        let labels = PathPrefixLabels::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        let label = |prefix: &str, at: u64| {
+            labels.label(
+                prefix.to_string(),
+                "web",
+                "200",
+                start + Duration::from_secs(at),
+            )
+        };
+
+        assert_eq!(label("/a", 0).value, "/a");
+        assert_eq!(label("/b", 10).value, "/b");
+        // Full, and nothing has been idle long enough to give up its series
+        assert_eq!(label("/c", 20).value, OTHER_PATH_PREFIX);
+        assert_eq!(label("/a", 30).value, "/a");
+
+        // /b was used least recently and has been idle a minute
+        let taken = label("/c", 70);
+        assert_eq!(taken.value, "/c");
+        let evicted = taken.evicted.unwrap();
+        assert_eq!(evicted.prefix, "/b");
+        let series = ("web".to_string(), "200".to_string());
+        assert!(evicted.series.contains(&series));
+        assert_eq!(label("/b", 71).value, OTHER_PATH_PREFIX);
+
+        let none = PathPrefixLabels::new(0, Duration::ZERO);
+        assert_eq!(
+            none.label("/a".to_string(), "web", "200", start).value,
+            OTHER_PATH_PREFIX
+        );
+    }
+}
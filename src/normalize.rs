@@ -0,0 +1,409 @@
+//! Request path normalization
+//!
+//! Canonicalizes request paths (duplicate slashes, dot-segments, trailing slash)
+//! before routing, edge rules, security checks, and cache key generation so that
+//! every layer sees the same path. Without this, payloads such as `//admin`,
+//! `/./admin`, `/%2fadmin` or `/%61dmin` slip past `^/admin` rules and are
+//! then resolved by the origin.
+
+use axum::http::{Request, Uri, uri::PathAndQuery};
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use tracing::debug;
+
+use crate::config::{PathNormalizationConfig, TrailingSlashPolicy};
+
+/// Shared path normalizer used by every layer that inspects the request path
+#[derive(Debug, Clone)]
+pub struct PathNormalizer {
+    config: PathNormalizationConfig,
+}
+
+impl PathNormalizer {
+    pub fn new(config: PathNormalizationConfig) -> Self {
+        Self { config }
+    }
+
+    /// Check if path normalization is enabled
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Normalize a request path
+    ///
+    /// - Decodes percent-encoded unreserved characters (RFC 3986 Section 6.2.2.2)
+    /// - Decodes `%2F` and `%5C` to `/`, since many origins split on them
+    /// - Collapses runs of `/` into a single `/`
+    /// - Resolves `.` and `..` segments (RFC 3986 Section 5.2.4), including their
+    ///   percent-encoded (`%2e`) and path-parameter (`..;`) forms
+    /// - Applies the configured trailing slash policy
+    ///
+    /// `..` segments can never climb above the root.
+    pub fn normalize(&self, path: &str) -> String {
+        if !self.config.enabled {
+            return path.to_string();
+        }
+
+        let path = decode_escapes(path, self.config.decode_encoded_slashes);
+
+        let mut segments: Vec<&str> = Vec::new();
+        // A path ending in a dot-segment names a directory, same as a trailing slash
+        let mut ends_in_dot_segment = false;
+
+        for (i, segment) in path.split('/').enumerate() {
+            // The first split element is whatever precedes the leading slash
+            if i == 0 && segment.is_empty() {
+                continue;
+            }
+
+            if segment.is_empty() {
+                if self.config.merge_slashes {
+                    continue;
+                }
+                segments.push(segment);
+                continue;
+            }
+
+            if self.config.resolve_dot_segments {
+                match dot_segment(segment) {
+                    Some(DotSegment::Current) => {
+                        ends_in_dot_segment = true;
+                        continue;
+                    }
+                    Some(DotSegment::Parent) => {
+                        segments.pop();
+                        ends_in_dot_segment = true;
+                        continue;
+                    }
+                    None => {}
+                }
+            }
+
+            ends_in_dot_segment = false;
+            segments.push(segment);
+        }
+
+        let mut trailing_slash = path.ends_with('/') || ends_in_dot_segment;
+
+        // A trailing empty segment already encodes the slash when slashes are not merged
+        if segments.last() == Some(&"") {
+            segments.pop();
+            trailing_slash = true;
+        }
+
+        let mut normalized = format!("/{}", segments.join("/"));
+
+        if trailing_slash && normalized != "/" {
+            match self.config.trailing_slash {
+                TrailingSlashPolicy::Preserve => normalized.push('/'),
+                TrailingSlashPolicy::Strip => {}
+            }
+        }
+
+        normalized
+    }
+
+    /// Rewrite the request URI in place with the normalized path, keeping the query
+    pub fn normalize_request<B>(&self, request: &mut Request<B>) {
+        if !self.config.enabled {
+            return;
+        }
+
+        let path = request.uri().path();
+        let normalized = self.normalize(path);
+        if normalized == path {
+            return;
+        }
+
+        let path_and_query = match request.uri().query() {
+            Some(q) => format!("{}?{}", normalized, q),
+            None => normalized,
+        };
+
+        let mut parts = request.uri().clone().into_parts();
+        parts.path_and_query = match path_and_query.parse::<PathAndQuery>() {
+            Ok(pq) => Some(pq),
+            Err(_) => return,
+        };
+
+        if let Ok(uri) = Uri::from_parts(parts) {
+            debug!(from = %request.uri(), to = %uri, "Normalized request path");
+            *request.uri_mut() = uri;
+        }
+    }
+}
+
+/// Decode percent-encoded unreserved characters, and slashes and backslashes
+/// to `/` if `slashes` is set
+///
+/// Unreserved characters (ALPHA / DIGIT / `-._~`) mean the same encoded or
+/// not, so `/%61dmin` is `/admin`. Other escapes are left as they are.
+fn decode_escapes(path: &str, slashes: bool) -> Cow<'_, str> {
+    if !path.contains('%') {
+        return Cow::Borrowed(path);
+    }
+
+    let mut decoded = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(at) = rest.find('%') {
+        decoded.push_str(&rest[..at]);
+        let byte = rest
+            .get(at + 1..at + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match byte {
+            Some(b) if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') => {
+                decoded.push(b as char);
+                rest = &rest[at + 3..];
+            }
+            Some(b'/' | b'\\') if slashes => {
+                decoded.push('/');
+                rest = &rest[at + 3..];
+            }
+            _ => {
+                decoded.push('%');
+                rest = &rest[at + 1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    Cow::Owned(decoded)
+}
+
+enum DotSegment {
+    Current,
+    Parent,
+}
+
+/// Classify a segment as `.` or `..`, accounting for `%2e` and `;params`
+fn dot_segment(segment: &str) -> Option<DotSegment> {
+    // Servlet containers strip path parameters, so "..;foo" acts like ".."
+    let segment = segment.split(';').next().unwrap_or(segment);
+    let decoded = segment.to_ascii_lowercase().replace("%2e", ".");
+
+    match decoded.as_str() {
+        "." => Some(DotSegment::Current),
+        ".." => Some(DotSegment::Parent),
+        _ => None,
+    }
+}
+
+/// Tower layer that normalizes the request path before routing
+///
+/// This must wrap the whole router: middleware added via `Router::layer`
+/// runs after routing, when path parameters have already been extracted.
+#[derive(Clone)]
+pub struct PathNormalizationLayer {
+    normalizer: Arc<PathNormalizer>,
+}
+
+impl PathNormalizationLayer {
+    pub fn new(normalizer: Arc<PathNormalizer>) -> Self {
+        Self { normalizer }
+    }
+}
+
+impl<S> Layer<S> for PathNormalizationLayer {
+    type Service = PathNormalization<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PathNormalization {
+            inner,
+            normalizer: Arc::clone(&self.normalizer),
+        }
+    }
+}
+
+/// Service produced by [`PathNormalizationLayer`]
+#[derive(Clone)]
+pub struct PathNormalization<S> {
+    inner: S,
+    normalizer: Arc<PathNormalizer>,
+}
+
+impl<S, B> Service<Request<B>> for PathNormalization<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        self.normalizer.normalize_request(&mut request);
+        self.inner.call(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RegexLimitsConfig;
+    use crate::edge::{ConditionalRouter, RoutingAction, RoutingCondition, RoutingRule};
+    use axum::http::{HeaderMap, Method};
+
+    fn normalizer() -> PathNormalizer {
+        PathNormalizer::new(PathNormalizationConfig::default())
+    }
+
+    #[test]
+    fn test_collapse_duplicate_slashes() {
+        let n = normalizer();
+        assert_eq!(n.normalize("//admin"), "/admin");
+        assert_eq!(n.normalize("/a//b///c"), "/a/b/c");
+        assert_eq!(n.normalize("///"), "/");
+    }
+
+    #[test]
+    fn test_resolve_dot_segments() {
+        let n = normalizer();
+        assert_eq!(n.normalize("/./admin"), "/admin");
+        assert_eq!(n.normalize("/a/b/../c"), "/a/c");
+        assert_eq!(n.normalize("/a/./b/."), "/a/b/");
+        assert_eq!(n.normalize("/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(n.normalize("/a/.."), "/");
+    }
+
+    #[test]
+    fn test_decode_encoded_slashes() {
+        let n = normalizer();
+        assert_eq!(n.normalize("/a%2Fb%5cc"), "/a/b/c");
+        assert_eq!(n.normalize("/a%2f%2F/b"), "/a/b");
+        // Other escapes, and stray or truncated percent signs, are left alone
+        assert_eq!(n.normalize("/a%20b/100%/c%2"), "/a%20b/100%/c%2");
+        assert_eq!(n.normalize("/a%%2fb"), "/a%/b");
+
+        let kept = PathNormalizer::new(PathNormalizationConfig {
+            decode_encoded_slashes: false,
+            ..Default::default()
+        });
+        assert_eq!(kept.normalize("/a%2Fb"), "/a%2Fb");
+        assert_eq!(kept.normalize("/%61%2Fb"), "/a%2Fb");
+    }
+
+    #[test]
+    fn test_decode_unreserved_escapes() {
+        let n = normalizer();
+        assert_eq!(
+            n.normalize("/%61dmin/%7Euser/a%2Db%5F%2E"),
+            "/admin/~user/a-b_."
+        );
+        // Reserved and non-ASCII escapes keep their meaning, so stay encoded
+        assert_eq!(n.normalize("/a%3Fb%23c%25/%C3%A9"), "/a%3Fb%23c%25/%C3%A9");
+        // Decoding happens once; an escaped percent sign isn't decoded again
+        assert_eq!(n.normalize("/%2561dmin"), "/%2561dmin");
+    }
+
+    #[test]
+    fn test_trailing_slash_policy() {
+        let n = normalizer();
+        assert_eq!(n.normalize("/static/"), "/static/");
+
+        let strip = PathNormalizer::new(PathNormalizationConfig {
+            trailing_slash: TrailingSlashPolicy::Strip,
+            ..Default::default()
+        });
+        assert_eq!(strip.normalize("/static/"), "/static");
+        assert_eq!(strip.normalize("/"), "/");
+    }
+
+    #[test]
+    fn test_disabled_is_passthrough() {
+        let n = PathNormalizer::new(PathNormalizationConfig {
+            enabled: false,
+            ..Default::default()
+        });
+        assert_eq!(n.normalize("//a/./b/../c"), "//a/./b/../c");
+    }
+
+    #[test]
+    fn test_normalize_request_keeps_query() {
+        let n = normalizer();
+        let mut request = Request::builder()
+            .uri("//origin/./img/../logo.png?size=large")
+            .body(())
+            .unwrap();
+        n.normalize_request(&mut request);
+        assert_eq!(request.uri().path(), "/origin/logo.png");
+        assert_eq!(request.uri().query(), Some("size=large"));
+    }
+
+    #[test]
+    fn test_block_rule_catches_bypass_payloads() {
+        let router = ConditionalRouter::new(
+            vec![RoutingRule {
+                name: "block-admin".to_string(),
+                conditions: vec![RoutingCondition::Path {
+                    pattern: r"^/admin".to_string(),
+                }],
+                action: RoutingAction::Block {
+                    status: 403,
+                    message: None,
+                },
+                priority: 0,
+            }],
+            &RegexLimitsConfig::default(),
+        );
+        let n = normalizer();
+        let headers = HeaderMap::new();
+
+        let payloads = [
+            "//admin",
+            "///admin/users",
+            "/./admin",
+            "/././admin",
+            "/public/../admin",
+            "/public/..//admin",
+            "/%2e/admin",
+            "/%2E%2E/admin",
+            "/public/%2e%2e/admin",
+            "/public/..;/admin",
+            "/.;/admin",
+            "/public/./../admin/",
+            "/../admin",
+            "/%2fadmin",
+            "/%2Fadmin",
+            "/%5cadmin",
+            "/%5Cadmin",
+            "/public%2f..%2fadmin",
+            "/public%5c..%5cadmin",
+            "/public/%2e%2e%2fadmin",
+            "/%61dmin",
+            "/ad%6Din",
+            "/adm%69n",
+            "/%61%64%6d%69%6e",
+            "/public/%2e%2e/%61dmin",
+        ];
+
+        for payload in payloads {
+            // Sanity check: the raw payload really does bypass the rule
+            let raw = router.evaluate(payload, None, &Method::GET, &headers, None);
+            assert!(raw.is_none(), "payload {} unexpectedly matched raw", payload);
+
+            let normalized = n.normalize(payload);
+            let result = router.evaluate(&normalized, None, &Method::GET, &headers, None);
+            assert!(
+                matches!(result, Some(RoutingAction::Block { .. })),
+                "payload {} normalized to {} was not blocked",
+                payload,
+                normalized
+            );
+        }
+
+        // Legitimate paths that merely contain "admin" must not be blocked
+        for path in [
+            "/public/admin",
+            "/administrator/../public",
+            "/public%2fadmin",
+        ] {
+            let normalized = n.normalize(path);
+            let result = router.evaluate(&normalized, None, &Method::GET, &headers, None);
+            assert!(result.is_none(), "path {} should not be blocked", path);
+        }
+    }
+}
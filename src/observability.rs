@@ -5,17 +5,20 @@
 
 use axum::{
     body::Body,
-    extract::ConnectInfo,
+    extract::{ConnectInfo, State},
     http::{Request, Response, StatusCode, header},
     middleware::Next,
 };
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
 use opentelemetry::{KeyValue, global};
 use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::{
     Resource,
-    trace::{RandomIdGenerator, Sampler, SdkTracerProvider},
+    propagation::TraceContextPropagator,
+    trace::{BatchSpanProcessor, RandomIdGenerator, SdkTracer, SdkTracerProvider},
 };
-use prometheus::{CounterVec, GaugeVec, HistogramOpts, HistogramVec, Opts, Registry};
+use prometheus::{CounterVec, Gauge, GaugeVec, HistogramOpts, HistogramVec, Opts, Registry};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
@@ -23,11 +26,20 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
-use tracing::{Instrument, debug, error, info, info_span, warn};
+use tracing::{Instrument, Span, debug, error, info, info_span, warn};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use uuid::Uuid;
+use xxhash_rust::xxh3::xxh3_64;
 
+use crate::bandwidth::{CountingBody, ServedFrom};
 use crate::cache::CacheStatus;
-use crate::config::ObservabilityConfig;
+use crate::config::{ObservabilityConfig, RegexLimitsConfig, RequestLoggingConfig};
+use crate::metrics::{
+    Exemplars, Metrics, ORIGIN_LATENCY_BUCKETS, REQUEST_DURATION_BUCKETS, SERIES_METRIC,
+    encode_openmetrics, gather_counted,
+};
+use crate::origin::OriginErrorKind;
+use crate::trace_sampling::{DeferredSampler, DeferredSampling, SamplingPolicy};
 
 /// Request context for tracking through the request lifecycle
 #[derive(Debug, Clone)]
@@ -73,8 +85,15 @@ pub struct RequestLogEntry {
     pub origin: Option<String>,
     pub status: u16,
     pub cache_status: String,
+    /// Kind of origin failure behind the response ("connect", "http_5xx", ...)
+    pub origin_error: Option<&'static str>,
     pub duration_ms: f64,
     pub bytes_sent: u64,
+    /// False when the client disconnected before the body was fully sent
+    pub completed: bool,
+    /// True when logged by the success sample rather than forced, so counts
+    /// from these lines should be scaled up by the sample rate
+    pub sampled: bool,
     pub client_ip: Option<String>,
     pub user_agent: Option<String>,
     pub referer: Option<String>,
@@ -114,13 +133,15 @@ pub struct EnhancedMetrics {
     circuit_breaker_state: GaugeVec,
     circuit_breaker_trips: CounterVec,
 
-    // Connection metrics
-    active_connections: GaugeVec,
-    connection_duration: HistogramVec,
+    // Series exposed per scrape
+    series: Gauge,
 
     // Path-level tracking (for top paths)
     path_stats: Arc<RwLock<HashMap<String, PathStats>>>,
     max_tracked_paths: usize,
+
+    // Trace exemplars for the latency histograms, when enabled
+    exemplars: Option<Exemplars>,
 }
 
 #[derive(Debug, Default)]
@@ -154,9 +175,7 @@ impl EnhancedMetrics {
         let request_duration = HistogramVec::new(
             HistogramOpts::new("cdn_request_duration_seconds", "Request duration")
                 .namespace("screaming_eagle")
-                .buckets(vec![
-                    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
-                ]),
+                .buckets(REQUEST_DURATION_BUCKETS.to_vec()),
             &["origin", "cache_status"],
         )
         .unwrap();
@@ -198,7 +217,7 @@ impl EnhancedMetrics {
         let origin_latency = HistogramVec::new(
             HistogramOpts::new("cdn_origin_latency_seconds", "Origin response latency")
                 .namespace("screaming_eagle")
-                .buckets(vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+                .buckets(ORIGIN_LATENCY_BUCKETS.to_vec()),
             &["origin"],
         )
         .unwrap();
@@ -261,18 +280,12 @@ impl EnhancedMetrics {
         )
         .unwrap();
 
-        // Connection metrics
-        let active_connections = GaugeVec::new(
-            Opts::new("cdn_active_connections", "Active connections").namespace("screaming_eagle"),
-            &["type"],
-        )
-        .unwrap();
-
-        let connection_duration = HistogramVec::new(
-            HistogramOpts::new("cdn_connection_duration_seconds", "Connection duration")
-                .namespace("screaming_eagle")
-                .buckets(vec![0.1, 1.0, 5.0, 30.0, 60.0, 300.0]),
-            &["type"],
+        let series = Gauge::with_opts(
+            Opts::new(
+                SERIES_METRIC,
+                "Time series exposed by this endpoint, counting each histogram bucket",
+            )
+            .namespace("screaming_eagle"),
         )
         .unwrap();
 
@@ -293,8 +306,7 @@ impl EnhancedMetrics {
             Box::new(rate_limited_requests.clone()),
             Box::new(circuit_breaker_state.clone()),
             Box::new(circuit_breaker_trips.clone()),
-            Box::new(active_connections.clone()),
-            Box::new(connection_duration.clone()),
+            Box::new(series.clone()),
         ];
 
         for metric in metrics_to_register {
@@ -320,14 +332,15 @@ impl EnhancedMetrics {
             rate_limited_requests,
             circuit_breaker_state,
             circuit_breaker_trips,
-            active_connections,
-            connection_duration,
+            series,
             path_stats: Arc::new(RwLock::new(HashMap::new())),
             max_tracked_paths: config.metrics.max_tracked_paths,
+            exemplars: config.exemplars_enabled().then(Exemplars::default),
         }
     }
 
     /// Record a complete request
+    #[allow(clippy::too_many_arguments)]
     pub async fn record_request(
         &self,
         origin: &str,
@@ -354,6 +367,12 @@ impl EnhancedMetrics {
         self.request_duration
             .with_label_values(&[origin, cache_str])
             .observe(duration.as_secs_f64());
+        self.record_exemplar(
+            "screaming_eagle_cdn_request_duration_seconds",
+            &[("origin", origin), ("cache_status", cache_str)],
+            &REQUEST_DURATION_BUCKETS,
+            duration,
+        );
 
         self.request_duration_by_path
             .with_label_values(&[origin, &path_prefix])
@@ -365,7 +384,10 @@ impl EnhancedMetrics {
 
         // Cache operation tracking
         match cache_status {
-            CacheStatus::Hit | CacheStatus::Stale | CacheStatus::StaleIfError => {
+            CacheStatus::Hit
+            | CacheStatus::Stale
+            | CacheStatus::StaleAdaptive
+            | CacheStatus::StaleIfError => {
                 self.cache_operations
                     .with_label_values(&["get", "hit"])
                     .inc();
@@ -408,6 +430,12 @@ impl EnhancedMetrics {
         self.origin_latency
             .with_label_values(&[origin])
             .observe(duration.as_secs_f64());
+        self.record_exemplar(
+            "screaming_eagle_cdn_origin_latency_seconds",
+            &[("origin", origin)],
+            &ORIGIN_LATENCY_BUCKETS,
+            duration,
+        );
 
         self.bytes_received
             .with_label_values(&[origin])
@@ -453,13 +481,6 @@ impl EnhancedMetrics {
             .set(size_bytes as f64);
     }
 
-    /// Set active connections count
-    pub fn set_active_connections(&self, count: usize) {
-        self.active_connections
-            .with_label_values(&["http"])
-            .set(count as f64);
-    }
-
     /// Update path-level stats
     async fn update_path_stats(
         &self,
@@ -487,7 +508,10 @@ impl EnhancedMetrics {
         entry.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
 
         match cache_status {
-            CacheStatus::Hit | CacheStatus::Stale | CacheStatus::StaleIfError => {
+            CacheStatus::Hit
+            | CacheStatus::Stale
+            | CacheStatus::StaleAdaptive
+            | CacheStatus::StaleIfError => {
                 entry.cache_hits.fetch_add(1, Ordering::Relaxed);
             }
             CacheStatus::Miss | CacheStatus::Bypass => {
@@ -538,11 +562,33 @@ impl EnhancedMetrics {
     pub fn gather(&self) -> String {
         use prometheus::{Encoder, TextEncoder};
         let encoder = TextEncoder::new();
-        let metric_families = self.registry.gather();
+        let metric_families = gather_counted(&self.registry, &self.series);
         let mut buffer = Vec::new();
         encoder.encode(&metric_families, &mut buffer).unwrap();
         String::from_utf8(buffer).unwrap_or_default()
     }
+
+    /// Export metrics in OpenMetrics format, with exemplars if enabled
+    pub fn gather_openmetrics(&self) -> String {
+        encode_openmetrics(
+            &gather_counted(&self.registry, &self.series),
+            self.exemplars.as_ref(),
+        )
+    }
+
+    fn record_exemplar(
+        &self,
+        metric: &str,
+        labels: &[(&str, &str)],
+        buckets: &[f64],
+        duration: Duration,
+    ) {
+        if let Some(exemplars) = &self.exemplars
+            && let Some(trace_id) = current_trace_id()
+        {
+            exemplars.observe(metric, labels, buckets, duration.as_secs_f64(), trace_id);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -557,7 +603,7 @@ pub struct PathStatsSnapshot {
 }
 
 /// Extract path prefix for grouping (e.g., /api/v1/users/123 -> /api/v1/users)
-fn extract_path_prefix(path: &str) -> String {
+pub(crate) fn extract_path_prefix(path: &str) -> String {
     let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
 
     // Keep first 3 segments, replace numeric segments with {id}
@@ -594,10 +640,17 @@ fn is_likely_id(s: &str) -> bool {
 }
 
 /// Initialize OpenTelemetry tracing
-pub fn init_tracing(config: &ObservabilityConfig) -> anyhow::Result<()> {
+///
+/// Returns the tracer for the `tracing-opentelemetry` layer, or `None` when
+/// tracing is disabled. Sampling is decided as each request finishes; see
+/// [`crate::trace_sampling`].
+pub fn init_tracing(
+    config: &ObservabilityConfig,
+    regex: &RegexLimitsConfig,
+) -> anyhow::Result<Option<SdkTracer>> {
     if !config.tracing.enabled {
         info!("OpenTelemetry tracing disabled");
-        return Ok(());
+        return Ok(None);
     }
 
     let otlp_endpoint = config
@@ -622,19 +675,61 @@ pub fn init_tracing(config: &ObservabilityConfig) -> anyhow::Result<()> {
         ])
         .build();
 
-    // Create tracer provider with batch processor
+    // Create tracer provider with a batch processor behind deferred sampling
+    let processor = DeferredSampling::new(
+        BatchSpanProcessor::builder(exporter).build(),
+        SamplingPolicy::new(&config.tracing, regex),
+        config.tracing.max_pending_spans,
+    );
     let tracer_provider = SdkTracerProvider::builder()
-        .with_batch_exporter(exporter)
-        .with_sampler(Sampler::TraceIdRatioBased(config.tracing.sample_rate))
+        .with_span_processor(processor)
+        .with_sampler(DeferredSampler::new(config.tracing.propagate_context))
         .with_id_generator(RandomIdGenerator::default())
         .with_resource(resource)
         .build();
 
-    // Set global tracer
+    // Set global tracer, and W3C trace context for incoming traceparent headers
+    let tracer = tracer_provider.tracer(config.tracing.service_name.clone());
     global::set_tracer_provider(tracer_provider);
+    global::set_text_map_propagator(TraceContextPropagator::new());
 
     info!("OpenTelemetry tracing initialized");
-    Ok(())
+    Ok(Some(tracer))
+}
+
+/// Trace ID of the active OpenTelemetry span, if any
+pub fn current_trace_id() -> Option<String> {
+    span_trace_id(&Span::current())
+}
+
+fn span_trace_id(span: &Span) -> Option<String> {
+    let context = span.context();
+    let span_context = context.span().span_context().clone();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+/// Trace ID field of a W3C `traceparent` header (`version-traceid-spanid-flags`)
+fn traceparent_trace_id(traceparent: &str) -> Option<&str> {
+    let trace_id = traceparent.trim().split('-').nth(1)?;
+    (trace_id.len() == 32
+        && trace_id.bytes().all(|b| b.is_ascii_hexdigit())
+        && trace_id.bytes().any(|b| b != b'0'))
+    .then_some(trace_id)
+}
+
+/// Reads propagation headers off an incoming request
+struct HeaderExtractor<'a>(&'a header::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
 }
 
 /// Shutdown OpenTelemetry
@@ -644,14 +739,122 @@ pub fn shutdown_tracing() {
     // and will be cleaned up when dropped
 }
 
+/// Whether a request made it into the access log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogDecision {
+    /// Error or slow request, logged regardless of sampling
+    Forced,
+    /// Picked by the success sample
+    Sampled,
+    /// Left out by the success sample
+    Suppressed,
+}
+
+impl LogDecision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogDecision::Forced => "forced",
+            LogDecision::Sampled => "sampled",
+            LogDecision::Suppressed => "suppressed",
+        }
+    }
+}
+
+/// State for [`request_logging_middleware`]
+#[derive(Clone)]
+pub struct RequestLogging {
+    config: Arc<RequestLoggingConfig>,
+    metrics: Arc<Metrics>,
+    /// Continue the trace in an incoming `traceparent`
+    trace_propagation: bool,
+}
+
+impl RequestLogging {
+    pub fn new(config: RequestLoggingConfig, metrics: Arc<Metrics>) -> Self {
+        Self {
+            config: Arc::new(config),
+            metrics,
+            trace_propagation: true,
+        }
+    }
+
+    /// Whether request spans continue the caller's trace (default: true)
+    pub fn with_trace_propagation(mut self, enabled: bool) -> Self {
+        self.trace_propagation = enabled;
+        self
+    }
+
+    /// Decide whether to log a finished request
+    ///
+    /// Sampling hashes the request ID, so a request is either logged in
+    /// full or not at all.
+    pub fn decide(&self, request_id: &str, status: StatusCode, duration: Duration) -> LogDecision {
+        let slow_ms = self.config.slow_request_threshold_ms;
+        if status.as_u16() >= self.config.always_log_status_gte
+            || (slow_ms > 0 && duration >= Duration::from_millis(slow_ms))
+        {
+            LogDecision::Forced
+        } else if in_sample(request_id, self.config.success_sample_rate) {
+            LogDecision::Sampled
+        } else {
+            LogDecision::Suppressed
+        }
+    }
+}
+
+/// Deterministic 1-in-N pick keyed on the request ID
+fn in_sample(request_id: &str, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    if rate <= 0.0 {
+        return false;
+    }
+    (xxh3_64(request_id.as_bytes()) as f64 / u64::MAX as f64) < rate
+}
+
+/// The ID assigned to a request, available to later layers as an extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    static REQUEST_ID: RequestId;
+}
+
+/// ID of the request being served on this task, if any
+///
+/// Lets error responses quote the ID without threading it through every
+/// handler; work spawned off the request task doesn't see it.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.0.clone()).ok()
+}
+
+/// Middleware assigning every request an ID, echoed in `x-request-id`
+pub async fn request_id_middleware(mut request: Request<Body>, next: Next) -> Response<Body> {
+    let request_id = RequestId(Uuid::new_v4().to_string());
+    request.extensions_mut().insert(request_id.clone());
+
+    let value = header::HeaderValue::from_str(&request_id.0);
+    let mut response = REQUEST_ID.scope(request_id, next.run(request)).await;
+    if let Ok(value) = value {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    response
+}
+
 /// Middleware for request logging and tracing
 pub async fn request_logging_middleware(
+    State(logging): State<RequestLogging>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     request: Request<Body>,
     next: Next,
 ) -> Response<Body> {
     let start = Instant::now();
-    let request_id = Uuid::new_v4().to_string();
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
 
     // Extract request info
     let method = request.method().to_string();
@@ -671,115 +874,168 @@ pub async fn request_logging_middleware(
     // Extract client IP from headers or connection
     let client_ip = extract_client_ip(&request, addr.ip().to_string());
 
-    // Extract trace context from headers
-    let trace_id = request
-        .headers()
-        .get("x-trace-id")
-        .or_else(|| request.headers().get("traceparent"))
-        .and_then(|v| v.to_str().ok())
-        .map(|s| s.to_string());
-
-    // Create span for tracing
+    // Create span for tracing, continuing the caller's trace if it sent one
     let span = info_span!(
         "http_request",
         request_id = %request_id,
         method = %method,
         path = %path,
         client_ip = %client_ip,
-        trace_id = ?trace_id,
+        trace_id = tracing::field::Empty,
+        "http.response.status_code" = tracing::field::Empty,
     );
+    if logging.trace_propagation {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        });
+        // Fails only when no OpenTelemetry layer is installed
+        let _ = span.set_parent(parent);
+    }
+
+    // Prefer the active span's trace ID, so logs match what was exported;
+    // without tracing, fall back to whatever the caller sent
+    let trace_id = span_trace_id(&span).or_else(|| {
+        let headers = request.headers();
+        headers
+            .get("traceparent")
+            .and_then(|v| v.to_str().ok())
+            .and_then(traceparent_trace_id)
+            .or_else(|| headers.get("x-trace-id").and_then(|v| v.to_str().ok()))
+            .map(|s| s.to_string())
+    });
+    if let Some(trace_id) = &trace_id {
+        span.record("trace_id", trace_id.as_str());
+    }
 
     // Execute request
-    let response = next.run(request).instrument(span).await;
+    let mut response = next.run(request).instrument(span.clone()).await;
 
-    let duration = start.elapsed();
     let status = response.status();
-
-    // Get response size if available
-    let bytes_sent = response
-        .headers()
-        .get(header::CONTENT_LENGTH)
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.parse::<u64>().ok())
-        .unwrap_or(0);
-
-    // Get cache status from response headers
-    let cache_status = response
-        .headers()
-        .get("x-cache-status")
-        .and_then(|v| v.to_str().ok())
+    // Read by trace sampling when the span closes
+    span.record("http.response.status_code", status.as_u16());
+
+    // Origin and cache status come from the CDN handler's extension, falling
+    // back to response headers for anything else
+    let served = response.extensions().get::<ServedFrom>();
+    let cache_status = served
+        .map(|s| s.cache_status.as_str())
+        .or_else(|| {
+            response
+                .headers()
+                .get("x-cache")
+                .and_then(|v| v.to_str().ok())
+        })
         .unwrap_or("NONE")
         .to_string();
-
-    // Get origin from response headers
-    let origin = response
-        .headers()
-        .get("x-origin")
-        .and_then(|v| v.to_str().ok())
-        .map(|s| s.to_string());
-
-    // Create structured log entry
-    let _log_entry = RequestLogEntry {
-        timestamp: SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map(|d| d.as_millis().to_string())
-            .unwrap_or_default(),
-        request_id: request_id.clone(),
-        trace_id,
-        method: method.clone(),
-        path: path.clone(),
-        query,
-        origin: origin.clone(),
-        status: status.as_u16(),
-        cache_status: cache_status.clone(),
-        duration_ms: duration.as_secs_f64() * 1000.0,
-        bytes_sent,
-        client_ip: Some(client_ip.clone()),
-        user_agent,
-        referer,
-        country: None, // Would come from GeoIP lookup
-    };
-
-    // Log based on status
-    if status.is_server_error() {
-        error!(
-            request_id = %request_id,
-            method = %method,
-            path = %path,
-            status = status.as_u16(),
-            duration_ms = duration.as_millis(),
-            cache_status = %cache_status,
-            "Request completed with server error"
-        );
-    } else if status.is_client_error() {
-        warn!(
-            request_id = %request_id,
-            method = %method,
-            path = %path,
-            status = status.as_u16(),
-            duration_ms = duration.as_millis(),
-            "Request completed with client error"
-        );
-    } else {
-        debug!(
-            request_id = %request_id,
-            method = %method,
-            path = %path,
-            status = status.as_u16(),
-            duration_ms = duration.as_millis(),
-            cache_status = %cache_status,
-            bytes = bytes_sent,
-            "Request completed"
-        );
-    }
+    let origin = served.map(|s| s.origin.clone()).or_else(|| {
+        response
+            .headers()
+            .get("x-origin")
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string())
+    });
+
+    // Set by the CDN handler (or its error response) when the origin failed
+    let origin_error = response
+        .extensions()
+        .get::<OriginErrorKind>()
+        .map(|kind| kind.as_str());
 
     // Add request ID to response headers
-    let mut response = response;
     if let Ok(value) = header::HeaderValue::from_str(&request_id) {
         response.headers_mut().insert("x-request-id", value);
     }
 
-    response
+    // Log once the body has been written (or abandoned), so bytes_sent is
+    // what actually went out rather than the Content-Length header
+    response.map(|body| {
+        Body::new(CountingBody::new(body, move |outcome| {
+            let duration = start.elapsed();
+
+            let decision = logging.decide(&request_id, status, duration);
+            logging.metrics.record_access_log(decision.as_str());
+            if decision == LogDecision::Suppressed {
+                return;
+            }
+            let sampled = decision == LogDecision::Sampled;
+
+            // Create structured log entry
+            let _log_entry = RequestLogEntry {
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis().to_string())
+                    .unwrap_or_default(),
+                request_id: request_id.clone(),
+                trace_id,
+                method: method.clone(),
+                path: path.clone(),
+                query,
+                origin,
+                status: status.as_u16(),
+                cache_status: cache_status.clone(),
+                origin_error,
+                duration_ms: duration.as_secs_f64() * 1000.0,
+                bytes_sent: outcome.bytes_sent,
+                completed: outcome.completed,
+                sampled,
+                client_ip: Some(client_ip),
+                user_agent,
+                referer,
+                country: None, // Would come from GeoIP lookup
+            };
+
+            // Log based on status
+            if status.is_server_error() {
+                error!(
+                    request_id = %request_id,
+                    method = %method,
+                    path = %path,
+                    status = status.as_u16(),
+                    duration_ms = duration.as_millis(),
+                    sampled,
+                    cache_status = %cache_status,
+                    origin_error,
+                    "Request completed with server error"
+                );
+            } else if status.is_client_error() {
+                warn!(
+                    request_id = %request_id,
+                    method = %method,
+                    path = %path,
+                    status = status.as_u16(),
+                    duration_ms = duration.as_millis(),
+                    sampled,
+                    "Request completed with client error"
+                );
+            } else if !outcome.completed {
+                info!(
+                    request_id = %request_id,
+                    method = %method,
+                    path = %path,
+                    status = status.as_u16(),
+                    duration_ms = duration.as_millis(),
+                    sampled,
+                    cache_status = %cache_status,
+                    bytes = outcome.bytes_sent,
+                    origin_error,
+                    "Request aborted by client"
+                );
+            } else {
+                debug!(
+                    request_id = %request_id,
+                    method = %method,
+                    path = %path,
+                    status = status.as_u16(),
+                    duration_ms = duration.as_millis(),
+                    sampled,
+                    cache_status = %cache_status,
+                    bytes = outcome.bytes_sent,
+                    origin_error,
+                    "Request completed"
+                );
+            }
+        }))
+    })
 }
 
 fn extract_client_ip(request: &Request<Body>, fallback: String) -> String {
@@ -993,6 +1249,157 @@ mod tests {
         assert_eq!(thresholds.latency_p99_threshold_ms, 1000);
     }
 
+    fn request_logging(config: RequestLoggingConfig) -> RequestLogging {
+        RequestLogging::new(config, Arc::new(Metrics::new()))
+    }
+
+    #[test]
+    fn test_log_decision() {
+        let logging = request_logging(RequestLoggingConfig {
+            success_sample_rate: 0.0,
+            ..Default::default()
+        });
+        let fast = Duration::from_millis(5);
+
+        assert_eq!(
+            logging.decide("a", StatusCode::OK, fast),
+            LogDecision::Suppressed
+        );
+        assert_eq!(
+            logging.decide("a", StatusCode::NOT_FOUND, fast),
+            LogDecision::Forced
+        );
+        assert_eq!(
+            logging.decide("a", StatusCode::OK, Duration::from_secs(2)),
+            LogDecision::Forced
+        );
+
+        let logging = request_logging(RequestLoggingConfig::default());
+        assert_eq!(
+            logging.decide("a", StatusCode::OK, fast),
+            LogDecision::Sampled
+        );
+    }
+
+    #[test]
+    fn test_success_sampling_is_deterministic() {
+        let ids: Vec<String> = (0..10_000).map(|_| Uuid::new_v4().to_string()).collect();
+        let picked: Vec<bool> = ids.iter().map(|id| in_sample(id, 0.1)).collect();
+        let again: Vec<bool> = ids.iter().map(|id| in_sample(id, 0.1)).collect();
+        assert_eq!(picked, again);
+
+        let count = picked.iter().filter(|p| **p).count();
+        assert!((800..1200).contains(&count), "sampled {count} of 10000");
+    }
+
+    #[tokio::test]
+    async fn test_middleware_counts_suppressed_logs() {
+        use axum::{Router, middleware::from_fn_with_state, routing::get};
+        use tower::ServiceExt;
+
+        let metrics = Arc::new(Metrics::new());
+        let logging = RequestLogging::new(
+            RequestLoggingConfig {
+                success_sample_rate: 0.0,
+                ..Default::default()
+            },
+            metrics.clone(),
+        );
+        let app = Router::new()
+            .route("/ok", get(|| async { "ok" }))
+            .route("/fail", get(|| async { StatusCode::BAD_GATEWAY }))
+            .layer(from_fn_with_state(logging, request_logging_middleware));
+
+        for path in ["/ok", "/ok", "/fail"] {
+            let mut request = Request::builder().uri(path).body(Body::empty()).unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert!(response.headers().contains_key("x-request-id"));
+            axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+        }
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"cdn_access_logs_total{decision="suppressed"} 2"#));
+        assert!(output.contains(r#"cdn_access_logs_total{decision="forced"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn test_error_responses_quote_the_request_id() {
+        use crate::error::CdnError;
+        use axum::{Router, middleware::from_fn, routing::get};
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route(
+                "/fail",
+                get(|| async { CdnError::GatewayTimeout("too slow".to_string()) }),
+            )
+            .layer(from_fn(request_id_middleware));
+
+        let request = Request::builder().uri("/fail").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let request_id = response.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["request_id"], request_id.as_str());
+
+        // Outside a request there is no ID to quote
+        assert_eq!(current_request_id(), None);
+    }
+
+    #[test]
+    fn test_traceparent_trace_id() {
+        assert_eq!(
+            traceparent_trace_id("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        assert_eq!(
+            traceparent_trace_id("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(traceparent_trace_id("00-not-a-trace-01"), None);
+        assert_eq!(traceparent_trace_id(""), None);
+    }
+
+    #[test]
+    fn test_exemplars_carry_active_trace_id() {
+        use tracing_subscriber::prelude::*;
+
+        let tracer = SdkTracerProvider::builder().build().tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let mut config = ObservabilityConfig::default();
+        config.tracing.enabled = true;
+        config.metrics.exemplars = true;
+        let metrics = EnhancedMetrics::new(&config);
+
+        let trace_id = tracing::subscriber::with_default(subscriber, || {
+            let _guard = info_span!("http_request").entered();
+            metrics.record_origin_request("example", StatusCode::OK, Duration::from_millis(70), 10);
+            current_trace_id()
+        })
+        .expect("active span has a trace ID");
+
+        let output = metrics.gather_openmetrics();
+        let exemplar = format!(r#"# {{trace_id="{}"}} 0.07 "#, trace_id);
+        assert!(output.lines().any(|line| {
+            line.starts_with(
+                r#"screaming_eagle_cdn_origin_latency_seconds_bucket{origin="example",le="0.1"} 1 "#,
+            ) && line.contains(&exemplar)
+        }));
+        assert!(current_trace_id().is_none());
+    }
+
     #[tokio::test]
     async fn test_alert_evaluator() {
         let evaluator = AlertEvaluator::new(AlertThresholds::default());
@@ -1,16 +1,29 @@
-use bytes::Bytes;
-use reqwest::{Client, Response, header};
-use std::collections::HashMap;
-use std::time::Duration;
+use bytes::{Bytes, BytesMut};
+use reqwest::{
+    Certificate, Client, ClientBuilder, Identity, Method, Proxy, RequestBuilder, Response, header,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info, warn};
 
-use crate::config::{ConnectionPoolConfig, OriginConfig};
+use crate::chaos::{FaultInjector, FaultKind, InjectedFault};
+use crate::config::{
+    ConnectionPoolConfig, OriginConfig, OriginErrorPolicyConfig, OriginTlsConfig,
+    redact_url_credentials,
+};
 use crate::error::{CdnError, CdnResult};
+use crate::headers::ResponseHeaders;
+use crate::metrics::Metrics;
+use crate::pool_sizing::{AdaptivePool, ConcurrencyGauge, OriginPoolStatus};
+use crate::validation::{DeclaredBody, VALIDATION_FAILED_HEADER, validate};
 
 #[derive(Debug, Clone)]
 pub struct OriginResponse {
     pub status_code: u16,
-    pub headers: HashMap<String, String>,
+    pub headers: ResponseHeaders,
     pub body: Bytes,
     pub content_type: Option<String>,
     pub etag: Option<String>,
@@ -18,9 +31,264 @@ pub struct OriginResponse {
     pub cache_control: Option<String>,
 }
 
+/// Why an origin fetch failed, so retries, the circuit breaker and
+/// stale-if-error can treat "network flapped" apart from "origin crashed"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OriginErrorKind {
+    /// TCP connection refused, reset or unroutable
+    ConnectError,
+    /// The origin's hostname didn't resolve
+    DnsError,
+    /// The whole request took longer than the origin's total timeout
+    Timeout,
+    /// No connection was established within the connect timeout
+    ConnectTimeout,
+    /// Connected, but the response didn't start within `time_to_first_byte_secs`
+    FirstByteTimeout,
+    /// TLS handshake or certificate verification failed
+    TlsError,
+    /// The origin answered with a 5xx status
+    Http5xx(u16),
+    /// The connection broke, or the body couldn't be decoded, mid-response
+    BodyError,
+    /// Anything else reqwest reports (redirect loops, malformed requests)
+    Other,
+}
+
+impl OriginErrorKind {
+    /// Label used in metrics, logs and the `[origin_errors]` config
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OriginErrorKind::ConnectError => "connect",
+            OriginErrorKind::DnsError => "dns",
+            OriginErrorKind::Timeout => "timeout",
+            OriginErrorKind::ConnectTimeout => "connect_timeout",
+            OriginErrorKind::FirstByteTimeout => "first_byte_timeout",
+            OriginErrorKind::TlsError => "tls",
+            OriginErrorKind::Http5xx(_) => "http_5xx",
+            OriginErrorKind::BodyError => "body",
+            OriginErrorKind::Other => "other",
+        }
+    }
+
+    /// The kind for an origin response status, if it's a server error
+    pub fn from_status(status: u16) -> Option<Self> {
+        (500..600)
+            .contains(&status)
+            .then_some(OriginErrorKind::Http5xx(status))
+    }
+
+    /// Classify a reqwest failure
+    ///
+    /// reqwest only flags connect/timeout/body errors, so DNS and TLS
+    /// failures are recognised from the messages in the source chain.
+    pub fn classify(err: &reqwest::Error) -> Self {
+        if err.is_timeout() {
+            return if err.is_connect() {
+                OriginErrorKind::ConnectTimeout
+            } else {
+                OriginErrorKind::Timeout
+            };
+        }
+        if err.is_body() || err.is_decode() {
+            return OriginErrorKind::BodyError;
+        }
+        if err.is_connect() {
+            let chain = error_chain(err).to_lowercase();
+            return if chain.contains("dns error") || chain.contains("failed to lookup address") {
+                OriginErrorKind::DnsError
+            } else if chain.contains("certificate")
+                || chain.contains("handshake")
+                || chain.contains("tls")
+            {
+                OriginErrorKind::TlsError
+            } else {
+                OriginErrorKind::ConnectError
+            };
+        }
+        if let Some(status) = err.status().and_then(|s| Self::from_status(s.as_u16())) {
+            return status;
+        }
+        OriginErrorKind::Other
+    }
+}
+
+impl std::fmt::Display for OriginErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OriginErrorKind::Http5xx(status) => write!(f, "http_5xx ({})", status),
+            other => f.write_str(other.as_str()),
+        }
+    }
+}
+
+/// Run one fetch attempt under an injected fault
+///
+/// Bodies are buffered whole, so a drip-fed body is a fetch followed by the
+/// time the body would take to arrive. Delays and drips that would outlast
+/// the origin timeout fail as a timeout once it's reached.
+async fn inject_fault(
+    fault: InjectedFault,
+    origin_name: &str,
+    origin: &OriginConfig,
+    fetch: impl Future<Output = CdnResult<OriginResponse>>,
+) -> CdnResult<OriginResponse> {
+    warn!(
+        origin = %origin_name,
+        rule = %fault.rule_id,
+        fault = fault.kind.as_str(),
+        "Injecting fault into origin fetch"
+    );
+    let timeout = origin.timeout();
+    let timed_out = || CdnError::OriginFetch {
+        kind: OriginErrorKind::Timeout,
+        message: format!(
+            "Injected fault {}: exceeded the origin timeout",
+            fault.rule_id
+        ),
+    };
+
+    match fault.kind {
+        FaultKind::Delay { ms } => {
+            let delay = Duration::from_millis(ms);
+            if delay >= timeout {
+                tokio::time::sleep(timeout).await;
+                return Err(timed_out());
+            }
+            tokio::time::sleep(delay).await;
+            fetch.await
+        }
+        FaultKind::Error { status } => {
+            let mut headers = ResponseHeaders::new();
+            headers.insert("x-cdn-fault", fault.rule_id.as_str());
+            Ok(OriginResponse {
+                status_code: status,
+                headers,
+                body: Bytes::new(),
+                content_type: None,
+                etag: None,
+                last_modified: None,
+                cache_control: None,
+            })
+        }
+        FaultKind::Abort => Err(CdnError::OriginFetch {
+            kind: OriginErrorKind::ConnectError,
+            message: format!("Injected fault {}: connection aborted", fault.rule_id),
+        }),
+        FaultKind::Drip { bytes_per_sec } => {
+            let response = fetch.await?;
+            let drip = Duration::from_secs_f64(response.body.len() as f64 / bytes_per_sec as f64);
+            if drip >= timeout {
+                tokio::time::sleep(timeout).await;
+                return Err(timed_out());
+            }
+            tokio::time::sleep(drip).await;
+            Ok(response)
+        }
+    }
+}
+
+/// An error and all of its sources, joined for inspection
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut chain = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        chain.push_str(": ");
+        chain.push_str(&err.to_string());
+        source = err.source();
+    }
+    chain
+}
+
+/// How an origin request differs from a plain GET or HEAD
+#[derive(Debug, Clone, Copy, Default)]
+struct FetchOptions<'a> {
+    /// Byte range to request; see [`OriginFetcher::fetch_range`]
+    range: Option<&'a str>,
+    /// Ask the origin, and any cache in front of it, for a fresh response
+    no_cache: bool,
+}
+
 pub struct OriginFetcher {
     client: Client,
-    origins: HashMap<String, OriginConfig>,
+    /// Registered origins; replaced or removed at runtime through the admin API
+    origins: RwLock<HashMap<String, Arc<OriginSlot>>>,
+    pool_config: ConnectionPoolConfig,
+    /// Extra request headers passed through to origins (lowercase)
+    forwarded_headers: HashSet<String>,
+    /// Extra origin response headers kept for the CDN's own use
+    kept_response_headers: Vec<String>,
+    /// Number of recent fetches kept per origin
+    latency_window: usize,
+    /// Which kinds of failure are retried
+    error_policy: OriginErrorPolicyConfig,
+    /// Admin-configured faults applied before or around each attempt
+    faults: Option<Arc<FaultInjector>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+/// An origin's config and the state that lives as long as it is registered
+///
+/// Fetches hold their own reference, so a fetch started before the origin
+/// was updated or removed finishes against the config it started with.
+struct OriginSlot {
+    config: Arc<OriginConfig>,
+    /// Dedicated client for unix socket, proxy and custom TLS, HTTP or pool
+    /// origins; replaced when an adaptive pool is resized
+    client: RwLock<Option<Client>>,
+    /// Recent fetch latencies, for adaptive stale serving
+    latencies: Mutex<LatencyWindow>,
+    /// Fetches in flight, sampled for pool sizing
+    concurrency: ConcurrencyGauge,
+    /// Recent peak concurrency and the idle limit the client was built with
+    pool: Mutex<AdaptivePool>,
+}
+
+/// Encodings requested from origins; the client decodes both before caching
+const ORIGIN_ACCEPT_ENCODING: &str = "gzip, br";
+
+/// Fewest samples before a p95 is reported; below this it's mostly noise
+const MIN_LATENCY_SAMPLES: usize = 10;
+
+/// Default number of recent fetches kept per origin
+const DEFAULT_LATENCY_WINDOW: usize = 100;
+
+/// Ring buffer of an origin's most recent fetch latencies
+#[derive(Debug)]
+struct LatencyWindow {
+    samples: Vec<Duration>,
+    capacity: usize,
+    next: usize,
+}
+
+impl LatencyWindow {
+    fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            samples: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        if self.samples.len() < self.capacity {
+            self.samples.push(latency);
+        } else {
+            self.samples[self.next] = latency;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    fn p95(&self) -> Option<Duration> {
+        if self.samples.len() < MIN_LATENCY_SAMPLES.min(self.capacity) {
+            return None;
+        }
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        let rank = (sorted.len() * 95).div_ceil(100);
+        sorted.get(rank.saturating_sub(1)).copied()
+    }
 }
 
 impl OriginFetcher {
@@ -32,32 +300,7 @@ impl OriginFetcher {
         origins: HashMap<String, OriginConfig>,
         pool_config: ConnectionPoolConfig,
     ) -> CdnResult<Self> {
-        let mut builder = Client::builder()
-            .gzip(true)
-            .brotli(true)
-            .pool_max_idle_per_host(pool_config.max_idle_per_host)
-            .pool_idle_timeout(Duration::from_secs(pool_config.idle_timeout_secs))
-            .connect_timeout(Duration::from_secs(pool_config.connect_timeout_secs))
-            .tcp_nodelay(pool_config.tcp_nodelay);
-
-        // Configure TCP keepalive
-        if pool_config.tcp_keepalive {
-            builder =
-                builder.tcp_keepalive(Duration::from_secs(pool_config.tcp_keepalive_interval_secs));
-        }
-
-        // Configure HTTP/2
-        if pool_config.http2_enabled {
-            builder = builder
-                .http2_prior_knowledge()
-                .http2_initial_stream_window_size(pool_config.http2_initial_stream_window_size)
-                .http2_initial_connection_window_size(
-                    pool_config.http2_initial_connection_window_size,
-                )
-                .http2_adaptive_window(true);
-        }
-
-        let client = builder
+        let client = pooled_client_builder(&pool_config)
             .build()
             .map_err(|e| CdnError::Internal(format!("Failed to create HTTP client: {}", e)))?;
 
@@ -71,7 +314,332 @@ impl OriginFetcher {
             "Initialized HTTP client with connection pool"
         );
 
-        Ok(Self { client, origins })
+        let fetcher = Self {
+            client,
+            origins: RwLock::new(HashMap::new()),
+            pool_config,
+            forwarded_headers: HashSet::new(),
+            kept_response_headers: Vec::new(),
+            latency_window: DEFAULT_LATENCY_WINDOW,
+            error_policy: OriginErrorPolicyConfig::default(),
+            faults: None,
+            metrics: None,
+        };
+        for (name, origin) in origins {
+            fetcher.upsert_origin(&name, origin)?;
+        }
+        Ok(fetcher)
+    }
+
+    /// Register an origin, replacing any origin of the same name
+    ///
+    /// Fetches already in progress finish against the old config.
+    pub fn upsert_origin(&self, name: &str, origin: OriginConfig) -> CdnResult<()> {
+        // Unix socket, proxy and custom TLS, HTTP or pool origins get their own
+        // client; everything else shares the default client
+        let max_idle = origin.pool.max_idle(&self.pool_config);
+        let client = if origin.needs_dedicated_client() {
+            let client = self.origin_client(name, &origin, max_idle)?;
+            if origin.unix_socket_path().is_some() {
+                info!(origin = %name, socket = %origin.url, "Using unix socket transport for origin");
+            }
+            if let Some(proxy_url) = &origin.proxy_url {
+                info!(origin = %name, proxy = %redact_url_credentials(proxy_url), "Using forward proxy for origin");
+            }
+            Some(client)
+        } else {
+            None
+        };
+
+        let slot = OriginSlot {
+            config: Arc::new(origin),
+            client: RwLock::new(client),
+            latencies: Mutex::new(LatencyWindow::new(self.latency_window)),
+            concurrency: ConcurrencyGauge::default(),
+            pool: Mutex::new(AdaptivePool::new(
+                max_idle,
+                self.pool_config.adaptive_samples(),
+            )),
+        };
+        self.origins
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name.to_string(), Arc::new(slot));
+        Ok(())
+    }
+
+    /// A dedicated client for an origin, keeping `max_idle` idle connections
+    fn origin_client(
+        &self,
+        name: &str,
+        origin: &OriginConfig,
+        max_idle: usize,
+    ) -> CdnResult<Client> {
+        let builder = pooled_client_builder(&self.pool_config)
+            .pool_max_idle_per_host(max_idle)
+            .pool_idle_timeout(origin.pool.idle_timeout(&self.pool_config));
+        configure_origin_client(builder, name, origin)?
+            .build()
+            .map_err(|e| {
+                CdnError::Internal(format!(
+                    "Failed to create HTTP client for origin {}: {}",
+                    name, e
+                ))
+            })
+    }
+
+    /// The client an origin's requests go out on
+    fn client_for(&self, slot: &OriginSlot) -> Client {
+        slot.client
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .unwrap_or_else(|| self.client.clone())
+    }
+
+    /// Take every origin's peak concurrency since the last call, and rebuild
+    /// the client of each adaptive origin whose idle target has moved
+    pub fn adapt_pools(&self) {
+        let slots: Vec<(String, Arc<OriginSlot>)> = self
+            .origins
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(name, slot)| (name.clone(), slot.clone()))
+            .collect();
+
+        for (name, slot) in slots {
+            let origin = slot.config.as_ref();
+            let mut pool = slot.pool.lock().unwrap_or_else(|e| e.into_inner());
+            pool.observe(slot.concurrency.take_peak());
+            let watermark = pool.watermark().unwrap_or(0);
+
+            let resize = if origin.pool.adaptive {
+                pool.resize(
+                    origin.pool.min_idle,
+                    origin.pool.max_idle(&self.pool_config),
+                )
+            } else {
+                None
+            };
+            if let Some(max_idle) = resize {
+                match self.origin_client(&name, origin, max_idle) {
+                    Ok(client) => {
+                        *slot.client.write().unwrap_or_else(|e| e.into_inner()) = Some(client);
+                        info!(
+                            origin = %name,
+                            from = pool.max_idle(),
+                            to = max_idle,
+                            watermark,
+                            "Resized origin connection pool"
+                        );
+                        if let Some(metrics) = &self.metrics {
+                            let direction = if max_idle > pool.max_idle() {
+                                "grow"
+                            } else {
+                                "shrink"
+                            };
+                            metrics.record_origin_pool_resize(&name, direction);
+                        }
+                        pool.resized(max_idle);
+                    }
+                    Err(e) => {
+                        warn!(origin = %name, error = %e, "Failed to resize origin connection pool");
+                    }
+                }
+            }
+
+            if let Some(metrics) = &self.metrics {
+                metrics.set_origin_pool(&name, pool.max_idle(), watermark);
+            }
+        }
+    }
+
+    /// Each origin's pool settings and observed concurrency
+    pub fn pool_status(&self) -> BTreeMap<String, OriginPoolStatus> {
+        self.origins
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(name, slot)| {
+                let origin = slot.config.as_ref();
+                let pool = slot.pool.lock().unwrap_or_else(|e| e.into_inner());
+                let status = OriginPoolStatus {
+                    max_idle_per_host: pool.max_idle(),
+                    idle_timeout_secs: origin.pool.idle_timeout(&self.pool_config).as_secs(),
+                    adaptive: origin.pool.adaptive,
+                    in_flight: slot.concurrency.in_flight(),
+                    concurrency_watermark: pool.watermark(),
+                };
+                (name.clone(), status)
+            })
+            .collect()
+    }
+
+    /// Unregister an origin; fetches already in progress still finish
+    pub fn remove_origin(&self, name: &str) -> bool {
+        self.origins
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(name)
+            .is_some()
+    }
+
+    fn slot(&self, name: &str) -> Option<Arc<OriginSlot>> {
+        self.origins
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(name)
+            .cloned()
+    }
+
+    /// Retry only the kinds of failure the policy allows
+    pub fn with_error_policy(mut self, policy: OriginErrorPolicyConfig) -> Self {
+        self.error_policy = policy;
+        self
+    }
+
+    /// Apply the injector's fault rules to every fetch attempt
+    pub fn with_fault_injector(mut self, faults: Arc<FaultInjector>) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Count origin responses that fail validation
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Compute each origin's latency p95 over its last `size` fetches
+    pub fn with_latency_window(mut self, size: usize) -> Self {
+        self.latency_window = size;
+        for slot in self
+            .origins
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+        {
+            *slot.latencies.lock().unwrap_or_else(|e| e.into_inner()) = LatencyWindow::new(size);
+        }
+        self
+    }
+
+    /// 95th percentile latency of an origin's recent fetches
+    ///
+    /// `None` until enough fetches have been seen to make it meaningful.
+    pub fn latency_p95(&self, origin_name: &str) -> Option<Duration> {
+        self.slot(origin_name)?
+            .latencies
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .p95()
+    }
+
+    /// Open `connection_pool.preconnect_count` connections to an origin
+    ///
+    /// The HEADs go out concurrently so each needs its own connection, which
+    /// the pool keeps once answered. Failures are only logged; returns how
+    /// many connected.
+    pub async fn preconnect(&self, origin_name: &str) -> usize {
+        let Some(slot) = self.slot(origin_name) else {
+            return 0;
+        };
+        let origin = slot.config.as_ref();
+        let url = join_origin_url(&origin.base_url(), origin.preconnect_target(), None);
+        let count = self.pool_config.preconnect_count;
+
+        let attempts = (0..count).map(|_| {
+            let request =
+                self.probe_request(&slot, Method::HEAD, &url, origin.health_check_timeout());
+            async move {
+                let started = Instant::now();
+                let result = request.send().await;
+                (result, started.elapsed())
+            }
+        });
+
+        let mut connected = 0;
+        for (result, elapsed) in futures::future::join_all(attempts).await {
+            if let Err(e) = &result {
+                debug!(origin = %origin_name, url = %url, error = %e, "Origin preconnect failed");
+            } else {
+                connected += 1;
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.record_preconnect(origin_name, result.is_ok(), elapsed);
+            }
+        }
+        debug!(origin = %origin_name, connected, attempted = count, "Preconnected to origin");
+        connected
+    }
+
+    /// Send one request for `path` to an origin, over the client and
+    /// connection pool its fetches use
+    ///
+    /// Only the origin's `host_header` and configured headers are added:
+    /// there are no retries, injected faults or latency samples, and the
+    /// response is returned as it arrived. Health checks use it to see the
+    /// same connections and protocol as real traffic.
+    pub async fn send_request(
+        &self,
+        origin_name: &str,
+        method: Method,
+        path: &str,
+        timeout: Duration,
+    ) -> CdnResult<Response> {
+        let slot = self
+            .slot(origin_name)
+            .ok_or_else(|| CdnError::ConfigError(format!("Unknown origin: {}", origin_name)))?;
+        let url = join_origin_url(&slot.config.base_url(), path, None);
+        Ok(self
+            .probe_request(&slot, method, &url, timeout)
+            .send()
+            .await?)
+    }
+
+    /// A bare request to an origin, with its Host override and headers
+    fn probe_request(
+        &self,
+        slot: &OriginSlot,
+        method: Method,
+        url: &str,
+        timeout: Duration,
+    ) -> RequestBuilder {
+        let origin = slot.config.as_ref();
+        let mut request = self.client_for(slot).request(method, url).timeout(timeout);
+        if let Some(ref host) = origin.host_header {
+            request = request.header(header::HOST, host);
+        }
+        for (key, value) in &origin.headers {
+            request = request.header(key.as_str(), value.as_str());
+        }
+        request
+    }
+
+    /// Preconnect to an origin in the background; nothing when
+    /// `connection_pool.preconnect_count` is 0
+    pub fn spawn_preconnect(self: &Arc<Self>, origin_name: &str) {
+        if self.pool_config.preconnect_count == 0 {
+            return;
+        }
+        let fetcher = self.clone();
+        let origin_name = origin_name.to_string();
+        tokio::spawn(async move {
+            fetcher.preconnect(&origin_name).await;
+        });
+    }
+
+    /// Forward these request headers to origins in addition to the default safe set
+    pub fn with_forwarded_headers(mut self, headers: impl IntoIterator<Item = String>) -> Self {
+        self.forwarded_headers = headers.into_iter().map(|h| h.to_lowercase()).collect();
+        self
+    }
+
+    /// Keep these origin response headers too, for the CDN to act on
+    pub fn with_kept_response_headers(mut self, headers: impl IntoIterator<Item = String>) -> Self {
+        self.kept_response_headers = headers.into_iter().map(|h| h.to_lowercase()).collect();
+        self
     }
 
     pub async fn fetch(
@@ -81,14 +649,109 @@ impl OriginFetcher {
         query: Option<&str>,
         request_headers: &HashMap<String, String>,
     ) -> CdnResult<OriginResponse> {
-        let origin = self
-            .origins
-            .get(origin_name)
+        self.fetch_with_method(
+            Method::GET,
+            origin_name,
+            path,
+            query,
+            request_headers,
+            FetchOptions::default(),
+        )
+        .await
+    }
+
+    /// Fetch with `Cache-Control: no-cache` and `Pragma: no-cache`, so the
+    /// origin's own caches are told to revalidate
+    pub async fn fetch_uncached(
+        &self,
+        origin_name: &str,
+        path: &str,
+        query: Option<&str>,
+        request_headers: &HashMap<String, String>,
+    ) -> CdnResult<OriginResponse> {
+        let options = FetchOptions {
+            no_cache: true,
+            ..Default::default()
+        };
+        self.fetch_with_method(
+            Method::GET,
+            origin_name,
+            path,
+            query,
+            request_headers,
+            options,
+        )
+        .await
+    }
+
+    /// Issue a HEAD to the origin
+    ///
+    /// The returned body is empty; the origin's Content-Length (when it sent
+    /// one) is kept in the headers so it can be passed on to clients.
+    pub async fn fetch_head(
+        &self,
+        origin_name: &str,
+        path: &str,
+        query: Option<&str>,
+        request_headers: &HashMap<String, String>,
+    ) -> CdnResult<OriginResponse> {
+        self.fetch_with_method(
+            Method::HEAD,
+            origin_name,
+            path,
+            query,
+            request_headers,
+            FetchOptions::default(),
+        )
+        .await
+    }
+
+    /// Fetch a byte range of an object, e.g. `bytes=0-1048575`
+    ///
+    /// The origin's response is returned as-is: a 206 with its Content-Range
+    /// when it honours the range, or the whole object when it doesn't. The
+    /// range is requested uncompressed so partial bodies are never gzip
+    /// fragments.
+    pub async fn fetch_range(
+        &self,
+        origin_name: &str,
+        path: &str,
+        query: Option<&str>,
+        request_headers: &HashMap<String, String>,
+        range: &str,
+    ) -> CdnResult<OriginResponse> {
+        self.fetch_with_method(
+            Method::GET,
+            origin_name,
+            path,
+            query,
+            request_headers,
+            FetchOptions {
+                range: Some(range),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    async fn fetch_with_method(
+        &self,
+        method: Method,
+        origin_name: &str,
+        path: &str,
+        query: Option<&str>,
+        request_headers: &HashMap<String, String>,
+        options: FetchOptions<'_>,
+    ) -> CdnResult<OriginResponse> {
+        let slot = self
+            .slot(origin_name)
             .ok_or_else(|| CdnError::ConfigError(format!("Unknown origin: {}", origin_name)))?;
+        let origin = slot.config.as_ref();
+        let _in_flight = slot.concurrency.enter();
 
-        let url = self.build_url(&origin.url, path, query)?;
+        let url = origin.request_url(path, query);
 
-        info!(origin = %origin_name, url = %url, "Fetching from origin");
+        info!(origin = %origin_name, url = %url, method = %method, "Fetching from origin");
 
         let mut attempt = 0;
         let max_retries = origin.max_retries;
@@ -96,42 +759,98 @@ impl OriginFetcher {
         loop {
             attempt += 1;
 
-            match self.do_fetch(&url, origin, request_headers).await {
-                Ok(response) => return Ok(response),
-                Err(e) => {
-                    if attempt >= max_retries {
-                        error!(
-                            origin = %origin_name,
-                            attempt = attempt,
-                            error = %e,
-                            "All origin fetch attempts failed"
-                        );
-                        return Err(e);
-                    }
+            // Failed attempts count too: a timeout is the slowest response of all
+            let started = Instant::now();
+            let client = self.client_for(&slot);
+            let fetch = self.do_fetch(
+                &client,
+                origin_name,
+                method.clone(),
+                &url,
+                origin,
+                request_headers,
+                options,
+            );
+            let fault = self
+                .faults
+                .as_ref()
+                .and_then(|faults| faults.pick(origin_name, path));
+            let result = match fault {
+                Some(fault) => inject_fault(fault, origin_name, origin, fetch).await,
+                None => fetch.await,
+            };
+            let elapsed = started.elapsed();
+            slot.latencies
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .record(elapsed);
+            if let Some(metrics) = &self.metrics {
+                metrics.record_origin_latency(origin_name, elapsed);
+            }
 
-                    warn!(
+            let kind = match &result {
+                Ok(response) => OriginErrorKind::from_status(response.status_code),
+                Err(e) => Some(e.origin_error_kind().unwrap_or(OriginErrorKind::Other)),
+            };
+            let Some(kind) = kind else {
+                return result;
+            };
+
+            let retryable = self.error_policy.for_kind(kind).retry;
+            if !retryable || attempt >= max_retries {
+                // A 5xx is still the origin's answer; pass it on unretried
+                if let Err(e) = &result {
+                    error!(
                         origin = %origin_name,
                         attempt = attempt,
-                        max_retries = max_retries,
+                        error_kind = kind.as_str(),
                         error = %e,
-                        "Origin fetch failed, retrying"
+                        "Origin fetch failed, giving up"
                     );
-
-                    // Exponential backoff
-                    let delay = Duration::from_millis(100 * 2u64.pow(attempt - 1));
-                    tokio::time::sleep(delay).await;
                 }
+                return result;
+            }
+
+            match &result {
+                Ok(response) => warn!(
+                    origin = %origin_name,
+                    attempt = attempt,
+                    max_retries = max_retries,
+                    status = response.status_code,
+                    "Origin returned a server error, retrying"
+                ),
+                Err(e) => warn!(
+                    origin = %origin_name,
+                    attempt = attempt,
+                    max_retries = max_retries,
+                    error_kind = kind.as_str(),
+                    error = %e,
+                    "Origin fetch failed, retrying"
+                ),
             }
+
+            // Exponential backoff
+            let delay = Duration::from_millis(100 * 2u64.pow(attempt - 1));
+            tokio::time::sleep(delay).await;
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn do_fetch(
         &self,
+        client: &Client,
+        origin_name: &str,
+        method: Method,
         url: &str,
         origin: &OriginConfig,
         request_headers: &HashMap<String, String>,
+        options: FetchOptions<'_>,
     ) -> CdnResult<OriginResponse> {
-        let mut request = self.client.get(url).timeout(origin.timeout());
+        let is_head = method == Method::HEAD;
+        let first_byte_deadline = origin
+            .time_to_first_byte()
+            .map(|ttfb| tokio::time::Instant::now() + ttfb);
+        let mut request = client.request(method, url).timeout(origin.timeout());
 
         // Set Host header if configured
         if let Some(ref host) = origin.host_header {
@@ -146,26 +865,126 @@ impl OriginFetcher {
         // Forward relevant request headers
         for (key, value) in request_headers {
             let key_lower = key.to_lowercase();
+            if key_lower == "accept-encoding" {
+                continue;
+            }
             // Only forward safe headers
             if matches!(
                 key_lower.as_str(),
-                "accept"
-                    | "accept-encoding"
-                    | "accept-language"
-                    | "if-none-match"
-                    | "if-modified-since"
-            ) {
+                "accept" | "accept-language" | "if-none-match" | "if-modified-since"
+            ) || self.forwarded_headers.contains(&key_lower)
+            {
                 request = request.header(key.as_str(), value.as_str());
             }
         }
 
-        let response = request.send().await?;
-        self.parse_response(response).await
+        // The origin-side encoding is ours to choose, not the client's: bodies
+        // are stored decoded and compressed per client on the way out. HEAD and
+        // range requests ask for identity so lengths and offsets describe that
+        // body, as do origins whose digests are verified.
+        let accept_encoding = if origin.request_compression
+            && origin.http.decompress
+            && !origin.validation.verify_digest
+            && !is_head
+            && options.range.is_none()
+        {
+            ORIGIN_ACCEPT_ENCODING
+        } else {
+            "identity"
+        };
+        request = request.header(header::ACCEPT_ENCODING, accept_encoding);
+
+        // Client Range headers are never forwarded implicitly, or a partial
+        // body could be cached as the whole object
+        if let Some(range) = options.range {
+            request = request.header(header::RANGE, range);
+        }
+
+        if options.no_cache {
+            request = request
+                .header(header::CACHE_CONTROL, "no-cache")
+                .header(header::PRAGMA, "no-cache");
+        }
+
+        let response = match first_byte_deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, request.send())
+                .await
+                .map_err(|_| first_byte_timeout())??,
+            None => request.send().await?,
+        };
+
+        // HEAD responses have no body to measure, so keep the origin's length
+        let content_length = if is_head {
+            response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .map(|s| s.to_string())
+        } else {
+            None
+        };
+
+        let declared = (!is_head).then(|| DeclaredBody::from_headers(response.headers()));
+
+        let mut parsed = self
+            .parse_response(
+                response,
+                origin.cookie_rewrite.pass_through,
+                first_byte_deadline,
+            )
+            .await?;
+        if let Some(length) = content_length {
+            parsed.headers.insert("content-length", length);
+        }
+
+        // Served as usual, but marked so it's never cached
+        if let Some(declared) = declared
+            && let Err(failure) = validate(
+                &origin.validation,
+                parsed.status_code,
+                &declared,
+                &parsed.body,
+            )
+        {
+            warn!(
+                origin = %origin_name,
+                url = %url,
+                reason = failure.reason(),
+                failure = %failure,
+                "Origin response failed validation, not caching it"
+            );
+            if let Some(metrics) = &self.metrics {
+                metrics.record_origin_validation_failure(origin_name, failure.reason());
+            }
+            parsed
+                .headers
+                .insert(VALIDATION_FAILED_HEADER, failure.reason());
+        }
+        Ok(parsed)
     }
 
-    async fn parse_response(&self, response: Response) -> CdnResult<OriginResponse> {
+    async fn parse_response(
+        &self,
+        response: Response,
+        pass_set_cookie: bool,
+        first_byte_deadline: Option<tokio::time::Instant>,
+    ) -> CdnResult<OriginResponse> {
         let status_code = response.status().as_u16();
-        let headers = self.extract_headers(&response);
+        let mut headers = self.extract_headers(&response, pass_set_cookie);
+
+        // The client decodes gzip and br and drops their Content-Encoding, so
+        // one left here wasn't decoded and must stay with the still-encoded body
+        if let Some(encoding) = headers.get("content-encoding").cloned() {
+            if encoding.trim().eq_ignore_ascii_case("identity") {
+                headers.remove("content-encoding");
+            } else {
+                warn!(
+                    url = %response.url(),
+                    encoding = %encoding,
+                    "Origin response uses an encoding the fetcher can't decode; storing it encoded"
+                );
+            }
+        }
 
         let content_type = response
             .headers()
@@ -191,7 +1010,7 @@ impl OriginFetcher {
             .and_then(|v| v.to_str().ok())
             .map(|s| s.to_string());
 
-        let body = response.bytes().await?;
+        let body = read_body(response, first_byte_deadline).await?;
 
         debug!(
             status_code = status_code,
@@ -211,8 +1030,8 @@ impl OriginFetcher {
         })
     }
 
-    fn extract_headers(&self, response: &Response) -> HashMap<String, String> {
-        let mut headers = HashMap::new();
+    fn extract_headers(&self, response: &Response, pass_set_cookie: bool) -> ResponseHeaders {
+        let mut headers = ResponseHeaders::new();
 
         // Headers to forward from origin
         let forward_headers = [
@@ -224,45 +1043,420 @@ impl OriginFetcher {
             header::LAST_MODIFIED,
             header::VARY,
             header::CONTENT_DISPOSITION,
+            header::CONTENT_RANGE,
             header::ACCESS_CONTROL_ALLOW_ORIGIN,
             header::ACCESS_CONTROL_ALLOW_METHODS,
             header::ACCESS_CONTROL_ALLOW_HEADERS,
             header::ACCESS_CONTROL_MAX_AGE,
         ];
 
-        for header_name in forward_headers {
-            if let Some(value) = response.headers().get(&header_name)
-                && let Ok(v) = value.to_str() {
-                    headers.insert(header_name.to_string(), v.to_string());
+        // Keep every value so repeated headers aren't collapsed
+        let set_cookie = pass_set_cookie.then_some(header::SET_COOKIE);
+        for header_name in forward_headers.into_iter().chain(set_cookie) {
+            for value in response.headers().get_all(&header_name) {
+                if let Ok(v) = value.to_str() {
+                    headers.append(header_name.as_str(), v);
+                }
+            }
+        }
+        for header_name in &self.kept_response_headers {
+            for value in response.headers().get_all(header_name.as_str()) {
+                if let Ok(v) = value.to_str() {
+                    headers.append(header_name, v);
                 }
+            }
         }
 
         headers
     }
 
-    fn build_url(&self, base: &str, path: &str, query: Option<&str>) -> CdnResult<String> {
-        let base = base.trim_end_matches('/');
-        let path = if path.starts_with('/') {
-            path.to_string()
-        } else {
-            format!("/{}", path)
-        };
+    pub fn has_origin(&self, name: &str) -> bool {
+        self.slot(name).is_some()
+    }
 
-        let url = match query {
-            Some(q) if !q.is_empty() => format!("{}{}?{}", base, path, q),
-            _ => format!("{}{}", base, path),
-        };
+    /// Whether requests to this origin go through a forward proxy
+    pub fn is_proxied(&self, name: &str) -> bool {
+        self.slot(name)
+            .is_some_and(|slot| slot.config.proxy_url.is_some())
+    }
 
-        Ok(url)
+    pub fn origin_names(&self) -> Vec<String> {
+        self.origins
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .keys()
+            .cloned()
+            .collect()
     }
 
-    pub fn has_origin(&self, name: &str) -> bool {
-        self.origins.contains_key(name)
+    /// Config of a registered origin
+    pub fn origin_config(&self, name: &str) -> Option<Arc<OriginConfig>> {
+        self.slot(name).map(|slot| slot.config.clone())
+    }
+
+    /// Configs of all registered origins, by name
+    pub fn origin_configs(&self) -> BTreeMap<String, Arc<OriginConfig>> {
+        self.origins
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(name, slot)| (name.clone(), slot.config.clone()))
+            .collect()
+    }
+}
+
+/// Read a response body, failing if none of it arrives by `first_byte_deadline`
+///
+/// Without a deadline the body is read whole; with one it's streamed so the
+/// first chunk can be waited on alone. After that only the total timeout
+/// applies, so a slow but steady body isn't cut off.
+async fn read_body(
+    mut response: Response,
+    first_byte_deadline: Option<tokio::time::Instant>,
+) -> CdnResult<Bytes> {
+    let Some(deadline) = first_byte_deadline else {
+        return Ok(response.bytes().await?);
+    };
+    let first = tokio::time::timeout_at(deadline, response.chunk())
+        .await
+        .map_err(|_| first_byte_timeout())??;
+    let Some(first) = first else {
+        return Ok(Bytes::new());
+    };
+    let Some(second) = response.chunk().await? else {
+        return Ok(first);
+    };
+    let mut body = BytesMut::from(first);
+    body.extend_from_slice(&second);
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
+fn first_byte_timeout() -> CdnError {
+    CdnError::OriginFetch {
+        kind: OriginErrorKind::FirstByteTimeout,
+        message: "origin didn't start responding within time_to_first_byte_secs".to_string(),
+    }
+}
+
+/// A pending [`OriginClient`] fetch
+pub type OriginFuture<'a> = Pin<Box<dyn Future<Output = CdnResult<OriginResponse>> + Send + 'a>>;
+
+/// The origin fetches a client request makes
+///
+/// Implemented by [`OriginFetcher`]; handler tests script responses with a
+/// fake instead of standing up an origin server.
+pub trait OriginClient: Send + Sync {
+    fn fetch<'a>(
+        &'a self,
+        origin_name: &'a str,
+        path: &'a str,
+        query: Option<&'a str>,
+        request_headers: &'a HashMap<String, String>,
+    ) -> OriginFuture<'a>;
+
+    /// Fetch telling the origin's own caches to revalidate
+    fn fetch_uncached<'a>(
+        &'a self,
+        origin_name: &'a str,
+        path: &'a str,
+        query: Option<&'a str>,
+        request_headers: &'a HashMap<String, String>,
+    ) -> OriginFuture<'a>;
+
+    fn fetch_head<'a>(
+        &'a self,
+        origin_name: &'a str,
+        path: &'a str,
+        query: Option<&'a str>,
+        request_headers: &'a HashMap<String, String>,
+    ) -> OriginFuture<'a>;
+
+    fn fetch_range<'a>(
+        &'a self,
+        origin_name: &'a str,
+        path: &'a str,
+        query: Option<&'a str>,
+        request_headers: &'a HashMap<String, String>,
+        range: &'a str,
+    ) -> OriginFuture<'a>;
+}
+
+impl OriginClient for OriginFetcher {
+    fn fetch<'a>(
+        &'a self,
+        origin_name: &'a str,
+        path: &'a str,
+        query: Option<&'a str>,
+        request_headers: &'a HashMap<String, String>,
+    ) -> OriginFuture<'a> {
+        Box::pin(OriginFetcher::fetch(
+            self,
+            origin_name,
+            path,
+            query,
+            request_headers,
+        ))
+    }
+
+    fn fetch_uncached<'a>(
+        &'a self,
+        origin_name: &'a str,
+        path: &'a str,
+        query: Option<&'a str>,
+        request_headers: &'a HashMap<String, String>,
+    ) -> OriginFuture<'a> {
+        Box::pin(OriginFetcher::fetch_uncached(
+            self,
+            origin_name,
+            path,
+            query,
+            request_headers,
+        ))
+    }
+
+    fn fetch_head<'a>(
+        &'a self,
+        origin_name: &'a str,
+        path: &'a str,
+        query: Option<&'a str>,
+        request_headers: &'a HashMap<String, String>,
+    ) -> OriginFuture<'a> {
+        Box::pin(OriginFetcher::fetch_head(
+            self,
+            origin_name,
+            path,
+            query,
+            request_headers,
+        ))
+    }
+
+    fn fetch_range<'a>(
+        &'a self,
+        origin_name: &'a str,
+        path: &'a str,
+        query: Option<&'a str>,
+        request_headers: &'a HashMap<String, String>,
+        range: &'a str,
+    ) -> OriginFuture<'a> {
+        Box::pin(OriginFetcher::fetch_range(
+            self,
+            origin_name,
+            path,
+            query,
+            request_headers,
+            range,
+        ))
+    }
+}
+
+/// Join an origin base URL and a request path
+///
+/// The path always lands beneath the base URL's own path. Slashes at the
+/// join collapse to one, and a trailing slash on the path is kept. `.` and
+/// `..` segments (also percent-encoded) are resolved within the path, so a
+/// request can't climb above the base. `?` and `#` in the path are encoded
+/// so they can't start a query or fragment. Other characters pass through
+/// as they are; spaces and non-ASCII are encoded when the request is built.
+pub fn join_origin_url(base: &str, path: &str, query: Option<&str>) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.trim_start_matches('/').split('/') {
+        trailing_slash = true;
+        match segment.to_ascii_lowercase().as_str() {
+            "." | "%2e" => {}
+            ".." | ".%2e" | "%2e." | "%2e%2e" => {
+                segments.pop();
+            }
+            _ => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
+        }
+    }
+    if segments.last() == Some(&"") {
+        segments.pop();
+        trailing_slash = true;
+    }
+
+    let mut url = base.trim_end_matches('/').to_string();
+    url.push('/');
+    for (i, segment) in segments.iter().enumerate() {
+        if i > 0 {
+            url.push('/');
+        }
+        for c in segment.chars() {
+            match c {
+                '?' => url.push_str("%3F"),
+                '#' => url.push_str("%23"),
+                c => url.push(c),
+            }
+        }
+    }
+    if trailing_slash && !segments.is_empty() {
+        url.push('/');
+    }
+    if let Some(query) = query.filter(|query| !query.is_empty()) {
+        url.push('?');
+        url.push_str(query);
+    }
+    url
+}
+
+/// Client builder with the shared connection pool settings applied
+fn pooled_client_builder(pool_config: &ConnectionPoolConfig) -> ClientBuilder {
+    // Decode everything in ORIGIN_ACCEPT_ENCODING so bodies are cached decoded
+    let mut builder = Client::builder()
+        .gzip(true)
+        .brotli(true)
+        .pool_max_idle_per_host(pool_config.max_idle_per_host)
+        .pool_idle_timeout(Duration::from_secs(pool_config.idle_timeout_secs))
+        .connect_timeout(Duration::from_secs(pool_config.connect_timeout_secs))
+        .tcp_nodelay(pool_config.tcp_nodelay);
+
+    // Configure TCP keepalive
+    if pool_config.tcp_keepalive {
+        builder =
+            builder.tcp_keepalive(Duration::from_secs(pool_config.tcp_keepalive_interval_secs));
+    }
+
+    // Configure HTTP/2
+    if pool_config.http2_enabled {
+        builder = builder
+            .http2_prior_knowledge()
+            .http2_initial_stream_window_size(pool_config.http2_initial_stream_window_size)
+            .http2_initial_connection_window_size(pool_config.http2_initial_connection_window_size)
+            .http2_adaptive_window(true);
+    }
+
+    builder
+}
+
+/// Certificates loaded from an origin's TLS config
+pub(crate) struct OriginTlsMaterial {
+    roots: Vec<Certificate>,
+    identity: Option<Identity>,
+}
+
+/// Read and parse the certificates named in an origin's TLS config
+pub(crate) fn load_origin_tls(name: &str, tls: &OriginTlsConfig) -> CdnResult<OriginTlsMaterial> {
+    let read = |path: &str, what: &str| {
+        std::fs::read(path).map_err(|e| {
+            CdnError::ConfigError(format!(
+                "Origin {}: failed to read {} {}: {}",
+                name, what, path, e
+            ))
+        })
+    };
+
+    let roots = match tls.ca_cert_path.as_deref() {
+        Some(path) => {
+            let roots =
+                Certificate::from_pem_bundle(&read(path, "CA certificate")?).map_err(|e| {
+                    CdnError::ConfigError(format!(
+                        "Origin {}: invalid CA certificate {}: {}",
+                        name, path, e
+                    ))
+                })?;
+            if roots.is_empty() {
+                return Err(CdnError::ConfigError(format!(
+                    "Origin {}: no certificates found in {}",
+                    name, path
+                )));
+            }
+            roots
+        }
+        None => Vec::new(),
+    };
+
+    let identity = match (
+        tls.client_cert_path.as_deref(),
+        tls.client_key_path.as_deref(),
+    ) {
+        (Some(cert_path), Some(key_path)) => {
+            let mut pem = read(cert_path, "client certificate")?;
+            pem.push(b'\n');
+            pem.extend(read(key_path, "client key")?);
+            let identity = Identity::from_pem(&pem).map_err(|e| {
+                CdnError::ConfigError(format!(
+                    "Origin {}: invalid client certificate or key ({}, {}): {}",
+                    name, cert_path, key_path, e
+                ))
+            })?;
+            Some(identity)
+        }
+        (None, None) => None,
+        _ => {
+            return Err(CdnError::ConfigError(format!(
+                "Origin {}: client_cert_path and client_key_path must be set together",
+                name
+            )));
+        }
+    };
+
+    Ok(OriginTlsMaterial { roots, identity })
+}
+
+/// Apply an origin's transport and TLS settings to a client builder
+///
+/// Routes connections through the origin's unix socket if it has one, and
+/// loads any custom CA, client certificate or verification override.
+pub(crate) fn configure_origin_client(
+    mut builder: ClientBuilder,
+    name: &str,
+    origin: &OriginConfig,
+) -> CdnResult<ClientBuilder> {
+    #[cfg(unix)]
+    if let Some(socket) = origin.unix_socket_path() {
+        builder = builder.unix_socket(socket.to_string());
+    }
+    if let Some(connect_timeout) = origin.connect_timeout() {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+
+    if let Some(proxy) = origin
+        .proxy()
+        .map_err(|e| CdnError::ConfigError(format!("Origin {}: {}", name, e)))?
+    {
+        let proxy = Proxy::all(proxy.as_str()).map_err(|e| {
+            CdnError::ConfigError(format!("Origin {}: invalid proxy_url: {}", name, e))
+        })?;
+        builder = builder.proxy(proxy);
+    }
+
+    let tls = load_origin_tls(name, &origin.tls)?;
+    for cert in tls.roots {
+        builder = builder.add_root_certificate(cert);
+    }
+    if let Some(identity) = tls.identity {
+        builder = builder.identity(identity);
+    }
+    if origin.tls.insecure_skip_verify {
+        warn!(
+            origin = %name,
+            "TLS certificate verification is DISABLED for this origin; \
+             connections are open to interception. Do not use in production."
+        );
+        builder = builder.danger_accept_invalid_certs(true);
     }
 
-    pub fn origin_names(&self) -> Vec<&str> {
-        self.origins.keys().map(|s| s.as_str()).collect()
+    let http = &origin.http;
+    if http.title_case_headers {
+        builder = builder.http1_title_case_headers();
+    }
+    if http.http1_only {
+        builder = builder.http1_only();
     }
+    if !http.decompress {
+        builder = builder.no_gzip().no_brotli();
+    }
+    if !http.follow_redirects {
+        builder = builder.redirect(reqwest::redirect::Policy::none());
+    }
+
+    Ok(builder)
 }
 
 pub async fn conditional_fetch(
@@ -292,3 +1486,601 @@ pub async fn conditional_fetch(
         Ok(Some(response))
     }
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+
+    /// Serve one HTTP/1.1 response on a unix socket, returning the request line
+    fn serve_once(listener: UnixListener) -> tokio::task::JoinHandle<String> {
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello",
+                )
+                .await
+                .unwrap();
+            let request = String::from_utf8_lossy(&request).into_owned();
+            request.lines().next().unwrap_or_default().to_string()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_fetch_over_unix_socket() {
+        let socket = std::env::temp_dir().join(format!(
+            "screaming-eagle-origin-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&socket);
+        let server = serve_once(UnixListener::bind(&socket).unwrap());
+
+        let origin: OriginConfig = toml::from_str(&format!(
+            "url = \"unix:{}\"\npath_prefix = \"/app\"\nmax_retries = 1",
+            socket.display()
+        ))
+        .unwrap();
+        let pool_config = ConnectionPoolConfig {
+            http2_enabled: false,
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::new());
+        let fetcher = OriginFetcher::with_pool_config(
+            HashMap::from([("local".to_string(), origin)]),
+            pool_config,
+        )
+        .unwrap()
+        .with_metrics(metrics.clone());
+
+        let response = fetcher
+            .fetch("local", "/index.html", Some("v=1"), &HashMap::new())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, Bytes::from("hello"));
+        assert_eq!(response.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(server.await.unwrap(), "GET /app/index.html?v=1 HTTP/1.1");
+        assert!(
+            metrics
+                .gather()
+                .contains(r#"cdn_origin_latency_seconds_count{origin="local"} 1"#)
+        );
+
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_through_http_proxy() {
+        // The origin's host doesn't resolve; only the proxy can reach it
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request).to_lowercase()
+        });
+
+        let origin: OriginConfig = toml::from_str(&format!(
+            "url = \"http://origin.invalid\"\nproxy_url = \"http://cdn:s3cr3t@{}\"\nmax_retries = 1",
+            proxy
+        ))
+        .unwrap();
+        let pool_config = ConnectionPoolConfig {
+            http2_enabled: false,
+            ..Default::default()
+        };
+        let fetcher = OriginFetcher::with_pool_config(
+            HashMap::from([("corp".to_string(), origin)]),
+            pool_config,
+        )
+        .unwrap();
+        assert!(fetcher.is_proxied("corp"));
+
+        let response = fetcher
+            .fetch("corp", "/index.html", None, &HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response.body, Bytes::from("ok"));
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("get http://origin.invalid/index.html http/1.1\r\n"));
+        // base64("cdn:s3cr3t"), lowercased with the rest of the request
+        assert!(request.contains("proxy-authorization: basic y2ruonmzy3izda==\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_picky_origin_http_options() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 302 Found\r\nLocation: /elsewhere\r\nContent-Length: 0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request).into_owned()
+        });
+
+        let origin: OriginConfig = toml::from_str(&format!(
+            "url = \"http://{}\"\nmax_retries = 1\nheaders = {{ x-legacy-token = \"abc\" }}\n\
+             [http]\ntitle_case_headers = true\nhttp1_only = true\n\
+             decompress = false\nfollow_redirects = false",
+            addr
+        ))
+        .unwrap();
+        assert!(origin.needs_dedicated_client());
+        // The pool default is HTTP/2 prior knowledge, which this server doesn't speak
+        let fetcher = OriginFetcher::with_pool_config(
+            HashMap::from([("legacy".to_string(), origin)]),
+            ConnectionPoolConfig::default(),
+        )
+        .unwrap();
+
+        let response = fetcher
+            .fetch("legacy", "/index.html", None, &HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response.status_code, 302);
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("GET /index.html HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nHost: 127.0.0.1:"));
+        assert!(request.contains("\r\nX-Legacy-Token: abc\r\n"));
+        assert!(request.contains("\r\nAccept-Encoding: identity\r\n"));
+    }
+
+    /// An origin answering one request with `parts`, `gap` apart, then
+    /// holding the connection open
+    async fn spawn_trickling_origin(parts: Vec<&'static str>, gap: Duration) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    return;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            for (i, part) in parts.into_iter().enumerate() {
+                if i > 0 {
+                    tokio::time::sleep(gap).await;
+                }
+                if stream.write_all(part.as_bytes()).await.is_err() {
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+        format!("http://{}", addr)
+    }
+
+    async fn fetch_with_timeouts(url: &str, timeouts: &str) -> CdnResult<OriginResponse> {
+        let origin: OriginConfig =
+            toml::from_str(&format!("url = \"{}\"\nmax_retries = 1\n{}", url, timeouts)).unwrap();
+        let pool_config = ConnectionPoolConfig {
+            http2_enabled: false,
+            ..Default::default()
+        };
+        let fetcher = OriginFetcher::with_pool_config(
+            HashMap::from([("slow".to_string(), origin)]),
+            pool_config,
+        )
+        .unwrap();
+        fetcher
+            .fetch("slow", "/report", None, &HashMap::new())
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_first_byte_timeout_on_a_silent_origin() {
+        let timeouts = "time_to_first_byte_secs = 1\ntotal_timeout_secs = 30";
+
+        // Accepts the request but never answers
+        let url = spawn_trickling_origin(vec![], Duration::ZERO).await;
+        let started = Instant::now();
+        let err = fetch_with_timeouts(&url, timeouts).await.unwrap_err();
+        assert_eq!(
+            err.origin_error_kind(),
+            Some(OriginErrorKind::FirstByteTimeout)
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Headers alone aren't a first byte of the body
+        let url = spawn_trickling_origin(
+            vec!["HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n"],
+            Duration::ZERO,
+        )
+        .await;
+        let err = fetch_with_timeouts(&url, timeouts).await.unwrap_err();
+        assert_eq!(
+            err.origin_error_kind(),
+            Some(OriginErrorKind::FirstByteTimeout)
+        );
+        assert_eq!(err.status_code(), axum::http::StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_trickling_body_is_bounded_only_by_the_total_timeout() {
+        // Finishes past the first-byte timeout but within the total
+        let url = spawn_trickling_origin(
+            vec![
+                "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nx",
+                "x",
+                "x",
+                "x",
+            ],
+            Duration::from_millis(500),
+        )
+        .await;
+        let response = fetch_with_timeouts(&url, "time_to_first_byte_secs = 1\ntimeout_secs = 3")
+            .await
+            .unwrap();
+        assert_eq!(response.body, Bytes::from("xxxx"));
+
+        // Keeps trickling past the total
+        let mut parts = vec!["HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nx"];
+        parts.extend(["x"; 9]);
+        let url = spawn_trickling_origin(parts, Duration::from_millis(400)).await;
+        let started = Instant::now();
+        let err = fetch_with_timeouts(&url, "time_to_first_byte_secs = 1\ntimeout_secs = 2")
+            .await
+            .unwrap_err();
+        assert_eq!(err.origin_error_kind(), Some(OriginErrorKind::Timeout));
+        assert!(started.elapsed() >= Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_injected_faults_around_the_fetch() {
+        let origin: OriginConfig = toml::from_str("url = \"http://o\"\ntimeout_secs = 1").unwrap();
+        let response = || async {
+            Ok(OriginResponse {
+                status_code: 200,
+                headers: ResponseHeaders::new(),
+                body: Bytes::from(vec![b'x'; 20]),
+                content_type: None,
+                etag: None,
+                last_modified: None,
+                cache_control: None,
+            })
+        };
+        let inject = |kind| {
+            let fault = InjectedFault {
+                rule_id: "fault-1".to_string(),
+                kind,
+            };
+            inject_fault(fault, "o", &origin, response())
+        };
+
+        let aborted = inject(FaultKind::Abort).await.unwrap_err();
+        assert_eq!(
+            aborted.origin_error_kind(),
+            Some(OriginErrorKind::ConnectError)
+        );
+
+        let error = inject(FaultKind::Error { status: 503 }).await.unwrap();
+        assert_eq!(error.status_code, 503);
+        assert_eq!(error.headers.get("x-cdn-fault").unwrap(), "fault-1");
+
+        // 20 bytes at 100 bytes a second
+        let started = Instant::now();
+        let dripped = inject(FaultKind::Drip { bytes_per_sec: 100 })
+            .await
+            .unwrap();
+        assert_eq!(dripped.body.len(), 20);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        // Past the origin timeout, the fetch times out at the timeout
+        let started = Instant::now();
+        let slow = inject(FaultKind::Delay { ms: 5000 }).await.unwrap_err();
+        assert_eq!(slow.origin_error_kind(), Some(OriginErrorKind::Timeout));
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_secs(1) && waited < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_join_origin_url() {
+        let cases: &[(&str, &str, Option<&str>, &str)] = &[
+            ("http://o", "/a/b", None, "http://o/a/b"),
+            ("http://o/", "a/b", None, "http://o/a/b"),
+            ("http://o/app", "/a", None, "http://o/app/a"),
+            ("http://o/app/", "//a", None, "http://o/app/a"),
+            // Trailing slashes and the empty path
+            ("http://o/app", "/dir/", None, "http://o/app/dir/"),
+            ("http://o/app", "/", None, "http://o/app/"),
+            ("http://o/app", "", None, "http://o/app/"),
+            ("http://o/app", "/a//b", None, "http://o/app/a//b"),
+            // Encoded characters pass through; decoded delimiters are encoded
+            ("http://o", "/a%20b/%2F", None, "http://o/a%20b/%2F"),
+            ("http://o", "/what?x#y", None, "http://o/what%3Fx%23y"),
+            ("http://o", "/caf\u{e9}", None, "http://o/caf\u{e9}"),
+            // Dot segments resolve without leaving the base path
+            (
+                "http://o/app",
+                "/../etc/passwd",
+                None,
+                "http://o/app/etc/passwd",
+            ),
+            ("http://o/app", "/a/%2e%2E/b", None, "http://o/app/b"),
+            ("http://o/app", "/a/./b/.", None, "http://o/app/a/b/"),
+            ("http://o/app", "/a/../../..", None, "http://o/app/"),
+            // Query strings
+            ("http://o", "/a", Some("x=1&y=2"), "http://o/a?x=1&y=2"),
+            ("http://o", "/a", Some(""), "http://o/a"),
+            ("http://o/app", "", Some("x=1"), "http://o/app/?x=1"),
+        ];
+
+        for (base, path, query, expected) in cases {
+            let joined = join_origin_url(base, path, *query);
+            assert_eq!(&joined, expected, "{} + {:?}", base, path);
+
+            let parsed = url::Url::parse(&joined).unwrap();
+            let base_path = url::Url::parse(base)
+                .unwrap()
+                .path()
+                .trim_end_matches('/')
+                .to_string();
+            assert!(
+                parsed.path().starts_with(&format!("{}/", base_path)),
+                "{} escaped {}",
+                joined,
+                base
+            );
+        }
+    }
+
+    #[test]
+    fn test_strip_path_prefix() {
+        let origin: OriginConfig = toml::from_str(
+            r#"
+            url = "https://bucket.s3.amazonaws.com"
+            path_prefix = "/site"
+            strip_path_prefix = "/assets/"
+            "#,
+        )
+        .unwrap();
+
+        let cases = [
+            (
+                "/assets/app.js",
+                "https://bucket.s3.amazonaws.com/site/app.js",
+            ),
+            ("/assets/css/", "https://bucket.s3.amazonaws.com/site/css/"),
+            ("/assets", "https://bucket.s3.amazonaws.com/site/"),
+            ("/assets/", "https://bucket.s3.amazonaws.com/site/"),
+            // Only whole segments are stripped
+            (
+                "/assets-v2/app.js",
+                "https://bucket.s3.amazonaws.com/site/assets-v2/app.js",
+            ),
+            (
+                "/other/assets/x",
+                "https://bucket.s3.amazonaws.com/site/other/assets/x",
+            ),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(origin.request_url(path, None), expected, "{}", path);
+        }
+
+        let unstripped: OriginConfig = toml::from_str(r#"url = "http://o""#).unwrap();
+        assert_eq!(
+            unstripped.request_url("/assets/app.js", None),
+            "http://o/assets/app.js"
+        );
+    }
+
+    #[test]
+    fn test_adaptive_pools_follow_concurrency() {
+        let origins: HashMap<String, OriginConfig> = toml::from_str(
+            r#"
+            [busy]
+            url = "http://busy.example.com"
+            pool = { max_idle_per_host = 40, adaptive = true, min_idle = 2 }
+
+            [tiny]
+            url = "http://tiny.example.com"
+            "#,
+        )
+        .unwrap();
+        let pool_config = ConnectionPoolConfig {
+            max_idle_per_host: 20,
+            adaptive_interval_secs: 10,
+            adaptive_window_secs: 30,
+            ..ConnectionPoolConfig::default()
+        };
+        let metrics = Arc::new(Metrics::new());
+        let fetcher = OriginFetcher::with_pool_config(origins, pool_config)
+            .unwrap()
+            .with_metrics(metrics.clone());
+        let busy = fetcher.slot("busy").unwrap();
+        let tiny = fetcher.slot("tiny").unwrap();
+        assert!(busy.client.read().unwrap().is_some());
+        assert!(tiny.client.read().unwrap().is_none());
+
+        let status = fetcher.pool_status();
+        assert_eq!(status["busy"].max_idle_per_host, 40);
+        assert_eq!(status["busy"].concurrency_watermark, None);
+        assert_eq!(status["tiny"].max_idle_per_host, 20);
+        assert!(!status["tiny"].adaptive);
+
+        // A quiet window shrinks the busy origin to its peak plus headroom
+        let held: Vec<_> = (0..6).map(|_| busy.concurrency.enter()).collect();
+        let _tiny_fetch = tiny.concurrency.enter();
+        fetcher.adapt_pools();
+        assert_eq!(fetcher.pool_status()["busy"].in_flight, 6);
+        drop(held);
+        fetcher.adapt_pools();
+        assert_eq!(fetcher.pool_status()["busy"].max_idle_per_host, 40);
+        fetcher.adapt_pools();
+        let status = fetcher.pool_status();
+        assert_eq!(status["busy"].max_idle_per_host, 8);
+        assert_eq!(status["busy"].concurrency_watermark, Some(6));
+        assert_eq!(status["tiny"].max_idle_per_host, 20);
+        assert_eq!(status["tiny"].concurrency_watermark, Some(1));
+
+        // A burst grows it straight back, up to its max_idle_per_host
+        let held: Vec<_> = (0..35).map(|_| busy.concurrency.enter()).collect();
+        fetcher.adapt_pools();
+        drop(held);
+        assert_eq!(fetcher.pool_status()["busy"].max_idle_per_host, 40);
+
+        let text = metrics.gather();
+        assert!(
+            text.contains(r#"cdn_origin_pool_resizes_total{direction="shrink",origin="busy"} 1"#)
+        );
+        assert!(
+            text.contains(r#"cdn_origin_pool_resizes_total{direction="grow",origin="busy"} 1"#)
+        );
+        assert!(text.contains(r#"cdn_origin_pool_max_idle{origin="busy"} 40"#));
+        assert!(text.contains(r#"cdn_origin_concurrency_watermark{origin="busy"} 35"#));
+        assert!(text.contains(r#"cdn_origin_pool_max_idle{origin="tiny"} 20"#));
+        assert!(text.contains(r#"cdn_origin_concurrency_watermark{origin="tiny"} 1"#));
+    }
+
+    #[test]
+    fn test_latency_window_p95() {
+        let mut window = LatencyWindow::new(20);
+        for _ in 0..MIN_LATENCY_SAMPLES - 1 {
+            window.record(Duration::from_millis(10));
+        }
+        assert_eq!(window.p95(), None);
+
+        for ms in 1..=20 {
+            window.record(Duration::from_millis(ms * 10));
+        }
+        assert_eq!(window.p95(), Some(Duration::from_millis(190)));
+
+        // Old samples roll out of the window
+        for _ in 0..20 {
+            window.record(Duration::from_millis(5));
+        }
+        assert_eq!(window.p95(), Some(Duration::from_millis(5)));
+    }
+
+    #[tokio::test]
+    async fn test_classify_origin_errors() {
+        let client = Client::new();
+
+        // Nothing listening on a freed port: connection refused
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+        let err = client
+            .get(format!("http://{}/", closed))
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            OriginErrorKind::classify(&err),
+            OriginErrorKind::ConnectError
+        );
+
+        // Accepts but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent = listener.local_addr().unwrap();
+        let _hold = tokio::spawn(async move {
+            let _conn = listener.accept().await;
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        let err = client
+            .get(format!("http://{}/", silent))
+            .timeout(Duration::from_millis(100))
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(OriginErrorKind::classify(&err), OriginErrorKind::Timeout);
+
+        let err = client
+            .get("http://origin.invalid/")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(OriginErrorKind::classify(&err), OriginErrorKind::DnsError);
+
+        assert_eq!(
+            OriginErrorKind::from_status(503),
+            Some(OriginErrorKind::Http5xx(503))
+        );
+        assert_eq!(OriginErrorKind::from_status(404), None);
+    }
+
+    #[tokio::test]
+    async fn test_retry_follows_error_policy() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hits = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    )
+                    .await;
+            }
+        });
+
+        let origin: OriginConfig =
+            toml::from_str(&format!("url = \"http://{}\"\nmax_retries = 3", addr)).unwrap();
+        let origins = HashMap::from([("web".to_string(), origin)]);
+        let pool_config = ConnectionPoolConfig {
+            http2_enabled: false,
+            ..Default::default()
+        };
+
+        // By default a 5xx is passed on after one attempt
+        let fetcher =
+            OriginFetcher::with_pool_config(origins.clone(), pool_config.clone()).unwrap();
+        let response = fetcher
+            .fetch("web", "/", None, &HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response.status_code, 502);
+        assert_eq!(hits.swap(0, std::sync::atomic::Ordering::SeqCst), 1);
+
+        let mut policy = OriginErrorPolicyConfig::default();
+        policy.http_5xx.retry = true;
+        let fetcher = OriginFetcher::with_pool_config(origins, pool_config)
+            .unwrap()
+            .with_error_policy(policy);
+        let response = fetcher
+            .fetch("web", "/", None, &HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response.status_code, 502);
+        assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+}
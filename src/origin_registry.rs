@@ -0,0 +1,436 @@
+//! Runtime origin management
+//!
+//! Adds, replaces and removes origins on a running server. Each change is
+//! validated exactly as the config file is, with `[origin_defaults]`
+//! applied, then registered with the origin fetcher, health checker and
+//! circuit breakers together. When `admin.origin_overrides_path` is set the
+//! change is saved there first, so a restart keeps it.
+//!
+//! A removed origin's in-flight fetches finish against the config they
+//! started with; new requests for it get a 404.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+use crate::cache::Cache;
+use crate::circuit_breaker::CircuitBreakerManager;
+use crate::config::{Config, OriginConfig, OriginOverrides};
+use crate::error::{CdnError, CdnResult};
+use crate::health::HealthChecker;
+use crate::origin::OriginFetcher;
+
+/// A change to one origin: its new table, or `None` to remove it
+type OriginChange = (String, Option<toml::Table>);
+
+/// Config and saved overrides with every change so far applied
+struct RegistryState {
+    config: Config,
+    overrides: OriginOverrides,
+}
+
+/// Live origin set, changed through the admin API
+pub struct OriginRegistry {
+    /// Held for the whole of a change, so changes apply one at a time
+    state: Mutex<RegistryState>,
+    overrides_path: Option<PathBuf>,
+    fetcher: Arc<OriginFetcher>,
+    health_checker: Arc<HealthChecker>,
+    circuit_breaker: Arc<CircuitBreakerManager>,
+    cache: Arc<Cache>,
+}
+
+impl OriginRegistry {
+    /// Manage the origins of `config`, which already has any saved
+    /// overrides applied
+    pub fn new(
+        config: &Config,
+        fetcher: Arc<OriginFetcher>,
+        health_checker: Arc<HealthChecker>,
+        circuit_breaker: Arc<CircuitBreakerManager>,
+        cache: Arc<Cache>,
+    ) -> CdnResult<Self> {
+        let overrides_path = config
+            .admin
+            .origin_overrides_path
+            .as_ref()
+            .map(PathBuf::from);
+        let overrides = match &overrides_path {
+            Some(path) => OriginOverrides::load(path)?,
+            None => OriginOverrides::default(),
+        };
+
+        Ok(Self {
+            state: Mutex::new(RegistryState {
+                config: config.clone(),
+                overrides,
+            }),
+            overrides_path,
+            fetcher,
+            health_checker,
+            circuit_breaker,
+            cache,
+        })
+    }
+
+    /// Effective config of every origin, with credentials redacted
+    pub fn list(&self) -> BTreeMap<String, OriginConfig> {
+        let state = self.lock();
+        let redacted_headers = &state.config.observability.request_logging.redacted_headers;
+        state
+            .config
+            .origins
+            .iter()
+            .map(|(name, origin)| (name.clone(), origin.redacted(redacted_headers)))
+            .collect()
+    }
+
+    /// Add new origins, all or none
+    ///
+    /// Fails with a conflict if any of them already exists.
+    pub fn add(
+        &self,
+        origins: BTreeMap<String, toml::Table>,
+    ) -> CdnResult<BTreeMap<String, OriginConfig>> {
+        let mut state = self.lock();
+        if origins.is_empty() {
+            return Err(CdnError::InvalidRequest("No origins given".to_string()));
+        }
+        if let Some(name) = origins
+            .keys()
+            .find(|name| state.config.origins.contains_key(*name))
+        {
+            return Err(CdnError::Conflict(format!(
+                "Origin {} already exists",
+                name
+            )));
+        }
+
+        let names: Vec<String> = origins.keys().cloned().collect();
+        let changes = origins
+            .into_iter()
+            .map(|(name, table)| (name, Some(table)))
+            .collect();
+        self.commit(&mut state, changes)?;
+
+        let redacted_headers = &state.config.observability.request_logging.redacted_headers;
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                let origin = state.config.origins[&name].redacted(redacted_headers);
+                (name, origin)
+            })
+            .collect())
+    }
+
+    /// Replace an existing origin's config
+    ///
+    /// Its health status and circuit breaker start over. Cached responses
+    /// are kept.
+    pub fn update(&self, name: &str, table: toml::Table) -> CdnResult<OriginConfig> {
+        let mut state = self.lock();
+        if !state.config.origins.contains_key(name) {
+            return Err(CdnError::NotFound(format!("Unknown origin: {}", name)));
+        }
+
+        self.commit(&mut state, vec![(name.to_string(), Some(table))])?;
+        let redacted_headers = &state.config.observability.request_logging.redacted_headers;
+        Ok(state.config.origins[name].redacted(redacted_headers))
+    }
+
+    /// Remove an origin and purge its cached responses
+    ///
+    /// Returns the number of cache entries purged.
+    pub fn remove(&self, name: &str) -> CdnResult<usize> {
+        let mut state = self.lock();
+        if !state.config.origins.contains_key(name) {
+            return Err(CdnError::NotFound(format!("Unknown origin: {}", name)));
+        }
+
+        self.commit(&mut state, vec![(name.to_string(), None)])?;
+        Ok(self.cache.invalidate_prefix(&format!("{}/", name)))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RegistryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Validate, save and apply a set of changes
+    ///
+    /// Nothing is applied unless the whole set validates, and the fetcher
+    /// is rolled back if the changes can't be saved.
+    fn commit(&self, state: &mut RegistryState, changes: Vec<OriginChange>) -> CdnResult<()> {
+        let mut config = state.config.clone();
+        let mut overrides = state.overrides.clone();
+        for (name, table) in &changes {
+            match table {
+                Some(table) => {
+                    let origin = config.origin_from_table(table.clone()).map_err(|e| {
+                        CdnError::InvalidRequest(format!("Origin {}: {}", name, e.message()))
+                    })?;
+                    config.origins.insert(name.clone(), origin);
+                    overrides.set(name, table.clone());
+                }
+                None => {
+                    config.origins.remove(name);
+                    overrides.remove(name);
+                }
+            }
+        }
+        config
+            .validate()
+            .map_err(|e| CdnError::InvalidRequest(e.message().to_string()))?;
+
+        // Building clients is the only step that can fail once validated
+        let mut registered = Vec::new();
+        for (name, table) in &changes {
+            if table.is_none() {
+                continue;
+            }
+            if let Err(e) = self
+                .fetcher
+                .upsert_origin(name, config.origins[name].clone())
+            {
+                self.restore_fetcher(&state.config, &registered);
+                return Err(e);
+            }
+            registered.push(name.clone());
+        }
+
+        if let Some(path) = &self.overrides_path
+            && let Err(e) = overrides.save(path)
+        {
+            self.restore_fetcher(&state.config, &registered);
+            return Err(e);
+        }
+
+        for (name, table) in &changes {
+            match table {
+                Some(_) => {
+                    self.health_checker
+                        .upsert_origin(name, config.origins[name].clone());
+                    info!(origin = %name, "Registered origin");
+                }
+                None => {
+                    self.fetcher.remove_origin(name);
+                    self.health_checker.remove_origin(name);
+                    info!(origin = %name, "Removed origin");
+                }
+            }
+            self.circuit_breaker.remove(name);
+        }
+
+        state.config = config;
+        state.overrides = overrides;
+        Ok(())
+    }
+
+    /// Put the fetcher's origins back as they were in `config`
+    fn restore_fetcher(&self, config: &Config, names: &[String]) {
+        for name in names {
+            match config.origins.get(name) {
+                Some(origin) => {
+                    if let Err(e) = self.fetcher.upsert_origin(name, origin.clone()) {
+                        warn!(origin = %name, error = %e, "Failed to restore origin");
+                    }
+                }
+                None => {
+                    self.fetcher.remove_origin(name);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_breaker::CircuitBreakerConfig;
+    use crate::health::HealthStatus;
+
+    fn registry(config: &Config) -> OriginRegistry {
+        let cache = Arc::new(Cache::new(config.cache.clone()));
+        OriginRegistry::new(
+            config,
+            Arc::new(OriginFetcher::new(config.origins.clone()).unwrap()),
+            Arc::new(HealthChecker::new(config.origins.clone())),
+            Arc::new(CircuitBreakerManager::new(CircuitBreakerConfig {
+                failure_threshold: 1,
+                ..Default::default()
+            })),
+            cache,
+        )
+        .unwrap()
+    }
+
+    fn table(content: &str) -> toml::Table {
+        toml::from_str(content).unwrap()
+    }
+
+    fn scratch_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "screaming-eagle-origins-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("origins.toml")
+    }
+
+    #[test]
+    fn test_add_update_remove() {
+        let config = Config::parse(
+            r#"
+            [origin_defaults]
+            timeout_secs = 7
+
+            [origins.web]
+            url = "http://web.internal"
+            "#,
+        )
+        .unwrap();
+        let registry = registry(&config);
+
+        let added = registry
+            .add(BTreeMap::from([(
+                "api".to_string(),
+                table(r#"url = "http://api.internal""#),
+            )]))
+            .unwrap();
+        // Defaults apply to origins added at runtime too
+        assert_eq!(added["api"].timeout_secs, 7);
+        assert!(registry.fetcher.has_origin("api"));
+        assert_eq!(registry.list().len(), 2);
+
+        let conflict = registry.add(BTreeMap::from([(
+            "api".to_string(),
+            table(r#"url = "http://other.internal""#),
+        )]));
+        assert!(matches!(conflict, Err(CdnError::Conflict(_))));
+
+        registry.circuit_breaker.record_failure("api");
+        let updated = registry
+            .update(
+                "api",
+                table(
+                    r#"
+                    url = "http://api-v2.internal"
+                    timeout_secs = 3
+                    "#,
+                ),
+            )
+            .unwrap();
+        assert_eq!(updated.timeout_secs, 3);
+        assert_eq!(
+            registry.fetcher.origin_config("api").unwrap().url,
+            "http://api-v2.internal"
+        );
+        // An updated origin starts with a closed breaker and unknown health
+        assert!(registry.circuit_breaker.should_allow("api"));
+        assert_eq!(
+            registry.health_checker.get_status("api").unwrap().status,
+            HealthStatus::Unknown
+        );
+
+        assert!(matches!(
+            registry.update("missing", table(r#"url = "http://x""#)),
+            Err(CdnError::NotFound(_))
+        ));
+
+        registry.remove("api").unwrap();
+        assert!(!registry.fetcher.has_origin("api"));
+        assert!(registry.health_checker.get_status("api").is_none());
+        assert!(matches!(registry.remove("api"), Err(CdnError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_invalid_changes_are_not_applied() {
+        let config = Config::parse(
+            r#"
+            [origins.web]
+            url = "http://web.internal"
+            "#,
+        )
+        .unwrap();
+        let registry = registry(&config);
+
+        let invalid = [
+            // Not an origin at all
+            table(r#"timeout_secs = 3"#),
+            // Parses, but fails config validation
+            table(r#"url = "unix:""#),
+        ];
+        for origin in invalid {
+            let result = registry.add(BTreeMap::from([
+                ("good".to_string(), table(r#"url = "http://good.internal""#)),
+                ("bad".to_string(), origin),
+            ]));
+            assert!(matches!(result, Err(CdnError::InvalidRequest(_))));
+        }
+        // One bad origin rejects the whole batch
+        assert!(!registry.fetcher.has_origin("good"));
+        assert!(!registry.fetcher.has_origin("bad"));
+
+        let result = registry.add(BTreeMap::from([(
+            "a/b".to_string(),
+            table(r#"url = "http://ab.internal""#),
+        )]));
+        assert!(matches!(result, Err(CdnError::InvalidRequest(_))));
+        assert_eq!(registry.list().len(), 1);
+    }
+
+    #[test]
+    fn test_changes_persist_across_restart() {
+        let path = scratch_path("persist");
+        let _ = std::fs::remove_file(&path);
+        let content = format!(
+            r#"
+            [admin]
+            origin_overrides_path = "{}"
+
+            [origin_defaults]
+            max_retries = 4
+
+            [origins.web]
+            url = "http://web.internal"
+
+            [origins.legacy]
+            url = "http://legacy.internal"
+            "#,
+            path.display()
+        );
+        let config_path = path.with_file_name("config.toml");
+        std::fs::write(&config_path, &content).unwrap();
+
+        let config = Config::load(&config_path).unwrap();
+        let registry = registry(&config);
+        registry
+            .add(BTreeMap::from([(
+                "api".to_string(),
+                table(r#"url = "http://api.internal""#),
+            )]))
+            .unwrap();
+        registry
+            .update("web", table(r#"url = "http://web-v2.internal""#))
+            .unwrap();
+        registry.remove("legacy").unwrap();
+
+        let restarted = Config::load(&config_path).unwrap();
+        let mut names: Vec<_> = restarted.origins.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, ["api", "web"]);
+        assert_eq!(restarted.origins["web"].url, "http://web-v2.internal");
+        // Saved as submitted, so the config file's defaults still apply
+        assert_eq!(restarted.origins["api"].max_retries, 4);
+
+        // Restarting and changing again keeps the earlier changes
+        let registry = super::tests::registry(&restarted);
+        registry.remove("api").unwrap();
+        let restarted = Config::load(&config_path).unwrap();
+        assert_eq!(restarted.origins.len(), 1);
+        assert_eq!(restarted.origins["web"].url, "http://web-v2.internal");
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,193 @@
+//! Adaptive idle connection pools per origin
+//!
+//! A client's idle connection limit is fixed when it's built, so an origin
+//! with `pool.adaptive` gets a dedicated client that is rebuilt when its
+//! traffic calls for a different limit. Every fetch counts itself in flight
+//! while it runs. Every `connection_pool.adaptive_interval_secs` the
+//! controller takes each origin's peak concurrency since the last tick; the
+//! highest peak over `adaptive_window_secs` is the origin's watermark, and
+//! its idle target is the watermark plus a quarter for headroom, kept
+//! between `pool.min_idle` and `pool.max_idle_per_host`.
+//!
+//! Adaptive pools start at `max_idle_per_host`. They grow as soon as the
+//! target is above their limit, but only shrink once a whole window has been
+//! seen and by at least a quarter, so a quiet minute doesn't cost a busy
+//! origin its warm connections. A rebuilt client starts with an empty pool;
+//! fetches already in flight finish on the connections they have.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::origin::OriginFetcher;
+
+/// Fetches in flight to an origin, and the most at once since last taken
+#[derive(Debug, Default)]
+pub struct ConcurrencyGauge {
+    in_flight: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl ConcurrencyGauge {
+    /// Count a fetch in flight until the returned guard drops
+    pub fn enter(&self) -> InFlight<'_> {
+        let now = self.in_flight.fetch_add(1, Ordering::AcqRel) + 1;
+        self.peak.fetch_max(now, Ordering::AcqRel);
+        InFlight(self)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    /// The peak since the last call; the next peak starts from what's in
+    /// flight now
+    pub fn take_peak(&self) -> usize {
+        self.peak.swap(self.in_flight(), Ordering::AcqRel)
+    }
+}
+
+/// One fetch counted by a [`ConcurrencyGauge`]
+pub struct InFlight<'a>(&'a ConcurrencyGauge);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// An origin's recent peak concurrency and the idle limit its client has
+#[derive(Debug)]
+pub struct AdaptivePool {
+    max_idle: usize,
+    /// Peak concurrency of each recent interval, oldest first
+    peaks: VecDeque<usize>,
+    capacity: usize,
+}
+
+impl AdaptivePool {
+    /// A pool built with `max_idle`, sized from the last `samples` peaks
+    pub fn new(max_idle: usize, samples: usize) -> Self {
+        let capacity = samples.max(1);
+        Self {
+            max_idle,
+            peaks: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn max_idle(&self) -> usize {
+        self.max_idle
+    }
+
+    /// Record an interval's peak concurrency
+    pub fn observe(&mut self, peak: usize) {
+        if self.peaks.len() == self.capacity {
+            self.peaks.pop_front();
+        }
+        self.peaks.push_back(peak);
+    }
+
+    /// Highest peak over the window; `None` before the first interval ends
+    pub fn watermark(&self) -> Option<usize> {
+        self.peaks.iter().copied().max()
+    }
+
+    /// The idle limit to rebuild the client with, if it should change
+    pub fn resize(&self, min_idle: usize, max_idle: usize) -> Option<usize> {
+        let watermark = self.watermark()?;
+        let target = (watermark + watermark.div_ceil(4)).clamp(min_idle, max_idle.max(min_idle));
+        let window_seen = self.peaks.len() == self.capacity;
+        let grow = target > self.max_idle;
+        let shrink = window_seen && target * 4 <= self.max_idle * 3;
+        (grow || shrink).then_some(target)
+    }
+
+    /// The client was rebuilt with `max_idle`
+    pub fn resized(&mut self, max_idle: usize) {
+        self.max_idle = max_idle;
+    }
+}
+
+/// An origin's connection pool as `GET /_cdn/origins` reports it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OriginPoolStatus {
+    /// Idle connections its client keeps now
+    pub max_idle_per_host: usize,
+    pub idle_timeout_secs: u64,
+    pub adaptive: bool,
+    /// Fetches to the origin in flight now
+    pub in_flight: usize,
+    /// Most concurrent fetches in any interval of the adaptive window
+    pub concurrency_watermark: Option<usize>,
+}
+
+/// Sample origin concurrency and resize adaptive pools every `interval`
+/// for the life of the process
+pub fn spawn_pool_sizing(fetcher: Arc<OriginFetcher>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(interval);
+        timer.tick().await; // Skip first tick
+        loop {
+            timer.tick().await;
+            fetcher.adapt_pools();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrency_gauge_peaks() {
+        let gauge = ConcurrencyGauge::default();
+        let first = gauge.enter();
+        let second = gauge.enter();
+        drop(gauge.enter());
+        assert_eq!(gauge.in_flight(), 2);
+        assert_eq!(gauge.take_peak(), 3);
+
+        // The next peak starts from the two still in flight
+        drop(first);
+        assert_eq!(gauge.take_peak(), 2);
+        drop(second);
+        assert_eq!(gauge.take_peak(), 1);
+        assert_eq!(gauge.take_peak(), 0);
+    }
+
+    #[test]
+    fn test_grows_at_once_and_shrinks_after_a_window() {
+        let mut pool = AdaptivePool::new(100, 3);
+        assert_eq!(pool.resize(2, 100), None);
+
+        // Quiet, but not for a whole window yet
+        pool.observe(8);
+        pool.observe(4);
+        assert_eq!(pool.watermark(), Some(8));
+        assert_eq!(pool.resize(2, 100), None);
+        pool.observe(0);
+        assert_eq!(pool.resize(2, 100), Some(10));
+        pool.resized(10);
+        assert_eq!(pool.resize(2, 100), None);
+
+        // A busier interval grows it straight away, up to the maximum
+        pool.observe(40);
+        assert_eq!(pool.resize(2, 100), Some(50));
+        pool.observe(300);
+        assert_eq!(pool.resize(2, 100), Some(100));
+        pool.resized(100);
+
+        // Small drops don't rebuild the client; an idle window floors at min
+        pool.observe(70);
+        pool.observe(70);
+        pool.observe(70);
+        assert_eq!(pool.resize(2, 100), None);
+        for _ in 0..3 {
+            pool.observe(0);
+        }
+        assert_eq!(pool.resize(2, 100), Some(2));
+    }
+}
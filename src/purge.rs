@@ -0,0 +1,634 @@
+//! Purge deduplication and batching
+//!
+//! A CMS saving a page often purges the same tag once per edited fragment,
+//! dozens of times a second. A purge identical to one run within
+//! `admin.purge_dedup.window_ms` gets that run's result back instead of
+//! running again. With `batch_window_ms` set, purges arriving together are
+//! gathered and run in one pass, so their prefixes share a single scan.
+//!
+//! Destructive purges (purge-all, and prefixes matching most of the cache)
+//! also pass a [`PurgeGuard`] first, so a script purging in a loop can't
+//! keep the cache cold.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+use crate::cache::Cache;
+use crate::cache_key::{KeyPrefix, canonical_key};
+use crate::config::{PurgeDedupConfig, PurgeSafetyConfig};
+use crate::handlers::PurgeRequest;
+use crate::metrics::Metrics;
+
+/// What a purge request removes, in a form where equal purges compare equal
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PurgeOperation {
+    All,
+    Tag(String),
+    Prefix(String),
+    /// Sorted and deduplicated
+    Keys(Vec<String>),
+}
+
+impl PurgeOperation {
+    /// The operation a request runs: `all`, else `tag`, else `prefix`, else its keys
+    pub fn from_request(request: &PurgeRequest) -> Self {
+        if request.all {
+            Self::All
+        } else if let Some(tag) = &request.tag {
+            Self::Tag(tag.clone())
+        } else if let Some(prefix) = &request.prefix {
+            Self::Prefix(prefix.clone())
+        } else {
+            let mut keys: Vec<String> = request.keys.iter().map(|key| canonical_key(key)).collect();
+            keys.sort_unstable();
+            keys.dedup();
+            Self::Keys(keys)
+        }
+    }
+}
+
+/// Result of a purge, or of the earlier identical one it was answered with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PurgeOutcome {
+    pub purged_count: usize,
+    /// Keys whose in-flight origin fetch will no longer be stored
+    pub cancelled_fills: usize,
+    /// Answered from an identical purge rather than run
+    pub deduplicated: bool,
+}
+
+type BatchResults = Arc<HashMap<PurgeOperation, PurgeOutcome>>;
+
+/// Purges waiting for the batch window to close
+struct Batch {
+    operations: Vec<PurgeOperation>,
+    done: watch::Sender<Option<BatchResults>>,
+}
+
+/// Runs purges, skipping repeats and batching bursts as configured
+pub struct PurgeDeduplicator {
+    config: PurgeDedupConfig,
+    /// Results of recent runs, by operation
+    recent: Mutex<HashMap<PurgeOperation, (Instant, PurgeOutcome)>>,
+    /// The batch purges currently join, if one is open
+    batch: Mutex<Option<Batch>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl PurgeDeduplicator {
+    pub fn new(config: PurgeDedupConfig) -> Self {
+        Self {
+            config,
+            recent: Mutex::new(HashMap::new()),
+            batch: Mutex::new(None),
+            metrics: None,
+        }
+    }
+
+    /// Count the purge executions saved
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Run a purge, or answer it from an identical one run within the window
+    pub async fn purge(
+        self: &Arc<Self>,
+        cache: &Arc<Cache>,
+        operation: PurgeOperation,
+    ) -> PurgeOutcome {
+        if let Some(outcome) = self.recent_outcome(&operation) {
+            self.record_saved("deduplicated");
+            return outcome;
+        }
+        if self.config.batch_window_ms == 0 {
+            let outcome = execute(cache, std::slice::from_ref(&operation))[0];
+            self.remember(&[(operation, outcome)]);
+            return outcome;
+        }
+
+        // Join the open batch, or open one that runs when the window closes.
+        // The run is spawned so it happens even if this request goes away.
+        let (mut done, joined_identical) = {
+            let mut batch = self.batch.lock().unwrap_or_else(PoisonError::into_inner);
+            match batch.as_mut() {
+                Some(open) => {
+                    let identical = open.operations.contains(&operation);
+                    if !identical {
+                        open.operations.push(operation.clone());
+                    }
+                    self.record_saved(if identical { "deduplicated" } else { "batched" });
+                    (open.done.subscribe(), identical)
+                }
+                None => {
+                    let (done, receiver) = watch::channel(None);
+                    *batch = Some(Batch {
+                        operations: vec![operation.clone()],
+                        done,
+                    });
+                    self.spawn_batch(cache.clone());
+                    (receiver, false)
+                }
+            }
+        };
+
+        let results = match done.wait_for(Option::is_some).await {
+            Ok(results) => results.clone().unwrap_or_default(),
+            // The batch task panicked; nothing is known to have been purged
+            Err(_) => return PurgeOutcome::default(),
+        };
+        let outcome = results.get(&operation).copied().unwrap_or_default();
+        PurgeOutcome {
+            deduplicated: joined_identical,
+            ..outcome
+        }
+    }
+
+    fn spawn_batch(self: &Arc<Self>, cache: Arc<Cache>) {
+        let purges = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(purges.config.batch_window()).await;
+            let Some(batch) = purges
+                .batch
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take()
+            else {
+                return;
+            };
+            let outcomes = execute(&cache, &batch.operations);
+            let results: Vec<_> = batch.operations.into_iter().zip(outcomes).collect();
+            debug!(operations = results.len(), "Ran batched purges");
+            purges.remember(&results);
+            batch
+                .done
+                .send_replace(Some(Arc::new(results.into_iter().collect())));
+        });
+    }
+
+    fn recent_outcome(&self, operation: &PurgeOperation) -> Option<PurgeOutcome> {
+        let window = self.config.window();
+        let recent = self.recent.lock().unwrap_or_else(PoisonError::into_inner);
+        let (ran_at, outcome) = recent.get(operation)?;
+        (ran_at.elapsed() < window).then_some(PurgeOutcome {
+            deduplicated: true,
+            ..*outcome
+        })
+    }
+
+    fn remember(&self, results: &[(PurgeOperation, PurgeOutcome)]) {
+        if self.config.window_ms == 0 {
+            return;
+        }
+        let window = self.config.window();
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap_or_else(PoisonError::into_inner);
+        recent.retain(|_, (ran_at, _)| now.duration_since(*ran_at) < window);
+        for (operation, outcome) in results {
+            recent.insert(operation.clone(), (now, *outcome));
+        }
+    }
+
+    fn record_saved(&self, reason: &str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_purge_saved(reason);
+        }
+    }
+}
+
+/// A purge that empties all or most of the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DestructivePurge {
+    All,
+    /// A prefix matching `matched` of the `total` cached entries
+    Prefix {
+        matched: usize,
+        total: usize,
+    },
+}
+
+impl DestructivePurge {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DestructivePurge::All => "all",
+            DestructivePurge::Prefix { .. } => "prefix",
+        }
+    }
+}
+
+/// A destructive purge let through by the [`PurgeGuard`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdmittedPurge {
+    pub purge: DestructivePurge,
+    /// Let through during the cooldown because the request said `force`
+    pub forced: bool,
+}
+
+/// Why a destructive purge was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PurgeRefusal {
+    /// Purge-all needs a purge token minted with `purge_all`
+    Forbidden,
+    /// Another destructive purge ran within the cooldown, which ends after
+    /// this long
+    CoolingDown(Duration),
+}
+
+/// Cooldown and permission checks for destructive purges
+pub struct PurgeGuard {
+    config: PurgeSafetyConfig,
+    /// When the last destructive purge was let through
+    last: Mutex<Option<Instant>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl PurgeGuard {
+    pub fn new(config: PurgeSafetyConfig) -> Self {
+        Self {
+            config,
+            last: Mutex::new(None),
+            metrics: None,
+        }
+    }
+
+    /// Count destructive purges run and refused
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Whether `operation` is destructive and, if so, whether it may run
+    ///
+    /// `force` is the request's override of the cooldown, and
+    /// `purge_all_token` whether it carries a purge token minted with
+    /// `purge_all`. A destructive purge let through starts a new cooldown.
+    pub fn admit(
+        &self,
+        cache: &Cache,
+        operation: &PurgeOperation,
+        force: bool,
+        purge_all_token: bool,
+    ) -> Result<Option<AdmittedPurge>, PurgeRefusal> {
+        let Some(purge) = self.destructive(cache, operation) else {
+            return Ok(None);
+        };
+        self.admit_at(purge, force, purge_all_token, Instant::now())
+            .map(Some)
+    }
+
+    fn destructive(&self, cache: &Cache, operation: &PurgeOperation) -> Option<DestructivePurge> {
+        match operation {
+            PurgeOperation::All => Some(DestructivePurge::All),
+            PurgeOperation::Prefix(prefix) if self.config.max_prefix_fraction > 0.0 => {
+                let (matched, total) = cache.count_prefix(prefix);
+                (total > 0 && matched as f64 / total as f64 > self.config.max_prefix_fraction)
+                    .then_some(DestructivePurge::Prefix { matched, total })
+            }
+            _ => None,
+        }
+    }
+
+    fn admit_at(
+        &self,
+        purge: DestructivePurge,
+        force: bool,
+        purge_all_token: bool,
+        now: Instant,
+    ) -> Result<AdmittedPurge, PurgeRefusal> {
+        if purge == DestructivePurge::All && self.config.require_purge_all_token && !purge_all_token
+        {
+            self.record(purge, "forbidden");
+            return Err(PurgeRefusal::Forbidden);
+        }
+
+        let mut last = self.last.lock().unwrap_or_else(PoisonError::into_inner);
+        let remaining = last
+            .map(|at| {
+                self.config
+                    .cooldown()
+                    .saturating_sub(now.duration_since(at))
+            })
+            .filter(|remaining| !remaining.is_zero());
+        let forced = match remaining {
+            Some(remaining) if !force => {
+                drop(last);
+                self.record(purge, "cooldown");
+                warn!(
+                    kind = purge.kind(),
+                    retry_after_secs = remaining.as_secs(),
+                    "Refused destructive purge during cooldown"
+                );
+                return Err(PurgeRefusal::CoolingDown(remaining));
+            }
+            Some(_) => true,
+            None => false,
+        };
+        *last = Some(now);
+        drop(last);
+
+        self.record(purge, if forced { "forced" } else { "executed" });
+        warn!(kind = purge.kind(), forced, "Running destructive purge");
+        Ok(AdmittedPurge { purge, forced })
+    }
+
+    fn record(&self, purge: DestructivePurge, outcome: &str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_destructive_purge(purge.kind(), outcome);
+        }
+    }
+}
+
+/// Run purge operations against the cache, prefixes in a single scan
+///
+/// In-flight fills are cancelled first so none can store purged content
+/// after the invalidation has run.
+fn execute(cache: &Cache, operations: &[PurgeOperation]) -> Vec<PurgeOutcome> {
+    let cancelled: Vec<usize> = operations
+        .iter()
+        .map(|operation| match operation {
+            PurgeOperation::All => cache.cancel_fills(|_| true),
+            PurgeOperation::Tag(tag) => {
+                cache.cancel_tagged_fills(tag);
+                0
+            }
+            PurgeOperation::Prefix(prefix) => {
+                let prefix = KeyPrefix::parse(prefix);
+                cache.cancel_fills(|key| prefix.matches(key))
+            }
+            PurgeOperation::Keys(keys) => cache.cancel_fills(|key| keys.iter().any(|k| k == key)),
+        })
+        .collect();
+
+    let prefixes: Vec<&str> = operations
+        .iter()
+        .filter_map(|operation| match operation {
+            PurgeOperation::Prefix(prefix) => Some(prefix.as_str()),
+            _ => None,
+        })
+        .collect();
+    let mut prefix_counts = if prefixes.is_empty() {
+        Vec::new()
+    } else {
+        cache.invalidate_prefixes(&prefixes)
+    }
+    .into_iter();
+
+    operations
+        .iter()
+        .zip(cancelled)
+        .map(|(operation, cancelled_fills)| PurgeOutcome {
+            purged_count: match operation {
+                PurgeOperation::All => cache.purge_all(),
+                PurgeOperation::Tag(tag) => cache.invalidate_by_tag(tag),
+                PurgeOperation::Prefix(_) => prefix_counts.next().unwrap_or(0),
+                PurgeOperation::Keys(keys) => {
+                    keys.iter().filter(|key| cache.invalidate(key)).count()
+                }
+            },
+            cancelled_fills,
+            deduplicated: false,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CacheEntry;
+    use crate::config::CacheConfig;
+    use crate::headers::ResponseHeaders;
+    use bytes::Bytes;
+    use std::time::{Duration, SystemTime};
+
+    fn entry() -> CacheEntry {
+        CacheEntry {
+            body: Bytes::from_static(b"ok"),
+            headers: ResponseHeaders::default(),
+            status_code: 200,
+            content_type: None,
+            etag: None,
+            last_modified: None,
+            created_at: Instant::now(),
+            expires_at: Instant::now() + Duration::from_secs(3600),
+            expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
+            size: 2,
+            stale_if_error_secs: None,
+            access_count: 0,
+            last_accessed: Instant::now(),
+            cache_tags: Vec::new(),
+            headers_only: false,
+            fetch_duration: Duration::ZERO,
+            immutable: false,
+        }
+    }
+
+    fn setup(config: PurgeDedupConfig) -> (Arc<PurgeDeduplicator>, Arc<Cache>, Arc<Metrics>) {
+        let cache = Arc::new(Cache::new(CacheConfig::default()));
+        for key in ["a/1", "a/2", "b/1"] {
+            cache.set(key.to_string(), entry());
+        }
+        let metrics = Arc::new(Metrics::new());
+        let purges = Arc::new(PurgeDeduplicator::new(config).with_metrics(metrics.clone()));
+        (purges, cache, metrics)
+    }
+
+    fn prefix(prefix: &str) -> PurgeOperation {
+        PurgeOperation::Prefix(prefix.to_string())
+    }
+
+    #[test]
+    fn test_operation_normalization() {
+        let keys = |keys: &[&str]| PurgeRequest {
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            PurgeOperation::from_request(&keys(&["b", "a", "b"])),
+            PurgeOperation::from_request(&keys(&["a", "b"]))
+        );
+        let request = PurgeRequest {
+            keys: vec!["a".to_string()],
+            prefix: Some("p".to_string()),
+            tag: Some("t".to_string()),
+            all: false,
+            key_hashes: Vec::new(),
+            force: false,
+        };
+        assert_eq!(
+            PurgeOperation::from_request(&request),
+            PurgeOperation::Tag("t".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_identical_purges_within_the_window() {
+        let (purges, cache, metrics) = setup(PurgeDedupConfig {
+            window_ms: 60_000,
+            batch_window_ms: 0,
+        });
+
+        let first = purges.purge(&cache, prefix("a/")).await;
+        assert_eq!((first.purged_count, first.deduplicated), (2, false));
+
+        // Answered from the first run; an entry stored since then stays
+        cache.set("a/3".to_string(), entry());
+        let repeat = purges.purge(&cache, prefix("a/")).await;
+        assert_eq!((repeat.purged_count, repeat.deduplicated), (2, true));
+        assert!(cache.get("a/3").is_some());
+
+        let other = purges.purge(&cache, prefix("b/")).await;
+        assert_eq!((other.purged_count, other.deduplicated), (1, false));
+        assert!(
+            metrics
+                .gather()
+                .contains(r#"cdn_purge_executions_saved_total{reason="deduplicated"} 1"#)
+        );
+
+        // Off by default: every purge runs
+        let (purges, cache, _) = setup(PurgeDedupConfig::default());
+        assert_eq!(purges.purge(&cache, prefix("a/")).await.purged_count, 2);
+        cache.set("a/3".to_string(), entry());
+        let repeat = purges.purge(&cache, prefix("a/")).await;
+        assert_eq!((repeat.purged_count, repeat.deduplicated), (1, false));
+    }
+
+    #[tokio::test]
+    async fn test_batched_purges_run_together() {
+        let (purges, cache, metrics) = setup(PurgeDedupConfig {
+            window_ms: 0,
+            batch_window_ms: 50,
+        });
+
+        let (a, b, again) = tokio::join!(
+            purges.purge(&cache, prefix("a/")),
+            purges.purge(&cache, prefix("b/")),
+            purges.purge(&cache, prefix("a/")),
+        );
+        assert_eq!((a.purged_count, a.deduplicated), (2, false));
+        assert_eq!((b.purged_count, b.deduplicated), (1, false));
+        assert_eq!((again.purged_count, again.deduplicated), (2, true));
+        assert_eq!(cache.stats().total_entries, 0);
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"cdn_purge_executions_saved_total{reason="batched"} 1"#));
+        assert!(output.contains(r#"cdn_purge_executions_saved_total{reason="deduplicated"} 1"#));
+
+        // The next burst opens a new batch
+        cache.set("a/3".to_string(), entry());
+        assert_eq!(purges.purge(&cache, prefix("a/")).await.purged_count, 1);
+    }
+
+    fn purge_guard(config: &str) -> (PurgeGuard, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::new());
+        let guard = PurgeGuard::new(toml::from_str(config).unwrap()).with_metrics(metrics.clone());
+        (guard, metrics)
+    }
+
+    #[test]
+    fn test_purge_all_cooldown_and_force() {
+        let (guard, metrics) = purge_guard("cooldown_secs = 600");
+        let start = Instant::now();
+        let all = DestructivePurge::All;
+
+        let first = guard.admit_at(all, false, false, start).unwrap();
+        assert!(!first.forced);
+
+        // Repeats inside the cooldown are refused, and don't extend it
+        let later = start + Duration::from_secs(60);
+        assert_eq!(
+            guard.admit_at(all, false, false, later),
+            Err(PurgeRefusal::CoolingDown(Duration::from_secs(540)))
+        );
+        assert_eq!(
+            guard.admit_at(all, false, false, later + Duration::from_secs(40)),
+            Err(PurgeRefusal::CoolingDown(Duration::from_secs(500)))
+        );
+
+        // Forcing runs it, and the cooldown starts over from there
+        let forced = later + Duration::from_secs(60);
+        assert!(guard.admit_at(all, true, false, forced).unwrap().forced);
+        assert_eq!(
+            guard.admit_at(all, false, false, start + Duration::from_secs(601)),
+            Err(PurgeRefusal::CoolingDown(Duration::from_secs(119)))
+        );
+
+        // Once it's over, no force is needed, and forcing isn't counted as such
+        let over = forced + Duration::from_secs(600);
+        assert!(!guard.admit_at(all, true, false, over).unwrap().forced);
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"cdn_destructive_purges_total{kind="all",outcome="executed"} 2"#)
+        );
+        assert!(output.contains(r#"cdn_destructive_purges_total{kind="all",outcome="forced"} 1"#));
+        assert!(
+            output.contains(r#"cdn_destructive_purges_total{kind="all",outcome="cooldown"} 3"#)
+        );
+
+        // No cooldown by default
+        let (guard, _) = purge_guard("");
+        assert!(guard.admit_at(all, false, false, start).is_ok());
+        assert!(guard.admit_at(all, false, false, start).is_ok());
+    }
+
+    #[test]
+    fn test_purge_all_can_require_a_token() {
+        let (guard, metrics) = purge_guard("require_purge_all_token = true");
+        let now = Instant::now();
+        assert_eq!(
+            guard.admit_at(DestructivePurge::All, true, false, now),
+            Err(PurgeRefusal::Forbidden)
+        );
+        assert!(
+            guard
+                .admit_at(DestructivePurge::All, false, true, now)
+                .is_ok()
+        );
+        // Large prefix purges don't need the token
+        let prefix = DestructivePurge::Prefix {
+            matched: 9,
+            total: 10,
+        };
+        assert!(guard.admit_at(prefix, false, false, now).is_ok());
+        assert!(
+            metrics
+                .gather()
+                .contains(r#"cdn_destructive_purges_total{kind="all",outcome="forbidden"} 1"#)
+        );
+    }
+
+    #[test]
+    fn test_large_prefix_purges_are_destructive() {
+        let cache = Arc::new(Cache::new(CacheConfig::default()));
+        for key in ["a/1", "a/2", "a/3", "b/1"] {
+            cache.set(key.to_string(), entry());
+        }
+        let (guard, _) = purge_guard("cooldown_secs = 600\nmax_prefix_fraction = 0.5");
+
+        // 1 of 4 entries is fine, however often
+        for _ in 0..2 {
+            assert_eq!(guard.admit(&cache, &prefix("b/"), false, false), Ok(None));
+        }
+        let admitted = guard.admit(&cache, &prefix("a/"), false, false).unwrap();
+        assert_eq!(
+            admitted.map(|admitted| admitted.purge),
+            Some(DestructivePurge::Prefix {
+                matched: 3,
+                total: 4
+            })
+        );
+        // It shares the cooldown with purge-all
+        assert!(matches!(
+            guard.admit(&cache, &PurgeOperation::All, false, false),
+            Err(PurgeRefusal::CoolingDown(_))
+        ));
+
+        // Off unless a fraction is set
+        let (guard, _) = purge_guard("");
+        assert_eq!(guard.admit(&cache, &prefix("a/"), false, false), Ok(None));
+    }
+}
@@ -34,6 +34,23 @@ impl ByteRange {
     pub fn content_range_header(&self, total_length: u64) -> String {
         format!("bytes {}-{}/{}", self.start, self.end, total_length)
     }
+
+    /// Check if every byte of `other` is within this range
+    pub fn contains(&self, other: &ByteRange) -> bool {
+        self.start <= other.start && other.end <= self.end
+    }
+}
+
+/// Parse a Content-Range header value like `bytes 0-499/1000`
+///
+/// Returns the range and the total length; an unknown length (`*`) or an
+/// unsatisfied range (`bytes */1000`) gives `None`.
+pub fn parse_content_range(value: &str) -> Option<(ByteRange, u64)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.trim().split_once('-')?;
+    let range = ByteRange::new(start.parse().ok()?, end.parse().ok()?);
+    let total: u64 = total.trim().parse().ok()?;
+    range.is_satisfiable(total).then_some((range, total))
 }
 
 /// Result of parsing a Range header
@@ -303,6 +320,20 @@ mod tests {
         assert_eq!(range.content_range_header(1000), "bytes 500-999/1000");
     }
 
+    #[test]
+    fn test_parse_content_range() {
+        let (range, total) = parse_content_range("bytes 0-499/1000").unwrap();
+        assert_eq!(range, ByteRange::new(0, 499));
+        assert_eq!(total, 1000);
+        assert!(range.contains(&ByteRange::new(100, 499)));
+        assert!(!range.contains(&ByteRange::new(400, 500)));
+
+        assert!(parse_content_range("bytes 0-499/*").is_none());
+        assert!(parse_content_range("bytes */1000").is_none());
+        assert!(parse_content_range("bytes 500-999/600").is_none());
+        assert!(parse_content_range("items 0-4/10").is_none());
+    }
+
     #[test]
     fn test_suffix_range_larger_than_content() {
         // Request last 2000 bytes of 1000 byte content
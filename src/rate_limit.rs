@@ -1,9 +1,48 @@
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Request, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
 use dashmap::DashMap;
-use std::net::IpAddr;
-use std::time::{Duration, Instant};
-use tracing::{debug, warn};
+use dashmap::mapref::entry::Entry;
+use regex::Regex;
+use serde::{Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
 
-#[derive(Debug, Clone)]
+use crate::config::RegexLimitsConfig;
+use crate::metrics::Metrics;
+use crate::request_timing::{Phase, lap};
+use crate::security::{TrustedProxies, check_cidr};
+
+/// Offenders listed in rate limiter stats
+const TOP_OFFENDERS: usize = 10;
+
+const DEFAULT_MAX_TRACKED_CLIENTS: usize = 100_000;
+const DEFAULT_IPV6_PREFIX_LEN: u8 = 64;
+
+/// Paths probed by load balancers and scrapers, which are never limited
+pub const EXEMPT_PATHS: &[&str] = &["/_cdn/health", "/_cdn/metrics"];
+
+/// The admin API, which is guarded by admin auth rather than the limiter, and
+/// `/_cdn/quota`, which reports a client's quota without spending it
+const ADMIN_PREFIX: &str = "/_cdn/";
+
+/// Hex digits of an API key's SHA-256 kept as its identity
+const API_KEY_FINGERPRINT_LEN: usize = 16;
+
+/// A full limiter evicts down to this share of its cap, so sweeps are rare
+const EVICT_TO_PERCENT: usize = 90;
+
+#[derive(Debug, Clone, Serialize)]
 pub struct RateLimitConfig {
     /// Maximum requests per window
     pub requests_per_window: u32,
@@ -15,6 +54,25 @@ pub struct RateLimitConfig {
     pub enabled: bool,
 }
 
+impl RateLimitConfig {
+    fn max_tokens(&self) -> f64 {
+        self.requests_per_window as f64 + self.burst_size as f64
+    }
+
+    fn refill_rate(&self) -> f64 {
+        self.requests_per_window as f64 / self.window_secs as f64
+    }
+}
+
+/// Partial rate limit config applied at runtime; unset fields are kept
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RateLimitUpdate {
+    pub requests_per_window: Option<u32>,
+    pub window_secs: Option<u64>,
+    pub burst_size: Option<u32>,
+    pub enabled: Option<bool>,
+}
+
 impl Default for RateLimitConfig {
     fn default() -> Self {
         Self {
@@ -32,6 +90,8 @@ struct TokenBucket {
     last_update: Instant,
     max_tokens: f64,
     refill_rate: f64, // tokens per second
+    /// Requests refused since the bucket was created
+    rejected: u64,
 }
 
 impl TokenBucket {
@@ -41,9 +101,26 @@ impl TokenBucket {
             last_update: Instant::now(),
             max_tokens,
             refill_rate,
+            rejected: 0,
         }
     }
 
+    /// Switch to new limits, keeping the bucket as full (proportionally) as it was
+    ///
+    /// A client that was limited stays limited, and nobody is handed a fresh
+    /// full bucket to burst with.
+    fn rescale(&mut self, max_tokens: f64, refill_rate: f64) {
+        self.refill();
+        let fill = if self.max_tokens > 0.0 {
+            self.tokens / self.max_tokens
+        } else {
+            1.0
+        };
+        self.tokens = fill * max_tokens;
+        self.max_tokens = max_tokens;
+        self.refill_rate = refill_rate;
+    }
+
     fn try_consume(&mut self, tokens: f64) -> bool {
         self.refill();
 
@@ -62,78 +139,595 @@ impl TokenBucket {
         self.last_update = now;
     }
 
+    /// Tokens available now, without touching the bucket (and its last use)
+    fn peek_tokens(&self) -> f64 {
+        let elapsed = self.last_update.elapsed().as_secs_f64();
+        (self.tokens + elapsed * self.refill_rate).min(self.max_tokens)
+    }
+
     fn tokens_available(&mut self) -> f64 {
         self.refill();
         self.tokens
     }
+
+    /// Seconds until a bucket holding `tokens` is full again
+    fn secs_until_full(&self, tokens: f64) -> u64 {
+        ((self.max_tokens - tokens).max(0.0) / self.refill_rate).ceil() as u64
+    }
+}
+
+/// Who a request is limited as
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ClientId {
+    /// The client's address, or its network for IPv6
+    Ip(IpAddr),
+    /// Fingerprint of the issued API key the client sent; the key itself
+    /// isn't kept
+    ApiKey(String),
+}
+
+/// Hex SHA-256 of an API key, as `rate_limit.api_keys` lists issued keys
+pub fn api_key_digest(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+impl From<IpAddr> for ClientId {
+    fn from(ip: IpAddr) -> Self {
+        ClientId::Ip(ip)
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientId::Ip(ip) => write!(f, "{}", ip),
+            ClientId::ApiKey(fingerprint) => write!(f, "key:{}", fingerprint),
+        }
+    }
+}
+
+impl Serialize for ClientId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
 }
 
 pub struct RateLimiter {
-    buckets: DashMap<IpAddr, TokenBucket>,
-    config: RateLimitConfig,
+    /// Keyed by API key, client address, or network for IPv6 (see `identify`)
+    buckets: DashMap<ClientId, TokenBucket>,
+    /// Entries in `buckets`, kept alongside so the cap check doesn't lock every shard
+    tracked: AtomicUsize,
+    max_tracked_clients: usize,
+    ipv6_prefix_len: u8,
+    /// Header whose value, when an issued key, identifies the client instead
+    /// of its address
+    api_key_header: Option<HeaderName>,
+    /// SHA-256 digests of the issued keys, lowercase hex
+    api_keys: HashSet<String>,
+    /// Whose `X-Forwarded-For` gives the client address
+    trusted_proxies: TrustedProxies,
+    /// Held while evicting, so concurrent new clients don't all sweep at once
+    eviction: Mutex<()>,
+    /// Swapped at runtime from the admin API
+    config: RwLock<RateLimitConfig>,
+    exemptions: RateLimitExemptions,
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl RateLimiter {
     pub fn new(config: RateLimitConfig) -> Self {
         Self {
             buckets: DashMap::new(),
-            config,
+            tracked: AtomicUsize::new(0),
+            max_tracked_clients: DEFAULT_MAX_TRACKED_CLIENTS,
+            ipv6_prefix_len: DEFAULT_IPV6_PREFIX_LEN,
+            api_key_header: None,
+            api_keys: HashSet::new(),
+            trusted_proxies: TrustedProxies::default(),
+            eviction: Mutex::new(()),
+            config: RwLock::new(config),
+            exemptions: RateLimitExemptions::default(),
+            metrics: None,
+        }
+    }
+
+    /// Let clients in these ranges, or with these User-Agents, through unchecked
+    pub fn with_exemptions(mut self, exemptions: RateLimitExemptions) -> Self {
+        self.exemptions = exemptions;
+        self
+    }
+
+    /// Cap the clients tracked at once; the least recently seen are evicted beyond it
+    ///
+    /// Without a cap, clients spoofing addresses (IPv6 makes this cheap) could
+    /// grow the limiter without bound between cleanups.
+    pub fn with_max_tracked_clients(mut self, max_tracked_clients: usize) -> Self {
+        self.max_tracked_clients = max_tracked_clients.max(1);
+        self
+    }
+
+    /// Limit IPv6 clients per network of this prefix length (128: per address)
+    pub fn with_ipv6_prefix_len(mut self, prefix_len: u8) -> Self {
+        self.ipv6_prefix_len = prefix_len.min(128);
+        self
+    }
+
+    /// Limit clients sending one of these keys in `header` by the key rather
+    /// than their address
+    ///
+    /// Keys are given as their SHA-256 digests. Anything else sent in the
+    /// header is ignored, so made-up keys can't buy fresh buckets. The
+    /// header name is checked by `Config::validate`; one that still fails to
+    /// parse leaves clients limited by address.
+    pub fn with_api_keys(mut self, header: Option<&str>, key_digests: &[String]) -> Self {
+        self.api_key_header = header.and_then(|name| match name.parse() {
+            Ok(name) => Some(name),
+            Err(e) => {
+                warn!(header = %name, error = %e, "Invalid rate limit API key header");
+                None
+            }
+        });
+        self.api_keys = key_digests
+            .iter()
+            .map(|digest| digest.to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Limit requests through these proxies by the client they forward for
+    pub fn with_trusted_proxies(mut self, trusted_proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Clients currently tracked
+    pub fn tracked_clients(&self) -> usize {
+        self.tracked.load(Ordering::Relaxed)
+    }
+
+    /// Who a request is limited as: the issued API key it sent, else its
+    /// client address
+    ///
+    /// The middleware and `/_cdn/quota` both identify clients here, so the
+    /// quota a client is shown is the one its requests spend.
+    pub fn identify(&self, headers: &HeaderMap, peer: IpAddr) -> ClientId {
+        if let Some(name) = &self.api_key_header
+            && let Some(key) = headers.get(name).and_then(|value| value.to_str().ok())
+        {
+            let digest = api_key_digest(key.trim());
+            if self.api_keys.contains(&digest) {
+                return ClientId::ApiKey(digest[..API_KEY_FINGERPRINT_LEN].to_string());
+            }
+        }
+        self.client_key(self.client_ip(headers, peer).into())
+    }
+
+    /// The address a request is limited and exempted as: the peer, or the
+    /// client a trusted proxy forwarded it for
+    pub fn client_ip(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        self.trusted_proxies.client_ip(headers, peer)
+    }
+
+    /// Bucket key for a client: its address, or its network for IPv6
+    ///
+    /// A single IPv6 host usually controls a whole /64, so limiting per
+    /// address would hand it billions of buckets.
+    fn client_key(&self, client: ClientId) -> ClientId {
+        let ClientId::Ip(ip) = client else {
+            return client;
+        };
+        ClientId::Ip(match ip.to_canonical() {
+            IpAddr::V6(v6) if self.ipv6_prefix_len < 128 => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.ipv6_prefix_len))
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+            ip => ip,
+        })
+    }
+
+    /// Make room for a new client once the cap is reached
+    ///
+    /// Evicts the least recently seen clients down to 90% of the cap, so the
+    /// full scan this takes is paid once per tenth of the cap in new clients.
+    fn evict_for_new_client(&self) {
+        let _sweep = self.eviction.lock().unwrap_or_else(|e| e.into_inner());
+        // Another caller may have made room while we waited
+        if self.tracked_clients() < self.max_tracked_clients {
+            return;
+        }
+
+        let mut by_age: Vec<(Instant, ClientId)> = self
+            .buckets
+            .iter()
+            .map(|bucket| (bucket.last_update, bucket.key().clone()))
+            .collect();
+        let target = self.max_tracked_clients * EVICT_TO_PERCENT / 100;
+        let excess = by_age.len().saturating_sub(target);
+        if excess == 0 {
+            return;
+        }
+        by_age.select_nth_unstable(excess - 1);
+
+        let mut evicted = 0;
+        for (_, key) in &by_age[..excess] {
+            if self.buckets.remove(key).is_some() {
+                self.tracked.fetch_sub(1, Ordering::Relaxed);
+                evicted += 1;
+            }
         }
+        debug!(
+            evicted,
+            max_tracked_clients = self.max_tracked_clients,
+            "Rate limiter full, evicted least recently seen clients"
+        );
+        if let Some(metrics) = &self.metrics {
+            metrics.record_rate_limit_evictions(evicted);
+        }
+    }
+
+    /// The limits currently in force
+    pub fn config(&self) -> RateLimitConfig {
+        self.config
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
     }
 
-    pub fn check(&self, ip: IpAddr) -> RateLimitResult {
-        if !self.config.enabled {
+    pub fn check(&self, client: impl Into<ClientId>) -> RateLimitResult {
+        let config = self.config();
+        if !config.enabled {
             return RateLimitResult::Allowed {
                 remaining: u32::MAX,
                 reset_secs: 0,
             };
         }
 
-        let max_tokens = self.config.requests_per_window as f64 + self.config.burst_size as f64;
-        let refill_rate = self.config.requests_per_window as f64 / self.config.window_secs as f64;
+        let max_tokens = config.max_tokens();
+        let refill_rate = config.refill_rate();
 
-        let mut bucket = self
-            .buckets
-            .entry(ip)
-            .or_insert_with(|| TokenBucket::new(max_tokens, refill_rate));
+        let key = self.client_key(client.into());
+        if !self.buckets.contains_key(&key) && self.tracked_clients() >= self.max_tracked_clients {
+            self.evict_for_new_client();
+        }
+        let mut bucket = match self.buckets.entry(key.clone()) {
+            Entry::Occupied(entry) => entry.into_ref(),
+            Entry::Vacant(entry) => {
+                self.tracked.fetch_add(1, Ordering::Relaxed);
+                entry.insert(TokenBucket::new(max_tokens, refill_rate))
+            }
+        };
 
         if bucket.try_consume(1.0) {
-            let remaining = bucket.tokens_available() as u32;
-            let reset_secs = if remaining == 0 {
-                (1.0 / refill_rate).ceil() as u64
-            } else {
-                0
-            };
+            let tokens = bucket.tokens_available();
+            let remaining = tokens as u32;
+            let reset_secs = bucket.secs_until_full(tokens);
 
-            debug!(ip = %ip, remaining = remaining, "Rate limit check passed");
+            debug!(client = %key, remaining = remaining, "Rate limit check passed");
 
             RateLimitResult::Allowed {
                 remaining,
                 reset_secs,
             }
         } else {
-            let retry_after = ((1.0 - bucket.tokens_available()) / refill_rate).ceil() as u64;
+            bucket.rejected += 1;
+            let retry_after =
+                ((1.0 - bucket.tokens_available()) / bucket.refill_rate).ceil() as u64;
 
-            warn!(ip = %ip, retry_after = retry_after, "Rate limit exceeded");
+            warn!(client = %key, retry_after = retry_after, "Rate limit exceeded");
 
             RateLimitResult::Limited { retry_after }
         }
     }
 
+    /// A client's quota as it stands, without spending any of it or starting
+    /// to track the client
+    pub fn quota(&self, client: impl Into<ClientId>) -> QuotaStatus {
+        let config = self.config();
+        let client = self.client_key(client.into());
+        let max_tokens = config.max_tokens();
+        let (remaining, reset_secs) = match self.buckets.get(&client) {
+            Some(bucket) if config.enabled => {
+                let tokens = bucket.peek_tokens();
+                (tokens as u32, bucket.secs_until_full(tokens))
+            }
+            // Unseen and unlimited clients have their whole allowance
+            _ => (max_tokens as u32, 0),
+        };
+        QuotaStatus {
+            client,
+            enabled: config.enabled,
+            limit: config.requests_per_window,
+            burst: config.burst_size,
+            window_secs: config.window_secs,
+            remaining,
+            used: (max_tokens as u32).saturating_sub(remaining),
+            reset_secs,
+            reset_at: unix_time_in(reset_secs),
+        }
+    }
+
+    /// Apply new limits without a restart, returning the resulting config
+    ///
+    /// Existing buckets are rescaled to the new size rather than reset.
+    pub fn update(&self, update: RateLimitUpdate) -> Result<RateLimitConfig, String> {
+        let mut config = self.config.write().unwrap_or_else(|e| e.into_inner());
+
+        let mut new_config = config.clone();
+        if let Some(requests_per_window) = update.requests_per_window {
+            new_config.requests_per_window = requests_per_window;
+        }
+        if let Some(window_secs) = update.window_secs {
+            new_config.window_secs = window_secs;
+        }
+        if let Some(burst_size) = update.burst_size {
+            new_config.burst_size = burst_size;
+        }
+        if let Some(enabled) = update.enabled {
+            new_config.enabled = enabled;
+        }
+
+        if new_config.requests_per_window == 0 {
+            return Err("requests_per_window must be greater than 0".to_string());
+        }
+        if new_config.window_secs == 0 {
+            return Err("window_secs must be greater than 0".to_string());
+        }
+
+        // Rescale under the write lock so concurrent updates can't interleave
+        let (max_tokens, refill_rate) = (new_config.max_tokens(), new_config.refill_rate());
+        for mut bucket in self.buckets.iter_mut() {
+            bucket.rescale(max_tokens, refill_rate);
+        }
+
+        info!(
+            requests_per_window = new_config.requests_per_window,
+            window_secs = new_config.window_secs,
+            burst_size = new_config.burst_size,
+            enabled = new_config.enabled,
+            "Rate limit config updated"
+        );
+        *config = new_config.clone();
+        Ok(new_config)
+    }
+
+    /// Tracked and currently limited clients, and the most rejected ones
+    pub fn stats(&self) -> RateLimitStats {
+        let mut limited_clients = 0;
+        let mut offenders = Vec::new();
+        for bucket in self.buckets.iter() {
+            if bucket.peek_tokens() < 1.0 {
+                limited_clients += 1;
+            }
+            if bucket.rejected > 0 {
+                offenders.push(RateLimitOffender {
+                    client: bucket.key().clone(),
+                    rejected: bucket.rejected,
+                });
+            }
+        }
+        offenders.sort_by(|a, b| b.rejected.cmp(&a.rejected).then(a.client.cmp(&b.client)));
+        offenders.truncate(TOP_OFFENDERS);
+
+        RateLimitStats {
+            tracked_clients: self.tracked_clients(),
+            max_tracked_clients: self.max_tracked_clients,
+            limited_clients,
+            top_offenders: offenders,
+        }
+    }
+
     /// Clean up old entries that haven't been used recently
     pub fn cleanup(&self, max_age: Duration) {
         let now = Instant::now();
-        self.buckets
-            .retain(|_, bucket| now.duration_since(bucket.last_update) < max_age);
+        self.buckets.retain(|_, bucket| {
+            let keep = now.duration_since(bucket.last_update) < max_age;
+            if !keep {
+                self.tracked.fetch_sub(1, Ordering::Relaxed);
+            }
+            keep
+        });
     }
 }
 
+/// Live rate limiter state for the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitStats {
+    /// Clients with a bucket (IPv6 clients are counted per network)
+    pub tracked_clients: usize,
+    /// Cap on `tracked_clients`, beyond which the least recently seen are evicted
+    pub max_tracked_clients: usize,
+    /// Clients whose next request would be refused
+    pub limited_clients: usize,
+    /// Clients with the most refused requests, most first
+    pub top_offenders: Vec<RateLimitOffender>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitOffender {
+    pub client: ClientId,
+    pub rejected: u64,
+}
+
+/// A client's own quota, as `GET /_cdn/quota` reports it
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaStatus {
+    /// Who the quota belongs to: an address, a network for IPv6, or `key:`
+    /// and a fingerprint of the API key
+    pub client: ClientId,
+    pub enabled: bool,
+    /// Requests per window
+    pub limit: u32,
+    /// Requests allowed above `limit` in a burst
+    pub burst: u32,
+    pub window_secs: u64,
+    /// Requests the client could make right now
+    pub remaining: u32,
+    /// Of `limit` and `burst`, requests spent and not yet refilled
+    pub used: u32,
+    /// Seconds until the whole allowance is back
+    pub reset_secs: u64,
+    /// `reset_secs` from now, as a Unix timestamp
+    pub reset_at: u64,
+}
+
+/// Unix time `secs` from now
+fn unix_time_in(secs: u64) -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        + secs
+}
+
+/// Clients let through without being counted against a limit
+///
+/// Ranges are matched against the same client address the limiter keys on:
+/// the peer, or the client a trusted proxy forwarded the request for. A
+/// forwarded address from anyone else is ignored and can't claim an exempt
+/// range, and listing a trusted load balancer's range exempts only its own
+/// requests.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitExemptions {
+    cidrs: Vec<String>,
+    user_agents: Vec<Regex>,
+}
+
+impl RateLimitExemptions {
+    /// Build from `rate_limit.exempt_cidrs` and `exempt_user_agents`
+    ///
+    /// Patterns are checked by `Config::validate`; any that still fail to
+    /// compile are skipped.
+    pub fn new(cidrs: &[String], user_agents: &[String], limits: &RegexLimitsConfig) -> Self {
+        let user_agents = user_agents
+            .iter()
+            .filter_map(|pattern| match limits.compile(pattern) {
+                Ok(re) => Some(re),
+                Err(e) => {
+                    warn!(pattern = %pattern, error = %e, "Failed to compile exempt User-Agent pattern");
+                    None
+                }
+            })
+            .collect();
+        Self {
+            cidrs: cidrs.to_vec(),
+            user_agents,
+        }
+    }
+
+    /// Why a request is exempt from limiting, if it is
+    pub fn reason(&self, path: &str, ip: IpAddr, user_agent: Option<&str>) -> Option<&'static str> {
+        if EXEMPT_PATHS.contains(&path) {
+            return Some("path");
+        }
+        let ip = ip.to_canonical();
+        if self
+            .cidrs
+            .iter()
+            .any(|cidr| check_cidr(&ip, cidr) == Some(true))
+        {
+            return Some("cidr");
+        }
+        if let Some(user_agent) = user_agent
+            && self.user_agents.iter().any(|re| re.is_match(user_agent))
+        {
+            return Some("user_agent");
+        }
+        None
+    }
+}
+
+/// Outcome of a rate limit check; `reset_secs` is how long until the
+/// client's whole allowance is back
 #[derive(Debug)]
 pub enum RateLimitResult {
     Allowed { remaining: u32, reset_secs: u64 },
     Limited { retry_after: u64 },
 }
 
+/// Set `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`
+fn insert_quota_headers(response: &mut Response, limit: u32, remaining: u32, reset_secs: u64) {
+    let headers = response.headers_mut();
+    for (name, value) in [
+        ("X-RateLimit-Limit", u64::from(limit)),
+        ("X-RateLimit-Remaining", u64::from(remaining)),
+        ("X-RateLimit-Reset", unix_time_in(reset_secs)),
+    ] {
+        headers.insert(name, HeaderValue::from(value));
+    }
+}
+
+/// Refuse CDN requests from clients over their limit with a 429
+///
+/// Exemptions are settled before the limiter is consulted, so an exempt
+/// request never spends or refills a client's tokens. The rest of the
+/// admin API is left to admin auth and passes through untouched. Counted
+/// requests carry the client's quota in `X-RateLimit-*` headers, whether
+/// they're refused or not.
+pub async fn rate_limit_middleware(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    if !limiter.config().enabled
+        || (path.starts_with(ADMIN_PREFIX) && !EXEMPT_PATHS.contains(&path))
+    {
+        return next.run(request).await;
+    }
+
+    lap(Phase::Queue);
+    let client_ip = limiter.client_ip(request.headers(), addr.ip());
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    if let Some(reason) = limiter.exemptions.reason(path, client_ip, user_agent) {
+        debug!(ip = %client_ip, path = %path, reason, "Request exempt from rate limit");
+        if let Some(metrics) = &limiter.metrics {
+            metrics.record_rate_limit_exempt(reason);
+        }
+        lap(Phase::RateLimit);
+        return next.run(request).await;
+    }
+
+    let limit = limiter.config().requests_per_window;
+    let client = limiter.identify(request.headers(), addr.ip());
+    let (remaining, reset_secs) = match limiter.check(client.clone()) {
+        RateLimitResult::Allowed {
+            remaining,
+            reset_secs,
+        } => (remaining, reset_secs),
+        RateLimitResult::Limited { retry_after } => {
+            let mut response = (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("Rate limit exceeded. Retry after {} seconds.", retry_after),
+            )
+                .into_response();
+
+            response
+                .headers_mut()
+                .insert("Retry-After", retry_after.to_string().parse().unwrap());
+            let reset_secs = limiter.quota(client).reset_secs;
+            insert_quota_headers(&mut response, limit, 0, reset_secs);
+
+            return response;
+        }
+    };
+
+    lap(Phase::RateLimit);
+    let mut response = next.run(request).await;
+    insert_quota_headers(&mut response, limit, remaining, reset_secs);
+    response
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -180,6 +774,189 @@ mod tests {
         }
     }
 
+    fn limiter(requests_per_window: u32, burst_size: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            requests_per_window,
+            window_secs: 3600,
+            burst_size,
+            enabled: true,
+        })
+    }
+
+    #[test]
+    fn test_update_rescales_buckets() {
+        let limiter = limiter(10, 0);
+        let busy = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let idle = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        for _ in 0..10 {
+            limiter.check(busy);
+        }
+        limiter.check(idle);
+        assert!(matches!(
+            limiter.check(busy),
+            RateLimitResult::Limited { .. }
+        ));
+
+        // Loosening the limit doesn't hand an exhausted client a fresh bucket
+        let config = limiter
+            .update(RateLimitUpdate {
+                requests_per_window: Some(100),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(config.requests_per_window, 100);
+        assert_eq!(config.window_secs, 3600);
+        assert!(matches!(
+            limiter.check(busy),
+            RateLimitResult::Limited { .. }
+        ));
+
+        // A client that had used a tenth of its bucket still has 90% of the new one
+        match limiter.check(idle) {
+            RateLimitResult::Allowed { remaining, .. } => assert_eq!(remaining, 89),
+            RateLimitResult::Limited { .. } => panic!("Should not be limited"),
+        }
+
+        assert!(
+            limiter
+                .update(RateLimitUpdate {
+                    window_secs: Some(0),
+                    ..Default::default()
+                })
+                .is_err()
+        );
+        assert_eq!(limiter.config().window_secs, 3600);
+
+        limiter
+            .update(RateLimitUpdate {
+                enabled: Some(false),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(matches!(
+            limiter.check(busy),
+            RateLimitResult::Allowed { .. }
+        ));
+    }
+
+    #[test]
+    fn test_rate_limit_stats() {
+        let limiter = limiter(2, 0);
+        let noisy = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let quiet = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        for _ in 0..5 {
+            limiter.check(noisy);
+        }
+        limiter.check(quiet);
+
+        let stats = limiter.stats();
+        assert_eq!(stats.tracked_clients, 2);
+        assert_eq!(stats.limited_clients, 1);
+        assert_eq!(stats.top_offenders.len(), 1);
+        assert_eq!(stats.top_offenders[0].client, ClientId::Ip(noisy));
+        assert_eq!(stats.top_offenders[0].rejected, 3);
+    }
+
+    #[test]
+    fn test_tracked_clients_stay_under_cap() {
+        let limiter = limiter(10, 0).with_max_tracked_clients(10_000);
+
+        // Millions of spoofed addresses, IPv4 and one IPv6 /64 each
+        for i in 0..1_000_000u32 {
+            limiter.check(IpAddr::V4(Ipv4Addr::from(i)));
+            limiter.check(IpAddr::V6(Ipv6Addr::from(u128::from(i) << 64 | 1)));
+            if i % 100_000 == 0 {
+                assert!(limiter.buckets.len() <= 10_000);
+            }
+        }
+        assert!(limiter.tracked_clients() <= 10_000);
+        assert_eq!(limiter.tracked_clients(), limiter.buckets.len());
+        assert_eq!(limiter.stats().max_tracked_clients, 10_000);
+    }
+
+    #[test]
+    fn test_eviction_keeps_recent_clients() {
+        let limiter = limiter(10, 0).with_max_tracked_clients(100);
+        let regular = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        for _ in 0..5 {
+            limiter.check(regular);
+        }
+        for i in 0..99u32 {
+            limiter.check(IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i)));
+        }
+        std::thread::sleep(Duration::from_millis(2));
+        limiter.check(regular);
+
+        // The hundred-and-first client evicts the least recently seen tenth
+        limiter.check(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)));
+        assert_eq!(limiter.tracked_clients(), 91);
+        let oldest = IpAddr::V4(Ipv4Addr::from(0x0a00_0000));
+        assert!(!limiter.buckets.contains_key(&ClientId::Ip(oldest)));
+
+        // The regular client kept its bucket, so its spent tokens still count
+        match limiter.check(regular) {
+            RateLimitResult::Allowed { remaining, .. } => assert_eq!(remaining, 3),
+            RateLimitResult::Limited { .. } => panic!("Should not be limited"),
+        }
+    }
+
+    #[test]
+    fn test_ipv6_clients_are_keyed_by_prefix() {
+        let limiter = limiter(10, 0);
+        let network = 0x2001_0db8_0000_0001u128 << 64;
+
+        // Rotating through a /64 doesn't buy a fresh bucket per address
+        for i in 0..100_000u128 {
+            limiter.check(IpAddr::V6(Ipv6Addr::from(network | i)));
+        }
+        assert_eq!(limiter.tracked_clients(), 1);
+        assert!(matches!(
+            limiter.check(IpAddr::V6(Ipv6Addr::from(network | 0xffff))),
+            RateLimitResult::Limited { .. }
+        ));
+        assert!(matches!(
+            limiter.check(IpAddr::V6(Ipv6Addr::from(network + (1u128 << 64)))),
+            RateLimitResult::Allowed { .. }
+        ));
+
+        // IPv4-mapped addresses are limited as the IPv4 client they are
+        let v4 = Ipv4Addr::new(192, 0, 2, 7);
+        limiter.check(IpAddr::V6(v4.to_ipv6_mapped()));
+        limiter.check(IpAddr::V4(v4));
+        assert_eq!(limiter.tracked_clients(), 3);
+
+        let per_address = limiter_with_prefix(128);
+        per_address.check(IpAddr::V6(Ipv6Addr::from(network | 1)));
+        per_address.check(IpAddr::V6(Ipv6Addr::from(network | 2)));
+        assert_eq!(per_address.tracked_clients(), 2);
+
+        let per_48 = limiter_with_prefix(48);
+        per_48.check(IpAddr::V6(Ipv6Addr::from(network)));
+        per_48.check(IpAddr::V6(Ipv6Addr::from(network | (0xfffeu128 << 64))));
+        assert_eq!(per_48.tracked_clients(), 1);
+    }
+
+    fn limiter_with_prefix(prefix_len: u8) -> RateLimiter {
+        limiter(10, 0).with_ipv6_prefix_len(prefix_len)
+    }
+
+    #[test]
+    fn test_cleanup_untracks_idle_clients() {
+        let limiter = limiter(10, 0);
+        limiter.check(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        limiter.check(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(limiter.tracked_clients(), 2);
+
+        limiter.cleanup(Duration::from_secs(60));
+        assert_eq!(limiter.tracked_clients(), 2);
+        limiter.cleanup(Duration::ZERO);
+        assert_eq!(limiter.tracked_clients(), 0);
+        assert!(limiter.buckets.is_empty());
+    }
+
     #[test]
     fn test_disabled_rate_limiter() {
         let config = RateLimitConfig {
@@ -198,4 +975,269 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_api_key_clients_are_limited_per_key() {
+        let issued = [api_key_digest("secret-a"), api_key_digest("secret-b")];
+        let limiter = limiter(1, 0).with_api_keys(Some("X-Api-Key"), &issued);
+        let first = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let second = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let with_key = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-api-key", key.parse().unwrap());
+            headers
+        };
+
+        // A key follows its client across addresses
+        let partner = limiter.identify(&with_key("secret-a"), first);
+        assert_eq!(limiter.identify(&with_key(" secret-a "), second), partner);
+        assert!(matches!(
+            limiter.check(partner.clone()),
+            RateLimitResult::Allowed { .. }
+        ));
+        assert!(matches!(
+            limiter.check(limiter.identify(&with_key("secret-a"), second)),
+            RateLimitResult::Limited { .. }
+        ));
+
+        // Other issued keys, and the address itself, have limits of their own
+        for headers in [with_key("secret-b"), HeaderMap::new()] {
+            let client = limiter.identify(&headers, first);
+            assert!(matches!(
+                limiter.check(client),
+                RateLimitResult::Allowed { .. }
+            ));
+        }
+
+        // Keys that weren't issued are limited as the address they come from
+        for made_up in ["", "secret-c", "SECRET-A"] {
+            let client = limiter.identify(&with_key(made_up), first);
+            assert_eq!(client, ClientId::Ip(first));
+            assert!(matches!(
+                limiter.check(client),
+                RateLimitResult::Limited { .. }
+            ));
+        }
+        assert_eq!(limiter.tracked_clients(), 3);
+
+        // Keys are only ever shown as a fingerprint
+        let shown = partner.to_string();
+        assert!(shown.starts_with("key:") && !shown.contains("secret"));
+        let offenders = limiter.stats().top_offenders;
+        assert!(offenders.iter().any(|offender| offender.client == partner));
+    }
+
+    #[test]
+    fn test_quota_peeks_without_spending() {
+        let limiter = limiter(10, 2);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        let quota = limiter.quota(ip);
+        assert_eq!((quota.limit, quota.burst, quota.remaining), (10, 2, 12));
+        assert_eq!((quota.used, quota.reset_secs), (0, 0));
+        assert_eq!(limiter.tracked_clients(), 0);
+
+        for _ in 0..3 {
+            limiter.check(ip);
+        }
+        for _ in 0..2 {
+            let quota = limiter.quota(ip);
+            assert_eq!(quota.client, ClientId::Ip(ip));
+            assert_eq!((quota.remaining, quota.used), (9, 3));
+            // Three tokens at one per six minutes
+            assert!(quota.reset_secs > 1070 && quota.reset_secs <= 1080);
+            assert!(quota.reset_at >= unix_time_in(1070));
+        }
+        match limiter.check(ip) {
+            RateLimitResult::Allowed { remaining, .. } => assert_eq!(remaining, 8),
+            RateLimitResult::Limited { .. } => panic!("Should not be limited"),
+        }
+    }
+
+    /// Allows one request per client, in front of a probe path and a CDN path
+    fn limited_app(
+        exemptions: RateLimitExemptions,
+        metrics: Arc<Metrics>,
+        peer: &str,
+    ) -> axum::Router {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            requests_per_window: 1,
+            window_secs: 3600,
+            burst_size: 0,
+            enabled: true,
+        })
+        .with_exemptions(exemptions)
+        .with_metrics(metrics);
+        axum::Router::new()
+            .route("/_cdn/health", axum::routing::get(|| async { "ok" }))
+            .route("/_cdn/stats", axum::routing::get(|| async { "stats" }))
+            .route("/{*path}", axum::routing::get(|| async { "asset" }))
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::new(limiter),
+                rate_limit_middleware,
+            ))
+            .layer(axum::extract::connect_info::MockConnectInfo(
+                peer.parse::<SocketAddr>().unwrap(),
+            ))
+    }
+
+    async fn status(app: &axum::Router, path: &str, user_agent: Option<&str>) -> StatusCode {
+        use tower::ServiceExt;
+
+        let mut request = Request::builder().uri(path);
+        if let Some(user_agent) = user_agent {
+            request = request.header(header::USER_AGENT, user_agent);
+        }
+        app.clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_limited_client_can_still_be_health_checked() {
+        let metrics = Arc::new(Metrics::new());
+        let app = limited_app(
+            RateLimitExemptions::default(),
+            metrics.clone(),
+            "192.0.2.1:4000",
+        );
+
+        assert_eq!(status(&app, "/web/a.css", None).await, StatusCode::OK);
+        assert_eq!(
+            status(&app, "/web/a.css", None).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        for _ in 0..3 {
+            assert_eq!(status(&app, "/_cdn/health", None).await, StatusCode::OK);
+        }
+        // Admin routes are left to admin auth and counted as neither
+        assert_eq!(status(&app, "/_cdn/stats", None).await, StatusCode::OK);
+
+        let text = metrics.gather();
+        assert!(text.contains(r#"cdn_rate_limit_exempt_total{reason="path"} 3"#));
+    }
+
+    #[tokio::test]
+    async fn test_counted_responses_carry_quota_headers() {
+        use tower::ServiceExt;
+
+        let app = limited_app(
+            RateLimitExemptions::default(),
+            Arc::new(Metrics::new()),
+            "192.0.2.1:4000",
+        );
+        let get = |path: &'static str| {
+            app.clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+        };
+        let header = |response: &Response, name: &str| -> Option<u64> {
+            response.headers().get(name)?.to_str().ok()?.parse().ok()
+        };
+
+        let allowed = get("/web/a.css").await.unwrap();
+        let limited = get("/web/a.css").await.unwrap();
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        for response in [&allowed, &limited] {
+            assert_eq!(header(response, "x-ratelimit-limit"), Some(1));
+            assert_eq!(header(response, "x-ratelimit-remaining"), Some(0));
+            let reset = header(response, "x-ratelimit-reset").unwrap();
+            assert!(reset > unix_time_in(3500) && reset <= unix_time_in(3600));
+        }
+
+        // Uncounted requests say nothing about the quota
+        let health = get("/_cdn/health").await.unwrap();
+        assert!(health.headers().get("x-ratelimit-limit").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_exempt_cidrs_and_user_agents_skip_the_limiter() {
+        let metrics = Arc::new(Metrics::new());
+        let exemptions = RateLimitExemptions::new(
+            &["10.0.0.0/8".to_string()],
+            &["^ELB-HealthChecker/".to_string()],
+            &RegexLimitsConfig::default(),
+        );
+        let peer = limited_app(exemptions.clone(), metrics.clone(), "10.1.2.3:4000");
+        for _ in 0..3 {
+            assert_eq!(status(&peer, "/web/a.css", None).await, StatusCode::OK);
+        }
+
+        let client = limited_app(exemptions, metrics.clone(), "192.0.2.1:4000");
+        for _ in 0..3 {
+            let probe = status(&client, "/web/a.css", Some("ELB-HealthChecker/2.0")).await;
+            assert_eq!(probe, StatusCode::OK);
+        }
+        assert_eq!(
+            status(&client, "/web/a.css", Some("curl/8.0")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&client, "/web/a.css", Some("curl/8.0")).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        let text = metrics.gather();
+        assert!(text.contains(r#"cdn_rate_limit_exempt_total{reason="cidr"} 3"#));
+        assert!(text.contains(r#"cdn_rate_limit_exempt_total{reason="user_agent"} 3"#));
+    }
+
+    #[tokio::test]
+    async fn test_forged_forwarded_for_is_still_limited() {
+        use tower::ServiceExt;
+
+        let exemptions = RateLimitExemptions::new(
+            &["10.0.0.0/8".to_string()],
+            &[],
+            &RegexLimitsConfig::default(),
+        );
+        let app = limited_app(exemptions, Arc::new(Metrics::new()), "192.0.2.1:4000");
+        let mut statuses = Vec::new();
+        for forged in ["10.0.0.2", "10.0.0.3", "198.51.100.7"] {
+            let request = Request::get("/web/a.css")
+                .header("X-Forwarded-For", forged)
+                .header("X-Real-IP", forged)
+                .body(Body::empty())
+                .unwrap();
+            statuses.push(app.clone().oneshot(request).await.unwrap().status());
+        }
+        // Neither an exempt range nor a fresh address buys another request
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exempt_cidrs_match_the_forwarded_client() {
+        let exemptions = RateLimitExemptions::new(
+            &["10.0.0.0/8".to_string()],
+            &[],
+            &RegexLimitsConfig::default(),
+        );
+        let limiter =
+            limiter(10, 0).with_trusted_proxies(TrustedProxies::new(&["10.0.0.2/32".to_string()]));
+        let lb: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            exemptions.reason("/web/a", limiter.client_ip(&headers, lb), None),
+            Some("cidr")
+        );
+
+        // Traffic proxied through the load balancer is judged by its client,
+        // even when the client claims an exempt address itself
+        headers.insert("X-Forwarded-For", "10.0.0.9, 198.51.100.7".parse().unwrap());
+        assert_eq!(
+            exemptions.reason("/web/a", limiter.client_ip(&headers, lb), None),
+            None
+        );
+
+        let mapped: IpAddr = "::ffff:10.0.0.9".parse().unwrap();
+        assert_eq!(exemptions.reason("/web/a", mapped, None), Some("cidr"));
+    }
 }
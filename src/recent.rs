@@ -0,0 +1,296 @@
+//! Recently served cache keys
+//!
+//! Keeps a bounded, rolling record of the distinct keys the CDN has served
+//! with a hit count and the time each was last seen. The record backs
+//! `GET /_cdn/cache/recent` and `POST /_cdn/warm/replay`, which feeds the
+//! busiest keys back into cache warming, and can be saved on shutdown so a
+//! restarted node re-warms what it was serving before.
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::auth::unix_now;
+use crate::config::RecentKeysConfig;
+
+/// One recently served key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecentEntry {
+    pub origin: String,
+    /// Request path, including the query string if there was one
+    pub path: String,
+    pub hits: u64,
+    /// Unix timestamp of the last recorded request
+    pub last_seen: u64,
+}
+
+/// Bounded record of recently served keys
+pub struct RecentRequests {
+    config: RecentKeysConfig,
+    entries: DashMap<String, RecentEntry>,
+    counter: AtomicU64,
+}
+
+impl RecentRequests {
+    pub fn new(config: RecentKeysConfig) -> Self {
+        Self {
+            config,
+            entries: DashMap::new(),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Number of distinct keys currently tracked
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Record a served request, subject to sampling
+    pub fn record(&self, origin: &str, path: &str, query: Option<&str>) {
+        self.record_at(origin, path, query, unix_now());
+    }
+
+    fn record_at(&self, origin: &str, path: &str, query: Option<&str>, now: u64) {
+        if !self.config.enabled {
+            return;
+        }
+        let sample_every = self.config.sample_every.max(1);
+        if sample_every > 1
+            && !self
+                .counter
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(sample_every)
+        {
+            return;
+        }
+
+        let path = match query {
+            Some(query) if !query.is_empty() => format!("{}?{}", path, query),
+            _ => path.to_string(),
+        };
+        let key = format!("{}:{}", origin, path);
+        self.entries
+            .entry(key)
+            .and_modify(|entry| {
+                entry.hits += 1;
+                entry.last_seen = now;
+            })
+            .or_insert_with(|| RecentEntry {
+                origin: origin.to_string(),
+                path,
+                hits: 1,
+                last_seen: now,
+            });
+
+        self.trim();
+    }
+
+    /// Drop the least recently seen keys once the record is over capacity
+    ///
+    /// Trimming waits for 10% slack above capacity so the sort runs rarely
+    /// rather than on every new key.
+    fn trim(&self) {
+        let capacity = self.config.capacity.max(1);
+        let slack = (capacity / 10).max(1);
+        if self.entries.len() <= capacity + slack {
+            return;
+        }
+
+        let mut by_age: Vec<(u64, String)> = self
+            .entries
+            .iter()
+            .map(|entry| (entry.last_seen, entry.key().clone()))
+            .collect();
+        by_age.sort_unstable();
+        let surplus = by_age.len().saturating_sub(capacity);
+        for (_, key) in by_age.into_iter().take(surplus) {
+            self.entries.remove(&key);
+        }
+    }
+
+    /// The most-hit keys, optionally only those seen within `window`
+    ///
+    /// Ties are broken by the most recently seen.
+    pub fn top(&self, limit: usize, window: Option<Duration>) -> Vec<RecentEntry> {
+        self.top_at(limit, window, unix_now())
+    }
+
+    fn top_at(&self, limit: usize, window: Option<Duration>, now: u64) -> Vec<RecentEntry> {
+        let cutoff = window.map(|window| now.saturating_sub(window.as_secs()));
+        let mut entries: Vec<RecentEntry> = self
+            .entries
+            .iter()
+            .filter(|entry| cutoff.is_none_or(|cutoff| entry.last_seen >= cutoff))
+            .map(|entry| entry.value().clone())
+            .collect();
+        entries.sort_by(|a, b| {
+            b.hits
+                .cmp(&a.hits)
+                .then(b.last_seen.cmp(&a.last_seen))
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        entries.truncate(limit);
+        entries
+    }
+
+    /// Write the record to `path` as JSON lines
+    pub fn save(&self, path: &Path) -> std::io::Result<usize> {
+        let entries = self.top_at(usize::MAX, None, 0);
+        let mut writer = BufWriter::new(std::fs::File::create(path)?);
+        for entry in &entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(entries.len())
+    }
+
+    /// Merge a record previously written by [`save`](Self::save)
+    ///
+    /// Lines that don't parse are skipped.
+    pub fn load(&self, path: &Path) -> std::io::Result<usize> {
+        let reader = BufReader::new(std::fs::File::open(path)?);
+        let mut loaded = 0;
+        for line in reader.lines() {
+            let Ok(entry) = serde_json::from_str::<RecentEntry>(&line?) else {
+                continue;
+            };
+            let key = format!("{}:{}", entry.origin, entry.path);
+            self.entries.insert(key, entry);
+            loaded += 1;
+        }
+        self.trim();
+        Ok(loaded)
+    }
+}
+
+/// Parse a window such as `90`, `45s`, `30m`, `1h` or `2d`
+pub fn parse_window(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let multiplier = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(multiplier)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recent(capacity: usize, sample_every: u64) -> RecentRequests {
+        RecentRequests::new(RecentKeysConfig {
+            capacity,
+            sample_every,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_top_orders_by_hits_and_window() {
+        let recent = recent(100, 1);
+        for _ in 0..3 {
+            recent.record_at("web", "/popular", None, 1_000);
+        }
+        recent.record_at("web", "/search", Some("q=rust"), 1_000);
+        recent.record_at("web", "/search", Some("q=rust"), 5_000);
+        recent.record_at("web", "/old", None, 100);
+        recent.record_at("api", "/popular", None, 4_000);
+
+        let top = recent.top_at(10, None, 5_000);
+        let order: Vec<_> = top
+            .iter()
+            .map(|e| (e.origin.as_str(), e.path.as_str(), e.hits))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("web", "/popular", 3),
+                ("web", "/search?q=rust", 2),
+                ("api", "/popular", 1),
+                ("web", "/old", 1),
+            ]
+        );
+
+        // Only keys seen in the last ~1h (from t=5000) survive the window
+        let windowed = recent.top_at(10, Some(Duration::from_secs(3_600)), 5_000);
+        let paths: Vec<_> = windowed.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["/search?q=rust", "/popular"]);
+
+        assert_eq!(recent.top_at(1, None, 5_000).len(), 1);
+    }
+
+    #[test]
+    fn test_capacity_drops_least_recently_seen() {
+        let recent = recent(10, 1);
+        for i in 0..30 {
+            recent.record_at("web", &format!("/p{}", i), None, i);
+        }
+        assert!(recent.len() <= 11);
+        let top = recent.top_at(100, None, 30);
+        assert!(top.iter().all(|e| e.last_seen >= 19));
+        assert!(top.iter().any(|e| e.path == "/p29"));
+    }
+
+    #[test]
+    fn test_sampling_and_disabled() {
+        let sampled = recent(100, 4);
+        for _ in 0..8 {
+            sampled.record_at("web", "/a", None, 1);
+        }
+        assert_eq!(sampled.top_at(1, None, 1)[0].hits, 2);
+
+        let disabled = RecentRequests::new(RecentKeysConfig {
+            enabled: false,
+            ..Default::default()
+        });
+        disabled.record("web", "/a", None);
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn test_save_and_load_roundtrip() {
+        let path = std::env::temp_dir().join(format!("se-recent-{}.jsonl", std::process::id()));
+        let recent = recent(100, 1);
+        recent.record_at("web", "/a", Some("x=1"), 10);
+        recent.record_at("web", "/a", Some("x=1"), 20);
+        recent.record_at("api", "/b", None, 30);
+        assert_eq!(recent.save(&path).unwrap(), 2);
+
+        let restored = self::recent(100, 1);
+        assert_eq!(restored.load(&path).unwrap(), 2);
+        assert_eq!(restored.top_at(10, None, 30), recent.top_at(10, None, 30));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_window("45s"), Some(Duration::from_secs(45)));
+        assert_eq!(parse_window("30m"), Some(Duration::from_secs(1_800)));
+        assert_eq!(parse_window("1h"), Some(Duration::from_secs(3_600)));
+        assert_eq!(parse_window("2d"), Some(Duration::from_secs(172_800)));
+        for bad in ["", "h", "1w", "-1h", "1.5h"] {
+            assert_eq!(parse_window(bad), None, "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Request shape limits applied before routing
+//!
+//! Rejects request targets over `server.max_url_length` with a 414 and
+//! strips or rejects bodies sent on GET and HEAD, per `server.get_body`.
+//! Bodies are never buffered: a declared Content-Length is trusted, and a
+//! chunked body is only read far enough to see whether it has any data.
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Method, Request, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body::Body as _;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::config::{GetBodyPolicy, ServerConfig};
+use crate::error::CdnError;
+use crate::metrics::Metrics;
+
+/// URL length and GET body policy
+pub struct RequestLimits {
+    max_url_length: usize,
+    get_body: GetBodyPolicy,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl RequestLimits {
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            max_url_length: config.max_url_length,
+            get_body: config.get_body,
+            metrics: None,
+        }
+    }
+
+    /// Count rejections and stripped bodies in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Whether a request target of `len` bytes is over the limit
+    pub fn url_too_long(&self, len: usize) -> bool {
+        self.max_url_length > 0 && len > self.max_url_length
+    }
+
+    fn record_get_body(&self, action: &str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_get_body(action);
+        }
+    }
+}
+
+/// Whether a GET/HEAD body has data, reading at most one frame of it
+///
+/// A Content-Length (exact size hint) is answered without reading. A
+/// chunked body is read with a zero-byte limit, which fails on the first
+/// data frame, so an empty chunked body is the only one read to the end.
+async fn has_body(body: Body) -> bool {
+    match body.size_hint().exact() {
+        Some(len) => len > 0,
+        None => axum::body::to_bytes(body, 0).await.is_err(),
+    }
+}
+
+pub async fn request_limits_middleware(
+    State(limits): State<Arc<RequestLimits>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let url_length = request
+        .uri()
+        .path_and_query()
+        .map_or(0, |target| target.as_str().len());
+    if limits.url_too_long(url_length) {
+        debug!(url_length, "Rejected request over URL length limit");
+        if let Some(metrics) = &limits.metrics {
+            metrics.record_url_length_rejection();
+        }
+        return CdnError::UriTooLong(format!(
+            "Request URL is {} bytes, over the {} byte limit",
+            url_length, limits.max_url_length
+        ))
+        .into_response();
+    }
+
+    let method = request.method();
+    if method != Method::GET && method != Method::HEAD {
+        return next.run(request).await;
+    }
+
+    if request.body().is_end_stream() {
+        return next.run(request).await;
+    }
+
+    let (mut parts, body) = request.into_parts();
+    match limits.get_body {
+        GetBodyPolicy::Strip => {
+            // Dropped unread; the connection is closed rather than drained
+            drop(body);
+            debug!(method = %parts.method, "Stripped body from request");
+            limits.record_get_body("stripped");
+        }
+        GetBodyPolicy::Reject => {
+            if has_body(body).await {
+                debug!(method = %parts.method, "Rejected request with a body");
+                limits.record_get_body("rejected");
+                return CdnError::InvalidRequest(format!(
+                    "{} requests must not have a body",
+                    parts.method
+                ))
+                .into_response();
+            }
+        }
+    }
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.remove(header::TRANSFER_ENCODING);
+    next.run(Request::from_parts(parts, Body::empty())).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use axum::{Router, middleware::from_fn_with_state, routing::get};
+    use bytes::Bytes;
+    use tower::ServiceExt;
+
+    /// Echoes the body length and framing headers the handler received
+    async fn run(limits: RequestLimits, request: Request<Body>) -> (StatusCode, String) {
+        let app = Router::new()
+            .route(
+                "/{*path}",
+                get(|request: Request<Body>| async move {
+                    let framed = request.headers().contains_key(header::CONTENT_LENGTH)
+                        || request.headers().contains_key(header::TRANSFER_ENCODING);
+                    let body = axum::body::to_bytes(request.into_body(), usize::MAX)
+                        .await
+                        .unwrap();
+                    format!("{} {}", body.len(), framed)
+                }),
+            )
+            .layer(from_fn_with_state(
+                Arc::new(limits),
+                request_limits_middleware,
+            ));
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    fn limits(get_body: GetBodyPolicy, metrics: &Arc<Metrics>) -> RequestLimits {
+        RequestLimits::new(&ServerConfig {
+            max_url_length: 64,
+            get_body,
+            ..crate::config::Config::default().server
+        })
+        .with_metrics(metrics.clone())
+    }
+
+    fn get_with_body(body: Body, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut request = Request::builder().uri("/a");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(body).unwrap()
+    }
+
+    /// A chunked body: a stream with no Content-Length
+    fn chunked(chunks: Vec<&'static str>) -> Body {
+        Body::from_stream(futures::stream::iter(
+            chunks
+                .into_iter()
+                .map(|chunk| Ok::<_, std::io::Error>(Bytes::from(chunk))),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_long_urls_get_414() {
+        let metrics = Arc::new(Metrics::new());
+        let request = |uri: String| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let (status, _) = run(
+            limits(GetBodyPolicy::Strip, &metrics),
+            request(format!("/a?q={}", "x".repeat(50))),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = run(
+            limits(GetBodyPolicy::Strip, &metrics),
+            request(format!("/a?q={}", "x".repeat(70))),
+        )
+        .await;
+        assert_eq!(status, StatusCode::URI_TOO_LONG);
+        assert!(body.contains("URL"), "{}", body);
+
+        let unlimited = RequestLimits::new(&ServerConfig {
+            max_url_length: 0,
+            ..crate::config::Config::default().server
+        });
+        let (status, _) = run(unlimited, request(format!("/{}", "x".repeat(20_000)))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        assert!(
+            metrics
+                .gather()
+                .contains("cdn_url_length_rejections_total 1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_bodies_are_stripped() {
+        let metrics = Arc::new(Metrics::new());
+        let strip = || limits(GetBodyPolicy::Strip, &metrics);
+
+        let (status, body) = run(
+            strip(),
+            get_with_body(Body::from("payload"), &[("content-length", "7")]),
+        )
+        .await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "0 false"));
+
+        let (status, body) = run(
+            strip(),
+            get_with_body(
+                chunked(vec!["chunk", "ed"]),
+                &[("transfer-encoding", "chunked")],
+            ),
+        )
+        .await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "0 false"));
+
+        let (status, body) = run(strip(), get_with_body(Body::empty(), &[])).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "0 false"));
+
+        assert!(
+            metrics
+                .gather()
+                .contains(r#"cdn_get_bodies_total{action="stripped"} 2"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_bodies_are_rejected() {
+        let metrics = Arc::new(Metrics::new());
+        let reject = || limits(GetBodyPolicy::Reject, &metrics);
+
+        let (status, _) = run(
+            reject(),
+            get_with_body(Body::from("payload"), &[("content-length", "7")]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Chunked bodies have no Content-Length to go on
+        let (status, _) = run(
+            reject(),
+            get_with_body(
+                chunked(vec!["chunk", "ed"]),
+                &[("transfer-encoding", "chunked")],
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // An empty chunked body is no body at all
+        let (status, body) = run(
+            reject(),
+            get_with_body(chunked(vec![]), &[("transfer-encoding", "chunked")]),
+        )
+        .await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "0 false"));
+
+        assert!(
+            metrics
+                .gather()
+                .contains(r#"cdn_get_bodies_total{action="rejected"} 2"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejection_reads_at_most_one_frame() {
+        let metrics = Arc::new(Metrics::new());
+        let pulled = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = pulled.clone();
+
+        // An endless chunked body must not be read to the end
+        let endless = futures::stream::repeat_with(move || {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok::<_, std::io::Error>(Bytes::from_static(&[0u8; 1024]))
+        });
+        let (status, _) = run(
+            limits(GetBodyPolicy::Reject, &metrics),
+            get_with_body(
+                Body::from_stream(endless),
+                &[("transfer-encoding", "chunked")],
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(pulled.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_other_methods_keep_their_bodies() {
+        use axum::routing::post;
+
+        let app = Router::new()
+            .route(
+                "/a",
+                post(|body: Bytes| async move { body.len().to_string() }),
+            )
+            .layer(from_fn_with_state(
+                Arc::new(RequestLimits::new(&ServerConfig {
+                    get_body: GetBodyPolicy::Reject,
+                    ..crate::config::Config::default().server
+                })),
+                request_limits_middleware,
+            ));
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/a")
+            .body(Body::from("payload"))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "7");
+    }
+}
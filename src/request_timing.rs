@@ -0,0 +1,362 @@
+//! Where a request's time went
+//!
+//! [`request_timing_middleware`] starts a [`RequestTiming`] for each request
+//! and scopes it to the request task. Code along the way calls [`lap`] at
+//! phase boundaries, charging the time since the previous boundary to a
+//! phase, so the phases always add up to the whole: nothing falls between
+//! them and nothing is counted twice. A phase lapped more than once, such as
+//! `queue` on either side of the rate limiter, accumulates.
+//!
+//! The `write` phase runs from the response headers until the body's last
+//! frame is handed to the connection, so it covers compression and any wait
+//! on a slow client. `Server-Timing` goes out with the headers and can't
+//! include it; `cdn_request_phase_seconds` does.
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::bandwidth::{CountingBody, ServedFrom};
+use crate::metrics::Metrics;
+
+/// A stretch of a request's life
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Arrival to the handler, through the middleware stack
+    Queue,
+    /// Rate-limit exemptions and the token bucket
+    RateLimit,
+    /// Keying the request and looking it up in the cache
+    Cache,
+    /// Waiting on another request's origin fetch
+    Coalesce,
+    /// Fetching from the origin
+    Origin,
+    /// Range handling and client-facing header changes
+    Transform,
+    /// Building the response and the way back out through middleware
+    Build,
+    /// Sending the body, compression included
+    Write,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 8] = [
+        Phase::Queue,
+        Phase::RateLimit,
+        Phase::Cache,
+        Phase::Coalesce,
+        Phase::Origin,
+        Phase::Transform,
+        Phase::Build,
+        Phase::Write,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::Queue => "queue",
+            Phase::RateLimit => "rate_limit",
+            Phase::Cache => "cache",
+            Phase::Coalesce => "coalesce",
+            Phase::Origin => "origin",
+            Phase::Transform => "transform",
+            Phase::Build => "build",
+            Phase::Write => "write",
+        }
+    }
+}
+
+/// Time charged to each phase of one request
+#[derive(Debug)]
+pub struct RequestTiming {
+    started: Instant,
+    laps: Mutex<Laps>,
+}
+
+#[derive(Debug)]
+struct Laps {
+    /// End of the last lap
+    mark: Instant,
+    /// By position in [`Phase::ALL`]; `None` for phases never lapped
+    phases: [Option<Duration>; Phase::ALL.len()],
+}
+
+impl RequestTiming {
+    pub fn start() -> Self {
+        Self::start_at(Instant::now())
+    }
+
+    pub fn start_at(started: Instant) -> Self {
+        Self {
+            started,
+            laps: Mutex::new(Laps {
+                mark: started,
+                phases: [None; Phase::ALL.len()],
+            }),
+        }
+    }
+
+    /// Charge the time since the last lap to `phase`
+    pub fn lap(&self, phase: Phase) {
+        self.lap_at(phase, Instant::now());
+    }
+
+    /// Charge the time from the last lap to `now` to `phase`
+    pub fn lap_at(&self, phase: Phase, now: Instant) {
+        let mut laps = self.laps.lock().unwrap_or_else(|e| e.into_inner());
+        let elapsed = now.saturating_duration_since(laps.mark);
+        laps.mark = laps.mark.max(now);
+        let slot = &mut laps.phases[phase as usize];
+        *slot = Some(slot.unwrap_or_default() + elapsed);
+    }
+
+    /// Phases lapped so far, in the order requests go through them
+    pub fn phases(&self) -> Vec<(Phase, Duration)> {
+        let laps = self.laps.lock().unwrap_or_else(|e| e.into_inner());
+        Phase::ALL
+            .into_iter()
+            .zip(laps.phases)
+            .filter_map(|(phase, duration)| Some((phase, duration?)))
+            .collect()
+    }
+
+    /// Start to the last lap, which the phases add up to
+    pub fn total(&self) -> Duration {
+        let laps = self.laps.lock().unwrap_or_else(|e| e.into_inner());
+        laps.mark.saturating_duration_since(self.started)
+    }
+
+    /// `Server-Timing` value for the phases so far, in milliseconds, ending
+    /// with their `total`
+    pub fn server_timing(&self) -> String {
+        let mut value = String::new();
+        for (phase, duration) in self.phases() {
+            let _ = write!(value, "{};dur={:.3}, ", phase.as_str(), millis(duration));
+        }
+        let _ = write!(value, "total;dur={:.3}", millis(self.total()));
+        value
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+tokio::task_local! {
+    static REQUEST_TIMING: Arc<RequestTiming>;
+}
+
+/// Run `work` with `timing` as the request's timing
+pub fn scope<F: Future>(timing: Arc<RequestTiming>, work: F) -> impl Future<Output = F::Output> {
+    REQUEST_TIMING.scope(timing, work)
+}
+
+/// Charge the time since the last boundary to `phase`
+///
+/// Does nothing outside a timed request, including in tasks spawned from
+/// one, so background fills and revalidations never lap.
+pub fn lap(phase: Phase) {
+    let _ = REQUEST_TIMING.try_with(|timing| timing.lap(phase));
+}
+
+/// What [`request_timing_middleware`] records to
+#[derive(Clone)]
+pub struct RequestTimingRecorder {
+    metrics: Arc<Metrics>,
+    server_timing: bool,
+}
+
+impl RequestTimingRecorder {
+    pub fn new(metrics: Arc<Metrics>, server_timing: bool) -> Self {
+        Self {
+            metrics,
+            server_timing,
+        }
+    }
+}
+
+/// Middleware timing each request's phases
+///
+/// Goes outside everything but logging and request IDs, so `queue` covers
+/// the rest of the stack. Only CDN responses (those carrying a
+/// [`ServedFrom`]) are reported; the admin API and early errors pass
+/// through untimed.
+pub async fn request_timing_middleware(
+    State(recorder): State<RequestTimingRecorder>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let timing = Arc::new(RequestTiming::start());
+    let mut response = scope(timing.clone(), next.run(request)).await;
+
+    let Some(served) = response.extensions().get::<ServedFrom>().cloned() else {
+        return response;
+    };
+    timing.lap(Phase::Build);
+
+    // Appended, so entries the origin sent are kept alongside ours
+    if recorder.server_timing
+        && let Ok(value) = HeaderValue::from_str(&timing.server_timing())
+    {
+        response.headers_mut().append("server-timing", value);
+    }
+
+    response.map(|body| {
+        Body::new(CountingBody::new(body, move |outcome| {
+            timing.lap_at(Phase::Write, outcome.finished_at);
+            recorder
+                .metrics
+                .record_request_phases(&served.origin, timing.phases());
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CacheStatus;
+    use axum::Router;
+    use axum::middleware;
+    use axum::routing::get;
+    use tower::ServiceExt;
+
+    /// Phases and total of a `Server-Timing` value, failing on anything
+    /// malformed
+    fn parse(value: &str) -> (Vec<(String, f64)>, f64) {
+        let mut entries: Vec<(String, f64)> = value
+            .split(", ")
+            .map(|entry| {
+                let (name, duration) = entry.split_once(";dur=").expect(entry);
+                assert!(
+                    !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+                );
+                let (_, decimals) = duration.split_once('.').expect(entry);
+                assert_eq!(decimals.len(), 3, "{}", entry);
+                (name.to_string(), duration.parse().expect(entry))
+            })
+            .collect();
+        let (name, total) = entries.pop().unwrap();
+        assert_eq!(name, "total");
+        (entries, total)
+    }
+
+    #[test]
+    fn test_laps_add_up_to_the_total() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let timing = RequestTiming::start_at(start);
+        timing.lap_at(Phase::Queue, at(2));
+        timing.lap_at(Phase::RateLimit, at(3));
+        timing.lap_at(Phase::Queue, at(4));
+        timing.lap_at(Phase::Origin, at(50));
+        timing.lap_at(Phase::Cache, at(51));
+        // A stamp from before the last lap charges nothing
+        timing.lap_at(Phase::Build, at(40));
+
+        assert_eq!(
+            timing.phases(),
+            vec![
+                (Phase::Queue, Duration::from_millis(3)),
+                (Phase::RateLimit, Duration::from_millis(1)),
+                (Phase::Cache, Duration::from_millis(1)),
+                (Phase::Origin, Duration::from_millis(46)),
+                (Phase::Build, Duration::ZERO),
+            ]
+        );
+        assert_eq!(timing.total(), Duration::from_millis(51));
+        assert_eq!(
+            timing.server_timing(),
+            "queue;dur=3.000, rate_limit;dur=1.000, cache;dur=1.000, origin;dur=46.000, \
+             build;dur=0.000, total;dur=51.000"
+        );
+    }
+
+    #[test]
+    fn test_lap_outside_a_request_does_nothing() {
+        lap(Phase::Cache);
+    }
+
+    #[tokio::test]
+    async fn test_middleware_reports_phases_of_cdn_responses() {
+        let metrics = Arc::new(Metrics::new());
+        let app = Router::new()
+            .route(
+                "/web/page",
+                get(|| async {
+                    lap(Phase::Queue);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    lap(Phase::Cache);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    lap(Phase::Origin);
+                    let mut response = Response::new(Body::from("page"));
+                    response.extensions_mut().insert(ServedFrom {
+                        origin: "web".to_string(),
+                        cache_status: CacheStatus::Miss,
+                    });
+                    response
+                }),
+            )
+            .route("/_cdn/health", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(
+                RequestTimingRecorder::new(metrics.clone(), true),
+                request_timing_middleware,
+            ));
+
+        let started = Instant::now();
+        let response = app
+            .clone()
+            .oneshot(Request::get("/web/page").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let measured = millis(started.elapsed());
+        let header = response.headers()["server-timing"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        let (phases, total) = parse(&header);
+        let names: Vec<&str> = phases.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["queue", "cache", "origin", "build"]);
+        let sum: f64 = phases.iter().map(|(_, duration)| duration).sum();
+        assert!((sum - total).abs() < 0.01, "{} vs {}", sum, total);
+        assert!(
+            total >= 25.0 && total <= measured,
+            "{} of {}",
+            total,
+            measured
+        );
+        assert!(phases[2].1 >= 20.0);
+
+        metrics.flush().await;
+        let text = metrics.gather();
+        for phase in ["queue", "cache", "origin", "build", "write"] {
+            assert!(
+                text.contains(&format!(
+                    "cdn_request_phase_seconds_count{{origin=\"web\",phase=\"{}\"}} 1",
+                    phase
+                )),
+                "{}",
+                phase
+            );
+        }
+        assert!(!text.contains("phase=\"coalesce\""));
+
+        // Admin responses aren't timed
+        let response = app
+            .oneshot(Request::get("/_cdn/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(response.headers().get("server-timing").is_none());
+    }
+}
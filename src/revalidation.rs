@@ -0,0 +1,142 @@
+//! Bound on background revalidations
+//!
+//! A stale hit, or an early refresh, revalidates in a detached task while
+//! the cached copy is served. When many entries expire together those tasks
+//! pile up and compete with live misses for origin connections, so at most
+//! `cache.max_concurrent_revalidations` run at once. A revalidation past the
+//! cap is skipped rather than queued: the entry keeps serving stale and a
+//! later request tries again.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::metrics::Metrics;
+
+/// What the limiter and its permits share
+struct Slots {
+    max: usize,
+    in_flight: AtomicUsize,
+    metrics: Option<Arc<Metrics>>,
+}
+
+/// Hands out a bounded number of background revalidation permits
+pub struct RevalidationLimiter {
+    slots: Arc<Slots>,
+}
+
+/// A running background revalidation; dropping it frees its slot
+pub struct RevalidationPermit {
+    slots: Arc<Slots>,
+}
+
+impl RevalidationLimiter {
+    pub fn new(max: usize) -> Self {
+        Self {
+            slots: Arc::new(Slots {
+                max,
+                in_flight: AtomicUsize::new(0),
+                metrics: None,
+            }),
+        }
+    }
+
+    /// Count skipped revalidations and track how many are in flight
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        if let Some(slots) = Arc::get_mut(&mut self.slots) {
+            slots.metrics = Some(metrics);
+        }
+        self
+    }
+
+    /// A permit to revalidate, or `None` (counted as skipped) when `max`
+    /// revalidations are already running
+    pub fn try_start(&self) -> Option<RevalidationPermit> {
+        let started = self
+            .slots
+            .in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < self.slots.max).then_some(n + 1)
+            });
+        match started {
+            Ok(previous) => {
+                self.slots.set_gauge(previous + 1);
+                Some(RevalidationPermit {
+                    slots: self.slots.clone(),
+                })
+            }
+            Err(_) => {
+                if let Some(metrics) = &self.slots.metrics {
+                    metrics.record_revalidation_skipped();
+                }
+                None
+            }
+        }
+    }
+
+    /// Background revalidations running now
+    pub fn in_flight(&self) -> usize {
+        self.slots.in_flight.load(Ordering::Acquire)
+    }
+}
+
+impl Slots {
+    fn set_gauge(&self, in_flight: usize) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_revalidations_in_flight(in_flight);
+        }
+    }
+}
+
+impl Drop for RevalidationPermit {
+    fn drop(&mut self) {
+        let in_flight = self.slots.in_flight.fetch_sub(1, Ordering::AcqRel) - 1;
+        self.slots.set_gauge(in_flight);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_in_flight_never_exceeds_the_cap() {
+        let metrics = Arc::new(Metrics::new());
+        let limiter = Arc::new(RevalidationLimiter::new(8).with_metrics(metrics.clone()));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..64)
+            .map(|i| {
+                let limiter = limiter.clone();
+                let peak = peak.clone();
+                tokio::spawn(async move {
+                    let mut started = 0;
+                    for _ in 0..50 {
+                        if let Some(permit) = limiter.try_start() {
+                            started += 1;
+                            peak.fetch_max(limiter.in_flight(), Ordering::Relaxed);
+                            tokio::time::sleep(Duration::from_micros(100 + i)).await;
+                            drop(permit);
+                        }
+                        tokio::task::yield_now().await;
+                    }
+                    started
+                })
+            })
+            .collect();
+        let mut started = 0;
+        for task in tasks {
+            started += task.await.unwrap();
+        }
+
+        assert!(started > 0);
+        assert!(peak.load(Ordering::Relaxed) <= 8);
+        assert_eq!(limiter.in_flight(), 0);
+        let text = metrics.gather();
+        assert!(text.contains("cdn_cache_revalidations_in_flight 0"));
+        assert!(text.contains(&format!(
+            "cdn_cache_revalidations_skipped_total {}",
+            64 * 50 - started
+        )));
+    }
+}
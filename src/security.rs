@@ -11,25 +11,104 @@ use axum::{
     response::{IntoResponse, Response},
 };
 use hmac::{Hmac, Mac};
+use regex::Regex;
 use sha2::Sha256;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::{debug, warn};
 
-use crate::config::SecurityConfig;
+use crate::config::{RegexLimitsConfig, SecurityConfig};
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// Set of path patterns compiled once at startup
+#[derive(Clone, Default)]
+struct PathPatterns {
+    patterns: Vec<Regex>,
+}
+
+impl PathPatterns {
+    fn compile(patterns: &[String], setting: &str, limits: &RegexLimitsConfig) -> Self {
+        let patterns = patterns
+            .iter()
+            .filter_map(|pattern| match limits.compile(pattern) {
+                Ok(re) => Some(re),
+                Err(e) => {
+                    warn!(setting, pattern = %pattern, error = %e, "Failed to compile path pattern");
+                    None
+                }
+            })
+            .collect();
+
+        Self { patterns }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        self.patterns.iter().any(|re| re.is_match(path))
+    }
+
+    /// An empty set places no restriction, so it matches every path
+    fn scopes(&self, path: &str) -> bool {
+        self.is_empty() || self.matches(path)
+    }
+}
+
 /// Security state for middleware
 #[derive(Clone)]
 pub struct Security {
     config: SecurityConfig,
+    signing_include: PathPatterns,
+    signing_exclude: PathPatterns,
+    allowlist_paths: PathPatterns,
+    blocklist_paths: PathPatterns,
 }
 
 impl Security {
-    pub fn new(config: SecurityConfig) -> Self {
-        Self { config }
+    pub fn new(config: SecurityConfig, regex_limits: &RegexLimitsConfig) -> Self {
+        let compile = |patterns: &[String], setting: &str| {
+            PathPatterns::compile(patterns, setting, regex_limits)
+        };
+        let signing_include = compile(&config.signing.include_paths, "signing.include_paths");
+        let signing_exclude = compile(&config.signing.exclude_paths, "signing.exclude_paths");
+        let allowlist_paths = compile(&config.ip_access.allowlist_paths, "ip_access.allowlist_paths");
+        let blocklist_paths = compile(&config.ip_access.blocklist_paths, "ip_access.blocklist_paths");
+
+        Self {
+            config,
+            signing_include,
+            signing_exclude,
+            allowlist_paths,
+            blocklist_paths,
+        }
+    }
+
+    /// Check whether request signing applies to a path.
+    ///
+    /// `include_paths` wins over `exclude_paths` when both match. When
+    /// `include_paths` is set, paths matching neither list are not checked.
+    pub fn signing_applies(&self, path: &str) -> bool {
+        if self.signing_include.matches(path) {
+            return true;
+        }
+        if self.signing_exclude.matches(path) {
+            return false;
+        }
+        self.signing_include.is_empty()
+    }
+
+    /// Check whether the IP allowlist is enforced on a path
+    pub fn allowlist_applies(&self, path: &str) -> bool {
+        self.allowlist_paths.scopes(path)
+    }
+
+    /// Check whether the IP blocklist is enforced on a path
+    pub fn blocklist_applies(&self, path: &str) -> bool {
+        self.blocklist_paths.scopes(path)
     }
 
     /// Check if security headers are enabled
@@ -139,7 +218,7 @@ pub async fn request_signing_middleware(
     request: Request<Body>,
     next: Next,
 ) -> Response {
-    if !security.signing_enabled() {
+    if !security.signing_enabled() || !security.signing_applies(request.uri().path()) {
         return next.run(request).await;
     }
 
@@ -221,15 +300,22 @@ pub async fn ip_access_control_middleware(
 
     let config = &security.config.ip_access;
     let client_ip = extract_client_ip(&request, addr.ip(), config.trust_proxy_headers);
+    let path = request.uri().path();
 
     // Check blocklist first (takes precedence)
-    if !config.blocklist.is_empty() && is_ip_in_list(&client_ip, &config.blocklist) {
+    if !config.blocklist.is_empty()
+        && security.blocklist_applies(path)
+        && is_ip_in_list(&client_ip, &config.blocklist)
+    {
         warn!(ip = %client_ip, "Request blocked: IP in blocklist");
         return (StatusCode::FORBIDDEN, "Access denied").into_response();
     }
 
     // Check allowlist if configured
-    if !config.allowlist.is_empty() && !is_ip_in_list(&client_ip, &config.allowlist) {
+    if !config.allowlist.is_empty()
+        && security.allowlist_applies(path)
+        && !is_ip_in_list(&client_ip, &config.allowlist)
+    {
         warn!(ip = %client_ip, "Request blocked: IP not in allowlist");
         return (StatusCode::FORBIDDEN, "Access denied").into_response();
     }
@@ -339,8 +425,15 @@ fn is_ip_in_list(ip: &IpAddr, list: &[String]) -> bool {
     false
 }
 
+/// Whether `cidr` is a well-formed `address/prefix` range
+pub(crate) fn is_valid_cidr(cidr: &str) -> bool {
+    cidr.split_once('/')
+        .and_then(|(network, _)| network.parse::<IpAddr>().ok())
+        .is_some_and(|network| check_cidr(&network, cidr).is_some())
+}
+
 /// Check if IP matches CIDR notation
-fn check_cidr(ip: &IpAddr, cidr: &str) -> Option<bool> {
+pub(crate) fn check_cidr(ip: &IpAddr, cidr: &str) -> Option<bool> {
     let parts: Vec<&str> = cidr.split('/').collect();
     if parts.len() != 2 {
         return None;
@@ -380,6 +473,72 @@ fn check_cidr(ip: &IpAddr, cidr: &str) -> Option<bool> {
     }
 }
 
+/// Proxies whose forwarding headers are believed, from `server.trusted_proxies`
+///
+/// A request's client is its connecting peer unless the peer is one of these.
+/// Then `X-Forwarded-For` is read from the right, past any further trusted
+/// proxies, to the address the innermost trusted proxy saw connect. Entries
+/// left of that were written by the client and could say anything.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    cidrs: Vec<String>,
+}
+
+impl TrustedProxies {
+    /// Ranges are checked by `Config::validate`; any that still don't parse
+    /// match nothing
+    pub fn new(cidrs: &[String]) -> Self {
+        Self {
+            cidrs: cidrs.to_vec(),
+        }
+    }
+
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.cidrs
+            .iter()
+            .any(|cidr| check_cidr(&ip, cidr) == Some(true))
+    }
+
+    /// The address a request came from
+    pub fn client_ip(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        if !self.trusts(peer) {
+            return peer;
+        }
+
+        let forwarded = self.forwarded_for(headers);
+        if forwarded.is_empty() {
+            return headers
+                .get("X-Real-IP")
+                .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+                .unwrap_or(peer);
+        }
+        let mut client = peer;
+        for entry in forwarded.iter().rev() {
+            let Ok(ip) = entry.parse::<IpAddr>() else {
+                break;
+            };
+            client = ip;
+            if !self.trusts(ip) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// Every `X-Forwarded-For` entry, oldest first
+    pub fn forwarded_for(&self, headers: &HeaderMap) -> Vec<String> {
+        headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|entry| entry.trim().to_string())
+            .filter(|entry| !entry.is_empty())
+            .collect()
+    }
+}
+
 /// Extract client IP from request headers or connection info
 fn extract_client_ip(request: &Request<Body>, fallback: IpAddr, trust_proxy: bool) -> IpAddr {
     if !trust_proxy {
@@ -451,6 +610,42 @@ mod tests {
         assert_eq!(check_cidr(&ip, "2001:db9::/32"), Some(false));
     }
 
+    #[test]
+    fn test_trusted_proxies_resolve_the_client() {
+        let proxies = TrustedProxies::new(&["10.0.0.0/8".to_string()]);
+        let lb: IpAddr = "10.0.0.2".parse().unwrap();
+        let outsider: IpAddr = "203.0.113.5".parse().unwrap();
+        let forwarded = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("X-Forwarded-For", HeaderValue::from_static(value));
+            headers
+        };
+
+        // Headers from untrusted peers are ignored
+        let forged = forwarded("10.0.0.9");
+        assert_eq!(proxies.client_ip(&forged, outsider), outsider);
+
+        // Behind the load balancer, the entry it appended is the client; a
+        // forged entry to its left is not
+        let chain = forwarded("10.0.0.9, 198.51.100.7");
+        assert_eq!(
+            proxies.client_ip(&chain, lb),
+            "198.51.100.7".parse::<IpAddr>().unwrap()
+        );
+        let through_two = forwarded("198.51.100.7, 10.0.0.3");
+        assert_eq!(
+            proxies.client_ip(&through_two, lb),
+            "198.51.100.7".parse::<IpAddr>().unwrap()
+        );
+
+        // The load balancer's own requests, and an unparsable chain
+        assert_eq!(proxies.client_ip(&HeaderMap::new(), lb), lb);
+        assert_eq!(proxies.client_ip(&forwarded("unknown"), lb), lb);
+        assert_eq!(proxies.forwarded_for(&chain), ["10.0.0.9", "198.51.100.7"]);
+
+        assert_eq!(TrustedProxies::default().client_ip(&chain, lb), lb);
+    }
+
     #[test]
     fn test_is_ip_in_list() {
         let ip: IpAddr = "192.168.1.100".parse().unwrap();
@@ -494,4 +689,91 @@ mod tests {
         let signature3 = generate_signature(secret, "POST", "/path", "query=1", 1234567890);
         assert_ne!(signature, signature3);
     }
+
+    fn security_with(
+        signing: crate::config::RequestSigningConfig,
+        ip_access: crate::config::IpAccessConfig,
+    ) -> Security {
+        Security::new(
+            SecurityConfig {
+                signing,
+                ip_access,
+                ..Default::default()
+            },
+            &RegexLimitsConfig::default(),
+        )
+    }
+
+    fn signing_paths(include: &[&str], exclude: &[&str]) -> Security {
+        security_with(
+            crate::config::RequestSigningConfig {
+                enabled: true,
+                include_paths: include.iter().map(|p| p.to_string()).collect(),
+                exclude_paths: exclude.iter().map(|p| p.to_string()).collect(),
+                ..Default::default()
+            },
+            Default::default(),
+        )
+    }
+
+    #[test]
+    fn test_signing_applies_everywhere_by_default() {
+        let security = signing_paths(&[], &[]);
+
+        assert!(security.signing_applies("/"));
+        assert!(security.signing_applies("/static/app.js"));
+    }
+
+    #[test]
+    fn test_signing_exclude_paths() {
+        let security = signing_paths(&[], &["^/static/"]);
+
+        assert!(!security.signing_applies("/static/app.js"));
+        assert!(security.signing_applies("/api/users"));
+    }
+
+    #[test]
+    fn test_signing_include_paths_scope_checks() {
+        let security = signing_paths(&["^/api/", "^/_cdn/"], &[]);
+
+        assert!(security.signing_applies("/api/users"));
+        assert!(security.signing_applies("/_cdn/purge"));
+        assert!(!security.signing_applies("/static/app.js"));
+    }
+
+    #[test]
+    fn test_signing_include_takes_precedence_over_exclude() {
+        let security = signing_paths(&["^/api/private/"], &["^/api/"]);
+
+        assert!(security.signing_applies("/api/private/keys"));
+        assert!(!security.signing_applies("/api/public/feed"));
+        assert!(!security.signing_applies("/static/app.js"));
+    }
+
+    #[test]
+    fn test_signing_invalid_pattern_is_skipped() {
+        let security = signing_paths(&[], &["(unclosed", "^/static/"]);
+
+        assert!(!security.signing_applies("/static/app.js"));
+        assert!(security.signing_applies("/api/users"));
+    }
+
+    #[test]
+    fn test_ip_list_path_scopes() {
+        let security = security_with(
+            Default::default(),
+            crate::config::IpAccessConfig {
+                enabled: true,
+                allowlist: vec!["10.0.0.0/8".to_string()],
+                blocklist: vec!["203.0.113.50".to_string()],
+                allowlist_paths: vec!["^/_cdn/".to_string()],
+                ..Default::default()
+            },
+        );
+
+        assert!(security.allowlist_applies("/_cdn/purge"));
+        assert!(!security.allowlist_applies("/static/app.js"));
+        assert!(security.blocklist_applies("/_cdn/purge"));
+        assert!(security.blocklist_applies("/static/app.js"));
+    }
 }
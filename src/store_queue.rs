@@ -0,0 +1,277 @@
+//! Cache writes of large responses, off the request path
+//!
+//! Storing a large entry can evict a long run of others, demote entries from
+//! L1 to L2 and update the tag index, all on the request that happened to
+//! trigger it. Responses of at least `cache.store_queue.min_body_bytes` are
+//! instead handed to a bounded queue drained by a few writer tasks, and the
+//! request carries on without waiting. A store arriving at a full queue is
+//! dropped rather than waited for; the next miss fetches the response again.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
+use std::time::Instant;
+
+use tokio::sync::{Notify, mpsc};
+use tracing::{debug, warn};
+
+use crate::cache::{Cache, CacheEntry, FillOutcome, FillSlot};
+use crate::config::StoreQueueConfig;
+use crate::metrics::Metrics;
+
+/// A store waiting for a writer
+struct QueuedStore {
+    slot: FillSlot,
+    entry: CacheEntry,
+    queued_at: Instant,
+}
+
+/// What the writer tasks share with the queue
+struct Writers {
+    cache: Arc<Cache>,
+    receiver: tokio::sync::Mutex<mpsc::Receiver<QueuedStore>>,
+    /// Stores queued or being written
+    pending: AtomicUsize,
+    idle: Notify,
+    metrics: Option<Arc<Metrics>>,
+}
+
+/// Stores cache entries, queueing large ones for background writers
+pub struct StoreQueue {
+    config: StoreQueueConfig,
+    sender: mpsc::Sender<QueuedStore>,
+    writers: Arc<Writers>,
+    /// Writers are spawned by the first queued store, from within the runtime
+    started: Once,
+}
+
+impl StoreQueue {
+    pub fn new(config: StoreQueueConfig, cache: Arc<Cache>) -> Self {
+        let (sender, receiver) = mpsc::channel(config.capacity.max(1));
+        Self {
+            config,
+            sender,
+            writers: Arc::new(Writers {
+                cache,
+                receiver: tokio::sync::Mutex::new(receiver),
+                pending: AtomicUsize::new(0),
+                idle: Notify::new(),
+                metrics: None,
+            }),
+            started: Once::new(),
+        }
+    }
+
+    /// Count queued and dropped stores and track the queue's depth
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        if let Some(writers) = Arc::get_mut(&mut self.writers) {
+            writers.metrics = Some(metrics);
+        }
+        self
+    }
+
+    /// Store `entry` in `slot`, returning whether it was stored or queued
+    ///
+    /// Small bodies, and every body when the queue is disabled, are stored
+    /// before this returns.
+    pub fn store(&self, slot: FillSlot, entry: CacheEntry) -> bool {
+        if !self.config.enabled || entry.body.len() < self.config.min_body_bytes {
+            return self.writers.cache.fill(slot, entry) == FillOutcome::Stored;
+        }
+        self.started.call_once(|| self.spawn_writers());
+
+        // Counted first so a writer finishing it can't make the count wrap
+        let depth = self.writers.pending.fetch_add(1, Ordering::AcqRel) + 1;
+        let queued = QueuedStore {
+            slot,
+            entry,
+            queued_at: Instant::now(),
+        };
+        match self.sender.try_send(queued) {
+            Ok(()) => {
+                self.writers.record("queued", depth);
+                true
+            }
+            Err(e) => {
+                let dropped = e.into_inner();
+                let depth = self.writers.finish_one();
+                debug!(
+                    cache_key = %dropped.slot.key(),
+                    size = dropped.entry.size,
+                    "Cache store queue full, dropping store"
+                );
+                self.writers.record("dropped", depth);
+                false
+            }
+        }
+    }
+
+    /// Wait until every store queued so far has been written
+    pub async fn flush(&self) {
+        loop {
+            let idle = self.writers.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+            if self.writers.pending.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    /// Stores queued or being written
+    pub fn depth(&self) -> usize {
+        self.writers.pending.load(Ordering::Acquire)
+    }
+
+    fn spawn_writers(&self) {
+        for _ in 0..self.config.workers.max(1) {
+            tokio::spawn(write_queued(self.writers.clone()));
+        }
+    }
+}
+
+impl Writers {
+    /// Mark a queued store done, returning the stores still pending
+    fn finish_one(&self) -> usize {
+        let depth = self.pending.fetch_sub(1, Ordering::AcqRel) - 1;
+        if depth == 0 {
+            self.idle.notify_waiters();
+        }
+        depth
+    }
+
+    fn record(&self, result: &str, depth: usize) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_store_queue(result);
+            metrics.set_store_queue_depth(depth);
+        }
+    }
+}
+
+/// A writer task: store queued entries until the queue is dropped
+async fn write_queued(writers: Arc<Writers>) {
+    loop {
+        let queued = writers.receiver.lock().await.recv().await;
+        let Some(QueuedStore {
+            slot,
+            entry,
+            queued_at,
+        }) = queued
+        else {
+            return;
+        };
+        if let Some(metrics) = &writers.metrics {
+            metrics.record_store_queue_wait(queued_at.elapsed());
+        }
+
+        // Eviction is CPU-bound, so it runs off the async workers too
+        let key = slot.key().to_string();
+        let cache = writers.cache.clone();
+        match tokio::task::spawn_blocking(move || cache.fill(slot, entry)).await {
+            Ok(outcome) => debug!(cache_key = %key, ?outcome, "Queued cache store written"),
+            Err(e) => warn!(cache_key = %key, error = %e, "Queued cache store failed"),
+        }
+
+        let depth = writers.finish_one();
+        if let Some(metrics) = &writers.metrics {
+            metrics.set_store_queue_depth(depth);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CacheConfig;
+    use crate::headers::ResponseHeaders;
+    use bytes::Bytes;
+    use std::time::{Duration, SystemTime};
+
+    fn entry(size: usize) -> CacheEntry {
+        CacheEntry {
+            body: Bytes::from(vec![b'x'; size]),
+            headers: ResponseHeaders::default(),
+            status_code: 200,
+            content_type: None,
+            etag: None,
+            last_modified: None,
+            created_at: Instant::now(),
+            expires_at: Instant::now() + Duration::from_secs(3600),
+            expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
+            size,
+            stale_if_error_secs: None,
+            access_count: 0,
+            last_accessed: Instant::now(),
+            cache_tags: Vec::new(),
+            headers_only: false,
+            fetch_duration: Duration::ZERO,
+            immutable: false,
+        }
+    }
+
+    fn setup(config: StoreQueueConfig) -> (StoreQueue, Arc<Cache>, Arc<Metrics>) {
+        let cache = Arc::new(Cache::new(CacheConfig::default()));
+        let metrics = Arc::new(Metrics::new());
+        let queue = StoreQueue::new(config, cache.clone()).with_metrics(metrics.clone());
+        (queue, cache, metrics)
+    }
+
+    fn config(capacity: usize) -> StoreQueueConfig {
+        StoreQueueConfig {
+            min_body_bytes: 1024,
+            capacity,
+            workers: 1,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_small_bodies_are_stored_inline() {
+        let (queue, cache, _) = setup(config(4));
+        assert!(queue.store(cache.reserve("web/small"), entry(1023)));
+        assert!(cache.get("web/small").is_some());
+        assert_eq!(queue.depth(), 0);
+
+        let (queue, cache, _) = setup(StoreQueueConfig {
+            enabled: false,
+            ..config(4)
+        });
+        assert!(queue.store(cache.reserve("web/large"), entry(4096)));
+        assert!(cache.get("web/large").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_large_bodies_are_queued_and_dropped_when_full() {
+        let (queue, cache, metrics) = setup(config(2));
+
+        // The writer can't run until this task yields, so the queue fills
+        for i in 0..3 {
+            let stored = queue.store(cache.reserve(&format!("web/large-{}", i)), entry(4096));
+            assert_eq!(stored, i < 2);
+        }
+        assert_eq!(queue.depth(), 2);
+        assert!(cache.get("web/large-0").is_none());
+
+        queue.flush().await;
+        assert_eq!(queue.depth(), 0);
+        assert!(cache.get("web/large-0").is_some());
+        assert!(cache.get("web/large-1").is_some());
+        assert!(cache.get("web/large-2").is_none());
+
+        let text = metrics.gather();
+        assert!(text.contains(r#"cdn_cache_store_queue_total{result="queued"} 2"#));
+        assert!(text.contains(r#"cdn_cache_store_queue_total{result="dropped"} 1"#));
+        assert!(text.contains("cdn_cache_store_queue_depth 0"));
+        assert!(text.contains("cdn_cache_store_queue_wait_seconds_count 2"));
+    }
+
+    #[tokio::test]
+    async fn test_purge_cancels_a_queued_store() {
+        let (queue, cache, _) = setup(config(4));
+        assert!(queue.store(cache.reserve("web/large"), entry(4096)));
+        assert_eq!(cache.cancel_fills(|key| key == "web/large"), 1);
+
+        queue.flush().await;
+        assert!(cache.get("web/large").is_none());
+    }
+}
@@ -0,0 +1,189 @@
+//! In-memory stand-ins for the cache and origin, for handler unit tests
+//!
+//! Swapped into `AppState::content_cache` and `AppState::origin_client` so a
+//! test can script exactly what the origin answers and what the cache holds,
+//! without a listening socket or arranging entries to expire.
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::watch;
+
+use crate::cache::{Cache, CacheEntry, CacheLookup, CachedRange, ContentCache};
+use crate::error::CdnResult;
+use crate::headers::ResponseHeaders;
+use crate::origin::{OriginClient, OriginFuture, OriginResponse};
+
+type Respond = dyn Fn(&str) -> CdnResult<OriginResponse> + Send + Sync;
+
+/// An origin answering every fetch from a closure of the request path
+pub struct FakeOrigin {
+    respond: Box<Respond>,
+    /// Fetches made, as "GET /path", "HEAD /path" or "RANGE /path bytes=..."
+    calls: Mutex<Vec<String>>,
+    /// While false, fetches wait before answering
+    open: watch::Sender<bool>,
+}
+
+impl FakeOrigin {
+    pub fn new(
+        respond: impl Fn(&str) -> CdnResult<OriginResponse> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            respond: Box::new(respond),
+            calls: Mutex::new(Vec::new()),
+            open: watch::Sender::new(true),
+        }
+    }
+
+    /// Hold every fetch until [`FakeOrigin::release`]
+    pub fn held(self) -> Self {
+        self.open.send_replace(false);
+        self
+    }
+
+    /// Let held fetches answer
+    pub fn release(&self) {
+        self.open.send_replace(true);
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Record the fetch, wait until released, then answer it
+    fn answer<'a>(&'a self, call: String, path: &'a str) -> OriginFuture<'a> {
+        self.calls.lock().unwrap().push(call);
+        let mut open = self.open.subscribe();
+        Box::pin(async move {
+            let _ = open.wait_for(|open| *open).await;
+            (self.respond)(path)
+        })
+    }
+}
+
+impl OriginClient for FakeOrigin {
+    fn fetch<'a>(
+        &'a self,
+        _origin_name: &'a str,
+        path: &'a str,
+        _query: Option<&'a str>,
+        _request_headers: &'a HashMap<String, String>,
+    ) -> OriginFuture<'a> {
+        self.answer(format!("GET {}", path), path)
+    }
+
+    fn fetch_uncached<'a>(
+        &'a self,
+        _origin_name: &'a str,
+        path: &'a str,
+        _query: Option<&'a str>,
+        _request_headers: &'a HashMap<String, String>,
+    ) -> OriginFuture<'a> {
+        self.answer(format!("GET {}", path), path)
+    }
+
+    fn fetch_head<'a>(
+        &'a self,
+        _origin_name: &'a str,
+        path: &'a str,
+        _query: Option<&'a str>,
+        _request_headers: &'a HashMap<String, String>,
+    ) -> OriginFuture<'a> {
+        self.answer(format!("HEAD {}", path), path)
+    }
+
+    fn fetch_range<'a>(
+        &'a self,
+        _origin_name: &'a str,
+        path: &'a str,
+        _query: Option<&'a str>,
+        _request_headers: &'a HashMap<String, String>,
+        range: &'a str,
+    ) -> OriginFuture<'a> {
+        self.answer(format!("RANGE {} {}", path, range), path)
+    }
+}
+
+/// A cache that never hits, optionally holding a copy to serve on errors
+///
+/// Fill slots come from `slots`, so a fill after a miss lands there and
+/// never changes what this cache answers.
+pub struct FakeCache {
+    slots: Arc<Cache>,
+    stale: Option<CacheEntry>,
+}
+
+impl FakeCache {
+    pub fn new(slots: Arc<Cache>) -> Self {
+        Self { slots, stale: None }
+    }
+
+    /// Serve `entry` whenever stale-if-error asks for a copy
+    pub fn with_stale(mut self, entry: CacheEntry) -> Self {
+        self.stale = Some(entry);
+        self
+    }
+}
+
+impl ContentCache for FakeCache {
+    fn get_or_reserve(
+        &self,
+        key: &str,
+        _allow_headers_only: bool,
+        _extra_stale: Duration,
+    ) -> CacheLookup {
+        CacheLookup::Vacant(self.slots.reserve(key))
+    }
+
+    fn get_stale_for_error(&self, _key: &str) -> Option<CacheEntry> {
+        self.stale.clone()
+    }
+
+    fn get_range(&self, _key: &str, _range_header: &str) -> Option<CachedRange> {
+        None
+    }
+}
+
+/// An origin response with a text body
+pub fn response(status_code: u16, body: &str) -> OriginResponse {
+    let mut headers = ResponseHeaders::new();
+    headers.insert("content-type", "text/plain");
+    OriginResponse {
+        status_code,
+        headers,
+        body: Bytes::copy_from_slice(body.as_bytes()),
+        content_type: Some("text/plain".to_string()),
+        etag: None,
+        last_modified: None,
+        cache_control: None,
+    }
+}
+
+/// A 200 entry with a text body that expired 30 seconds ago, within the
+/// default stale window
+pub fn expired_entry(body: &str) -> CacheEntry {
+    let mut headers = ResponseHeaders::new();
+    headers.insert("content-type", "text/plain");
+    let created_at = Instant::now() - Duration::from_secs(90);
+    CacheEntry {
+        body: Bytes::copy_from_slice(body.as_bytes()),
+        headers,
+        status_code: 200,
+        content_type: Some("text/plain".to_string()),
+        etag: None,
+        last_modified: None,
+        created_at,
+        expires_at: created_at + Duration::from_secs(60),
+        expires_at_wall: SystemTime::now() - Duration::from_secs(30),
+        size: body.len(),
+        stale_if_error_secs: Some(3600),
+        access_count: 0,
+        last_accessed: created_at,
+        cache_tags: Vec::new(),
+        headers_only: false,
+        fetch_duration: Duration::ZERO,
+        immutable: false,
+    }
+}
@@ -0,0 +1,549 @@
+//! Trace sampling decided when a request finishes
+//!
+//! A head sampler picks traces before anything about the request is known,
+//! so at a low rate it misses the errors and slow requests worth looking at.
+//! Here spans are recorded but not sampled when they start, and
+//! [`DeferredSampling`] holds finished spans per trace until the trace's
+//! local root span (the request) ends. Its status, duration and path then
+//! decide whether the whole trace is exported or dropped.
+//!
+//! A trace the caller marked sampled in `traceparent` is sampled from the
+//! start and exported as usual.
+
+use opentelemetry::trace::{
+    Link, SamplingDecision, SamplingResult, SpanContext, SpanId, SpanKind, Status, TraceContextExt,
+    TraceId,
+};
+use opentelemetry::{Context, KeyValue, Value};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{ShouldSample, Span, SpanData, SpanProcessor};
+use regex::Regex;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::config::{RegexLimitsConfig, TracingConfig};
+
+/// Attribute on the request span carrying the request path
+pub const PATH_ATTRIBUTE: &str = "path";
+
+/// Attribute on the request span carrying the response status
+pub const STATUS_ATTRIBUTE: &str = "http.response.status_code";
+
+/// Decided traces remembered, for spans that end after their request
+const DECIDED_TRACES: usize = 4096;
+
+/// Samples nothing up front unless the caller did; see [`DeferredSampling`]
+#[derive(Debug, Clone)]
+pub struct DeferredSampler {
+    /// Honor a remote parent's sampled flag
+    propagate_context: bool,
+}
+
+impl DeferredSampler {
+    pub fn new(propagate_context: bool) -> Self {
+        Self { propagate_context }
+    }
+}
+
+impl ShouldSample for DeferredSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        _trace_id: TraceId,
+        _name: &str,
+        _span_kind: &SpanKind,
+        _attributes: &[KeyValue],
+        _links: &[Link],
+    ) -> SamplingResult {
+        let parent = parent_context.filter(|cx| cx.has_active_span());
+        let decision = match parent.map(|cx| cx.span()) {
+            Some(span) => {
+                let context = span.span_context();
+                if context.is_sampled() && (!context.is_remote() || self.propagate_context) {
+                    SamplingDecision::RecordAndSample
+                } else if context.is_remote() || span.is_recording() {
+                    SamplingDecision::RecordOnly
+                } else {
+                    SamplingDecision::Drop
+                }
+            }
+            None => SamplingDecision::RecordOnly,
+        };
+        SamplingResult {
+            decision,
+            attributes: Vec::new(),
+            trace_state: parent
+                .map(|cx| cx.span().span_context().trace_state().clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// When a finished request's trace is kept
+#[derive(Debug)]
+pub struct SamplingPolicy {
+    sample_rate: f64,
+    path_rates: Vec<(Regex, f64)>,
+    status_gte: u16,
+    slower_than: Option<Duration>,
+}
+
+impl SamplingPolicy {
+    pub fn new(config: &TracingConfig, regex: &RegexLimitsConfig) -> Self {
+        let path_rates = config
+            .path_sample_rates
+            .iter()
+            .filter_map(|rule| match regex.compile(&rule.path_pattern) {
+                Ok(pattern) => Some((pattern, rule.rate)),
+                Err(e) => {
+                    warn!(pattern = %rule.path_pattern, error = %e, "Failed to compile trace sampling pattern");
+                    None
+                }
+            })
+            .collect();
+        Self {
+            sample_rate: config.sample_rate,
+            path_rates,
+            status_gte: config.always_sample_status_gte,
+            slower_than: (config.always_sample_slower_than_ms > 0)
+                .then(|| Duration::from_millis(config.always_sample_slower_than_ms)),
+        }
+    }
+
+    /// Keep the trace `root` is the local root of?
+    fn keep(&self, root: &SpanData) -> bool {
+        let status = attribute(root, STATUS_ATTRIBUTE).and_then(|value| match value {
+            Value::I64(status) => u16::try_from(*status).ok(),
+            Value::String(status) => status.as_str().parse().ok(),
+            _ => None,
+        });
+        if matches!(root.status, Status::Error { .. })
+            || (self.status_gte > 0 && status.is_some_and(|status| status >= self.status_gte))
+        {
+            return true;
+        }
+
+        let duration = root
+            .end_time
+            .duration_since(root.start_time)
+            .unwrap_or_default();
+        if self.slower_than.is_some_and(|slow| duration >= slow) {
+            return true;
+        }
+
+        let path = attribute(root, PATH_ATTRIBUTE).map(|value| value.as_str());
+        let rate = path
+            .and_then(|path| {
+                self.path_rates
+                    .iter()
+                    .find(|(pattern, _)| pattern.is_match(&path))
+            })
+            .map_or(self.sample_rate, |(_, rate)| *rate);
+        in_sample(root.span_context.trace_id(), rate)
+    }
+}
+
+fn attribute<'a>(span: &'a SpanData, key: &str) -> Option<&'a Value> {
+    span.attributes
+        .iter()
+        .find(|kv| kv.key.as_str() == key)
+        .map(|kv| &kv.value)
+}
+
+/// Deterministic pick keyed on the trace ID, so every span of a trace agrees
+fn in_sample(trace_id: TraceId, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    if rate <= 0.0 {
+        return false;
+    }
+    let bytes = trace_id.to_bytes();
+    let low = u64::from_be_bytes(bytes[8..].try_into().expect("8 bytes"));
+    (low as f64 / u64::MAX as f64) < rate
+}
+
+/// Spans waiting on their trace's decision, and recent decisions
+#[derive(Debug, Default)]
+struct Pending {
+    traces: HashMap<TraceId, Vec<SpanData>>,
+    spans: usize,
+    decided: VecDeque<(TraceId, bool)>,
+    decisions: HashMap<TraceId, bool>,
+}
+
+impl Pending {
+    fn remember(&mut self, trace_id: TraceId, keep: bool) {
+        if self.decisions.insert(trace_id, keep).is_none() {
+            self.decided.push_back((trace_id, keep));
+        }
+        while self.decided.len() > DECIDED_TRACES {
+            if let Some((old, _)) = self.decided.pop_front() {
+                self.decisions.remove(&old);
+            }
+        }
+    }
+}
+
+/// Span processor holding unsampled spans until their request is sampled
+///
+/// Spans already sampled go straight to `inner`. The rest wait, grouped by
+/// trace, until the trace's local root ends; then the [`SamplingPolicy`]
+/// decides and a kept trace's spans are passed on marked sampled. Spans
+/// ending after their root follow the decision made for it.
+#[derive(Debug)]
+pub struct DeferredSampling<P> {
+    inner: P,
+    policy: SamplingPolicy,
+    max_pending_spans: usize,
+    pending: Mutex<Pending>,
+}
+
+impl<P: SpanProcessor> DeferredSampling<P> {
+    pub fn new(inner: P, policy: SamplingPolicy, max_pending_spans: usize) -> Self {
+        Self {
+            inner,
+            policy,
+            max_pending_spans,
+            pending: Mutex::new(Pending::default()),
+        }
+    }
+
+    /// Spans waiting for their trace to be decided
+    pub fn pending_spans(&self) -> usize {
+        self.pending.lock().unwrap().spans
+    }
+
+    fn export(&self, mut span: SpanData) {
+        let context = &span.span_context;
+        span.span_context = SpanContext::new(
+            context.trace_id(),
+            context.span_id(),
+            context.trace_flags().with_sampled(true),
+            context.is_remote(),
+            context.trace_state().clone(),
+        );
+        self.inner.on_end(span);
+    }
+}
+
+impl<P: SpanProcessor> SpanProcessor for DeferredSampling<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        if span.span_context.is_sampled() {
+            self.inner.on_end(span);
+            return;
+        }
+
+        let trace_id = span.span_context.trace_id();
+        let is_local_root = span.parent_span_id == SpanId::INVALID || span.parent_span_is_remote;
+        let mut pending = self.pending.lock().unwrap();
+        if !is_local_root {
+            match pending.decisions.get(&trace_id) {
+                Some(true) => {
+                    drop(pending);
+                    self.export(span);
+                }
+                Some(false) => {}
+                None if pending.spans < self.max_pending_spans => {
+                    pending.spans += 1;
+                    pending.traces.entry(trace_id).or_default().push(span);
+                }
+                None => {
+                    debug!(trace_id = %trace_id, "Too many spans awaiting sampling, dropped one")
+                }
+            }
+            return;
+        }
+
+        let keep = self.policy.keep(&span);
+        let children = pending.traces.remove(&trace_id).unwrap_or_default();
+        pending.spans -= children.len();
+        pending.remember(trace_id, keep);
+        drop(pending);
+        if keep {
+            for child in children {
+                self.export(child);
+            }
+            self.export(span);
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{Span as _, TraceFlags, TraceState, Tracer, TracerProvider as _};
+    use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::SystemTime;
+
+    /// Keeps every span handed to it
+    #[derive(Debug, Clone, Default)]
+    struct Collected(Arc<Mutex<Vec<SpanData>>>);
+
+    impl Collected {
+        fn take_names(&self) -> Vec<String> {
+            self.0
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|span| span.name.to_string())
+                .collect()
+        }
+    }
+
+    impl SpanProcessor for Collected {
+        fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+        fn on_end(&self, span: SpanData) {
+            assert!(span.span_context.is_sampled());
+            self.0.lock().unwrap().push(span);
+        }
+
+        fn force_flush(&self) -> OTelSdkResult {
+            Ok(())
+        }
+
+        fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+            Ok(())
+        }
+    }
+
+    /// Lets the test look at the processor the provider owns
+    #[derive(Debug)]
+    struct Shared(Arc<DeferredSampling<Collected>>);
+
+    impl SpanProcessor for Shared {
+        fn on_start(&self, span: &mut Span, cx: &Context) {
+            self.0.on_start(span, cx);
+        }
+
+        fn on_end(&self, span: SpanData) {
+            self.0.on_end(span);
+        }
+
+        fn force_flush(&self) -> OTelSdkResult {
+            self.0.force_flush()
+        }
+
+        fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+            self.0.shutdown_with_timeout(timeout)
+        }
+    }
+
+    struct Harness {
+        _provider: SdkTracerProvider,
+        tracer: SdkTracer,
+        exported: Collected,
+        processor: Arc<DeferredSampling<Collected>>,
+    }
+
+    impl Harness {
+        /// Nothing sampled by rate except paths under /api/
+        fn new(propagate_context: bool, max_pending_spans: usize) -> Self {
+            let mut config: TracingConfig = toml::from_str(
+                r#"
+                sample_rate = 0.0
+                always_sample_status_gte = 500
+                always_sample_slower_than_ms = "1s"
+
+                [[path_sample_rates]]
+                path_pattern = "^/api/"
+                rate = 1.0
+                "#,
+            )
+            .unwrap();
+            config.propagate_context = propagate_context;
+
+            let exported = Collected::default();
+            let policy = SamplingPolicy::new(&config, &RegexLimitsConfig::default());
+            let processor = Arc::new(DeferredSampling::new(
+                exported.clone(),
+                policy,
+                max_pending_spans,
+            ));
+            let provider = SdkTracerProvider::builder()
+                .with_sampler(DeferredSampler::new(config.propagate_context))
+                .with_span_processor(Shared(processor.clone()))
+                .build();
+            let tracer = provider.tracer("test");
+            Self {
+                _provider: provider,
+                tracer,
+                exported,
+                processor,
+            }
+        }
+
+        /// Start a request span under `parent`; the returned context holds it
+        fn start(&self, parent: &Context, path: &str) -> (Context, SystemTime) {
+            let start = SystemTime::now();
+            let root = self
+                .tracer
+                .span_builder("http_request")
+                .with_start_time(start)
+                .with_attributes([KeyValue::new(PATH_ATTRIBUTE, path.to_string())])
+                .start_with_context(&self.tracer, parent);
+            (parent.with_span(root), start)
+        }
+
+        fn child(&self, request: &Context) -> opentelemetry_sdk::trace::Span {
+            self.tracer.start_with_context("origin_fetch", request)
+        }
+
+        fn finish(&self, request: &Context, start: SystemTime, status: i64, took: Duration) {
+            let root = request.span();
+            root.set_attribute(KeyValue::new(STATUS_ATTRIBUTE, status));
+            root.end_with_timestamp(start + took);
+        }
+
+        /// A request with one child span, finished after `took`
+        fn request(&self, parent: &Context, path: &str, status: i64, took: Duration) {
+            let (request, start) = self.start(parent, path);
+            self.child(&request).end();
+            self.finish(&request, start, status, took);
+        }
+    }
+
+    /// A caller's context, in a trace of its own
+    fn remote_parent(sampled: bool) -> Context {
+        static TRACES: AtomicU64 = AtomicU64::new(1);
+        let flags = if sampled {
+            TraceFlags::SAMPLED
+        } else {
+            TraceFlags::default()
+        };
+        Context::new().with_remote_span_context(SpanContext::new(
+            TraceId::from(u128::from(TRACES.fetch_add(1, Ordering::Relaxed))),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            flags,
+            true,
+            TraceState::default(),
+        ))
+    }
+
+    const FAST: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn test_fast_successful_requests_are_dropped() {
+        let harness = Harness::new(true, 100);
+        for status in [200, 304, 404] {
+            harness.request(&Context::new(), "/static/app.css", status, FAST);
+        }
+        assert!(harness.exported.take_names().is_empty());
+        assert_eq!(harness.processor.pending_spans(), 0);
+    }
+
+    #[test]
+    fn test_errors_and_slow_requests_are_kept_whole() {
+        let harness = Harness::new(true, 100);
+        harness.request(&Context::new(), "/static/app.css", 503, FAST);
+        assert_eq!(
+            harness.exported.take_names(),
+            vec!["origin_fetch", "http_request"]
+        );
+
+        harness.request(
+            &Context::new(),
+            "/static/app.css",
+            200,
+            Duration::from_millis(1500),
+        );
+        assert_eq!(
+            harness.exported.take_names(),
+            vec!["origin_fetch", "http_request"]
+        );
+
+        // Just under both thresholds
+        harness.request(
+            &Context::new(),
+            "/static/app.css",
+            499,
+            Duration::from_millis(999),
+        );
+        assert!(harness.exported.take_names().is_empty());
+    }
+
+    #[test]
+    fn test_path_rates_apply_by_pattern() {
+        let harness = Harness::new(true, 100);
+        harness.request(&Context::new(), "/api/users", 200, FAST);
+        assert_eq!(
+            harness.exported.take_names(),
+            vec!["origin_fetch", "http_request"]
+        );
+        harness.request(&Context::new(), "/static/api/users", 200, FAST);
+        assert!(harness.exported.take_names().is_empty());
+    }
+
+    #[test]
+    fn test_upstream_sampled_flag_is_honored() {
+        let harness = Harness::new(true, 100);
+        harness.request(&remote_parent(true), "/static/app.css", 200, FAST);
+        assert_eq!(
+            harness.exported.take_names(),
+            vec!["origin_fetch", "http_request"]
+        );
+        // Unsampled upstream is still kept for an error
+        harness.request(&remote_parent(false), "/static/app.css", 200, FAST);
+        assert!(harness.exported.take_names().is_empty());
+        harness.request(&remote_parent(false), "/static/app.css", 502, FAST);
+        assert_eq!(harness.exported.take_names().len(), 2);
+
+        let harness = Harness::new(false, 100);
+        harness.request(&remote_parent(true), "/static/app.css", 200, FAST);
+        assert!(harness.exported.take_names().is_empty());
+    }
+
+    #[test]
+    fn test_late_spans_follow_the_decision() {
+        let harness = Harness::new(true, 100);
+
+        let (kept, start) = harness.start(&Context::new(), "/static/a");
+        let mut late = harness.child(&kept);
+        harness.finish(&kept, start, 500, FAST);
+        assert_eq!(harness.exported.take_names(), vec!["http_request"]);
+        late.end();
+        assert_eq!(harness.exported.take_names(), vec!["origin_fetch"]);
+
+        let (dropped, start) = harness.start(&Context::new(), "/static/b");
+        let mut late = harness.child(&dropped);
+        harness.finish(&dropped, start, 200, FAST);
+        late.end();
+        assert!(harness.exported.take_names().is_empty());
+        assert_eq!(harness.processor.pending_spans(), 0);
+    }
+
+    #[test]
+    fn test_pending_spans_are_bounded() {
+        let harness = Harness::new(true, 2);
+        let (request, start) = harness.start(&Context::new(), "/static/a");
+        for _ in 0..5 {
+            harness.child(&request).end();
+        }
+        assert_eq!(harness.processor.pending_spans(), 2);
+        harness.finish(&request, start, 500, FAST);
+        assert_eq!(harness.exported.take_names().len(), 3);
+        assert_eq!(harness.processor.pending_spans(), 0);
+    }
+}
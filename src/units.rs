@@ -0,0 +1,341 @@
+//! Human-readable durations and sizes in config
+//!
+//! Config fields keep their unit in their name (`ttl_secs`, `interval_ms`,
+//! `max_size_mb`) and still take a bare integer in that unit. They also take
+//! a string carrying its own unit, such as `"30s"`, `"5m"`, `"1h30m"` or
+//! `"512MB"`, which is converted to the field's unit. A value that isn't a
+//! whole number of the field's unit (`"1500ms"` for a `_secs` field) is an
+//! error rather than being rounded.
+//!
+//! Sizes are 1024-based whether written `MB` or `MiB`, as the `_mb` fields
+//! have always been.
+
+use serde::de::{self, Deserializer, Unexpected, Visitor};
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+const KIB: u64 = 1024;
+
+/// Parse a duration such as `"30s"`, `"250ms"` or `"1h 30m"`
+///
+/// Units are `ms`, `s`, `m`, `h` and `d`. Every number needs one.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err("empty duration".to_string());
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number: u64 = rest[..digits]
+            .parse()
+            .map_err(|_| format!("invalid duration {:?}", value))?;
+        rest = rest[digits..].trim_start();
+        let unit_len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let unit = &rest[..unit_len];
+        let part = match unit.to_ascii_lowercase().as_str() {
+            "ms" => Duration::from_millis(number),
+            "s" => Duration::from_secs(number),
+            "m" => Duration::from_secs(number.saturating_mul(60)),
+            "h" => Duration::from_secs(number.saturating_mul(3600)),
+            "d" => Duration::from_secs(number.saturating_mul(86_400)),
+            "" => {
+                return Err(format!(
+                    "duration {:?} needs a unit (ms, s, m, h or d)",
+                    value
+                ));
+            }
+            _ => return Err(format!("unknown unit {:?} in duration {:?}", unit, value)),
+        };
+        total = total.saturating_add(part);
+        rest = rest[unit_len..].trim_start();
+    }
+    Ok(total)
+}
+
+/// Parse a size in bytes such as `"512MB"`, `"2GiB"` or `"64 KB"`
+///
+/// Units are `B`, `K`/`KB`/`KiB`, `M`/`MB`/`MiB`, `G`/`GB`/`GiB` and
+/// `T`/`TB`/`TiB`, case-insensitive, all 1024-based. A bare number is bytes.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let number: u64 = value[..digits]
+        .parse()
+        .map_err(|_| format!("invalid size {:?}", value))?;
+    let scale = match value[digits..].trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => KIB,
+        "m" | "mb" | "mib" => KIB.pow(2),
+        "g" | "gb" | "gib" => KIB.pow(3),
+        "t" | "tb" | "tib" => KIB.pow(4),
+        unit => return Err(format!("unknown unit {:?} in size {:?}", unit, value)),
+    };
+    number
+        .checked_mul(scale)
+        .ok_or_else(|| format!("size {:?} is too large", value))
+}
+
+/// The unit a config field is counted in
+#[derive(Debug, Clone, Copy)]
+enum Unit {
+    Millis,
+    Secs,
+    Hours,
+    Bytes,
+    Mebibytes,
+}
+
+impl Unit {
+    fn name(self) -> &'static str {
+        match self {
+            Unit::Millis => "milliseconds",
+            Unit::Secs => "seconds",
+            Unit::Hours => "hours",
+            Unit::Bytes => "bytes",
+            Unit::Mebibytes => "MiB",
+        }
+    }
+
+    /// `value` counted in this unit
+    fn convert(self, value: &str) -> Result<u64, String> {
+        let (amount, per_unit) = match self {
+            Unit::Millis | Unit::Secs | Unit::Hours => {
+                let duration = parse_duration(value)?;
+                let nanos_per_unit: u128 = match self {
+                    Unit::Millis => 1_000_000,
+                    Unit::Secs => 1_000_000_000,
+                    _ => 3_600_000_000_000,
+                };
+                (duration.as_nanos(), nanos_per_unit)
+            }
+            Unit::Bytes | Unit::Mebibytes => {
+                let bytes = parse_size(value)?;
+                let per_unit = match self {
+                    Unit::Bytes => 1,
+                    _ => KIB.pow(2),
+                };
+                (u128::from(bytes), u128::from(per_unit))
+            }
+        };
+        if amount % per_unit != 0 {
+            return Err(format!(
+                "{:?} is not a whole number of {}",
+                value,
+                self.name()
+            ));
+        }
+        u64::try_from(amount / per_unit).map_err(|_| format!("{:?} is too large", value))
+    }
+}
+
+struct UnitVisitor<T> {
+    unit: Unit,
+    target: PhantomData<T>,
+}
+
+impl<T: TryFrom<u64>> UnitVisitor<T> {
+    fn fit<E: de::Error>(&self, value: u64) -> Result<T, E> {
+        T::try_from(value).map_err(|_| {
+            E::invalid_value(Unexpected::Unsigned(value), &"a value that fits the field")
+        })
+    }
+}
+
+impl<'de, T: TryFrom<u64>> Visitor<'de> for UnitVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.unit {
+            Unit::Millis | Unit::Secs | Unit::Hours => write!(
+                f,
+                "a whole number of {} or a duration such as \"30s\" or \"5m\"",
+                self.unit.name()
+            ),
+            Unit::Bytes | Unit::Mebibytes => write!(
+                f,
+                "a whole number of {} or a size such as \"512MB\" or \"2GiB\"",
+                self.unit.name()
+            ),
+        }
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<T, E> {
+        self.fit(value)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<T, E> {
+        let value =
+            u64::try_from(value).map_err(|_| E::invalid_value(Unexpected::Signed(value), &self))?;
+        self.fit(value)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<T, E> {
+        // A number in quotes is in the field's own unit
+        let amount = match value.trim().parse::<u64>() {
+            Ok(amount) => amount,
+            Err(_) => self.unit.convert(value).map_err(E::custom)?,
+        };
+        self.fit(amount)
+    }
+}
+
+fn deserialize_in<'de, D, T>(deserializer: D, unit: Unit) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    deserializer.deserialize_any(UnitVisitor {
+        unit,
+        target: PhantomData,
+    })
+}
+
+/// Deserialize a `_ms` field
+pub fn millis<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    deserialize_in(deserializer, Unit::Millis)
+}
+
+/// Deserialize a `_secs` field
+pub fn secs<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    deserialize_in(deserializer, Unit::Secs)
+}
+
+/// Deserialize an optional `_secs` field; leave it out for `None`
+pub fn option_secs<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    secs(deserializer).map(Some)
+}
+
+/// Deserialize a `_hours` field
+pub fn hours<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    deserialize_in(deserializer, Unit::Hours)
+}
+
+/// Deserialize a `_bytes` field
+pub fn bytes<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    deserialize_in(deserializer, Unit::Bytes)
+}
+
+/// Deserialize an optional `_bytes` field; leave it out for `None`
+pub fn option_bytes<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    bytes(deserializer).map(Some)
+}
+
+/// Deserialize a `_mb` field, counted in MiB
+pub fn mebibytes<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    deserialize_in(deserializer, Unit::Mebibytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("2d"), Ok(Duration::from_secs(172_800)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(
+            parse_duration(" 1h 30m 15s "),
+            Ok(Duration::from_secs(5415))
+        );
+        assert_eq!(parse_duration("10S"), Ok(Duration::from_secs(10)));
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("30").unwrap_err().contains("needs a unit"));
+        assert!(parse_duration("1h30").unwrap_err().contains("needs a unit"));
+        assert!(parse_duration("5 minutes").unwrap_err().contains("minutes"));
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("-5s").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("100B"), Ok(100));
+        assert_eq!(parse_size("64KB"), Ok(64 * 1024));
+        assert_eq!(parse_size("64 KiB"), Ok(64 * 1024));
+        assert_eq!(parse_size("512MB"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_size("512mib"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_size("2GiB"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("1T"), Ok(1024u64.pow(4)));
+
+        assert!(parse_size("").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("1.5GB").is_err());
+        assert!(parse_size("10 parsecs").unwrap_err().contains("parsecs"));
+        assert!(
+            parse_size("99999999999TB")
+                .unwrap_err()
+                .contains("too large")
+        );
+    }
+
+    #[test]
+    fn test_converts_to_the_field_unit() {
+        assert_eq!(Unit::Secs.convert("5m"), Ok(300));
+        assert_eq!(Unit::Millis.convert("2s"), Ok(2000));
+        assert_eq!(Unit::Hours.convert("2d"), Ok(48));
+        assert_eq!(Unit::Mebibytes.convert("2GiB"), Ok(2048));
+        assert_eq!(Unit::Bytes.convert("1KB"), Ok(1024));
+
+        assert!(
+            Unit::Secs
+                .convert("1500ms")
+                .unwrap_err()
+                .contains("whole number of seconds")
+        );
+        assert!(
+            Unit::Hours
+                .convert("90m")
+                .unwrap_err()
+                .contains("whole number of hours")
+        );
+        assert!(
+            Unit::Mebibytes
+                .convert("1536KB")
+                .unwrap_err()
+                .contains("whole number of MiB")
+        );
+    }
+}
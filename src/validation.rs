@@ -0,0 +1,345 @@
+//! Origin response validation
+//!
+//! Checks run on origin responses before they're cached, so a truncated or
+//! corrupt body is served once rather than out of the cache until it
+//! expires. A response that fails carries [`VALIDATION_FAILED_HEADER`],
+//! which keeps it out of the cache on every path that stores responses.
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use md5::Md5;
+use reqwest::header::HeaderMap;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::config::ResponseValidationConfig;
+
+/// Set on a response that failed validation, with the failure's reason
+pub const VALIDATION_FAILED_HEADER: &str = "x-cdn-validation-failed";
+
+/// Why an origin response was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationFailure {
+    /// The body's size differs from the origin's Content-Length
+    ContentLength { declared: u64, received: usize },
+    /// The body doesn't hash to a digest the origin sent
+    Digest { algorithm: String },
+    /// A 200 with no body for a content type that must have one
+    EmptyBody,
+    /// An image body without its format's signature
+    MagicBytes { content_type: String },
+}
+
+impl ValidationFailure {
+    /// Label used in metrics and the validation header
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ValidationFailure::ContentLength { .. } => "content_length",
+            ValidationFailure::Digest { .. } => "digest",
+            ValidationFailure::EmptyBody => "empty_body",
+            ValidationFailure::MagicBytes { .. } => "magic_bytes",
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationFailure::ContentLength { declared, received } => write!(
+                f,
+                "Content-Length is {} but {} bytes were received",
+                declared, received
+            ),
+            ValidationFailure::Digest { algorithm } => {
+                write!(f, "body doesn't match its {} digest", algorithm)
+            }
+            ValidationFailure::EmptyBody => f.write_str("empty body"),
+            ValidationFailure::MagicBytes { content_type } => {
+                write!(f, "body isn't a valid {}", content_type)
+            }
+        }
+    }
+}
+
+/// What the origin said about a body, read before the body is consumed
+#[derive(Debug, Clone, Default)]
+pub struct DeclaredBody {
+    pub content_length: Option<u64>,
+    pub content_type: Option<String>,
+    /// Digest, Repr-Digest and Content-Digest values; Content-MD5 is
+    /// carried as `md5=<value>`
+    pub digests: Vec<String>,
+}
+
+impl DeclaredBody {
+    /// Read from an origin response's headers
+    ///
+    /// The HTTP client drops Content-Length from bodies it decodes, so the
+    /// length is only checked for bodies received as sent.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let value = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let digests = ["digest", "repr-digest", "content-digest"]
+            .into_iter()
+            .flat_map(|name| headers.get_all(name))
+            .filter_map(|v| v.to_str().ok())
+            .map(str::to_string);
+        Self {
+            content_length: value("content-length").and_then(|v| v.trim().parse().ok()),
+            content_type: value("content-type").map(str::to_string),
+            digests: value("content-md5")
+                .map(|md5| format!("md5={}", md5.trim()))
+                .into_iter()
+                .chain(digests)
+                .collect(),
+        }
+    }
+}
+
+/// Check a received body against the origin's declarations and the rules
+///
+/// Only 2xx responses other than 204 are checked. Digests, empty bodies and
+/// image signatures describe the whole object, so only 200s are checked
+/// for those.
+pub fn validate(
+    config: &ResponseValidationConfig,
+    status: u16,
+    declared: &DeclaredBody,
+    body: &[u8],
+) -> Result<(), ValidationFailure> {
+    if !(200..300).contains(&status) || status == 204 {
+        return Ok(());
+    }
+
+    if config.content_length
+        && let Some(length) = declared.content_length
+        && length != body.len() as u64
+    {
+        return Err(ValidationFailure::ContentLength {
+            declared: length,
+            received: body.len(),
+        });
+    }
+
+    if status != 200 {
+        return Ok(());
+    }
+
+    if config.verify_digest {
+        for value in &declared.digests {
+            verify_digests(value, body)?;
+        }
+    }
+
+    let media_type = declared.content_type.as_deref().map(media_type);
+    if let Some(media_type) = &media_type {
+        if body.is_empty()
+            && config
+                .reject_empty
+                .iter()
+                .any(|pattern| type_matches(pattern, media_type))
+        {
+            return Err(ValidationFailure::EmptyBody);
+        }
+        if config.magic_bytes && !has_image_signature(media_type, body) {
+            return Err(ValidationFailure::MagicBytes {
+                content_type: media_type.clone(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Lowercased type and subtype, without parameters
+fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Whether `pattern` (`image/png` or `image/*`) covers a media type
+fn type_matches(pattern: &str, media_type: &str) -> bool {
+    let pattern = pattern.trim().to_ascii_lowercase();
+    match pattern.strip_suffix("/*") {
+        Some(top_level) => media_type
+            .split_once('/')
+            .is_some_and(|(ty, _)| ty == top_level),
+        None => pattern == media_type,
+    }
+}
+
+/// Check every digest in a header value this crate knows how to compute
+///
+/// Accepts `alg=base64` pairs (Digest) and `alg=:base64:` pairs
+/// (Repr-Digest and Content-Digest). Unknown algorithms are skipped.
+fn verify_digests(value: &str, body: &[u8]) -> Result<(), ValidationFailure> {
+    for item in value.split(',') {
+        let Some((algorithm, encoded)) = item.split_once('=') else {
+            continue;
+        };
+        let algorithm = algorithm.trim().to_ascii_lowercase();
+        let actual = match algorithm.as_str() {
+            "md5" => Md5::digest(body).to_vec(),
+            "sha-256" => Sha256::digest(body).to_vec(),
+            "sha-512" => Sha512::digest(body).to_vec(),
+            _ => continue,
+        };
+        let expected = BASE64.decode(encoded.trim().trim_matches(':'));
+        if expected.ok() != Some(actual) {
+            return Err(ValidationFailure::Digest { algorithm });
+        }
+    }
+    Ok(())
+}
+
+/// Whether an image body starts with its declared format's signature;
+/// formats without a fixed signature always pass
+fn has_image_signature(media_type: &str, body: &[u8]) -> bool {
+    match media_type {
+        "image/png" => body.starts_with(b"\x89PNG\r\n\x1a\n"),
+        "image/jpeg" | "image/jpg" | "image/pjpeg" => body.starts_with(&[0xff, 0xd8, 0xff]),
+        "image/gif" => body.starts_with(b"GIF87a") || body.starts_with(b"GIF89a"),
+        "image/webp" => body.starts_with(b"RIFF") && body.get(8..12) == Some(b"WEBP"),
+        "image/avif" => body.get(4..8) == Some(b"ftyp"),
+        "image/bmp" => body.starts_with(b"BM"),
+        "image/x-icon" | "image/vnd.microsoft.icon" => body.starts_with(&[0, 0, 1, 0]),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn declared(content_type: &str) -> DeclaredBody {
+        DeclaredBody {
+            content_type: Some(content_type.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_content_length_mismatch() {
+        let config = ResponseValidationConfig::default();
+        let body = DeclaredBody {
+            content_length: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            validate(&config, 200, &body, b"hello"),
+            Err(ValidationFailure::ContentLength {
+                declared: 10,
+                received: 5
+            })
+        );
+        assert!(validate(&config, 206, &body, b"hello").is_err());
+        assert!(validate(&config, 200, &body, b"helloworld").is_ok());
+        // Error responses aren't cached, so aren't checked
+        assert!(validate(&config, 502, &body, b"hello").is_ok());
+
+        let config = ResponseValidationConfig {
+            content_length: false,
+            ..Default::default()
+        };
+        assert!(validate(&config, 200, &body, b"hello").is_ok());
+    }
+
+    #[test]
+    fn test_digests() {
+        let config = ResponseValidationConfig {
+            verify_digest: true,
+            ..Default::default()
+        };
+        let with_digests = |digests: &[&str]| DeclaredBody {
+            digests: digests.iter().map(|d| d.to_string()).collect(),
+            ..Default::default()
+        };
+        let md5 = BASE64.encode(Md5::digest(b"hello"));
+        let sha256 = BASE64.encode(Sha256::digest(b"hello"));
+        let sha512 = BASE64.encode(Sha512::digest(b"hello"));
+
+        for digests in [
+            vec![format!("md5={}", md5)],
+            vec![format!("SHA-256={}, unknown=abc", sha256)],
+            vec![format!("md5={},sha-512={}", md5, sha512)],
+            vec![format!("sha-256=:{}:", sha256)],
+        ] {
+            let refs: Vec<&str> = digests.iter().map(String::as_str).collect();
+            assert!(validate(&config, 200, &with_digests(&refs), b"hello").is_ok());
+            assert_eq!(
+                validate(&config, 200, &with_digests(&refs), b"hellp")
+                    .unwrap_err()
+                    .reason(),
+                "digest"
+            );
+        }
+        assert!(validate(&config, 200, &with_digests(&["sha-256=!!"]), b"hello").is_err());
+
+        // Off by default
+        let default = ResponseValidationConfig::default();
+        let md5 = format!("md5={}", md5);
+        assert!(validate(&default, 200, &with_digests(&[&md5]), b"hellp").is_ok());
+
+        // Content-MD5 is read as an md5 digest
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "content-md5",
+            BASE64.encode(Md5::digest(b"hello")).parse().unwrap(),
+        );
+        let declared = DeclaredBody::from_headers(&headers);
+        assert!(validate(&config, 200, &declared, b"hello").is_ok());
+        assert!(validate(&config, 200, &declared, b"hellp").is_err());
+    }
+
+    #[test]
+    fn test_empty_bodies() {
+        let config = ResponseValidationConfig {
+            reject_empty: vec!["image/*".to_string(), "application/json".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            validate(&config, 200, &declared("image/png"), b""),
+            Err(ValidationFailure::EmptyBody)
+        );
+        assert!(
+            validate(
+                &config,
+                200,
+                &declared("Application/JSON; charset=utf-8"),
+                b""
+            )
+            .is_err()
+        );
+        assert!(validate(&config, 200, &declared("text/plain"), b"").is_ok());
+        assert!(validate(&config, 200, &DeclaredBody::default(), b"").is_ok());
+        assert!(validate(&config, 200, &declared("application/json"), b"{}").is_ok());
+    }
+
+    #[test]
+    fn test_image_signatures() {
+        let config = ResponseValidationConfig {
+            magic_bytes: true,
+            ..Default::default()
+        };
+        for (content_type, body) in [
+            ("image/png", &b"\x89PNG\r\n\x1a\n...."[..]),
+            ("image/jpeg", &[0xff, 0xd8, 0xff, 0xe0][..]),
+            ("image/gif", b"GIF89a...."),
+            ("image/webp", b"RIFF\x10\0\0\0WEBPVP8 "),
+            ("image/avif", b"\0\0\0\x1cftypavif"),
+            ("image/svg+xml", b"<svg/>"),
+        ] {
+            assert!(validate(&config, 200, &declared(content_type), body).is_ok());
+        }
+        assert_eq!(
+            validate(&config, 200, &declared("image/png"), b"<html>error</html>"),
+            Err(ValidationFailure::MagicBytes {
+                content_type: "image/png".to_string()
+            })
+        );
+        assert!(validate(&config, 200, &declared("image/jpeg"), b"").is_err());
+        // A range of an image doesn't start at the signature
+        assert!(validate(&config, 206, &declared("image/png"), b"....").is_ok());
+    }
+}
@@ -8,8 +8,8 @@ fn test_cache_operations() {
     use bytes::Bytes;
     use screaming_eagle::cache::{Cache, CacheEntry, CacheStatus};
     use screaming_eagle::config::CacheConfig;
-    use std::collections::HashMap;
-    use std::time::Instant;
+    use screaming_eagle::headers::ResponseHeaders;
+    use std::time::{Instant, SystemTime};
 
     let config = CacheConfig::default();
     let cache = Cache::new(config);
@@ -19,18 +19,22 @@ fn test_cache_operations() {
     let now = Instant::now();
     let entry = CacheEntry {
         body: body.clone(),
-        headers: HashMap::new(),
+        headers: ResponseHeaders::new(),
         status_code: 200,
         content_type: Some("text/plain".to_string()),
         etag: Some("\"abc123\"".to_string()),
         last_modified: None,
         created_at: now,
         expires_at: now + Duration::from_secs(3600),
+        expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
         size: body.len(),
         stale_if_error_secs: Some(300),
         access_count: 0,
         last_accessed: now,
         cache_tags: Vec::new(),
+        headers_only: false,
+        fetch_duration: Duration::ZERO,
+        immutable: false,
     };
 
     // Store the entry
@@ -52,8 +56,8 @@ fn test_cache_invalidation() {
     use bytes::Bytes;
     use screaming_eagle::cache::Cache;
     use screaming_eagle::config::CacheConfig;
-    use std::collections::HashMap;
-    use std::time::Instant;
+    use screaming_eagle::headers::ResponseHeaders;
+    use std::time::{Instant, SystemTime};
 
     let config = CacheConfig::default();
     let cache = Cache::new(config);
@@ -63,18 +67,22 @@ fn test_cache_invalidation() {
     let now = Instant::now();
     let entry = screaming_eagle::cache::CacheEntry {
         body,
-        headers: HashMap::new(),
+        headers: ResponseHeaders::new(),
         status_code: 200,
         content_type: None,
         etag: None,
         last_modified: None,
         created_at: now,
         expires_at: now + Duration::from_secs(3600),
+        expires_at_wall: SystemTime::now() + Duration::from_secs(3600),
         size: 9,
         stale_if_error_secs: None,
         access_count: 0,
         last_accessed: now,
         cache_tags: Vec::new(),
+        headers_only: false,
+        fetch_duration: Duration::ZERO,
+        immutable: false,
     };
 
     cache.set("key1".to_string(), entry.clone());
@@ -242,3 +250,251 @@ fn test_cache_key_generation() {
         "origin1/path"
     );
 }
+
+/// Load test: a hot key's expiry under steady traffic, with and without early refresh
+///
+/// Simulates 2 requests/ms against one key with a 10s TTL and a 200ms origin
+/// fetch, on a mock clock. Without early refresh every request arriving during
+/// the refetch after each expiry has to wait on it; with early refresh the key
+/// is refetched shortly before expiry and no request waits.
+#[test]
+fn test_early_refresh_flattens_expiry_spike() {
+    let baseline = simulate_hot_key_expiry(false);
+    let early = simulate_hot_key_expiry(true);
+
+    // Three expiries, each stalling ~200ms of traffic
+    assert!(baseline.blocked >= 3 * 300, "{:?}", baseline);
+    assert_eq!(early.blocked, 0, "{:?}", early);
+
+    // Refreshing a little early costs at most about one extra fetch per cycle
+    assert!(
+        early.origin_fetches <= 2 * baseline.origin_fetches,
+        "{:?} vs {:?}",
+        early,
+        baseline
+    );
+}
+
+#[derive(Debug)]
+struct HotKeyRun {
+    /// Requests that found no usable entry and waited on an origin fetch
+    blocked: u64,
+    origin_fetches: u64,
+}
+
+fn simulate_hot_key_expiry(early_refresh: bool) -> HotKeyRun {
+    use bytes::Bytes;
+    use screaming_eagle::cache::{Cache, CacheEntry, CacheLookup, Clock, FillSlot};
+    use screaming_eagle::config::CacheConfig;
+    use screaming_eagle::headers::ResponseHeaders;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Instant, SystemTime};
+
+    struct SimClock {
+        start: Instant,
+        start_wall: SystemTime,
+        elapsed: Mutex<Duration>,
+    }
+
+    impl Clock for SimClock {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.lock().unwrap()
+        }
+
+        fn wall_now(&self) -> SystemTime {
+            self.start_wall + *self.elapsed.lock().unwrap()
+        }
+    }
+
+    const TTL: Duration = Duration::from_secs(10);
+    const FETCH: Duration = Duration::from_millis(200);
+    const REQUESTS_PER_MS: usize = 2;
+    const WARMUP_MS: u64 = 1_000;
+    const RUN_MS: u64 = 35_000;
+
+    let clock = Arc::new(SimClock {
+        start: Instant::now(),
+        start_wall: SystemTime::now(),
+        elapsed: Mutex::new(Duration::ZERO),
+    });
+    let mut config = CacheConfig {
+        stale_while_revalidate_secs: 0,
+        ..Default::default()
+    };
+    config.early_refresh.enabled = early_refresh;
+    let cache = Cache::new(config).with_clock(clock.clone());
+
+    let mut in_flight: Option<(FillSlot, Instant)> = None;
+    let mut run = HotKeyRun {
+        blocked: 0,
+        origin_fetches: 0,
+    };
+
+    for ms in 0..RUN_MS {
+        *clock.elapsed.lock().unwrap() = Duration::from_millis(ms);
+        let now = clock.now();
+
+        if in_flight.as_ref().is_some_and(|(_, done)| *done <= now) {
+            let (slot, _) = in_flight.take().unwrap();
+            let body = Bytes::from_static(b"hot");
+            let entry = CacheEntry {
+                size: body.len(),
+                body,
+                headers: ResponseHeaders::new(),
+                status_code: 200,
+                content_type: None,
+                etag: None,
+                last_modified: None,
+                created_at: slot.requested_at(),
+                expires_at: slot.requested_at() + TTL,
+                expires_at_wall: slot.requested_at_wall() + TTL,
+                stale_if_error_secs: None,
+                access_count: 0,
+                last_accessed: now,
+                cache_tags: Vec::new(),
+                headers_only: false,
+                fetch_duration: Duration::ZERO,
+                immutable: false,
+            };
+            cache.fill(slot, entry);
+        }
+
+        for _ in 0..REQUESTS_PER_MS {
+            let slot = match cache.get_or_reserve("hot", false, Duration::ZERO) {
+                CacheLookup::Found(entry, _) => cache.reserve_early_refresh("hot", &entry),
+                CacheLookup::Vacant(slot) => {
+                    if ms >= WARMUP_MS {
+                        run.blocked += 1;
+                    }
+                    // Later misses coalesce onto the fetch already running
+                    in_flight.is_none().then_some(slot)
+                }
+            };
+            if let Some(slot) = slot {
+                run.origin_fetches += 1;
+                in_flight = Some((slot, now + FETCH));
+            }
+        }
+    }
+    run
+}
+
+/// A server process started from the built binary, killed when dropped
+#[cfg(unix)]
+struct ServerProcess(std::process::Child);
+
+#[cfg(unix)]
+impl ServerProcess {
+    fn start(config_path: &std::path::Path) -> Self {
+        let child = std::process::Command::new(env!("CARGO_BIN_EXE_screaming-eagle"))
+            .env("CDN_CONFIG", config_path)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        Self(child)
+    }
+
+    fn pid(&self) -> u32 {
+        self.0.id()
+    }
+
+    /// Wait for the process to exit on its own
+    async fn exited(&mut self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while tokio::time::Instant::now() < deadline {
+            if self.0.try_wait().unwrap().is_some() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Two processes bound with `reuse_port` share a port and both serve it;
+/// draining one leaves the other serving every request
+#[cfg(unix)]
+#[tokio::test]
+async fn test_reuse_port_lets_two_processes_share_a_port() {
+    use std::collections::HashSet;
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let dir = std::env::temp_dir().join(format!("se-reuse-port-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config_path = dir.join("cdn.toml");
+    std::fs::write(
+        &config_path,
+        format!("[server]\nlisten = [\"127.0.0.1:{port}\"]\nreuse_port = true\n"),
+    )
+    .unwrap();
+
+    // A fresh connection per request, so the kernel picks a process each time
+    let client = reqwest::Client::builder()
+        .pool_max_idle_per_host(0)
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    let base = format!("http://127.0.0.1:{port}/_cdn");
+    let serving_pid = |client: reqwest::Client, url: String| async move {
+        let response = client.get(url).send().await.ok()?;
+        let info: serde_json::Value = serde_json::from_slice(&response.bytes().await.ok()?).ok()?;
+        info["pid"].as_u64().map(|pid| pid as u32)
+    };
+
+    // The second process binds while the first is still listening
+    let mut first = ServerProcess::start(&config_path);
+    let mut seen = HashSet::new();
+    for _ in 0..100 {
+        if let Some(pid) = serving_pid(client.clone(), format!("{base}/info")).await {
+            seen.insert(pid);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(seen, HashSet::from([first.pid()]));
+    let mut second = ServerProcess::start(&config_path);
+
+    for _ in 0..400 {
+        if let Some(pid) = serving_pid(client.clone(), format!("{base}/info")).await {
+            seen.insert(pid);
+        }
+        if seen.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(seen, HashSet::from([first.pid(), second.pid()]));
+
+    // Drain whichever process answers; the other keeps serving
+    let response = client.post(format!("{base}/drain")).send().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+    let drained: screaming_eagle::handlers::DrainResponse =
+        serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert!(!drained.already_draining);
+    let (draining, remaining) = if drained.pid == first.pid() {
+        (&mut first, &second)
+    } else {
+        (&mut second, &first)
+    };
+    assert!(draining.exited(Duration::from_secs(10)).await);
+
+    for _ in 0..20 {
+        let pid = serving_pid(client.clone(), format!("{base}/info")).await;
+        assert_eq!(pid, Some(remaining.pid()));
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}